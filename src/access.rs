@@ -0,0 +1,364 @@
+use axum::{
+    body::Bytes,
+    http::{HeaderMap, HeaderValue, StatusCode},
+    response::{IntoResponse, Response},
+};
+use serde::{Deserialize, Serialize, de::DeserializeOwned};
+use std::{
+    path::{Path, PathBuf},
+    sync::Mutex,
+};
+use tokio::fs;
+use tracing::{info, warn};
+
+use crate::{AppState, ErrorResponse, error_response, tmp_path, write_and_rename};
+
+// Bucket access settings, the `?publicAccessBlock` and `?ownershipControls`
+// subresources of the bucket. Each is kept as XML under .simple-s3 and acts
+// on the ACLs that object writes ask for with their headers:
+//
+// - BlockPublicAcls refuses writes that ask for a public canned ACL or grant
+//   to AllUsers or AuthenticatedUsers with 403 AccessDenied.
+// - BucketOwnerEnforced disables ACLs, refusing writes with any ACL header
+//   but bucket-owner-full-control with 400 AccessControlListNotSupported.
+//
+// The same goes for PUT ?acl. IgnorePublicAcls stops ACLs letting requests
+// without credentials in, see acl.rs, and BlockPublicPolicy and
+// RestrictPublicBuckets act on the bucket policy, see policy.rs. There's one
+// owner for everything, so BucketOwnerPreferred and ObjectWriter have nothing
+// to change. Each node of a cluster or replica set keeps its own settings.
+
+const XMLNS: &str = "http://s3.amazonaws.com/doc/2006-03-01/";
+
+const OBJECT_OWNERSHIPS: [&str; 3] = ["BucketOwnerPreferred", "ObjectWriter", "BucketOwnerEnforced"];
+const PUBLIC_CANNED_ACLS: [&str; 3] = ["public-read", "public-read-write", "authenticated-read"];
+const GRANT_HEADERS: [&str; 5] = [
+    "x-amz-grant-read",
+    "x-amz-grant-write",
+    "x-amz-grant-read-acp",
+    "x-amz-grant-write-acp",
+    "x-amz-grant-full-control",
+];
+pub const PUBLIC_GROUPS: [&str; 2] = [
+    "http://acs.amazonaws.com/groups/global/AllUsers",
+    "http://acs.amazonaws.com/groups/global/AuthenticatedUsers",
+];
+
+// A configuration document of the bucket
+pub trait Document: Clone + Serialize + DeserializeOwned {
+    const FILE: &'static str;
+    // Error code and message of a GET when none is set
+    const NOT_FOUND: (&'static str, &'static str);
+
+    fn set_xmlns(&mut self, xmlns: &str);
+
+    fn is_valid(&self) -> bool {
+        true
+    }
+}
+
+#[derive(Debug, Clone, Default, Serialize, Deserialize)]
+#[serde(rename = "PublicAccessBlockConfiguration")]
+pub struct PublicAccessBlockConfiguration {
+    #[serde(rename = "@xmlns", default)]
+    xmlns: String,
+    #[serde(rename = "BlockPublicAcls", default)]
+    block_public_acls: bool,
+    #[serde(rename = "IgnorePublicAcls", default)]
+    ignore_public_acls: bool,
+    #[serde(rename = "BlockPublicPolicy", default)]
+    block_public_policy: bool,
+    #[serde(rename = "RestrictPublicBuckets", default)]
+    restrict_public_buckets: bool,
+}
+
+impl Document for PublicAccessBlockConfiguration {
+    const FILE: &'static str = ".simple-s3/public-access-block.xml";
+    const NOT_FOUND: (&'static str, &'static str) = (
+        "NoSuchPublicAccessBlockConfiguration",
+        "The public access block configuration was not found",
+    );
+
+    fn set_xmlns(&mut self, xmlns: &str) {
+        self.xmlns = xmlns.to_string();
+    }
+}
+
+impl PublicAccessBlockConfiguration {
+    pub fn new(
+        block_public_acls: bool,
+        ignore_public_acls: bool,
+        block_public_policy: bool,
+        restrict_public_buckets: bool,
+    ) -> Self {
+        Self {
+            xmlns: XMLNS.to_string(),
+            block_public_acls,
+            ignore_public_acls,
+            block_public_policy,
+            restrict_public_buckets,
+        }
+    }
+}
+
+#[derive(Debug, Clone, Serialize, Deserialize)]
+pub struct OwnershipControlsRule {
+    // BucketOwnerPreferred, ObjectWriter or BucketOwnerEnforced
+    #[serde(rename = "ObjectOwnership")]
+    object_ownership: String,
+}
+
+#[derive(Debug, Clone, Serialize, Deserialize)]
+#[serde(rename = "OwnershipControls")]
+pub struct OwnershipControls {
+    #[serde(rename = "@xmlns", default)]
+    xmlns: String,
+    #[serde(rename = "Rule", default)]
+    rules: Vec<OwnershipControlsRule>,
+}
+
+impl Document for OwnershipControls {
+    const FILE: &'static str = ".simple-s3/ownership-controls.xml";
+    const NOT_FOUND: (&'static str, &'static str) = (
+        "OwnershipControlsNotFoundError",
+        "The bucket ownership controls were not found",
+    );
+
+    fn set_xmlns(&mut self, xmlns: &str) {
+        self.xmlns = xmlns.to_string();
+    }
+
+    // S3 takes exactly one rule
+    fn is_valid(&self) -> bool {
+        self.rules.len() == 1
+            && OBJECT_OWNERSHIPS.contains(&self.rules[0].object_ownership.as_str())
+    }
+}
+
+impl OwnershipControls {
+    // None unless `object_ownership` is one S3 knows
+    pub fn new(object_ownership: &str) -> Option<Self> {
+        let controls = Self {
+            xmlns: XMLNS.to_string(),
+            rules: vec![OwnershipControlsRule {
+                object_ownership: object_ownership.to_string(),
+            }],
+        };
+        controls.is_valid().then_some(controls)
+    }
+}
+
+pub struct BucketConfig<T> {
+    path: PathBuf,
+    config: Mutex<Option<T>>,
+}
+
+impl<T: Document> BucketConfig<T> {
+    pub async fn load(data_dir: &Path) -> std::io::Result<Self> {
+        let path = data_dir.join(T::FILE);
+        let config = match fs::read(&path).await {
+            Ok(bytes) => Some(serde_xml_rs::from_reader(bytes.as_slice()).map_err(|e| {
+                std::io::Error::other(format!("invalid {}: {}", path.display(), e))
+            })?),
+            Err(e) if e.kind() == std::io::ErrorKind::NotFound => None,
+            Err(e) => return Err(e),
+        };
+        Ok(Self {
+            path,
+            config: Mutex::new(config),
+        })
+    }
+
+    pub fn get(&self) -> Option<T> {
+        self.config.lock().unwrap().clone()
+    }
+
+    // Saves `document` and applies it
+    pub async fn store(&self, state: &AppState, document: T) -> std::io::Result<()> {
+        let xml = serde_xml_rs::to_string(&document).map_err(std::io::Error::other)?;
+        let tmp = tmp_path(state);
+        if let Err(e) = write_and_rename(state, &tmp, &self.path, xml.as_bytes()).await {
+            let _ = fs::remove_file(&tmp).await;
+            return Err(e);
+        }
+        info!("🔒 Stored {}", self.path.display());
+        *self.config.lock().unwrap() = Some(document);
+        Ok(())
+    }
+}
+
+pub struct Access {
+    pub public_access_block: BucketConfig<PublicAccessBlockConfiguration>,
+    pub ownership_controls: BucketConfig<OwnershipControls>,
+}
+
+impl Access {
+    pub async fn load(data_dir: &Path) -> std::io::Result<Self> {
+        Ok(Self {
+            public_access_block: BucketConfig::load(data_dir).await?,
+            ownership_controls: BucketConfig::load(data_dir).await?,
+        })
+    }
+
+    fn blocks_public_acls(&self) -> bool {
+        self.public_access_block
+            .get()
+            .is_some_and(|config| config.block_public_acls)
+    }
+
+    pub fn ignores_public_acls(&self) -> bool {
+        self.public_access_block
+            .get()
+            .is_some_and(|config| config.ignore_public_acls)
+    }
+
+    pub fn blocks_public_policy(&self) -> bool {
+        self.public_access_block
+            .get()
+            .is_some_and(|config| config.block_public_policy)
+    }
+
+    pub fn restricts_public_buckets(&self) -> bool {
+        self.public_access_block
+            .get()
+            .is_some_and(|config| config.restrict_public_buckets)
+    }
+
+    fn acls_disabled(&self) -> bool {
+        self.ownership_controls.get().is_some_and(|controls| {
+            controls
+                .rules
+                .iter()
+                .any(|rule| rule.object_ownership == "BucketOwnerEnforced")
+        })
+    }
+}
+
+fn malformed_xml() -> Response {
+    error_response(
+        StatusCode::BAD_REQUEST,
+        ErrorResponse {
+            code: "MalformedXML".to_string(),
+            message: "The XML you provided was not well-formed".to_string(),
+            max_size_allowed: None,
+        },
+    )
+}
+
+// Whether a write's ACL headers would make the object public on AWS
+fn public_acl(headers: &HeaderMap) -> bool {
+    let canned = headers
+        .get("x-amz-acl")
+        .and_then(|v| v.to_str().ok())
+        .is_some_and(|acl| PUBLIC_CANNED_ACLS.contains(&acl.trim()));
+    let granted = GRANT_HEADERS.iter().any(|header| {
+        headers
+            .get(*header)
+            .and_then(|v| v.to_str().ok())
+            .is_some_and(|grantees| PUBLIC_GROUPS.iter().any(|group| grantees.contains(group)))
+    });
+    canned || granted
+}
+
+// Whether a write sets an ACL at all. bucket-owner-full-control is what
+// BucketOwnerEnforced gives every object anyway, so it doesn't count
+fn sets_acl(headers: &HeaderMap) -> bool {
+    let canned = headers
+        .get("x-amz-acl")
+        .and_then(|v| v.to_str().ok())
+        .is_some_and(|acl| acl.trim() != "bucket-owner-full-control");
+    canned || GRANT_HEADERS.iter().any(|header| headers.contains_key(*header))
+}
+
+// The refusal for a write whose ACL headers the bucket's settings don't
+// allow, None if it may go ahead
+pub fn refuse_acl(state: &AppState, key: &str, headers: &HeaderMap) -> Option<Response> {
+    refuse_grants(state, key, sets_acl(headers), public_acl(headers))
+}
+
+// The refusal for setting an ACL, which is public or not, that the bucket's
+// settings don't allow, None if it may go ahead
+pub fn refuse_grants(
+    state: &AppState,
+    key: &str,
+    sets_acl: bool,
+    public: bool,
+) -> Option<Response> {
+    let access = &state.access;
+    if access.acls_disabled() && sets_acl {
+        warn!("🔒 Refused ACL on {}, ACLs are disabled by BucketOwnerEnforced", key);
+        return Some(error_response(
+            StatusCode::BAD_REQUEST,
+            ErrorResponse {
+                code: "AccessControlListNotSupported".to_string(),
+                message: "The bucket does not allow ACLs".to_string(),
+                max_size_allowed: None,
+            },
+        ));
+    }
+    if access.blocks_public_acls() && public {
+        warn!("🔒 Refused public ACL on {}, BlockPublicAcls is set", key);
+        return Some(error_response(
+            StatusCode::FORBIDDEN,
+            ErrorResponse {
+                code: "AccessDenied".to_string(),
+                message: "Access Denied".to_string(),
+                max_size_allowed: None,
+            },
+        ));
+    }
+    None
+}
+
+// GET /?publicAccessBlock or /?ownershipControls
+pub async fn get<T: Document>(config: &BucketConfig<T>) -> Result<Response, StatusCode> {
+    let Some(mut document) = config.get() else {
+        let (code, message) = T::NOT_FOUND;
+        return Ok(error_response(
+            StatusCode::NOT_FOUND,
+            ErrorResponse {
+                code: code.to_string(),
+                message: message.to_string(),
+                max_size_allowed: None,
+            },
+        ));
+    };
+    document.set_xmlns(XMLNS);
+    let xml = serde_xml_rs::to_string(&document).map_err(|_| StatusCode::INTERNAL_SERVER_ERROR)?;
+
+    let mut headers = HeaderMap::new();
+    headers.insert("content-type", HeaderValue::from_static("application/xml"));
+    Ok((StatusCode::OK, headers, xml).into_response())
+}
+
+// PUT /?publicAccessBlock or /?ownershipControls
+pub async fn put<T: Document>(
+    state: &AppState,
+    config: &BucketConfig<T>,
+    body: &Bytes,
+) -> Result<Response, StatusCode> {
+    let Ok(mut document) = serde_xml_rs::from_reader::<T, _>(body.as_ref()) else {
+        return Ok(malformed_xml());
+    };
+    if !document.is_valid() {
+        return Ok(malformed_xml());
+    }
+    document.set_xmlns(XMLNS);
+    if let Err(e) = config.store(state, document).await {
+        warn!("Failed to store {}: {}", config.path.display(), e);
+        return Err(StatusCode::INTERNAL_SERVER_ERROR);
+    }
+    Ok(StatusCode::OK.into_response())
+}
+
+// DELETE /?publicAccessBlock or /?ownershipControls
+pub async fn delete<T: Document>(config: &BucketConfig<T>) -> Result<Response, StatusCode> {
+    match fs::remove_file(&config.path).await {
+        Ok(()) => {}
+        Err(e) if e.kind() == std::io::ErrorKind::NotFound => {}
+        Err(_) => return Err(StatusCode::INTERNAL_SERVER_ERROR),
+    }
+    *config.config.lock().unwrap() = None;
+    info!("🔒 Removed {}", config.path.display());
+    Ok(StatusCode::NO_CONTENT.into_response())
+}
@@ -0,0 +1,536 @@
+use axum::{
+    body::Bytes,
+    http::{HeaderMap, HeaderValue, StatusCode},
+    response::{IntoResponse, Response},
+};
+use serde::{Deserialize, Serialize};
+use sha2::{Digest, Sha256};
+use std::{
+    io,
+    path::{Path, PathBuf},
+};
+use tokio::fs;
+use tracing::{info, warn};
+
+use crate::{
+    AppState, ErrorResponse, Owner, access, error_response, normalize, object_path, object_stamp,
+    policy, tmp_path, write_and_rename,
+};
+
+// Access control lists, S3's ?acl subresource and x-amz-acl header. PUT and
+// GET /?acl and /{key}?acl replace and read the ACL of the bucket or an
+// object, as an AccessControlPolicy or with the x-amz-acl and x-amz-grant-*
+// headers, which a PUT, copy or multipart upload can also set the ACL with. Canned ACLs are expanded into the grants S3 would give.
+//
+// Every ACL grants the owner FULL_CONTROL, and the one access key is the
+// owner, so grants only change what requests without credentials may do:
+//
+// - READ for AllUsers on an object lets them GET and HEAD it
+// - READ for AllUsers on the bucket lets them list it
+//
+// WRITE and the _ACP permissions, and grants to AuthenticatedUsers or to
+// canonical user IDs, are kept and returned but give no access. An explicit
+// Deny in the bucket policy wins over any grant, and with IgnorePublicAcls
+// none count. Object ACLs are kept under ACLS_DIR stamped like tags, so
+// writing an object anew makes it private again unless the write sets one.
+
+pub const ACLS_DIR: &str = ".simple-s3/acls";
+const BUCKET_ACL_FILE: &str = ".simple-s3/acl.json";
+const XMLNS: &str = "http://s3.amazonaws.com/doc/2006-03-01/";
+const XSI: &str = "http://www.w3.org/2001/XMLSchema-instance";
+
+const ALL_USERS: &str = "http://acs.amazonaws.com/groups/global/AllUsers";
+const AUTHENTICATED_USERS: &str = "http://acs.amazonaws.com/groups/global/AuthenticatedUsers";
+const LOG_DELIVERY: &str = "http://acs.amazonaws.com/groups/s3/LogDelivery";
+const GROUPS: [&str; 3] = [ALL_USERS, AUTHENTICATED_USERS, LOG_DELIVERY];
+const PERMISSIONS: [&str; 5] = ["FULL_CONTROL", "READ", "WRITE", "READ_ACP", "WRITE_ACP"];
+// The permission each x-amz-grant-* header gives
+const GRANT_HEADERS: [(&str, &str); 5] = [
+    ("x-amz-grant-full-control", "FULL_CONTROL"),
+    ("x-amz-grant-read", "READ"),
+    ("x-amz-grant-write", "WRITE"),
+    ("x-amz-grant-read-acp", "READ_ACP"),
+    ("x-amz-grant-write-acp", "WRITE_ACP"),
+];
+
+#[derive(Debug, Clone, PartialEq, Serialize, Deserialize)]
+#[serde(rename_all = "lowercase")]
+enum Grantee {
+    // A canonical user ID
+    Id(String),
+    // A group
+    Uri(String),
+}
+
+#[derive(Debug, Clone, PartialEq, Serialize, Deserialize)]
+pub struct Grant {
+    grantee: Grantee,
+    permission: String,
+}
+
+pub type Grants = Vec<Grant>;
+
+#[derive(Serialize, Deserialize)]
+struct Stored {
+    stamp: String,
+    grants: Grants,
+}
+
+#[derive(Debug, Serialize, Deserialize)]
+#[serde(rename = "AccessControlPolicy")]
+struct AccessControlPolicy {
+    #[serde(rename = "@xmlns", default)]
+    xmlns: String,
+    #[serde(rename = "Owner", skip_serializing_if = "Option::is_none")]
+    owner: Option<AclOwner>,
+    #[serde(rename = "AccessControlList")]
+    access_control_list: AccessControlList,
+}
+
+#[derive(Debug, Serialize, Deserialize)]
+struct AclOwner {
+    #[serde(rename = "ID", skip_serializing_if = "Option::is_none")]
+    id: Option<String>,
+    #[serde(rename = "DisplayName", skip_serializing_if = "Option::is_none")]
+    display_name: Option<String>,
+}
+
+#[derive(Debug, Serialize, Deserialize)]
+struct AccessControlList {
+    #[serde(rename = "Grant", default)]
+    grants: Vec<XmlGrant>,
+}
+
+#[derive(Debug, Serialize, Deserialize)]
+struct XmlGrant {
+    #[serde(rename = "Grantee")]
+    grantee: XmlGrantee,
+    #[serde(rename = "Permission")]
+    permission: String,
+}
+
+#[derive(Debug, Serialize, Deserialize)]
+struct XmlGrantee {
+    #[serde(rename = "@xmlns:xsi", default)]
+    xmlns_xsi: String,
+    // CanonicalUser, Group or AmazonCustomerByEmail. Which it is gets told by
+    // the element given when reading
+    #[serde(rename = "@xsi:type", default)]
+    kind: String,
+    #[serde(rename = "ID", skip_serializing_if = "Option::is_none")]
+    id: Option<String>,
+    #[serde(rename = "DisplayName", skip_serializing_if = "Option::is_none")]
+    display_name: Option<String>,
+    #[serde(rename = "URI", skip_serializing_if = "Option::is_none")]
+    uri: Option<String>,
+    #[serde(rename = "EmailAddress", skip_serializing_if = "Option::is_none")]
+    email_address: Option<String>,
+}
+
+fn refusal(status: StatusCode, code: &str, message: &str) -> Response {
+    error_response(
+        status,
+        ErrorResponse {
+            code: code.to_string(),
+            message: message.to_string(),
+            max_size_allowed: None,
+        },
+    )
+}
+
+// Why an ACL asked for can't be set
+#[derive(Debug, Clone, Copy)]
+enum Invalid {
+    Malformed,
+    Group,
+    Email,
+    Canned,
+    CannedAndGrants,
+}
+
+impl Invalid {
+    fn response(self) -> Response {
+        let (code, message) = match self {
+            Self::Malformed => (
+                "MalformedACLError",
+                "The XML you provided was not well-formed or did not validate against our published schema",
+            ),
+            Self::Group => ("InvalidArgument", "Invalid group uri"),
+            Self::Email => (
+                "UnresolvableGrantByEmailAddress",
+                "The e-mail address you provided does not match any account on record.",
+            ),
+            Self::Canned => ("InvalidArgument", "Invalid canned ACL"),
+            Self::CannedAndGrants => (
+                "InvalidRequest",
+                "Specifying both Canned ACLs and Header Grants is not allowed",
+            ),
+        };
+        refusal(StatusCode::BAD_REQUEST, code, message)
+    }
+}
+
+fn owner_grant(state: &AppState) -> Grant {
+    Grant {
+        grantee: Grantee::Id(Owner::of(state).id),
+        permission: "FULL_CONTROL".to_string(),
+    }
+}
+
+// The ACL of whatever has none set, and of the private canned ACL
+fn private(state: &AppState) -> Grants {
+    vec![owner_grant(state)]
+}
+
+// The grants of a canned ACL, None if S3 has no such one
+fn canned(state: &AppState, name: &str) -> Option<Grants> {
+    let group = |uri: &str, permission: &str| Grant {
+        grantee: Grantee::Uri(uri.to_string()),
+        permission: permission.to_string(),
+    };
+    let mut grants = private(state);
+    match name {
+        // The owner of the bucket and of its objects are one
+        "private" | "bucket-owner-read" | "bucket-owner-full-control" | "aws-exec-read" => {}
+        "public-read" => grants.push(group(ALL_USERS, "READ")),
+        "public-read-write" => {
+            grants.push(group(ALL_USERS, "READ"));
+            grants.push(group(ALL_USERS, "WRITE"));
+        }
+        "authenticated-read" => grants.push(group(AUTHENTICATED_USERS, "READ")),
+        "log-delivery-write" => {
+            grants.push(group(LOG_DELIVERY, "WRITE"));
+            grants.push(group(LOG_DELIVERY, "READ_ACP"));
+        }
+        _ => return None,
+    }
+    Some(grants)
+}
+
+// The grants of an x-amz-grant-* header value, `id="..."` and `uri="..."`
+// separated by commas
+fn header_grants(value: &str, permission: &str) -> Result<Grants, Invalid> {
+    value
+        .split(',')
+        .map(str::trim)
+        .filter(|grantee| !grantee.is_empty())
+        .map(|grantee| {
+            let (kind, value) = grantee.split_once('=').ok_or(Invalid::Malformed)?;
+            let value = value.trim().trim_matches('"').to_string();
+            let grantee = match kind.trim().to_ascii_lowercase().as_str() {
+                "id" => Grantee::Id(value),
+                "uri" if GROUPS.contains(&value.as_str()) => Grantee::Uri(value),
+                "uri" => return Err(Invalid::Group),
+                "emailaddress" => return Err(Invalid::Email),
+                _ => return Err(Invalid::Malformed),
+            };
+            Ok(Grant {
+                grantee,
+                permission: permission.to_string(),
+            })
+        })
+        .collect()
+}
+
+// The ACL a request's x-amz-acl or x-amz-grant-* headers set, None without
+// them
+fn requested(state: &AppState, headers: &HeaderMap) -> Option<Result<Grants, Invalid>> {
+    let canned_acl = headers.get("x-amz-acl");
+    let granted = GRANT_HEADERS
+        .iter()
+        .any(|(header, _)| headers.contains_key(*header));
+    if canned_acl.is_some() && granted {
+        return Some(Err(Invalid::CannedAndGrants));
+    }
+    if let Some(canned_acl) = canned_acl {
+        let grants = canned_acl
+            .to_str()
+            .ok()
+            .and_then(|name| canned(state, name.trim()));
+        return Some(grants.ok_or(Invalid::Canned));
+    }
+    if !granted {
+        return None;
+    }
+    let mut grants = private(state);
+    for (header, permission) in GRANT_HEADERS {
+        for value in headers.get_all(header) {
+            let Ok(value) = value.to_str() else {
+                return Some(Err(Invalid::Malformed));
+            };
+            match header_grants(value, permission) {
+                Ok(granted) => grants.extend(granted),
+                Err(invalid) => return Some(Err(invalid)),
+            }
+        }
+    }
+    Some(Ok(grants))
+}
+
+// The refusal for ACL headers that don't make an ACL, None if the request
+// may go ahead
+pub fn refuse_invalid(state: &AppState, headers: &HeaderMap) -> Option<Response> {
+    requested(state, headers)?.err().map(Invalid::response)
+}
+
+// The ACL a write sets with its headers, once refuse_invalid let it through
+pub fn from_headers(state: &AppState, headers: &HeaderMap) -> Option<Grants> {
+    requested(state, headers)?.ok()
+}
+
+fn is_public(grants: &Grants) -> bool {
+    grants.iter().any(|grant| {
+        matches!(&grant.grantee, Grantee::Uri(uri) if access::PUBLIC_GROUPS.contains(&uri.as_str()))
+    })
+}
+
+fn acl_path(state: &AppState, key: &str) -> PathBuf {
+    let key = normalize::stored_key(state, key);
+    state
+        .data_dir
+        .join(ACLS_DIR)
+        .join(hex::encode(Sha256::digest(key.as_bytes())))
+}
+
+async fn write(state: &AppState, path: &Path, json: &[u8]) -> io::Result<()> {
+    if let Some(parent) = path.parent() {
+        fs::create_dir_all(parent).await?;
+    }
+    let tmp = tmp_path(state);
+    let written = write_and_rename(state, &tmp, path, json).await;
+    if written.is_err() {
+        let _ = fs::remove_file(&tmp).await;
+    }
+    written
+}
+
+async fn remove(path: &Path) -> io::Result<()> {
+    match fs::remove_file(path).await {
+        Err(e) if e.kind() != io::ErrorKind::NotFound => Err(e),
+        _ => Ok(()),
+    }
+}
+
+// Keeps `grants` as the ACL of the object now at `file_path`
+async fn store(state: &AppState, key: &str, file_path: &Path, grants: Grants) -> io::Result<()> {
+    let path = acl_path(state, key);
+    if grants == private(state) {
+        return remove(&path).await;
+    }
+    let metadata = fs::metadata(file_path).await?;
+    let stored = Stored {
+        stamp: object_stamp(metadata.modified()?, metadata.len()),
+        grants,
+    };
+    write(state, &path, &serde_json::to_vec(&stored).map_err(io::Error::other)?).await
+}
+
+// Like store, for writes that have already succeeded and can't be refused
+// any more
+pub async fn store_or_warn(state: &AppState, key: &str, file_path: &Path, grants: Grants) {
+    if let Err(e) = store(state, key, file_path, grants).await {
+        warn!("Failed to keep the ACL of {}: {}", key, e);
+    }
+}
+
+// The ACL of the object at `file_path`, if it was set on the object as it is
+// now
+async fn load(state: &AppState, key: &str, file_path: &Path) -> Grants {
+    let stamp = fs::metadata(file_path)
+        .await
+        .ok()
+        .and_then(|metadata| Some(object_stamp(metadata.modified().ok()?, metadata.len())));
+    let stored = fs::read(acl_path(state, key))
+        .await
+        .ok()
+        .and_then(|data| serde_json::from_slice::<Stored>(&data).ok());
+    match (stamp, stored) {
+        (Some(stamp), Some(stored)) if stored.stamp == stamp => stored.grants,
+        _ => private(state),
+    }
+}
+
+async fn store_bucket(state: &AppState, grants: Grants) -> io::Result<()> {
+    let path = state.data_dir.join(BUCKET_ACL_FILE);
+    if grants == private(state) {
+        return remove(&path).await;
+    }
+    write(state, &path, &serde_json::to_vec(&grants).map_err(io::Error::other)?).await
+}
+
+async fn load_bucket(state: &AppState) -> Grants {
+    fs::read(state.data_dir.join(BUCKET_ACL_FILE))
+        .await
+        .ok()
+        .and_then(|data| serde_json::from_slice(&data).ok())
+        .unwrap_or_else(|| private(state))
+}
+
+fn grants_read_to_all(grants: &Grants) -> bool {
+    grants.iter().any(|grant| {
+        grant.grantee == Grantee::Uri(ALL_USERS.to_string())
+            && matches!(grant.permission.as_str(), "READ" | "FULL_CONTROL")
+    })
+}
+
+// Whether ACLs let a request without credentials do what it asks for
+pub async fn allows_anonymous(state: &AppState, targets: Vec<policy::Target>) -> bool {
+    if state.access.ignores_public_acls() || targets.is_empty() {
+        return false;
+    }
+    for target in targets {
+        let granted = match (target.action, &target.key) {
+            ("s3:GetObject", Some(key)) => {
+                let Ok(file_path) = object_path(state, key) else {
+                    return false;
+                };
+                grants_read_to_all(&load(state, key, &file_path).await)
+            }
+            ("s3:ListBucket" | "s3:ListBucketVersions", None) => {
+                grants_read_to_all(&load_bucket(state).await)
+            }
+            _ => false,
+        };
+        if !granted {
+            return false;
+        }
+    }
+    true
+}
+
+// GET /?acl, or /{key}?acl with the object's key and path
+pub async fn get(state: &AppState, object: Option<(&str, &Path)>) -> Result<Response, StatusCode> {
+    let grants = match object {
+        Some((key, file_path)) => {
+            if !fs::try_exists(file_path).await.unwrap_or(false) {
+                return Ok(refusal(
+                    StatusCode::NOT_FOUND,
+                    "NoSuchKey",
+                    "The specified key does not exist",
+                ));
+            }
+            load(state, key, file_path).await
+        }
+        None => load_bucket(state).await,
+    };
+
+    let owner = Owner::of(state);
+    let display_name = |id: &str| (id == owner.id).then(|| owner.display_name.clone());
+    let grants = grants
+        .into_iter()
+        .map(|grant| {
+            let grantee = match grant.grantee {
+                Grantee::Id(id) => XmlGrantee {
+                    xmlns_xsi: XSI.to_string(),
+                    kind: "CanonicalUser".to_string(),
+                    display_name: display_name(&id),
+                    id: Some(id),
+                    uri: None,
+                    email_address: None,
+                },
+                Grantee::Uri(uri) => XmlGrantee {
+                    xmlns_xsi: XSI.to_string(),
+                    kind: "Group".to_string(),
+                    id: None,
+                    display_name: None,
+                    uri: Some(uri),
+                    email_address: None,
+                },
+            };
+            XmlGrant {
+                grantee,
+                permission: grant.permission,
+            }
+        })
+        .collect();
+    let policy = AccessControlPolicy {
+        xmlns: XMLNS.to_string(),
+        owner: Some(AclOwner {
+            id: Some(owner.id.clone()),
+            display_name: Some(owner.display_name.clone()),
+        }),
+        access_control_list: AccessControlList { grants },
+    };
+    let xml = serde_xml_rs::to_string(&policy).map_err(|_| StatusCode::INTERNAL_SERVER_ERROR)?;
+    let mut headers = HeaderMap::new();
+    headers.insert("content-type", HeaderValue::from_static("application/xml"));
+    Ok((headers, xml).into_response())
+}
+
+// The grants of an AccessControlPolicy
+fn body_grants(state: &AppState, body: &Bytes) -> Result<Grants, Invalid> {
+    let policy = serde_xml_rs::from_reader::<AccessControlPolicy, _>(body.as_ref())
+        .map_err(|_| Invalid::Malformed)?;
+    let mut grants = Grants::new();
+    for grant in policy.access_control_list.grants {
+        if !PERMISSIONS.contains(&grant.permission.as_str()) {
+            return Err(Invalid::Malformed);
+        }
+        let XmlGrantee {
+            id,
+            uri,
+            email_address,
+            ..
+        } = grant.grantee;
+        let grantee = match (id, uri, email_address) {
+            (Some(id), None, None) => Grantee::Id(id),
+            (None, Some(uri), None) if GROUPS.contains(&uri.as_str()) => Grantee::Uri(uri),
+            (None, Some(_), None) => return Err(Invalid::Group),
+            (None, None, Some(_)) => return Err(Invalid::Email),
+            _ => return Err(Invalid::Malformed),
+        };
+        grants.push(Grant {
+            grantee,
+            permission: grant.permission,
+        });
+    }
+    // The owner keeps FULL_CONTROL, as nothing can take it away here
+    let owner = owner_grant(state);
+    if !grants.contains(&owner) {
+        grants.insert(0, owner);
+    }
+    Ok(grants)
+}
+
+// PUT /?acl, or /{key}?acl with the object's key and path, replacing the
+// ACL with the one the headers or the body give
+pub async fn put(
+    state: &AppState,
+    object: Option<(&str, &Path)>,
+    headers: &HeaderMap,
+    body: &Bytes,
+) -> Result<Response, StatusCode> {
+    let grants = match requested(state, headers) {
+        Some(grants) => grants,
+        None => body_grants(state, body),
+    };
+    let grants = match grants {
+        Ok(grants) => grants,
+        Err(invalid) => return Ok(invalid.response()),
+    };
+    let name = object.map_or(state.bucket_name.as_str(), |(key, _)| key);
+    let sets_acl = grants != private(state);
+    if let Some(refusal) = access::refuse_grants(state, name, sets_acl, is_public(&grants)) {
+        return Ok(refusal);
+    }
+
+    let stored = match object {
+        Some((key, file_path)) => {
+            if !fs::try_exists(file_path).await.unwrap_or(false) {
+                return Ok(refusal(
+                    StatusCode::NOT_FOUND,
+                    "NoSuchKey",
+                    "The specified key does not exist",
+                ));
+            }
+            store(state, key, file_path, grants).await
+        }
+        None => store_bucket(state, grants).await,
+    };
+    if let Err(e) = stored {
+        warn!("Failed to store the ACL of {}: {}", name, e);
+        return Err(StatusCode::INTERNAL_SERVER_ERROR);
+    }
+    info!("🪪 Set the ACL of {}", name);
+    Ok(StatusCode::OK.into_response())
+}
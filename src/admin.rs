@@ -0,0 +1,275 @@
+use axum::{
+    Json, Router,
+    extract::{Request, State},
+    middleware::{self, Next},
+    response::{Html, Response},
+    routing::{get, post},
+};
+use serde::Serialize;
+use std::{path::Path, sync::Arc};
+use tokio::fs;
+
+use crate::{
+    AppState, metrics::Counts, mirror, quota, snapshot, stats::Totals, timing, trash, tus::TUS_DIR,
+    usage, webdav,
+};
+
+// Admin listener on --admin-port, for people rather than S3 clients. It
+// takes Basic auth with the S3 credentials, like WebDAV, so a browser can
+// open it. `/` is a status page that refreshes itself, /admin/status the
+// same figures as JSON, /admin/quotas each access key's quota usage,
+// /admin/usage/export the usage ledger for billing, /admin/trash the
+// objects deleted with --trash-days, /admin/snapshots the scheduled
+// snapshots, and /admin/requests and /admin/profile what requests are
+// spending their time on.
+//
+// grpc.rs serves the status and quotas over gRPC as well, with the grpc
+// feature. The users and buckets it would manage wait on there being more
+// than one (see buckets.rs).
+
+const REFRESH_SECS: u32 = 10;
+
+#[derive(Debug, Serialize)]
+pub struct Disk {
+    pub total_bytes: u64,
+    pub free_bytes: u64,
+}
+
+#[derive(Debug, Serialize)]
+pub struct Status {
+    pub bucket: String,
+    #[serde(flatten)]
+    pub counts: Counts,
+    pub objects: Totals,
+    // Filesystem the data dir is on, if the platform reports it
+    pub disk: Option<Disk>,
+    // Writes are refused as free space is under --min-free-disk
+    pub disk_low: bool,
+    // Requests copied to --mirror-to
+    #[serde(skip_serializing_if = "Option::is_none")]
+    pub mirror: Option<mirror::Counts>,
+    // tus uploads created and not yet finished or terminated
+    pub tus_uploads: u64,
+    pub snapshots: Vec<snapshot::ScheduleStatus>,
+}
+
+pub fn router(state: Arc<AppState>) -> Router {
+    Router::new()
+        .route("/", get(dashboard))
+        .route("/admin/status", get(status_json))
+        .route("/admin/quotas", get(quota::quota_usage))
+        .route("/admin/usage/export", get(usage::export))
+        .route("/admin/trash", get(trash::list_trash))
+        .route("/admin/snapshots", get(snapshot::snapshot_status))
+        .route("/admin/requests", get(timing::requests_in_flight))
+        .route("/admin/profile", get(timing::profile))
+        .route("/admin/trash/restore", post(trash::restore_trash))
+        .layer(middleware::from_fn_with_state(
+            state.clone(),
+            admin_auth_middleware,
+        ))
+        .with_state(state)
+}
+
+async fn admin_auth_middleware(
+    State(state): State<Arc<AppState>>,
+    request: Request,
+    next: Next,
+) -> Response {
+    match webdav::basic_auth(&state, &request, "admin") {
+        None => next.run(request).await,
+        Some(refusal) => refusal,
+    }
+}
+
+#[cfg(unix)]
+pub fn disk(path: &Path) -> Option<Disk> {
+    use std::os::unix::ffi::OsStrExt;
+
+    let path = std::ffi::CString::new(path.as_os_str().as_bytes()).ok()?;
+    let mut stat: libc::statvfs = unsafe { std::mem::zeroed() };
+    if unsafe { libc::statvfs(path.as_ptr(), &mut stat) } != 0 {
+        return None;
+    }
+    let block = stat.f_frsize as u64;
+    Some(Disk {
+        total_bytes: stat.f_blocks as u64 * block,
+        free_bytes: stat.f_bavail as u64 * block,
+    })
+}
+
+#[cfg(windows)]
+pub fn disk(path: &Path) -> Option<Disk> {
+    use std::os::windows::ffi::OsStrExt;
+    use windows_sys::Win32::Storage::FileSystem::GetDiskFreeSpaceExW;
+
+    let path: Vec<u16> = path.as_os_str().encode_wide().chain(std::iter::once(0)).collect();
+    let (mut free, mut total) = (0u64, 0u64);
+    let ok = unsafe {
+        GetDiskFreeSpaceExW(path.as_ptr(), &mut free, &mut total, std::ptr::null_mut())
+    };
+    (ok != 0).then_some(Disk {
+        total_bytes: total,
+        free_bytes: free,
+    })
+}
+
+#[cfg(not(any(unix, windows)))]
+pub fn disk(_path: &Path) -> Option<Disk> {
+    None
+}
+
+async fn tus_uploads(state: &AppState) -> u64 {
+    let Ok(mut entries) = fs::read_dir(state.data_dir.join(TUS_DIR)).await else {
+        return 0;
+    };
+    let mut count = 0;
+    while let Ok(Some(entry)) = entries.next_entry().await {
+        if entry.path().extension().is_some_and(|ext| ext == "json") {
+            count += 1;
+        }
+    }
+    count
+}
+
+pub async fn status(state: &AppState) -> Status {
+    let data_root = state.data_root.clone();
+    Status {
+        bucket: state.bucket_name.clone(),
+        counts: state.metrics.counts(),
+        objects: state.stats.totals(),
+        disk: tokio::task::spawn_blocking(move || disk(&data_root))
+            .await
+            .ok()
+            .flatten(),
+        disk_low: state.shedder.disk_low(),
+        mirror: state.mirror.as_ref().map(|mirror| mirror.counts()),
+        tus_uploads: tus_uploads(state).await,
+        snapshots: state.snapshots.statuses(state).await,
+    }
+}
+
+// GET /admin/status
+async fn status_json(State(state): State<Arc<AppState>>) -> Json<Status> {
+    Json(status(&state).await)
+}
+
+fn escape(s: &str) -> String {
+    s.replace('&', "&amp;")
+        .replace('<', "&lt;")
+        .replace('>', "&gt;")
+        .replace('"', "&quot;")
+}
+
+fn bytes(n: u64) -> String {
+    const UNITS: [&str; 6] = ["B", "KiB", "MiB", "GiB", "TiB", "PiB"];
+    let mut value = n as f64;
+    let mut unit = 0;
+    while value >= 1024.0 && unit < UNITS.len() - 1 {
+        value /= 1024.0;
+        unit += 1;
+    }
+    match unit {
+        0 => format!("{} B", n),
+        _ => format!("{:.1} {}", value, UNITS[unit]),
+    }
+}
+
+fn duration(secs: u64) -> String {
+    let (days, hours, minutes) = (secs / 86400, secs / 3600 % 24, secs / 60 % 60);
+    match (days, hours) {
+        (0, 0) => format!("{}m {}s", minutes, secs % 60),
+        (0, _) => format!("{}h {}m", hours, minutes),
+        _ => format!("{}d {}h", days, hours),
+    }
+}
+
+// GET /
+async fn dashboard(State(state): State<Arc<AppState>>) -> Html<String> {
+    let status = status(&state).await;
+    let counts = &status.counts;
+    let mut disk = match &status.disk {
+        Some(disk) => format!(
+            "{} free of {} ({:.0}% used)",
+            bytes(disk.free_bytes),
+            bytes(disk.total_bytes),
+            100.0 - disk.free_bytes as f64 * 100.0 / disk.total_bytes.max(1) as f64
+        ),
+        None => "unknown".to_string(),
+    };
+    if status.disk_low {
+        disk.push_str(", read-only until space is freed");
+    }
+    let mut rows = vec![
+        ("Uptime".to_string(), duration(counts.uptime_secs)),
+        (
+            "Requests".to_string(),
+            format!("{} ({:.2}/s)", counts.requests, counts.rates.requests),
+        ),
+        (
+            "Client errors (4xx)".to_string(),
+            format!("{} ({:.2}/s)", counts.client_errors, counts.rates.client_errors),
+        ),
+        (
+            "Server errors (5xx)".to_string(),
+            format!("{} ({:.2}/s)", counts.server_errors, counts.rates.server_errors),
+        ),
+        ("Objects".to_string(), status.objects.objects.to_string()),
+        ("Stored".to_string(), bytes(status.objects.bytes)),
+        ("Disk".to_string(), disk),
+        ("Uploads in progress".to_string(), counts.writes_in_flight.to_string()),
+        ("Shed (503 SlowDown)".to_string(), counts.shed.to_string()),
+        ("tus uploads open".to_string(), status.tus_uploads.to_string()),
+    ];
+    if let Some(mirror) = &status.mirror {
+        rows.push((
+            "Mirrored".to_string(),
+            format!(
+                "{} ({} failed, {} answered differently, {} skipped)",
+                mirror.mirrored, mirror.failed, mirror.mismatched, mirror.skipped
+            ),
+        ));
+    }
+    for snapshots in &status.snapshots {
+        let mut value = match &snapshots.last_success {
+            Some(time) => format!("last succeeded {}", time),
+            None => "never succeeded".to_string(),
+        };
+        if let (Some(time), Some(error)) = (&snapshots.last_failure, &snapshots.last_error) {
+            value.push_str(&format!(", last failed {}: {}", time, error));
+        }
+        rows.push((format!("Snapshots {}", snapshots.schedule), value));
+    }
+
+    let rows: String = rows
+        .iter()
+        .map(|(name, value)| format!("<tr><th>{}</th><td>{}</td></tr>\n", name, escape(value)))
+        .collect();
+    Html(format!(
+        r#"<!doctype html>
+<html>
+<head>
+<meta charset="utf-8">
+<meta http-equiv="refresh" content="{refresh}">
+<title>simpleS3 · {bucket}</title>
+<style>
+body {{ font-family: system-ui, sans-serif; margin: 2em; color: #222; }}
+table {{ border-collapse: collapse; }}
+th, td {{ padding: 0.4em 1.2em 0.4em 0; text-align: left; border-bottom: 1px solid #ddd; }}
+th {{ font-weight: normal; color: #666; }}
+small {{ color: #888; }}
+</style>
+</head>
+<body>
+<h1>{bucket}</h1>
+<table>
+{rows}</table>
+<p><small>Rates are per second over the last minute. Refreshes every {refresh}s. <a href="/admin/status">JSON</a></small></p>
+</body>
+</html>
+"#,
+        refresh = REFRESH_SECS,
+        bucket = escape(&status.bucket),
+        rows = rows,
+    ))
+}
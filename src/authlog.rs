@@ -0,0 +1,95 @@
+use std::{
+    fs::OpenOptions,
+    io::Write,
+    net::IpAddr,
+    path::{Path, PathBuf},
+};
+use tracing::warn;
+
+use crate::AppState;
+
+// Failed logins written to the file given to --auth-log, one per line, for
+// fail2ban and similar tools:
+//
+//   2026-01-31T12:00:00Z simple-s3 auth failure: ip=203.0.113.7 service=s3 reason=invalid-credentials key=AKIA1
+//
+// The format is stable: fields are in this order, ip and key are "-" when
+// unknown, and the key, the only client-chosen field, comes last with
+// anything but printable ASCII replaced by '?'. The file is opened for each
+// line, so logrotate can move it away without a restart.
+
+// Longest access key written, so the log can't be flooded with huge keys
+const MAX_KEY_LEN: usize = 128;
+
+#[derive(Debug, Clone, Copy)]
+pub enum Reason {
+    // Credentials were given and are wrong
+    InvalidCredentials,
+    // No credentials, or none in a form the server accepts
+    MissingCredentials,
+    // Refused without checking, after too many failures
+    Throttled,
+}
+
+impl Reason {
+    fn as_str(self) -> &'static str {
+        match self {
+            Reason::InvalidCredentials => "invalid-credentials",
+            Reason::MissingCredentials => "missing-credentials",
+            Reason::Throttled => "throttled",
+        }
+    }
+}
+
+pub struct AuthLog {
+    path: PathBuf,
+}
+
+fn sanitize(key: &str) -> String {
+    key.chars()
+        .take(MAX_KEY_LEN)
+        .map(|c| if c.is_ascii_graphic() { c } else { '?' })
+        .collect()
+}
+
+impl AuthLog {
+    pub fn open(path: &Path) -> Result<Self, std::io::Error> {
+        // Fail at startup rather than on the first failed login
+        OpenOptions::new().create(true).append(true).open(path)?;
+        Ok(Self {
+            path: path.to_path_buf(),
+        })
+    }
+
+    fn write(&self, line: &str) -> Result<(), std::io::Error> {
+        let mut file = OpenOptions::new()
+            .create(true)
+            .append(true)
+            .open(&self.path)?;
+        file.write_all(line.as_bytes())
+    }
+}
+
+// Records a failed login, if --auth-log is set
+pub fn failure(
+    state: &AppState,
+    service: &str,
+    ip: Option<IpAddr>,
+    key: Option<&str>,
+    reason: Reason,
+) {
+    let Some(log) = &state.auth_log else {
+        return;
+    };
+    let line = format!(
+        "{} simple-s3 auth failure: ip={} service={} reason={} key={}\n",
+        chrono::Utc::now().format("%Y-%m-%dT%H:%M:%SZ"),
+        ip.map_or("-".to_string(), |ip| ip.to_canonical().to_string()),
+        service,
+        reason.as_str(),
+        key.filter(|key| !key.is_empty()).map_or("-".to_string(), sanitize),
+    );
+    if let Err(e) = log.write(&line) {
+        warn!("Failed to write auth log {}: {}", log.path.display(), e);
+    }
+}
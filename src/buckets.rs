@@ -0,0 +1,100 @@
+use axum::{
+    http::{HeaderMap, HeaderValue, StatusCode},
+    response::{IntoResponse, Response},
+};
+use serde::Serialize;
+use std::time::SystemTime;
+
+use crate::{AppState, ErrorResponse, Owner, error_response};
+
+// Buckets. The server serves one bucket, --bucket, whose objects are the
+// data dir. With --path-style, ListBuckets (GET /, where no bucket is named)
+// lists it, so `aws s3 ls` and SDKs that look for the bucket find it.
+// CreateBucket and DeleteBucket (PUT and DELETE / with no subresource) are
+// refused with 501 NotImplemented: there are no other buckets to make, and
+// this one is the data dir.
+//
+// Serving more than one bucket isn't supported yet. Each bucket would need a
+// directory of its own, and everything kept in the data dir next to the
+// objects, from versions, ACLs, policies, CORS and lifecycle rules to
+// tiering stubs, erasure shards, statistics and the WebDAV and SFTP roots,
+// is kept for the whole data dir and would have to be split by bucket first.
+// Per key quotas, provisioning, the MinIO admin API and managing users and
+// buckets over gRPC wait on the same.
+
+const XMLNS: &str = "http://s3.amazonaws.com/doc/2006-03-01/";
+
+#[derive(Debug, Serialize)]
+#[serde(rename = "ListAllMyBucketsResult")]
+struct ListAllMyBucketsResult {
+    #[serde(rename = "@xmlns")]
+    xmlns: String,
+    #[serde(rename = "Owner")]
+    owner: Owner,
+    #[serde(rename = "Buckets")]
+    buckets: Buckets,
+}
+
+#[derive(Debug, Serialize)]
+struct Buckets {
+    #[serde(rename = "Bucket")]
+    buckets: Vec<Bucket>,
+}
+
+#[derive(Debug, Serialize)]
+struct Bucket {
+    #[serde(rename = "Name")]
+    name: String,
+    #[serde(rename = "CreationDate")]
+    creation_date: String,
+}
+
+// Whether a request to / names no subresource, leaving out the credentials
+// and presigned URL parameters that authenticated it
+pub fn is_plain(query: Option<&str>) -> bool {
+    query.unwrap_or_default().split('&').all(|param| {
+        let name = param.split_once('=').map_or(param, |(name, _)| name);
+        name.is_empty() || name.to_ascii_lowercase().starts_with("x-amz-")
+    })
+}
+
+// GET / with --path-style, ListBuckets
+pub async fn list(state: &AppState) -> Result<Response, StatusCode> {
+    // The data dir stands in for the bucket, made when it was
+    let created = tokio::fs::metadata(&state.data_dir)
+        .await
+        .and_then(|metadata| metadata.created().or_else(|_| metadata.modified()))
+        .unwrap_or_else(|_| SystemTime::now());
+    let created: chrono::DateTime<chrono::Utc> = created.into();
+
+    let result = ListAllMyBucketsResult {
+        xmlns: XMLNS.to_string(),
+        owner: Owner::of(state),
+        buckets: Buckets {
+            buckets: vec![Bucket {
+                name: state.bucket_name.clone(),
+                creation_date: created.format("%Y-%m-%dT%H:%M:%S%.3fZ").to_string(),
+            }],
+        },
+    };
+    let xml = serde_xml_rs::to_string(&result).map_err(|_| StatusCode::INTERNAL_SERVER_ERROR)?;
+
+    let mut headers = HeaderMap::new();
+    headers.insert("content-type", HeaderValue::from_static("application/xml"));
+    Ok((headers, xml).into_response())
+}
+
+// PUT or DELETE / with no subresource, CreateBucket or DeleteBucket
+pub fn refuse_change(state: &AppState) -> Response {
+    error_response(
+        StatusCode::NOT_IMPLEMENTED,
+        ErrorResponse {
+            code: "NotImplemented".to_string(),
+            message: format!(
+                "simpleS3 serves one bucket, {}, which can't be created or deleted",
+                state.bucket_name
+            ),
+            max_size_allowed: None,
+        },
+    )
+}
@@ -0,0 +1,213 @@
+use axum::{
+    body::{Body, Bytes},
+    extract::{Request, State},
+    http::{HeaderValue, Method, StatusCode},
+    middleware::Next,
+    response::Response,
+};
+use futures_util::StreamExt;
+use std::{collections::HashMap, sync::Arc, time::Duration};
+use tracing::warn;
+
+use crate::{ErrorResponse, error_response};
+
+// Fault injection for testing clients' retry logic. Only enabled through the
+// SIMPLE_S3_CHAOS environment variable, a comma separated list of
+// `[op.]fault=probability` settings plus two tunables:
+//
+//   SIMPLE_S3_CHAOS="error=0.05,get.truncate=0.1,put.latency=0.5,latency_ms=2000"
+//
+// Faults are `latency` (delay before handling), `error` (500 InternalError),
+// `truncate` (response body cut short of its Content-Length) and `slow`
+// (response body trickled out at slow_bytes_per_sec). Ops are list, get,
+// head, put, post and delete; a setting without an op applies to all of them
+// unless the op sets its own.
+
+pub const CHAOS_ENV: &str = "SIMPLE_S3_CHAOS";
+const OPS: [&str; 6] = ["list", "get", "head", "put", "post", "delete"];
+const SLOW_TICK: Duration = Duration::from_millis(100);
+
+#[derive(Debug, Default, Clone, Copy)]
+struct Faults {
+    latency: f64,
+    error: f64,
+    truncate: f64,
+    slow: f64,
+}
+
+impl Faults {
+    fn set(&mut self, fault: &str, probability: f64) -> Result<(), String> {
+        match fault {
+            "latency" => self.latency = probability,
+            "error" => self.error = probability,
+            "truncate" => self.truncate = probability,
+            "slow" => self.slow = probability,
+            _ => return Err(format!("unknown fault {:?}", fault)),
+        }
+        Ok(())
+    }
+}
+
+pub struct Chaos {
+    all: Faults,
+    ops: HashMap<&'static str, Faults>,
+    latency: Duration,
+    slow_bytes_per_sec: u64,
+}
+
+impl Chaos {
+    pub fn parse(config: &str) -> Result<Self, String> {
+        let mut chaos = Chaos {
+            all: Faults::default(),
+            ops: HashMap::new(),
+            latency: Duration::from_millis(1000),
+            slow_bytes_per_sec: 16 * 1024,
+        };
+        let mut overrides = Vec::new();
+
+        for setting in config.split(',').map(str::trim).filter(|s| !s.is_empty()) {
+            let (name, value) = setting
+                .split_once('=')
+                .ok_or_else(|| format!("expected name=value, got {:?}", setting))?;
+            let number = || {
+                value
+                    .parse::<f64>()
+                    .ok()
+                    .filter(|n| *n >= 0.0)
+                    .ok_or_else(|| format!("bad value for {}: {:?}", name, value))
+            };
+
+            match name {
+                "latency_ms" => chaos.latency = Duration::from_millis(number()? as u64),
+                "slow_bytes_per_sec" => chaos.slow_bytes_per_sec = (number()? as u64).max(1),
+                _ => match name.split_once('.') {
+                    Some((op, fault)) => {
+                        let op = OPS
+                            .into_iter()
+                            .find(|known| *known == op)
+                            .ok_or_else(|| format!("unknown operation {:?}", op))?;
+                        overrides.push((op, fault, number()?.min(1.0)));
+                    }
+                    None => chaos.all.set(name, number()?.min(1.0))?,
+                },
+            }
+        }
+
+        for (op, fault, probability) in overrides {
+            let all = chaos.all;
+            chaos.ops.entry(op).or_insert(all).set(fault, probability)?;
+        }
+
+        Ok(chaos)
+    }
+
+    fn faults(&self, op: &str) -> Faults {
+        self.ops.get(op).copied().unwrap_or(self.all)
+    }
+}
+
+fn operation(method: &Method, path: &str) -> &'static str {
+    match *method {
+        Method::GET if path == "/" => "list",
+        Method::GET => "get",
+        Method::HEAD => "head",
+        Method::PUT => "put",
+        Method::POST => "post",
+        Method::DELETE => "delete",
+        _ => "other",
+    }
+}
+
+// True with the given probability. v4 UUIDs are 122 random bits, which saves
+// pulling in an RNG for a test-only feature
+fn roll(probability: f64) -> bool {
+    if probability <= 0.0 {
+        return false;
+    }
+    let sample = (uuid::Uuid::new_v4().as_u128() >> 75) as f64 / (1u64 << 53) as f64;
+    sample < probability
+}
+
+pub async fn chaos_middleware(
+    State(chaos): State<Arc<Chaos>>,
+    request: Request,
+    next: Next,
+) -> Response {
+    let op = operation(request.method(), request.uri().path());
+    let faults = chaos.faults(op);
+
+    if roll(faults.latency) {
+        warn!("🐒 Delaying {} by {:?}", op, chaos.latency);
+        tokio::time::sleep(chaos.latency).await;
+    }
+
+    if roll(faults.error) {
+        warn!("🐒 Failing {} with 500", op);
+        return error_response(
+            StatusCode::INTERNAL_SERVER_ERROR,
+            ErrorResponse {
+                code: "InternalError".to_string(),
+                message: "We encountered an internal error. Please try again.".to_string(),
+                max_size_allowed: None,
+            },
+        );
+    }
+
+    let response = next.run(request).await;
+
+    if roll(faults.truncate) {
+        warn!("🐒 Truncating {} response", op);
+        return truncate(response).await;
+    }
+    if roll(faults.slow) {
+        warn!("🐒 Slowing {} response to {} bytes/s", op, chaos.slow_bytes_per_sec);
+        return slow(response, chaos.slow_bytes_per_sec);
+    }
+
+    response
+}
+
+// Send half the body while still announcing the full length, so the client
+// sees the connection drop mid-response
+async fn truncate(response: Response) -> Response {
+    let (mut parts, body) = response.into_parts();
+    let Ok(bytes) = axum::body::to_bytes(body, usize::MAX).await else {
+        return Response::from_parts(parts, Body::empty());
+    };
+
+    parts
+        .headers
+        .insert("content-length", HeaderValue::from(bytes.len()));
+    // Pause before failing the body so hyper flushes what was sent first
+    let half = bytes.slice(..bytes.len() / 2);
+    let stream = futures_util::stream::once(async move { Ok(half) }).chain(futures_util::stream::once(
+        async {
+            tokio::time::sleep(SLOW_TICK).await;
+            Err(axum::Error::new(std::io::Error::other("injected truncation")))
+        },
+    ));
+    Response::from_parts(parts, Body::from_stream(stream))
+}
+
+fn slow(response: Response, bytes_per_sec: u64) -> Response {
+    let (parts, body) = response.into_parts();
+    let chunk_size = (bytes_per_sec / (1000 / SLOW_TICK.as_millis() as u64)).max(1) as usize;
+
+    let stream = futures_util::stream::unfold(
+        (body.into_data_stream(), Bytes::new()),
+        move |(mut body, mut pending)| async move {
+            while pending.is_empty() {
+                match body.next().await? {
+                    Ok(chunk) => pending = chunk,
+                    Err(e) => return Some((Err(e), (body, Bytes::new()))),
+                }
+            }
+
+            tokio::time::sleep(SLOW_TICK).await;
+            let chunk = pending.split_to(chunk_size.min(pending.len()));
+            Some((Ok(chunk), (body, pending)))
+        },
+    );
+
+    Response::from_parts(parts, Body::from_stream(stream))
+}
@@ -0,0 +1,344 @@
+use axum::{
+    body::Bytes,
+    http::{HeaderMap, HeaderValue, StatusCode},
+    response::{IntoResponse, Response},
+};
+use base64::Engine;
+use serde::{Deserialize, Serialize};
+use sha2::{Digest, Sha256};
+use std::{
+    io,
+    path::{Path, PathBuf},
+    time::SystemTime,
+};
+use tokio::fs;
+use tracing::warn;
+
+use crate::{
+    AppState, ErrorResponse, INLINE_HASH_LIMIT, error_response, etag, http_date, object_stamp,
+    tmp_path, write_and_rename,
+};
+
+// Additional checksums, S3's x-amz-checksum-* headers. A PUT may carry one
+// of x-amz-checksum-crc32, -crc32c, -sha1 or -sha256 with the base64 digest
+// of its body, or only x-amz-sdk-checksum-algorithm to have the server work
+// it out. The body is checked against it, refused with 400 BadDigest if it
+// doesn't match, and the checksum is echoed back on the PUT. Recent AWS SDKs
+// send a CRC32 on every upload and fail when it isn't echoed.
+//
+// The checksum is kept under CHECKSUMS_DIR, one file per key stamped with
+// the object's modification time and size, as image variants are, so it's
+// only given out while the object is the one it was taken of. GET and HEAD
+// return it when asked with x-amz-checksum-mode: ENABLED, and
+// GetObjectAttributes always does.
+
+pub const CHECKSUMS_DIR: &str = ".simple-s3/checksums";
+
+#[derive(Debug, Clone, Copy, PartialEq, Serialize, Deserialize)]
+pub enum Algorithm {
+    #[serde(rename = "CRC32")]
+    Crc32,
+    #[serde(rename = "CRC32C")]
+    Crc32c,
+    #[serde(rename = "SHA1")]
+    Sha1,
+    #[serde(rename = "SHA256")]
+    Sha256,
+}
+
+const ALGORITHMS: [Algorithm; 4] = [
+    Algorithm::Crc32,
+    Algorithm::Crc32c,
+    Algorithm::Sha1,
+    Algorithm::Sha256,
+];
+
+impl Algorithm {
+    fn name(self) -> &'static str {
+        match self {
+            Algorithm::Crc32 => "CRC32",
+            Algorithm::Crc32c => "CRC32C",
+            Algorithm::Sha1 => "SHA1",
+            Algorithm::Sha256 => "SHA256",
+        }
+    }
+
+    pub fn header(self) -> &'static str {
+        match self {
+            Algorithm::Crc32 => "x-amz-checksum-crc32",
+            Algorithm::Crc32c => "x-amz-checksum-crc32c",
+            Algorithm::Sha1 => "x-amz-checksum-sha1",
+            Algorithm::Sha256 => "x-amz-checksum-sha256",
+        }
+    }
+
+    fn digest_len(self) -> usize {
+        match self {
+            Algorithm::Crc32 | Algorithm::Crc32c => 4,
+            Algorithm::Sha1 => 20,
+            Algorithm::Sha256 => 32,
+        }
+    }
+
+    fn digest(self, data: &[u8]) -> Vec<u8> {
+        match self {
+            Algorithm::Crc32 => crc32fast::hash(data).to_be_bytes().to_vec(),
+            Algorithm::Crc32c => crc32c::crc32c(data).to_be_bytes().to_vec(),
+            Algorithm::Sha1 => <sha1::Sha1 as sha1::Digest>::digest(data).to_vec(),
+            Algorithm::Sha256 => Sha256::digest(data).to_vec(),
+        }
+    }
+}
+
+#[derive(Debug, Clone, Serialize, Deserialize)]
+pub struct Checksum {
+    pub algorithm: Algorithm,
+    // Base64, as the headers carry it
+    pub value: String,
+}
+
+impl Checksum {
+    pub fn insert_into(&self, headers: &mut HeaderMap) {
+        if let Ok(value) = HeaderValue::from_str(&self.value) {
+            headers.insert(self.algorithm.header(), value);
+        }
+    }
+}
+
+#[derive(Serialize, Deserialize)]
+struct Stored {
+    stamp: String,
+    #[serde(flatten)]
+    checksum: Checksum,
+}
+
+fn invalid_request(message: String) -> Response {
+    error_response(
+        StatusCode::BAD_REQUEST,
+        ErrorResponse {
+            code: "InvalidRequest".to_string(),
+            message,
+            max_size_allowed: None,
+        },
+    )
+}
+
+const SINGLE_CHECKSUM: &str =
+    "Expecting a single x-amz-checksum- header. Multiple checksum Types are not allowed.";
+
+// The algorithm a request asks for and the checksum it gives, if any
+fn requested(headers: &HeaderMap) -> Result<Option<(Algorithm, Option<String>)>, String> {
+    let given: Vec<_> = ALGORITHMS
+        .into_iter()
+        .filter_map(|algorithm| {
+            let value = headers.get(algorithm.header())?;
+            Some((algorithm, value.to_str().unwrap_or_default().trim().to_string()))
+        })
+        .collect();
+    let sdk = headers
+        .get("x-amz-sdk-checksum-algorithm")
+        .map(|name| name.to_str().unwrap_or_default().trim());
+
+    let (algorithm, value) = match (given.as_slice(), sdk) {
+        ([], None) => return Ok(None),
+        ([], Some(name)) => {
+            let algorithm = ALGORITHMS
+                .into_iter()
+                .find(|algorithm| algorithm.name().eq_ignore_ascii_case(name))
+                .ok_or_else(|| format!("Checksum algorithm {} is not supported.", name))?;
+            return Ok(Some((algorithm, None)));
+        }
+        ([(algorithm, value)], _) => (*algorithm, value.clone()),
+        _ => return Err(SINGLE_CHECKSUM.to_string()),
+    };
+    let decoded = base64::engine::general_purpose::STANDARD.decode(&value);
+    if !decoded.is_ok_and(|digest| digest.len() == algorithm.digest_len()) {
+        return Err(format!("Value for {} header is invalid.", algorithm.header()));
+    }
+    Ok(Some((algorithm, Some(value))))
+}
+
+// Checks `body` against the checksum the request gives, or works out the one
+// it asks for. The refusal if the request's checksum is malformed or doesn't
+// match, None if it has none
+pub async fn verify(headers: &HeaderMap, body: &Bytes) -> Result<Option<Checksum>, Response> {
+    let (algorithm, expected) = match requested(headers) {
+        Ok(Some(requested)) => requested,
+        Ok(None) => return Ok(None),
+        Err(message) => return Err(invalid_request(message)),
+    };
+    let value = if body.len() <= INLINE_HASH_LIMIT {
+        base64::engine::general_purpose::STANDARD.encode(algorithm.digest(body))
+    } else {
+        let body = body.clone();
+        tokio::task::spawn_blocking(move || {
+            base64::engine::general_purpose::STANDARD.encode(algorithm.digest(&body))
+        })
+        .await
+        .map_err(|_| StatusCode::INTERNAL_SERVER_ERROR.into_response())?
+    };
+    if expected.is_some_and(|expected| expected != value) {
+        return Err(error_response(
+            StatusCode::BAD_REQUEST,
+            ErrorResponse {
+                code: "BadDigest".to_string(),
+                message: format!(
+                    "The {} you specified did not match the calculated checksum.",
+                    algorithm.name()
+                ),
+                max_size_allowed: None,
+            },
+        ));
+    }
+    Ok(Some(Checksum { algorithm, value }))
+}
+
+fn checksum_path(state: &AppState, key: &str) -> PathBuf {
+    state
+        .data_dir
+        .join(CHECKSUMS_DIR)
+        .join(hex::encode(Sha256::digest(key.as_bytes())))
+}
+
+// Keeps the checksum of the object just stored at `file_path`
+pub async fn store(state: &AppState, key: &str, file_path: &Path, checksum: Checksum) {
+    let tmp = tmp_path(state);
+    let stored = async {
+        let metadata = fs::metadata(file_path).await?;
+        let stored = Stored {
+            stamp: object_stamp(metadata.modified()?, metadata.len()),
+            checksum,
+        };
+        let json = serde_json::to_vec(&stored).map_err(io::Error::other)?;
+        fs::create_dir_all(state.data_dir.join(CHECKSUMS_DIR)).await?;
+        write_and_rename(state, &tmp, &checksum_path(state, key), &json).await
+    }
+    .await;
+    if let Err(e) = stored {
+        let _ = fs::remove_file(&tmp).await;
+        warn!("Failed to keep the checksum of {}: {}", key, e);
+    }
+}
+
+// The checksum kept for `key`, if it was taken of the object as it is now
+pub async fn load(state: &AppState, key: &str, modified: SystemTime, len: u64) -> Option<Checksum> {
+    let data = fs::read(checksum_path(state, key)).await.ok()?;
+    let stored: Stored = serde_json::from_slice(&data).ok()?;
+    (stored.stamp == object_stamp(modified, len)).then_some(stored.checksum)
+}
+
+#[derive(Debug, Serialize)]
+#[serde(rename = "GetObjectAttributesResponse")]
+struct ObjectAttributes {
+    #[serde(rename = "@xmlns")]
+    xmlns: String,
+    #[serde(rename = "ETag", skip_serializing_if = "Option::is_none")]
+    etag: Option<String>,
+    #[serde(rename = "Checksum", skip_serializing_if = "Option::is_none")]
+    checksum: Option<ChecksumElement>,
+    #[serde(rename = "ObjectSize", skip_serializing_if = "Option::is_none")]
+    object_size: Option<u64>,
+    #[serde(rename = "StorageClass", skip_serializing_if = "Option::is_none")]
+    storage_class: Option<String>,
+}
+
+#[derive(Debug, Default, Serialize)]
+struct ChecksumElement {
+    #[serde(rename = "ChecksumCRC32", skip_serializing_if = "Option::is_none")]
+    crc32: Option<String>,
+    #[serde(rename = "ChecksumCRC32C", skip_serializing_if = "Option::is_none")]
+    crc32c: Option<String>,
+    #[serde(rename = "ChecksumSHA1", skip_serializing_if = "Option::is_none")]
+    sha1: Option<String>,
+    #[serde(rename = "ChecksumSHA256", skip_serializing_if = "Option::is_none")]
+    sha256: Option<String>,
+}
+
+impl From<Checksum> for ChecksumElement {
+    fn from(checksum: Checksum) -> Self {
+        let mut element = Self::default();
+        let field = match checksum.algorithm {
+            Algorithm::Crc32 => &mut element.crc32,
+            Algorithm::Crc32c => &mut element.crc32c,
+            Algorithm::Sha1 => &mut element.sha1,
+            Algorithm::Sha256 => &mut element.sha256,
+        };
+        *field = Some(checksum.value);
+        element
+    }
+}
+
+// GET /{key}?attributes (GetObjectAttributes), the attributes named in
+// x-amz-object-attributes. The ETag is the cheap one HEAD gives, and there
+// are no parts to list, the parts of a multipart upload aren't kept
+pub async fn attributes(
+    state: &AppState,
+    key: &str,
+    file_path: &Path,
+    headers: &HeaderMap,
+) -> Result<Response, StatusCode> {
+    let names: Vec<&str> = headers
+        .get("x-amz-object-attributes")
+        .and_then(|names| names.to_str().ok())
+        .map(|names| names.split(',').map(str::trim).filter(|name| !name.is_empty()).collect())
+        .unwrap_or_default();
+    const KNOWN: [&str; 5] = ["ETag", "Checksum", "ObjectParts", "StorageClass", "ObjectSize"];
+    let message = if names.is_empty() {
+        "The x-amz-object-attributes header specifying the attributes to be retrieved is either missing or empty"
+    } else if names.iter().any(|name| !KNOWN.contains(name)) {
+        "Invalid attribute name specified."
+    } else {
+        ""
+    };
+    if !message.is_empty() {
+        return Ok(error_response(
+            StatusCode::BAD_REQUEST,
+            ErrorResponse {
+                code: "InvalidArgument".to_string(),
+                message: message.to_string(),
+                max_size_allowed: None,
+            },
+        ));
+    }
+
+    let handle = state
+        .handles
+        .open(file_path)
+        .await
+        .map_err(|_| StatusCode::NOT_FOUND)?;
+    let wants = |name: &str| names.contains(&name);
+    let checksum = match wants("Checksum") {
+        true => load(state, key, handle.modified, handle.len).await,
+        false => None,
+    };
+    let etag = match wants("ETag") {
+        true => Some(
+            etag::of_file(state, key, file_path)
+                .await
+                .map_err(|_| StatusCode::INTERNAL_SERVER_ERROR)?,
+        ),
+        false => None,
+    };
+    let result = ObjectAttributes {
+        xmlns: "http://s3.amazonaws.com/doc/2006-03-01/".to_string(),
+        // Unquoted, as S3 gives it here
+        etag: etag.map(|etag| etag.trim_matches('"').to_string()),
+        checksum: checksum.map(ChecksumElement::from),
+        object_size: wants("ObjectSize").then_some(handle.len),
+        storage_class: wants("StorageClass").then(|| "STANDARD".to_string()),
+    };
+    let xml = serde_xml_rs::to_string(&result).map_err(|_| StatusCode::INTERNAL_SERVER_ERROR)?;
+
+    let mut headers = HeaderMap::new();
+    headers.insert("content-type", HeaderValue::from_static("application/xml"));
+    headers.insert("last-modified", http_date(handle.modified));
+    Ok((headers, xml).into_response())
+}
+
+// Whether a GET or HEAD asks for the checksum
+pub fn wanted(headers: &HeaderMap) -> bool {
+    headers
+        .get("x-amz-checksum-mode")
+        .and_then(|mode| mode.to_str().ok())
+        .is_some_and(|mode| mode.eq_ignore_ascii_case("enabled"))
+}
@@ -0,0 +1,442 @@
+use axum::{
+    Json,
+    extract::State,
+    http::{HeaderMap, StatusCode},
+};
+use serde::{Deserialize, Serialize};
+use sha2::{Digest, Sha256};
+use std::{
+    collections::HashMap,
+    sync::{Arc, Mutex},
+    time::{Duration, Instant},
+};
+use tokio::{fs, sync::RwLock};
+use tracing::{info, warn};
+
+use crate::{AppState, send_signed};
+
+// Cluster membership. Nodes start from a static list of seed peers and learn
+// the rest by gossip: every heartbeat returns the caller's view of the
+// members, which is merged into ours. All nodes must share credentials so
+// clients can talk to any of them, and a fingerprint of that configuration
+// is exchanged so a misconfigured node is flagged instead of silently
+// rejecting traffic.
+//
+// Destructive background work (GC, expiration) runs only on the leader. Each
+// node votes for the healthy member with the lowest node ID it can see, and
+// hands its vote out with its status. A node only takes the lease once it has
+// voted for itself for LEASE and a strict majority of the members it knows
+// of, itself included, has voted for it for as long. A node cut off with a
+// minority of the cluster never leads, so two sides of a network partition
+// can't both run the work; a cluster split with no majority anywhere has no
+// leader until it heals, and a cluster of two needs both nodes up. Votes are
+// up to a heartbeat old, which LEASE outlasts: by the time a node has held
+// a majority for LEASE, every member that voted for the previous leader has
+// moved on and been heard to. Membership only grows, so a node that leaves
+// for good still counts towards the majority until the others restart.
+
+pub const STATUS_PATH: &str = "/.simple-s3/cluster";
+const NODE_ID_FILE: &str = ".simple-s3/node-id";
+const NODE_HEADER: &str = "x-simple-s3-node";
+const HEARTBEAT_INTERVAL: Duration = Duration::from_secs(5);
+// Missed heartbeats before a member is reported down
+const SUSPECT_AFTER: u32 = 3;
+const LEASE: Duration = Duration::from_secs(HEARTBEAT_INTERVAL.as_secs() * (SUSPECT_AFTER as u64 + 1));
+
+#[derive(Debug, Clone, Serialize, Deserialize)]
+pub struct Member {
+    pub node_id: Option<String>,
+    pub url: String,
+    pub healthy: bool,
+    pub consistent: bool,
+    pub last_seen: Option<String>,
+    #[serde(skip)]
+    missed: u32,
+    // Who the member voted for in its last heartbeat
+    #[serde(skip)]
+    vote: Option<String>,
+}
+
+#[derive(Debug, Serialize, Deserialize)]
+pub struct ClusterStatus {
+    pub node_id: String,
+    pub url: String,
+    pub fingerprint: String,
+    pub leader: String,
+    pub members: Vec<Member>,
+}
+
+pub struct Cluster {
+    pub node_id: String,
+    pub url: String,
+    fingerprint: String,
+    members: RwLock<HashMap<String, Member>>,
+    client: reqwest::Client,
+    // Who we currently think leads, and since when
+    leader_view: Mutex<(String, Instant)>,
+    // Since when a majority has voted for this node, if it has
+    majority_since: Mutex<Option<Instant>>,
+}
+
+impl Cluster {
+    pub async fn new(state: &AppState, url: String, peers: &[String]) -> std::io::Result<Self> {
+        let node_id = node_id(state).await?;
+        let fingerprint = hex::encode(Sha256::digest(format!(
+            "{}\n{}\n{}",
+            state.bucket_name, state.access_key, state.secret_key
+        )))[..16]
+            .to_string();
+
+        let members = peers
+            .iter()
+            .map(|peer| normalize_url(peer))
+            .filter(|peer| *peer != url)
+            .map(|peer| (peer.clone(), Member::new(peer)))
+            .collect();
+
+        let client = reqwest::Client::builder()
+            .connect_timeout(HEARTBEAT_INTERVAL)
+            .build()
+            .map_err(std::io::Error::other)?;
+
+        Ok(Self {
+            leader_view: Mutex::new((node_id.clone(), Instant::now())),
+            majority_since: Mutex::new(None),
+            node_id,
+            url,
+            fingerprint,
+            members: RwLock::new(members),
+            client,
+        })
+    }
+
+    pub async fn status(&self) -> ClusterStatus {
+        let mut members: Vec<Member> = self.members.read().await.values().cloned().collect();
+        members.sort_by(|a, b| a.url.cmp(&b.url));
+
+        ClusterStatus {
+            node_id: self.node_id.clone(),
+            url: self.url.clone(),
+            fingerprint: self.fingerprint.clone(),
+            leader: self.leader_view.lock().unwrap().0.clone(),
+            members,
+        }
+    }
+
+    // Members that answered their last heartbeat with a matching configuration
+    pub async fn healthy_members(&self) -> Vec<Member> {
+        self.members
+            .read()
+            .await
+            .values()
+            .filter(|member| member.healthy && member.consistent && member.node_id.is_some())
+            .cloned()
+            .collect()
+    }
+
+    pub fn client(&self) -> &reqwest::Client {
+        &self.client
+    }
+
+    // Whether this node holds the worker lease
+    pub fn is_leader(&self) -> bool {
+        let (leader, since) = &*self.leader_view.lock().unwrap();
+        let majority = self.majority_since.lock().unwrap();
+        *leader == self.node_id
+            && since.elapsed() >= LEASE
+            && majority.is_some_and(|since| since.elapsed() >= LEASE)
+    }
+
+    async fn update_leader(&self) {
+        let members = self.members.read().await;
+        let reachable = members
+            .values()
+            .filter(|member| member.healthy && member.consistent);
+        let leader = reachable
+            .clone()
+            .filter_map(|member| member.node_id.clone())
+            .chain([self.node_id.clone()])
+            .min()
+            .unwrap_or_else(|| self.node_id.clone());
+
+        // Our own vote, and those of the members we heard from
+        let votes = usize::from(leader == self.node_id)
+            + reachable
+                .filter(|member| member.vote.as_ref() == Some(&self.node_id))
+                .count();
+        // Members running with other credentials aren't part of the cluster
+        let known = members.values().filter(|member| member.consistent).count() + 1;
+        let majority = votes * 2 > known;
+        drop(members);
+
+        {
+            let mut view = self.leader_view.lock().unwrap();
+            if view.0 != leader {
+                info!("🛰️ Cluster leader is now {}", leader);
+                *view = (leader, Instant::now());
+            }
+        }
+        let mut since = self.majority_since.lock().unwrap();
+        match (majority, since.is_some()) {
+            (true, false) => *since = Some(Instant::now()),
+            (false, true) => {
+                warn!("🛰️ Lost the votes of a majority of the cluster, not leading");
+                *since = None;
+            }
+            _ => {}
+        }
+    }
+
+    // Learn about a node that contacted us directly
+    async fn observe(&self, url: &str) {
+        let url = normalize_url(url);
+        if url == self.url {
+            return;
+        }
+
+        let mut members = self.members.write().await;
+        if !members.contains_key(&url) {
+            info!("🛰️ Cluster member joined: {}", url);
+            members.insert(url.clone(), Member::new(url));
+        }
+    }
+
+    pub fn spawn_heartbeats(self: Arc<Self>, state: Arc<AppState>) {
+        tokio::spawn(async move {
+            let mut interval = tokio::time::interval(HEARTBEAT_INTERVAL);
+            loop {
+                interval.tick().await;
+                self.heartbeat(&state).await;
+            }
+        });
+    }
+
+    async fn heartbeat(&self, state: &AppState) {
+        let urls: Vec<String> = self.members.read().await.keys().cloned().collect();
+
+        for url in urls {
+            let response = send_signed(
+                state,
+                self.client
+                    .get(format!("{}{}", url, STATUS_PATH))
+                    .timeout(HEARTBEAT_INTERVAL)
+                    .header(NODE_HEADER, &self.url),
+            )
+            .await;
+
+            let heartbeat = match response {
+                Ok(response)
+                    if response.status() == reqwest::StatusCode::UNAUTHORIZED
+                        || response.status() == reqwest::StatusCode::FORBIDDEN =>
+                {
+                    Heartbeat::Rejected
+                }
+                Ok(response) if response.status().is_success() => {
+                    match response.json::<ClusterStatus>().await {
+                        Ok(status) => Heartbeat::Status(status),
+                        Err(_) => Heartbeat::Missed,
+                    }
+                }
+                _ => Heartbeat::Missed,
+            };
+
+            self.record(&url, heartbeat).await;
+        }
+
+        self.update_leader().await;
+    }
+
+    async fn record(&self, url: &str, heartbeat: Heartbeat) {
+        let mut members = self.members.write().await;
+
+        let status = match heartbeat {
+            Heartbeat::Status(status) => status,
+            Heartbeat::Rejected => {
+                if let Some(member) = members.get_mut(url)
+                    && member.consistent
+                {
+                    warn!("🛰️ Cluster member {} rejected our credentials, ignoring it", url);
+                    member.consistent = false;
+                    member.healthy = true;
+                    member.missed = 0;
+                }
+                return;
+            }
+            Heartbeat::Missed => {
+                if let Some(member) = members.get_mut(url) {
+                    member.missed += 1;
+                    if member.missed == SUSPECT_AFTER {
+                        warn!("🛰️ Cluster member unreachable: {}", url);
+                    }
+                    if member.missed >= SUSPECT_AFTER {
+                        member.healthy = false;
+                    }
+                }
+                return;
+            }
+        };
+
+        let consistent = status.fingerprint == self.fingerprint;
+        if let Some(member) = members.get_mut(url) {
+            if !member.healthy && consistent {
+                info!("🛰️ Cluster member healthy: {} ({})", url, status.node_id);
+            }
+            if member.consistent && !consistent {
+                warn!(
+                    "🛰️ Cluster member {} has a different bucket or credentials, ignoring it",
+                    url
+                );
+            }
+
+            member.node_id = Some(status.node_id);
+            member.vote = Some(status.leader);
+            member.healthy = true;
+            member.consistent = consistent;
+            member.missed = 0;
+            member.last_seen = Some(chrono::Utc::now().to_rfc3339());
+        }
+
+        // Gossip: adopt the members the peer knows about
+        for member in status.members {
+            let peer = normalize_url(&member.url);
+            if peer != self.url && !members.contains_key(&peer) {
+                info!("🛰️ Discovered cluster member via {}: {}", url, peer);
+                members.insert(peer.clone(), Member::new(peer));
+            }
+        }
+    }
+}
+
+enum Heartbeat {
+    Status(ClusterStatus),
+    // The peer is up but doesn't accept our credentials
+    Rejected,
+    Missed,
+}
+
+impl Member {
+    fn new(url: String) -> Self {
+        Self {
+            node_id: None,
+            url,
+            healthy: false,
+            consistent: true,
+            last_seen: None,
+            missed: 0,
+            vote: None,
+        }
+    }
+}
+
+fn normalize_url(url: &str) -> String {
+    let url = url.trim().trim_end_matches('/');
+    if url.contains("://") {
+        url.to_string()
+    } else {
+        format!("http://{}", url)
+    }
+}
+
+// Node IDs are generated once and kept in the data dir
+async fn node_id(state: &AppState) -> std::io::Result<String> {
+    let path = state.data_dir.join(NODE_ID_FILE);
+
+    if let Ok(id) = fs::read_to_string(&path).await {
+        return Ok(id.trim().to_string());
+    }
+
+    let id = uuid::Uuid::new_v4().to_string();
+    fs::write(&path, &id).await?;
+    Ok(id)
+}
+
+// GET /.simple-s3/cluster: this node's view of the cluster. Peers identify
+// themselves with a header, which is how nodes learn about each other
+pub async fn cluster_status(
+    State(state): State<Arc<AppState>>,
+    headers: HeaderMap,
+) -> Result<Json<ClusterStatus>, StatusCode> {
+    let cluster = state.cluster.as_ref().ok_or(StatusCode::NOT_FOUND)?;
+
+    if let Some(peer) = headers.get(NODE_HEADER).and_then(|v| v.to_str().ok()) {
+        cluster.observe(peer).await;
+    }
+
+    Ok(Json(cluster.status().await))
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    // Node "b" of a cluster of itself and `peers`, (url, node ID, vote),
+    // healthy ones having a vote
+    fn cluster(peers: &[(&str, &str, Option<&str>)]) -> Cluster {
+        let members = peers
+            .iter()
+            .map(|(url, node_id, vote)| {
+                let mut member = Member::new(url.to_string());
+                member.node_id = Some(node_id.to_string());
+                member.healthy = vote.is_some();
+                member.vote = vote.map(str::to_string);
+                (url.to_string(), member)
+            })
+            .collect();
+        Cluster {
+            node_id: "b".to_string(),
+            url: "http://b".to_string(),
+            fingerprint: String::new(),
+            members: RwLock::new(members),
+            client: reqwest::Client::new(),
+            leader_view: Mutex::new(("b".to_string(), Instant::now())),
+            majority_since: Mutex::new(None),
+        }
+    }
+
+    fn has_majority(cluster: &Cluster) -> bool {
+        cluster.majority_since.lock().unwrap().is_some()
+    }
+
+    #[tokio::test]
+    async fn the_lowest_reachable_node_leads_with_a_majority() {
+        let cluster = cluster(&[("http://a", "a", None), ("http://c", "c", Some("b"))]);
+        cluster.update_leader().await;
+        assert_eq!(cluster.leader_view.lock().unwrap().0, "b");
+        assert!(has_majority(&cluster));
+        // Not before the lease has passed
+        assert!(!cluster.is_leader());
+    }
+
+    #[tokio::test]
+    async fn a_node_cut_off_with_a_minority_never_leads() {
+        // Only c is reachable out of five, and votes for b
+        let cluster = cluster(&[
+            ("http://a", "a", None),
+            ("http://c", "c", Some("b")),
+            ("http://d", "d", None),
+            ("http://e", "e", None),
+        ]);
+        cluster.update_leader().await;
+        assert_eq!(cluster.leader_view.lock().unwrap().0, "b");
+        assert!(!has_majority(&cluster));
+    }
+
+    #[tokio::test]
+    async fn votes_for_another_node_dont_count() {
+        // c and d can reach a, which b can't, and vote for it
+        let cluster = cluster(&[("http://c", "c", Some("a")), ("http://d", "d", Some("a"))]);
+        cluster.update_leader().await;
+        assert!(!has_majority(&cluster));
+    }
+
+    #[tokio::test]
+    async fn losing_the_majority_gives_up_the_lease() {
+        let cluster = cluster(&[("http://c", "c", Some("b"))]);
+        cluster.update_leader().await;
+        assert!(has_majority(&cluster));
+
+        cluster.members.write().await.get_mut("http://c").unwrap().healthy = false;
+        cluster.update_leader().await;
+        assert!(!has_majority(&cluster));
+    }
+}
@@ -0,0 +1,404 @@
+use axum::{
+    body::Bytes,
+    http::{HeaderMap, HeaderValue, StatusCode},
+    response::{IntoResponse, Response},
+};
+use std::{
+    io,
+    path::Path,
+    time::{Duration, SystemTime, UNIX_EPOCH},
+};
+use tokio::{fs, io::AsyncWriteExt};
+use tracing::info;
+
+use crate::{AppState, ErrorResponse, error_response, etag, http_date, rename_into_place, tier};
+
+// Conditional writes, like S3's: a PUT with `If-None-Match: *` only creates
+// the object, and one with `If-Match: ETAG` only replaces the object that
+// has that ETag, the one PUT and GET answer with. Otherwise it's refused
+// with 412 PreconditionFailed. Terraform's S3 backend takes its state lock
+// this way, so two runs can't both hold it.
+//
+// Creating is atomic: the body is written aside and hard linked into place,
+// which fails if anything got there first. Replacing holds a lock from the
+// ETag check to the rename, so of two PUTs matching the same ETag only the
+// first lands; PUTs without conditions aren't held up by it. A
+// CompleteMultipartUpload takes the same conditions for the object its
+// parts are joined into.
+//
+// Reads take the usual HTTP conditions, so browsers and CDNs can revalidate
+// what they cached: GET and HEAD answer 304 Not Modified when If-None-Match
+// names the object's ETag or it hasn't changed since If-Modified-Since, and
+// 412 when If-Match names another ETag or it changed after
+// If-Unmodified-Since. Each compares against the ETag it answers with.
+
+pub enum Condition {
+    // If-None-Match: *
+    Absent,
+    // If-Match, unquoted, or `*` for any existing object
+    Matches(String),
+}
+
+fn refusal(status: StatusCode, code: &str, message: &str) -> Response {
+    error_response(
+        status,
+        ErrorResponse {
+            code: code.to_string(),
+            message: message.to_string(),
+            max_size_allowed: None,
+        },
+    )
+}
+
+fn precondition_failed() -> Response {
+    refusal(
+        StatusCode::PRECONDITION_FAILED,
+        "PreconditionFailed",
+        "At least one of the pre-conditions you specified did not hold",
+    )
+}
+
+fn header(headers: &HeaderMap, name: &str) -> Option<String> {
+    headers
+        .get(name)
+        .and_then(|value| value.to_str().ok())
+        .map(|value| value.trim().trim_matches('"').to_string())
+}
+
+// The refusal for conditions on a PUT the server doesn't support, None if
+// it may go ahead
+pub fn refuse_unsupported(headers: &HeaderMap) -> Option<Response> {
+    let message = match (header(headers, "if-none-match"), header(headers, "if-match")) {
+        (Some(_), Some(_)) => "If-None-Match and If-Match can't be combined",
+        (Some(value), None) if value != "*" => "If-None-Match only supports * on writes",
+        _ => return None,
+    };
+    Some(refusal(StatusCode::NOT_IMPLEMENTED, "NotImplemented", message))
+}
+
+// The condition on a PUT, once refuse_unsupported let it through
+pub fn condition(headers: &HeaderMap) -> Option<Condition> {
+    if header(headers, "if-none-match").is_some() {
+        return Some(Condition::Absent);
+    }
+    header(headers, "if-match").map(Condition::Matches)
+}
+
+// Stores `bytes` at `path` if `condition` holds, answering with the refusal
+// if it doesn't
+pub async fn write(
+    state: &AppState,
+    key: &str,
+    tmp_path: &Path,
+    path: &Path,
+    bytes: &Bytes,
+    condition: &Condition,
+) -> io::Result<Option<Response>> {
+    let mut file = fs::File::create(tmp_path).await?;
+    file.set_max_buf_size(state.write_buffer);
+    file.write_all(bytes).await?;
+    file.flush().await?;
+    drop(file);
+    place(state, key, tmp_path, path, condition).await
+}
+
+// Moves the finished file at `tmp_path` to `path` if `condition` holds,
+// answering with the refusal and removing it if it doesn't
+pub async fn place(
+    state: &AppState,
+    key: &str,
+    tmp_path: &Path,
+    path: &Path,
+    condition: &Condition,
+) -> io::Result<Option<Response>> {
+    let placed = match condition {
+        Condition::Absent => create(key, tmp_path, path).await,
+        Condition::Matches(etag) => {
+            let _guard = state.conditional_writes.lock().await;
+            tier::recall(state, key, path).await?;
+            match etag::of_file(state, key, path).await {
+                Ok(current) if etag == "*" || current.trim_matches('"') == etag => {
+                    rename_into_place(tmp_path, path).await.map(|()| None)
+                }
+                Ok(_) => {
+                    info!("🔐 Refused to replace {}, its ETag is no longer {}", key, etag);
+                    Ok(Some(precondition_failed()))
+                }
+                Err(e) if e.kind() == io::ErrorKind::NotFound => Ok(Some(no_such_key())),
+                Err(e) => Err(e),
+            }
+        }
+    };
+    if !matches!(placed, Ok(None)) {
+        let _ = fs::remove_file(tmp_path).await;
+    }
+    placed
+}
+
+// The answer to a GET or HEAD whose conditions don't hold, None if it may
+// be served. As in RFC 9110, If-Match takes the place of
+// If-Unmodified-Since and If-None-Match that of If-Modified-Since
+pub fn check_read(headers: &HeaderMap, etag: &str, modified: SystemTime) -> Option<Response> {
+    // HTTP dates have whole seconds
+    let modified = modified
+        .duration_since(UNIX_EPOCH)
+        .map_or(0, |since| since.as_secs());
+
+    let unmodified = match header(headers, "if-match") {
+        Some(tags) => tag_listed(&tags, etag, false),
+        None => since(headers, "if-unmodified-since").is_none_or(|date| modified <= date),
+    };
+    if !unmodified {
+        return Some(precondition_failed());
+    }
+
+    let cached = match header(headers, "if-none-match") {
+        Some(tags) => tag_listed(&tags, etag, true),
+        None => since(headers, "if-modified-since").is_some_and(|date| modified <= date),
+    };
+    if !cached {
+        return None;
+    }
+    let mut response = StatusCode::NOT_MODIFIED.into_response();
+    let response_headers = response.headers_mut();
+    if let Ok(etag) = HeaderValue::from_str(etag) {
+        response_headers.insert("etag", etag);
+    }
+    response_headers.insert("last-modified", http_date(UNIX_EPOCH + Duration::from_secs(modified)));
+    Some(response)
+}
+
+// Whether a list of ETags, outer quotes already trimmed, holds `etag` or is
+// `*`. If-None-Match also matches weak tags
+fn tag_listed(tags: &str, etag: &str, weak: bool) -> bool {
+    let etag = etag.trim_matches('"');
+    tags.split(',').map(str::trim).any(|tag| {
+        let tag = match tag.strip_prefix("W/") {
+            Some(tag) if weak => tag,
+            Some(_) => return false,
+            None => tag,
+        };
+        tag == "*" || tag.trim_matches('"') == etag
+    })
+}
+
+// The date in an If-Modified-Since style header, in seconds since the epoch.
+// None without one, or if it's not a date, which HTTP says to ignore
+fn since(headers: &HeaderMap, name: &str) -> Option<u64> {
+    let date = headers.get(name)?.to_str().ok()?;
+    let date = chrono::DateTime::parse_from_rfc2822(date.trim()).ok()?;
+    u64::try_from(date.timestamp()).ok()
+}
+
+fn no_such_key() -> Response {
+    refusal(
+        StatusCode::NOT_FOUND,
+        "NoSuchKey",
+        "The specified key does not exist",
+    )
+}
+
+async fn create(key: &str, tmp_path: &Path, path: &Path) -> io::Result<Option<Response>> {
+    let mut linked = fs::hard_link(tmp_path, path).await;
+    // A delete removed the parent as empty since it was created
+    if let Err(e) = &linked
+        && e.kind() == io::ErrorKind::NotFound
+        && let Some(parent) = path.parent()
+    {
+        fs::create_dir_all(parent).await?;
+        linked = fs::hard_link(tmp_path, path).await;
+    }
+    let _ = fs::remove_file(tmp_path).await;
+    match linked {
+        Ok(()) => Ok(None),
+        Err(e) if e.kind() == io::ErrorKind::AlreadyExists => {
+            info!("🔐 Refused to create {}, it already exists", key);
+            Ok(Some(precondition_failed()))
+        }
+        Err(e) => Err(e),
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use std::{path::PathBuf, sync::Arc};
+
+    // A server state on a fresh data dir, removed when done
+    struct Fixture {
+        state: Arc<AppState>,
+    }
+
+    impl Fixture {
+        async fn new() -> Self {
+            let dir = std::env::temp_dir().join(format!("conditional-{}", uuid::Uuid::new_v4()));
+            Self {
+                state: Arc::new(crate::tests::state(&dir).await),
+            }
+        }
+
+        fn path(&self, key: &str) -> PathBuf {
+            self.state.data_dir.join(key)
+        }
+
+        async fn staged(&self, contents: &str) -> PathBuf {
+            let tmp = crate::tmp_path(&self.state);
+            fs::write(&tmp, contents).await.unwrap();
+            tmp
+        }
+    }
+
+    impl Drop for Fixture {
+        fn drop(&mut self) {
+            let _ = std::fs::remove_dir_all(&self.state.data_dir);
+        }
+    }
+
+    fn headers(pairs: &[(&'static str, &str)]) -> HeaderMap {
+        let mut headers = HeaderMap::new();
+        for (name, value) in pairs {
+            headers.insert(*name, HeaderValue::from_str(value).unwrap());
+        }
+        headers
+    }
+
+    fn status(response: Option<Response>) -> Option<StatusCode> {
+        response.map(|response| response.status())
+    }
+
+    #[tokio::test]
+    async fn create_refuses_an_existing_key() {
+        let fixture = Fixture::new().await;
+        let path = fixture.path("lock.tflock");
+        fs::write(&path, "held").await.unwrap();
+        let tmp = fixture.staged("mine").await;
+
+        let refused = create("lock.tflock", &tmp, &path).await.unwrap();
+        assert_eq!(status(refused), Some(StatusCode::PRECONDITION_FAILED));
+        assert_eq!(fs::read_to_string(&path).await.unwrap(), "held");
+        assert!(!tmp.exists());
+
+        // A missing key is created, along with its parent
+        let path = fixture.path("env/prod/lock.tflock");
+        let tmp = fixture.staged("mine").await;
+        assert!(create("env/prod/lock.tflock", &tmp, &path).await.unwrap().is_none());
+        assert_eq!(fs::read_to_string(&path).await.unwrap(), "mine");
+    }
+
+    #[tokio::test(flavor = "multi_thread", worker_threads = 4)]
+    async fn of_racing_creates_exactly_one_wins() {
+        let fixture = Fixture::new().await;
+        for round in 0..20 {
+            let key = format!("state-{}.tflock", round);
+            let mut racers = Vec::new();
+            for racer in 0..4 {
+                let state = fixture.state.clone();
+                let key = key.clone();
+                let path = fixture.path(&key);
+                let tmp = fixture.staged(&format!("racer {}", racer)).await;
+                racers.push(tokio::spawn(async move {
+                    let placed = place(&state, &key, &tmp, &path, &Condition::Absent).await;
+                    (racer, status(placed.unwrap()))
+                }));
+            }
+            let mut winners = Vec::new();
+            for racer in racers {
+                match racer.await.unwrap() {
+                    (racer, None) => winners.push(racer),
+                    (_, refused) => assert_eq!(refused, Some(StatusCode::PRECONDITION_FAILED)),
+                }
+            }
+            assert_eq!(winners.len(), 1, "round {}", round);
+            let stored = fs::read_to_string(fixture.path(&key)).await.unwrap();
+            assert_eq!(stored, format!("racer {}", winners[0]));
+        }
+    }
+
+    #[tokio::test]
+    async fn if_match_only_replaces_the_object_it_names() {
+        let fixture = Fixture::new().await;
+        let path = fixture.path("terraform.tfstate");
+        fs::write(&path, "serial 2").await.unwrap();
+        let current = etag::of_bytes(b"serial 2");
+        let stale = etag::of_bytes(b"serial 1");
+
+        let tmp = fixture.staged("serial 3").await;
+        let condition = Condition::Matches(stale.trim_matches('"').to_string());
+        let refused = place(&fixture.state, "terraform.tfstate", &tmp, &path, &condition).await;
+        assert_eq!(status(refused.unwrap()), Some(StatusCode::PRECONDITION_FAILED));
+        assert_eq!(fs::read_to_string(&path).await.unwrap(), "serial 2");
+        assert!(!tmp.exists());
+
+        let tmp = fixture.staged("serial 3").await;
+        let condition = Condition::Matches(current.trim_matches('"').to_string());
+        let placed = place(&fixture.state, "terraform.tfstate", &tmp, &path, &condition).await;
+        assert!(placed.unwrap().is_none());
+        assert_eq!(fs::read_to_string(&path).await.unwrap(), "serial 3");
+    }
+
+    #[tokio::test]
+    async fn if_match_on_a_missing_key_is_not_found() {
+        let fixture = Fixture::new().await;
+        let path = fixture.path("gone.tfstate");
+        let tmp = fixture.staged("serial 1").await;
+
+        let condition = Condition::Matches("*".to_string());
+        let refused = place(&fixture.state, "gone.tfstate", &tmp, &path, &condition).await;
+        assert_eq!(status(refused.unwrap()), Some(StatusCode::NOT_FOUND));
+        assert!(!path.exists());
+        assert!(!tmp.exists());
+    }
+
+    #[test]
+    fn unsupported_write_conditions_are_not_implemented() {
+        let combined = headers(&[("if-none-match", "*"), ("if-match", "\"abc\"")]);
+        let tag = headers(&[("if-none-match", "\"abc\"")]);
+        assert_eq!(status(refuse_unsupported(&combined)), Some(StatusCode::NOT_IMPLEMENTED));
+        assert_eq!(status(refuse_unsupported(&tag)), Some(StatusCode::NOT_IMPLEMENTED));
+
+        let create = headers(&[("if-none-match", "*")]);
+        let replace = headers(&[("if-match", "\"abc\"")]);
+        assert!(refuse_unsupported(&create).is_none());
+        assert!(refuse_unsupported(&replace).is_none());
+        assert!(matches!(condition(&create), Some(Condition::Absent)));
+        assert!(matches!(condition(&replace), Some(Condition::Matches(etag)) if etag == "abc"));
+    }
+
+    #[test]
+    fn read_conditions_take_precedence_as_in_http() {
+        let etag = "\"abc\"";
+        let modified = UNIX_EPOCH + Duration::from_secs(1_750_000_000);
+        let before = http_date(modified - Duration::from_secs(60));
+        let after = http_date(modified + Duration::from_secs(60));
+        let before = before.to_str().unwrap();
+        let after = after.to_str().unwrap();
+        let check = |pairs: &[(&'static str, &str)]| {
+            status(check_read(&headers(pairs), etag, modified))
+        };
+
+        // If-Match takes the place of If-Unmodified-Since
+        assert_eq!(check(&[("if-match", etag), ("if-unmodified-since", before)]), None);
+        assert_eq!(
+            check(&[("if-match", "\"xyz\""), ("if-unmodified-since", after)]),
+            Some(StatusCode::PRECONDITION_FAILED)
+        );
+        assert_eq!(
+            check(&[("if-unmodified-since", before)]),
+            Some(StatusCode::PRECONDITION_FAILED)
+        );
+
+        // If-None-Match takes the place of If-Modified-Since
+        assert_eq!(check(&[("if-none-match", "\"xyz\""), ("if-modified-since", after)]), None);
+        assert_eq!(
+            check(&[("if-none-match", etag), ("if-modified-since", before)]),
+            Some(StatusCode::NOT_MODIFIED)
+        );
+        assert_eq!(check(&[("if-modified-since", after)]), Some(StatusCode::NOT_MODIFIED));
+        assert_eq!(check(&[("if-modified-since", "yesterday")]), None);
+
+        // Weak tags only count for If-None-Match
+        assert_eq!(check(&[("if-none-match", "W/\"abc\"")]), Some(StatusCode::NOT_MODIFIED));
+        assert_eq!(check(&[("if-match", "W/\"abc\"")]), Some(StatusCode::PRECONDITION_FAILED));
+        assert_eq!(check(&[("if-none-match", "\"xyz\", *")]), Some(StatusCode::NOT_MODIFIED));
+    }
+}
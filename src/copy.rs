@@ -0,0 +1,133 @@
+use std::{
+    fs::File,
+    io::{self, Write},
+    path::Path,
+};
+
+// Copy the rest of `src` onto the end of `dst`, keeping the bytes inside the
+// kernel with copy_file_range where the platform and filesystem allow it
+pub fn copy_into(src: &mut File, dst: &mut File) -> io::Result<u64> {
+    #[cfg(target_os = "linux")]
+    if let Some(copied) = copy_file_range_all(src, dst)? {
+        return Ok(copied);
+    }
+
+    let copied = io::copy(src, dst)?;
+    dst.flush()?;
+    Ok(copied)
+}
+
+// Copy a whole file to a new path. On filesystems with reflinks (btrfs, XFS,
+// APFS) the copy is a copy-on-write clone that shares the source's extents
+pub async fn copy_file(src: &Path, dst: &Path) -> io::Result<u64> {
+    let src = src.to_path_buf();
+    let dst = dst.to_path_buf();
+
+    tokio::task::spawn_blocking(move || {
+        #[cfg(target_os = "macos")]
+        if let Some(len) = clonefile(&src, &dst)? {
+            return Ok(len);
+        }
+
+        let mut src = File::open(src)?;
+        let mut dst = File::create(dst)?;
+
+        #[cfg(target_os = "linux")]
+        if let Some(len) = ficlone(&src, &dst)? {
+            return Ok(len);
+        }
+
+        copy_into(&mut src, &mut dst)
+    })
+    .await
+    .map_err(io::Error::other)?
+}
+
+// Returns None when the filesystem can't share extents between the two files
+#[cfg(target_os = "linux")]
+fn ficlone(src: &File, dst: &File) -> io::Result<Option<u64>> {
+    use std::os::fd::AsRawFd;
+
+    // SAFETY: FICLONE takes the source descriptor as its argument and both
+    // descriptors are open for the duration of the call
+    let res = unsafe { libc::ioctl(dst.as_raw_fd(), libc::FICLONE, src.as_raw_fd()) };
+
+    if res == 0 {
+        return Ok(Some(src.metadata()?.len()));
+    }
+
+    let err = io::Error::last_os_error();
+    match err.raw_os_error() {
+        Some(libc::EOPNOTSUPP | libc::ENOTTY | libc::EXDEV | libc::EINVAL | libc::EPERM) => {
+            Ok(None)
+        }
+        _ => Err(err),
+    }
+}
+
+// clonefile(2) creates the destination itself, so it runs before the
+// destination is opened. Returns None when the volume can't clone
+#[cfg(target_os = "macos")]
+fn clonefile(src: &Path, dst: &Path) -> io::Result<Option<u64>> {
+    use std::{ffi::CString, os::unix::ffi::OsStrExt};
+
+    let c_src = CString::new(src.as_os_str().as_bytes())?;
+    let c_dst = CString::new(dst.as_os_str().as_bytes())?;
+
+    // SAFETY: both arguments are valid NUL-terminated paths
+    let res = unsafe { libc::clonefile(c_src.as_ptr(), c_dst.as_ptr(), 0) };
+
+    if res == 0 {
+        return Ok(Some(std::fs::metadata(dst)?.len()));
+    }
+
+    let err = io::Error::last_os_error();
+    match err.raw_os_error() {
+        Some(libc::ENOTSUP | libc::EXDEV) => Ok(None),
+        _ => Err(err),
+    }
+}
+
+// Returns None when copy_file_range is unsupported for this pair of files
+// (old kernel, cross-device on older kernels, special files) and nothing has
+// been copied yet, so the caller can fall back to a userspace copy
+#[cfg(target_os = "linux")]
+fn copy_file_range_all(src: &File, dst: &File) -> io::Result<Option<u64>> {
+    use std::os::fd::AsRawFd;
+
+    let mut copied = 0u64;
+
+    loop {
+        // SAFETY: both descriptors are open for the duration of the call and
+        // null offsets make the kernel use (and advance) the file positions
+        let n = unsafe {
+            libc::copy_file_range(
+                src.as_raw_fd(),
+                std::ptr::null_mut(),
+                dst.as_raw_fd(),
+                std::ptr::null_mut(),
+                1 << 30,
+                0,
+            )
+        };
+
+        match n {
+            0 => return Ok(Some(copied)),
+            n if n > 0 => copied += n as u64,
+            _ => {
+                let err = io::Error::last_os_error();
+                let unsupported = matches!(
+                    err.raw_os_error(),
+                    Some(libc::ENOSYS | libc::EXDEV | libc::EINVAL | libc::EOPNOTSUPP)
+                );
+
+                if unsupported && copied == 0 {
+                    return Ok(None);
+                }
+                if err.kind() != io::ErrorKind::Interrupted {
+                    return Err(err);
+                }
+            }
+        }
+    }
+}
@@ -0,0 +1,410 @@
+use axum::{
+    extract::{Request, State},
+    http::{HeaderMap, HeaderName, HeaderValue, Method, StatusCode, header},
+    middleware::Next,
+    response::{IntoResponse, Response},
+};
+use serde::{Deserialize, Serialize};
+use std::{sync::Arc, time::Duration};
+use tower_http::cors::{AllowHeaders, AllowMethods, AllowOrigin, CorsLayer, ExposeHeaders};
+
+use crate::{AppState, ErrorResponse, access::Document, error_response};
+
+// Server-wide CORS. Origins, methods and request headers default to `*`, and
+// closed deployments list the ones their browser clients use instead.
+// Browsers only let scripts read response headers that are listed in
+// Access-Control-Expose-Headers, so the defaults expose ETag (which multipart
+// uploads need from each part), the x-amz-* headers the server sends and the
+// headers tus clients read. Browsers refuse `*` on credentialed requests, and
+// echoing each request's own origin in its place would let any site call the
+// server with a visitor's cookies, so allowing credentials needs origins,
+// methods and headers to be listed.
+//
+// Once a CORSConfiguration is stored with PUT /?cors, its rules take over
+// from the flags, as on S3: the first rule matching a request's Origin and
+// method, and for a preflight its Access-Control-Request-Headers, gives the
+// CORS headers, and preflights no rule matches get 403 AccessForbidden.
+// DELETE /?cors goes back to the flags. S3 allows credentials for every rule
+// but those allowing any origin; here a rule with a `*` in any of its origins
+// or headers doesn't allow them either, for the same reason as the flags.
+
+const MAX_RULES: usize = 100;
+const MAX_ID_LEN: usize = 255;
+const RULE_METHODS: [&str; 5] = ["GET", "PUT", "HEAD", "POST", "DELETE"];
+const CORS_HEADERS: [HeaderName; 6] = [
+    header::ACCESS_CONTROL_ALLOW_ORIGIN,
+    header::ACCESS_CONTROL_ALLOW_METHODS,
+    header::ACCESS_CONTROL_ALLOW_HEADERS,
+    header::ACCESS_CONTROL_EXPOSE_HEADERS,
+    header::ACCESS_CONTROL_MAX_AGE,
+    header::ACCESS_CONTROL_ALLOW_CREDENTIALS,
+];
+
+pub const DEFAULT_EXPOSE_HEADERS: &str = "etag,x-amz-request-id,x-amz-version-id,x-amz-delete-marker,\
+x-amz-checksum-crc32,x-amz-checksum-crc32c,x-amz-checksum-sha1,x-amz-checksum-sha256,\
+x-simple-s3-object-count,x-simple-s3-bytes-used,location,tus-resumable,upload-offset,upload-length";
+
+fn is_any(values: &[String]) -> bool {
+    values.is_empty() || values.iter().any(|value| value == "*")
+}
+
+pub fn layer(
+    origins: &[String],
+    methods: &[String],
+    allow_headers: &[String],
+    expose_headers: &[String],
+    max_age: u64,
+    allow_credentials: bool,
+) -> Result<CorsLayer, String> {
+    if allow_credentials {
+        let flags = [
+            (origins, "--cors-origins"),
+            (methods, "--cors-methods"),
+            (allow_headers, "--cors-allow-headers"),
+        ];
+        if let Some((_, flag)) = flags.iter().find(|(values, _)| is_any(values)) {
+            return Err(format!(
+                "--cors-allow-credentials needs {} to list what's allowed instead of *",
+                flag
+            ));
+        }
+    }
+
+    let allow_origin = if is_any(origins) {
+        AllowOrigin::any()
+    } else {
+        AllowOrigin::list(
+            origins
+                .iter()
+                .map(|origin| {
+                    HeaderValue::from_str(origin.trim_end_matches('/'))
+                        .map_err(|_| format!("invalid CORS origin {:?}", origin))
+                })
+                .collect::<Result<Vec<_>, _>>()?,
+        )
+    };
+
+    let expose_headers = expose_headers
+        .iter()
+        .map(|name| {
+            HeaderName::from_bytes(name.trim().as_bytes())
+                .map_err(|_| format!("invalid CORS exposed header {:?}", name))
+        })
+        .collect::<Result<Vec<_>, _>>()?;
+
+    let allow_methods = if is_any(methods) {
+        AllowMethods::any()
+    } else {
+        AllowMethods::list(
+            methods
+                .iter()
+                .map(|method| {
+                    Method::from_bytes(method.trim().to_ascii_uppercase().as_bytes())
+                        .map_err(|_| format!("invalid CORS method {:?}", method))
+                })
+                .collect::<Result<Vec<_>, _>>()?,
+        )
+    };
+
+    let allow_headers = if is_any(allow_headers) {
+        AllowHeaders::any()
+    } else {
+        AllowHeaders::list(
+            allow_headers
+                .iter()
+                .map(|name| {
+                    HeaderName::from_bytes(name.trim().as_bytes())
+                        .map_err(|_| format!("invalid CORS allowed header {:?}", name))
+                })
+                .collect::<Result<Vec<_>, _>>()?,
+        )
+    };
+
+    Ok(CorsLayer::new()
+        .allow_origin(allow_origin)
+        .allow_methods(allow_methods)
+        .allow_headers(allow_headers)
+        .expose_headers(ExposeHeaders::list(expose_headers))
+        .max_age(Duration::from_secs(max_age))
+        .allow_credentials(allow_credentials))
+}
+
+#[derive(Debug, Clone, Serialize, Deserialize)]
+#[serde(rename = "CORSConfiguration")]
+pub struct CorsConfiguration {
+    #[serde(rename = "@xmlns", default)]
+    xmlns: String,
+    #[serde(rename = "CORSRule", default)]
+    rules: Vec<CorsRule>,
+}
+
+#[derive(Debug, Clone, Serialize, Deserialize)]
+struct CorsRule {
+    #[serde(rename = "ID", skip_serializing_if = "Option::is_none")]
+    id: Option<String>,
+    #[serde(rename = "AllowedOrigin", default)]
+    allowed_origins: Vec<String>,
+    #[serde(rename = "AllowedMethod", default)]
+    allowed_methods: Vec<String>,
+    #[serde(rename = "AllowedHeader", default, skip_serializing_if = "Vec::is_empty")]
+    allowed_headers: Vec<String>,
+    #[serde(rename = "ExposeHeader", default, skip_serializing_if = "Vec::is_empty")]
+    expose_headers: Vec<String>,
+    #[serde(rename = "MaxAgeSeconds", skip_serializing_if = "Option::is_none")]
+    max_age_seconds: Option<u64>,
+}
+
+impl Document for CorsConfiguration {
+    const FILE: &'static str = ".simple-s3/cors.xml";
+    const NOT_FOUND: (&'static str, &'static str) = (
+        "NoSuchCORSConfiguration",
+        "The CORS configuration does not exist",
+    );
+
+    fn set_xmlns(&mut self, xmlns: &str) {
+        self.xmlns = xmlns.to_string();
+    }
+
+    fn is_valid(&self) -> bool {
+        (1..=MAX_RULES).contains(&self.rules.len()) && self.rules.iter().all(CorsRule::is_valid)
+    }
+}
+
+// Whether `value` matches `pattern`, which may have one `*` standing for
+// any run of characters
+fn wildcard(pattern: &str, value: &str) -> bool {
+    match pattern.split_once('*') {
+        Some((start, end)) => {
+            value.len() >= start.len() + end.len()
+                && value.starts_with(start)
+                && value.ends_with(end)
+        }
+        None => pattern == value,
+    }
+}
+
+impl CorsRule {
+    fn is_valid(&self) -> bool {
+        let one_wildcard = |pattern: &String| pattern.matches('*').count() <= 1;
+        !self.allowed_origins.is_empty()
+            && !self.allowed_methods.is_empty()
+            && self.id.as_ref().is_none_or(|id| id.len() <= MAX_ID_LEN)
+            && self.allowed_origins.iter().all(one_wildcard)
+            && self.allowed_headers.iter().all(one_wildcard)
+            && self
+                .allowed_methods
+                .iter()
+                .all(|method| RULE_METHODS.contains(&method.as_str()))
+    }
+
+    // Whether the rule lets `origin` make a `method` request sending
+    // `headers`
+    fn allows(&self, origin: &str, method: &str, headers: &[String]) -> bool {
+        self.allowed_origins
+            .iter()
+            .any(|pattern| wildcard(pattern, origin))
+            && self.allowed_methods.iter().any(|allowed| allowed == method)
+            && headers.iter().all(|name| {
+                self.allowed_headers
+                    .iter()
+                    .any(|pattern| wildcard(&pattern.to_ascii_lowercase(), name))
+            })
+    }
+
+    // Whether the rule lets browsers send cookies and HTTP auth, only when it
+    // names every origin and header it allows
+    fn allows_credentials(&self) -> bool {
+        !self
+            .allowed_origins
+            .iter()
+            .chain(&self.allowed_headers)
+            .any(|pattern| pattern.contains('*'))
+    }
+
+    // The CORS headers of a response to `origin` the rule allowed
+    fn insert_headers(&self, origin: &str, headers: &mut HeaderMap) {
+        let any_origin = self.allowed_origins.iter().any(|pattern| pattern == "*");
+        let mut insert = |name: HeaderName, value: &str| {
+            if let Ok(value) = HeaderValue::from_str(value) {
+                headers.insert(name, value);
+            }
+        };
+        insert(
+            header::ACCESS_CONTROL_ALLOW_ORIGIN,
+            if any_origin { "*" } else { origin },
+        );
+        insert(
+            header::ACCESS_CONTROL_ALLOW_METHODS,
+            &self.allowed_methods.join(", "),
+        );
+        if !self.expose_headers.is_empty() {
+            insert(
+                header::ACCESS_CONTROL_EXPOSE_HEADERS,
+                &self.expose_headers.join(", "),
+            );
+        }
+        if let Some(max_age) = self.max_age_seconds {
+            insert(header::ACCESS_CONTROL_MAX_AGE, &max_age.to_string());
+        }
+        if self.allows_credentials() {
+            insert(header::ACCESS_CONTROL_ALLOW_CREDENTIALS, "true");
+        }
+        headers.append(header::VARY, HeaderValue::from_static("Origin"));
+    }
+}
+
+impl CorsConfiguration {
+    fn rule(&self, origin: &str, method: &str, headers: &[String]) -> Option<&CorsRule> {
+        self.rules
+            .iter()
+            .find(|rule| rule.allows(origin, method, headers))
+    }
+}
+
+fn forbidden() -> Response {
+    error_response(
+        StatusCode::FORBIDDEN,
+        ErrorResponse {
+            code: "AccessForbidden".to_string(),
+            message: "CORSResponse: This CORS request is not allowed. This is usually because \
+the evalution of Origin, request method / Access-Control-Request-Method or \
+Access-Control-Request-Headers are not whitelisted by the resource's CORS spec."
+                .to_string(),
+            max_size_allowed: None,
+        },
+    )
+}
+
+// Answers an OPTIONS preflight by the stored rules
+fn preflight(config: &CorsConfiguration, origin: &str, request: &HeaderMap) -> Response {
+    let method = request
+        .get(header::ACCESS_CONTROL_REQUEST_METHOD)
+        .and_then(|method| method.to_str().ok())
+        .unwrap_or_default();
+    let requested: Vec<String> = request
+        .get_all(header::ACCESS_CONTROL_REQUEST_HEADERS)
+        .iter()
+        .filter_map(|value| value.to_str().ok())
+        .flat_map(|value| value.split(','))
+        .map(|name| name.trim().to_ascii_lowercase())
+        .filter(|name| !name.is_empty())
+        .collect();
+    let Some(rule) = config.rule(origin, method, &requested) else {
+        return forbidden();
+    };
+
+    let mut headers = HeaderMap::new();
+    rule.insert_headers(origin, &mut headers);
+    if !requested.is_empty()
+        && let Ok(value) = HeaderValue::from_str(&requested.join(", "))
+    {
+        headers.insert(header::ACCESS_CONTROL_ALLOW_HEADERS, value);
+    }
+    for vary in ["Access-Control-Request-Headers", "Access-Control-Request-Method"] {
+        headers.append(header::VARY, HeaderValue::from_static(vary));
+    }
+    (StatusCode::OK, headers).into_response()
+}
+
+// Runs around the server-wide CORS layer, putting the bucket's rules in its
+// place while there are some
+pub async fn bucket_cors_middleware(
+    State(state): State<Arc<AppState>>,
+    request: Request,
+    next: Next,
+) -> Response {
+    let Some(config) = state.cors.get() else {
+        return next.run(request).await;
+    };
+    let origin = request
+        .headers()
+        .get(header::ORIGIN)
+        .and_then(|origin| origin.to_str().ok())
+        .map(str::to_string);
+    if let Some(origin) = &origin
+        && request.method() == Method::OPTIONS
+        && request.headers().contains_key(header::ACCESS_CONTROL_REQUEST_METHOD)
+    {
+        return preflight(&config, origin, request.headers());
+    }
+
+    let method = request.method().clone();
+    let mut response = next.run(request).await;
+    let headers = response.headers_mut();
+    for name in CORS_HEADERS {
+        headers.remove(name);
+    }
+    if let Some(origin) = origin
+        && let Some(rule) = config.rule(&origin, method.as_str(), &[])
+    {
+        rule.insert_headers(&origin, headers);
+    }
+    response
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    fn list(values: &[&str]) -> Vec<String> {
+        values.iter().map(|value| value.to_string()).collect()
+    }
+
+    #[test]
+    fn credentials_need_everything_listed() {
+        let origins = list(&["https://app.example.com"]);
+        let methods = list(&["GET", "PUT"]);
+        let headers = list(&["authorization"]);
+        let expose = list(&["etag"]);
+
+        assert!(layer(&origins, &methods, &headers, &expose, 0, true).is_ok());
+        let any = list(&["*"]);
+        for (origins, methods, headers) in [
+            (&any, &methods, &headers),
+            (&origins, &any, &headers),
+            (&origins, &methods, &Vec::new()),
+        ] {
+            assert!(layer(origins, methods, headers, &expose, 0, true).is_err());
+            assert!(layer(origins, methods, headers, &expose, 0, false).is_ok());
+        }
+    }
+
+    fn rule(origins: &[&str], headers: &[&str]) -> CorsRule {
+        CorsRule {
+            id: None,
+            allowed_origins: list(origins),
+            allowed_methods: list(&["GET"]),
+            allowed_headers: list(headers),
+            expose_headers: Vec::new(),
+            max_age_seconds: None,
+        }
+    }
+
+    fn response_headers(rule: &CorsRule, origin: &str) -> HeaderMap {
+        let mut headers = HeaderMap::new();
+        rule.insert_headers(origin, &mut headers);
+        headers
+    }
+
+    #[test]
+    fn only_rules_without_wildcards_allow_credentials() {
+        let origin = "https://app.example.com";
+
+        let listed = response_headers(&rule(&[origin], &["authorization"]), origin);
+        assert_eq!(listed[header::ACCESS_CONTROL_ALLOW_ORIGIN], origin);
+        assert_eq!(listed[header::ACCESS_CONTROL_ALLOW_CREDENTIALS], "true");
+
+        let any = response_headers(&rule(&["*"], &[]), origin);
+        assert_eq!(any[header::ACCESS_CONTROL_ALLOW_ORIGIN], "*");
+        assert!(!any.contains_key(header::ACCESS_CONTROL_ALLOW_CREDENTIALS));
+
+        for rule in [
+            rule(&["https://*.example.com"], &[]),
+            rule(&[origin], &["*"]),
+        ] {
+            let headers = response_headers(&rule, origin);
+            assert_eq!(headers[header::ACCESS_CONTROL_ALLOW_ORIGIN], origin);
+            assert!(!headers.contains_key(header::ACCESS_CONTROL_ALLOW_CREDENTIALS));
+        }
+    }
+}
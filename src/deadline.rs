@@ -0,0 +1,55 @@
+use axum::{
+    extract::{Request, State},
+    http::StatusCode,
+    middleware::Next,
+    response::Response,
+};
+use std::{sync::Arc, time::Duration};
+use tracing::warn;
+
+use crate::{AppState, ErrorResponse, error_response};
+
+// Per-request deadlines. With --deadline-header x-deadline-ms, a request
+// carrying that header with a number of milliseconds is abandoned with 408
+// RequestTimeout once they've passed, so callers that will have given up by
+// then don't keep the server busy. The clock starts when the request
+// arrives and stops when the response starts; a body already being streamed
+// isn't cut off. A write abandoned this way may or may not have been stored.
+
+fn deadline_exceeded(budget: Duration) -> Response {
+    error_response(
+        StatusCode::REQUEST_TIMEOUT,
+        ErrorResponse {
+            code: "RequestTimeout".to_string(),
+            message: format!("The request did not complete within its {}ms deadline", budget.as_millis()),
+            max_size_allowed: None,
+        },
+    )
+}
+
+pub async fn deadline_middleware(
+    State(state): State<Arc<AppState>>,
+    request: Request,
+    next: Next,
+) -> Response {
+    let budget = state
+        .deadline_header
+        .as_deref()
+        .and_then(|header| request.headers().get(header))
+        .and_then(|v| v.to_str().ok())
+        .and_then(|v| v.trim().parse::<u64>().ok())
+        .map(Duration::from_millis);
+    let Some(budget) = budget else {
+        return next.run(request).await;
+    };
+
+    let method = request.method().clone();
+    let path = request.uri().path().to_string();
+    match tokio::time::timeout(budget, next.run(request)).await {
+        Ok(response) => response,
+        Err(_) => {
+            warn!("⏱️ {} {} abandoned after its {}ms deadline", method, path, budget.as_millis());
+            deadline_exceeded(budget)
+        }
+    }
+}
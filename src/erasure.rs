@@ -0,0 +1,752 @@
+use axum::{
+    extract::{Request, State},
+    http::{Method, StatusCode},
+    middleware::Next,
+    response::{IntoResponse, Response},
+};
+use percent_encoding::percent_decode_str;
+use reed_solomon_erasure::galois_8::ReedSolomon;
+use std::{
+    collections::{HashMap, HashSet},
+    fs::{self, File, FileTimes, Metadata},
+    io::{self, Read, Write},
+    path::{Path, PathBuf},
+    sync::{Arc, Mutex},
+    time::{Duration, SystemTime},
+};
+use tracing::{info, warn};
+
+use crate::{
+    AppState, INTERNAL_DIR, keys, multipart, normalize, object_path, relative_path, tmp_path,
+    walk,
+};
+
+// Erasure coding. With --erasure-dirs, every object is also kept as shards
+// spread over those directories, one per directory, the last
+// --parity-shards of them parity, so with 6 dirs and 2 parity shards any 4
+// of them are enough to rebuild an object. Objects are still read from and
+// written to the data dir as usual, and an object written over the S3 API,
+// as a browser upload or with tus has its shards written before the write is
+// acknowledged; if they can't be, the request fails with 500 so the client
+// writes it again. Writes over WebDAV and SFTP, and changes made on disk,
+// are encoded by the next pass. The shards only come in when disks fail:
+//
+// - A shard dir that comes back empty, after its disk was replaced, gets
+//   its shards rebuilt from the data dir on the next pass.
+// - A data dir that comes back empty gets every object rebuilt from the
+//   shards on startup.
+//
+// Each dir is told apart from a fresh one by MARKER_FILE. Passes run every
+// PASS_INTERVAL, encoding objects changed since the last that weren't
+// encoded as they were written and removing the shards of deleted ones. A
+// shard file is the shard's Header followed by its part of every stripe of
+// the object, a stripe being up to BLOCK bytes of each data shard and the
+// parity computed over them.
+
+const PASS_INTERVAL: Duration = Duration::from_secs(5 * 60);
+const MARKER_FILE: &str = ".simple-s3/erasure";
+const TMP_DIR: &str = ".simple-s3/tmp";
+const MAGIC: &[u8; 4] = b"S3EC";
+const HEADER_LEN: usize = 28;
+const BLOCK: usize = 256 * 1024;
+
+// The size and modification time of the object a shard was made from
+#[derive(Debug, Clone, Copy, PartialEq, Eq, Hash)]
+struct Version {
+    len: u64,
+    modified_secs: u64,
+    modified_nanos: u32,
+}
+
+impl Version {
+    fn of(metadata: &Metadata) -> Option<Self> {
+        let modified = metadata
+            .modified()
+            .ok()?
+            .duration_since(SystemTime::UNIX_EPOCH)
+            .ok()?;
+        Some(Self {
+            len: metadata.len(),
+            modified_secs: modified.as_secs(),
+            modified_nanos: modified.subsec_nanos(),
+        })
+    }
+
+    fn modified(self) -> SystemTime {
+        SystemTime::UNIX_EPOCH + Duration::new(self.modified_secs, self.modified_nanos)
+    }
+}
+
+#[derive(Debug, Clone, Copy, PartialEq)]
+struct Header {
+    data: u8,
+    parity: u8,
+    index: u8,
+    version: Version,
+}
+
+impl Header {
+    fn to_bytes(self) -> [u8; HEADER_LEN] {
+        let mut bytes = [0; HEADER_LEN];
+        bytes[..4].copy_from_slice(MAGIC);
+        bytes[4] = 1;
+        bytes[5] = self.data;
+        bytes[6] = self.parity;
+        bytes[7] = self.index;
+        bytes[8..16].copy_from_slice(&self.version.len.to_le_bytes());
+        bytes[16..24].copy_from_slice(&self.version.modified_secs.to_le_bytes());
+        bytes[24..28].copy_from_slice(&self.version.modified_nanos.to_le_bytes());
+        bytes
+    }
+
+    fn parse(bytes: &[u8; HEADER_LEN]) -> Option<Self> {
+        if &bytes[..4] != MAGIC || bytes[4] != 1 {
+            return None;
+        }
+        Some(Self {
+            data: bytes[5],
+            parity: bytes[6],
+            index: bytes[7],
+            version: Version {
+                len: u64::from_le_bytes(bytes[8..16].try_into().ok()?),
+                modified_secs: u64::from_le_bytes(bytes[16..24].try_into().ok()?),
+                modified_nanos: u32::from_le_bytes(bytes[24..28].try_into().ok()?),
+            },
+        })
+    }
+}
+
+// The object bytes in each stripe of an object of `len` bytes, and the
+// length of each shard's block of it
+fn stripes(len: u64, data: usize) -> impl Iterator<Item = (usize, usize)> {
+    let full = (data * BLOCK) as u64;
+    let mut left = len;
+    std::iter::from_fn(move || {
+        if left == 0 {
+            return None;
+        }
+        let bytes = left.min(full) as usize;
+        left -= bytes as u64;
+        Some((bytes, bytes.div_ceil(data)))
+    })
+}
+
+fn codec_error(e: reed_solomon_erasure::Error) -> io::Error {
+    io::Error::other(format!("erasure coding failed: {:?}", e))
+}
+
+fn marked(dir: &Path) -> bool {
+    dir.join(MARKER_FILE).exists()
+}
+
+fn mark(dir: &Path) -> io::Result<()> {
+    let marker = dir.join(MARKER_FILE);
+    if let Some(parent) = marker.parent() {
+        fs::create_dir_all(parent)?;
+    }
+    fs::write(marker, b"")
+}
+
+// What a pass did with an object
+enum Outcome {
+    Current,
+    Encoded,
+    // The object changed while it was being encoded
+    Changed,
+}
+
+pub struct ErasureSet {
+    dirs: Vec<PathBuf>,
+    data: usize,
+    parity: usize,
+    codec: ReedSolomon,
+    // Objects whose shards were current as of the last pass or write
+    encoded: Mutex<HashMap<String, Version>>,
+}
+
+impl ErasureSet {
+    pub fn new(dirs: Vec<PathBuf>, parity: usize) -> Result<Self, String> {
+        if parity == 0 || dirs.len() <= parity {
+            return Err(format!(
+                "--erasure-dirs needs more than --parity-shards ({}) directories, got {}",
+                parity,
+                dirs.len()
+            ));
+        }
+        let data = dirs.len() - parity;
+        let codec = ReedSolomon::new(data, parity)
+            .map_err(|e| format!("invalid erasure set of {}+{} shards: {:?}", data, parity, e))?;
+        Ok(Self {
+            dirs,
+            data,
+            parity,
+            codec,
+            encoded: Mutex::new(HashMap::new()),
+        })
+    }
+
+    fn shard_path(&self, index: usize, key: &str) -> io::Result<PathBuf> {
+        relative_path(key)
+            .map(|rel| self.dirs[index].join(rel))
+            .ok_or_else(|| io::Error::other("invalid key"))
+    }
+
+    fn expected(&self, index: usize, version: Version) -> Header {
+        Header {
+            data: self.data as u8,
+            parity: self.parity as u8,
+            index: index as u8,
+            version,
+        }
+    }
+
+    // A shard and its header, positioned at its first block
+    fn open_shard(&self, index: usize, key: &str) -> Option<(Header, File)> {
+        let mut file = File::open(self.shard_path(index, key).ok()?).ok()?;
+        let mut bytes = [0; HEADER_LEN];
+        file.read_exact(&mut bytes).ok()?;
+        Some((Header::parse(&bytes)?, file))
+    }
+
+    // The dirs whose shard of `key` is missing or from another version
+    fn stale(&self, key: &str, version: Version) -> Vec<usize> {
+        (0..self.dirs.len())
+            .filter(|&index| {
+                self.open_shard(index, key)
+                    .is_none_or(|(header, _)| header != self.expected(index, version))
+            })
+            .collect()
+    }
+
+    // Writes the shards of `targets` from the object at `path`
+    fn encode(&self, path: &Path, key: &str, version: Version, targets: &[usize]) -> io::Result<Outcome> {
+        let mut outs = Vec::new();
+        for &index in targets {
+            let tmp = self.dirs[index]
+                .join(TMP_DIR)
+                .join(uuid::Uuid::new_v4().to_string());
+            if let Some(parent) = tmp.parent() {
+                fs::create_dir_all(parent)?;
+            }
+            outs.push((index, tmp.clone(), File::create(&tmp)?));
+        }
+
+        let written = self.write_shards(path, version, &mut outs);
+        let unchanged = fs::metadata(path)
+            .ok()
+            .and_then(|metadata| Version::of(&metadata))
+            == Some(version);
+        if written.is_err() || !unchanged {
+            for (_, tmp, _) in &outs {
+                let _ = fs::remove_file(tmp);
+            }
+            written?;
+            return Ok(Outcome::Changed);
+        }
+
+        for (index, tmp, _) in outs {
+            let shard = self.shard_path(index, key)?;
+            if let Some(parent) = shard.parent() {
+                fs::create_dir_all(parent)?;
+            }
+            fs::rename(&tmp, &shard)?;
+        }
+        Ok(Outcome::Encoded)
+    }
+
+    fn write_shards(
+        &self,
+        path: &Path,
+        version: Version,
+        outs: &mut [(usize, PathBuf, File)],
+    ) -> io::Result<()> {
+        for (index, _, out) in outs.iter_mut() {
+            out.write_all(&self.expected(*index, version).to_bytes())?;
+        }
+        let mut file = File::open(path)?;
+        let mut stripe = vec![0; self.data * BLOCK];
+        for (bytes, block) in stripes(version.len, self.data) {
+            file.read_exact(&mut stripe[..bytes])?;
+            let mut shards: Vec<Vec<u8>> = (0..self.data + self.parity)
+                .map(|i| {
+                    let mut shard = vec![0; block];
+                    let start = (i * block).min(bytes);
+                    let end = ((i + 1) * block).min(bytes);
+                    if i < self.data {
+                        shard[..end - start].copy_from_slice(&stripe[start..end]);
+                    }
+                    shard
+                })
+                .collect();
+            self.codec.encode(&mut shards).map_err(codec_error)?;
+            for (index, _, out) in outs.iter_mut() {
+                out.write_all(&shards[*index])?;
+            }
+        }
+        for (_, _, out) in outs.iter_mut() {
+            out.sync_all()?;
+        }
+        Ok(())
+    }
+
+    // Brings the shards of the object at `path` up to date with it as it is
+    // now, and notes it as encoded so passes leave it be
+    fn encode_current(&self, path: &Path, key: &str) -> io::Result<()> {
+        let metadata = match fs::metadata(path) {
+            Ok(metadata) => metadata,
+            // Deleted since
+            Err(e) if e.kind() == io::ErrorKind::NotFound => return Ok(()),
+            Err(e) => return Err(e),
+        };
+        if !metadata.is_file() {
+            return Ok(());
+        }
+        let version = Version::of(&metadata)
+            .ok_or_else(|| io::Error::other("the object has no modification time"))?;
+        let stale = self.stale(key, version);
+        // Changed means another write got there first, and it encodes the
+        // object in turn
+        if !stale.is_empty() && let Outcome::Changed = self.encode(path, key, version, &stale)? {
+            return Ok(());
+        }
+        self.encoded.lock().unwrap().insert(key.to_string(), version);
+        Ok(())
+    }
+
+    // Rebuilds `key` from its shards into `dst`, from the newest version
+    // enough of them agree on. None if there aren't enough
+    fn restore(&self, key: &str, dst: &Path) -> io::Result<Option<Version>> {
+        let shards: Vec<Option<(Header, File)>> = (0..self.dirs.len())
+            .map(|index| {
+                self.open_shard(index, key)
+                    .filter(|(header, _)| header == &self.expected(index, header.version))
+            })
+            .collect();
+        let mut counts: HashMap<Version, usize> = HashMap::new();
+        for (header, _) in shards.iter().flatten() {
+            *counts.entry(header.version).or_default() += 1;
+        }
+        let Some(version) = counts
+            .into_iter()
+            .filter(|(_, count)| *count >= self.data)
+            .map(|(version, _)| version)
+            .max_by_key(|version| (version.modified_secs, version.modified_nanos))
+        else {
+            return Ok(None);
+        };
+        let mut sources: Vec<Option<File>> = shards
+            .into_iter()
+            .map(|shard| {
+                shard
+                    .filter(|(header, _)| header.version == version)
+                    .map(|(_, file)| file)
+            })
+            .collect();
+
+        let mut out = File::create(dst)?;
+        for (bytes, block) in stripes(version.len, self.data) {
+            let mut blocks: Vec<Option<Vec<u8>>> = sources
+                .iter_mut()
+                .map(|source| {
+                    let mut buf = vec![0; block];
+                    match source.as_mut()?.read_exact(&mut buf) {
+                        Ok(()) => Some(buf),
+                        Err(_) => {
+                            *source = None;
+                            None
+                        }
+                    }
+                })
+                .collect();
+            self.codec.reconstruct_data(&mut blocks).map_err(codec_error)?;
+            let mut left = bytes;
+            for block in blocks.iter().take(self.data).flatten() {
+                let take = left.min(block.len());
+                out.write_all(&block[..take])?;
+                left -= take;
+            }
+        }
+        out.set_times(FileTimes::new().set_modified(version.modified()))?;
+        out.sync_all()?;
+        Ok(Some(version))
+    }
+
+    // Removes the shards of `key`, and the dirs that leaves empty
+    fn remove(&self, key: &str) {
+        for (index, dir) in self.dirs.iter().enumerate() {
+            let Ok(shard) = self.shard_path(index, key) else {
+                continue;
+            };
+            if fs::remove_file(&shard).is_err() {
+                continue;
+            }
+            let mut parent = shard.parent();
+            while let Some(current) = parent
+                && current != dir
+                && fs::remove_dir(current).is_ok()
+            {
+                parent = current.parent();
+            }
+        }
+    }
+
+    // The keys of every shard in any of the dirs
+    fn shard_keys(&self) -> HashSet<String> {
+        let mut found = HashSet::new();
+        for dir in &self.dirs {
+            let mut pending = vec![dir.clone()];
+            while let Some(current) = pending.pop() {
+                let Ok(entries) = fs::read_dir(&current) else {
+                    continue;
+                };
+                for entry in entries.flatten() {
+                    if current == *dir && entry.file_name() == INTERNAL_DIR {
+                        continue;
+                    }
+                    let Ok(file_type) = entry.file_type() else {
+                        continue;
+                    };
+                    if file_type.is_dir() {
+                        pending.push(entry.path());
+                    } else if file_type.is_file()
+                        && let Ok(rel) = entry.path().strip_prefix(dir)
+                    {
+                        found.insert(keys::key_of(rel));
+                    }
+                }
+            }
+        }
+        found
+    }
+}
+
+// Writes the shards of the object just written to `key`, if there's an
+// erasure set
+pub async fn protect(state: &AppState, key: &str) -> io::Result<()> {
+    let Some(set) = state.erasure.clone() else {
+        return Ok(());
+    };
+    let key = normalize::stored_key(state, key).into_owned();
+    let path = object_path(state, &key).map_err(|_| io::Error::other("invalid key"))?;
+    tokio::task::spawn_blocking(move || set.encode_current(&path, &key))
+        .await
+        .map_err(io::Error::other)?
+}
+
+// Writes the shards of objects written over the S3 API before the response
+// goes out
+pub async fn protect_writes(
+    State(state): State<Arc<AppState>>,
+    request: Request,
+    next: Next,
+) -> Response {
+    let method = request.method().clone();
+    if state.erasure.is_none() || !matches!(method, Method::PUT | Method::POST) {
+        return next.run(request).await;
+    }
+    let upload_step = multipart::is_upload_step(&method, request.uri().query());
+    let key = percent_decode_str(request.uri().path().trim_start_matches('/'))
+        .decode_utf8_lossy()
+        .to_string();
+
+    let response = next.run(request).await;
+
+    if upload_step || !response.status().is_success() || object_path(&state, &key).is_err() {
+        return response;
+    }
+    match protect(&state, &key).await {
+        Ok(()) => response,
+        Err(e) => {
+            warn!("🧩 Failed to write the shards of {}: {}", key, e);
+            StatusCode::INTERNAL_SERVER_ERROR.into_response()
+        }
+    }
+}
+
+// Brings back every object the data dir doesn't have from the shards
+async fn restore_all(state: &AppState, set: &Arc<ErasureSet>) {
+    let keys = {
+        let set = set.clone();
+        tokio::task::spawn_blocking(move || set.shard_keys())
+            .await
+            .unwrap_or_default()
+    };
+    info!("🧩 Data dir is new, restoring {} objects from the erasure set", keys.len());
+
+    let mut restored = 0;
+    for key in keys {
+        let Ok(path) = object_path(state, &key) else {
+            continue;
+        };
+        if tokio::fs::try_exists(&path).await.unwrap_or(true) {
+            continue;
+        }
+        let tmp = tmp_path(state);
+        let rebuilt = {
+            let (set, key, tmp) = (set.clone(), key.clone(), tmp.clone());
+            tokio::task::spawn_blocking(move || set.restore(&key, &tmp))
+                .await
+                .map_err(io::Error::other)
+                .and_then(|rebuilt| rebuilt)
+        };
+        let moved = match rebuilt {
+            Ok(Some(version)) => {
+                let moved = async {
+                    if let Some(parent) = path.parent() {
+                        tokio::fs::create_dir_all(parent).await?;
+                    }
+                    tokio::fs::rename(&tmp, &path).await
+                }
+                .await;
+                moved.map(|()| Some(version))
+            }
+            other => other,
+        };
+        match moved {
+            Ok(Some(version)) => {
+                state.stats.replaced(None, Some(version.len));
+                restored += 1;
+            }
+            Ok(None) => warn!("🧩 Too few shards left to restore {}", key),
+            Err(e) => warn!("🧩 Failed to restore {}: {}", key, e),
+        }
+        let _ = tokio::fs::remove_file(&tmp).await;
+    }
+    info!("🧩 Restored {} objects from the erasure set", restored);
+}
+
+// Encodes objects whose shards are missing or out of date, and removes the
+// shards of deleted objects. A full pass checks every shard on disk, the
+// others only objects that changed since the last pass
+async fn pass(state: &AppState, set: &Arc<ErasureSet>, full: bool) -> usize {
+    let mut objects = HashMap::new();
+    walk::walk(state, state.data_dir.clone(), |key, metadata| {
+        if let Some(version) = Version::of(&metadata) {
+            objects.insert(key, version);
+        }
+    })
+    .await;
+    if full {
+        set.encoded.lock().unwrap().clear();
+    }
+
+    let mut encoded = 0;
+    for (key, version) in &objects {
+        if set.encoded.lock().unwrap().get(key) == Some(version) {
+            continue;
+        }
+        let Ok(path) = object_path(state, key) else {
+            continue;
+        };
+        let outcome = {
+            let (set, key, version) = (set.clone(), key.clone(), *version);
+            tokio::task::spawn_blocking(move || {
+                let stale = set.stale(&key, version);
+                if stale.is_empty() {
+                    return Ok(Outcome::Current);
+                }
+                set.encode(&path, &key, version, &stale)
+            })
+            .await
+            .map_err(io::Error::other)
+            .and_then(|outcome| outcome)
+        };
+        match outcome {
+            Ok(Outcome::Current) => {}
+            Ok(Outcome::Encoded) => encoded += 1,
+            Ok(Outcome::Changed) => continue,
+            Err(e) => {
+                warn!("🧩 Failed to encode {}: {}", key, e);
+                continue;
+            }
+        }
+        set.encoded.lock().unwrap().insert(key.clone(), *version);
+    }
+
+    let candidates: Vec<String> = if full {
+        let set = set.clone();
+        tokio::task::spawn_blocking(move || set.shard_keys())
+            .await
+            .unwrap_or_default()
+            .into_iter()
+            .collect()
+    } else {
+        set.encoded.lock().unwrap().keys().cloned().collect()
+    };
+    for key in candidates {
+        if objects.contains_key(&key) {
+            continue;
+        }
+        set.encoded.lock().unwrap().remove(&key);
+        let set = set.clone();
+        let _ = tokio::task::spawn_blocking(move || set.remove(&key)).await;
+    }
+    encoded
+}
+
+pub fn spawn(state: Arc<AppState>, set: Arc<ErasureSet>) {
+    tokio::spawn(async move {
+        for dir in &set.dirs {
+            let _ = tokio::fs::remove_dir_all(dir.join(TMP_DIR)).await;
+        }
+        if !marked(&state.data_dir) && set.dirs.iter().any(|dir| marked(dir)) {
+            restore_all(&state, &set).await;
+        }
+        if let Err(e) = mark(&state.data_dir) {
+            warn!("🧩 Failed to mark {}: {}", state.data_dir.display(), e);
+        }
+
+        let mut interval = tokio::time::interval(PASS_INTERVAL);
+        let mut full = true;
+        loop {
+            interval.tick().await;
+            // Without its marker the data dir isn't the one the shards were
+            // made from, or its disk is gone, and every shard would look
+            // like it belongs to a deleted object
+            if !marked(&state.data_dir) {
+                warn!("🧩 {} is missing from the data dir, skipping erasure coding", MARKER_FILE);
+                continue;
+            }
+            let fresh: Vec<&PathBuf> = set.dirs.iter().filter(|dir| !marked(dir)).collect();
+            full |= !fresh.is_empty();
+
+            let encoded = pass(&state, &set, full).await;
+            if encoded > 0 {
+                info!("🧩 Encoded {} objects across {} dirs", encoded, set.dirs.len());
+            }
+            for dir in fresh {
+                if let Err(e) = mark(dir) {
+                    warn!("🧩 Failed to mark {}: {}", dir.display(), e);
+                }
+            }
+            full = false;
+        }
+    });
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    // An erasure set of 4 data and 2 parity shards, and the object it
+    // protects, under a fresh temp dir
+    struct Fixture {
+        root: PathBuf,
+        set: ErasureSet,
+        object: PathBuf,
+    }
+
+    impl Fixture {
+        fn new(contents: &[u8]) -> Self {
+            let root = std::env::temp_dir().join(format!("erasure-{}", uuid::Uuid::new_v4()));
+            let dirs: Vec<PathBuf> = (0..6).map(|i| root.join(format!("disk{}", i))).collect();
+            let object = root.join("data").join("object.bin");
+            fs::create_dir_all(object.parent().unwrap()).unwrap();
+            fs::write(&object, contents).unwrap();
+            Self {
+                set: ErasureSet::new(dirs, 2).unwrap(),
+                root,
+                object,
+            }
+        }
+    }
+
+    impl Drop for Fixture {
+        fn drop(&mut self) {
+            let _ = fs::remove_dir_all(&self.root);
+        }
+    }
+
+    // Spans three full stripes and part of a fourth, whose last block is short
+    fn contents() -> Vec<u8> {
+        (0..3 * 4 * BLOCK + 123).map(|i| (i * 7 % 251) as u8).collect()
+    }
+
+    #[test]
+    fn encoding_writes_a_current_shard_to_every_dir() {
+        let fixture = Fixture::new(&contents());
+        let set = &fixture.set;
+
+        set.encode_current(&fixture.object, "dir/object.bin").unwrap();
+
+        let version = Version::of(&fs::metadata(&fixture.object).unwrap()).unwrap();
+        assert!(set.stale("dir/object.bin", version).is_empty());
+        // Every shard holds a quarter of the object, rounded up per stripe
+        for index in 0..6 {
+            let shard = set.shard_path(index, "dir/object.bin").unwrap();
+            let len = fs::metadata(shard).unwrap().len() as usize;
+            assert_eq!(len, HEADER_LEN + 3 * BLOCK + 123usize.div_ceil(4));
+        }
+        assert_eq!(set.encoded.lock().unwrap().get("dir/object.bin"), Some(&version));
+    }
+
+    #[test]
+    fn changing_the_object_leaves_its_shards_stale() {
+        let fixture = Fixture::new(&contents());
+        let set = &fixture.set;
+        set.encode_current(&fixture.object, "object.bin").unwrap();
+
+        fs::write(&fixture.object, b"rewritten").unwrap();
+        let version = Version::of(&fs::metadata(&fixture.object).unwrap()).unwrap();
+        assert_eq!(set.stale("object.bin", version), (0..6).collect::<Vec<_>>());
+
+        set.encode_current(&fixture.object, "object.bin").unwrap();
+        assert!(set.stale("object.bin", version).is_empty());
+    }
+
+    #[test]
+    fn losing_up_to_parity_shards_restores_the_object() {
+        let contents = contents();
+        let fixture = Fixture::new(&contents);
+        let set = &fixture.set;
+        set.encode_current(&fixture.object, "object.bin").unwrap();
+        let modified = fs::metadata(&fixture.object).unwrap().modified().unwrap();
+
+        // A data shard and a parity shard
+        fs::remove_file(set.shard_path(1, "object.bin").unwrap()).unwrap();
+        fs::remove_file(set.shard_path(5, "object.bin").unwrap()).unwrap();
+        fs::remove_file(&fixture.object).unwrap();
+
+        let version = set.restore("object.bin", &fixture.object).unwrap();
+        assert!(version.is_some());
+        assert_eq!(fs::read(&fixture.object).unwrap(), contents);
+        assert_eq!(fs::metadata(&fixture.object).unwrap().modified().unwrap(), modified);
+    }
+
+    #[test]
+    fn a_shard_from_another_version_counts_as_lost() {
+        let contents = contents();
+        let fixture = Fixture::new(&contents);
+        let set = &fixture.set;
+        set.encode_current(&fixture.object, "object.bin").unwrap();
+
+        // Two shards lost and a third overwritten by a different object
+        fs::remove_file(set.shard_path(0, "object.bin").unwrap()).unwrap();
+        fs::remove_file(set.shard_path(2, "object.bin").unwrap()).unwrap();
+        let other = fixture.root.join("other.bin");
+        fs::write(&other, b"something else").unwrap();
+        set.encode_current(&other, "other.bin").unwrap();
+        fs::copy(
+            set.shard_path(3, "other.bin").unwrap(),
+            set.shard_path(3, "object.bin").unwrap(),
+        )
+        .unwrap();
+
+        let restored = fixture.root.join("restored.bin");
+        assert_eq!(set.restore("object.bin", &restored).unwrap(), None);
+    }
+
+    #[test]
+    fn losing_more_than_parity_shards_restores_nothing() {
+        let fixture = Fixture::new(&contents());
+        let set = &fixture.set;
+        set.encode_current(&fixture.object, "object.bin").unwrap();
+
+        for index in [0, 3, 4] {
+            fs::remove_file(set.shard_path(index, "object.bin").unwrap()).unwrap();
+        }
+        let restored = fixture.root.join("restored.bin");
+        assert_eq!(set.restore("object.bin", &restored).unwrap(), None);
+        assert!(!restored.exists());
+    }
+}
@@ -0,0 +1,102 @@
+use serde::{Deserialize, Serialize};
+use sha2::{Digest, Sha256};
+use std::{
+    io::{self, Read},
+    path::{Path, PathBuf},
+};
+use tokio::fs;
+use tracing::warn;
+
+use crate::{AppState, object_stamp, tier, tmp_path, write_and_rename};
+
+// ETags. An object's ETag is the SHA-256 of its contents, quoted, whichever
+// request gives it out: GET and PUT, HEAD, listings, copies, conditional
+// requests and version listings all agree, so a client can send back the
+// one it got from any of them.
+//
+// Hashing a whole object for every HEAD or listing entry would be slow, so
+// writes keep the ETag under ETAGS_DIR, one file per key stamped with the
+// object's modification time and size, as checksums are. An object changed
+// some other way, through WebDAV, SFTP or the data directory itself, no
+// longer matches its stamp and is hashed again the next time it's asked for.
+
+pub const ETAGS_DIR: &str = ".simple-s3/etags";
+
+#[derive(Serialize, Deserialize)]
+struct Stored {
+    stamp: String,
+    etag: String,
+}
+
+fn etag_path(state: &AppState, key: &str) -> PathBuf {
+    state
+        .data_dir
+        .join(ETAGS_DIR)
+        .join(hex::encode(Sha256::digest(key.as_bytes())))
+}
+
+// The ETag of `data`
+pub fn of_bytes(data: &[u8]) -> String {
+    format!("\"{}\"", hex::encode(Sha256::digest(data)))
+}
+
+// The ETag of the file at `path`, read a buffer at a time so a large object
+// isn't held in memory
+pub async fn hash_file(state: &AppState, path: &Path) -> io::Result<String> {
+    let path = path.to_path_buf();
+    let read_buffer = state.read_buffer;
+    tokio::task::spawn_blocking(move || {
+        let mut file = std::fs::File::open(path)?;
+        let mut hasher = Sha256::new();
+        let mut buf = vec![0; read_buffer];
+        loop {
+            let n = file.read(&mut buf)?;
+            if n == 0 {
+                break;
+            }
+            hasher.update(&buf[..n]);
+        }
+        Ok(format!("\"{}\"", hex::encode(hasher.finalize())))
+    })
+    .await
+    .map_err(io::Error::other)?
+}
+
+// Keeps `etag` as that of the object just stored at `file_path`
+pub async fn store(state: &AppState, key: &str, file_path: &Path, etag: &str) {
+    let tmp = tmp_path(state);
+    let stored = async {
+        let metadata = fs::metadata(file_path).await?;
+        let stored = Stored {
+            stamp: object_stamp(metadata.modified()?, metadata.len()),
+            etag: etag.to_string(),
+        };
+        let json = serde_json::to_vec(&stored).map_err(io::Error::other)?;
+        fs::create_dir_all(state.data_dir.join(ETAGS_DIR)).await?;
+        write_and_rename(state, &tmp, &etag_path(state, key), &json).await
+    }
+    .await;
+    if let Err(e) = stored {
+        let _ = fs::remove_file(&tmp).await;
+        warn!("Failed to keep the ETag of {}: {}", key, e);
+    }
+}
+
+// The ETag of the object of `key` at `file_path`, the one kept for it if
+// it's still the object it was taken of, or else hashed and kept
+pub async fn of_file(state: &AppState, key: &str, file_path: &Path) -> io::Result<String> {
+    let metadata = fs::metadata(file_path).await?;
+    let stamp = object_stamp(metadata.modified()?, metadata.len());
+    if let Ok(data) = fs::read(etag_path(state, key)).await
+        && let Ok(stored) = serde_json::from_slice::<Stored>(&data)
+        && stored.stamp == stamp
+    {
+        return Ok(stored.etag);
+    }
+
+    // A tiered object's stub has the size and time but not the data
+    tier::recall(state, key, file_path).await?;
+    let etag = hash_file(state, file_path).await?;
+    store(state, key, file_path, &etag).await;
+    Ok(etag)
+}
@@ -0,0 +1,184 @@
+use axum::{
+    body::{Body, Bytes},
+    http::{HeaderMap, HeaderValue, StatusCode},
+    response::{IntoResponse, Response},
+};
+use std::{io, sync::Arc, time::UNIX_EPOCH};
+use tokio::{fs, io::AsyncReadExt, sync::mpsc};
+use tracing::{info, warn};
+
+use crate::{AppState, object_path, tier, walk};
+
+// GET /?export&prefix=photos/2024/ streams every object under the prefix as
+// a tar archive, named by key and carrying each object's mtime, so a folder
+// can be fetched in one request. Objects are read one at a time while the
+// archive is sent, nothing is staged. An object that shrinks while being
+// sent ends the response with an error rather than a corrupt archive.
+
+const BLOCK: usize = 512;
+// Longest name a ustar header holds, longer ones get a GNU long name entry
+const NAME_LEN: usize = 100;
+
+// Writes `value` as a NUL terminated octal field filling `field`
+fn octal(field: &mut [u8], value: u64) {
+    let digits = format!("{:0width$o}", value, width = field.len() - 1);
+    field[..digits.len()].copy_from_slice(digits.as_bytes());
+    field[digits.len()] = 0;
+}
+
+// Sizes past the 11 octal digits of a ustar field (8 GiB) are stored in
+// base-256, as GNU tar and every modern reader understand
+fn size_field(field: &mut [u8], size: u64) {
+    if size < 1 << 33 {
+        octal(field, size);
+    } else {
+        field.fill(0);
+        field[0] = 0x80;
+        let len = field.len();
+        field[len - 8..].copy_from_slice(&size.to_be_bytes());
+    }
+}
+
+fn header(name: &[u8], size: u64, mtime: u64, kind: u8) -> [u8; BLOCK] {
+    let mut block = [0u8; BLOCK];
+    let name_len = name.len().min(NAME_LEN);
+    block[..name_len].copy_from_slice(&name[..name_len]);
+    octal(&mut block[100..108], 0o644);
+    octal(&mut block[108..116], 0);
+    octal(&mut block[116..124], 0);
+    size_field(&mut block[124..136], size);
+    octal(&mut block[136..148], mtime);
+    block[156] = kind;
+    block[257..263].copy_from_slice(b"ustar\0");
+    block[263..265].copy_from_slice(b"00");
+
+    // The checksum is taken with its own field as spaces
+    block[148..156].fill(b' ');
+    let sum: u64 = block.iter().map(|b| *b as u64).sum();
+    octal(&mut block[148..155], sum);
+    block
+}
+
+fn padding(len: u64) -> usize {
+    (BLOCK - (len % BLOCK as u64) as usize) % BLOCK
+}
+
+// Header blocks for the object `key`, led by a GNU long name entry when the
+// key doesn't fit in the header
+fn headers(key: &str, size: u64, mtime: u64) -> Vec<u8> {
+    let name = key.as_bytes();
+    let mut out = Vec::with_capacity(BLOCK * 2);
+    if name.len() > NAME_LEN {
+        out.extend_from_slice(&header(b"././@LongLink", name.len() as u64 + 1, 0, b'L'));
+        out.extend_from_slice(name);
+        out.push(0);
+        out.resize(out.len() + padding(name.len() as u64 + 1), 0);
+    }
+    out.extend_from_slice(&header(name, size, mtime, b'0'));
+    out
+}
+
+async fn send_object(
+    state: &AppState,
+    key: &str,
+    tx: &mpsc::Sender<io::Result<Bytes>>,
+) -> io::Result<()> {
+    let Ok(path) = object_path(state, key) else {
+        return Ok(());
+    };
+    tier::recall(state, key, &path).await?;
+    let mut file = match fs::File::open(&path).await {
+        Ok(file) => file,
+        // Deleted since the walk
+        Err(e) if e.kind() == io::ErrorKind::NotFound => return Ok(()),
+        Err(e) => return Err(e),
+    };
+    let metadata = file.metadata().await?;
+    let size = metadata.len();
+    let mtime = metadata
+        .modified()
+        .ok()
+        .and_then(|modified| modified.duration_since(UNIX_EPOCH).ok())
+        .map_or(0, |since| since.as_secs());
+
+    let send = |chunk: Vec<u8>| async move {
+        tx.send(Ok(Bytes::from(chunk)))
+            .await
+            .map_err(|_| io::Error::from(io::ErrorKind::BrokenPipe))
+    };
+    send(headers(key, size, mtime)).await?;
+
+    let mut left = size;
+    while left > 0 {
+        let mut chunk = vec![0; state.read_buffer.min(left as usize)];
+        let n = file.read(&mut chunk).await?;
+        if n == 0 {
+            return Err(io::Error::new(
+                io::ErrorKind::UnexpectedEof,
+                format!("{} shrank while being exported", key),
+            ));
+        }
+        chunk.truncate(n);
+        left -= n as u64;
+        send(chunk).await?;
+    }
+    send(vec![0; padding(size)]).await
+}
+
+// Name for the downloaded archive, "bucket-photos-2024.tar"
+fn file_name(bucket: &str, prefix: &str) -> String {
+    let prefix: String = prefix
+        .trim_matches('/')
+        .chars()
+        .map(|c| if c.is_ascii_alphanumeric() || c == '.' || c == '_' { c } else { '-' })
+        .collect();
+    if prefix.is_empty() {
+        format!("{}.tar", bucket)
+    } else {
+        format!("{}-{}.tar", bucket, prefix)
+    }
+}
+
+pub async fn export_prefix(state: Arc<AppState>, prefix: String) -> Result<Response, StatusCode> {
+    let mut keys = Vec::new();
+    if let Some(start) = walk::start_dir(&state, &prefix) {
+        walk::walk(&state, start, |key, _| {
+            if key.starts_with(&prefix) {
+                keys.push(key);
+            }
+        })
+        .await;
+    }
+    keys.sort();
+
+    let mut headers = HeaderMap::new();
+    headers.insert("content-type", HeaderValue::from_static("application/x-tar"));
+    if let Ok(disposition) = HeaderValue::from_str(&format!(
+        "attachment; filename=\"{}\"",
+        file_name(&state.bucket_name, &prefix)
+    )) {
+        headers.insert("content-disposition", disposition);
+    }
+    info!("📦 Exporting {} objects under '{}'", keys.len(), prefix);
+
+    let (tx, rx) = mpsc::channel(4);
+    tokio::spawn(async move {
+        for key in &keys {
+            if let Err(e) = send_object(&state, key, &tx).await {
+                // Gone client, nothing left to tell
+                if e.kind() != io::ErrorKind::BrokenPipe {
+                    warn!("Export of '{}' failed at {}: {}", prefix, key, e);
+                    let _ = tx.send(Err(e)).await;
+                }
+                return;
+            }
+        }
+        // End of archive
+        let _ = tx.send(Ok(Bytes::from(vec![0; BLOCK * 2]))).await;
+    });
+
+    let body = futures_util::stream::unfold(rx, |mut rx| async move {
+        rx.recv().await.map(|chunk| (chunk, rx))
+    });
+    Ok((headers, Body::from_stream(body)).into_response())
+}
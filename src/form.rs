@@ -0,0 +1,1019 @@
+use axum::{
+    body::{Body, BodyDataStream, Bytes},
+    extract::{Path, Query, Request, State},
+    http::{HeaderMap, HeaderName, HeaderValue, Method, StatusCode, header},
+    response::{IntoResponse, Response},
+};
+use base64::{Engine, engine::general_purpose::STANDARD};
+use chrono::{DateTime, Utc};
+use hmac::{KeyInit, Mac};
+use serde::{Deserialize, Serialize};
+use serde_json::Value;
+use sha1::{Digest, Sha1};
+use futures_util::StreamExt;
+use std::{
+    io,
+    sync::{Arc, Mutex},
+};
+use tracing::{info, warn};
+
+use crate::{
+    AppState, ErrorResponse, HmacSha256, Keys, PutObjectQuery, authlog, erasure, error_response,
+    object_path, pathstyle, policy, put_object, record_put, signing_key, stats, tagging,
+};
+
+// Browser uploads, S3's POST Object. A POST to the bucket with a
+// multipart/form-data body stores its `file` field as the object named by
+// its `key` field, with `${filename}` in the key replaced by the name of the
+// uploaded file. Fields after `file` are ignored.
+//
+// A form carries its own credentials, so it goes past the auth middleware:
+// `policy` is a base64 JSON policy document with an expiration and the
+// conditions the other fields must meet, signed either with SigV4
+// (x-amz-algorithm, x-amz-credential, x-amz-date and x-amz-signature) or
+// with the legacy HMAC-SHA1 fields AWSAccessKeyId and signature. Every field
+// but those, `file` and x-ignore-* must be named by a condition. A form
+// without a policy is only taken when the bucket policy lets anyone put the
+// key, like an anonymous PUT.
+//
+// The body is streamed: fields before `file` are read into memory, up to
+// MAX_FIELDS_SIZE, and the file goes to the PUT as it arrives, cut off once
+// it is over the policy's content-length-range or --max-body-size.
+//
+// The upload goes through the same checks as a PUT, with `acl`, `tagging`,
+// x-amz-grant-*, x-amz-object-lock-* and x-amz-checksum-* standing for
+// their headers. success_action_redirect (or the older `redirect`) answers
+// with a 303 to that URL with the bucket, key and ETag added to its query,
+// and success_action_status picks 200, 201 with a PostResponse document or
+// the default 204.
+
+const SIGV4_ALGORITHM: &str = "AWS4-HMAC-SHA256";
+// Fields before the file are held in memory, up to S3's limit on them
+const MAX_FIELDS_SIZE: usize = 20 * 1024;
+const XMLNS: &str = "http://s3.amazonaws.com/doc/2006-03-01/";
+
+// What a form couldn't be stored for
+struct Rejected {
+    status: StatusCode,
+    code: &'static str,
+    message: String,
+}
+
+impl Rejected {
+    fn new(status: StatusCode, code: &'static str, message: impl Into<String>) -> Self {
+        Rejected {
+            status,
+            code,
+            message: message.into(),
+        }
+    }
+
+    fn response(self) -> Response {
+        error_response(
+            self.status,
+            ErrorResponse {
+                code: self.code.to_string(),
+                message: self.message,
+                max_size_allowed: None,
+            },
+        )
+    }
+}
+
+fn malformed() -> Rejected {
+    Rejected::new(
+        StatusCode::BAD_REQUEST,
+        "MalformedPOSTRequest",
+        "The body of your POST request is not well-formed multipart/form-data.",
+    )
+}
+
+fn invalid_argument(message: &str) -> Rejected {
+    Rejected::new(StatusCode::BAD_REQUEST, "InvalidArgument", message)
+}
+
+fn invalid_policy(message: &str) -> Rejected {
+    Rejected::new(
+        StatusCode::BAD_REQUEST,
+        "InvalidPolicyDocument",
+        format!("Invalid Policy: {}", message),
+    )
+}
+
+fn against_policy(message: &str) -> Rejected {
+    Rejected::new(
+        StatusCode::FORBIDDEN,
+        "AccessDenied",
+        format!("Invalid according to Policy: {}", message),
+    )
+}
+
+// The boundary of a multipart/form-data body
+fn boundary(headers: &HeaderMap) -> Option<String> {
+    let content_type = headers.get(header::CONTENT_TYPE)?.to_str().ok()?;
+    let (mime, params) = content_type.split_once(';')?;
+    if !mime.trim().eq_ignore_ascii_case("multipart/form-data") {
+        return None;
+    }
+    params.split(';').find_map(|param| {
+        let (name, value) = param.split_once('=')?;
+        name.trim()
+            .eq_ignore_ascii_case("boundary")
+            .then(|| value.trim().trim_matches('"').to_string())
+            .filter(|boundary| !boundary.is_empty())
+    })
+}
+
+// Whether `request` is a browser upload, which the auth middleware leaves to
+// the form's own credentials
+pub fn is_upload(request: &Request) -> bool {
+    request.method() == Method::POST
+        && request.uri().path() == "/"
+        && request.uri().query().is_none()
+        && request.extensions().get::<pathstyle::ServiceRequest>().is_none()
+        && boundary(request.headers()).is_some()
+}
+
+pub fn is_form(headers: &HeaderMap) -> bool {
+    boundary(headers).is_some()
+}
+
+// The fields of a form before its file, with lowercased names, and the
+// file's name
+struct Form {
+    fields: Vec<(String, String)>,
+    filename: String,
+}
+
+impl Form {
+    fn field(&self, name: &str) -> Option<&str> {
+        self.fields
+            .iter()
+            .find(|(field, _)| field == name)
+            .map(|(_, value)| value.as_str())
+    }
+}
+
+fn find(haystack: &[u8], needle: &[u8], from: usize) -> Option<usize> {
+    haystack
+        .get(from..)?
+        .windows(needle.len())
+        .position(|window| window == needle)
+        .map(|at| from + at)
+}
+
+// The name and filename in a part's Content-Disposition
+fn disposition(head: &str) -> Option<(String, Option<String>)> {
+    let line = head.split("\r\n").find(|line| {
+        line.split_once(':')
+            .is_some_and(|(name, _)| name.trim().eq_ignore_ascii_case("content-disposition"))
+    })?;
+    let (_, value) = line.split_once(':')?;
+    let mut name = None;
+    let mut filename = None;
+    for param in value.split(';').skip(1) {
+        let Some((param, value)) = param.split_once('=') else {
+            continue;
+        };
+        let value = value.trim().trim_matches('"').to_string();
+        match param.trim().to_ascii_lowercase().as_str() {
+            "name" => name = Some(value),
+            "filename" => filename = Some(value),
+            _ => {}
+        }
+    }
+    Some((name?, filename))
+}
+
+// The delimiter between parts, which ends the file's content
+fn delimiter(boundary: &str) -> Vec<u8> {
+    format!("\r\n--{}", boundary).into_bytes()
+}
+
+// The fields before the `file` part and where its content starts in `body`,
+// or None while `body` doesn't hold all of them yet
+fn parse_fields(body: &[u8], delimiter: &[u8]) -> Result<Option<(Form, usize)>, Rejected> {
+    // The first delimiter may start the body, without the line break
+    let first = match body.starts_with(&delimiter[2..]) {
+        true => Some(delimiter.len() - 2),
+        false => find(body, delimiter, 0).map(|at| at + delimiter.len()),
+    };
+    let Some(mut at) = first else {
+        return Ok(None);
+    };
+
+    let mut fields = Vec::new();
+    loop {
+        match body.get(at..at + 2) {
+            None => return Ok(None),
+            Some(b"--") => {
+                return Err(invalid_argument("POST requires exactly one file upload per request."));
+            }
+            Some(_) => {}
+        }
+        let Some(line_end) = find(body, b"\r\n", at) else {
+            return Ok(None);
+        };
+        at = line_end + 2;
+        let Some(head_end) = find(body, b"\r\n\r\n", at) else {
+            return Ok(None);
+        };
+        let head = String::from_utf8_lossy(&body[at..head_end]);
+        let (name, filename) = disposition(&head).ok_or_else(malformed)?;
+        let start = head_end + 4;
+
+        let name = name.to_ascii_lowercase();
+        if name == "file" {
+            // The base name, as browsers that send a full path don't mean it
+            let filename = filename.unwrap_or_default();
+            let filename = filename.rsplit(['/', '\\']).next().unwrap_or_default();
+            let form = Form {
+                fields,
+                filename: filename.to_string(),
+            };
+            return Ok(Some((form, start)));
+        }
+        let Some(end) = find(body, delimiter, start) else {
+            return Ok(None);
+        };
+        let value = String::from_utf8(body[start..end].to_vec()).map_err(|_| malformed())?;
+        fields.push((name, value));
+        at = end + delimiter.len();
+    }
+}
+
+// Reads the body up to the file's content. Returns the form, the part of the
+// file that came in with its fields and the rest of the body
+async fn read_fields(
+    mut body: BodyDataStream,
+    delimiter: &[u8],
+) -> Result<(Form, Bytes, BodyDataStream), Rejected> {
+    let mut read = Vec::new();
+    loop {
+        let parsed = parse_fields(&read, delimiter)?;
+        let fields_size = parsed.as_ref().map_or(read.len(), |(_, start)| *start);
+        if fields_size > MAX_FIELDS_SIZE {
+            return Err(Rejected::new(
+                StatusCode::BAD_REQUEST,
+                "MaxPostPreDataLengthExceeded",
+                "Your POST request fields preceeding the upload file were too large.",
+            ));
+        }
+        if let Some((form, start)) = parsed {
+            let file = Bytes::from(read).slice(start..);
+            return Ok((form, file, body));
+        }
+        match body.next().await {
+            Some(Ok(chunk)) => read.extend_from_slice(&chunk),
+            Some(Err(_)) | None => return Err(malformed()),
+        }
+    }
+}
+
+// The sizes a policy's content-length-range lets the file have
+#[derive(Clone, Copy, Debug, PartialEq)]
+struct Sizes {
+    min: u64,
+    max: u64,
+}
+
+impl Default for Sizes {
+    fn default() -> Self {
+        Sizes {
+            min: 0,
+            max: u64::MAX,
+        }
+    }
+}
+
+fn too_large() -> Rejected {
+    Rejected::new(
+        StatusCode::BAD_REQUEST,
+        "EntityTooLarge",
+        "Your proposed upload exceeds the maximum allowed size",
+    )
+}
+
+fn too_small() -> Rejected {
+    Rejected::new(
+        StatusCode::BAD_REQUEST,
+        "EntityTooSmall",
+        "Your proposed upload is smaller than the minimum allowed size",
+    )
+}
+
+// Why a file stream failed, for the upload to answer with
+type Failure = Arc<Mutex<Option<Rejected>>>;
+
+// The file's content as the rest of the body arrives, up to the delimiter
+// after it. Holds back as many bytes as could start the delimiter
+struct FileReader {
+    body: BodyDataStream,
+    pending: Bytes,
+    delimiter: Vec<u8>,
+    sizes: Sizes,
+    received: u64,
+    done: bool,
+    failure: Failure,
+}
+
+impl FileReader {
+    async fn next(&mut self) -> Option<Result<Bytes, axum::Error>> {
+        loop {
+            if self.done {
+                return None;
+            }
+            if let Some(end) = find(&self.pending, &self.delimiter, 0) {
+                let chunk = self.pending.split_to(end);
+                self.done = true;
+                let chunk = self.count(chunk);
+                return Some(match self.received < self.sizes.min {
+                    true => self.fail(too_small()),
+                    false => chunk,
+                });
+            }
+            let held = self.delimiter.len() - 1;
+            if self.pending.len() > held {
+                let chunk = self.pending.split_to(self.pending.len() - held);
+                return Some(self.count(chunk));
+            }
+            match self.body.next().await {
+                Some(Ok(chunk)) => {
+                    let mut pending = Vec::with_capacity(self.pending.len() + chunk.len());
+                    pending.extend_from_slice(&self.pending);
+                    pending.extend_from_slice(&chunk);
+                    self.pending = pending.into();
+                }
+                Some(Err(e)) => {
+                    self.done = true;
+                    return Some(Err(e));
+                }
+                None => return Some(self.fail(malformed())),
+            }
+        }
+    }
+
+    fn count(&mut self, chunk: Bytes) -> Result<Bytes, axum::Error> {
+        self.received += chunk.len() as u64;
+        match self.received > self.sizes.max {
+            true => self.fail(too_large()),
+            false => Ok(chunk),
+        }
+    }
+
+    fn fail(&mut self, rejected: Rejected) -> Result<Bytes, axum::Error> {
+        let error = io::Error::other(rejected.message.clone());
+        *self.failure.lock().unwrap() = Some(rejected);
+        self.done = true;
+        Err(axum::Error::new(error))
+    }
+}
+
+// The file's content from `file` on, as a body. A file outside `sizes` or a
+// body that ends before the file does fail the body, and leave why in
+// `failure`
+fn file_body(
+    file: Bytes,
+    body: BodyDataStream,
+    delimiter: Vec<u8>,
+    sizes: Sizes,
+    failure: Failure,
+) -> Body {
+    let reader = FileReader {
+        body,
+        pending: file,
+        delimiter,
+        sizes,
+        received: 0,
+        done: false,
+        failure,
+    };
+    Body::from_stream(futures_util::stream::unfold(reader, |mut reader| async move {
+        let chunk = reader.next().await?;
+        Some((chunk, reader))
+    }))
+}
+
+#[derive(Deserialize)]
+struct Document {
+    expiration: Option<String>,
+    conditions: Option<Vec<Value>>,
+}
+
+// Fields that sign a form rather than describe the upload, which conditions
+// needn't name
+fn is_exempt(name: &str) -> bool {
+    matches!(
+        name,
+        "policy" | "x-amz-signature" | "awsaccesskeyid" | "signature" | "file"
+    ) || name.starts_with("x-ignore-")
+}
+
+// The value a condition on `name` is checked against
+fn value_of<'a>(bucket: &'a str, form: &'a Form, key: &'a str, name: &str) -> &'a str {
+    match name {
+        "bucket" => bucket,
+        "key" => key,
+        _ => form.field(name).unwrap_or_default(),
+    }
+}
+
+fn number(value: &Value) -> Option<u64> {
+    value
+        .as_u64()
+        .or_else(|| value.as_str().and_then(|value| value.trim().parse().ok()))
+}
+
+// Checks the decoded policy document against the form, for the final `key`
+// in `bucket`, and returns the sizes it allows the file
+fn check_policy(bucket: &str, form: &Form, key: &str, encoded: &str) -> Result<Sizes, Rejected> {
+    let document = STANDARD
+        .decode(encoded.trim())
+        .ok()
+        .and_then(|json| serde_json::from_slice::<Document>(&json).ok())
+        .ok_or_else(|| invalid_policy("Invalid JSON."))?;
+    let expiration = document
+        .expiration
+        .ok_or_else(|| invalid_policy("Policy missing expiration."))?;
+    let expiration = DateTime::parse_from_rfc3339(&expiration)
+        .map_err(|_| invalid_policy("Invalid 'expiration' value."))?;
+    let conditions = document
+        .conditions
+        .ok_or_else(|| invalid_policy("Policy missing conditions."))?;
+    if expiration < Utc::now() {
+        return Err(against_policy("Policy expired."));
+    }
+
+    let failed = |condition: &Value| {
+        against_policy(&format!("Policy Condition failed: {}", condition))
+    };
+    let mut named = Vec::new();
+    let mut sizes = Sizes::default();
+    for condition in &conditions {
+        match condition {
+            Value::Object(pairs) => {
+                for (name, expected) in pairs {
+                    let name = name.trim_start_matches('$').to_ascii_lowercase();
+                    let Some(expected) = expected.as_str() else {
+                        return Err(invalid_policy("Invalid Simple-Condition."));
+                    };
+                    if value_of(bucket, form, key, &name) != expected {
+                        return Err(failed(condition));
+                    }
+                    named.push(name);
+                }
+            }
+            Value::Array(parts) if parts.len() == 3 => {
+                let op = parts[0].as_str().unwrap_or_default().to_ascii_lowercase();
+                if op == "content-length-range" {
+                    let (Some(min), Some(max)) = (number(&parts[1]), number(&parts[2])) else {
+                        return Err(invalid_policy("Invalid content-length-range."));
+                    };
+                    // The file isn't read yet, so its size is checked as it streams
+                    sizes.min = sizes.min.max(min);
+                    sizes.max = sizes.max.min(max);
+                    continue;
+                }
+                let (Some(name), Some(expected)) = (parts[1].as_str(), parts[2].as_str()) else {
+                    return Err(invalid_policy("Invalid condition."));
+                };
+                let Some(name) = name.strip_prefix('$') else {
+                    return Err(invalid_policy("Condition field names must start with '$'."));
+                };
+                let name = name.to_ascii_lowercase();
+                let value = value_of(bucket, form, key, &name);
+                let holds = match op.as_str() {
+                    "eq" => value == expected,
+                    "starts-with" => value.starts_with(expected),
+                    _ => return Err(invalid_policy("Invalid condition operator.")),
+                };
+                if !holds {
+                    return Err(failed(condition));
+                }
+                named.push(name);
+            }
+            _ => return Err(invalid_policy("Invalid condition.")),
+        }
+    }
+
+    let extra: Vec<&str> = form
+        .fields
+        .iter()
+        .map(|(name, _)| name.as_str())
+        .filter(|name| !is_exempt(name) && !named.iter().any(|named| named == name))
+        .collect();
+    if !extra.is_empty() {
+        return Err(against_policy(&format!("Extra input fields: {}", extra.join(", "))));
+    }
+    Ok(sizes)
+}
+
+// HMAC-SHA1 of RFC 2104, for legacy signatures. The hmac crate's version
+// doesn't take sha1's digest
+fn hmac_sha1(key: &[u8], data: &[u8]) -> [u8; 20] {
+    let mut block = [0u8; 64];
+    match key.len() > block.len() {
+        true => block[..20].copy_from_slice(&Sha1::digest(key)),
+        false => block[..key.len()].copy_from_slice(key),
+    }
+    let mut inner = Sha1::new();
+    inner.update(block.map(|byte| byte ^ 0x36));
+    inner.update(data);
+    let mut outer = Sha1::new();
+    outer.update(block.map(|byte| byte ^ 0x5c));
+    outer.update(inner.finalize());
+    outer.finalize().into()
+}
+
+fn signature_mismatch() -> Rejected {
+    Rejected::new(
+        StatusCode::FORBIDDEN,
+        "SignatureDoesNotMatch",
+        "The request signature we calculated does not match the signature you provided. \
+Check your key and signing method.",
+    )
+}
+
+fn unknown_access_key() -> Rejected {
+    Rejected::new(
+        StatusCode::FORBIDDEN,
+        "InvalidAccessKeyId",
+        "The AWS Access Key Id you provided does not exist in our records.",
+    )
+}
+
+// The access key a form claims, to count failures against
+fn claimed_access_key(form: &Form) -> Option<&str> {
+    match form.field("x-amz-credential") {
+        Some(credential) => credential.split('/').next(),
+        None => form.field("awsaccesskeyid"),
+    }
+}
+
+// Checks the signature of the form's `policy` field
+fn verify_signature(keys: &Keys, form: &Form, encoded: &str) -> Result<(), Rejected> {
+    if let Some(signature) = form.field("x-amz-signature") {
+        if form.field("x-amz-algorithm") != Some(SIGV4_ALGORITHM) {
+            return Err(invalid_argument("Only AWS4-HMAC-SHA256 is supported for x-amz-algorithm"));
+        }
+        let credential = form.field("x-amz-credential").unwrap_or_default();
+        let parts: Vec<&str> = credential.split('/').collect();
+        let [access_key, date, region, service, "aws4_request"] = parts[..] else {
+            return Err(invalid_argument("Invalid x-amz-credential"));
+        };
+        if access_key != keys.access_key {
+            return Err(unknown_access_key());
+        }
+        let mut mac = HmacSha256::new_from_slice(&signing_key(
+            keys.secret_key,
+            date,
+            region,
+            service,
+        ))
+        .unwrap();
+        mac.update(encoded.as_bytes());
+        return match hex::encode(mac.finalize().into_bytes()) == signature {
+            true => Ok(()),
+            false => Err(signature_mismatch()),
+        };
+    }
+
+    let Some(access_key) = form.field("awsaccesskeyid") else {
+        return Err(invalid_argument(
+            "Bucket POST must contain a field named 'AWSAccessKeyId'.  \
+If it is specified, please check the order of the fields.",
+        ));
+    };
+    let Some(signature) = form.field("signature") else {
+        return Err(invalid_argument(
+            "Bucket POST must contain a field named 'Signature'.  \
+If it is specified, please check the order of the fields.",
+        ));
+    };
+    if access_key != keys.access_key {
+        return Err(unknown_access_key());
+    }
+    let calculated = STANDARD.encode(hmac_sha1(keys.secret_key.as_bytes(), encoded.as_bytes()));
+    match calculated == signature {
+        true => Ok(()),
+        false => Err(signature_mismatch()),
+    }
+}
+
+// The headers of a PUT the form's fields stand for
+fn put_headers(form: &Form) -> Result<HeaderMap, Rejected> {
+    let mut headers = HeaderMap::new();
+    for (name, value) in &form.fields {
+        let header = match name.as_str() {
+            "acl" => "x-amz-acl".to_string(),
+            "tagging" => {
+                let tags = tagging::header_value(value).ok_or_else(|| {
+                    Rejected::new(
+                        StatusCode::BAD_REQUEST,
+                        "MalformedXML",
+                        "The XML you provided was not well-formed or did not validate against \
+our published schema",
+                    )
+                })?;
+                let tags = HeaderValue::from_str(&tags).map_err(|_| malformed())?;
+                headers.insert("x-amz-tagging", tags);
+                continue;
+            }
+            "content-type" => name.clone(),
+            _ if name.starts_with("x-amz-grant-")
+                || name.starts_with("x-amz-object-lock-")
+                || name.starts_with("x-amz-checksum-") =>
+            {
+                name.clone()
+            }
+            _ => continue,
+        };
+        let header = HeaderName::from_bytes(header.as_bytes()).map_err(|_| malformed())?;
+        headers.insert(header, HeaderValue::from_str(value).map_err(|_| malformed())?);
+    }
+    Ok(headers)
+}
+
+#[derive(Serialize)]
+#[serde(rename = "PostResponse")]
+struct PostResponse {
+    #[serde(rename = "@xmlns")]
+    xmlns: &'static str,
+    #[serde(rename = "Location")]
+    location: String,
+    #[serde(rename = "Bucket")]
+    bucket: String,
+    #[serde(rename = "Key")]
+    key: String,
+    #[serde(rename = "ETag")]
+    etag: String,
+}
+
+// The answer to a stored upload, as its success_action_* fields ask
+fn answer(state: &AppState, form: &Form, key: &str, stored: Response) -> Response {
+    let mut headers = stored.headers().clone();
+    let etag = headers
+        .get("etag")
+        .and_then(|etag| etag.to_str().ok())
+        .unwrap_or_default()
+        .to_string();
+
+    // S3 falls back to the status when the redirect isn't a URL
+    let redirect = form
+        .field("success_action_redirect")
+        .or(form.field("redirect"))
+        .and_then(|redirect| url::Url::parse(redirect).ok());
+    if let Some(mut redirect) = redirect {
+        redirect
+            .query_pairs_mut()
+            .append_pair("bucket", &state.bucket_name)
+            .append_pair("key", key)
+            .append_pair("etag", &etag);
+        if let Ok(location) = HeaderValue::from_str(redirect.as_str()) {
+            headers.insert(header::LOCATION, location);
+            return (StatusCode::SEE_OTHER, headers).into_response();
+        }
+    }
+
+    let location = format!("/{}", key);
+    if let Ok(location) = HeaderValue::from_str(&location) {
+        headers.insert(header::LOCATION, location);
+    }
+    match form.field("success_action_status") {
+        Some("200") => (StatusCode::OK, headers).into_response(),
+        Some("201") => {
+            let document = PostResponse {
+                xmlns: XMLNS,
+                location,
+                bucket: state.bucket_name.clone(),
+                key: key.to_string(),
+                etag,
+            };
+            let Ok(xml) = serde_xml_rs::to_string(&document) else {
+                return StatusCode::INTERNAL_SERVER_ERROR.into_response();
+            };
+            headers.insert(header::CONTENT_TYPE, HeaderValue::from_static("application/xml"));
+            (StatusCode::CREATED, headers, xml).into_response()
+        }
+        _ => (StatusCode::NO_CONTENT, headers).into_response(),
+    }
+}
+
+// POST / with a multipart/form-data body
+pub async fn upload(
+    state: &Arc<AppState>,
+    caller: Option<&policy::Caller>,
+    headers: &HeaderMap,
+    body: Body,
+) -> Result<Response, StatusCode> {
+    let Some(boundary) = boundary(headers) else {
+        return Ok(malformed().response());
+    };
+    let delimiter = delimiter(&boundary);
+    let (form, file, body) = match read_fields(body.into_data_stream(), &delimiter).await {
+        Ok(read) => read,
+        Err(rejected) => return Ok(rejected.response()),
+    };
+    let Some(key) = form.field("key") else {
+        return Ok(invalid_argument(
+            "Bucket POST must contain a field named 'key'.  \
+If it is specified, please check the order of the fields.",
+        )
+        .response());
+    };
+    let key = key.replace("${filename}", &form.filename);
+    if key.is_empty() {
+        return Ok(invalid_argument("User key must have a length greater than 0.").response());
+    }
+
+    let ip = caller.and_then(|caller| caller.ip);
+    let policy_sizes = match form.field("policy") {
+        Some(encoded) => {
+            let access_key = claimed_access_key(&form);
+            if let Err(rejected) = verify_signature(&Keys::of(state), &form, encoded) {
+                if rejected.status == StatusCode::FORBIDDEN {
+                    warn!("🚫 Browser upload with a bad signature for {}", key);
+                    state.lockout.failed(ip);
+                    let reason = authlog::Reason::InvalidCredentials;
+                    authlog::failure(state, "s3", ip, access_key, reason);
+                }
+                return Ok(rejected.response());
+            }
+            state.lockout.succeeded(ip);
+            match check_policy(&state.bucket_name, &form, &key, encoded) {
+                Ok(sizes) => Some(sizes),
+                Err(rejected) => return Ok(rejected.response()),
+            }
+        }
+        None => None,
+    };
+
+    // Signed forms act for the owner, and unsigned ones are anonymous
+    // unless the request itself was authenticated
+    let caller = policy::Caller {
+        anonymous: policy_sizes.is_none() && caller.is_none_or(|caller| caller.anonymous),
+        ip,
+        secure: caller.is_some_and(|caller| caller.secure),
+    };
+    if !policy::permits(state, &caller, "s3:PutObject", &key) {
+        return Ok(Rejected::new(StatusCode::FORBIDDEN, "AccessDenied", "Access Denied").response());
+    }
+
+    let put = match put_headers(&form) {
+        Ok(put) => put,
+        Err(rejected) => return Ok(rejected.response()),
+    };
+    let mut sizes = policy_sizes.unwrap_or_default();
+    if state.max_body_size > 0 {
+        sizes.max = sizes.max.min(state.max_body_size);
+    }
+    let failure = Failure::default();
+    let file = file_body(file, body, delimiter, sizes, failure.clone());
+
+    let file_path = object_path(state, &key)?;
+    let old = stats::size(&file_path).await;
+    let stored = put_object(
+        State(state.clone()),
+        Path(key.clone()),
+        Query(PutObjectQuery::default()),
+        put,
+        file,
+    )
+    .await;
+    if let Some(rejected) = failure.lock().unwrap().take() {
+        return Ok(rejected.response());
+    }
+    let stored = stored?;
+    if !stored.status().is_success() {
+        return Ok(stored);
+    }
+    record_put(state, &key, &file_path, old).await;
+    if let Err(e) = erasure::protect(state, &key).await {
+        warn!("🧩 Failed to write the shards of {}: {}", key, e);
+        return Err(StatusCode::INTERNAL_SERVER_ERROR);
+    }
+    info!("🌐 Browser upload stored {}", key);
+
+    Ok(answer(state, &form, &key, stored))
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    const BOUNDARY: &str = "----form";
+    const KEYS: Keys = Keys {
+        access_key: "mykey",
+        secret_key: "mysecret",
+        public_host: None,
+    };
+
+    // A form body with `fields` before a file holding `file`
+    fn form_body(fields: &[(&str, &str)], file: &[u8]) -> Vec<u8> {
+        let mut body = Vec::new();
+        for (name, value) in fields {
+            body.extend_from_slice(
+                format!(
+                    "--{}\r\nContent-Disposition: form-data; name=\"{}\"\r\n\r\n{}\r\n",
+                    BOUNDARY, name, value
+                )
+                .as_bytes(),
+            );
+        }
+        body.extend_from_slice(
+            format!(
+                "--{}\r\nContent-Disposition: form-data; name=\"file\"; \
+                 filename=\"C:\\photos\\cat.jpg\"\r\nContent-Type: image/jpeg\r\n\r\n",
+                BOUNDARY
+            )
+            .as_bytes(),
+        );
+        body.extend_from_slice(file);
+        body.extend_from_slice(format!("\r\n--{}--\r\n", BOUNDARY).as_bytes());
+        body
+    }
+
+    // A body arriving in chunks of `chunk` bytes
+    fn chunked(body: Vec<u8>, chunk: usize) -> Body {
+        let chunks: Vec<Result<Bytes, io::Error>> =
+            body.chunks(chunk).map(|chunk| Ok(Bytes::copy_from_slice(chunk))).collect();
+        Body::from_stream(futures_util::stream::iter(chunks))
+    }
+
+    // The form and file read from `body`, or the code it was rejected with
+    async fn read(body: Body, sizes: Sizes) -> Result<(Form, Vec<u8>), &'static str> {
+        let delimiter = delimiter(BOUNDARY);
+        let (form, file, rest) = read_fields(body.into_data_stream(), &delimiter)
+            .await
+            .map_err(|rejected| rejected.code)?;
+        let failure = Failure::default();
+        let file = file_body(file, rest, delimiter, sizes, failure.clone());
+        let file = axum::body::to_bytes(file, usize::MAX).await;
+        if let Some(rejected) = failure.lock().unwrap().take() {
+            return Err(rejected.code);
+        }
+        Ok((form, file.unwrap().to_vec()))
+    }
+
+    fn code<T>(result: Result<T, Rejected>) -> Option<&'static str> {
+        result.err().map(|rejected| rejected.code)
+    }
+
+    fn policy(expiration: &str, conditions: &str) -> String {
+        STANDARD.encode(format!(
+            r#"{{"expiration": "{}", "conditions": [{}]}}"#,
+            expiration, conditions
+        ))
+    }
+
+    fn fields(fields: &[(&str, &str)]) -> Form {
+        Form {
+            fields: fields
+                .iter()
+                .map(|(name, value)| (name.to_string(), value.to_string()))
+                .collect(),
+            filename: "cat.jpg".to_string(),
+        }
+    }
+
+    #[tokio::test]
+    async fn reads_fields_and_file() {
+        let body = form_body(&[("key", "uploads/${filename}"), ("Policy", "p")], b"meow");
+        let (form, file) = read(chunked(body, 1 << 20), Sizes::default()).await.unwrap();
+        assert_eq!(form.field("key"), Some("uploads/${filename}"));
+        assert_eq!(form.field("policy"), Some("p"));
+        assert_eq!(form.filename, "cat.jpg");
+        assert_eq!(file, b"meow");
+    }
+
+    #[tokio::test]
+    async fn reads_a_preamble_and_delimiters_split_across_chunks() {
+        let mut body = b"ignored preamble\r\n".to_vec();
+        body.extend(form_body(&[("key", "a")], b"line\r\n--not the boundary\r\n--"));
+        for chunk in [1, 2, 3, 7, 64] {
+            let (form, file) = read(chunked(body.clone(), chunk), Sizes::default()).await.unwrap();
+            assert_eq!(form.field("key"), Some("a"));
+            assert_eq!(file, b"line\r\n--not the boundary\r\n--");
+        }
+    }
+
+    #[tokio::test]
+    async fn streams_files_over_two_mebibytes() {
+        let file: Vec<u8> = (0..3 * 1024 * 1024).map(|i| (i % 251) as u8).collect();
+        let body = form_body(&[("key", "big")], &file);
+        let (_, read_file) = read(chunked(body, 64 * 1024), Sizes::default()).await.unwrap();
+        assert_eq!(read_file, file);
+    }
+
+    #[tokio::test]
+    async fn enforces_the_allowed_sizes_while_streaming() {
+        let file = vec![b'x'; 3 * 1024 * 1024];
+        let body = || chunked(form_body(&[("key", "big")], &file), 64 * 1024);
+        let sizes = |min, max| Sizes { min, max };
+        let exact = file.len() as u64;
+        assert!(read(body(), sizes(exact, exact)).await.is_ok());
+        assert_eq!(read(body(), sizes(0, exact - 1)).await.err(), Some("EntityTooLarge"));
+        assert_eq!(read(body(), sizes(exact + 1, u64::MAX)).await.err(), Some("EntityTooSmall"));
+    }
+
+    #[tokio::test]
+    async fn rejects_malformed_bodies() {
+        let no_file = format!(
+            "--{0}\r\nContent-Disposition: form-data; name=\"key\"\r\n\r\na\r\n--{0}--\r\n",
+            BOUNDARY
+        );
+        let no_file = chunked(no_file.into_bytes(), 16);
+        assert_eq!(read(no_file, Sizes::default()).await.err(), Some("InvalidArgument"));
+
+        let mut unterminated = form_body(&[("key", "a")], b"data");
+        unterminated.truncate(unterminated.len() - BOUNDARY.len() - 6);
+        let unterminated = chunked(unterminated, 16);
+        assert_eq!(read(unterminated, Sizes::default()).await.err(), Some("MalformedPOSTRequest"));
+
+        let long = "x".repeat(MAX_FIELDS_SIZE + 1);
+        let long = chunked(form_body(&[("x-ignore-long", &long)], b"data"), 1024);
+        assert_eq!(read(long, Sizes::default()).await.err(), Some("MaxPostPreDataLengthExceeded"));
+    }
+
+    #[test]
+    fn policy_conditions() {
+        let form = fields(&[("key", "user/alice/cat.jpg"), ("content-type", "image/jpeg")]);
+        let encoded = policy(
+            "2099-01-01T00:00:00Z",
+            r#"{"bucket": "photos"}, ["starts-with", "$key", "user/alice/"],
+               ["eq", "$Content-Type", "image/jpeg"], ["content-length-range", 10, "1024"]"#,
+        );
+        let sizes = check_policy("photos", &form, "user/alice/cat.jpg", &encoded);
+        assert_eq!(sizes.ok(), Some(Sizes { min: 10, max: 1024 }));
+
+        let outside = check_policy("photos", &form, "user/bob/cat.jpg", &encoded);
+        assert_eq!(code(outside), Some("AccessDenied"));
+        let other_bucket = check_policy("videos", &form, "user/alice/cat.jpg", &encoded);
+        assert_eq!(code(other_bucket), Some("AccessDenied"));
+
+        let unnamed = policy("2099-01-01T00:00:00Z", r#"["starts-with", "$key", ""]"#);
+        let extra = check_policy("photos", &form, "user/alice/cat.jpg", &unnamed);
+        assert_eq!(code(extra), Some("AccessDenied"));
+    }
+
+    #[test]
+    fn bad_policies() {
+        let form = fields(&[("key", "a")]);
+        let check = |encoded: &str| code(check_policy("photos", &form, "a", encoded));
+        assert_eq!(check("not base64!"), Some("InvalidPolicyDocument"));
+        assert_eq!(check(&STANDARD.encode("{}")), Some("InvalidPolicyDocument"));
+        let no_expiration = STANDARD.encode(r#"{"conditions": []}"#);
+        assert_eq!(check(&no_expiration), Some("InvalidPolicyDocument"));
+        let operator = policy("2099-01-01T00:00:00Z", r#"["ends-with", "$key", "a"]"#);
+        assert_eq!(check(&operator), Some("InvalidPolicyDocument"));
+        let range = policy("2099-01-01T00:00:00Z", r#"["content-length-range", "a", 1]"#);
+        assert_eq!(check(&range), Some("InvalidPolicyDocument"));
+        let expired = policy("2000-01-01T00:00:00Z", r#"{"key": "a"}"#);
+        assert_eq!(check(&expired), Some("AccessDenied"));
+    }
+
+    #[test]
+    fn sigv4_signatures() {
+        let encoded = policy("2099-01-01T00:00:00Z", r#"{"key": "a"}"#);
+        let mut mac =
+            HmacSha256::new_from_slice(&signing_key("mysecret", "20260101", "us-east-1", "s3"))
+                .unwrap();
+        mac.update(encoded.as_bytes());
+        let signature = hex::encode(mac.finalize().into_bytes());
+        let signed = |credential: &str, signature: &str| {
+            fields(&[
+                ("x-amz-algorithm", SIGV4_ALGORITHM),
+                ("x-amz-credential", credential),
+                ("x-amz-signature", signature),
+            ])
+        };
+
+        let credential = "mykey/20260101/us-east-1/s3/aws4_request";
+        let verify = |form: &Form| code(verify_signature(&KEYS, form, &encoded));
+        assert_eq!(verify(&signed(credential, &signature)), None);
+        let tampered = signature.replace(&signature[..1], "0").replacen("0", "1", 1);
+        assert_eq!(verify(&signed(credential, &tampered)), Some("SignatureDoesNotMatch"));
+        let other_day = "mykey/20260102/us-east-1/s3/aws4_request";
+        assert_eq!(verify(&signed(other_day, &signature)), Some("SignatureDoesNotMatch"));
+        let other_key = "otherkey/20260101/us-east-1/s3/aws4_request";
+        assert_eq!(verify(&signed(other_key, &signature)), Some("InvalidAccessKeyId"));
+        assert_eq!(verify(&signed("mykey/20260101", &signature)), Some("InvalidArgument"));
+    }
+
+    #[test]
+    fn legacy_signatures() {
+        // RFC 2202's second HMAC-SHA1 test case
+        assert_eq!(
+            hex::encode(hmac_sha1(b"Jefe", b"what do ya want for nothing?")),
+            "effcdf6ae5eb2fa2d27416d5f184df9c259a7c79"
+        );
+
+        let encoded = policy("2099-01-01T00:00:00Z", r#"{"key": "a"}"#);
+        let signature = STANDARD.encode(hmac_sha1(b"mysecret", encoded.as_bytes()));
+        let signed = |signature: &str| fields(&[("awsaccesskeyid", "mykey"), ("signature", signature)]);
+        assert_eq!(code(verify_signature(&KEYS, &signed(&signature), &encoded)), None);
+        let wrong = STANDARD.encode(hmac_sha1(b"othersecret", encoded.as_bytes()));
+        let refused = verify_signature(&KEYS, &signed(&wrong), &encoded);
+        assert_eq!(code(refused), Some("SignatureDoesNotMatch"));
+    }
+}
@@ -0,0 +1,198 @@
+use std::{
+    path::Path,
+    sync::Arc,
+    time::{Duration, SystemTime},
+};
+use tokio::fs;
+use tracing::{info, warn};
+
+use crate::{AppState, INTERNAL_DIR, TMP_DIR, multipart, trash, tus};
+
+// Removes staging files left behind by uploads that never finished: crashed
+// PUTs and copies in the tmp dir, and tus and multipart uploads the client
+// gave up on.
+// Also empties the trash of objects deleted longer ago than --trash-days,
+// and removes directories left empty by deletes that didn't clean up after
+// themselves, once nothing has changed in them for EMPTY_DIR_MAX_AGE. In
+// cluster mode only the leader runs it.
+
+const GC_INTERVAL: Duration = Duration::from_secs(60 * 60);
+const TMP_MAX_AGE: Duration = Duration::from_secs(24 * 60 * 60);
+const TUS_MAX_AGE: Duration = Duration::from_secs(7 * 24 * 60 * 60);
+const MULTIPART_MAX_AGE: Duration = Duration::from_secs(7 * 24 * 60 * 60);
+const EMPTY_DIR_MAX_AGE: Duration = Duration::from_secs(24 * 60 * 60);
+
+pub fn spawn(state: Arc<AppState>) {
+    tokio::spawn(async move {
+        let mut interval = tokio::time::interval(GC_INTERVAL);
+        loop {
+            interval.tick().await;
+
+            if let Some(cluster) = &state.cluster
+                && !cluster.is_leader()
+            {
+                continue;
+            }
+
+            let removed = remove_stale(&state.data_dir.join(TMP_DIR), TMP_MAX_AGE).await
+                + remove_stale_tus(&state.data_dir.join(tus::TUS_DIR), TUS_MAX_AGE).await
+                + remove_stale_multipart(
+                    &state.data_dir.join(multipart::MULTIPART_DIR),
+                    MULTIPART_MAX_AGE,
+                )
+                .await;
+            if removed > 0 {
+                info!("🧹 Removed {} stale staging files", removed);
+            }
+
+            if let Some(retention) = state.trash_retention {
+                let expired =
+                    remove_expired_trash(&state.data_dir.join(trash::TRASH_DIR), retention).await;
+                if expired > 0 {
+                    info!("🧹 Removed {} expired objects from the trash", expired);
+                }
+            }
+
+            let emptied = remove_empty_dirs(&state.data_dir, EMPTY_DIR_MAX_AGE).await;
+            if emptied > 0 {
+                info!("🧹 Removed {} empty directories", emptied);
+            }
+        }
+    });
+}
+
+async fn is_stale(path: &Path, max_age: Duration) -> bool {
+    fs::metadata(path)
+        .await
+        .and_then(|metadata| metadata.modified())
+        .ok()
+        .and_then(|modified| SystemTime::now().duration_since(modified).ok())
+        .is_some_and(|age| age > max_age)
+}
+
+async fn remove(path: &Path) -> bool {
+    match fs::remove_file(path).await {
+        Ok(()) => true,
+        Err(e) => {
+            warn!("Failed to remove {}: {}", path.display(), e);
+            false
+        }
+    }
+}
+
+async fn remove_stale(dir: &Path, max_age: Duration) -> usize {
+    let Ok(mut entries) = fs::read_dir(dir).await else {
+        return 0;
+    };
+    let mut removed = 0;
+
+    while let Ok(Some(entry)) = entries.next_entry().await {
+        if is_stale(&entry.path(), max_age).await && remove(&entry.path()).await {
+            removed += 1;
+        }
+    }
+
+    removed
+}
+
+// tus uploads are a data file plus a .json info file that is never touched
+// after creation, so age is judged by the data file and both go together
+async fn remove_stale_tus(dir: &Path, max_age: Duration) -> usize {
+    let Ok(mut entries) = fs::read_dir(dir).await else {
+        return 0;
+    };
+    let mut removed = 0;
+
+    while let Ok(Some(entry)) = entries.next_entry().await {
+        let path = entry.path();
+        if path.extension().is_some_and(|ext| ext == "json") {
+            continue;
+        }
+
+        if is_stale(&path, max_age).await && remove(&path).await {
+            let _ = fs::remove_file(path.with_extension("json")).await;
+            removed += 1;
+        }
+    }
+
+    removed
+}
+
+// A multipart upload is a directory its parts are renamed into, so its
+// mtime is when the last part arrived
+async fn remove_stale_multipart(dir: &Path, max_age: Duration) -> usize {
+    let Ok(mut entries) = fs::read_dir(dir).await else {
+        return 0;
+    };
+    let mut removed = 0;
+
+    while let Ok(Some(entry)) = entries.next_entry().await {
+        let path = entry.path();
+        if !is_stale(&path, max_age).await {
+            continue;
+        }
+        match fs::remove_dir_all(&path).await {
+            Ok(()) => removed += 1,
+            Err(e) => warn!("Failed to remove {}: {}", path.display(), e),
+        }
+    }
+
+    removed
+}
+
+// Trashed objects keep the mtime they had when deleted, so their age is
+// judged by the .json file written at deletion
+async fn remove_expired_trash(dir: &Path, retention: Duration) -> usize {
+    let Ok(mut entries) = fs::read_dir(dir).await else {
+        return 0;
+    };
+    let mut removed = 0;
+
+    while let Ok(Some(entry)) = entries.next_entry().await {
+        let path = entry.path();
+        if path.extension().is_none_or(|ext| ext != "json") {
+            continue;
+        }
+
+        if is_stale(&path, retention).await && remove(&path.with_extension("")).await {
+            let _ = fs::remove_file(&path).await;
+            removed += 1;
+        }
+    }
+
+    removed
+}
+
+// Empty directories under the data dir. Staleness is decided for every
+// directory before any is removed, since removing a child updates its
+// parent's mtime, and children go first so a parent emptied by them goes in
+// the same pass
+async fn remove_empty_dirs(data_dir: &Path, max_age: Duration) -> usize {
+    let mut dirs = Vec::new();
+    let mut pending = vec![data_dir.to_path_buf()];
+    while let Some(dir) = pending.pop() {
+        let Ok(mut entries) = fs::read_dir(&dir).await else {
+            continue;
+        };
+        while let Ok(Some(entry)) = entries.next_entry().await {
+            if dir == data_dir && entry.file_name() == INTERNAL_DIR {
+                continue;
+            }
+            // file_type doesn't follow symlinks, so linked directories are left be
+            if entry.file_type().await.is_ok_and(|file_type| file_type.is_dir()) {
+                let path = entry.path();
+                let stale = is_stale(&path, max_age).await;
+                pending.push(path.clone());
+                dirs.push((path, stale));
+            }
+        }
+    }
+
+    let mut removed = 0;
+    for (dir, stale) in dirs.iter().rev() {
+        if *stale && fs::remove_dir(dir).await.is_ok() {
+            removed += 1;
+        }
+    }
+    removed
+}
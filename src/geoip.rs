@@ -0,0 +1,146 @@
+use axum::{
+    extract::{Request, State},
+    http::StatusCode,
+    middleware::Next,
+    response::Response,
+};
+use maxminddb::{Reader, geoip2};
+use serde::Deserialize;
+use std::{collections::HashMap, error::Error, net::IpAddr, path::Path, sync::Arc};
+use tracing::{info, warn};
+
+use crate::{AppState, ErrorResponse, error_response, proxy::ClientInfo};
+
+// Country restrictions from a MaxMind database (GeoLite2 or GeoIP2 Country
+// or City) given to --geoip-db. The file given to --geoip-rules is JSON,
+// keyed by bucket:
+//
+//   {"my-bucket": {"allow": ["DE", "FR"], "deny": [], "mode": "log"}}
+//
+// Countries are ISO 3166 codes. Deny wins over allow, and an empty allow
+// list allows every country. Addresses the database has no country for,
+// like private ranges, only pass an allow list with "allow_unknown". In
+// "log" mode requests that would be refused are logged and let through.
+
+#[derive(Debug, Default, Clone, Copy, PartialEq, Deserialize)]
+#[serde(rename_all = "lowercase")]
+enum Mode {
+    #[default]
+    Enforce,
+    Log,
+}
+
+#[derive(Debug, Default, Deserialize)]
+#[serde(deny_unknown_fields)]
+struct Countries {
+    #[serde(default)]
+    allow: Vec<String>,
+    #[serde(default)]
+    deny: Vec<String>,
+    #[serde(default)]
+    allow_unknown: bool,
+    #[serde(default)]
+    mode: Mode,
+}
+
+impl Countries {
+    fn allows(&self, country: Option<&str>) -> bool {
+        match country {
+            Some(country) => {
+                !self.deny.iter().any(|code| code == country)
+                    && (self.allow.is_empty() || self.allow.iter().any(|code| code == country))
+            }
+            None => self.allow.is_empty() || self.allow_unknown,
+        }
+    }
+}
+
+pub struct GeoIp {
+    reader: Reader<Vec<u8>>,
+    // Rules of the bucket served, if the file has any
+    countries: Option<Countries>,
+}
+
+impl GeoIp {
+    pub fn load(db: &Path, rules: &Path, bucket: &str) -> Result<Self, Box<dyn Error>> {
+        let reader = Reader::open_readfile(db).map_err(|e| format!("{}: {}", db.display(), e))?;
+        let mut buckets: HashMap<String, Countries> =
+            serde_json::from_str(&std::fs::read_to_string(rules)?)
+                .map_err(|e| format!("{}: {}", rules.display(), e))?;
+
+        let countries = buckets.remove(bucket).map(|mut countries| {
+            countries.allow.iter_mut().for_each(|code| code.make_ascii_uppercase());
+            countries.deny.iter_mut().for_each(|code| code.make_ascii_uppercase());
+            countries
+        });
+        match &countries {
+            Some(countries) => info!(
+                "🌍 Loaded GeoIP database {} ({} countries allowed, {} denied, {:?} mode)",
+                db.display(),
+                countries.allow.len(),
+                countries.deny.len(),
+                countries.mode
+            ),
+            None => warn!("🌍 {} has no rules for bucket {}", rules.display(), bucket),
+        }
+
+        Ok(Self { reader, countries })
+    }
+
+    fn country(&self, ip: IpAddr) -> Option<String> {
+        self.reader
+            .lookup::<geoip2::Country>(ip.to_canonical())
+            .ok()?
+            .country?
+            .iso_code
+            .map(str::to_string)
+    }
+
+    // Whether a client may use the bucket. Clients whose address isn't known
+    // are let through, like with the IP filter. Refusals are logged, and in
+    // log mode only logged
+    pub fn allows(&self, ip: Option<IpAddr>) -> bool {
+        let (Some(countries), Some(ip)) = (&self.countries, ip) else {
+            return true;
+        };
+        let country = self.country(ip);
+        if countries.allows(country.as_deref()) {
+            return true;
+        }
+
+        let country = country.as_deref().unwrap_or("unknown country");
+        match countries.mode {
+            Mode::Enforce => {
+                warn!("🌍 GeoIP refused request from {} ({})", ip, country);
+                false
+            }
+            Mode::Log => {
+                info!("🌍 GeoIP would refuse request from {} ({})", ip, country);
+                true
+            }
+        }
+    }
+}
+
+pub async fn geoip_middleware(
+    State(state): State<Arc<AppState>>,
+    request: Request,
+    next: Next,
+) -> Response {
+    let ip = request
+        .extensions()
+        .get::<ClientInfo>()
+        .and_then(|client| client.ip);
+
+    match &state.geoip {
+        Some(geoip) if !geoip.allows(ip) => error_response(
+            StatusCode::FORBIDDEN,
+            ErrorResponse {
+                code: "AccessDeniedByLocation".to_string(),
+                message: "Access from your location is not allowed".to_string(),
+                max_size_allowed: None,
+            },
+        ),
+        _ => next.run(request).await,
+    }
+}
@@ -0,0 +1,554 @@
+use axum::{
+    Router,
+    extract::{Request, State},
+    http::{self, StatusCode},
+    middleware::{self, Next},
+    response::Response,
+};
+use futures_util::{Stream, stream};
+use std::{
+    collections::VecDeque,
+    convert::Infallible,
+    future::Future,
+    pin::Pin,
+    sync::Arc,
+    task::{Context, Poll},
+    time::Duration,
+};
+use tonic::{
+    body::Body,
+    server::{Grpc, NamedService, ServerStreamingService, UnaryService},
+    service::Routes,
+};
+use tonic_prost::ProstCodec;
+use tower::Service;
+use tracing::warn;
+
+use crate::{AppState, admin, metrics, mirror, quota, replica, snapshot, stats, webdav};
+
+// gRPC version of the admin API on --grpc-port, built with the grpc
+// feature. It serves the status and quota figures the JSON endpoints on
+// --admin-port do and streams the --change-feed log, under the
+// simples3.admin.v1.Admin service that proto/simples3/admin/v1/admin.proto
+// describes. Calls take Basic auth with the S3 credentials in the
+// `authorization` metadata, checked and throttled like the admin port.
+//
+// The messages and the service are written out here rather than generated
+// at build time, so building doesn't need protoc; they have to be kept in
+// step with the .proto by hand. v1 only ever gains fields.
+
+const SERVICE: &str = "simples3.admin.v1.Admin";
+const WATCH_INTERVAL: Duration = Duration::from_secs(1);
+
+type BoxFuture<T> = Pin<Box<dyn Future<Output = Result<T, tonic::Status>> + Send>>;
+type ChangeStream = Pin<Box<dyn Stream<Item = Result<Change, tonic::Status>> + Send>>;
+
+#[derive(Clone, PartialEq, prost::Message)]
+pub struct GetStatusRequest {}
+
+#[derive(Clone, PartialEq, prost::Message)]
+pub struct ServerStatus {
+    #[prost(string, tag = "1")]
+    pub bucket: String,
+    #[prost(uint64, tag = "2")]
+    pub uptime_secs: u64,
+    #[prost(uint64, tag = "3")]
+    pub requests: u64,
+    #[prost(uint64, tag = "4")]
+    pub client_errors: u64,
+    #[prost(uint64, tag = "5")]
+    pub server_errors: u64,
+    #[prost(message, optional, tag = "6")]
+    pub rates: Option<Rates>,
+    #[prost(uint64, tag = "7")]
+    pub writes_in_flight: u64,
+    #[prost(uint64, tag = "8")]
+    pub shed: u64,
+    #[prost(message, optional, tag = "9")]
+    pub objects: Option<Totals>,
+    #[prost(message, optional, tag = "10")]
+    pub disk: Option<Disk>,
+    #[prost(bool, tag = "11")]
+    pub disk_low: bool,
+    #[prost(message, optional, tag = "12")]
+    pub mirror: Option<MirrorCounts>,
+    #[prost(uint64, tag = "13")]
+    pub tus_uploads: u64,
+    #[prost(message, repeated, tag = "14")]
+    pub snapshots: Vec<SnapshotSchedule>,
+}
+
+#[derive(Clone, PartialEq, prost::Message)]
+pub struct Rates {
+    #[prost(double, tag = "1")]
+    pub requests: f64,
+    #[prost(double, tag = "2")]
+    pub client_errors: f64,
+    #[prost(double, tag = "3")]
+    pub server_errors: f64,
+}
+
+#[derive(Clone, PartialEq, prost::Message)]
+pub struct Totals {
+    #[prost(uint64, tag = "1")]
+    pub objects: u64,
+    #[prost(uint64, tag = "2")]
+    pub bytes: u64,
+}
+
+#[derive(Clone, PartialEq, prost::Message)]
+pub struct Disk {
+    #[prost(uint64, tag = "1")]
+    pub total_bytes: u64,
+    #[prost(uint64, tag = "2")]
+    pub free_bytes: u64,
+}
+
+#[derive(Clone, PartialEq, prost::Message)]
+pub struct MirrorCounts {
+    #[prost(uint64, tag = "1")]
+    pub mirrored: u64,
+    #[prost(uint64, tag = "2")]
+    pub failed: u64,
+    #[prost(uint64, tag = "3")]
+    pub mismatched: u64,
+    #[prost(uint64, tag = "4")]
+    pub skipped: u64,
+}
+
+#[derive(Clone, PartialEq, prost::Message)]
+pub struct SnapshotSchedule {
+    #[prost(string, tag = "1")]
+    pub schedule: String,
+    #[prost(string, optional, tag = "2")]
+    pub last_success: Option<String>,
+    #[prost(string, optional, tag = "3")]
+    pub last_failure: Option<String>,
+    #[prost(string, optional, tag = "4")]
+    pub last_error: Option<String>,
+    #[prost(string, repeated, tag = "5")]
+    pub snapshots: Vec<String>,
+}
+
+#[derive(Clone, PartialEq, prost::Message)]
+pub struct GetQuotasRequest {}
+
+#[derive(Clone, PartialEq, prost::Message)]
+pub struct Quotas {
+    #[prost(message, repeated, tag = "1")]
+    pub keys: Vec<KeyUsage>,
+}
+
+#[derive(Clone, PartialEq, prost::Message)]
+pub struct KeyUsage {
+    #[prost(string, tag = "1")]
+    pub access_key: String,
+    #[prost(uint64, tag = "2")]
+    pub stored_bytes: u64,
+    #[prost(uint64, tag = "3")]
+    pub stored_limit: u64,
+    #[prost(string, tag = "4")]
+    pub month: String,
+    #[prost(uint64, tag = "5")]
+    pub transferred_bytes: u64,
+    #[prost(uint64, tag = "6")]
+    pub transfer_limit: u64,
+}
+
+#[derive(Clone, PartialEq, prost::Message)]
+pub struct WatchChangesRequest {
+    #[prost(uint64, tag = "1")]
+    pub since: u64,
+}
+
+#[derive(Clone, PartialEq, prost::Message)]
+pub struct Change {
+    #[prost(uint64, tag = "1")]
+    pub seq: u64,
+    #[prost(enumeration = "ChangeOp", tag = "2")]
+    pub op: i32,
+    #[prost(string, tag = "3")]
+    pub key: String,
+}
+
+// Change.Op in the .proto
+#[derive(Clone, Copy, Debug, PartialEq, Eq, Hash, PartialOrd, Ord, prost::Enumeration)]
+#[repr(i32)]
+pub enum ChangeOp {
+    Unspecified = 0,
+    Put = 1,
+    Delete = 2,
+}
+
+impl From<admin::Status> for ServerStatus {
+    fn from(status: admin::Status) -> Self {
+        let metrics::Counts {
+            uptime_secs,
+            requests,
+            client_errors,
+            server_errors,
+            rates,
+            writes_in_flight,
+            shed,
+        } = status.counts;
+        let stats::Totals { objects, bytes } = status.objects;
+        Self {
+            bucket: status.bucket,
+            uptime_secs,
+            requests,
+            client_errors,
+            server_errors,
+            rates: Some(Rates {
+                requests: rates.requests,
+                client_errors: rates.client_errors,
+                server_errors: rates.server_errors,
+            }),
+            writes_in_flight,
+            shed,
+            objects: Some(Totals { objects, bytes }),
+            disk: status.disk.map(|disk| Disk {
+                total_bytes: disk.total_bytes,
+                free_bytes: disk.free_bytes,
+            }),
+            disk_low: status.disk_low,
+            mirror: status.mirror.map(|counts: mirror::Counts| MirrorCounts {
+                mirrored: counts.mirrored,
+                failed: counts.failed,
+                mismatched: counts.mismatched,
+                skipped: counts.skipped,
+            }),
+            tus_uploads: status.tus_uploads,
+            snapshots: status
+                .snapshots
+                .into_iter()
+                .map(|schedule: snapshot::ScheduleStatus| SnapshotSchedule {
+                    schedule: schedule.schedule,
+                    last_success: schedule.last_success,
+                    last_failure: schedule.last_failure,
+                    last_error: schedule.last_error,
+                    snapshots: schedule.snapshots,
+                })
+                .collect(),
+        }
+    }
+}
+
+impl From<quota::KeyUsage> for KeyUsage {
+    fn from(usage: quota::KeyUsage) -> Self {
+        Self {
+            access_key: usage.access_key,
+            stored_bytes: usage.stored_bytes,
+            stored_limit: usage.stored_limit,
+            month: usage.month,
+            transferred_bytes: usage.transferred_bytes,
+            transfer_limit: usage.transfer_limit,
+        }
+    }
+}
+
+impl From<replica::Change> for Change {
+    fn from(change: replica::Change) -> Self {
+        let op = match change.op {
+            replica::Op::Put => ChangeOp::Put,
+            replica::Op::Delete => ChangeOp::Delete,
+        };
+        Self {
+            seq: change.seq,
+            op: op.into(),
+            key: change.key,
+        }
+    }
+}
+
+pub fn router(state: Arc<AppState>) -> Router {
+    Routes::new(Admin {
+        state: state.clone(),
+    })
+    .into_axum_router()
+    .layer(middleware::from_fn_with_state(state, grpc_auth_middleware))
+}
+
+// The admin port's Basic auth, with its refusals as gRPC statuses
+async fn grpc_auth_middleware(
+    State(state): State<Arc<AppState>>,
+    request: Request,
+    next: Next,
+) -> Response {
+    let Some(refusal) = webdav::basic_auth(&state, &request, "grpc") else {
+        return next.run(request).await;
+    };
+    let status = match refusal.status() {
+        StatusCode::TOO_MANY_REQUESTS => {
+            tonic::Status::resource_exhausted("Too many failed logins, try again later")
+        }
+        _ => tonic::Status::unauthenticated(
+            "Send Basic auth with the access key and secret key in the authorization metadata",
+        ),
+    };
+    status.into_http()
+}
+
+#[derive(Clone)]
+struct Admin {
+    state: Arc<AppState>,
+}
+
+impl NamedService for Admin {
+    const NAME: &'static str = SERVICE;
+}
+
+impl Service<http::Request<Body>> for Admin {
+    type Response = http::Response<Body>;
+    type Error = Infallible;
+    type Future = Pin<Box<dyn Future<Output = Result<Self::Response, Infallible>> + Send>>;
+
+    fn poll_ready(&mut self, _: &mut Context<'_>) -> Poll<Result<(), Infallible>> {
+        Poll::Ready(Ok(()))
+    }
+
+    fn call(&mut self, request: http::Request<Body>) -> Self::Future {
+        let state = self.state.clone();
+        Box::pin(async move {
+            let method = request.uri().path().rsplit('/').next().unwrap_or_default();
+            Ok(match method {
+                "GetStatus" => {
+                    let mut grpc = Grpc::new(ProstCodec::default());
+                    grpc.unary(GetStatus(state), request).await
+                }
+                "GetQuotas" => {
+                    let mut grpc = Grpc::new(ProstCodec::default());
+                    grpc.unary(GetQuotas(state), request).await
+                }
+                "WatchChanges" => {
+                    let mut grpc = Grpc::new(ProstCodec::default());
+                    grpc.server_streaming(WatchChanges(state), request).await
+                }
+                _ => tonic::Status::unimplemented(format!("{} has no such method", SERVICE))
+                    .into_http(),
+            })
+        })
+    }
+}
+
+struct GetStatus(Arc<AppState>);
+
+impl UnaryService<GetStatusRequest> for GetStatus {
+    type Response = ServerStatus;
+    type Future = BoxFuture<tonic::Response<ServerStatus>>;
+
+    fn call(&mut self, _: tonic::Request<GetStatusRequest>) -> Self::Future {
+        let state = self.0.clone();
+        Box::pin(async move { Ok(tonic::Response::new(admin::status(&state).await.into())) })
+    }
+}
+
+struct GetQuotas(Arc<AppState>);
+
+impl UnaryService<GetQuotasRequest> for GetQuotas {
+    type Response = Quotas;
+    type Future = BoxFuture<tonic::Response<Quotas>>;
+
+    fn call(&mut self, _: tonic::Request<GetQuotasRequest>) -> Self::Future {
+        let state = self.0.clone();
+        Box::pin(async move {
+            let usage = state.quotas.usage(&state, &state.access_key);
+            Ok(tonic::Response::new(Quotas {
+                keys: vec![usage.into()],
+            }))
+        })
+    }
+}
+
+struct WatchChanges(Arc<AppState>);
+
+impl ServerStreamingService<WatchChangesRequest> for WatchChanges {
+    type Response = Change;
+    type ResponseStream = ChangeStream;
+    type Future = BoxFuture<tonic::Response<ChangeStream>>;
+
+    fn call(&mut self, request: tonic::Request<WatchChangesRequest>) -> Self::Future {
+        let feed = self.0.changes.clone();
+        let since = request.into_inner().since;
+        Box::pin(async move {
+            let feed = feed.ok_or_else(|| {
+                tonic::Status::failed_precondition("The server was started without --change-feed")
+            })?;
+            Ok(tonic::Response::new(watch(feed, since)))
+        })
+    }
+}
+
+// Changes after `since`, then each new one as it's recorded. The log is
+// read again every WATCH_INTERVAL once the stream has caught up, until the
+// client goes away.
+fn watch(feed: Arc<replica::ChangeFeed>, since: u64) -> ChangeStream {
+    let start = Some((feed, since, VecDeque::new()));
+    Box::pin(stream::unfold(start, |watching| async move {
+        let (feed, mut since, mut pending) = watching?;
+        loop {
+            if let Some(change) = pending.pop_front() {
+                return Some((Ok(change), Some((feed, since, pending))));
+            }
+            match feed.read(since, replica::MAX_BATCH).await {
+                Ok(batch) => match batch.last() {
+                    Some(last) => {
+                        since = last.seq;
+                        pending.extend(batch.into_iter().map(Change::from));
+                    }
+                    None => tokio::time::sleep(WATCH_INTERVAL).await,
+                },
+                Err(e) => {
+                    warn!("Failed to read the change feed: {}", e);
+                    let status = tonic::Status::internal("Failed to read the change feed");
+                    return Some((Err(status), None));
+                }
+            }
+        }
+    }))
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use axum::http::uri::PathAndQuery;
+    use base64::Engine;
+    use futures_util::StreamExt;
+    use tonic::{Code, client};
+
+    // A server state on a fresh data dir, removed when done
+    struct Fixture {
+        state: Arc<AppState>,
+    }
+
+    impl Fixture {
+        async fn new(change_feed: bool) -> Self {
+            let dir = std::env::temp_dir().join(format!("grpc-{}", uuid::Uuid::new_v4()));
+            let mut state = crate::tests::state(&dir).await;
+            if change_feed {
+                let feed = replica::ChangeFeed::open(&state).await.unwrap();
+                state.changes = Some(Arc::new(feed));
+            }
+            Self {
+                state: Arc::new(state),
+            }
+        }
+
+        fn client(&self) -> client::Grpc<Router> {
+            client::Grpc::new(router(self.state.clone()))
+        }
+
+        fn request<T>(&self, message: T, secret: &str) -> tonic::Request<T> {
+            let credentials = format!("{}:{}", self.state.access_key, secret);
+            let header = format!(
+                "Basic {}",
+                base64::engine::general_purpose::STANDARD.encode(credentials)
+            );
+            let mut request = tonic::Request::new(message);
+            request
+                .metadata_mut()
+                .insert("authorization", header.parse().unwrap());
+            request
+        }
+
+        fn signed<T>(&self, message: T) -> tonic::Request<T> {
+            self.request(message, &self.state.secret_key)
+        }
+    }
+
+    impl Drop for Fixture {
+        fn drop(&mut self) {
+            let _ = std::fs::remove_dir_all(&self.state.data_dir);
+        }
+    }
+
+    async fn get_status(
+        client: &mut client::Grpc<Router>,
+        request: tonic::Request<GetStatusRequest>,
+    ) -> Result<ServerStatus, tonic::Status> {
+        client.ready().await.unwrap();
+        let path = PathAndQuery::from_static("/simples3.admin.v1.Admin/GetStatus");
+        let response = client.unary(request, path, ProstCodec::default()).await?;
+        Ok(response.into_inner())
+    }
+
+    async fn watch_changes(
+        client: &mut client::Grpc<Router>,
+        request: tonic::Request<WatchChangesRequest>,
+    ) -> Result<tonic::Streaming<Change>, tonic::Status> {
+        client.ready().await.unwrap();
+        let path = PathAndQuery::from_static("/simples3.admin.v1.Admin/WatchChanges");
+        let response = client
+            .server_streaming(request, path, ProstCodec::default())
+            .await?;
+        Ok(response.into_inner())
+    }
+
+    #[tokio::test]
+    async fn calls_need_the_s3_credentials() {
+        let fixture = Fixture::new(false).await;
+        let mut client = fixture.client();
+
+        let unsigned = tonic::Request::new(GetStatusRequest {});
+        let refused = get_status(&mut client, unsigned).await.unwrap_err();
+        assert_eq!(refused.code(), Code::Unauthenticated);
+
+        let wrong = fixture.request(GetStatusRequest {}, "not-the-secret");
+        let refused = get_status(&mut client, wrong).await.unwrap_err();
+        assert_eq!(refused.code(), Code::Unauthenticated);
+
+        let status = get_status(&mut client, fixture.signed(GetStatusRequest {}))
+            .await
+            .unwrap();
+        assert_eq!(status.bucket, fixture.state.bucket_name);
+        assert_eq!(status.objects, Some(Totals::default()));
+    }
+
+    #[tokio::test]
+    async fn quotas_are_per_access_key() {
+        let fixture = Fixture::new(false).await;
+        let mut client = fixture.client();
+
+        client.ready().await.unwrap();
+        let path = PathAndQuery::from_static("/simples3.admin.v1.Admin/GetQuotas");
+        let request = fixture.signed(GetQuotasRequest {});
+        let quotas: tonic::Response<Quotas> = client
+            .unary(request, path, ProstCodec::default())
+            .await
+            .unwrap();
+
+        let keys = quotas.into_inner().keys;
+        assert_eq!(keys.len(), 1);
+        assert_eq!(keys[0].access_key, fixture.state.access_key);
+    }
+
+    #[tokio::test]
+    async fn watching_changes_needs_the_change_feed() {
+        let fixture = Fixture::new(false).await;
+        let request = fixture.signed(WatchChangesRequest { since: 0 });
+
+        let refused = watch_changes(&mut fixture.client(), request).await.unwrap_err();
+        assert_eq!(refused.code(), Code::FailedPrecondition);
+    }
+
+    #[tokio::test]
+    async fn watching_changes_catches_up_then_follows() {
+        let fixture = Fixture::new(true).await;
+        let feed = fixture.state.changes.clone().unwrap();
+        feed.record(replica::Op::Put, "a.txt").await;
+        feed.record(replica::Op::Put, "b.txt").await;
+        feed.record(replica::Op::Delete, "a.txt").await;
+
+        let request = fixture.signed(WatchChangesRequest { since: 1 });
+        let mut changes = watch_changes(&mut fixture.client(), request).await.unwrap();
+
+        let mut next = async || changes.next().await.unwrap().unwrap();
+        let change = next().await;
+        assert_eq!((change.seq, change.op(), change.key), (2, ChangeOp::Put, "b.txt".into()));
+        let change = next().await;
+        assert_eq!((change.seq, change.op()), (3, ChangeOp::Delete));
+
+        feed.record(replica::Op::Put, "c.txt").await;
+        let change = next().await;
+        assert_eq!((change.seq, change.key), (4, "c.txt".into()));
+    }
+}
@@ -0,0 +1,186 @@
+use std::{
+    collections::HashMap,
+    fs::File,
+    io,
+    path::{Path, PathBuf},
+    sync::{Arc, Mutex},
+    time::{Duration, Instant, SystemTime},
+};
+
+// Cache of open files and their sizes for hot objects, so repeated GETs and
+// HEADs of the same object skip the open and stat. Writes made through the
+// server invalidate their paths; entries also expire after HANDLE_TTL so
+// changes made to the data dir behind the server's back show up soon after.
+// Least recently used entries are closed once the cache is full.
+//
+// Misses are cached the same way, so clients probing for optional keys
+// don't stat the filesystem on every request. They're kept apart from the
+// handles, so a stream of 404s can't push hot objects out. A miss is only
+// recorded if nothing was invalidated while it was being looked up, or a
+// write that finished meanwhile could be hidden until it expires.
+
+const HANDLE_TTL: Duration = Duration::from_secs(5);
+
+#[derive(Clone)]
+pub struct Handle {
+    pub file: Arc<File>,
+    pub len: u64,
+    pub modified: SystemTime,
+}
+
+struct Entry {
+    handle: Handle,
+    opened: Instant,
+    used: u64,
+}
+
+#[derive(Default)]
+struct Entries {
+    map: HashMap<PathBuf, Entry>,
+    // Paths found missing, and when
+    misses: HashMap<PathBuf, Instant>,
+    clock: u64,
+    // Bumped by every invalidation
+    generation: u64,
+}
+
+pub struct HandleCache {
+    capacity: usize,
+    entries: Mutex<Entries>,
+}
+
+impl HandleCache {
+    pub fn new(capacity: usize) -> Self {
+        Self {
+            capacity,
+            entries: Mutex::new(Entries::default()),
+        }
+    }
+
+    pub async fn open(&self, path: &Path) -> io::Result<Handle> {
+        if self.missing(path) {
+            return Err(io::Error::from(io::ErrorKind::NotFound));
+        }
+        if let Some(handle) = self.get(path) {
+            return Ok(handle);
+        }
+
+        let generation = self.entries.lock().unwrap().generation;
+        let owned = path.to_path_buf();
+        let handle = tokio::task::spawn_blocking(move || {
+            let file = File::open(&owned)?;
+            let metadata = file.metadata()?;
+            if !metadata.is_file() {
+                return Err(io::Error::from(io::ErrorKind::NotFound));
+            }
+            Ok(Handle {
+                file: Arc::new(file),
+                len: metadata.len(),
+                modified: metadata.modified().unwrap_or(SystemTime::UNIX_EPOCH),
+            })
+        })
+        .await
+        .map_err(io::Error::other)?;
+
+        match handle {
+            Ok(handle) => {
+                self.insert(path, handle.clone());
+                Ok(handle)
+            }
+            Err(e) => {
+                if e.kind() == io::ErrorKind::NotFound {
+                    self.insert_miss(path, generation);
+                }
+                Err(e)
+            }
+        }
+    }
+
+    fn missing(&self, path: &Path) -> bool {
+        let mut entries = self.entries.lock().unwrap();
+        match entries.misses.get(path) {
+            Some(missed) if missed.elapsed() <= HANDLE_TTL => true,
+            Some(_) => {
+                entries.misses.remove(path);
+                false
+            }
+            None => false,
+        }
+    }
+
+    fn insert_miss(&self, path: &Path, generation: u64) {
+        if self.capacity == 0 {
+            return;
+        }
+        let mut entries = self.entries.lock().unwrap();
+        if entries.generation != generation {
+            return;
+        }
+        if entries.misses.len() >= self.capacity {
+            entries.misses.retain(|_, missed| missed.elapsed() <= HANDLE_TTL);
+            if entries.misses.len() >= self.capacity {
+                return;
+            }
+        }
+        entries.misses.insert(path.to_path_buf(), Instant::now());
+    }
+
+    fn get(&self, path: &Path) -> Option<Handle> {
+        let mut entries = self.entries.lock().unwrap();
+        entries.clock += 1;
+        let clock = entries.clock;
+
+        let entry = entries.map.get_mut(path)?;
+        if entry.opened.elapsed() > HANDLE_TTL {
+            entries.map.remove(path);
+            return None;
+        }
+        entry.used = clock;
+        Some(entry.handle.clone())
+    }
+
+    fn insert(&self, path: &Path, handle: Handle) {
+        if self.capacity == 0 {
+            return;
+        }
+        let mut entries = self.entries.lock().unwrap();
+        entries.clock += 1;
+        let clock = entries.clock;
+
+        if entries.map.len() >= self.capacity
+            && !entries.map.contains_key(path)
+            && let Some(oldest) = entries
+                .map
+                .iter()
+                .min_by_key(|(_, entry)| entry.used)
+                .map(|(path, _)| path.clone())
+        {
+            entries.map.remove(&oldest);
+        }
+
+        entries.map.insert(
+            path.to_path_buf(),
+            Entry {
+                handle,
+                opened: Instant::now(),
+                used: clock,
+            },
+        );
+    }
+
+    // Forget a path after it was written or removed
+    pub fn invalidate(&self, path: &Path) {
+        let mut entries = self.entries.lock().unwrap();
+        entries.map.remove(path);
+        entries.misses.remove(path);
+        entries.generation += 1;
+    }
+
+    // Forget everything, for renames of whole directories
+    pub fn clear(&self) {
+        let mut entries = self.entries.lock().unwrap();
+        entries.map.clear();
+        entries.misses.clear();
+        entries.generation += 1;
+    }
+}
@@ -0,0 +1,168 @@
+use axum::http::StatusCode;
+use image::{DynamicImage, ImageFormat, ImageReader, imageops::FilterType};
+use serde::Deserialize;
+use sha2::{Digest, Sha256};
+use std::{
+    io::{self, Cursor},
+    path::{Path, PathBuf},
+    time::UNIX_EPOCH,
+};
+use tokio::fs;
+use tracing::{info, warn};
+
+use crate::{AppState, tmp_path, write_and_rename};
+
+// On-the-fly image variants. A GET for a JPEG, PNG or WebP object with
+// width, height or format query parameters returns the image scaled to fit
+// within width x height (never enlarged) and/or converted. Variants are
+// rendered once and cached under VARIANTS_DIR, one directory per object,
+// named after the source's modification time and size so an overwritten
+// object gets fresh variants and the stale ones are cleared out.
+
+pub const VARIANTS_DIR: &str = ".simple-s3/variants";
+const MAX_DIMENSION: u32 = 8192;
+
+#[derive(Debug, Deserialize)]
+pub struct ImageQuery {
+    width: Option<u32>,
+    height: Option<u32>,
+    format: Option<String>,
+}
+
+fn output_format(name: &str) -> Option<ImageFormat> {
+    match name.to_ascii_lowercase().as_str() {
+        "jpeg" | "jpg" => Some(ImageFormat::Jpeg),
+        "png" => Some(ImageFormat::Png),
+        "webp" => Some(ImageFormat::WebP),
+        _ => None,
+    }
+}
+
+// The file to serve for a GET: the object itself, or the requested variant
+// of an image, rendered if it isn't cached yet
+pub async fn variant(
+    state: &AppState,
+    key: &str,
+    file_path: PathBuf,
+    query: &ImageQuery,
+) -> Result<PathBuf, StatusCode> {
+    if query.width.is_none() && query.height.is_none() && query.format.is_none() {
+        return Ok(file_path);
+    }
+    let Some(source_format) = ImageFormat::from_path(&file_path)
+        .ok()
+        .filter(|format| output_format(format.extensions_str()[0]).is_some())
+    else {
+        return Ok(file_path);
+    };
+
+    let format = match &query.format {
+        Some(name) => output_format(name).ok_or(StatusCode::BAD_REQUEST)?,
+        None => source_format,
+    };
+    if [query.width, query.height]
+        .into_iter()
+        .flatten()
+        .any(|size| size == 0 || size > MAX_DIMENSION)
+    {
+        return Err(StatusCode::BAD_REQUEST);
+    }
+
+    let metadata = fs::metadata(&file_path).await.map_err(|_| StatusCode::NOT_FOUND)?;
+    let modified = metadata
+        .modified()
+        .ok()
+        .and_then(|modified| modified.duration_since(UNIX_EPOCH).ok())
+        .map(|modified| modified.as_nanos())
+        .unwrap_or_default();
+    let stamp = format!("{}-{}-", modified, metadata.len());
+
+    let dir = state
+        .data_dir
+        .join(VARIANTS_DIR)
+        .join(hex::encode(Sha256::digest(file_path.to_string_lossy().as_bytes())));
+    let size = |size: Option<u32>| size.map_or("auto".to_string(), |size| size.to_string());
+    let variant_path = dir.join(format!(
+        "{}{}x{}.{}",
+        stamp,
+        size(query.width),
+        size(query.height),
+        format.extensions_str()[0]
+    ));
+
+    if fs::try_exists(&variant_path).await.unwrap_or(false) {
+        return Ok(variant_path);
+    }
+
+    let (width, height) = (query.width, query.height);
+    let source = file_path.clone();
+    let bytes = tokio::task::spawn_blocking(move || render(&source, width, height, format))
+        .await
+        .map_err(|_| StatusCode::INTERNAL_SERVER_ERROR)?
+        .map_err(|e| {
+            warn!("🖼️ Failed to render {}: {}", key, e);
+            StatusCode::UNSUPPORTED_MEDIA_TYPE
+        })?;
+
+    if let Err(e) = store(state, &dir, &stamp, &variant_path, &bytes).await {
+        warn!("🖼️ Failed to cache variant of {}: {}", key, e);
+        return Err(StatusCode::INTERNAL_SERVER_ERROR);
+    }
+
+    info!(
+        "🖼️ Rendered {} as {} ({} bytes)",
+        key,
+        variant_path.file_name().unwrap_or_default().to_string_lossy(),
+        bytes.len()
+    );
+    Ok(variant_path)
+}
+
+fn render(
+    source: &Path,
+    width: Option<u32>,
+    height: Option<u32>,
+    format: ImageFormat,
+) -> image::ImageResult<Vec<u8>> {
+    let mut image = ImageReader::open(source)?.with_guessed_format()?.decode()?;
+
+    let fit_width = width.unwrap_or(u32::MAX).min(image.width());
+    let fit_height = height.unwrap_or(u32::MAX).min(image.height());
+    if (fit_width, fit_height) != (image.width(), image.height()) {
+        image = image.resize(fit_width, fit_height, FilterType::Lanczos3);
+    }
+
+    // JPEG has no alpha channel
+    if format == ImageFormat::Jpeg && image.color().has_alpha() {
+        image = DynamicImage::ImageRgb8(image.to_rgb8());
+    }
+
+    let mut bytes = Cursor::new(Vec::new());
+    image.write_to(&mut bytes, format)?;
+    Ok(bytes.into_inner())
+}
+
+// Cache a variant, removing ones rendered from an older version of the object
+async fn store(
+    state: &AppState,
+    dir: &Path,
+    stamp: &str,
+    variant_path: &Path,
+    bytes: &[u8],
+) -> io::Result<()> {
+    fs::create_dir_all(dir).await?;
+
+    let mut entries = fs::read_dir(dir).await?;
+    while let Some(entry) = entries.next_entry().await? {
+        if !entry.file_name().to_string_lossy().starts_with(stamp) {
+            let _ = fs::remove_file(entry.path()).await;
+        }
+    }
+
+    let tmp_path = tmp_path(state);
+    let written = write_and_rename(state, &tmp_path, variant_path, bytes).await;
+    if written.is_err() {
+        let _ = fs::remove_file(&tmp_path).await;
+    }
+    written
+}
@@ -0,0 +1,164 @@
+use std::{
+    error::Error,
+    fs, io,
+    path::{Path, PathBuf},
+};
+use tracing::{info, warn};
+use unicode_normalization::UnicodeNormalization;
+
+use crate::{TMP_DIR, copy, keys, relative_path};
+
+// `simpleS3 ingest --from DIR`: adopt an existing directory tree as objects,
+// each file's path under DIR becoming its key. Objects are plain files and
+// ETags and content types are worked out when they're served, so there is
+// nothing to record beyond putting the files in place. By default they are
+// copied, as reflinks where the filesystem has them; --move renames them
+// into the data dir and --link hard links them, so neither copies data when
+// DIR is on the same filesystem. Run it with the server stopped, like the
+// other subcommands.
+
+#[derive(Debug, Clone, Copy, PartialEq)]
+pub enum Mode {
+    Copy,
+    Move,
+    Link,
+}
+
+#[derive(Default)]
+struct Counts {
+    objects: u64,
+    bytes: u64,
+    skipped: u64,
+}
+
+pub async fn ingest(
+    data_dir: &Path,
+    bucket: &str,
+    from: &Path,
+    mode: Mode,
+    normalize_keys: bool,
+) -> Result<(), Box<dyn Error>> {
+    let from = fs::canonicalize(from)?;
+    let data_root = fs::canonicalize(data_dir)?;
+    if from.starts_with(&data_root) || data_root.starts_with(&from) {
+        return Err(format!(
+            "{} and the data dir {} overlap",
+            from.display(),
+            data_dir.display()
+        )
+        .into());
+    }
+    info!(
+        "📥 Ingesting {} into bucket {} at {} ({:?})",
+        from.display(),
+        bucket,
+        data_dir.display(),
+        mode
+    );
+
+    let mut counts = Counts::default();
+    let mut dirs = vec![from.clone()];
+    while let Some(dir) = dirs.pop() {
+        for entry in fs::read_dir(&dir)? {
+            let entry = entry?;
+            let path = entry.path();
+            let file_type = entry.file_type()?;
+            if file_type.is_dir() {
+                dirs.push(path);
+                continue;
+            }
+            if !file_type.is_file() {
+                warn!("📥 Skipping {}, not a regular file", path.display());
+                counts.skipped += 1;
+                continue;
+            }
+
+            match adopt(data_dir, &from, &path, mode, normalize_keys).await {
+                Ok(Some(len)) => {
+                    counts.objects += 1;
+                    counts.bytes += len;
+                }
+                Ok(None) => counts.skipped += 1,
+                Err(e) => return Err(format!("{}: {}", path.display(), e).into()),
+            }
+        }
+    }
+
+    info!(
+        "✅ Ingested {} objects ({} bytes), skipped {}",
+        counts.objects, counts.bytes, counts.skipped
+    );
+    Ok(())
+}
+
+// Key for the file at `path` under `from`, None if it can't be one
+fn key_for(from: &Path, path: &Path, normalize_keys: bool) -> Option<String> {
+    let rel = path.strip_prefix(from).ok()?;
+    let segments = rel
+        .components()
+        .map(|component| component.as_os_str().to_str())
+        .collect::<Option<Vec<_>>>()?;
+    let key = segments.join("/");
+    Some(if normalize_keys { key.nfc().collect() } else { key })
+}
+
+// Puts one file in place as an object. Ok(None) if it was skipped
+async fn adopt(
+    data_dir: &Path,
+    from: &Path,
+    path: &Path,
+    mode: Mode,
+    normalize_keys: bool,
+) -> io::Result<Option<u64>> {
+    let Some(key) = key_for(from, path, normalize_keys) else {
+        warn!("📥 Skipping non UTF-8 name {}", path.display());
+        return Ok(None);
+    };
+    let Some(rel) = relative_path(&key).filter(|rel| !rel.as_os_str().is_empty()) else {
+        warn!("📥 Skipping {}, {} is not a valid key", path.display(), key);
+        return Ok(None);
+    };
+    let target = keys::long_path(data_dir.join(rel));
+    if target.exists() {
+        warn!("📥 Skipping {}, object {} already exists", path.display(), key);
+        return Ok(None);
+    }
+    if let Some(parent) = target.parent() {
+        fs::create_dir_all(parent)?;
+    }
+
+    let metadata = fs::metadata(path)?;
+    match mode {
+        Mode::Link => fs::hard_link(path, &target)?,
+        Mode::Move => match fs::rename(path, &target) {
+            Ok(()) => {}
+            Err(e) if e.kind() == io::ErrorKind::CrossesDevices => {
+                place_copy(data_dir, path, &target).await?;
+                fs::remove_file(path)?;
+            }
+            Err(e) => return Err(e),
+        },
+        Mode::Copy => place_copy(data_dir, path, &target).await?,
+    }
+    Ok(Some(metadata.len()))
+}
+
+// Copies through the staging dir, so an interrupted run never leaves a
+// partial object behind. The copy keeps the original's modification time,
+// which listings report as LastModified
+pub async fn place_copy(data_dir: &Path, path: &Path, target: &Path) -> io::Result<()> {
+    let tmp: PathBuf = data_dir
+        .join(TMP_DIR)
+        .join(uuid::Uuid::new_v4().to_string());
+    if let Err(e) = copy::copy_file(path, &tmp).await {
+        let _ = fs::remove_file(&tmp);
+        return Err(e);
+    }
+    if let Ok(modified) = fs::metadata(path)?.modified() {
+        let _ = fs::File::options()
+            .write(true)
+            .open(&tmp)
+            .and_then(|file| file.set_modified(modified));
+    }
+    fs::rename(&tmp, target)
+}
@@ -0,0 +1,103 @@
+use axum::{
+    body::Bytes,
+    http::{HeaderMap, StatusCode},
+    response::Response,
+};
+use base64::Engine;
+use md5::{Digest, Md5};
+
+use crate::{ErrorResponse, INLINE_HASH_LIMIT, error_response};
+
+// Content-MD5 checks, as S3 makes them: a body sent with a Content-MD5
+// header is only stored if it hashes to that, so data corrupted between the
+// client and the disk is refused with 400 BadDigest rather than kept. A
+// header that isn't a base64 MD5 gets 400 InvalidDigest. Bodies without the
+// header aren't hashed.
+
+pub struct ContentMd5 {
+    expected: [u8; 16],
+    hasher: Md5,
+}
+
+fn refusal(code: &str, message: &str) -> Response {
+    error_response(
+        StatusCode::BAD_REQUEST,
+        ErrorResponse {
+            code: code.to_string(),
+            message: message.to_string(),
+            max_size_allowed: None,
+        },
+    )
+}
+
+fn expected(headers: &HeaderMap) -> Option<Result<[u8; 16], ()>> {
+    let value = headers.get("content-md5")?;
+    let expected = value
+        .to_str()
+        .ok()
+        .and_then(|value| {
+            base64::engine::general_purpose::STANDARD
+                .decode(value.trim())
+                .ok()
+        })
+        .and_then(|digest| <[u8; 16]>::try_from(digest).ok());
+    Some(expected.ok_or(()))
+}
+
+// The refusal for a Content-MD5 that isn't one, None if it may go ahead
+pub fn refuse_invalid(headers: &HeaderMap) -> Option<Response> {
+    matches!(expected(headers), Some(Err(()))).then(|| {
+        refusal(
+            "InvalidDigest",
+            "The Content-MD5 you specified was not valid.",
+        )
+    })
+}
+
+impl ContentMd5 {
+    // The check a request's Content-MD5 asks for, once refuse_invalid let it
+    // through. None without one
+    pub fn from_headers(headers: &HeaderMap) -> Option<Self> {
+        let expected = expected(headers)?.ok()?;
+        Some(Self {
+            expected,
+            hasher: Md5::new(),
+        })
+    }
+
+    pub fn update(&mut self, data: &[u8]) {
+        self.hasher.update(data);
+    }
+
+    // The refusal if what was hashed isn't what the client sent, None if it is
+    pub fn finish(self) -> Option<Response> {
+        (self.hasher.finalize().as_slice() != self.expected).then(|| {
+            refusal(
+                "BadDigest",
+                "The Content-MD5 you specified did not match what we received.",
+            )
+        })
+    }
+}
+
+// Checks a whole body against the request's Content-MD5. Like ETags, large
+// bodies are hashed on the blocking pool
+pub async fn verify(headers: &HeaderMap, body: &Bytes) -> Result<Option<Response>, StatusCode> {
+    if let Some(refusal) = refuse_invalid(headers) {
+        return Ok(Some(refusal));
+    }
+    let Some(mut check) = ContentMd5::from_headers(headers) else {
+        return Ok(None);
+    };
+    if body.len() <= INLINE_HASH_LIMIT {
+        check.update(body);
+        return Ok(check.finish());
+    }
+    let body = body.clone();
+    tokio::task::spawn_blocking(move || {
+        check.update(&body);
+        check.finish()
+    })
+    .await
+    .map_err(|_| StatusCode::INTERNAL_SERVER_ERROR)
+}
@@ -0,0 +1,224 @@
+use flate2::{Compression, write::GzEncoder};
+use md5::{Digest as _, Md5};
+use percent_encoding::utf8_percent_encode;
+use serde::Serialize;
+use std::{io::Write, str::FromStr, sync::Arc, time::Duration};
+use tokio::fs;
+use tracing::{info, warn};
+
+use crate::{
+    AppState, KEY_ENCODE_SET, etag, object_path, replica::Op, stats, tmp_path, walk, write_and_rename,
+};
+
+// S3 Inventory. With --inventory daily or weekly, a report of every object
+// is written into the bucket under --inventory-prefix, laid out like AWS
+// does it so tools that read AWS inventories read these too:
+//
+//   PREFIX/BUCKET/simple-s3/data/UUID.csv.gz
+//   PREFIX/BUCKET/simple-s3/YYYY-MM-DDTHH-MMZ/manifest.json
+//   PREFIX/BUCKET/simple-s3/YYYY-MM-DDTHH-MMZ/manifest.checksum
+//
+// Rows are Bucket, Key (URL encoded), Size, LastModifiedDate, ETag,
+// StorageClass and EncryptionStatus. Objects under PREFIX are left out, so
+// reports don't list earlier reports. Old reports are never removed.
+
+const CONFIG_ID: &str = "simple-s3";
+const CHECK_INTERVAL: Duration = Duration::from_secs(60);
+const STAMP_FORMAT: &str = "%Y-%m-%dT%H-%MZ";
+const FILE_SCHEMA: &str = "Bucket, Key, Size, LastModifiedDate, ETag, StorageClass, EncryptionStatus";
+
+#[derive(Debug, Clone, Copy)]
+pub enum Frequency {
+    Daily,
+    Weekly,
+}
+
+impl FromStr for Frequency {
+    type Err = String;
+
+    fn from_str(s: &str) -> Result<Self, Self::Err> {
+        match s.to_ascii_lowercase().as_str() {
+            "daily" => Ok(Self::Daily),
+            "weekly" => Ok(Self::Weekly),
+            _ => Err(format!("unknown inventory frequency {}, expected daily or weekly", s)),
+        }
+    }
+}
+
+impl Frequency {
+    fn every(self) -> Duration {
+        match self {
+            Self::Daily => Duration::from_secs(24 * 60 * 60),
+            Self::Weekly => Duration::from_secs(7 * 24 * 60 * 60),
+        }
+    }
+}
+
+#[derive(Serialize)]
+#[serde(rename_all = "camelCase")]
+struct Manifest {
+    source_bucket: String,
+    destination_bucket: String,
+    version: &'static str,
+    creation_timestamp: String,
+    file_format: &'static str,
+    file_schema: &'static str,
+    files: Vec<ManifestFile>,
+}
+
+#[derive(Serialize)]
+struct ManifestFile {
+    key: String,
+    size: u64,
+    #[serde(rename = "MD5checksum")]
+    md5_checksum: String,
+}
+
+// Key prefix of this bucket's reports, "inventory/photos/simple-s3/"
+fn root(state: &AppState, prefix: &str) -> String {
+    format!("{}/{}/{}/", prefix, state.bucket_name, CONFIG_ID)
+}
+
+// When the latest report was made, from the names of the manifest dirs
+async fn latest(state: &AppState, prefix: &str) -> Option<chrono::DateTime<chrono::Utc>> {
+    let dir = object_path(state, &root(state, prefix)).ok()?;
+    let mut entries = fs::read_dir(dir).await.ok()?;
+    let mut latest = None;
+    while let Ok(Some(entry)) = entries.next_entry().await {
+        let name = entry.file_name().to_string_lossy().to_string();
+        if let Ok(time) = chrono::NaiveDateTime::parse_from_str(&name, STAMP_FORMAT) {
+            latest = latest.max(Some(time.and_utc()));
+        }
+    }
+    latest
+}
+
+// Writes an object from within the server, keeping stats, the handle cache
+// and the change feed up to date like a PUT would
+async fn store(state: &AppState, key: &str, bytes: &[u8]) -> std::io::Result<()> {
+    let path = object_path(state, key).map_err(|_| std::io::Error::other("invalid key"))?;
+    if let Some(parent) = path.parent() {
+        fs::create_dir_all(parent).await?;
+    }
+    let old = stats::size(&path).await;
+    let tmp = tmp_path(state);
+    if let Err(e) = write_and_rename(state, &tmp, &path, bytes).await {
+        let _ = fs::remove_file(&tmp).await;
+        return Err(e);
+    }
+    state.stats.replaced(old, Some(bytes.len() as u64));
+    state.handles.invalidate(&path);
+    if let Some(feed) = &state.changes {
+        feed.record(Op::Put, key).await;
+    }
+    Ok(())
+}
+
+fn csv_row(fields: &[&str]) -> String {
+    let quoted: Vec<String> = fields
+        .iter()
+        .map(|field| format!("\"{}\"", field.replace('"', "\"\"")))
+        .collect();
+    quoted.join(",") + "\n"
+}
+
+async fn report(state: &AppState, prefix: &str) -> std::io::Result<usize> {
+    let skip = format!("{}/", prefix);
+    let mut rows = Vec::new();
+    walk::walk(state, state.data_dir.clone(), |key, metadata| {
+        if !key.starts_with(&skip) {
+            rows.push((key, metadata));
+        }
+    })
+    .await;
+    rows.sort_by(|a, b| a.0.cmp(&b.0));
+    let count = rows.len();
+    // The ETags listings and HEAD report, unquoted as S3 reports them
+    let mut etags = Vec::with_capacity(count);
+    for (key, _) in &rows {
+        let etag = match object_path(state, key) {
+            Ok(path) => etag::of_file(state, key, &path).await.unwrap_or_default(),
+            Err(_) => String::new(),
+        };
+        etags.push(etag.trim_matches('"').to_string());
+    }
+
+    let bucket = state.bucket_name.clone();
+    let data = tokio::task::spawn_blocking(move || {
+        let mut gz = GzEncoder::new(Vec::new(), Compression::default());
+        for ((key, metadata), etag) in rows.iter().zip(&etags) {
+            let size = metadata.len();
+            let modified: chrono::DateTime<chrono::Utc> = metadata
+                .modified()
+                .unwrap_or(std::time::SystemTime::now())
+                .into();
+            gz.write_all(
+                csv_row(&[
+                    &bucket,
+                    &utf8_percent_encode(key, KEY_ENCODE_SET).to_string(),
+                    &size.to_string(),
+                    &modified.format("%Y-%m-%dT%H:%M:%S%.3fZ").to_string(),
+                    etag,
+                    "STANDARD",
+                    "NOT-SSE",
+                ])
+                .as_bytes(),
+            )?;
+        }
+        gz.finish()
+    })
+    .await
+    .map_err(std::io::Error::other)??;
+
+    let now = chrono::Utc::now();
+    let root = root(state, prefix);
+    let data_key = format!("{}data/{}.csv.gz", root, uuid::Uuid::new_v4());
+    store(state, &data_key, &data).await?;
+
+    let manifest = Manifest {
+        source_bucket: state.bucket_name.clone(),
+        destination_bucket: format!("arn:aws:s3:::{}", state.bucket_name),
+        version: "2016-11-30",
+        creation_timestamp: now.timestamp_millis().to_string(),
+        file_format: "CSV",
+        file_schema: FILE_SCHEMA,
+        files: vec![ManifestFile {
+            key: data_key,
+            size: data.len() as u64,
+            md5_checksum: hex::encode(Md5::digest(&data)),
+        }],
+    };
+    let manifest = serde_json::to_vec_pretty(&manifest)?;
+    let dir = format!("{}{}/", root, now.format(STAMP_FORMAT));
+    // The checksum goes last, its presence marks a complete report
+    store(state, &format!("{}manifest.json", dir), &manifest).await?;
+    store(
+        state,
+        &format!("{}manifest.checksum", dir),
+        hex::encode(Md5::digest(&manifest)).as_bytes(),
+    )
+    .await?;
+    Ok(count)
+}
+
+pub fn spawn(state: Arc<AppState>, frequency: Frequency, prefix: String) {
+    let prefix = prefix.trim_matches('/').to_string();
+    tokio::spawn(async move {
+        let mut interval = tokio::time::interval(CHECK_INTERVAL);
+        loop {
+            interval.tick().await;
+            let due = latest(&state, &prefix).await.is_none_or(|latest| {
+                (chrono::Utc::now() - latest)
+                    .to_std()
+                    .is_ok_and(|age| age >= frequency.every())
+            });
+            if !due {
+                continue;
+            }
+            match report(&state, &prefix).await {
+                Ok(count) => info!("📋 Wrote inventory of {} objects under {}/", count, prefix),
+                Err(e) => warn!("📋 Inventory report failed: {}", e),
+            }
+        }
+    });
+}
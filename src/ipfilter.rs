@@ -0,0 +1,161 @@
+use axum::{
+    extract::{Request, State},
+    http::StatusCode,
+    middleware::Next,
+    response::Response,
+};
+use serde::Deserialize;
+use std::{
+    collections::HashMap,
+    error::Error,
+    net::IpAddr,
+    path::{Path, PathBuf},
+    sync::{Arc, Mutex, RwLock},
+    time::{Duration, SystemTime},
+};
+use tracing::{info, warn};
+
+use crate::{
+    AppState, ErrorResponse, error_response,
+    proxy::{Cidr, ClientInfo},
+};
+
+// Address allow and deny lists, checked before authentication. The file
+// given to --ip-filter is JSON:
+//
+//   {"allow": ["10.8.0.0/16"], "deny": ["10.8.3.7"],
+//    "buckets": {"my-bucket": {"allow": ["192.0.2.0/24"]}}}
+//
+// A client must pass the global lists and then those of the bucket. Deny
+// wins over allow, and an empty allow list allows everyone. The file is
+// checked for changes every RELOAD_INTERVAL; one that fails to parse is
+// reported and the previous lists are kept.
+
+const RELOAD_INTERVAL: Duration = Duration::from_secs(5);
+
+#[derive(Debug, Default, Deserialize)]
+#[serde(deny_unknown_fields)]
+struct Lists {
+    #[serde(default)]
+    allow: Vec<Cidr>,
+    #[serde(default)]
+    deny: Vec<Cidr>,
+}
+
+fn allowed(allow: &[Cidr], deny: &[Cidr], ip: IpAddr) -> bool {
+    !deny.iter().any(|cidr| cidr.contains(ip))
+        && (allow.is_empty() || allow.iter().any(|cidr| cidr.contains(ip)))
+}
+
+#[derive(Debug, Default, Deserialize)]
+#[serde(deny_unknown_fields)]
+struct Rules {
+    #[serde(default)]
+    allow: Vec<Cidr>,
+    #[serde(default)]
+    deny: Vec<Cidr>,
+    #[serde(default)]
+    buckets: HashMap<String, Lists>,
+}
+
+pub struct IpFilter {
+    path: PathBuf,
+    bucket: String,
+    rules: RwLock<Rules>,
+    modified: Mutex<Option<SystemTime>>,
+}
+
+fn modified(path: &Path) -> Option<SystemTime> {
+    std::fs::metadata(path).and_then(|metadata| metadata.modified()).ok()
+}
+
+fn read_rules(path: &Path) -> Result<Rules, Box<dyn Error>> {
+    Ok(serde_json::from_str(&std::fs::read_to_string(path)?)
+        .map_err(|e| format!("{}: {}", path.display(), e))?)
+}
+
+impl IpFilter {
+    pub fn load(path: &Path, bucket: &str) -> Result<Self, Box<dyn Error>> {
+        let modified = modified(path);
+        let rules = read_rules(path)?;
+        info!(
+            "🧱 Loaded IP filter from {} ({} allowed, {} denied)",
+            path.display(),
+            rules.allow.len(),
+            rules.deny.len()
+        );
+
+        Ok(Self {
+            path: path.to_path_buf(),
+            bucket: bucket.to_string(),
+            rules: RwLock::new(rules),
+            modified: Mutex::new(modified),
+        })
+    }
+
+    // Whether a client may talk to the server at all. Clients whose address
+    // isn't known are let through
+    pub fn allows(&self, ip: Option<IpAddr>) -> bool {
+        let Some(ip) = ip.map(|ip| ip.to_canonical()) else {
+            return true;
+        };
+        let rules = self.rules.read().unwrap();
+        allowed(&rules.allow, &rules.deny, ip)
+            && rules
+                .buckets
+                .get(&self.bucket)
+                .is_none_or(|lists| allowed(&lists.allow, &lists.deny, ip))
+    }
+
+    fn reload(&self) {
+        let modified = modified(&self.path);
+        if modified == *self.modified.lock().unwrap() {
+            return;
+        }
+        *self.modified.lock().unwrap() = modified;
+
+        match read_rules(&self.path) {
+            Ok(rules) => {
+                info!("🧱 Reloaded IP filter from {}", self.path.display());
+                *self.rules.write().unwrap() = rules;
+            }
+            Err(e) => warn!("🧱 Keeping the previous IP filter, failed to reload: {}", e),
+        }
+    }
+
+    pub fn spawn_reload(self: Arc<Self>) {
+        tokio::spawn(async move {
+            let mut interval = tokio::time::interval(RELOAD_INTERVAL);
+            loop {
+                interval.tick().await;
+                let filter = self.clone();
+                let _ = tokio::task::spawn_blocking(move || filter.reload()).await;
+            }
+        });
+    }
+}
+
+pub async fn ip_filter_middleware(
+    State(state): State<Arc<AppState>>,
+    request: Request,
+    next: Next,
+) -> Response {
+    let client = request.extensions().get::<ClientInfo>();
+
+    match &state.ip_filter {
+        Some(filter) if !filter.allows(client.and_then(|client| client.ip)) => {
+            if let Some(client) = client {
+                warn!("🧱 IP filter refused request from {}", client);
+            }
+            error_response(
+                StatusCode::FORBIDDEN,
+                ErrorResponse {
+                    code: "AccessDenied".to_string(),
+                    message: "Access Denied".to_string(),
+                    max_size_allowed: None,
+                },
+            )
+        }
+        _ => next.run(request).await,
+    }
+}
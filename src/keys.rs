@@ -0,0 +1,159 @@
+use percent_encoding::{AsciiSet, CONTROLS, percent_decode_str, utf8_percent_encode};
+use std::{
+    borrow::Cow,
+    io,
+    path::Path,
+    sync::atomic::{AtomicBool, Ordering},
+};
+
+// Mapping between key segments and file names. Names are escaped the same
+// way on every platform, so a data dir can move between Linux and Windows:
+// characters Windows forbids in file names, '\', and the '%' used for
+// escaping become %XX; trailing dots and spaces, which Windows strips, are
+// escaped; and reserved device names like CON or lpt1.txt get their first
+// character escaped.
+//
+// On a case-insensitive filesystem, the default on Windows and macOS, keys
+// that differ only in case would share a file, so there upper-case letters
+// are escaped too. Non-ASCII characters always are. Whether a data dir's
+// names escape case is fixed when it's created or migrated, as the layout
+// records it, and set here once at startup.
+
+const FORBIDDEN: &AsciiSet = &CONTROLS
+    .add(b'"')
+    .add(b'%')
+    .add(b'*')
+    .add(b':')
+    .add(b'<')
+    .add(b'>')
+    .add(b'?')
+    .add(b'\\')
+    .add(b'|');
+
+const FORBIDDEN_OR_UPPER: &AsciiSet = &with_upper(FORBIDDEN);
+
+const fn with_upper(set: &AsciiSet) -> AsciiSet {
+    let mut set = set.add(b'A');
+    let mut letter = b'B';
+    while letter <= b'Z' {
+        set = set.add(letter);
+        letter += 1;
+    }
+    set
+}
+
+static ESCAPE_CASE: AtomicBool = AtomicBool::new(false);
+
+// Escape upper-case letters in file names from now on
+pub fn set_escape_case(escape: bool) {
+    ESCAPE_CASE.store(escape, Ordering::Relaxed);
+}
+
+const RESERVED_NAMES: [&str; 22] = [
+    "CON", "PRN", "AUX", "NUL", "COM1", "COM2", "COM3", "COM4", "COM5", "COM6", "COM7", "COM8",
+    "COM9", "LPT1", "LPT2", "LPT3", "LPT4", "LPT5", "LPT6", "LPT7", "LPT8", "LPT9",
+];
+
+// File name for one segment of a key
+pub fn encode_segment(segment: &str) -> Cow<'_, str> {
+    encode_name(segment, ESCAPE_CASE.load(Ordering::Relaxed))
+}
+
+// File name for one segment of a key, with or without upper-case letters
+// escaped
+pub fn encode_name(segment: &str, escape_case: bool) -> Cow<'_, str> {
+    let set = match escape_case {
+        true => FORBIDDEN_OR_UPPER,
+        false => FORBIDDEN,
+    };
+    let mut name: Cow<str> = utf8_percent_encode(segment, set).into();
+
+    let stem = name.split('.').next().unwrap_or_default();
+    if RESERVED_NAMES.iter().any(|reserved| stem.eq_ignore_ascii_case(reserved)) {
+        name = format!("%{:02X}{}", name.as_bytes()[0], &name[1..]).into();
+    }
+
+    if let Some(last) = name.chars().last()
+        && (last == '.' || last == ' ')
+    {
+        name = format!("{}%{:02X}", &name[..name.len() - 1], last as u8).into();
+    }
+
+    name
+}
+
+// Whether names in `dir` are looked up ignoring case, tried with a file
+// that's removed again
+pub fn is_case_insensitive(dir: &Path) -> io::Result<bool> {
+    let probe = dir.join(format!("case-probe-{}", uuid::Uuid::new_v4().simple()));
+    std::fs::write(&probe, b"")?;
+    let upper = dir.join(probe.file_name().unwrap_or_default().to_ascii_uppercase());
+    let insensitive = upper.exists();
+    std::fs::remove_file(&probe)?;
+    Ok(insensitive)
+}
+
+// Key segment for a file name
+pub fn decode_segment(name: &str) -> Cow<'_, str> {
+    percent_decode_str(name).decode_utf8_lossy()
+}
+
+// Key for a path relative to the data dir
+pub fn key_of(rel: &Path) -> String {
+    rel.components()
+        .map(|component| decode_segment(&component.as_os_str().to_string_lossy()).into_owned())
+        .collect::<Vec<_>>()
+        .join("/")
+}
+
+// Paths past MAX_PATH only open on Windows with the verbatim prefix, which
+// needs an absolute path
+#[cfg(windows)]
+pub fn long_path(path: std::path::PathBuf) -> std::path::PathBuf {
+    if path.as_os_str().len() < 260 {
+        return path;
+    }
+    match std::path::absolute(&path) {
+        Ok(absolute) => {
+            let mut verbatim = std::ffi::OsString::from(r"\\?\");
+            verbatim.push(absolute);
+            verbatim.into()
+        }
+        Err(_) => path,
+    }
+}
+
+#[cfg(not(windows))]
+pub fn long_path(path: std::path::PathBuf) -> std::path::PathBuf {
+    path
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn escapes_case_only_when_asked() {
+        assert_eq!(encode_name("Photo.JPG", false), "Photo.JPG");
+        assert_eq!(encode_name("Photo.JPG", true), "%50hoto.%4A%50%47");
+        assert_eq!(encode_name("photo.jpg", true), "photo.jpg");
+        for key in ["Photo.JPG", "CON.txt", "a:B*c", "Ünïcode ", "100%"] {
+            assert_eq!(decode_segment(&encode_name(key, true)), key);
+            assert_eq!(decode_segment(&encode_name(key, false)), key);
+        }
+    }
+
+    #[test]
+    fn keys_differing_in_case_get_names_differing_in_more_than_case() {
+        let upper = encode_name("README.md", true);
+        let lower = encode_name("readme.md", true);
+        assert!(!upper.eq_ignore_ascii_case(&lower));
+    }
+
+    #[test]
+    fn reserved_names_stay_escaped() {
+        assert_eq!(encode_name("con", true), "%63on");
+        assert_eq!(encode_name("Con.txt", true), "%43on.txt");
+        assert_eq!(encode_name("lpt1", false), "%6Cpt1");
+    }
+}
@@ -0,0 +1,305 @@
+use std::{
+    error::Error,
+    fs,
+    io::{self, Write},
+    path::{Path, PathBuf},
+};
+use tracing::{info, warn};
+
+use crate::{INTERNAL_DIR, TMP_DIR, keys};
+
+// On-disk layout versioning. The data dir is stamped with the layout version
+// that wrote it, and the server refuses to start on any other version:
+// older layouts are upgraded offline with `simpleS3 migrate`, newer ones
+// belong to a newer server. Data dirs from before versioning have no stamp
+// and count as version 0.
+//
+// Each migration upgrades one version and can revert itself. The stamp is
+// bumped after every successful step, so a failed migration rolls back only
+// its own step and a rerun continues from there. Steps that rename files
+// journal each rename first so they can be undone.
+
+const LAYOUT_FILE: &str = ".simple-s3/layout";
+const JOURNAL_FILE: &str = ".simple-s3/migrate-journal";
+// Present when file names escape upper-case letters, see keys.rs
+const CASE_FILE: &str = ".simple-s3/escaped-case";
+
+struct Migration {
+    description: &'static str,
+    apply: fn(&Path) -> io::Result<()>,
+    revert: fn(&Path) -> io::Result<()>,
+}
+
+// MIGRATIONS[n] upgrades version n to n + 1
+const MIGRATIONS: &[Migration] = &[
+    Migration {
+        description: "create the internal directory",
+        apply: create_internal_dirs,
+        revert: remove_internal_dirs,
+    },
+    Migration {
+        description: "escape file names that aren't portable to Windows",
+        apply: encode_names,
+        revert: undo_renames,
+    },
+    Migration {
+        description: "escape upper-case letters on a case-insensitive filesystem",
+        apply: escape_case,
+        revert: unescape_case,
+    },
+];
+
+pub const LAYOUT_VERSION: u32 = MIGRATIONS.len() as u32;
+
+fn read_version(data_dir: &Path) -> Result<Option<u32>, Box<dyn Error>> {
+    match fs::read_to_string(data_dir.join(LAYOUT_FILE)) {
+        Ok(version) => Ok(Some(version.trim().parse().map_err(|_| {
+            format!("{} is not a layout version", data_dir.join(LAYOUT_FILE).display())
+        })?)),
+        Err(e) if e.kind() == io::ErrorKind::NotFound => Ok(None),
+        Err(e) => Err(e.into()),
+    }
+}
+
+fn write_version(data_dir: &Path, version: u32) -> io::Result<()> {
+    fs::create_dir_all(data_dir.join(INTERNAL_DIR))?;
+    fs::write(data_dir.join(LAYOUT_FILE), version.to_string())
+}
+
+fn too_new(data_dir: &Path, version: u32) -> Box<dyn Error> {
+    format!(
+        "data dir {} uses layout version {}, newer than this server's version {}",
+        data_dir.display(),
+        version,
+        LAYOUT_VERSION
+    )
+    .into()
+}
+
+// Refuse to serve a data dir written with another layout. An empty data dir
+// is new and gets the current version
+pub fn check(data_dir: &Path) -> Result<(), Box<dyn Error>> {
+    let version = match read_version(data_dir)? {
+        Some(version) => version,
+        None if fs::read_dir(data_dir)?.next().is_none() => {
+            write_version(data_dir, LAYOUT_VERSION)?;
+            if keys::is_case_insensitive(&data_dir.join(INTERNAL_DIR))? {
+                fs::write(data_dir.join(CASE_FILE), "")?;
+            }
+            return Ok(());
+        }
+        None => 0,
+    };
+
+    if version > LAYOUT_VERSION {
+        return Err(too_new(data_dir, version));
+    }
+    if version < LAYOUT_VERSION {
+        return Err(format!(
+            "data dir {} uses layout version {}, this server needs version {}; run `simpleS3 migrate` first",
+            data_dir.display(),
+            version,
+            LAYOUT_VERSION
+        )
+        .into());
+    }
+
+    Ok(())
+}
+
+// Whether the data dir's file names escape upper-case letters. One on a
+// case-insensitive filesystem that doesn't was made on another filesystem,
+// and is refused rather than let keys differing in case share files
+pub fn escapes_case(data_dir: &Path) -> Result<bool, Box<dyn Error>> {
+    if data_dir.join(CASE_FILE).exists() {
+        return Ok(true);
+    }
+    if keys::is_case_insensitive(&data_dir.join(INTERNAL_DIR))? {
+        return Err(format!(
+            "data dir {} is on a case-insensitive filesystem but was laid out on a \
+case-sensitive one, so keys differing only in case would share a file; serve it from a \
+case-sensitive filesystem",
+            data_dir.display()
+        )
+        .into());
+    }
+    Ok(false)
+}
+
+// `simpleS3 migrate`: upgrade the data dir to LAYOUT_VERSION in place
+pub fn migrate(data_dir: &Path) -> Result<(), Box<dyn Error>> {
+    let mut version = read_version(data_dir)?.unwrap_or(0);
+
+    if version > LAYOUT_VERSION {
+        return Err(too_new(data_dir, version));
+    }
+    if version == LAYOUT_VERSION {
+        info!("✅ Data dir {} is already at layout version {}", data_dir.display(), version);
+        return Ok(());
+    }
+
+    info!(
+        "⬆️ Migrating {} from layout version {} to {}",
+        data_dir.display(),
+        version,
+        LAYOUT_VERSION
+    );
+
+    while version < LAYOUT_VERSION {
+        let migration = &MIGRATIONS[version as usize];
+        info!(
+            "⬆️ [{}/{}] {}",
+            version + 1,
+            LAYOUT_VERSION,
+            migration.description
+        );
+
+        if let Err(e) = (migration.apply)(data_dir).and_then(|()| write_version(data_dir, version + 1)) {
+            warn!("Migration to layout version {} failed, rolling back: {}", version + 1, e);
+            if let Err(e) = (migration.revert)(data_dir) {
+                warn!("Rollback failed: {}", e);
+            }
+            return Err(format!(
+                "migration to layout version {} failed, data dir left at version {}: {}",
+                version + 1,
+                version,
+                e
+            )
+            .into());
+        }
+
+        let _ = fs::remove_file(data_dir.join(JOURNAL_FILE));
+        version += 1;
+    }
+
+    info!("✅ Data dir {} is now at layout version {}", data_dir.display(), version);
+    Ok(())
+}
+
+// 0 -> 1: the server's own files moved under INTERNAL_DIR
+fn create_internal_dirs(data_dir: &Path) -> io::Result<()> {
+    let internal = data_dir.join(INTERNAL_DIR);
+    if internal.exists() && !internal.is_dir() {
+        return Err(io::Error::other(format!(
+            "{} is a file, move it out of the data dir first",
+            internal.display()
+        )));
+    }
+    fs::create_dir_all(data_dir.join(TMP_DIR))
+}
+
+// Only removes what is still empty, older servers may have put files there
+fn remove_internal_dirs(data_dir: &Path) -> io::Result<()> {
+    let _ = fs::remove_dir(data_dir.join(TMP_DIR));
+    let _ = fs::remove_file(data_dir.join(LAYOUT_FILE));
+    let _ = fs::remove_dir(data_dir.join(INTERNAL_DIR));
+    Ok(())
+}
+
+// 1 -> 2: file names escaped by keys::encode_segment
+fn encode_names(data_dir: &Path) -> io::Result<()> {
+    rename_all(data_dir, |name| keys::encode_name(name, false).into_owned())
+}
+
+// 2 -> 3: on a case-insensitive filesystem, file names with upper-case
+// letters escaped, so keys differing only in case get files of their own
+fn escape_case(data_dir: &Path) -> io::Result<()> {
+    if !keys::is_case_insensitive(&data_dir.join(INTERNAL_DIR))? {
+        return Ok(());
+    }
+    rename_all(data_dir, |name| {
+        keys::encode_name(&keys::decode_segment(name), true).into_owned()
+    })?;
+    fs::write(data_dir.join(CASE_FILE), "")
+}
+
+fn unescape_case(data_dir: &Path) -> io::Result<()> {
+    let _ = fs::remove_file(data_dir.join(CASE_FILE));
+    undo_renames(data_dir)
+}
+
+// Renames every file and directory outside INTERNAL_DIR to what `rename`
+// makes of its name, journaling each rename
+fn rename_all(data_dir: &Path, rename: fn(&str) -> String) -> io::Result<()> {
+    let mut journal = fs::File::create(data_dir.join(JOURNAL_FILE))?;
+    let mut renamed = 0;
+    rename_dir(data_dir, data_dir, rename, &mut journal, &mut renamed)?;
+    info!("⬆️ Renamed {} files and directories", renamed);
+    Ok(())
+}
+
+fn rename_dir(
+    data_dir: &Path,
+    dir: &Path,
+    rename: fn(&str) -> String,
+    journal: &mut fs::File,
+    renamed: &mut usize,
+) -> io::Result<()> {
+    let mut renames = Vec::new();
+
+    for entry in fs::read_dir(dir)? {
+        let entry = entry?;
+        if dir == data_dir && entry.file_name() == INTERNAL_DIR {
+            continue;
+        }
+
+        let path = entry.path();
+        if entry.file_type()?.is_dir() {
+            rename_dir(data_dir, &path, rename, journal, renamed)?;
+        }
+
+        let Some(name) = entry.file_name().to_str().map(str::to_string) else {
+            warn!("Skipping non UTF-8 name {}", path.display());
+            continue;
+        };
+        let encoded = rename(&name);
+        if encoded != name {
+            renames.push((path, dir.join(encoded)));
+        }
+    }
+
+    // An escaped name is always longer than the original and can only match
+    // another name that needs escaping, so going longest first renames that
+    // one out of the way before anything lands on it
+    renames.sort_by_key(|(from, _)| std::cmp::Reverse(from.as_os_str().len()));
+
+    for (from, to) in renames {
+        if to.exists() {
+            return Err(io::Error::other(format!(
+                "can't rename {} to {}, it already exists",
+                from.display(),
+                to.display()
+            )));
+        }
+
+        let entry = serde_json::to_string(&(&from, &to)).map_err(io::Error::other)?;
+        writeln!(journal, "{}", entry)?;
+        fs::rename(&from, &to)?;
+        *renamed += 1;
+        if renamed.is_multiple_of(10_000) {
+            info!("⬆️ Renamed {} so far", renamed);
+        }
+    }
+
+    Ok(())
+}
+
+// Undo the renames in the journal, newest first
+fn undo_renames(data_dir: &Path) -> io::Result<()> {
+    let journal = match fs::read_to_string(data_dir.join(JOURNAL_FILE)) {
+        Ok(journal) => journal,
+        Err(e) if e.kind() == io::ErrorKind::NotFound => return Ok(()),
+        Err(e) => return Err(e),
+    };
+
+    for line in journal.lines().rev() {
+        let Ok((from, to)) = serde_json::from_str::<(PathBuf, PathBuf)>(line) else {
+            continue;
+        };
+        if to.exists() {
+            fs::rename(&to, &from)?;
+        }
+    }
+
+    fs::remove_file(data_dir.join(JOURNAL_FILE))
+}
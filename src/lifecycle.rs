@@ -0,0 +1,359 @@
+use chrono::{DateTime, Utc};
+use serde::{Deserialize, Serialize};
+use std::{
+    collections::BTreeSet,
+    sync::Arc,
+    time::{Duration, SystemTime},
+};
+use tracing::{info, warn};
+
+use crate::{
+    AppState, access::Document, delete_file, multipart, object_path, record_delete, stats,
+    tagging, versioning, walk, worm,
+};
+
+// Lifecycle rules, S3's ?lifecycle subresource. PUT, GET and DELETE
+// /?lifecycle store, read and remove a LifecycleConfiguration, and a
+// background task applies its enabled rules every LIFECYCLE_INTERVAL and at
+// startup:
+//
+// - Expiration deletes objects older than Days, or all of them once Date has
+//   passed, the way a DELETE would: into the trash with --trash-days, behind
+//   a delete marker when the bucket is versioned
+// - AbortIncompleteMultipartUpload aborts uploads started longer than
+//   DaysAfterInitiation ago
+//
+// Rules pick objects by prefix, tags and size, with Filter or the older
+// top-level Prefix. Rules with tags start from the objects the tag index
+// has under their first tag, so a pass doesn't read every object's tags;
+// the others walk the objects. Objects under --worm or an object lock are
+// left alone.
+// Age is the time since the object was last written. Transitions and the
+// rules for noncurrent versions and delete markers aren't supported, and
+// rules with nothing else are refused. In cluster mode only the leader runs
+// it.
+
+const LIFECYCLE_INTERVAL: Duration = Duration::from_secs(60 * 60);
+const DAY: Duration = Duration::from_secs(24 * 60 * 60);
+const MAX_RULES: usize = 1000;
+const MAX_ID_LEN: usize = 255;
+
+#[derive(Debug, Clone, Serialize, Deserialize)]
+#[serde(rename = "LifecycleConfiguration")]
+pub struct LifecycleConfiguration {
+    #[serde(rename = "@xmlns", default)]
+    xmlns: String,
+    #[serde(rename = "Rule", default)]
+    rules: Vec<Rule>,
+}
+
+#[derive(Debug, Clone, Serialize, Deserialize)]
+struct Rule {
+    #[serde(rename = "ID", skip_serializing_if = "Option::is_none")]
+    id: Option<String>,
+    #[serde(rename = "Filter", skip_serializing_if = "Option::is_none")]
+    filter: Option<Filter>,
+    // Before Filter, rules only had a prefix
+    #[serde(rename = "Prefix", skip_serializing_if = "Option::is_none")]
+    prefix: Option<String>,
+    // Enabled or Disabled
+    #[serde(rename = "Status")]
+    status: String,
+    #[serde(rename = "Expiration", skip_serializing_if = "Option::is_none")]
+    expiration: Option<Expiration>,
+    #[serde(
+        rename = "AbortIncompleteMultipartUpload",
+        skip_serializing_if = "Option::is_none"
+    )]
+    abort_incomplete_multipart_upload: Option<AbortIncompleteMultipartUpload>,
+}
+
+#[derive(Debug, Clone, Default, Serialize, Deserialize)]
+struct Filter {
+    #[serde(rename = "Prefix", skip_serializing_if = "Option::is_none")]
+    prefix: Option<String>,
+    #[serde(rename = "Tag", skip_serializing_if = "Option::is_none")]
+    tag: Option<Tag>,
+    #[serde(rename = "ObjectSizeGreaterThan", skip_serializing_if = "Option::is_none")]
+    object_size_greater_than: Option<u64>,
+    #[serde(rename = "ObjectSizeLessThan", skip_serializing_if = "Option::is_none")]
+    object_size_less_than: Option<u64>,
+    #[serde(rename = "And", skip_serializing_if = "Option::is_none")]
+    and: Option<And>,
+}
+
+#[derive(Debug, Clone, Default, Serialize, Deserialize)]
+struct And {
+    #[serde(rename = "Prefix", skip_serializing_if = "Option::is_none")]
+    prefix: Option<String>,
+    #[serde(rename = "Tag", default, skip_serializing_if = "Vec::is_empty")]
+    tags: Vec<Tag>,
+    #[serde(rename = "ObjectSizeGreaterThan", skip_serializing_if = "Option::is_none")]
+    object_size_greater_than: Option<u64>,
+    #[serde(rename = "ObjectSizeLessThan", skip_serializing_if = "Option::is_none")]
+    object_size_less_than: Option<u64>,
+}
+
+#[derive(Debug, Clone, PartialEq, Serialize, Deserialize)]
+struct Tag {
+    #[serde(rename = "Key")]
+    key: String,
+    #[serde(rename = "Value", default)]
+    value: String,
+}
+
+#[derive(Debug, Clone, Serialize, Deserialize)]
+struct Expiration {
+    #[serde(rename = "Days", skip_serializing_if = "Option::is_none")]
+    days: Option<u32>,
+    #[serde(rename = "Date", skip_serializing_if = "Option::is_none")]
+    date: Option<String>,
+}
+
+#[derive(Debug, Clone, Serialize, Deserialize)]
+struct AbortIncompleteMultipartUpload {
+    #[serde(rename = "DaysAfterInitiation")]
+    days_after_initiation: u32,
+}
+
+impl Document for LifecycleConfiguration {
+    const FILE: &'static str = ".simple-s3/lifecycle.xml";
+    const NOT_FOUND: (&'static str, &'static str) = (
+        "NoSuchLifecycleConfiguration",
+        "The lifecycle configuration does not exist",
+    );
+
+    fn set_xmlns(&mut self, xmlns: &str) {
+        self.xmlns = xmlns.to_string();
+    }
+
+    fn is_valid(&self) -> bool {
+        (1..=MAX_RULES).contains(&self.rules.len()) && self.rules.iter().all(Rule::is_valid)
+    }
+}
+
+// What a rule picks objects by
+struct Criteria<'a> {
+    prefix: &'a str,
+    tags: Vec<&'a Tag>,
+    greater_than: Option<u64>,
+    less_than: Option<u64>,
+}
+
+impl Criteria<'_> {
+    fn matches_object(&self, key: &str, size: u64) -> bool {
+        key.starts_with(self.prefix)
+            && self.greater_than.is_none_or(|greater_than| size > greater_than)
+            && self.less_than.is_none_or(|less_than| size < less_than)
+    }
+
+    fn matches_tags(&self, tags: &tagging::Tags) -> bool {
+        self.tags.iter().all(|tag| {
+            tags.iter()
+                .any(|(key, value)| *key == tag.key && *value == tag.value)
+        })
+    }
+}
+
+impl Rule {
+    fn is_valid(&self) -> bool {
+        let expiration_valid = self.expiration.as_ref().is_none_or(|expiration| {
+            match (expiration.days, &expiration.date) {
+                (Some(days), None) => days > 0,
+                (None, Some(date)) => expiration_date(date).is_some(),
+                _ => false,
+            }
+        });
+        let abort_valid = self
+            .abort_incomplete_multipart_upload
+            .as_ref()
+            .is_none_or(|abort| abort.days_after_initiation > 0);
+        let has_action =
+            self.expiration.is_some() || self.abort_incomplete_multipart_upload.is_some();
+        // S3 doesn't abort uploads by tags or size, which they don't have yet
+        let abort_filtered = self.abort_incomplete_multipart_upload.is_some() && {
+            let criteria = self.criteria();
+            !criteria.tags.is_empty()
+                || criteria.greater_than.is_some()
+                || criteria.less_than.is_some()
+        };
+        matches!(self.status.as_str(), "Enabled" | "Disabled")
+            && self.id.as_ref().is_none_or(|id| id.len() <= MAX_ID_LEN)
+            && !(self.prefix.is_some() && self.filter.is_some())
+            && has_action
+            && expiration_valid
+            && abort_valid
+            && !abort_filtered
+    }
+
+    fn enabled(&self) -> bool {
+        self.status == "Enabled"
+    }
+
+    fn criteria(&self) -> Criteria<'_> {
+        let filter = self.filter.as_ref();
+        let and = filter.and_then(|filter| filter.and.as_ref());
+        let prefix = self
+            .prefix
+            .as_deref()
+            .or(filter.and_then(|filter| filter.prefix.as_deref()))
+            .or(and.and_then(|and| and.prefix.as_deref()))
+            .unwrap_or_default();
+        let tags = filter
+            .and_then(|filter| filter.tag.as_ref())
+            .into_iter()
+            .chain(and.iter().flat_map(|and| &and.tags))
+            .collect();
+        Criteria {
+            prefix,
+            tags,
+            greater_than: filter
+                .and_then(|filter| filter.object_size_greater_than)
+                .or(and.and_then(|and| and.object_size_greater_than)),
+            less_than: filter
+                .and_then(|filter| filter.object_size_less_than)
+                .or(and.and_then(|and| and.object_size_less_than)),
+        }
+    }
+
+    // Whether an object last written at `modified` has expired under the rule
+    fn expired(&self, modified: SystemTime, now: SystemTime) -> bool {
+        let Some(expiration) = &self.expiration else {
+            return false;
+        };
+        match (expiration.days, &expiration.date) {
+            (Some(days), _) => {
+                now.duration_since(modified).is_ok_and(|age| age >= DAY * days)
+            }
+            (None, Some(date)) => {
+                expiration_date(date).is_some_and(|date| DateTime::<Utc>::from(now) >= date)
+            }
+            (None, None) => false,
+        }
+    }
+}
+
+fn expiration_date(date: &str) -> Option<DateTime<Utc>> {
+    DateTime::parse_from_rfc3339(date.trim())
+        .ok()
+        .map(|date| date.with_timezone(&Utc))
+}
+
+pub fn spawn(state: Arc<AppState>) {
+    tokio::spawn(async move {
+        let mut interval = tokio::time::interval(LIFECYCLE_INTERVAL);
+        loop {
+            interval.tick().await;
+
+            if let Some(cluster) = &state.cluster
+                && !cluster.is_leader()
+            {
+                continue;
+            }
+            let Some(config) = state.lifecycle.get() else {
+                continue;
+            };
+            let rules: Vec<&Rule> = config.rules.iter().filter(|rule| rule.enabled()).collect();
+
+            let expired = expire_objects(&state, &rules).await;
+            if expired > 0 {
+                info!("⏳ Expired {} objects", expired);
+            }
+            let aborted = abort_uploads(&state, &rules).await;
+            if aborted > 0 {
+                info!("⏳ Aborted {} incomplete multipart uploads", aborted);
+            }
+        }
+    });
+}
+
+async fn expire_objects(state: &AppState, rules: &[&Rule]) -> usize {
+    let rules: Vec<(&Rule, Criteria)> = rules
+        .iter()
+        .filter(|rule| rule.expiration.is_some())
+        .map(|rule| (*rule, rule.criteria()))
+        .collect();
+    if rules.is_empty() {
+        return 0;
+    }
+
+    let now = SystemTime::now();
+    let (tagged, untagged): (Vec<_>, Vec<_>) = rules
+        .iter()
+        .partition(|(_, criteria)| !criteria.tags.is_empty());
+    let mut expiring = BTreeSet::new();
+
+    if !untagged.is_empty() {
+        walk::walk(state, state.data_dir.clone(), |key, metadata| {
+            let Ok(modified) = metadata.modified() else {
+                return;
+            };
+            if untagged.iter().any(|(rule, criteria)| {
+                criteria.matches_object(&key, metadata.len()) && rule.expired(modified, now)
+            }) {
+                expiring.insert(key);
+            }
+        })
+        .await;
+    }
+
+    for (rule, criteria) in tagged {
+        let first = criteria.tags[0];
+        for (key, metadata, tags) in tagging::tagged_with(state, &first.key, &first.value).await {
+            let Ok(modified) = metadata.modified() else {
+                continue;
+            };
+            if criteria.matches_object(&key, metadata.len())
+                && criteria.matches_tags(&tags)
+                && rule.expired(modified, now)
+            {
+                expiring.insert(key);
+            }
+        }
+    }
+
+    let mut expired = 0;
+    for key in expiring {
+        let Ok(file_path) = object_path(state, &key) else {
+            continue;
+        };
+        if worm::locked(state, &file_path).await {
+            continue;
+        }
+
+        let old = stats::size(&file_path).await;
+        let deleted = match versioning::delete(state, &key, &file_path, None).await {
+            Ok(Some(_)) => true,
+            Ok(None) => delete_file(state, &key, &file_path).await.is_ok(),
+            Err(_) => false,
+        };
+        if !deleted {
+            warn!("⏳ Failed to expire {}", key);
+            continue;
+        }
+        record_delete(state, &key, &file_path, old).await;
+        expired += 1;
+    }
+    expired
+}
+
+async fn abort_uploads(state: &AppState, rules: &[&Rule]) -> usize {
+    let rules: Vec<(&str, Duration)> = rules
+        .iter()
+        .filter_map(|rule| {
+            let abort = rule.abort_incomplete_multipart_upload.as_ref()?;
+            Some((rule.criteria().prefix, DAY * abort.days_after_initiation))
+        })
+        .collect();
+    if rules.is_empty() {
+        return 0;
+    }
+    let now = Utc::now();
+    multipart::abort_stale(state, |key, initiated| {
+        let age = (now - initiated).to_std().unwrap_or_default();
+        rules
+            .iter()
+            .any(|(prefix, max_age)| key.starts_with(prefix) && age >= *max_age)
+    })
+    .await
+}
@@ -0,0 +1,220 @@
+use base64::Engine;
+use std::{
+    collections::{HashMap, HashSet},
+    sync::{Arc, Mutex},
+    time::{Duration, Instant},
+};
+
+use crate::ObjectInfo;
+
+// Snapshot-consistent ListObjectsV2. When a listing doesn't fit on one page,
+// the objects it found are kept in memory and its continuation tokens name
+// them, so later pages come from the same point in time: keys written or
+// deleted meanwhile neither appear, disappear nor shift between pages. A
+// snapshot not asked for in SNAPSHOT_TTL, or taken more than SNAPSHOT_MAX_AGE
+// ago, is dropped. Snapshots together hold at most MAX_BYTES of objects, the
+// least recently used going first to make room, and a listing bigger than
+// that on its own isn't kept at all. Clients paging through the same prefix
+// at once mostly see the same objects, so a listing identical to one already
+// kept shares its objects rather than holding a second copy. A token whose
+// snapshot is gone, after a restart for instance, still carries the last key
+// of its page, so the listing carries on from there over the current
+// objects. ListObjects v1 pages by marker, which can't name a snapshot.
+
+const SNAPSHOT_TTL: Duration = Duration::from_secs(15 * 60);
+const SNAPSHOT_MAX_AGE: Duration = Duration::from_secs(60 * 60);
+const MAX_BYTES: usize = 256 * 1024 * 1024;
+
+struct Entry {
+    prefix: String,
+    // Sorted by key
+    objects: Arc<Vec<ObjectInfo>>,
+    // The memory `objects` takes, roughly
+    bytes: usize,
+    taken: Instant,
+    used: Instant,
+}
+
+impl Entry {
+    fn expired(&self) -> bool {
+        self.used.elapsed() >= SNAPSHOT_TTL || self.taken.elapsed() >= SNAPSHOT_MAX_AGE
+    }
+}
+
+#[derive(Default)]
+pub struct Snapshots {
+    entries: Mutex<HashMap<uuid::Uuid, Entry>>,
+}
+
+// Where a continuation token picks up
+pub struct Position {
+    pub snapshot: Option<uuid::Uuid>,
+    // The last key or common prefix of the previous page
+    pub after: String,
+}
+
+fn footprint(objects: &[ObjectInfo]) -> usize {
+    objects
+        .iter()
+        .map(|object| {
+            size_of::<ObjectInfo>()
+                + object.key.len()
+                + object.last_modified.len()
+                + object.etag.len()
+                + object.storage_class.len()
+        })
+        .sum()
+}
+
+fn same_objects(a: &[ObjectInfo], b: &[ObjectInfo]) -> bool {
+    a.len() == b.len()
+        && a.iter().zip(b).all(|(a, b)| {
+            a.key == b.key && a.size == b.size && a.last_modified == b.last_modified
+        })
+}
+
+// The memory the snapshots take, counting objects they share once
+fn held(entries: &HashMap<uuid::Uuid, Entry>) -> usize {
+    let mut seen = HashSet::new();
+    entries
+        .values()
+        .filter(|entry| seen.insert(Arc::as_ptr(&entry.objects)))
+        .map(|entry| entry.bytes)
+        .sum()
+}
+
+impl Snapshots {
+    // Keeps `objects`, sorted by key, for the pages after the first. None if
+    // they're too many to keep
+    pub fn insert(&self, prefix: &str, objects: Arc<Vec<ObjectInfo>>) -> Option<uuid::Uuid> {
+        let mut entries = self.entries.lock().unwrap();
+        entries.retain(|_, entry| !entry.expired());
+
+        let shared = entries
+            .values()
+            .find(|entry| entry.prefix == prefix && same_objects(&entry.objects, &objects))
+            .map(|entry| (entry.objects.clone(), entry.bytes));
+        let (objects, bytes) = match shared {
+            Some(shared) => shared,
+            None => {
+                let bytes = footprint(&objects);
+                if bytes > MAX_BYTES {
+                    return None;
+                }
+                while held(&entries) + bytes > MAX_BYTES
+                    && let Some(oldest) = entries
+                        .iter()
+                        .min_by_key(|(_, entry)| entry.used)
+                        .map(|(id, _)| *id)
+                {
+                    entries.remove(&oldest);
+                }
+                (objects, bytes)
+            }
+        };
+
+        let id = uuid::Uuid::new_v4();
+        let now = Instant::now();
+        entries.insert(
+            id,
+            Entry {
+                prefix: prefix.to_string(),
+                objects,
+                bytes,
+                taken: now,
+                used: now,
+            },
+        );
+        Some(id)
+    }
+
+    // The snapshot `id` of a listing of `prefix`, None once it's dropped
+    pub fn get(&self, id: uuid::Uuid, prefix: &str) -> Option<Arc<Vec<ObjectInfo>>> {
+        let mut entries = self.entries.lock().unwrap();
+        let entry = entries.get_mut(&id)?;
+        if entry.expired() || entry.prefix != prefix {
+            return None;
+        }
+        entry.used = Instant::now();
+        Some(entry.objects.clone())
+    }
+
+    // Drops snapshot `id` once its last page has been served
+    pub fn remove(&self, id: uuid::Uuid) {
+        self.entries.lock().unwrap().remove(&id);
+    }
+}
+
+// A token is the snapshot id and the last key of the page, base64 encoded
+pub fn token(snapshot: Option<uuid::Uuid>, after: &str) -> String {
+    let token = match snapshot {
+        Some(id) => format!("{}\n{}", id, after),
+        None => after.to_string(),
+    };
+    base64::engine::general_purpose::STANDARD.encode(token)
+}
+
+// None for a token this server didn't hand out. Tokens without a snapshot
+// id, like those from before snapshots, are just the last key
+pub fn parse_token(token: &str) -> Option<Position> {
+    let token = base64::engine::general_purpose::STANDARD.decode(token).ok()?;
+    let token = String::from_utf8(token).ok()?;
+    if let Some((id, after)) = token.split_once('\n')
+        && let Ok(id) = uuid::Uuid::parse_str(id)
+    {
+        return Some(Position {
+            snapshot: Some(id),
+            after: after.to_string(),
+        });
+    }
+    Some(Position {
+        snapshot: None,
+        after: token,
+    })
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    fn objects(keys: &[&str]) -> Arc<Vec<ObjectInfo>> {
+        Arc::new(
+            keys.iter()
+                .map(|key| ObjectInfo {
+                    key: key.to_string(),
+                    last_modified: "2024-01-01T00:00:00.000Z".to_string(),
+                    etag: String::new(),
+                    size: 1,
+                    owner: None,
+                    storage_class: "STANDARD".to_string(),
+                })
+                .collect(),
+        )
+    }
+
+    #[test]
+    fn identical_listings_share_their_objects() {
+        let snapshots = Snapshots::default();
+        let first = snapshots.insert("", objects(&["a", "b"])).unwrap();
+        let second = snapshots.insert("", objects(&["a", "b"])).unwrap();
+        let changed = snapshots.insert("", objects(&["a", "c"])).unwrap();
+        let elsewhere = snapshots.insert("a", objects(&["a", "b"])).unwrap();
+
+        let first = snapshots.get(first, "").unwrap();
+        assert!(Arc::ptr_eq(&first, &snapshots.get(second, "").unwrap()));
+        assert!(!Arc::ptr_eq(&first, &snapshots.get(changed, "").unwrap()));
+        assert!(!Arc::ptr_eq(&first, &snapshots.get(elsewhere, "a").unwrap()));
+
+        let entries = snapshots.entries.lock().unwrap();
+        assert_eq!(held(&entries), 3 * footprint(&first));
+    }
+
+    #[test]
+    fn a_snapshot_is_only_served_for_its_prefix() {
+        let snapshots = Snapshots::default();
+        let id = snapshots.insert("photos/", objects(&["photos/a"])).unwrap();
+        assert!(snapshots.get(id, "videos/").is_none());
+        snapshots.remove(id);
+        assert!(snapshots.get(id, "photos/").is_none());
+    }
+}
@@ -0,0 +1,152 @@
+use axum::{Json, extract::State};
+use serde::Serialize;
+use std::{
+    collections::HashMap,
+    net::IpAddr,
+    sync::{
+        Arc, Mutex,
+        atomic::{AtomicU64, Ordering},
+    },
+    time::{Duration, Instant},
+};
+use tracing::warn;
+
+use crate::AppState;
+
+// Brute-force protection. Failed logins are counted per client address. Past
+// --auth-backoff-after failures each further one blocks that address for
+// twice as long as the last, starting at a second, and at
+// --auth-lockout-after failures it's locked out for --auth-lockout seconds.
+// A successful login clears its address. Counts are forgotten once an
+// address has been quiet for the lockout period. --auth-lockout-after 0
+// turns all of this off. Access keys aren't counted: anyone can send bad
+// signatures for a key, and locking it would lock its owner out too.
+
+pub const AUTH_STATS_PATH: &str = "/.simple-s3/auth";
+const BASE_DELAY: Duration = Duration::from_secs(1);
+// Bound on tracked addresses, so spoofed or rotating ones can't exhaust
+// memory
+const MAX_TRACKED: usize = 100_000;
+
+struct Failures {
+    count: u32,
+    last: Instant,
+    blocked_until: Instant,
+}
+
+#[derive(Debug, Default, Serialize)]
+pub struct AuthStats {
+    pub failures: u64,
+    pub throttled: u64,
+    pub lockouts: u64,
+    pub blocked: usize,
+}
+
+pub struct Lockout {
+    backoff_after: u32,
+    lockout_after: u32,
+    lockout: Duration,
+    failures: Mutex<HashMap<IpAddr, Failures>>,
+    failed: AtomicU64,
+    throttled: AtomicU64,
+    lockouts: AtomicU64,
+}
+
+impl Lockout {
+    pub fn new(backoff_after: u32, lockout_after: u32, lockout: Duration) -> Self {
+        Self {
+            backoff_after,
+            lockout_after,
+            lockout,
+            failures: Mutex::new(HashMap::new()),
+            failed: AtomicU64::new(0),
+            throttled: AtomicU64::new(0),
+            lockouts: AtomicU64::new(0),
+        }
+    }
+
+    // How long the client must wait before trying again, if it's blocked
+    pub fn blocked(&self, ip: Option<IpAddr>) -> Option<Duration> {
+        let now = Instant::now();
+        let failures = self.failures.lock().unwrap();
+        let wait = ip
+            .and_then(|ip| failures.get(&ip))
+            .map(|failures| failures.blocked_until.saturating_duration_since(now))
+            .filter(|wait| !wait.is_zero());
+
+        if wait.is_some() {
+            self.throttled.fetch_add(1, Ordering::Relaxed);
+        }
+        wait
+    }
+
+    pub fn failed(&self, ip: Option<IpAddr>) {
+        self.failed.fetch_add(1, Ordering::Relaxed);
+        let Some(ip) = ip.filter(|_| self.lockout_after > 0) else {
+            return;
+        };
+        let now = Instant::now();
+        let mut failures = self.failures.lock().unwrap();
+
+        if failures.len() >= MAX_TRACKED {
+            let lockout = self.lockout;
+            failures.retain(|_, failures| now.duration_since(failures.last) < lockout);
+        }
+
+        if failures.len() >= MAX_TRACKED && !failures.contains_key(&ip) {
+            return;
+        }
+        let entry = failures.entry(ip).or_insert(Failures {
+            count: 0,
+            last: now,
+            blocked_until: now,
+        });
+        if now.duration_since(entry.last) >= self.lockout {
+            entry.count = 0;
+        }
+        entry.count += 1;
+        entry.last = now;
+
+        if entry.count >= self.lockout_after {
+            if entry.count == self.lockout_after {
+                self.lockouts.fetch_add(1, Ordering::Relaxed);
+                warn!("🔒 Locked out address {} for {:?}", ip, self.lockout);
+            }
+            entry.blocked_until = now + self.lockout;
+        } else if entry.count > self.backoff_after {
+            let doublings = (entry.count - self.backoff_after - 1).min(20);
+            entry.blocked_until = now + (BASE_DELAY * 2u32.pow(doublings)).min(self.lockout);
+        }
+    }
+
+    pub fn succeeded(&self, ip: Option<IpAddr>) {
+        let Some(ip) = ip else {
+            return;
+        };
+        let mut failures = self.failures.lock().unwrap();
+        if !failures.is_empty() {
+            failures.remove(&ip);
+        }
+    }
+
+    pub fn stats(&self) -> AuthStats {
+        let now = Instant::now();
+        AuthStats {
+            failures: self.failed.load(Ordering::Relaxed),
+            throttled: self.throttled.load(Ordering::Relaxed),
+            lockouts: self.lockouts.load(Ordering::Relaxed),
+            blocked: self
+                .failures
+                .lock()
+                .unwrap()
+                .values()
+                .filter(|failures| failures.blocked_until > now)
+                .count(),
+        }
+    }
+}
+
+// GET /.simple-s3/auth
+pub async fn auth_stats(State(state): State<Arc<AppState>>) -> Json<AuthStats> {
+    Json(state.lockout.stats())
+}
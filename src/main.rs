@@ -1,51 +1,501 @@
 use axum::{
-    body::Body,
-    extract::{Path, Query, Request, State},
+    body::{Body, Bytes},
+    extract::{DefaultBodyLimit, Extension, Path, Query, RawQuery, Request, State},
+    handler::Handler,
     http::{HeaderMap, HeaderValue, Method, StatusCode},
     middleware::{self, Next},
     response::{IntoResponse, Response},
-    routing::{delete, get, head, put},
+    routing::{delete, get, head, post, put},
     Router,
 };
-use clap::Parser;
+use clap::{Parser, Subcommand};
+use futures_util::StreamExt;
 use hmac::{Hmac, KeyInit, Mac}; 
+use percent_encoding::{AsciiSet, NON_ALPHANUMERIC, percent_decode_str, utf8_percent_encode};
 use serde::{Deserialize, Serialize};
 use sha2::{Digest, Sha256};
-use std::{path::PathBuf, sync::Arc};
-use tokio::{fs, io::AsyncWriteExt};
-use tower_http::cors::CorsLayer;
+use std::{
+    io,
+    net::IpAddr,
+    path::{Path as FsPath, PathBuf},
+    sync::Arc,
+    time::{Duration, SystemTime, UNIX_EPOCH},
+};
+use tokio::{
+    fs,
+    io::{AsyncSeekExt, AsyncWriteExt},
+};
 use tracing::{info, warn};
 
+mod access;
+mod acl;
+mod admin;
+mod authlog;
+mod buckets;
+mod chaos;
+mod checksum;
+mod cluster;
+mod conditional;
+mod copy;
+mod cors;
+mod deadline;
+mod erasure;
+mod etag;
+mod export;
+mod form;
+mod gc;
+mod handles;
+mod ingest;
+mod integrity;
+mod inventory;
+#[cfg(feature = "geoip")]
+mod geoip;
+#[cfg(feature = "grpc")]
+mod grpc;
+#[cfg(feature = "images")]
+mod images;
+mod ipfilter;
+mod keys;
+mod layout;
+mod lifecycle;
+mod listing;
+mod lockout;
+mod metrics;
+mod minio;
+mod mirror;
+mod multipart;
+mod normalize;
+mod pathstyle;
+mod placement;
+mod policy;
+mod provision;
+mod proxy;
+mod quota;
+mod readahead;
+mod replica;
+mod retention;
+mod scan;
+#[cfg(feature = "lua")]
+mod scripts;
+mod search;
+mod shed;
+mod sniff;
+mod snapshot;
+mod stats;
+#[cfg(feature = "sftp")]
+mod sftp;
+mod systemd;
+mod tagging;
+mod tier;
+mod timing;
+mod torrent;
+mod tuning;
+mod trash;
+mod tus;
+mod usage;
+mod versioning;
+mod walk;
+#[cfg(feature = "wasm")]
+mod wasm;
+#[cfg(feature = "watch")]
+mod watch;
+mod webdav;
+mod worm;
+#[cfg(windows)]
+mod winservice;
 
 type HmacSha256 = Hmac<Sha256>;
 
+// Server-owned files under the data dir, never exposed as objects
+const INTERNAL_DIR: &str = ".simple-s3";
+
+// Uploads are written here first and renamed into place once complete
+const TMP_DIR: &str = ".simple-s3/tmp";
+// Bodies up to this size are hashed on the async worker
+const INLINE_HASH_LIMIT: usize = 64 * 1024;
+
+const MAX_COMPOSE_SOURCES: usize = 1000;
+const MAX_DELETE_KEYS: usize = 1000;
+// Presigned URLs last at most a week, as in S3
+const MAX_PRESIGNED_EXPIRES: i64 = 7 * 24 * 60 * 60;
+// How far ahead of ours a client's clock may be when presigning, as in S3
+const MAX_CLOCK_SKEW: i64 = 15 * 60;
+
+// Characters left alone when putting a key into a URL
+const KEY_ENCODE_SET: &AsciiSet = &NON_ALPHANUMERIC
+    .remove(b'/')
+    .remove(b'-')
+    .remove(b'_')
+    .remove(b'.')
+    .remove(b'~');
+
 #[derive(Parser)]
 #[command(name = "simple-s3-server")]
 struct Args {
+    #[command(subcommand)]
+    command: Option<Command>,
+
     #[arg(long, default_value = "0.0.0.0", env = "HOST")]
     host: String,
 
     #[arg(short, long, default_value = "9000", env = "PORT")]
     port: u16,
 
-    #[arg(short, long, default_value = "simple-bucket", env = "BUCKET")]
+    #[arg(short, long, default_value = "simple-bucket", env = "BUCKET", global = true)]
     bucket: String,
 
-    #[arg(long, default_value = "mykey", env = "ACCESS_KEY")]
+    #[arg(long, env = "PATH_STYLE")]
+    path_style: bool,
+
+    #[arg(long, default_value = "mykey", env = "ACCESS_KEY", global = true)]
     access_key: String,
 
-    #[arg(long, default_value = "mysecret", env = "SECRET_KEY")]
+    #[arg(long, default_value = "mysecret", env = "SECRET_KEY", global = true)]
     secret_key: String,
 
-    #[arg(short, long, default_value = "./s3-data", env = "DATA_DIR")]
+    #[arg(short, long, default_value = "./s3-data", env = "DATA_DIR", global = true)]
     data_dir: PathBuf,
+
+    #[arg(long, default_value = "0", env = "MMAP_THRESHOLD")]
+    mmap_threshold: u64,
+
+    #[arg(long, default_value = "64", env = "OPEN_FILE_CACHE")]
+    open_file_cache: usize,
+
+    #[arg(long, default_value = "0", env = "MAX_BODY_SIZE")]
+    max_body_size: u64,
+
+    #[arg(long, default_value = "1000", env = "MAX_LIST_KEYS")]
+    max_list_keys: usize,
+
+    #[arg(long, env = "DEADLINE_HEADER")]
+    deadline_header: Option<String>,
+
+    #[arg(long, default_value = "0", env = "SLOW_REQUEST_MS")]
+    slow_request_ms: u64,
+
+    #[arg(long, default_value = "0", env = "MAX_CONCURRENT_REQUESTS")]
+    max_concurrent_requests: u64,
+
+    #[arg(long, default_value = "0", env = "MIN_FREE_DISK")]
+    min_free_disk: u64,
+
+    #[arg(long, env = "WORKER_THREADS")]
+    worker_threads: Option<usize>,
+
+    #[arg(long, env = "MAX_BLOCKING_THREADS")]
+    max_blocking_threads: Option<usize>,
+
+    #[arg(long, env = "READ_BUFFER_SIZE")]
+    read_buffer_size: Option<usize>,
+
+    #[arg(long, env = "WRITE_BUFFER_SIZE")]
+    write_buffer_size: Option<usize>,
+
+    #[arg(long, env = "NORMALIZE_KEYS")]
+    normalize_keys: bool,
+
+    #[arg(long, env = "FOLLOW_SYMLINKS")]
+    follow_symlinks: bool,
+
+    #[arg(long, env = "TRUSTED_PROXIES", value_delimiter = ',')]
+    trusted_proxies: Vec<proxy::Cidr>,
+
+    #[arg(long, env = "PUBLIC_ENDPOINT")]
+    public_endpoint: Option<String>,
+
+    #[arg(long, env = "STRICT_AUTH")]
+    strict_auth: bool,
+
+    #[arg(long, env = "DISABLE_QUERY_AUTH")]
+    disable_query_auth: bool,
+
+    #[arg(long, env = "IP_FILTER")]
+    ip_filter: Option<PathBuf>,
+
+    #[arg(long, default_value = "3", env = "AUTH_BACKOFF_AFTER")]
+    auth_backoff_after: u32,
+
+    #[arg(long, default_value = "10", env = "AUTH_LOCKOUT_AFTER")]
+    auth_lockout_after: u32,
+
+    #[arg(long, default_value = "900", env = "AUTH_LOCKOUT")]
+    auth_lockout: u64,
+
+    #[arg(long, env = "AUTH_LOG")]
+    auth_log: Option<PathBuf>,
+
+    #[arg(long, default_value = "*", env = "CORS_ORIGINS", value_delimiter = ',')]
+    cors_origins: Vec<String>,
+
+    #[arg(long, default_value = "*", env = "CORS_METHODS", value_delimiter = ',')]
+    cors_methods: Vec<String>,
+
+    #[arg(long, default_value = "*", env = "CORS_ALLOW_HEADERS", value_delimiter = ',')]
+    cors_allow_headers: Vec<String>,
+
+    #[arg(long, default_value = cors::DEFAULT_EXPOSE_HEADERS, env = "CORS_EXPOSE_HEADERS", value_delimiter = ',')]
+    cors_expose_headers: Vec<String>,
+
+    #[arg(long, default_value = "3600", env = "CORS_MAX_AGE")]
+    cors_max_age: u64,
+
+    #[arg(long, env = "CORS_ALLOW_CREDENTIALS")]
+    cors_allow_credentials: bool,
+
+    #[arg(long, env = "TUS")]
+    tus: bool,
+
+    #[arg(long, env = "MINIO_ADMIN")]
+    minio_admin: bool,
+
+    #[arg(long, env = "SEARCH")]
+    search: bool,
+
+    #[arg(long, env = "SEARCH_PREFIXES", value_delimiter = ',')]
+    search_prefixes: Vec<String>,
+
+    #[arg(long, default_value = "0", env = "TRASH_DAYS")]
+    trash_days: u64,
+
+    #[arg(long, env = "WORM")]
+    worm: bool,
+
+    #[arg(long, env = "SNIFF_CONTENT_TYPE")]
+    sniff_content_type: bool,
+
+    #[arg(long, env = "CLAMD")]
+    clamd: Option<String>,
+
+    #[arg(long, env = "SCAN_COMMAND")]
+    scan_command: Option<String>,
+
+    #[arg(long, default_value = "reject", env = "SCAN_ACTION")]
+    scan_action: scan::Action,
+
+    #[arg(long, env = "SCAN_LOG")]
+    scan_log: Option<PathBuf>,
+
+    #[arg(long, env = "INVENTORY")]
+    inventory: Option<inventory::Frequency>,
+
+    #[arg(long, default_value = "inventory", env = "INVENTORY_PREFIX")]
+    inventory_prefix: String,
+
+    #[arg(long, default_value = "0", env = "TIER_AFTER_DAYS")]
+    tier_after_days: u64,
+
+    #[arg(long, env = "COLD_DIR")]
+    cold_dir: Option<PathBuf>,
+
+    #[arg(long, env = "COLD_URL")]
+    cold_url: Option<String>,
+
+    #[arg(long, env = "COLD_ACCESS_KEY")]
+    cold_access_key: Option<String>,
+
+    #[arg(long, env = "COLD_SECRET_KEY")]
+    cold_secret_key: Option<String>,
+
+    #[arg(long, env = "COLD_AZURE_URL")]
+    cold_azure_url: Option<String>,
+
+    #[arg(long, env = "ERASURE_DIRS", value_delimiter = ',')]
+    erasure_dirs: Vec<PathBuf>,
+
+    #[arg(long, default_value = "2", env = "PARITY_SHARDS")]
+    parity_shards: usize,
+
+    #[arg(long, env = "TORRENT_TRACKERS", value_delimiter = ',')]
+    torrent_trackers: Vec<String>,
+
+    #[arg(long, env = "SNAPSHOT_SCHEDULES", value_delimiter = ',')]
+    snapshot_schedules: Vec<snapshot::Schedule>,
+
+    #[arg(long, env = "WEBDAV_PORT")]
+    webdav_port: Option<u16>,
+
+    #[arg(long, env = "ADMIN_PORT")]
+    admin_port: Option<u16>,
+
+    #[cfg(feature = "grpc")]
+    #[arg(long, env = "GRPC_PORT")]
+    grpc_port: Option<u16>,
+
+    #[arg(long, env = "PROVISION")]
+    provision: Option<PathBuf>,
+
+    #[arg(long, default_value = "0", env = "QUOTA_STORED_BYTES")]
+    quota_stored_bytes: u64,
+
+    #[arg(long, default_value = "0", env = "QUOTA_MONTHLY_TRANSFER")]
+    quota_monthly_transfer: u64,
+
+    #[arg(long, env = "PEERS", value_delimiter = ',')]
+    peers: Vec<String>,
+
+    #[arg(long, env = "ADVERTISE_URL")]
+    advertise_url: Option<String>,
+
+    #[arg(long, env = "REPLICAS")]
+    replicas: Option<usize>,
+
+    #[arg(long, env = "CHANGE_FEED")]
+    change_feed: bool,
+
+    #[arg(long, env = "REPLICATE_FROM")]
+    replicate_from: Option<String>,
+
+    #[arg(long, env = "MIRROR_TO")]
+    mirror_to: Option<String>,
+
+    #[arg(long, default_value = "100", env = "MIRROR_PERCENT")]
+    mirror_percent: f64,
+
+    #[cfg(feature = "sftp")]
+    #[arg(long, env = "SFTP_PORT")]
+    sftp_port: Option<u16>,
+
+    #[cfg(feature = "sftp")]
+    #[arg(long, env = "SFTP_AUTHORIZED_KEYS")]
+    sftp_authorized_keys: Option<PathBuf>,
+
+    #[cfg(feature = "wasm")]
+    #[arg(long, env = "TRANSFORMS")]
+    transforms: Option<PathBuf>,
+
+    #[cfg(feature = "wasm")]
+    #[arg(long, env = "PLUGINS")]
+    plugins: Option<PathBuf>,
+
+    #[cfg(feature = "lua")]
+    #[arg(long, env = "SCRIPTS_DIR")]
+    scripts_dir: Option<PathBuf>,
+
+    #[cfg(feature = "geoip")]
+    #[arg(long, env = "GEOIP_DB", requires = "geoip_rules")]
+    geoip_db: Option<PathBuf>,
+
+    #[cfg(feature = "geoip")]
+    #[arg(long, env = "GEOIP_RULES", requires = "geoip_db")]
+    geoip_rules: Option<PathBuf>,
+}
+#[derive(Subcommand)]
+enum Command {
+    #[command(about = "Upgrade the data directory to the current storage layout")]
+    Migrate,
+    #[command(about = "Rename stored keys to Unicode NFC, for use with --normalize-keys")]
+    NormalizeKeys,
+    #[command(about = "Adopt an existing directory tree as objects")]
+    Ingest {
+        #[arg(long)]
+        from: PathBuf,
+        // Rename the files into the data dir instead of copying them
+        #[arg(long = "move", conflicts_with = "link")]
+        move_files: bool,
+        // Hard link the files into the data dir instead of copying them
+        #[arg(long)]
+        link: bool,
+    },
+    #[cfg(feature = "watch")]
+    #[command(about = "Mirror a local directory into a bucket as it changes")]
+    Watch {
+        #[arg(long)]
+        dir: PathBuf,
+        // Bucket endpoint to upload to, like http://localhost:9000
+        #[arg(long)]
+        to: String,
+        // Key prefix for the mirrored files
+        #[arg(long, default_value = "")]
+        prefix: String,
+    },
+    #[cfg(windows)]
+    #[command(about = "Install, remove or run the server as a Windows service")]
+    Service {
+        #[command(subcommand)]
+        command: winservice::ServiceCommand,
+    },
 }
+
 #[derive(Clone)]
 struct AppState {
     bucket_name: String,
+    // Requests may name the bucket at the start of the path
+    path_style: bool,
     access_key: String,
     secret_key: String,
+    // Only SigV4 is accepted
+    strict_auth: bool,
+    // ?access_key=&secret_key= is accepted
+    query_auth: bool,
+    lockout: Arc<lockout::Lockout>,
+    auth_log: Option<Arc<authlog::AuthLog>>,
+    ip_filter: Option<Arc<ipfilter::IpFilter>>,
+    #[cfg(feature = "geoip")]
+    geoip: Option<Arc<geoip::GeoIp>>,
     data_dir: PathBuf,
+    // data_dir with symlinks resolved
+    data_root: PathBuf,
+    follow_symlinks: bool,
+    trusted_proxies: Vec<proxy::Cidr>,
+    // Host clients address the server by, from --public-endpoint
+    public_host: Option<String>,
+    mmap_threshold: u64,
+    handles: Arc<handles::HandleCache>,
+    readahead: Arc<readahead::Readahead>,
+    // Objects of the ListObjectsV2 listings still being paged through
+    listings: Arc<listing::Snapshots>,
+    max_body_size: u64,
+    max_list_keys: usize,
+    // Request header giving the milliseconds a client will wait
+    deadline_header: Option<String>,
+    // Requests taking longer are logged with where the time went
+    slow_request: Option<Duration>,
+    requests: Arc<timing::Requests>,
+    // Bytes read and written at a time when streaming objects to and from
+    // disk
+    read_buffer: usize,
+    write_buffer: usize,
+    shedder: Arc<shed::Shedder>,
+    normalize_keys: bool,
+    cluster: Option<Arc<cluster::Cluster>>,
+    replicas: Option<usize>,
+    changes: Option<Arc<replica::ChangeFeed>>,
+    read_only: bool,
+    // Shadow endpoint a share of requests is copied to
+    mirror: Option<Arc<mirror::Mirror>>,
+    stats: Arc<stats::BucketStats>,
+    metrics: Arc<metrics::RequestMetrics>,
+    quotas: Arc<quota::Quotas>,
+    usage: Arc<usage::Ledger>,
+    // How long deleted objects are kept in the trash, None deletes outright
+    trash_retention: Option<Duration>,
+    // Objects can't be overwritten or deleted once written
+    worm: bool,
+    // Held by PUTs with If-Match from checking the ETag to storing the object
+    conditional_writes: Arc<tokio::sync::Mutex<()>>,
+    // Held by ranged writes from copying the object to renaming it back
+    ranged_writes: Arc<tokio::sync::Mutex<()>>,
+    // Objects without a known extension are served with the type of their contents
+    sniff_content_type: bool,
+    // Uploads are scanned for malware before they're stored
+    scanner: Option<Arc<scan::Scanner>>,
+    snapshots: Arc<snapshot::Snapshots>,
+    access: Arc<access::Access>,
+    versioning: Arc<versioning::Versioning>,
+    lifecycle: Arc<access::BucketConfig<lifecycle::LifecycleConfiguration>>,
+    cors: Arc<access::BucketConfig<cors::CorsConfiguration>>,
+    policy: Arc<policy::BucketPolicy>,
+    torrents: Arc<torrent::Torrents>,
+    tiering: Option<Arc<tier::Tiering>>,
+    // --erasure-dirs, which objects written over S3 are encoded into before
+    // the write is acknowledged
+    erasure: Option<Arc<erasure::ErasureSet>>,
+    search: Option<Arc<search::SearchIndex>>,
+    // Announce URLs put in torrents, none leaves peers to DHT
+    torrent_trackers: Vec<String>,
+    #[cfg(feature = "wasm")]
+    transforms: Option<Arc<wasm::Transforms>>,
 }
 
 #[derive(Debug, Deserialize)]
@@ -54,6 +504,175 @@ struct ListObjectsQuery {
     max_keys: Option<usize>,
     prefix: Option<String>,
     marker: Option<String>,
+    delimiter: Option<String>,
+    // 2 for ListObjectsV2
+    #[serde(rename = "list-type")]
+    list_type: Option<String>,
+    #[serde(rename = "continuation-token")]
+    continuation_token: Option<String>,
+    #[serde(rename = "start-after")]
+    start_after: Option<String>,
+    // ListObjectsV2 only gives owners when asked
+    #[serde(rename = "fetch-owner")]
+    fetch_owner: Option<String>,
+    uploads: Option<String>,
+    #[serde(rename = "max-uploads")]
+    max_uploads: Option<usize>,
+    #[serde(rename = "key-marker")]
+    key_marker: Option<String>,
+    #[serde(rename = "upload-id-marker")]
+    upload_id_marker: Option<String>,
+    #[serde(rename = "encoding-type")]
+    encoding_type: Option<String>,
+    // Non-standard: stream the objects under the prefix as a tar archive
+    export: Option<String>,
+    #[serde(rename = "publicAccessBlock")]
+    public_access_block: Option<String>,
+    #[serde(rename = "ownershipControls")]
+    ownership_controls: Option<String>,
+    versioning: Option<String>,
+    versions: Option<String>,
+    #[serde(rename = "object-lock")]
+    object_lock: Option<String>,
+    lifecycle: Option<String>,
+    policy: Option<String>,
+    acl: Option<String>,
+    cors: Option<String>,
+    // Non-standard: full-text search of the objects indexed with --search
+    query: Option<String>,
+}
+
+// Bucket subresources written with PUT, POST or DELETE /
+#[derive(Debug, Deserialize)]
+struct BucketQuery {
+    delete: Option<String>,
+    #[serde(rename = "publicAccessBlock")]
+    public_access_block: Option<String>,
+    #[serde(rename = "ownershipControls")]
+    ownership_controls: Option<String>,
+    versioning: Option<String>,
+    lifecycle: Option<String>,
+    policy: Option<String>,
+    acl: Option<String>,
+    cors: Option<String>,
+}
+
+#[derive(Debug, Deserialize)]
+struct GetObjectQuery {
+    torrent: Option<String>,
+    #[serde(rename = "uploadId")]
+    upload_id: Option<String>,
+    #[serde(rename = "part-number-marker")]
+    part_number_marker: Option<u32>,
+    #[serde(rename = "max-parts")]
+    max_parts: Option<usize>,
+    attributes: Option<String>,
+    tagging: Option<String>,
+    retention: Option<String>,
+    #[serde(rename = "legal-hold")]
+    legal_hold: Option<String>,
+    acl: Option<String>,
+    #[serde(rename = "versionId")]
+    version_id: Option<String>,
+}
+
+#[derive(Debug, Default, Deserialize)]
+struct PutObjectQuery {
+    // Non-standard: write the body at this byte offset of the object
+    offset: Option<u64>,
+    #[serde(rename = "partNumber")]
+    part_number: Option<u32>,
+    #[serde(rename = "uploadId")]
+    upload_id: Option<String>,
+    tagging: Option<String>,
+    retention: Option<String>,
+    #[serde(rename = "legal-hold")]
+    legal_hold: Option<String>,
+    acl: Option<String>,
+}
+
+#[derive(Debug, Deserialize)]
+struct PostObjectQuery {
+    compose: Option<String>,
+    uploads: Option<String>,
+    #[serde(rename = "uploadId")]
+    upload_id: Option<String>,
+}
+
+#[derive(Debug, Deserialize)]
+struct DeleteObjectQuery {
+    #[serde(rename = "uploadId")]
+    upload_id: Option<String>,
+    tagging: Option<String>,
+    #[serde(rename = "versionId")]
+    version_id: Option<String>,
+}
+
+// The keys to delete for POST /?delete
+#[derive(Debug, Deserialize)]
+struct DeleteRequest {
+    #[serde(rename = "Object", default)]
+    objects: Vec<DeleteObject>,
+    #[serde(rename = "Quiet")]
+    quiet: Option<String>,
+}
+
+#[derive(Debug, Deserialize)]
+struct DeleteObject {
+    #[serde(rename = "Key")]
+    key: String,
+    // Without versioning there are no versions, but one asked for is echoed
+    // back
+    #[serde(rename = "VersionId")]
+    version_id: Option<String>,
+}
+
+#[derive(Debug, Serialize)]
+#[serde(rename = "DeleteResult")]
+struct DeleteResult {
+    #[serde(rename = "@xmlns")]
+    xmlns: String,
+    #[serde(rename = "Deleted")]
+    deleted: Vec<DeletedObject>,
+    #[serde(rename = "Error")]
+    errors: Vec<DeleteError>,
+}
+
+#[derive(Debug, Serialize)]
+struct DeletedObject {
+    #[serde(rename = "Key")]
+    key: String,
+    #[serde(rename = "VersionId", skip_serializing_if = "Option::is_none")]
+    version_id: Option<String>,
+    #[serde(rename = "DeleteMarker", skip_serializing_if = "Option::is_none")]
+    delete_marker: Option<bool>,
+    #[serde(rename = "DeleteMarkerVersionId", skip_serializing_if = "Option::is_none")]
+    delete_marker_version_id: Option<String>,
+}
+
+#[derive(Debug, Serialize)]
+struct DeleteError {
+    #[serde(rename = "Key")]
+    key: String,
+    #[serde(rename = "VersionId", skip_serializing_if = "Option::is_none")]
+    version_id: Option<String>,
+    #[serde(rename = "Code")]
+    code: String,
+    #[serde(rename = "Message")]
+    message: String,
+}
+
+// Non-standard: the sources to concatenate for POST /{key}?compose
+#[derive(Debug, Deserialize)]
+struct ComposeRequest {
+    #[serde(rename = "Source", default)]
+    sources: Vec<ComposeSource>,
+}
+
+#[derive(Debug, Deserialize)]
+struct ComposeSource {
+    #[serde(rename = "Key")]
+    key: String,
 }
 
 #[derive(Debug, Serialize)]
@@ -65,17 +684,41 @@ struct ListBucketResult {
     name: String,
     #[serde(rename = "Prefix")]
     prefix: String,
-    #[serde(rename = "Marker")]
-    marker: String,
+    // ListObjects only
+    #[serde(rename = "Marker", skip_serializing_if = "Option::is_none")]
+    marker: Option<String>,
+    #[serde(rename = "NextMarker", skip_serializing_if = "Option::is_none")]
+    next_marker: Option<String>,
+    // ListObjectsV2 only
+    #[serde(rename = "ContinuationToken", skip_serializing_if = "Option::is_none")]
+    continuation_token: Option<String>,
+    #[serde(rename = "NextContinuationToken", skip_serializing_if = "Option::is_none")]
+    next_continuation_token: Option<String>,
+    #[serde(rename = "StartAfter", skip_serializing_if = "Option::is_none")]
+    start_after: Option<String>,
+    #[serde(rename = "KeyCount", skip_serializing_if = "Option::is_none")]
+    key_count: Option<usize>,
+    #[serde(rename = "Delimiter", skip_serializing_if = "Option::is_none")]
+    delimiter: Option<String>,
     #[serde(rename = "MaxKeys")]
     max_keys: usize,
     #[serde(rename = "IsTruncated")]
     is_truncated: bool,
+    #[serde(rename = "EncodingType", skip_serializing_if = "Option::is_none")]
+    encoding_type: Option<String>,
     #[serde(rename = "Contents")]
     contents: Vec<ObjectInfo>,
+    #[serde(rename = "CommonPrefixes")]
+    common_prefixes: Vec<CommonPrefix>,
 }
 
 #[derive(Debug, Serialize)]
+struct CommonPrefix {
+    #[serde(rename = "Prefix")]
+    prefix: String,
+}
+
+#[derive(Debug, Clone, Serialize)]
 struct ObjectInfo {
     #[serde(rename = "Key")]
     key: String,
@@ -85,10 +728,103 @@ struct ObjectInfo {
     etag: String,
     #[serde(rename = "Size")]
     size: u64,
+    #[serde(rename = "Owner", skip_serializing_if = "Option::is_none")]
+    owner: Option<Owner>,
     #[serde(rename = "StorageClass")]
     storage_class: String,
 }
 
+// Every object belongs to the one access key. Its ID stands in for S3's
+// canonical user ID, which is 64 hex digits too
+#[derive(Debug, Clone, Serialize)]
+struct Owner {
+    #[serde(rename = "ID")]
+    id: String,
+    #[serde(rename = "DisplayName")]
+    display_name: String,
+}
+
+impl Owner {
+    fn of(state: &AppState) -> Self {
+        Self {
+            id: hex::encode(Sha256::digest(state.access_key.as_bytes())),
+            display_name: state.access_key.clone(),
+        }
+    }
+}
+
+#[derive(Debug, Serialize)]
+#[serde(rename = "Error")]
+struct ErrorResponse {
+    #[serde(rename = "Code")]
+    code: String,
+    #[serde(rename = "Message")]
+    message: String,
+    #[serde(rename = "MaxSizeAllowed", skip_serializing_if = "Option::is_none")]
+    max_size_allowed: Option<u64>,
+}
+
+#[derive(Debug, Serialize)]
+#[serde(rename = "CopyObjectResult")]
+struct CopyObjectResult {
+    #[serde(rename = "LastModified")]
+    last_modified: String,
+    #[serde(rename = "ETag")]
+    etag: String,
+}
+
+
+// The query string as SigV4 signs it: every parameter as name=value, even
+// ones without a value like ?uploads, encoded the same way and sorted
+fn canonical_query(query: &str) -> String {
+    const QUERY_ENCODE_SET: &AsciiSet = &KEY_ENCODE_SET.add(b'/');
+    let encode = |s: &str| {
+        let decoded = percent_decode_str(s).decode_utf8_lossy();
+        utf8_percent_encode(&decoded, QUERY_ENCODE_SET).to_string()
+    };
+    let mut params: Vec<(String, String)> = query
+        .split('&')
+        .filter(|param| !param.is_empty())
+        .map(|param| {
+            let (name, value) = param.split_once('=').unwrap_or((param, ""));
+            (encode(name), encode(value))
+        })
+        .collect();
+    params.sort();
+    params
+        .iter()
+        .map(|(name, value)| format!("{}={}", name, value))
+        .collect::<Vec<_>>()
+        .join("&")
+}
+
+// What SigV4 signatures are checked against: the bucket's keys, and the
+// public host clients sign when a proxy rewrites Host
+struct Keys<'a> {
+    access_key: &'a str,
+    secret_key: &'a str,
+    public_host: Option<&'a str>,
+}
+
+impl<'a> Keys<'a> {
+    fn of(state: &'a AppState) -> Self {
+        Self {
+            access_key: &state.access_key,
+            secret_key: &state.secret_key,
+            public_host: state.public_host.as_deref(),
+        }
+    }
+}
+
+// What a SigV4 signature covers, from the Authorization header or the
+// query string of a presigned URL
+struct SignedV4<'a> {
+    credential: &'a str,
+    signed_headers: &'a str,
+    signature: &'a str,
+    amz_date: &'a str,
+    content_sha256: &'a str,
+}
 
 fn verify_aws_v4_signature(
     auth_header: &str,
@@ -127,6 +863,146 @@ fn verify_aws_v4_signature(
         }
     }
 
+    let signed = SignedV4 {
+        credential,
+        signed_headers,
+        signature,
+        amz_date,
+        content_sha256,
+    };
+    verify_v4(&signed, headers, method, uri_path, &canonical_query(query), &Keys::of(state))
+}
+
+fn is_presigned(query: &str) -> bool {
+    query.split('&').any(|param| param == "X-Amz-Algorithm=AWS4-HMAC-SHA256")
+}
+
+// Why a presigned URL can't be used now
+#[derive(Debug, PartialEq)]
+enum PresignedRefusal {
+    Malformed(&'static str),
+    NotYetValid,
+    Expired,
+}
+
+impl PresignedRefusal {
+    fn response(&self) -> Response {
+        let (status, code, message) = match self {
+            Self::Malformed(message) => {
+                (StatusCode::BAD_REQUEST, "AuthorizationQueryParametersError", *message)
+            }
+            Self::NotYetValid => {
+                (StatusCode::FORBIDDEN, "AccessDenied", "Request is not valid yet")
+            }
+            Self::Expired => (StatusCode::FORBIDDEN, "AccessDenied", "Request has expired"),
+        };
+        error_response(
+            status,
+            ErrorResponse {
+                code: code.to_string(),
+                message: message.to_string(),
+                max_size_allowed: None,
+            },
+        )
+    }
+}
+
+// The value of query parameter `name`, decoded, or "" without one
+fn query_param(query: &str, name: &str) -> String {
+    query
+        .split('&')
+        .filter_map(|param| param.split_once('='))
+        .find(|(param, _)| *param == name)
+        .map_or(String::new(), |(_, value)| {
+            percent_decode_str(value).decode_utf8_lossy().into_owned()
+        })
+}
+
+// Whether a presigned URL may be used at `now`: from its X-Amz-Date, give or
+// take MAX_CLOCK_SKEW for a client clock running ahead, until X-Amz-Expires
+// seconds after it
+fn presigned_window(query: &str, now: chrono::NaiveDateTime) -> Result<(), PresignedRefusal> {
+    let date = query_param(query, "X-Amz-Date");
+    let Ok(date) = chrono::NaiveDateTime::parse_from_str(&date, "%Y%m%dT%H%M%SZ") else {
+        return Err(PresignedRefusal::Malformed("X-Amz-Date must be in the ISO8601 Long Format"));
+    };
+    let expires = query_param(query, "X-Amz-Expires")
+        .parse::<i64>()
+        .ok()
+        .filter(|expires| (1..=MAX_PRESIGNED_EXPIRES).contains(expires));
+    let Some(expires) = expires else {
+        return Err(PresignedRefusal::Malformed(
+            "X-Amz-Expires must be between 1 and 604800 seconds",
+        ));
+    };
+    if date > now + chrono::Duration::seconds(MAX_CLOCK_SKEW) {
+        return Err(PresignedRefusal::NotYetValid);
+    }
+    if now > date + chrono::Duration::seconds(expires) {
+        return Err(PresignedRefusal::Expired);
+    }
+    Ok(())
+}
+
+// Presigned URLs, SigV4 in the query string: X-Amz-Algorithm,
+// X-Amz-Credential, X-Amz-Date, X-Amz-Expires, X-Amz-SignedHeaders and
+// X-Amz-Signature. The signature covers the rest of the query, and the URL
+// works within presigned_window
+fn verify_presigned_url(
+    headers: &HeaderMap,
+    method: &Method,
+    uri_path: &str,
+    query: &str,
+    keys: &Keys,
+    now: chrono::NaiveDateTime,
+) -> bool {
+    if let Err(refusal) = presigned_window(query, now) {
+        warn!("⌛ Presigned URL refused: {:?}", refusal);
+        return false;
+    }
+
+    // Everything but the signature itself is signed
+    let unsigned: String = query
+        .split('&')
+        .filter(|param| param.split_once('=').map_or(*param, |(name, _)| name) != "X-Amz-Signature")
+        .collect::<Vec<_>>()
+        .join("&");
+    let content_sha256 = headers
+        .get("x-amz-content-sha256")
+        .and_then(|v| v.to_str().ok())
+        .unwrap_or("UNSIGNED-PAYLOAD");
+    let (credential, signed_headers, signature, amz_date) = (
+        query_param(query, "X-Amz-Credential"),
+        query_param(query, "X-Amz-SignedHeaders"),
+        query_param(query, "X-Amz-Signature"),
+        query_param(query, "X-Amz-Date"),
+    );
+    let signed = SignedV4 {
+        credential: &credential,
+        signed_headers: &signed_headers,
+        signature: &signature,
+        amz_date: &amz_date,
+        content_sha256,
+    };
+    verify_v4(&signed, headers, method, uri_path, &canonical_query(&unsigned), keys)
+}
+
+// Whether `signed` is the signature of the request under the secret key
+fn verify_v4(
+    signed: &SignedV4,
+    headers: &HeaderMap,
+    method: &Method,
+    uri_path: &str,
+    canonical_query: &str,
+    keys: &Keys,
+) -> bool {
+    let SignedV4 {
+        credential,
+        signed_headers,
+        signature,
+        amz_date,
+        content_sha256,
+    } = *signed;
 
     let cred_parts: Vec<&str> = credential.split('/').collect();
     if cred_parts.len() != 5 {
@@ -137,67 +1013,195 @@ fn verify_aws_v4_signature(
     let region = cred_parts[2];
     let service = cred_parts[3];
 
-    if access_key != state.access_key {
+    if access_key != keys.access_key {
         warn!("Mismatched access key in V4 auth");
         return false;
     }
 
-    let mut canonical_headers = String::new();
     let mut sorted_signed_headers: Vec<&str> =
         signed_headers.split(';').collect();
     sorted_signed_headers.sort_unstable();
 
-    for header_name in &sorted_signed_headers {
-        if let Some(value) = headers.get(*header_name) {
-            canonical_headers
-                .push_str(&format!("{}:{}\n", header_name, value.to_str().unwrap_or("").trim()));
+    // The string to sign, with the Host header optionally replaced
+    let string_to_sign = |host: Option<&str>| {
+        let mut canonical_headers = String::new();
+        for header_name in &sorted_signed_headers {
+            let value = match (*header_name, host) {
+                ("host", Some(host)) => Some(host),
+                _ => headers
+                    .get(*header_name)
+                    .map(|value| value.to_str().unwrap_or("")),
+            };
+            if let Some(value) = value {
+                canonical_headers.push_str(&format!("{}:{}\n", header_name, value.trim()));
+            }
+        }
+
+        let canonical_request = format!(
+            "{}\n{}\n{}\n{}\n{}\n{}",
+            method,
+            uri_path,
+            canonical_query,
+            canonical_headers,
+            signed_headers,
+            content_sha256
+        );
+
+        let canonical_request_hash =
+            hex::encode(Sha256::digest(canonical_request.as_bytes()));
+        let scope = format!("{}/{}/{}/aws4_request", date, region, service);
+        format!(
+            "AWS4-HMAC-SHA256\n{}\n{}\n{}",
+            amz_date, scope, canonical_request_hash
+        )
+    };
+
+    let signing_key = signing_key(keys.secret_key, date, region, service);
+    let sign = |string_to_sign: String| {
+        let mut mac = HmacSha256::new_from_slice(&signing_key).unwrap();
+        mac.update(string_to_sign.as_bytes());
+        hex::encode(mac.finalize().into_bytes())
+    };
+    let calculated_signature = sign(string_to_sign(None));
+
+    info!("Provided Signature:   {}", signature);
+    info!("Calculated Signature: {}", calculated_signature);
+
+    if calculated_signature == signature {
+        return true;
+    }
+
+    // Behind a proxy that rewrites Host, the client signed the public host
+    match keys.public_host {
+        Some(public_host) if sorted_signed_headers.contains(&"host") => {
+            sign(string_to_sign(Some(public_host))) == signature
         }
+        _ => false,
+    }
+}
+
+// The SigV4 signing key for one day, region and service
+fn signing_key(secret_key: &str, date: &str, region: &str, service: &str) -> Vec<u8> {
+    let mut key = format!("AWS4{}", secret_key).into_bytes();
+    for part in [date, region, service, "aws4_request"] {
+        let mut mac = HmacSha256::new_from_slice(&key).unwrap();
+        mac.update(part.as_bytes());
+        key = mac.finalize().into_bytes().to_vec();
     }
+    key
+}
+
+// Sign a request to another node with SigV4, the way verify_aws_v4_signature
+// checks it, so the secret key never goes over the wire
+fn sign_request(access_key: &str, secret_key: &str, request: &mut reqwest::Request) {
+    let url = request.url();
+    let host = match url.port() {
+        Some(port) => format!("{}:{}", url.host_str().unwrap_or_default(), port),
+        None => url.host_str().unwrap_or_default().to_string(),
+    };
+    let now = chrono::Utc::now();
+    let amz_date = now.format("%Y%m%dT%H%M%SZ").to_string();
+    let date = now.format("%Y%m%d").to_string();
+    let signed_headers = "host;x-amz-content-sha256;x-amz-date";
 
     let canonical_request = format!(
-        "{}\n{}\n{}\n{}\n{}\n{}",
-        method,
-        uri_path,
-        query,
-        canonical_headers,
-        signed_headers,
-        content_sha256
+        "{}\n{}\n{}\nhost:{}\nx-amz-content-sha256:UNSIGNED-PAYLOAD\nx-amz-date:{}\n\n{}\nUNSIGNED-PAYLOAD",
+        request.method(),
+        url.path(),
+        url.query().unwrap_or(""),
+        host,
+        amz_date,
+        signed_headers
     );
-
-    let canonical_request_hash =
-        hex::encode(Sha256::digest(canonical_request.as_bytes()));
-    let scope = format!("{}/{}/{}/{}/aws4_request", date, region, service, "aws4_request");
+    let scope = format!("{}/us-east-1/s3/aws4_request", date);
     let string_to_sign = format!(
         "AWS4-HMAC-SHA256\n{}\n{}\n{}",
-        amz_date, scope, canonical_request_hash
+        amz_date,
+        scope,
+        hex::encode(Sha256::digest(canonical_request.as_bytes()))
     );
 
-    let secret = format!("AWS4{}", state.secret_key);
-    let mut mac = HmacSha256::new_from_slice(secret.as_bytes()).unwrap();
-    mac.update(date.as_bytes());
-    let date_key = mac.finalize().into_bytes();
-
-    let mut mac = HmacSha256::new_from_slice(&date_key).unwrap();
-    mac.update(region.as_bytes());
-    let region_key = mac.finalize().into_bytes();
-
-    let mut mac = HmacSha256::new_from_slice(&region_key).unwrap();
-    mac.update(service.as_bytes());
-    let service_key = mac.finalize().into_bytes();
-
-    let mut mac = HmacSha256::new_from_slice(&service_key).unwrap();
-    mac.update(b"aws4_request");
-    let signing_key = mac.finalize().into_bytes();
-
-    let mut mac = HmacSha256::new_from_slice(&signing_key).unwrap();
+    let mut mac = HmacSha256::new_from_slice(&signing_key(
+        secret_key,
+        &date,
+        "us-east-1",
+        "s3",
+    ))
+    .unwrap();
     mac.update(string_to_sign.as_bytes());
-    let calculated_signature = hex::encode(mac.finalize().into_bytes());
+    let authorization = format!(
+        "AWS4-HMAC-SHA256 Credential={}/{}, SignedHeaders={}, Signature={}",
+        access_key,
+        scope,
+        signed_headers,
+        hex::encode(mac.finalize().into_bytes())
+    );
 
-    info!("Provided Signature:   {}", signature);
-    info!("Calculated Signature: {}", calculated_signature);
+    let headers = request.headers_mut();
+    for (name, value) in [
+        ("host", host),
+        ("x-amz-date", amz_date),
+        ("x-amz-content-sha256", "UNSIGNED-PAYLOAD".to_string()),
+        ("authorization", authorization),
+    ] {
+        if let Ok(value) = HeaderValue::from_str(&value) {
+            headers.insert(name, value);
+        }
+    }
+}
+
+// Send a request to another node, signed with SigV4
+async fn send_signed(
+    state: &AppState,
+    request: reqwest::RequestBuilder,
+) -> Result<reqwest::Response, reqwest::Error> {
+    let (client, request) = request.build_split();
+    let mut request = request?;
+    sign_request(&state.access_key, &state.secret_key, &mut request);
+    client.execute(request).await
+}
 
+// The cold backend of --tier-after-days, None when tiering is off
+fn tiering_backend(args: &Args) -> Result<Option<tier::Backend>, String> {
+    if args.tier_after_days == 0 {
+        return Ok(None);
+    }
+    match (&args.cold_dir, &args.cold_url, &args.cold_azure_url) {
+        (Some(dir), None, None) => Ok(Some(tier::Backend::Dir(dir.clone()))),
+        (None, Some(url), None) => Ok(Some(tier::Backend::S3 {
+            client: reqwest::Client::new(),
+            url: url.trim_end_matches('/').to_string(),
+            access_key: args.cold_access_key.clone().unwrap_or_else(|| args.access_key.clone()),
+            secret_key: args.cold_secret_key.clone().unwrap_or_else(|| args.secret_key.clone()),
+        })),
+        (None, None, Some(url)) => {
+            let (url, sas) = url
+                .split_once('?')
+                .filter(|(_, sas)| !sas.is_empty())
+                .ok_or("--cold-azure-url needs a SAS token, as in https://ACCOUNT.blob.core.windows.net/CONTAINER?sv=...")?;
+            Ok(Some(tier::Backend::Azure {
+                client: reqwest::Client::new(),
+                url: url.trim_end_matches('/').to_string(),
+                sas: sas.to_string(),
+            }))
+        }
+        _ => Err(
+            "--tier-after-days needs one of --cold-dir, --cold-url or --cold-azure-url".to_string(),
+        ),
+    }
+}
 
-    calculated_signature == signature
+// host[:port] of --public-endpoint, as clients put it in the Host header
+fn public_host(endpoint: &str) -> Result<String, String> {
+    let url = url::Url::parse(endpoint)
+        .map_err(|e| format!("invalid --public-endpoint {:?}: {}", endpoint, e))?;
+    let host = url
+        .host_str()
+        .ok_or_else(|| format!("--public-endpoint {:?} has no host", endpoint))?;
+    Ok(match url.port() {
+        Some(port) => format!("{}:{}", host, port),
+        None => host.to_string(),
+    })
 }
 
 fn verify_auth(
@@ -207,51 +1211,73 @@ fn verify_auth(
     uri_path: &str,
     state: &AppState,
 ) -> bool {
+    if state.strict_auth {
+        if let Some(auth_header) = headers.get("authorization")
+            && let Ok(auth_str) = auth_header.to_str()
+            && auth_str.starts_with("AWS4-HMAC-SHA256")
+        {
+            info!("🔐 Verifying AWS v4 signature...");
+            return verify_aws_v4_signature(auth_str, headers, method, uri_path, query, state);
+        }
+        if is_presigned(query) {
+            info!("🔐 Verifying presigned URL...");
+            let now = chrono::Utc::now().naive_utc();
+            return verify_presigned_url(headers, method, uri_path, query, &Keys::of(state), now);
+        }
+        warn!("❌ Strict auth: only AWS v4 signatures are accepted");
+        return false;
+    }
+
     if let (Some(access_header), Some(secret_header)) = (
         headers.get("x-amz-access-key"),
         headers.get("x-amz-secret-key"),
-    ) {
-        if let (Ok(access_str), Ok(secret_str)) =
-            (access_header.to_str(), secret_header.to_str())
-        {
-            info!("✓ Using custom headers auth");
-            return access_str == state.access_key && secret_str == state.secret_key;
-        }
+    ) && let (Ok(access_str), Ok(secret_str)) =
+        (access_header.to_str(), secret_header.to_str())
+    {
+        info!("✓ Using custom headers auth");
+        return access_str == state.access_key && secret_str == state.secret_key;
     }
 
-    if let Some(auth_header) = headers.get("authorization") {
-        if let Ok(auth_str) = auth_header.to_str() {
-            let auth_clean = auth_str.strip_prefix("Bearer ").unwrap_or(auth_str);
+    if let Some(auth_header) = headers.get("authorization")
+        && let Ok(auth_str) = auth_header.to_str()
+    {
+        let auth_clean = auth_str.strip_prefix("Bearer ").unwrap_or(auth_str);
 
-            if let Some((access, secret)) = auth_clean.split_once(':') {
-                info!("✓ Using simple auth header");
-                return access == state.access_key && secret == state.secret_key;
-            }
+        if let Some((access, secret)) = auth_clean.split_once(':') {
+            info!("✓ Using simple auth header");
+            return access == state.access_key && secret == state.secret_key;
         }
     }
 
-    if let Some(auth_header) = headers.get("authorization") {
-        if let Ok(auth_str) = auth_header.to_str() {
-            if auth_str.starts_with("AWS4-HMAC-SHA256") {
-                info!("🔐 Verifying AWS v4 signature...");
-                return verify_aws_v4_signature(
-                    auth_str, headers, method, uri_path, query, state,
-                );
-            }
-        }
+    if let Some(auth_header) = headers.get("authorization")
+        && let Ok(auth_str) = auth_header.to_str()
+        && auth_str.starts_with("AWS4-HMAC-SHA256")
+    {
+        info!("🔐 Verifying AWS v4 signature...");
+        return verify_aws_v4_signature(
+            auth_str, headers, method, uri_path, query, state,
+        );
     }
 
-    if !query.is_empty() {
+    if is_presigned(query) {
+        info!("🔐 Verifying presigned URL...");
+        let now = chrono::Utc::now().naive_utc();
+        return verify_presigned_url(headers, method, uri_path, query, &Keys::of(state), now);
+    }
+
+    if !query.is_empty() && state.query_auth {
         for param in query.split('&') {
-            if let Some((key, value)) = param.split_once('=') {
-                if key == "access_key" && value == state.access_key {
-                    for param2 in query.split('&') {
-                        if let Some((key2, value2)) = param2.split_once('=') {
-                            if key2 == "secret_key" && value2 == state.secret_key {
-                                info!("✓ Using query param auth");
-                                return true;
-                            }
-                        }
+            if let Some((key, value)) = param.split_once('=')
+                && key == "access_key"
+                && value == state.access_key
+            {
+                for param2 in query.split('&') {
+                    if let Some((key2, value2)) = param2.split_once('=')
+                        && key2 == "secret_key"
+                        && value2 == state.secret_key
+                    {
+                        info!("✓ Using query param auth");
+                        return true;
                     }
                 }
             }
@@ -262,90 +1288,453 @@ fn verify_auth(
     false
 }
 
-// Auth middleware
-async fn auth_middleware(
-    State(state): State<Arc<AppState>>,
-    request: Request,
-    next: Next,
+// The access key a request claims, for brute-force tracking
+fn claimed_access_key(headers: &HeaderMap, query: &str) -> Option<String> {
+    if let Some(access) = headers.get("x-amz-access-key").and_then(|v| v.to_str().ok()) {
+        return Some(access.to_string());
+    }
+    if let Some(auth) = headers.get("authorization").and_then(|v| v.to_str().ok()) {
+        let access = match auth.strip_prefix("AWS4-HMAC-SHA256 ") {
+            Some(v4) => v4
+                .split(", ")
+                .find_map(|part| part.strip_prefix("Credential="))
+                .and_then(|credential| credential.split('/').next()),
+            None => auth
+                .strip_prefix("Bearer ")
+                .unwrap_or(auth)
+                .split_once(':')
+                .map(|(access, _)| access),
+        };
+        return access.map(str::to_string);
+    }
+    let credential = query
+        .split('&')
+        .find_map(|param| param.strip_prefix("X-Amz-Credential="));
+    if let Some(credential) = credential {
+        let credential = percent_decode_str(credential).decode_utf8_lossy();
+        return credential.split('/').next().map(str::to_string);
+    }
+    query
+        .split('&')
+        .find_map(|param| param.strip_prefix("access_key="))
+        .map(str::to_string)
+}
+
+// Query parameters that carry credentials, in plain text or as the
+// signature of a presigned URL
+const CREDENTIAL_PARAMS: [&str; 8] = [
+    "access_key",
+    "secret_key",
+    "X-Amz-Algorithm",
+    "X-Amz-Credential",
+    "X-Amz-Date",
+    "X-Amz-Expires",
+    "X-Amz-SignedHeaders",
+    "X-Amz-Signature",
+];
+
+// The URI without credential query parameters, if it had any, so nothing past
+// auth can log or forward them
+fn scrub_credentials(uri: &axum::http::Uri) -> Option<axum::http::Uri> {
+    let query = uri.query()?;
+    let is_credential = |param: &&str| {
+        let name = param.split_once('=').map_or(*param, |(name, _)| name);
+        CREDENTIAL_PARAMS.contains(&name)
+    };
+    if !query.split('&').any(|param| is_credential(&param)) {
+        return None;
+    }
+
+    let kept: Vec<&str> = query.split('&').filter(|param| !is_credential(param)).collect();
+    let path_and_query = if kept.is_empty() {
+        uri.path().to_string()
+    } else {
+        format!("{}?{}", uri.path(), kept.join("&"))
+    };
+    let mut parts = uri.clone().into_parts();
+    parts.path_and_query = Some(path_and_query.parse().ok()?);
+    axum::http::Uri::from_parts(parts).ok()
+}
+
+// Auth middleware
+async fn auth_middleware(
+    State(state): State<Arc<AppState>>,
+    mut request: Request,
+    next: Next,
 ) -> Result<Response, StatusCode> {
     let headers = request.headers().clone();
     let query = request.uri().query().unwrap_or("").to_string();
     let method = request.method().clone();
     let uri_path = request.uri().path().to_string();
+    let ip = request
+        .extensions()
+        .get::<proxy::ClientInfo>()
+        .and_then(|client| client.ip);
+    let access_key = claimed_access_key(&headers, &query);
+
+    let secure = request
+        .extensions()
+        .get::<proxy::ClientInfo>()
+        .is_some_and(|client| client.scheme == "https");
+
+    if let Some(wait) = state.lockout.blocked(ip) {
+        authlog::failure(&state, "s3", ip, access_key.as_deref(), authlog::Reason::Throttled);
+        return Ok(slow_down(wait));
+    }
+
+    // Web seeds of a torrent read their one object without credentials, as
+    // anonymous callers the bucket policy may still deny
+    let key = percent_decode_str(uri_path.trim_start_matches('/')).decode_utf8_lossy();
+    if torrent::is_seed_request(&state, &method, &key, &query) {
+        let caller = policy::Caller {
+            anonymous: true,
+            ip,
+            secure,
+        };
+        if let Some(refusal) = policy::refuse(&state, &caller, &request) {
+            return Ok(refusal);
+        }
+        request.extensions_mut().insert(caller);
+        return Ok(next.run(request).await);
+    }
 
-    if verify_auth(&headers, &query, &method, &uri_path, &state) {
+    let started = std::time::Instant::now();
+    let verified = verify_auth(&headers, &query, &method, &pathstyle::signed_path(&request), &state);
+    timing::record("auth", started.elapsed());
+    let caller = policy::Caller {
+        anonymous: !verified,
+        ip,
+        secure,
+    };
+    // Requests without credentials may go ahead by the bucket policy or a
+    // public ACL, with a Deny in the policy winning over both. Browser
+    // uploads carry theirs in the form, which post_bucket checks
+    let anonymous = !verified && access_key.is_none() && (form::is_upload(&request) || {
+        let anonymous = policy::anonymous(&state, &caller, &request);
+        match anonymous {
+            policy::Anonymous::Allowed => true,
+            policy::Anonymous::Denied => false,
+            policy::Anonymous::Undecided(targets) => acl::allows_anonymous(&state, targets).await,
+        }
+    });
+    if verified {
+        state.lockout.succeeded(ip);
+        if let Some(refusal) = policy::refuse(&state, &caller, &request) {
+            return Ok(refusal);
+        }
+        if let Some(uri) = scrub_credentials(request.uri()) {
+            *request.uri_mut() = uri;
+        }
+        request.extensions_mut().insert(caller);
+        Ok(next.run(request).await)
+    } else if anonymous {
+        request.extensions_mut().insert(caller);
         Ok(next.run(request).await)
     } else {
-        warn!("🚫 Unauthorized request");
+        // A presigned URL used outside its window isn't a guess at the keys
+        if is_presigned(&query)
+            && let Err(refusal) = presigned_window(&query, chrono::Utc::now().naive_utc())
+        {
+            return Ok(refusal.response());
+        }
+        match request.extensions().get::<proxy::ClientInfo>() {
+            Some(client) => warn!("🚫 Unauthorized request from {}", client),
+            None => warn!("🚫 Unauthorized request"),
+        }
+        state.lockout.failed(ip);
+        let reason = match access_key {
+            Some(_) => authlog::Reason::InvalidCredentials,
+            None => authlog::Reason::MissingCredentials,
+        };
+        authlog::failure(&state, "s3", ip, access_key.as_deref(), reason);
         Err(StatusCode::UNAUTHORIZED)
     }
 }
 
+// Refusal for a client blocked after too many failed logins
+fn slow_down(wait: Duration) -> Response {
+    let mut response = error_response(
+        StatusCode::TOO_MANY_REQUESTS,
+        ErrorResponse {
+            code: "SlowDown".to_string(),
+            message: "Too many failed authentication attempts".to_string(),
+            max_size_allowed: None,
+        },
+    );
+    response.headers_mut().insert(
+        "retry-after",
+        HeaderValue::from(wait.as_secs_f64().ceil() as u64),
+    );
+    response
+}
+
+// Body limit middleware: requests whose Content-Length is over the limit
+// are refused before the body is read, and bodies that turn out larger while
+// streaming are cut off and answered the same way
+async fn body_limit_middleware(
+    State(state): State<Arc<AppState>>,
+    request: Request,
+    next: Next,
+) -> Response {
+    let limit = state.max_body_size;
+    if limit == 0 {
+        return next.run(request).await;
+    }
+
+    let declared = request
+        .headers()
+        .get("content-length")
+        .and_then(|v| v.to_str().ok())
+        .and_then(|v| v.parse::<u64>().ok());
+    if declared.is_some_and(|len| len > limit) {
+        return entity_too_large(limit);
+    }
+
+    let exceeded = Arc::new(std::sync::atomic::AtomicBool::new(false));
+    let (parts, body) = request.into_parts();
+    let mut received = 0u64;
+    let flag = exceeded.clone();
+    let stream = body.into_data_stream().map(move |chunk| {
+        let chunk = chunk?;
+        received += chunk.len() as u64;
+        if received > limit {
+            flag.store(true, std::sync::atomic::Ordering::Relaxed);
+            return Err(axum::Error::new(io::Error::other("request body too large")));
+        }
+        Ok(chunk)
+    });
+
+    let response = next
+        .run(Request::from_parts(parts, Body::from_stream(stream)))
+        .await;
+
+    if exceeded.load(std::sync::atomic::Ordering::Relaxed) {
+        warn!("🚫 Request body over {} bytes", limit);
+        return entity_too_large(limit);
+    }
+    response
+}
+
+fn entity_too_large(limit: u64) -> Response {
+    error_response(
+        StatusCode::PAYLOAD_TOO_LARGE,
+        ErrorResponse {
+            code: "EntityTooLarge".to_string(),
+            message: "Your proposed upload exceeds the maximum allowed size".to_string(),
+            max_size_allowed: Some(limit),
+        },
+    )
+}
+
+// S3 error body
+fn error_response(status: StatusCode, error: ErrorResponse) -> Response {
+    let xml = serde_xml_rs::to_string(&error).unwrap_or_default();
+
+    let mut headers = HeaderMap::new();
+    headers.insert(
+        "content-type",
+        HeaderValue::from_static("application/xml"),
+    );
+    (status, headers, xml).into_response()
+}
+
 // List objects in bucket
 async fn list_objects(
     State(state): State<Arc<AppState>>,
     Query(params): Query<ListObjectsQuery>,
-) -> Result<impl IntoResponse, StatusCode> {
-    let max_keys = params.max_keys.unwrap_or(1000).min(1000);
-    let prefix = params.prefix.unwrap_or_default();
+    service: Option<Extension<pathstyle::ServiceRequest>>,
+) -> Result<Response, StatusCode> {
+    if service.is_some() {
+        return buckets::list(&state).await;
+    }
+    // S3 never returns more than 1000 keys, operators may allow more
+    let max_keys = params
+        .max_keys
+        .unwrap_or(1000)
+        .min(state.max_list_keys);
+    let url_encode = match params.encoding_type.as_deref() {
+        None => false,
+        Some("url") => true,
+        Some(_) => return Err(StatusCode::BAD_REQUEST),
+    };
+    let prefix = params
+        .prefix
+        .map(|prefix| normalize::stored_key(&state, &prefix).into_owned())
+        .unwrap_or_default();
 
-    let mut objects = Vec::new();
+    if params.export.is_some() {
+        return export::export_prefix(state, prefix).await;
+    }
+    if params.public_access_block.is_some() {
+        return access::get(&state.access.public_access_block).await;
+    }
+    if params.ownership_controls.is_some() {
+        return access::get(&state.access.ownership_controls).await;
+    }
+    if params.versioning.is_some() {
+        return versioning::get(&state).await;
+    }
+    if params.versions.is_some() {
+        return versioning::list(&state, &prefix).await;
+    }
+    if params.object_lock.is_some() {
+        return retention::configuration();
+    }
+    if params.lifecycle.is_some() {
+        return access::get(&state.lifecycle).await;
+    }
+    if params.policy.is_some() {
+        return policy::get(&state).await;
+    }
+    if params.acl.is_some() {
+        return acl::get(&state, None).await;
+    }
+    if params.cors.is_some() {
+        return access::get(&state.cors).await;
+    }
+    if let Some(query) = &params.query {
+        let Some(index) = &state.search else {
+            return Err(StatusCode::NOT_IMPLEMENTED);
+        };
+        return search::search(&state, index, query, &prefix, params.max_keys).await;
+    }
 
-    if let Ok(mut entries) = fs::read_dir(&state.data_dir).await {
-        while let Ok(Some(entry)) = entries.next_entry().await {
-            if let Ok(metadata) = entry.metadata().await {
-                if metadata.is_file() {
-                    let file_name =
-                        entry.file_name().to_string_lossy().to_string();
-
-                    if file_name.starts_with(&prefix) {
-                        let size = metadata.len();
-
-                        let modified = metadata
-                            .modified()
-                            .unwrap_or(std::time::SystemTime::now());
-
-                        let datetime: chrono::DateTime<chrono::Utc> =
-                            modified.into();
-                        let last_modified = datetime
-                            .format("%Y-%m-%dT%H:%M:%S%.3fZ")
-                            .to_string();
-
-                        let etag = format!(
-                            "\"{}\"",
-                            hex::encode(Sha256::digest(format!(
-                                "{}:{}",
-                                file_name, size
-                            )))
-                        );
-
-                        objects.push(ObjectInfo {
-                            key: file_name,
-                            last_modified,
-                            etag,
-                            size,
-                            storage_class: "STANDARD".to_string(),
-                        });
-
-                        if objects.len() >= max_keys {
-                            break;
-                        }
-                    }
-                }
+    if params.uploads.is_some() {
+        return multipart::list_uploads(
+            &state,
+            &prefix,
+            params.delimiter.as_deref(),
+            params.key_marker.as_deref().unwrap_or_default(),
+            params.upload_id_marker.as_deref().unwrap_or_default(),
+            params.max_uploads,
+        )
+        .await;
+    }
+
+    let v2 = params.list_type.as_deref() == Some("2");
+    // Where the listing starts, exclusive: the last key or common prefix of
+    // the previous page, or what the client asked to start after
+    let position = match (v2, &params.continuation_token) {
+        (true, Some(token)) => listing::parse_token(token).ok_or(StatusCode::BAD_REQUEST)?,
+        (true, None) => listing::Position {
+            snapshot: None,
+            after: params.start_after.clone().unwrap_or_default(),
+        },
+        (false, _) => listing::Position {
+            snapshot: None,
+            after: params.marker.clone().unwrap_or_default(),
+        },
+    };
+    let start = position.after;
+    let delimiter = params.delimiter.clone().filter(|delimiter| !delimiter.is_empty());
+
+    // Later pages of a ListObjectsV2 come from the snapshot the first one
+    // took, if it's still around
+    let resumed = position
+        .snapshot
+        .and_then(|id| Some((id, state.listings.get(id, &prefix)?)));
+    let (snapshot, objects) = match resumed {
+        Some((id, objects)) => (Some(id), objects),
+        None => {
+            let objects = Arc::new(timing::timed("list", list_from(&state, &prefix, &start)).await);
+            let snapshot = (v2 && objects.len() > max_keys)
+                .then(|| state.listings.insert(&prefix, objects.clone()))
+                .flatten();
+            (snapshot, objects)
+        }
+    };
+    let first = objects.partition_point(|object| object.key <= start);
+
+    // With a delimiter, keys with more of it after the prefix are rolled up
+    // into one common prefix each, which counts towards max-keys like a key
+    let mut contents = Vec::new();
+    let mut common_prefixes: Vec<String> = Vec::new();
+    let mut last = None;
+    let mut is_truncated = false;
+    for object in &objects[first..] {
+        let common = delimiter.as_deref().and_then(|delimiter| {
+            object.key[prefix.len()..]
+                .find(delimiter)
+                .map(|i| object.key[..prefix.len() + i + delimiter.len()].to_string())
+        });
+        // The previous page ended on this common prefix
+        if common.as_deref() == Some(start.as_str())
+            || common.is_some() && common.as_ref() == common_prefixes.last()
+        {
+            continue;
+        }
+        if contents.len() + common_prefixes.len() == max_keys {
+            // max-keys=0 asks for nothing rather than a page with no way on,
+            // which would have a client asking again forever
+            is_truncated = max_keys > 0;
+            break;
+        }
+        match common {
+            Some(common) => {
+                last = Some(common.clone());
+                common_prefixes.push(common);
+            }
+            None => {
+                last = Some(object.key.clone());
+                contents.push(object.clone());
             }
         }
     }
+    let next = last.filter(|_| is_truncated);
+    let snapshot = snapshot.filter(|id| {
+        if !is_truncated {
+            state.listings.remove(*id);
+        }
+        is_truncated
+    });
 
-    objects.sort_by(|a, b| a.key.cmp(&b.key));
+    // encoding-type=url: keys can hold characters XML can't carry, so SDKs
+    // ask for every key-like field URL encoded
+    let encode = |value: String| {
+        if url_encode {
+            url_encode_key(&value)
+        } else {
+            value
+        }
+    };
+    // ListObjects always gives owners
+    let owner = (!v2 || params.fetch_owner.as_deref() == Some("true")).then(|| Owner::of(&state));
+    for object in &mut contents {
+        if let Ok(path) = object_path(&state, &object.key) {
+            object.etag = etag::of_file(&state, &object.key, &path).await.unwrap_or_default();
+        }
+        object.key = encode(std::mem::take(&mut object.key));
+        object.owner = owner.clone();
+    }
 
     let result = ListBucketResult {
         xmlns: "http://s3.amazonaws.com/doc/2006-03-01/".to_string(),
         name: state.bucket_name.clone(),
-        prefix,
-        marker: params.marker.unwrap_or_default(),
+        prefix: encode(prefix),
+        marker: (!v2).then(|| encode(params.marker.unwrap_or_default())),
+        // S3 only gives the next marker with a delimiter, clients carry on
+        // from the last key otherwise
+        next_marker: next
+            .clone()
+            .filter(|_| !v2 && delimiter.is_some())
+            .map(encode),
+        continuation_token: params.continuation_token.filter(|_| v2),
+        next_continuation_token: next
+            .filter(|_| v2)
+            .map(|next| listing::token(snapshot, &next)),
+        start_after: params.start_after.filter(|_| v2).map(encode),
+        key_count: v2.then_some(contents.len() + common_prefixes.len()),
+        delimiter: delimiter.map(encode),
         max_keys,
-        is_truncated: false,
-        contents: objects,
+        is_truncated,
+        encoding_type: params.encoding_type,
+        contents,
+        common_prefixes: common_prefixes
+            .into_iter()
+            .map(|prefix| CommonPrefix {
+                prefix: encode(prefix),
+            })
+            .collect(),
     };
 
     let xml = serde_xml_rs::to_string(&result)
@@ -358,168 +1747,2103 @@ async fn list_objects(
     );
     headers.insert("server", HeaderValue::from_static("SimpleS3/1.0"));
 
-    Ok((headers, xml))
+    Ok((headers, xml).into_response())
 }
 
-// Get object
-async fn get_object(
-    State(state): State<Arc<AppState>>,
-    Path(key): Path<String>,
-) -> Result<impl IntoResponse, StatusCode> {
-    let file_path = state.data_dir.join(&key);
+// The objects under `prefix` after `start`, sorted by key
+async fn list_from(state: &AppState, prefix: &str, start: &str) -> Vec<ObjectInfo> {
+    let mut objects = Vec::new();
 
-    match fs::read(&file_path).await {
-        Ok(data) => {
-            let mut headers = HeaderMap::new();
+    if let Some(dir) = walk::start_dir(state, prefix) {
+        walk::walk(state, dir, |key, metadata| {
+            if !key.starts_with(prefix) || key.as_str() <= start {
+                return;
+            }
+            let size = metadata.len();
+
+            let modified = metadata
+                .modified()
+                .unwrap_or(std::time::SystemTime::now());
+
+            let datetime: chrono::DateTime<chrono::Utc> = modified.into();
+            let last_modified = datetime
+                .format("%Y-%m-%dT%H:%M:%S%.3fZ")
+                .to_string();
+
+            objects.push(ObjectInfo {
+                key,
+                last_modified,
+                // Only looked up for the page the object lands on
+                etag: String::new(),
+                size,
+                owner: None,
+                storage_class: "STANDARD".to_string(),
+            });
+        })
+        .await;
+    }
 
-            let mime_type =
-                mime_guess::from_path(&file_path).first_or_octet_stream();
-            headers.insert(
-                "content-type",
-                HeaderValue::from_str(mime_type.as_ref()).unwrap(),
-            );
+    // The walk finds objects in no particular order, so the first max_keys
+    // by key are only known once it's done
+    objects.sort_by(|a, b| a.key.cmp(&b.key));
+    objects
+}
 
-            let etag = format!("\"{}\"", hex::encode(Sha256::digest(&data)));
-            headers.insert("etag", HeaderValue::from_str(&etag).unwrap());
-            headers.insert(
-                "content-length",
-                HeaderValue::from_str(&data.len().to_string()).unwrap(),
-            );
-            headers
-                .insert("accept-ranges", HeaderValue::from_static("bytes"));
+// URL encode a key the way S3 listings do, with spaces as '+'
+fn url_encode_key(key: &str) -> String {
+    utf8_percent_encode(key, KEY_ENCODE_SET)
+        .to_string()
+        .replace("%20", "+")
+}
 
-            Ok((headers, data))
+// Read an object into memory, or map it when it is at least `mmap_threshold`
+// bytes so large objects are not copied into a second buffer. The file comes
+// from the handle cache, so hot objects aren't reopened on every GET
+async fn read_object(state: &AppState, file_path: &FsPath) -> io::Result<(Bytes, SystemTime)> {
+    let handle = state.handles.open(file_path).await?;
+    let modified = handle.modified;
+    let mmap_threshold = state.mmap_threshold;
+    let path = file_path.to_path_buf();
+
+    let read = tokio::task::spawn_blocking(move || {
+        if mmap_threshold > 0 && handle.len >= mmap_threshold {
+            if let Some(data) = map_object(&handle.file, handle.len)? {
+                return Ok(data);
+            }
+            warn!("Object changed while mapping, falling back to read: {}", path.display());
         }
-        Err(_) => Err(StatusCode::NOT_FOUND),
-    }
-}
 
-// Put object
-async fn put_object(
-    State(state): State<Arc<AppState>>,
-    Path(key): Path<String>,
-    body: Body,
-) -> Result<impl IntoResponse, StatusCode> {
-    let file_path = state.data_dir.join(&key);
+        let mut data = vec![0; handle.len as usize];
+        read_at(&handle.file, &mut data, 0)?;
+        Ok(Bytes::from(data))
+    })
+    .await
+    .map_err(io::Error::other)?;
 
-    if let Some(parent) = file_path.parent() {
-        fs::create_dir_all(parent)
-            .await
-            .map_err(|_| StatusCode::INTERNAL_SERVER_ERROR)?;
+    // Changed in place behind the server's back; open it afresh next time
+    if read.is_err() {
+        state.handles.invalidate(file_path);
     }
+    read.map(|data| (data, modified))
+}
 
-    let bytes = axum::body::to_bytes(body, usize::MAX)
-        .await
-        .map_err(|_| StatusCode::BAD_REQUEST)?;
-
-    let mut file = fs::File::create(&file_path)
-        .await
-        .map_err(|_| StatusCode::INTERNAL_SERVER_ERROR)?;
+// Identifies an object as it is now by its mtime and size, for what's kept
+// about it on the side and has to be dropped once it changes
+fn object_stamp(modified: SystemTime, len: u64) -> String {
+    let modified = modified
+        .duration_since(UNIX_EPOCH)
+        .map(|modified| modified.as_nanos())
+        .unwrap_or_default();
+    format!("{}-{}", modified, len)
+}
 
-    file.write_all(&bytes)
-        .await
-        .map_err(|_| StatusCode::INTERNAL_SERVER_ERROR)?;
+// Last-Modified header value for an object's mtime
+fn http_date(modified: SystemTime) -> HeaderValue {
+    let modified: chrono::DateTime<chrono::Utc> = modified.into();
+    HeaderValue::from_str(&modified.format("%a, %d %b %Y %H:%M:%S GMT").to_string()).unwrap()
+}
 
-    let etag = format!("\"{}\"", hex::encode(Sha256::digest(&bytes)));
+// Positional read, so cached handles can be shared between requests
+#[cfg(unix)]
+fn read_at(file: &std::fs::File, buf: &mut [u8], offset: u64) -> io::Result<()> {
+    std::os::unix::fs::FileExt::read_exact_at(file, buf, offset)
+}
 
-    let mut headers = HeaderMap::new();
-    headers.insert("etag", HeaderValue::from_str(&etag).unwrap());
+#[cfg(windows)]
+fn read_at(file: &std::fs::File, mut buf: &mut [u8], mut offset: u64) -> io::Result<()> {
+    while !buf.is_empty() {
+        match std::os::windows::fs::FileExt::seek_read(file, buf, offset)? {
+            0 => return Err(io::ErrorKind::UnexpectedEof.into()),
+            n => {
+                buf = &mut buf[n..];
+                offset += n as u64;
+            }
+        }
+    }
+    Ok(())
+}
 
-    info!("📁 Stored object: {} ({} bytes)", key, bytes.len());
+fn map_object(file: &std::fs::File, len: u64) -> io::Result<Option<Bytes>> {
+    // SAFETY: the server never changes an object's file in place. Every write
+    // to an object, over S3, WebDAV, SFTP or tus, goes to a new file renamed
+    // over the key, so the mapped inode keeps its contents and length while
+    // it's mapped. A length that changed before the map was made is caught
+    // below. Nothing keeps another process from truncating the file while
+    // it's mapped, which kills the server with SIGBUS, so --mmap-threshold is
+    // only for data dirs nothing but the server writes to.
+    let map = unsafe { memmap2::Mmap::map(file)? };
+
+    if map.len() as u64 != len || file.metadata()?.len() != len {
+        return Ok(None);
+    }
 
-    Ok((StatusCode::OK, headers))
+    Ok(Some(Bytes::from_owner(map)))
 }
 
-// Delete object
-async fn delete_object(
-    State(state): State<Arc<AppState>>,
-    Path(key): Path<String>,
-) -> Result<impl IntoResponse, StatusCode> {
-    let file_path = state.data_dir.join(&key);
-
-    match fs::remove_file(&file_path).await {
-        Ok(_) => {
-            info!("🗑️ Deleted object: {}", key);
-            Ok(StatusCode::NO_CONTENT)
-        }
-        Err(_) => Ok(StatusCode::NO_CONTENT),
+// ETag of a whole body. Hashing a large one would hold up every other
+// request on the same worker thread, so those go to the blocking pool
+async fn content_etag(data: &Bytes) -> Result<String, StatusCode> {
+    if data.len() <= INLINE_HASH_LIMIT {
+        return Ok(etag::of_bytes(data));
     }
+    let data = data.clone();
+    timing::timed("hash", tokio::task::spawn_blocking(move || etag::of_bytes(&data)))
+        .await
+        .map_err(|_| StatusCode::INTERNAL_SERVER_ERROR)
 }
 
-// Head object
-async fn head_object(
+// Get object
+async fn get_object(
     State(state): State<Arc<AppState>>,
     Path(key): Path<String>,
-) -> Result<impl IntoResponse, StatusCode> {
-    let file_path = state.data_dir.join(&key);
+    Query(params): Query<GetObjectQuery>,
+    #[cfg(feature = "images")] Query(image): Query<images::ImageQuery>,
+    client: Option<Extension<proxy::ClientInfo>>,
+    request_headers: HeaderMap,
+) -> Result<Response, StatusCode> {
+    if let Some(upload_id) = &params.upload_id {
+        return multipart::list_parts(
+            &state,
+            &key,
+            upload_id,
+            params.part_number_marker,
+            params.max_parts,
+        )
+        .await;
+    }
+    let file_path = object_path(&state, &key)?;
+    if params.attributes.is_some() {
+        return checksum::attributes(&state, &key, &file_path, &request_headers).await;
+    }
+    if params.tagging.is_some() {
+        return tagging::get(&state, &key, &file_path).await;
+    }
+    if params.retention.is_some() {
+        return retention::get_retention(&state, &key, &file_path).await;
+    }
+    if params.legal_hold.is_some() {
+        return retention::get_legal_hold(&state, &key, &file_path).await;
+    }
+    if params.acl.is_some() {
+        return acl::get(&state, Some((&key, &file_path))).await;
+    }
+    // A noncurrent version is read from where it's kept, and served as it
+    // was stored
+    let version_path = match &params.version_id {
+        Some(version_id) => match versioning::resolve(&state, &key, &file_path, version_id).await {
+            Ok(version_path) => version_path,
+            Err(refusal) => return Ok(refusal),
+        },
+        None => None,
+    };
+    timing::timed("recall", tier::recall(&state, &key, &file_path))
+        .await
+        .map_err(|_| StatusCode::INTERNAL_SERVER_ERROR)?;
+    if params.torrent.is_some() && version_path.is_none() {
+        let client = client.as_ref().map(|Extension(client)| client);
+        return torrent::get_torrent(&state, &key, file_path, &request_headers, client).await;
+    }
+    let stored_path = file_path.clone();
+    #[cfg(feature = "images")]
+    let file_path = match &version_path {
+        Some(_) => file_path,
+        None => images::variant(&state, &key, file_path, &image).await?,
+    };
+    let data_path = version_path.as_deref().unwrap_or(&file_path);
+    // Whether what's sent is the object as stored, which has its ETag kept
+    #[cfg(feature = "wasm")]
+    let as_stored = file_path == stored_path && state.transforms.is_none();
+    #[cfg(not(feature = "wasm"))]
+    let as_stored = file_path == stored_path;
+    if as_stored && request_headers.contains_key("range") {
+        let ip = client.as_ref().and_then(|Extension(client)| client.ip);
+        let version = params.version_id.as_deref().zip(version_path.as_deref());
+        if let Some(response) =
+            get_range(&state, &key, &file_path, version, ip, &request_headers).await?
+        {
+            return Ok(response);
+        }
+    }
 
-    match fs::metadata(&file_path).await {
-        Ok(metadata) => {
+    match timing::timed("read", read_object(&state, data_path)).await {
+        Ok((data, modified)) => {
             let mut headers = HeaderMap::new();
+            let last_modified = http_date(modified);
+            headers.insert("last-modified", last_modified.clone());
 
-            let mime_type =
-                mime_guess::from_path(&file_path).first_or_octet_stream();
+            let mime_type = sniff::content_type(&state, &file_path, &data);
             headers.insert(
                 "content-type",
-                HeaderValue::from_str(mime_type.as_ref()).unwrap(),
+                HeaderValue::from_str(&mime_type).unwrap(),
             );
+
+            #[cfg(feature = "wasm")]
+            let data = match &state.transforms {
+                Some(transforms) => transforms.apply(&key, &mut headers, data).await?,
+                None => data,
+            };
+
+            let etag = match (&params.version_id, &version_path) {
+                _ if !as_stored => content_etag(&data).await?,
+                (Some(version_id), Some(version_path)) => {
+                    versioning::etag(&state, &key, version_id, version_path)
+                        .await
+                        .map_err(|_| StatusCode::INTERNAL_SERVER_ERROR)?
+                }
+                _ => etag::of_file(&state, &key, &file_path)
+                    .await
+                    .map_err(|_| StatusCode::INTERNAL_SERVER_ERROR)?,
+            };
+            if let Some(response) = conditional::check_read(&request_headers, &etag, modified) {
+                return Ok(response);
+            }
+            headers.insert("etag", HeaderValue::from_str(&etag).unwrap());
+            headers
+                .insert("accept-ranges", HeaderValue::from_static("bytes"));
+
+            let len = data.len() as u64;
+            let range = byte_range(&request_headers, len)
+                .filter(|_| range_applies(&request_headers, &etag, &last_modified));
+            let (status, data) = match range {
+                None => {
+                    if checksum::wanted(&request_headers)
+                        && let Some(checksum) = checksum::load(&state, &key, modified, len).await
+                    {
+                        checksum.insert_into(&mut headers);
+                    }
+                    tagging::insert_count(&state, &key, modified, len, &mut headers).await;
+                    retention::insert_headers(&state, &key, &mut headers).await;
+                    match &params.version_id {
+                        Some(version_id) => versioning::insert_id(&mut headers, version_id),
+                        None => {
+                            versioning::insert_current_id(&state, &key, modified, len, &mut headers)
+                                .await
+                        }
+                    }
+                    (StatusCode::OK, data)
+                }
+                Some(Some((start, end))) => {
+                    headers.insert(
+                        "content-range",
+                        HeaderValue::from_str(&format!("bytes {}-{}/{}", start, end, len)).unwrap(),
+                    );
+                    (
+                        StatusCode::PARTIAL_CONTENT,
+                        data.slice(start as usize..=end as usize),
+                    )
+                }
+                Some(None) => {
+                    headers.insert(
+                        "content-range",
+                        HeaderValue::from_str(&format!("bytes */{}", len)).unwrap(),
+                    );
+                    return Ok((StatusCode::RANGE_NOT_SATISFIABLE, headers).into_response());
+                }
+            };
             headers.insert(
                 "content-length",
-                HeaderValue::from_str(&metadata.len().to_string()).unwrap(),
-            );
-
-            let etag = format!(
-                "\"{}\"",
-                hex::encode(Sha256::digest(format!(
-                    "{}:{}",
-                    key,
-                    metadata.len()
-                )))
+                HeaderValue::from_str(&data.len().to_string()).unwrap(),
             );
-            headers.insert("etag", HeaderValue::from_str(&etag).unwrap());
 
-            Ok((StatusCode::OK, headers))
+            Ok((status, headers, data).into_response())
         }
         Err(_) => Err(StatusCode::NOT_FOUND),
     }
 }
 
-#[tokio::main]
-async fn main() -> Result<(), Box<dyn std::error::Error>> {
-    tracing_subscriber::fmt::init();
-
-    let args = Args::parse();
+// GET of one byte range of an object as stored. Only the range is read, from
+// the open file, and what follows is read ahead when the client's ranges go
+// through the object in order (see readahead.rs). None when the whole object
+// is to be sent after all, for a list of ranges or an If-Range that doesn't
+// match
+async fn get_range(
+    state: &AppState,
+    key: &str,
+    file_path: &FsPath,
+    version: Option<(&str, &FsPath)>,
+    ip: Option<IpAddr>,
+    request_headers: &HeaderMap,
+) -> Result<Option<Response>, StatusCode> {
+    let data_path = version.map_or(file_path, |(_, version_path)| version_path);
+    let handle = state
+        .handles
+        .open(data_path)
+        .await
+        .map_err(|_| StatusCode::NOT_FOUND)?;
+    let etag = match version {
+        Some((version_id, version_path)) => {
+            versioning::etag(state, key, version_id, version_path).await
+        }
+        None => etag::of_file(state, key, file_path).await,
+    }
+    .map_err(|_| StatusCode::INTERNAL_SERVER_ERROR)?;
+    if let Some(response) = conditional::check_read(request_headers, &etag, handle.modified) {
+        return Ok(Some(response));
+    }
+    let last_modified = http_date(handle.modified);
+    let Some(range) = byte_range(request_headers, handle.len)
+        .filter(|_| range_applies(request_headers, &etag, &last_modified))
+    else {
+        return Ok(None);
+    };
 
-    fs::create_dir_all(&args.data_dir).await?;
+    let mut headers = HeaderMap::new();
+    headers.insert("last-modified", last_modified);
+    let mime_type = sniff::content_type_of(state, file_path, &handle)
+        .await
+        .map_err(|_| StatusCode::INTERNAL_SERVER_ERROR)?;
+    headers.insert("content-type", HeaderValue::from_str(&mime_type).unwrap());
+    headers.insert("etag", HeaderValue::from_str(&etag).unwrap());
+    headers.insert("accept-ranges", HeaderValue::from_static("bytes"));
+    let Some((start, end)) = range else {
+        headers.insert(
+            "content-range",
+            HeaderValue::from_str(&format!("bytes */{}", handle.len)).unwrap(),
+        );
+        return Ok(Some((StatusCode::RANGE_NOT_SATISFIABLE, headers).into_response()));
+    };
 
-    let state = Arc::new(AppState {
-        bucket_name: args.bucket.clone(),
-        access_key: args.access_key.clone(),
-        secret_key: args.secret_key.clone(),
-        data_dir: args.data_dir.clone(),
+    let file = handle.file.clone();
+    let read = tokio::task::spawn_blocking(move || {
+        let mut data = vec![0; (end - start + 1) as usize];
+        read_at(&file, &mut data, start).map(|()| data)
     });
+    let data = match timing::timed("read", read).await {
+        Ok(Ok(data)) => data,
+        _ => {
+            // Changed in place behind the server's back
+            state.handles.invalidate(data_path);
+            return Err(StatusCode::INTERNAL_SERVER_ERROR);
+        }
+    };
+    state.readahead.after(ip, data_path, &handle, start, end);
 
-    let app = Router::new()
-        .route("/", get(list_objects))
-        .route("/{*key}", get(get_object))
-        .route("/{*key}", put(put_object))
-        .route("/{*key}", delete(delete_object))
-        .route("/{*key}", head(head_object))
-        .layer(middleware::from_fn_with_state(
-            state.clone(),
-            auth_middleware,
-        ))
-        .layer(CorsLayer::permissive())
-        .with_state(state);
+    headers.insert(
+        "content-range",
+        HeaderValue::from_str(&format!("bytes {}-{}/{}", start, end, handle.len)).unwrap(),
+    );
+    headers.insert("content-length", HeaderValue::from(data.len()));
+    Ok(Some((StatusCode::PARTIAL_CONTENT, headers, data).into_response()))
+}
+
+// The first and last byte asked for by a Range header, None without one.
+// Some(None) when the range is past the end. A list of ranges is answered
+// with the whole object, which the spec allows
+fn byte_range(headers: &HeaderMap, len: u64) -> Option<Option<(u64, u64)>> {
+    let range = headers.get("range")?.to_str().ok()?.trim();
+    let spec = range.strip_prefix("bytes=")?;
+    if spec.contains(',') {
+        return None;
+    }
+    let (start, end) = spec.split_once('-')?;
+    let (start, end) = match (start.trim(), end.trim()) {
+        ("", suffix) => {
+            let suffix: u64 = suffix.parse().ok()?;
+            if suffix == 0 {
+                return Some(None);
+            }
+            (len.saturating_sub(suffix), len.checked_sub(1)?)
+        }
+        (start, "") => (start.parse().ok()?, len.saturating_sub(1)),
+        (start, end) => {
+            let (start, end): (u64, u64) = (start.parse().ok()?, end.parse().ok()?);
+            if end < start {
+                return None;
+            }
+            (start, end.min(len.saturating_sub(1)))
+        }
+    };
+    Some((start < len).then_some((start, end)))
+}
 
-    let addr = format!("{}:{}", args.host, args.port);
-    let listener = tokio::net::TcpListener::bind(&addr).await?;
+// If-Range: a resumed download only gets the rest of the object if it's the
+// object the first part came from, by ETag or exact Last-Modified, and the
+// whole object otherwise
+fn range_applies(headers: &HeaderMap, etag: &str, last_modified: &HeaderValue) -> bool {
+    match headers.get("if-range") {
+        None => true,
+        Some(validator) => validator == etag || validator == last_modified,
+    }
+}
 
-    info!("🚀 S3-compatible server starting on http://{}", addr);
-    info!("📦 Bucket: {}", args.bucket);
-    info!("💾 Data directory: {}", args.data_dir.display());
+// PUT /: bucket configuration
+async fn put_bucket(
+    State(state): State<Arc<AppState>>,
+    Query(params): Query<BucketQuery>,
+    RawQuery(query): RawQuery,
+    headers: HeaderMap,
+    body: Bytes,
+) -> Result<Response, StatusCode> {
+    if buckets::is_plain(query.as_deref()) {
+        return Ok(buckets::refuse_change(&state));
+    }
+    if params.public_access_block.is_some() {
+        return access::put(&state, &state.access.public_access_block, &body).await;
+    }
+    if params.ownership_controls.is_some() {
+        return access::put(&state, &state.access.ownership_controls, &body).await;
+    }
+    if params.versioning.is_some() {
+        return access::put(&state, &state.versioning.config, &body).await;
+    }
+    if params.lifecycle.is_some() {
+        return access::put(&state, &state.lifecycle, &body).await;
+    }
+    if params.policy.is_some() {
+        return policy::put(&state, &body).await;
+    }
+    if params.acl.is_some() {
+        return acl::put(&state, None, &headers, &body).await;
+    }
+    if params.cors.is_some() {
+        return access::put(&state, &state.cors, &body).await;
+    }
+    Err(StatusCode::NOT_IMPLEMENTED)
+}
 
-    axum::serve(listener, app).await?;
+// POST /: DeleteObjects
+async fn post_bucket(
+    State(state): State<Arc<AppState>>,
+    Query(params): Query<BucketQuery>,
+    caller: Option<Extension<policy::Caller>>,
+    headers: HeaderMap,
+    body: Body,
+) -> Result<Response, StatusCode> {
+    if params.delete.is_some() {
+        let body = axum::body::to_bytes(body, usize::MAX)
+            .await
+            .map_err(|_| StatusCode::BAD_REQUEST)?;
+        if let Some(refusal) = integrity::verify(&headers, &body).await? {
+            return Ok(refusal);
+        }
+        return delete_objects(&state, caller.as_deref(), &headers, &body).await;
+    }
+    if form::is_form(&headers) {
+        return form::upload(&state, caller.as_deref(), &headers, body).await;
+    }
+    Err(StatusCode::METHOD_NOT_ALLOWED)
+}
 
-    Ok(())
-}
\ No newline at end of file
+// DELETE /: bucket configuration
+async fn delete_bucket(
+    State(state): State<Arc<AppState>>,
+    Query(params): Query<BucketQuery>,
+    RawQuery(query): RawQuery,
+) -> Result<Response, StatusCode> {
+    if buckets::is_plain(query.as_deref()) {
+        return Ok(buckets::refuse_change(&state));
+    }
+    if params.public_access_block.is_some() {
+        return access::delete(&state.access.public_access_block).await;
+    }
+    if params.ownership_controls.is_some() {
+        return access::delete(&state.access.ownership_controls).await;
+    }
+    if params.lifecycle.is_some() {
+        return access::delete(&state.lifecycle).await;
+    }
+    if params.policy.is_some() {
+        return policy::delete(&state).await;
+    }
+    if params.cors.is_some() {
+        return access::delete(&state.cors).await;
+    }
+    Err(StatusCode::NOT_IMPLEMENTED)
+}
+
+// Put object
+async fn put_object(
+    State(state): State<Arc<AppState>>,
+    Path(key): Path<String>,
+    Query(params): Query<PutObjectQuery>,
+    headers: HeaderMap,
+    body: Body,
+) -> Result<Response, StatusCode> {
+    let file_path = object_path(&state, &key)?;
+
+    if let Some(refusal) = access::refuse_acl(&state, &key, &headers) {
+        return Ok(refusal);
+    }
+    if let (Some(part_number), Some(upload_id)) = (params.part_number, &params.upload_id) {
+        return multipart::upload_part(&state, &key, upload_id, part_number, &headers, body).await;
+    }
+    if params.tagging.is_some() {
+        let bytes = axum::body::to_bytes(body, usize::MAX)
+            .await
+            .map_err(|_| StatusCode::BAD_REQUEST)?;
+        return tagging::put(&state, &key, &file_path, &bytes).await;
+    }
+    if params.acl.is_some() {
+        let bytes = axum::body::to_bytes(body, usize::MAX)
+            .await
+            .map_err(|_| StatusCode::BAD_REQUEST)?;
+        return acl::put(&state, Some((&key, &file_path)), &headers, &bytes).await;
+    }
+    if params.retention.is_some() || params.legal_hold.is_some() {
+        let bytes = axum::body::to_bytes(body, usize::MAX)
+            .await
+            .map_err(|_| StatusCode::BAD_REQUEST)?;
+        return match params.retention {
+            Some(_) => retention::put_retention(&state, &key, &file_path, &headers, &bytes).await,
+            None => retention::put_legal_hold(&state, &key, &file_path, &bytes).await,
+        };
+    }
+    if let Some(refusal) = worm::refuse_with(&state, &key, &file_path, &headers).await {
+        return Ok(refusal);
+    }
+    if let Some(refusal) = tagging::refuse_invalid(&headers) {
+        return Ok(refusal);
+    }
+    if let Some(refusal) = retention::refuse_invalid(&headers) {
+        return Ok(refusal);
+    }
+    if let Some(refusal) = acl::refuse_invalid(&state, &headers) {
+        return Ok(refusal);
+    }
+
+    if let Some(parent) = file_path.parent() {
+        fs::create_dir_all(parent)
+            .await
+            .map_err(|_| StatusCode::INTERNAL_SERVER_ERROR)?;
+    }
+
+    if let Some(copy_source) = headers.get("x-amz-copy-source") {
+        let copy_source = copy_source.to_str().map_err(|_| StatusCode::BAD_REQUEST)?;
+        return copy_object(&state, &key, copy_source, &headers).await;
+    }
+
+    let bytes = timing::timed("body", axum::body::to_bytes(body, usize::MAX))
+        .await
+        .map_err(|_| StatusCode::BAD_REQUEST)?;
+    if let Some(refusal) = integrity::verify(&headers, &bytes).await? {
+        return Ok(refusal);
+    }
+    let checksum = match checksum::verify(&headers, &bytes).await {
+        Ok(checksum) => checksum,
+        Err(refusal) => return Ok(refusal),
+    };
+    let tags = tagging::from_headers(&headers);
+    let lock = retention::from_headers(&headers);
+    let grants = acl::from_headers(&state, &headers);
+
+    if let Err(refusal) = scan::check_bytes(&state, "s3", &key, &bytes).await {
+        return Ok(refusal.response());
+    }
+
+    if let Some(refusal) = conditional::refuse_unsupported(&headers) {
+        return Ok(refusal);
+    }
+    let condition = conditional::condition(&headers);
+
+    if let Some(offset) = params.offset {
+        if condition.is_some() {
+            return Err(StatusCode::NOT_IMPLEMENTED);
+        }
+        tier::recall(&state, &key, &file_path)
+            .await
+            .map_err(|_| StatusCode::INTERNAL_SERVER_ERROR)?;
+        return write_object_at(&state, &file_path, &key, offset, &bytes).await;
+    }
+
+    let tmp_path = tmp_path(&state);
+    let versions = versioning::prepare(&state, &key, &file_path).await?;
+
+    let stored = timing::timed("write", async {
+        match &condition {
+            Some(condition) => {
+                conditional::write(&state, &key, &tmp_path, &file_path, &bytes, condition).await
+            }
+            None => write_and_rename(&state, &tmp_path, &file_path, &bytes).await.map(|()| None),
+        }
+    })
+    .await;
+    match stored {
+        Ok(None) => {}
+        Ok(Some(refusal)) => {
+            versioning::abort(versions).await;
+            return Ok(refusal);
+        }
+        Err(e) => {
+            warn!("Failed to store object {}: {}", key, e);
+            let _ = fs::remove_file(&tmp_path).await;
+            versioning::abort(versions).await;
+            return Err(StatusCode::INTERNAL_SERVER_ERROR);
+        }
+    }
+
+    let mut headers = HeaderMap::new();
+    versioning::commit(&state, versions, &file_path, &mut headers).await;
+    let etag = content_etag(&bytes).await?;
+    etag::store(&state, &key, &file_path, &etag).await;
+    headers.insert("etag", HeaderValue::from_str(&etag).unwrap());
+    if let Some(checksum) = checksum {
+        checksum.insert_into(&mut headers);
+        checksum::store(&state, &key, &file_path, checksum).await;
+    }
+    if let Some(tags) = tags {
+        tagging::store_or_warn(&state, &key, &file_path, tags).await;
+    }
+    retention::store_or_warn(&state, &key, lock).await;
+    if let Some(grants) = grants {
+        acl::store_or_warn(&state, &key, &file_path, grants).await;
+    }
+
+    info!("📁 Stored object: {} ({} bytes)", key, bytes.len());
+
+    Ok((StatusCode::OK, headers).into_response())
+}
+
+// Ranged write (PUT with ?offset=N). Writes the body at `offset` of a copy
+// of the object, a clone where the filesystem has reflinks, and renames it
+// over the key as a PUT does, never truncating, so anything not yet written
+// stays a hole. Ranged writes are serialized, so parallel uploaders filling
+// disjoint ranges of one object don't undo each other's. Like a PUT, the
+// object it replaces is kept as a version and the new one's ETag is kept
+async fn write_object_at(
+    state: &AppState,
+    file_path: &FsPath,
+    key: &str,
+    offset: u64,
+    bytes: &[u8],
+) -> Result<Response, StatusCode> {
+    let _guard = state.ranged_writes.lock().await;
+    let versions = versioning::prepare(state, key, file_path).await?;
+    let tmp_path = tmp_path(state);
+
+    let written = async {
+        match copy::copy_file(file_path, &tmp_path).await {
+            Ok(_) => {}
+            // Created by this write
+            Err(e) if e.kind() == io::ErrorKind::NotFound => {}
+            Err(e) => return Err(e),
+        }
+        let mut file = fs::OpenOptions::new()
+            .write(true)
+            .create(true)
+            .truncate(false)
+            .open(&tmp_path)
+            .await?;
+        file.set_max_buf_size(state.write_buffer);
+        file.seek(io::SeekFrom::Start(offset)).await?;
+        file.write_all(bytes).await?;
+        file.flush().await?;
+        drop(file);
+        rename_into_place(&tmp_path, file_path).await
+    }
+    .await;
+    if let Err(e) = written {
+        warn!("Failed to write {} at offset {}: {}", key, offset, e);
+        let _ = fs::remove_file(&tmp_path).await;
+        versioning::abort(versions).await;
+        return Err(StatusCode::INTERNAL_SERVER_ERROR);
+    }
+
+    let mut headers = HeaderMap::new();
+    versioning::commit(state, versions, file_path, &mut headers).await;
+    let etag = etag::hash_file(state, file_path)
+        .await
+        .map_err(|_| StatusCode::INTERNAL_SERVER_ERROR)?;
+    etag::store(state, key, file_path, &etag).await;
+    headers.insert("etag", HeaderValue::from_str(&etag).unwrap());
+
+    info!(
+        "✏️ Wrote {} bytes to {} at offset {}",
+        bytes.len(),
+        key,
+        offset
+    );
+
+    Ok((StatusCode::OK, headers).into_response())
+}
+
+// Post object: the steps of a multipart upload, and the non-standard
+// ?compose extension
+async fn post_object(
+    State(state): State<Arc<AppState>>,
+    Path(key): Path<String>,
+    Query(params): Query<PostObjectQuery>,
+    headers: HeaderMap,
+    body: Body,
+) -> Result<Response, StatusCode> {
+    if params.uploads.is_some() {
+        return multipart::create(&state, &key, &headers).await;
+    }
+    if params.upload_id.is_none() && params.compose.is_none() {
+        return Err(StatusCode::METHOD_NOT_ALLOWED);
+    }
+
+    let bytes = axum::body::to_bytes(body, usize::MAX)
+        .await
+        .map_err(|_| StatusCode::BAD_REQUEST)?;
+    if let Some(upload_id) = &params.upload_id {
+        return multipart::complete(&state, &key, upload_id, &headers, bytes).await;
+    }
+    let request: ComposeRequest = serde_xml_rs::from_reader(bytes.as_ref())
+        .map_err(|_| StatusCode::BAD_REQUEST)?;
+
+    compose_object(&state, &key, request).await
+}
+
+// Compose object: concatenate existing objects, in order, into `key`
+async fn compose_object(
+    state: &AppState,
+    key: &str,
+    request: ComposeRequest,
+) -> Result<Response, StatusCode> {
+    if request.sources.is_empty() || request.sources.len() > MAX_COMPOSE_SOURCES {
+        return Err(StatusCode::BAD_REQUEST);
+    }
+
+    let file_path = object_path(state, key)?;
+    if let Some(refusal) = worm::refuse(state, key, &file_path).await {
+        return Ok(refusal);
+    }
+    if let Some(parent) = file_path.parent() {
+        fs::create_dir_all(parent)
+            .await
+            .map_err(|_| StatusCode::INTERNAL_SERVER_ERROR)?;
+    }
+
+    let sources = request
+        .sources
+        .iter()
+        .map(|source| object_path(state, &source.key))
+        .collect::<Result<Vec<PathBuf>, StatusCode>>()?;
+    for (source, path) in request.sources.iter().zip(&sources) {
+        tier::recall(state, &source.key, path)
+            .await
+            .map_err(|_| StatusCode::INTERNAL_SERVER_ERROR)?;
+    }
+    let tmp_path = tmp_path(state);
+
+    let staged = tmp_path.clone();
+    let result = tokio::task::spawn_blocking(move || {
+        let mut dst = std::fs::File::create(&staged)?;
+        let mut size = 0;
+        for source in &sources {
+            let mut src = std::fs::File::open(source)?;
+            size += copy::copy_into(&mut src, &mut dst)?;
+        }
+        Ok::<_, io::Error>(size)
+    })
+    .await
+    .map_err(|_| StatusCode::INTERNAL_SERVER_ERROR)?;
+
+    let size = match result {
+        Ok(size) => size,
+        Err(e) => {
+            let _ = fs::remove_file(&tmp_path).await;
+            if e.kind() == io::ErrorKind::NotFound {
+                return Err(StatusCode::NOT_FOUND);
+            }
+            warn!("Failed to compose {}: {}", key, e);
+            return Err(StatusCode::INTERNAL_SERVER_ERROR);
+        }
+    };
+
+    let versions = versioning::prepare(state, key, &file_path).await?;
+    if let Err(e) = rename_into_place(&tmp_path, &file_path).await {
+        warn!("Failed to store object {}: {}", key, e);
+        let _ = fs::remove_file(&tmp_path).await;
+        versioning::abort(versions).await;
+        return Err(StatusCode::INTERNAL_SERVER_ERROR);
+    }
+
+    let mut headers = HeaderMap::new();
+    versioning::commit(state, versions, &file_path, &mut headers).await;
+    let etag = etag::hash_file(state, &file_path)
+        .await
+        .map_err(|_| StatusCode::INTERNAL_SERVER_ERROR)?;
+    etag::store(state, key, &file_path, &etag).await;
+    headers.insert("etag", HeaderValue::from_str(&etag).unwrap());
+
+    info!(
+        "🧩 Composed object: {} from {} sources ({} bytes)",
+        key,
+        request.sources.len(),
+        size
+    );
+
+    Ok((StatusCode::OK, headers).into_response())
+}
+
+// What an x-amz-copy-source header names
+struct CopySource {
+    key: String,
+    // The version to copy, the current one if None
+    version_id: Option<String>,
+}
+
+// Reads an x-amz-copy-source header, `/bucket/key` or `bucket/key`
+// URL-encoded, possibly with a ?versionId
+fn parse_copy_source(state: &AppState, copy_source: &str) -> Result<CopySource, StatusCode> {
+    let (path, query) = copy_source.split_once('?').unwrap_or((copy_source, ""));
+    let path = percent_decode_str(path)
+        .decode_utf8()
+        .map_err(|_| StatusCode::BAD_REQUEST)?;
+    let key = path
+        .trim_start_matches('/')
+        .strip_prefix(state.bucket_name.as_str())
+        .and_then(|k| k.strip_prefix('/'))
+        .map(str::to_string)
+        .ok_or(StatusCode::BAD_REQUEST)?;
+    let version_id = url::form_urlencoded::parse(query.as_bytes())
+        .find(|(name, _)| name == "versionId")
+        .map(|(_, version_id)| version_id.into_owned());
+    Ok(CopySource { key, version_id })
+}
+
+// Copy object (PUT with x-amz-copy-source)
+async fn copy_object(
+    state: &AppState,
+    key: &str,
+    copy_source: &str,
+    headers: &HeaderMap,
+) -> Result<Response, StatusCode> {
+    let source = parse_copy_source(state, copy_source)?;
+    let source_key = source.key.as_str();
+
+    let source_path = object_path(state, source_key)?;
+    let file_path = object_path(state, key)?;
+    // A noncurrent version is copied from where it's kept
+    let version_path = match &source.version_id {
+        Some(version_id) => {
+            match versioning::resolve_source(state, source_key, &source_path, version_id).await {
+                Ok(version_path) => version_path,
+                Err(refusal) => return Ok(refusal),
+            }
+        }
+        None => None,
+    };
+    if version_path.is_none() {
+        tier::recall(state, source_key, &source_path)
+            .await
+            .map_err(|_| StatusCode::INTERNAL_SERVER_ERROR)?;
+    }
+    let source_path = version_path.unwrap_or(source_path);
+    // Taken before the copy, which may replace the source
+    let tags = match tagging::replaces(headers) {
+        true => tagging::from_headers(headers).unwrap_or_default(),
+        false => tagging::load_file(state, source_key, &source_path)
+            .await
+            .unwrap_or_default(),
+    };
+    let lock = retention::from_headers(headers);
+    let grants = acl::from_headers(state, headers);
+    let tmp_path = tmp_path(state);
+
+    let size = match copy::copy_file(&source_path, &tmp_path).await {
+        Ok(size) => size,
+        Err(e) if e.kind() == io::ErrorKind::NotFound => {
+            let _ = fs::remove_file(&tmp_path).await;
+            return Err(StatusCode::NOT_FOUND);
+        }
+        Err(e) => {
+            warn!("Failed to copy {} to {}: {}", source_key, key, e);
+            let _ = fs::remove_file(&tmp_path).await;
+            return Err(StatusCode::INTERNAL_SERVER_ERROR);
+        }
+    };
+
+    let versions = versioning::prepare(state, key, &file_path).await?;
+    if let Err(e) = rename_into_place(&tmp_path, &file_path).await {
+        warn!("Failed to store object {}: {}", key, e);
+        let _ = fs::remove_file(&tmp_path).await;
+        versioning::abort(versions).await;
+        return Err(StatusCode::INTERNAL_SERVER_ERROR);
+    }
+    let mut headers = HeaderMap::new();
+    versioning::commit(state, versions, &file_path, &mut headers).await;
+    if let Some(version_id) = &source.version_id
+        && let Ok(version_id) = HeaderValue::from_str(version_id)
+    {
+        headers.insert("x-amz-copy-source-version-id", version_id);
+    }
+    // Hashed from the copy, as the source may have changed since
+    let etag = etag::of_file(state, key, &file_path)
+        .await
+        .map_err(|_| StatusCode::INTERNAL_SERVER_ERROR)?;
+
+    let modified: chrono::DateTime<chrono::Utc> = fs::metadata(&file_path)
+        .await
+        .and_then(|metadata| metadata.modified())
+        .unwrap_or_else(|_| SystemTime::now())
+        .into();
+    tagging::store_or_warn(state, key, &file_path, tags).await;
+    retention::store_or_warn(state, key, lock).await;
+    if let Some(grants) = grants {
+        acl::store_or_warn(state, key, &file_path, grants).await;
+    }
+    let result = CopyObjectResult {
+        last_modified: modified.format("%Y-%m-%dT%H:%M:%S%.3fZ").to_string(),
+        etag,
+    };
+
+    let xml = serde_xml_rs::to_string(&result)
+        .map_err(|_| StatusCode::INTERNAL_SERVER_ERROR)?;
+
+    headers.insert(
+        "content-type",
+        HeaderValue::from_static("application/xml"),
+    );
+
+    info!("📋 Copied object: {} -> {} ({} bytes)", source_key, key, size);
+
+    Ok((StatusCode::OK, headers, xml).into_response())
+}
+
+// Map a slash separated path to one relative to the data dir, rejecting
+// anything that could escape it or reach the server's internal directory.
+// Segments are escaped into file names that are valid on every platform
+fn relative_path(path: &str) -> Option<PathBuf> {
+    let mut rel = PathBuf::new();
+
+    for segment in path.split('/') {
+        match segment {
+            "" | "." => {}
+            ".." => return None,
+            segment => rel.push(keys::encode_segment(segment).as_ref()),
+        }
+    }
+
+    if is_internal(&rel) {
+        return None;
+    }
+    Some(rel)
+}
+
+// Whether `rel` is in the internal directory, in any case, as
+// case-insensitive filesystems would open it for `.SIMPLE-S3` too
+fn is_internal(rel: &FsPath) -> bool {
+    rel.components().next().is_some_and(|first| {
+        first
+            .as_os_str()
+            .to_str()
+            .is_some_and(|first| first.eq_ignore_ascii_case(INTERNAL_DIR))
+    })
+}
+
+// Where the object `key` lives on disk. Every S3 key goes through here, so
+// keys under the internal directory or with ".." can never be read, written
+// or deleted
+fn object_path(state: &AppState, key: &str) -> Result<PathBuf, StatusCode> {
+    relative_path(&normalize::stored_key(state, key))
+        .filter(|rel| !rel.as_os_str().is_empty())
+        .map(|rel| keys::long_path(state.data_dir.join(rel)))
+        .filter(|path| inside_data_dir(state, path))
+        .ok_or(StatusCode::FORBIDDEN)
+}
+
+// Whether `path` stays inside the data dir once symlinks are resolved, so a
+// link planted in the data dir can't expose or overwrite files elsewhere.
+// Paths that don't exist yet are judged by their nearest existing ancestor.
+// Always true with --follow-symlinks
+fn inside_data_dir(state: &AppState, path: &FsPath) -> bool {
+    if state.follow_symlinks {
+        return true;
+    }
+
+    let mut existing = path;
+    loop {
+        match std::fs::canonicalize(existing) {
+            Ok(real) => return real.starts_with(&state.data_root),
+            // A dangling link could still be written through
+            Err(_) if existing.is_symlink() => return false,
+            Err(_) => match existing.parent() {
+                Some(parent) => existing = parent,
+                None => return false,
+            },
+        }
+    }
+}
+
+// Every object under the data dir as (key, size), sorted by key
+async fn walk_objects(state: &AppState) -> Vec<(String, u64)> {
+    let mut objects = Vec::new();
+    walk::walk(state, state.data_dir.clone(), |key, metadata| {
+        objects.push((key, metadata.len()))
+    })
+    .await;
+    objects.sort();
+    objects
+}
+
+// Fresh staging path for an object being written
+fn tmp_path(state: &AppState) -> PathBuf {
+    state
+        .data_dir
+        .join(TMP_DIR)
+        .join(uuid::Uuid::new_v4().to_string())
+}
+
+// Write into a staging file and rename it over the key, so readers (and
+// memory maps) of the previous version never see a truncated object
+async fn write_and_rename(
+    state: &AppState,
+    tmp_path: &FsPath,
+    file_path: &FsPath,
+    bytes: &[u8],
+) -> io::Result<()> {
+    let mut file = fs::File::create(tmp_path).await?;
+    file.set_max_buf_size(state.write_buffer);
+    file.write_all(bytes).await?;
+    file.flush().await?;
+    drop(file);
+    rename_into_place(tmp_path, file_path).await
+}
+
+// Moves a finished temporary file over the object at `file_path`
+async fn rename_into_place(tmp_path: &FsPath, file_path: &FsPath) -> io::Result<()> {
+    let renamed = fs::rename(tmp_path, file_path).await;
+    // A delete removed the parent as empty since it was created
+    if let Err(e) = &renamed
+        && e.kind() == io::ErrorKind::NotFound
+        && let Some(parent) = file_path.parent()
+    {
+        fs::create_dir_all(parent).await?;
+        return fs::rename(tmp_path, file_path).await;
+    }
+    renamed
+}
+
+// Removes the directories above the deleted object at `path` that are now
+// empty, stopping at the data dir. remove_dir refuses a directory that isn't
+// empty, so one another request has just written into stays
+async fn remove_empty_parents(state: &AppState, path: &FsPath) {
+    let mut dir = path.parent();
+    while let Some(current) = dir {
+        if current == state.data_dir
+            || !current.starts_with(&state.data_dir)
+            || fs::remove_dir(current).await.is_err()
+        {
+            break;
+        }
+        dir = current.parent();
+    }
+}
+
+// Delete object, or abort a multipart upload
+async fn delete_object(
+    State(state): State<Arc<AppState>>,
+    Path(key): Path<String>,
+    Query(params): Query<DeleteObjectQuery>,
+    headers: HeaderMap,
+) -> Result<Response, StatusCode> {
+    if let Some(upload_id) = &params.upload_id {
+        return multipart::abort(&state, &key, upload_id).await;
+    }
+    let file_path = object_path(&state, &key)?;
+    if params.tagging.is_some() {
+        return tagging::delete(&state, &key, &file_path).await;
+    }
+
+    if let Some(refusal) = worm::refuse_with(&state, &key, &file_path, &headers).await {
+        return Ok(refusal);
+    }
+
+    match versioning::delete(&state, &key, &file_path, params.version_id.as_deref()).await {
+        Ok(Some(removed)) => {
+            let mut headers = HeaderMap::new();
+            removed.insert_into(&mut headers);
+            return Ok((StatusCode::NO_CONTENT, headers).into_response());
+        }
+        Ok(None) => {}
+        Err(refusal) => return Ok(refusal),
+    }
+    delete_file(&state, &key, &file_path).await?;
+    Ok(StatusCode::NO_CONTENT.into_response())
+}
+
+// Removes the object `key` at `path`, or moves it to the trash. Deleting
+// one that doesn't exist succeeds
+async fn delete_file(state: &AppState, key: &str, file_path: &FsPath) -> Result<(), StatusCode> {
+    if state.trash_retention.is_some() {
+        return match trash::move_to_trash(state, key, file_path).await {
+            Ok(true) => {
+                info!("🗑️ Moved object to the trash: {}", key);
+                remove_empty_parents(state, file_path).await;
+                retention::forget(state, key).await;
+                Ok(())
+            }
+            Ok(false) => Ok(()),
+            Err(e) => {
+                warn!("Failed to move {} to the trash: {}", key, e);
+                Err(StatusCode::INTERNAL_SERVER_ERROR)
+            }
+        };
+    }
+
+    if fs::remove_file(file_path).await.is_ok() {
+        info!("🗑️ Deleted object: {}", key);
+        remove_empty_parents(state, file_path).await;
+        retention::forget(state, key).await;
+    }
+    Ok(())
+}
+
+// Does for a delete the middlewares don't see what they do for a DELETE:
+// counts it, logs it to the change feed and drops the key from the search
+// index. `old` is the object's size before
+async fn record_delete(state: &AppState, key: &str, file_path: &FsPath, old: Option<u64>) {
+    state.stats.replaced(old, stats::size(file_path).await);
+    state.handles.invalidate(file_path);
+    if let Some(feed) = &state.changes {
+        feed.record(replica::Op::Delete, key).await;
+    }
+    if let Some(index) = &state.search {
+        search::update(state, index, &normalize::stored_key(state, key)).await;
+    }
+}
+
+// Does for a write the middlewares don't see what they do for a PUT, as
+// record_delete does for a delete
+async fn record_put(state: &AppState, key: &str, file_path: &FsPath, old: Option<u64>) {
+    state.stats.replaced(old, stats::size(file_path).await);
+    state.handles.invalidate(file_path);
+    if let Some(feed) = &state.changes {
+        feed.record(replica::Op::Put, key).await;
+    }
+    if let Some(index) = &state.search {
+        search::update(state, index, &normalize::stored_key(state, key)).await;
+    }
+}
+
+// POST /?delete: DeleteObjects, up to MAX_DELETE_KEYS keys at once. The
+// middlewares only see a POST to the bucket, so each deleted key is
+// recorded here
+async fn delete_objects(
+    state: &AppState,
+    caller: Option<&policy::Caller>,
+    headers: &HeaderMap,
+    body: &[u8],
+) -> Result<Response, StatusCode> {
+    // S3 takes between 1 and MAX_DELETE_KEYS keys
+    let request = serde_xml_rs::from_reader::<DeleteRequest, _>(body)
+        .ok()
+        .filter(|request| (1..=MAX_DELETE_KEYS).contains(&request.objects.len()));
+    let Some(request) = request else {
+        return Ok(error_response(
+            StatusCode::BAD_REQUEST,
+            ErrorResponse {
+                code: "MalformedXML".to_string(),
+                message: "The XML you provided was not well-formed or did not validate against our published schema".to_string(),
+                max_size_allowed: None,
+            },
+        ));
+    };
+    let quiet = request
+        .quiet
+        .as_deref()
+        .is_some_and(|quiet| quiet.trim().eq_ignore_ascii_case("true"));
+
+    let mut result = DeleteResult {
+        xmlns: "http://s3.amazonaws.com/doc/2006-03-01/".to_string(),
+        deleted: Vec::new(),
+        errors: Vec::new(),
+    };
+    for object in request.objects {
+        let DeleteObject { key, version_id } = object;
+        let failure = |code: &str, message: &str| DeleteError {
+            key: key.clone(),
+            version_id: version_id.clone(),
+            code: code.to_string(),
+            message: message.to_string(),
+        };
+        let action = match version_id {
+            Some(_) => "s3:DeleteObjectVersion",
+            None => "s3:DeleteObject",
+        };
+        if caller.is_some_and(|caller| !policy::permits(state, caller, action, &key)) {
+            result.errors.push(failure("AccessDenied", "Access Denied"));
+            continue;
+        }
+        let Ok(file_path) = object_path(state, &key) else {
+            result.errors.push(failure("AccessDenied", "Access Denied"));
+            continue;
+        };
+        if worm::write_once(state, &file_path).await {
+            result.errors.push(failure("AccessDenied", "Object is locked under --worm"));
+            continue;
+        }
+        if retention::protects(state, &key, retention::bypasses(headers)).await {
+            result.errors.push(failure("AccessDenied", "Access Denied because object protected by object lock."));
+            continue;
+        }
+
+        let old = stats::size(&file_path).await;
+        let removed = versioning::delete(state, &key, &file_path, version_id.as_deref()).await;
+        let removed = match removed {
+            Ok(Some(removed)) => Ok(Some(removed)),
+            Ok(None) => delete_file(state, &key, &file_path).await.map(|()| None),
+            Err(refusal) => Err(refusal.status()),
+        };
+        let removed = match removed {
+            Ok(removed) => removed,
+            Err(StatusCode::BAD_REQUEST) => {
+                result.errors.push(failure("InvalidArgument", "Invalid version id specified"));
+                continue;
+            }
+            Err(_) => {
+                result.errors.push(failure("InternalError", "We encountered an internal error. Please try again."));
+                continue;
+            }
+        };
+        record_delete(state, &key, &file_path, old).await;
+        if quiet {
+            continue;
+        }
+        result.deleted.push(match removed {
+            Some(removed) if removed.delete_marker && version_id.is_none() => DeletedObject {
+                key,
+                version_id: None,
+                delete_marker: Some(true),
+                delete_marker_version_id: Some(removed.version_id),
+            },
+            Some(removed) => DeletedObject {
+                key,
+                version_id,
+                delete_marker: removed.delete_marker.then_some(true),
+                delete_marker_version_id: removed.delete_marker.then_some(removed.version_id),
+            },
+            None => DeletedObject {
+                key,
+                version_id,
+                delete_marker: None,
+                delete_marker_version_id: None,
+            },
+        });
+    }
+
+    let xml = serde_xml_rs::to_string(&result).map_err(|_| StatusCode::INTERNAL_SERVER_ERROR)?;
+    let mut headers = HeaderMap::new();
+    headers.insert("content-type", HeaderValue::from_static("application/xml"));
+    Ok((StatusCode::OK, headers, xml).into_response())
+}
+
+// Head object
+async fn head_object(
+    State(state): State<Arc<AppState>>,
+    Path(key): Path<String>,
+    Query(params): Query<GetObjectQuery>,
+    request_headers: HeaderMap,
+) -> Result<Response, StatusCode> {
+    let file_path = object_path(&state, &key)?;
+    let version_path = match &params.version_id {
+        Some(version_id) => match versioning::resolve(&state, &key, &file_path, version_id).await {
+            Ok(version_path) => version_path,
+            Err(refusal) => return Ok(refusal),
+        },
+        None => None,
+    };
+
+    match state.handles.open(version_path.as_deref().unwrap_or(&file_path)).await {
+        Ok(handle) => {
+            let mut headers = HeaderMap::new();
+
+            let mime_type = sniff::content_type_of(&state, &file_path, &handle)
+                .await
+                .map_err(|_| StatusCode::INTERNAL_SERVER_ERROR)?;
+            headers.insert(
+                "content-type",
+                HeaderValue::from_str(&mime_type).unwrap(),
+            );
+            headers.insert(
+                "content-length",
+                HeaderValue::from_str(&handle.len.to_string()).unwrap(),
+            );
+            headers.insert("last-modified", http_date(handle.modified));
+
+            let etag = match (&params.version_id, &version_path) {
+                (Some(version_id), Some(version_path)) => {
+                    versioning::etag(&state, &key, version_id, version_path).await
+                }
+                _ => etag::of_file(&state, &key, &file_path).await,
+            }
+            .map_err(|_| StatusCode::INTERNAL_SERVER_ERROR)?;
+            if let Some(response) =
+                conditional::check_read(&request_headers, &etag, handle.modified)
+            {
+                return Ok(response);
+            }
+            headers.insert("etag", HeaderValue::from_str(&etag).unwrap());
+            if checksum::wanted(&request_headers)
+                && let Some(checksum) =
+                    checksum::load(&state, &key, handle.modified, handle.len).await
+            {
+                checksum.insert_into(&mut headers);
+            }
+            tagging::insert_count(&state, &key, handle.modified, handle.len, &mut headers).await;
+            retention::insert_headers(&state, &key, &mut headers).await;
+            match &params.version_id {
+                Some(version_id) => versioning::insert_id(&mut headers, version_id),
+                None => {
+                    versioning::insert_current_id(
+                        &state,
+                        &key,
+                        handle.modified,
+                        handle.len,
+                        &mut headers,
+                    )
+                    .await
+                }
+            }
+
+            Ok((StatusCode::OK, headers).into_response())
+        }
+        Err(_) => Err(StatusCode::NOT_FOUND),
+    }
+}
+
+fn main() -> Result<(), Box<dyn std::error::Error>> {
+    let args = Args::parse();
+
+    #[cfg(windows)]
+    if let Some(Command::Service { command }) = &args.command {
+        return winservice::command(command);
+    }
+
+    tracing_subscriber::fmt::init();
+    runtime(&args)?.block_on(serve(args, std::future::pending()))
+}
+
+// The async workers run request handlers; file I/O, hashing of large bodies
+// and other blocking work go to a separate pool of up to
+// --max-blocking-threads, so a big upload can't stall unrelated requests.
+// --worker-threads defaults to one per CPU core
+fn runtime(args: &Args) -> io::Result<tokio::runtime::Runtime> {
+    let workers = args.worker_threads.unwrap_or_else(tuning::cores).max(1);
+    let blocking = args
+        .max_blocking_threads
+        .unwrap_or_else(tuning::max_blocking_threads)
+        .max(1);
+    info!("⚙️ Worker threads: {}, blocking threads: up to {}", workers, blocking);
+    tokio::runtime::Builder::new_multi_thread()
+        .worker_threads(workers)
+        .max_blocking_threads(blocking)
+        .enable_all()
+        .build()
+}
+
+// The state every request shares, as the command line sets it up. Cluster
+// membership, replicas and the change feed are added once it exists
+async fn app_state(args: &Args) -> Result<AppState, Box<dyn std::error::Error>> {
+    Ok(AppState {
+        bucket_name: args.bucket.clone(),
+        path_style: args.path_style,
+        access_key: args.access_key.clone(),
+        secret_key: args.secret_key.clone(),
+        strict_auth: args.strict_auth,
+        query_auth: !args.disable_query_auth,
+        lockout: Arc::new(lockout::Lockout::new(
+            args.auth_backoff_after,
+            args.auth_lockout_after,
+            Duration::from_secs(args.auth_lockout),
+        )),
+        auth_log: match &args.auth_log {
+            Some(path) => Some(Arc::new(authlog::AuthLog::open(path)?)),
+            None => None,
+        },
+        ip_filter: match &args.ip_filter {
+            Some(path) => Some(Arc::new(ipfilter::IpFilter::load(path, &args.bucket)?)),
+            None => None,
+        },
+        #[cfg(feature = "geoip")]
+        geoip: match (&args.geoip_db, &args.geoip_rules) {
+            (Some(db), Some(rules)) => Some(Arc::new(geoip::GeoIp::load(db, rules, &args.bucket)?)),
+            _ => None,
+        },
+        data_dir: args.data_dir.clone(),
+        data_root: fs::canonicalize(&args.data_dir).await?,
+        follow_symlinks: args.follow_symlinks,
+        trusted_proxies: args.trusted_proxies.clone(),
+        public_host: args.public_endpoint.as_deref().map(public_host).transpose()?,
+        mmap_threshold: args.mmap_threshold,
+        handles: Arc::new(handles::HandleCache::new(args.open_file_cache)),
+        readahead: Arc::new(readahead::Readahead::default()),
+        listings: Arc::new(listing::Snapshots::default()),
+        max_body_size: args.max_body_size,
+        max_list_keys: args.max_list_keys.max(1),
+        deadline_header: args.deadline_header.clone(),
+        slow_request: (args.slow_request_ms > 0).then(|| Duration::from_millis(args.slow_request_ms)),
+        requests: Arc::new(timing::Requests::default()),
+        read_buffer: args.read_buffer_size.unwrap_or_else(tuning::buffer_size).max(1),
+        write_buffer: args.write_buffer_size.unwrap_or_else(tuning::buffer_size).max(1),
+        shedder: Arc::new(shed::Shedder::new(
+            args.max_concurrent_requests,
+            args.min_free_disk,
+        )),
+        normalize_keys: args.normalize_keys,
+        cluster: None,
+        replicas: None,
+        changes: None,
+        read_only: args.replicate_from.is_some(),
+        mirror: args
+            .mirror_to
+            .as_deref()
+            .map(|url| mirror::Mirror::new(url, args.mirror_percent).map(Arc::new))
+            .transpose()?,
+        stats: Arc::new(stats::BucketStats::default()),
+        metrics: Arc::new(metrics::RequestMetrics::default()),
+        quotas: Arc::new(quota::Quotas::new(
+            args.quota_stored_bytes,
+            args.quota_monthly_transfer,
+        )),
+        usage: Arc::new(usage::Ledger::load(&args.data_dir).await?),
+        trash_retention: (args.trash_days > 0)
+            .then(|| Duration::from_secs(args.trash_days * 24 * 60 * 60)),
+        worm: args.worm,
+        conditional_writes: Arc::new(tokio::sync::Mutex::new(())),
+        ranged_writes: Arc::new(tokio::sync::Mutex::new(())),
+        sniff_content_type: args.sniff_content_type,
+        scanner: scan::Scanner::new(
+            args.clamd.clone(),
+            args.scan_command.clone(),
+            args.scan_action,
+            args.scan_log.clone(),
+        )?
+        .map(Arc::new),
+        snapshots: Arc::new(snapshot::Snapshots::new(args.snapshot_schedules.clone())),
+        access: Arc::new(access::Access::load(&args.data_dir).await?),
+        versioning: Arc::new(versioning::Versioning::load(&args.data_dir).await?),
+        lifecycle: Arc::new(access::BucketConfig::load(&args.data_dir).await?),
+        cors: Arc::new(access::BucketConfig::load(&args.data_dir).await?),
+        policy: Arc::new(policy::BucketPolicy::load(&args.data_dir).await?),
+        torrents: Arc::new(torrent::Torrents::default()),
+        tiering: match tiering_backend(args)? {
+            Some(backend) => Some(Arc::new(
+                tier::Tiering::load(&args.data_dir, args.tier_after_days, backend).await?,
+            )),
+            None => None,
+        },
+        erasure: match args.erasure_dirs.is_empty() {
+            true => None,
+            false => Some(Arc::new(erasure::ErasureSet::new(
+                args.erasure_dirs.clone(),
+                args.parity_shards,
+            )?)),
+        },
+        search: match args.search {
+            true => Some(Arc::new(
+                search::SearchIndex::load(&args.data_dir, args.search_prefixes.clone()).await?,
+            )),
+            false => None,
+        },
+        torrent_trackers: args.torrent_trackers.clone(),
+        #[cfg(feature = "wasm")]
+        transforms: match &args.transforms {
+            Some(path) => Some(Arc::new(wasm::Transforms::load(path)?)),
+            None => None,
+        },
+    })
+}
+
+// Runs the server until `shutdown` completes
+async fn serve(
+    mut args: Args,
+    shutdown: impl Future<Output = ()> + Send + 'static,
+) -> Result<(), Box<dyn std::error::Error>> {
+    #[cfg(feature = "watch")]
+    if let Some(Command::Watch { dir, to, prefix }) = &args.command {
+        return watch::watch(dir, to, prefix, &args.access_key, &args.secret_key).await;
+    }
+
+    fs::create_dir_all(&args.data_dir).await?;
+
+    if let Some(Command::Migrate) = args.command {
+        return layout::migrate(&args.data_dir);
+    }
+    layout::check(&args.data_dir)?;
+    keys::set_escape_case(layout::escapes_case(&args.data_dir)?);
+
+    if let Some(Command::NormalizeKeys) = args.command {
+        return normalize::normalize_keys(&args.data_dir);
+    }
+
+    fs::create_dir_all(args.data_dir.join(TMP_DIR)).await?;
+
+    if let Some(Command::Ingest {
+        from,
+        move_files,
+        link,
+    }) = &args.command
+    {
+        let mode = match (move_files, link) {
+            (true, _) => ingest::Mode::Move,
+            (_, true) => ingest::Mode::Link,
+            _ => ingest::Mode::Copy,
+        };
+        return ingest::ingest(&args.data_dir, &args.bucket, from, mode, args.normalize_keys).await;
+    }
+
+    let provision = match &args.provision {
+        Some(path) => Some(provision::Provision::load(path)?),
+        None => None,
+    };
+    if let Some(provision) = &provision {
+        provision.apply_to(&mut args);
+        provision.seed(&args.data_dir, args.normalize_keys).await?;
+    }
+
+    let mut state = app_state(&args).await?;
+
+    if args.change_feed {
+        state.changes = Some(Arc::new(replica::ChangeFeed::open(&state).await?));
+    }
+
+    if !args.peers.is_empty() || args.advertise_url.is_some() {
+        let url = args
+            .advertise_url
+            .clone()
+            .unwrap_or_else(|| format!("http://{}:{}", args.host, args.port));
+        let cluster = cluster::Cluster::new(&state, url, &args.peers).await?;
+
+        info!("🛰️ Cluster node {} advertising {}", cluster.node_id, cluster.url);
+        state.cluster = Some(Arc::new(cluster));
+        state.replicas = args.replicas.map(|replicas| replicas.max(1));
+    } else if args.replicas.is_some() {
+        warn!("--replicas needs cluster mode (--peers or --advertise-url), ignoring it");
+    }
+
+    if state.max_list_keys > 1000 {
+        warn!(
+            "📋 Listings may return up to {} keys, more than S3's limit of 1000",
+            state.max_list_keys
+        );
+    }
+
+    if let Some(provision) = &provision {
+        provision.apply_access(&state).await?;
+    }
+    let state = Arc::new(state);
+
+    if let Some(cluster) = &state.cluster {
+        cluster.clone().spawn_heartbeats(state.clone());
+    }
+    if let Some(ip_filter) = &state.ip_filter {
+        ip_filter.clone().spawn_reload();
+    }
+    gc::spawn(state.clone());
+    lifecycle::spawn(state.clone());
+    if let Some(scanner) = &state.scanner {
+        scan::describe(scanner);
+    }
+    if let Some(set) = &state.erasure {
+        // Shards would be made of the stubs tiering leaves behind
+        if state.tiering.is_some() {
+            return Err("--erasure-dirs can't be combined with --tier-after-days".into());
+        }
+        erasure::spawn(state.clone(), set.clone());
+    }
+    stats::spawn(state.clone());
+    usage::spawn(state.clone());
+    snapshot::spawn(state.clone());
+    if let Some(frequency) = args.inventory {
+        inventory::spawn(state.clone(), frequency, args.inventory_prefix.clone());
+    }
+    tier::spawn(state.clone());
+    shed::spawn(state.clone());
+    search::spawn(state.clone());
+    if let Some(primary) = &args.replicate_from {
+        replica::spawn_follower(state.clone(), primary.clone());
+    }
+
+    let mut app = Router::new()
+        .route(
+            "/",
+            get(list_objects)
+                .head(stats::head_bucket)
+                .put(put_bucket)
+                // Browser uploads stream their file, limited by its policy
+                .post(post_bucket.layer(DefaultBodyLimit::disable()))
+                .delete(delete_bucket),
+        )
+        .route(stats::STATS_PATH, get(stats::bucket_stats))
+        .route(lockout::AUTH_STATS_PATH, get(lockout::auth_stats))
+        .route(cluster::STATUS_PATH, get(cluster::cluster_status))
+        .route(replica::CHANGES_PATH, get(replica::changes))
+        .route("/{*key}", get(get_object))
+        .route("/{*key}", put(put_object))
+        .route("/{*key}", post(post_object))
+        .route("/{*key}", delete(delete_object))
+        .route("/{*key}", head(head_object))
+        .route_layer(middleware::from_fn_with_state(
+            state.clone(),
+            replica::record_changes,
+        ))
+        .route_layer(middleware::from_fn_with_state(
+            state.clone(),
+            erasure::protect_writes,
+        ))
+        .route_layer(middleware::from_fn_with_state(
+            state.clone(),
+            stats::track_changes,
+        ))
+        .route_layer(middleware::from_fn_with_state(
+            state.clone(),
+            search::index_changes,
+        ));
+
+    if args.tus {
+        tus::init(&state).await?;
+        app = app.merge(tus::router());
+    }
+    if args.minio_admin {
+        app = app.merge(minio::router());
+    }
+    if state.trash_retention.is_some() {
+        trash::init(&state).await?;
+    }
+
+    let app = app
+        .layer(middleware::from_fn_with_state(
+            state.clone(),
+            placement::placement_middleware,
+        ))
+        .layer(middleware::from_fn_with_state(
+            state.clone(),
+            replica::read_only_middleware,
+        ));
+
+    // Layers run after routing, so plugins wrap the routed app as a fallback
+    // service for a key they rewrite to be routed again
+    #[cfg(feature = "wasm")]
+    let app = match &args.plugins {
+        Some(path) => Router::new()
+            .fallback_service(app.with_state(state.clone()))
+            .layer(middleware::from_fn_with_state(
+                Arc::new(wasm::Plugins::load(path, args.tus)?),
+                wasm::plugin_middleware,
+            )),
+        None => app,
+    };
+
+    #[cfg(feature = "lua")]
+    let app = match &args.scripts_dir {
+        Some(dir) => app.layer(middleware::from_fn_with_state(
+            Arc::new(scripts::Scripts::load(dir, args.tus)?),
+            scripts::script_middleware,
+        )),
+        None => app,
+    };
+
+    let app = app
+        .layer(middleware::from_fn_with_state(
+            state.clone(),
+            usage::usage_middleware,
+        ))
+        .layer(middleware::from_fn_with_state(
+            state.clone(),
+            quota::quota_middleware,
+        ))
+        .layer(middleware::from_fn_with_state(
+            state.clone(),
+            auth_middleware,
+        ))
+        .layer(middleware::from_fn_with_state(
+            state.clone(),
+            body_limit_middleware,
+        ));
+    #[cfg(feature = "geoip")]
+    let app = app.layer(middleware::from_fn_with_state(
+        state.clone(),
+        geoip::geoip_middleware,
+    ));
+    let app = app
+        .layer(middleware::from_fn_with_state(
+            state.clone(),
+            ipfilter::ip_filter_middleware,
+        ))
+        .layer(middleware::from_fn_with_state(
+            state.clone(),
+            deadline::deadline_middleware,
+        ))
+        .layer(middleware::from_fn_with_state(
+            state.clone(),
+            mirror::mirror_middleware,
+        ))
+        .layer(middleware::from_fn_with_state(
+            state.clone(),
+            shed::shed_middleware,
+        ))
+        .layer(middleware::from_fn_with_state(
+            state.clone(),
+            metrics::metrics_middleware,
+        ))
+        .layer(middleware::from_fn_with_state(
+            state.clone(),
+            timing::timing_middleware,
+        ))
+        .layer(middleware::from_fn_with_state(
+            state.clone(),
+            proxy::forwarded_middleware,
+        ));
+
+    let app = match std::env::var(chaos::CHAOS_ENV) {
+        Ok(config) => {
+            let chaos = chaos::Chaos::parse(&config)
+                .map_err(|e| format!("invalid {}: {}", chaos::CHAOS_ENV, e))?;
+            warn!("🐒 Chaos mode enabled: {}", config);
+            app.layer(middleware::from_fn_with_state(
+                Arc::new(chaos),
+                chaos::chaos_middleware,
+            ))
+        }
+        Err(_) => app,
+    };
+
+    let cors = cors::layer(
+        &args.cors_origins,
+        &args.cors_methods,
+        &args.cors_allow_headers,
+        &args.cors_expose_headers,
+        args.cors_max_age,
+        args.cors_allow_credentials,
+    )?;
+    let app = app
+        .layer(cors)
+        .layer(middleware::from_fn_with_state(
+            state.clone(),
+            cors::bucket_cors_middleware,
+        ))
+        .with_state(state.clone());
+
+    // Sockets from systemd socket activation take the place of the ports
+    let mut sockets = systemd::listeners();
+    let webdav_listener = match systemd::take(&mut sockets, |name| name == "webdav")? {
+        Some(listener) => Some(listener),
+        None => match args.webdav_port {
+            Some(port) => {
+                Some(tokio::net::TcpListener::bind(format!("{}:{}", args.host, port)).await?)
+            }
+            None => None,
+        },
+    };
+
+    if let Some(webdav_listener) = webdav_listener {
+        let webdav_addr = webdav_listener.local_addr()?;
+        let webdav_app = webdav::router(state.clone()).layer(middleware::from_fn_with_state(
+            state.clone(),
+            body_limit_middleware,
+        ));
+        #[cfg(feature = "geoip")]
+        let webdav_app = webdav_app.layer(middleware::from_fn_with_state(
+            state.clone(),
+            geoip::geoip_middleware,
+        ));
+        let webdav_app = webdav_app
+            .layer(middleware::from_fn_with_state(
+                state.clone(),
+                ipfilter::ip_filter_middleware,
+            ))
+            .layer(middleware::from_fn_with_state(
+                state.clone(),
+                proxy::forwarded_middleware,
+            ));
+
+        info!("📂 WebDAV server starting on http://{}", webdav_addr);
+        if args.strict_auth {
+            warn!("📂 --strict-auth covers the S3 API only, WebDAV still takes Basic auth");
+        }
+        tokio::spawn(async move {
+            if let Err(e) = axum::serve(
+                webdav_listener,
+                webdav_app.into_make_service_with_connect_info::<std::net::SocketAddr>(),
+            )
+            .await
+            {
+                warn!("WebDAV server stopped: {}", e);
+            }
+        });
+    }
+
+    let admin_listener = match systemd::take(&mut sockets, |name| name == "admin")? {
+        Some(listener) => Some(listener),
+        None => match args.admin_port {
+            Some(port) => {
+                Some(tokio::net::TcpListener::bind(format!("{}:{}", args.host, port)).await?)
+            }
+            None => None,
+        },
+    };
+
+    if let Some(admin_listener) = admin_listener {
+        let admin_addr = admin_listener.local_addr()?;
+        let admin_app = admin::router(state.clone());
+        #[cfg(feature = "geoip")]
+        let admin_app = admin_app.layer(middleware::from_fn_with_state(
+            state.clone(),
+            geoip::geoip_middleware,
+        ));
+        let admin_app = admin_app
+            .layer(middleware::from_fn_with_state(
+                state.clone(),
+                ipfilter::ip_filter_middleware,
+            ))
+            .layer(middleware::from_fn_with_state(
+                state.clone(),
+                proxy::forwarded_middleware,
+            ));
+
+        info!("📈 Status page on http://{}", admin_addr);
+        tokio::spawn(async move {
+            if let Err(e) = axum::serve(
+                admin_listener,
+                admin_app.into_make_service_with_connect_info::<std::net::SocketAddr>(),
+            )
+            .await
+            {
+                warn!("Admin server stopped: {}", e);
+            }
+        });
+    }
+
+    #[cfg(feature = "grpc")]
+    if let Some(grpc_port) = args.grpc_port {
+        let grpc_listener =
+            tokio::net::TcpListener::bind(format!("{}:{}", args.host, grpc_port)).await?;
+        let grpc_addr = grpc_listener.local_addr()?;
+        let grpc_app = grpc::router(state.clone())
+            .layer(middleware::from_fn_with_state(
+                state.clone(),
+                ipfilter::ip_filter_middleware,
+            ))
+            .layer(middleware::from_fn_with_state(
+                state.clone(),
+                proxy::forwarded_middleware,
+            ));
+
+        info!("🛰️ gRPC admin API on {}", grpc_addr);
+        tokio::spawn(async move {
+            if let Err(e) = axum::serve(
+                grpc_listener,
+                grpc_app.into_make_service_with_connect_info::<std::net::SocketAddr>(),
+            )
+            .await
+            {
+                warn!("gRPC server stopped: {}", e);
+            }
+        });
+    }
+
+    #[cfg(feature = "sftp")]
+    if let Some(sftp_port) = args.sftp_port {
+        let sftp_addr = format!("{}:{}", args.host, sftp_port);
+        let sftp_state = state.clone();
+        let authorized_keys = args.sftp_authorized_keys.clone();
+
+        info!("🔐 SFTP server starting on {}", sftp_addr);
+        tokio::spawn(async move {
+            if let Err(e) = sftp::serve(sftp_state, sftp_addr, authorized_keys).await {
+                warn!("SFTP server stopped: {}", e);
+            }
+        });
+    }
+
+    let listener = match systemd::take(&mut sockets, |_| true)? {
+        Some(listener) => listener,
+        None => tokio::net::TcpListener::bind(format!("{}:{}", args.host, args.port)).await?,
+    };
+    let addr = listener.local_addr()?;
+    for (name, _) in &sockets {
+        warn!("🔌 Ignoring extra socket {} passed by systemd", name);
+    }
+
+    info!("🚀 S3-compatible server starting on http://{}", addr);
+    info!("📦 Bucket: {}", args.bucket);
+    info!("💾 Data directory: {}", args.data_dir.display());
+    if args.tus {
+        info!("⏫ tus uploads enabled at /tus/");
+    }
+    if let Some(primary) = &args.replicate_from {
+        info!("📜 Read replica of {}", primary);
+    }
+
+    systemd::notify(&format!("READY=1\nSTATUS=Serving bucket {} on {}", args.bucket, addr));
+    systemd::spawn_watchdog();
+
+    // Path-style requests are rewritten before they're routed
+    let app = tower::Layer::layer(
+        &middleware::from_fn_with_state(state.clone(), pathstyle::path_style_middleware),
+        app,
+    );
+    axum::serve(
+        listener,
+        axum::ServiceExt::<Request>::into_make_service_with_connect_info::<std::net::SocketAddr>(app),
+    )
+    .with_graceful_shutdown(shutdown)
+    .await?;
+
+    Ok(())
+}
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    const KEYS: Keys = Keys {
+        access_key: "mykey",
+        secret_key: "mysecret",
+        public_host: None,
+    };
+    const HOST: &str = "localhost:9000";
+
+    // The state of a server started with default flags on `data_dir`, for
+    // the tests of other modules
+    pub(crate) async fn state(data_dir: &FsPath) -> AppState {
+        fs::create_dir_all(data_dir.join(TMP_DIR)).await.unwrap();
+        let args = Args::parse_from(["simpleS3", "--data-dir", data_dir.to_str().unwrap()]);
+        app_state(&args).await.unwrap()
+    }
+
+    fn at(time: &str) -> chrono::NaiveDateTime {
+        chrono::NaiveDateTime::parse_from_str(time, "%Y%m%dT%H%M%SZ").unwrap()
+    }
+
+    // The query of a GET of /photo.jpg presigned at `date` for `expires`
+    // seconds, signed the way the AWS SDKs do it
+    fn presign(date: &str, expires: i64) -> String {
+        let scope = format!("{}/us-east-1/s3/aws4_request", &date[..8]);
+        let query = format!(
+            "X-Amz-Algorithm=AWS4-HMAC-SHA256&X-Amz-Credential={}&X-Amz-Date={}\
+             &X-Amz-Expires={}&X-Amz-SignedHeaders=host",
+            utf8_percent_encode(&format!("mykey/{}", scope), NON_ALPHANUMERIC),
+            date,
+            expires
+        );
+        let canonical_request = format!(
+            "GET\n/photo.jpg\n{}\nhost:{}\n\nhost\nUNSIGNED-PAYLOAD",
+            canonical_query(&query),
+            HOST
+        );
+        let string_to_sign = format!(
+            "AWS4-HMAC-SHA256\n{}\n{}\n{}",
+            date,
+            scope,
+            hex::encode(Sha256::digest(canonical_request.as_bytes()))
+        );
+        let key = signing_key(KEYS.secret_key, &date[..8], "us-east-1", "s3");
+        let mut mac = HmacSha256::new_from_slice(&key).unwrap();
+        mac.update(string_to_sign.as_bytes());
+        format!("{}&X-Amz-Signature={}", query, hex::encode(mac.finalize().into_bytes()))
+    }
+
+    fn verify(query: &str, now: &str) -> bool {
+        let mut headers = HeaderMap::new();
+        headers.insert("host", HeaderValue::from_static(HOST));
+        verify_presigned_url(&headers, &Method::GET, "/photo.jpg", query, &KEYS, at(now))
+    }
+
+    #[test]
+    fn presigned_url_verifies_within_its_window() {
+        let query = presign("20260101T120000Z", 3600);
+        assert!(verify(&query, "20260101T120000Z"));
+        assert!(verify(&query, "20260101T130000Z"));
+    }
+
+    #[test]
+    fn presigned_url_expires() {
+        let query = presign("20260101T120000Z", 3600);
+        assert!(!verify(&query, "20260101T130001Z"));
+        assert_eq!(
+            presigned_window(&query, at("20260101T130001Z")),
+            Err(PresignedRefusal::Expired)
+        );
+    }
+
+    #[test]
+    fn presigned_url_dated_in_the_future_is_not_valid_yet() {
+        let query = presign("20260601T120000Z", MAX_PRESIGNED_EXPIRES);
+        assert!(!verify(&query, "20260101T120000Z"));
+        assert_eq!(
+            presigned_window(&query, at("20260101T120000Z")),
+            Err(PresignedRefusal::NotYetValid)
+        );
+        // Within the allowed clock skew it works
+        assert!(verify(&query, "20260601T114600Z"));
+        assert!(!verify(&query, "20260601T114400Z"));
+    }
+
+    #[test]
+    fn presigned_url_expiry_is_capped_at_a_week() {
+        let query = presign("20260101T120000Z", MAX_PRESIGNED_EXPIRES + 1);
+        assert!(!verify(&query, "20260101T120000Z"));
+        assert!(matches!(
+            presigned_window(&query, at("20260101T120000Z")),
+            Err(PresignedRefusal::Malformed(_))
+        ));
+        assert!(!verify(&presign("20260101T120000Z", 0), "20260101T120000Z"));
+    }
+
+    #[test]
+    fn tampered_presigned_url_is_refused() {
+        let query = presign("20260101T120000Z", 3600);
+        let now = "20260101T120000Z";
+        assert!(!verify(&format!("{}&acl", query), now));
+        assert!(!verify(&query.replace("X-Amz-Expires=3600", "X-Amz-Expires=7200"), now));
+        assert!(!verify(&query.replace("mykey%2F", "other%2F"), now));
+        let signature = query.rsplit_once('=').unwrap().1;
+        let last = if signature.ends_with('0') { "1" } else { "0" };
+        let flipped = format!("{}{}", &signature[..signature.len() - 1], last);
+        assert!(!verify(&query.replace(signature, &flipped), now));
+    }
+
+    async fn put(state: &Arc<AppState>, key: &str, headers: HeaderMap, body: &str) -> Response {
+        let (key, query) = (Path(key.to_string()), Query(PutObjectQuery::default()));
+        let body = Body::from(body.to_string());
+        put_object(State(state.clone()), key, query, headers, body).await.unwrap()
+    }
+
+    async fn copy(state: &Arc<AppState>, key: &str, source: &str) -> Response {
+        let mut headers = HeaderMap::new();
+        headers.insert("x-amz-copy-source", HeaderValue::from_str(source).unwrap());
+        put(state, key, headers, "").await
+    }
+
+    #[tokio::test]
+    async fn copy_reads_the_source_version_it_names() {
+        let dir = std::env::temp_dir().join(format!("copy-{}", uuid::Uuid::new_v4()));
+        let state = Arc::new(state(&dir).await);
+        let enable = Bytes::from_static(
+            b"<VersioningConfiguration><Status>Enabled</Status></VersioningConfiguration>",
+        );
+        access::put(&state, &state.versioning.config, &enable).await.unwrap();
+
+        let first = put(&state, "v.txt", HeaderMap::new(), "one").await;
+        let first = first.headers()["x-amz-version-id"].to_str().unwrap().to_string();
+        put(&state, "v.txt", HeaderMap::new(), "two").await;
+
+        let source = format!("/simple-bucket/v.txt?versionId={}", first);
+        let copied = copy(&state, "old.txt", &source).await;
+        assert_eq!(copied.status(), StatusCode::OK);
+        assert_eq!(copied.headers()["x-amz-copy-source-version-id"], first.as_str());
+        assert_eq!(fs::read_to_string(dir.join("old.txt")).await.unwrap(), "one");
+        copy(&state, "new.txt", "simple-bucket/v.txt").await;
+        assert_eq!(fs::read_to_string(dir.join("new.txt")).await.unwrap(), "two");
+
+        let unknown = "/simple-bucket/v.txt?versionId=00000000000000ff";
+        let missing = copy(&state, "x.txt", unknown).await;
+        assert_eq!(missing.status(), StatusCode::NOT_FOUND);
+        assert!(!dir.join("x.txt").exists());
+        let _ = fs::remove_dir_all(&dir).await;
+    }
+
+    #[tokio::test]
+    async fn copy_of_a_version_needs_a_versioned_bucket() {
+        let dir = std::env::temp_dir().join(format!("copy-{}", uuid::Uuid::new_v4()));
+        let state = Arc::new(state(&dir).await);
+        put(&state, "v.txt", HeaderMap::new(), "one").await;
+
+        let copied = copy(&state, "x.txt", "/simple-bucket/v.txt?versionId=null").await;
+        assert_eq!(copied.status(), StatusCode::BAD_REQUEST);
+        assert!(!dir.join("x.txt").exists());
+        let _ = fs::remove_dir_all(&dir).await;
+    }
+
+    #[test]
+    fn internal_directory_is_unreachable_in_any_case() {
+        for key in [".simple-s3/layout", ".SIMPLE-S3/versions/x", "./.Simple-S3/tmp", ".simple-s3"] {
+            assert_eq!(relative_path(key), None, "{}", key);
+        }
+        assert!(relative_path("docs/.simple-s3/x").is_some());
+        assert!(relative_path(".simple-s3-old/x").is_some());
+    }
+}
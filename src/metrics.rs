@@ -0,0 +1,158 @@
+use axum::{
+    extract::{Request, State},
+    http::Method,
+    middleware::Next,
+    response::Response,
+};
+use serde::Serialize;
+use std::{
+    sync::{
+        Arc, Mutex,
+        atomic::{AtomicU64, Ordering},
+    },
+    time::{Duration, Instant},
+};
+
+use crate::AppState;
+
+// Request counters for the status page. Totals since startup, plus a ring of
+// per-second counts covering the last WINDOW for current rates.
+
+const WINDOW: usize = 60;
+
+#[derive(Debug, Default, Clone, Copy)]
+struct Second {
+    // Seconds since startup this slot counts, so stale slots can be told apart
+    at: u64,
+    requests: u64,
+    client_errors: u64,
+    server_errors: u64,
+}
+
+#[derive(Debug, Default, Serialize)]
+pub struct Rates {
+    pub requests: f64,
+    pub client_errors: f64,
+    pub server_errors: f64,
+}
+
+#[derive(Debug, Serialize)]
+pub struct Counts {
+    pub uptime_secs: u64,
+    pub requests: u64,
+    pub client_errors: u64,
+    pub server_errors: u64,
+    // Per second, over the last minute
+    pub rates: Rates,
+    // PUT and POST requests being handled right now
+    pub writes_in_flight: u64,
+    // Requests turned away with 503 SlowDown by load shedding
+    pub shed: u64,
+}
+
+pub struct RequestMetrics {
+    started: Instant,
+    requests: AtomicU64,
+    client_errors: AtomicU64,
+    server_errors: AtomicU64,
+    writes_in_flight: AtomicU64,
+    shed: AtomicU64,
+    seconds: Mutex<[Second; WINDOW]>,
+}
+
+impl Default for RequestMetrics {
+    fn default() -> Self {
+        Self {
+            started: Instant::now(),
+            requests: AtomicU64::new(0),
+            client_errors: AtomicU64::new(0),
+            server_errors: AtomicU64::new(0),
+            writes_in_flight: AtomicU64::new(0),
+            shed: AtomicU64::new(0),
+            seconds: Mutex::new([Second::default(); WINDOW]),
+        }
+    }
+}
+
+impl RequestMetrics {
+    pub fn uptime(&self) -> Duration {
+        self.started.elapsed()
+    }
+
+    pub fn shed(&self) {
+        self.shed.fetch_add(1, Ordering::Relaxed);
+    }
+
+    fn record(&self, status: u16) {
+        let client_error = (400..500).contains(&status) as u64;
+        let server_error = (status >= 500) as u64;
+        self.requests.fetch_add(1, Ordering::Relaxed);
+        self.client_errors.fetch_add(client_error, Ordering::Relaxed);
+        self.server_errors.fetch_add(server_error, Ordering::Relaxed);
+
+        let now = self.uptime().as_secs();
+        let mut seconds = self.seconds.lock().unwrap();
+        let slot = &mut seconds[now as usize % WINDOW];
+        if slot.at != now {
+            *slot = Second {
+                at: now,
+                ..Second::default()
+            };
+        }
+        slot.requests += 1;
+        slot.client_errors += client_error;
+        slot.server_errors += server_error;
+    }
+
+    pub fn counts(&self) -> Counts {
+        let now = self.uptime().as_secs();
+        // The current second is still filling up, so rates cover the ones before
+        let span = now.min(WINDOW as u64 - 1).max(1) as f64;
+        let mut rates = Rates::default();
+        for second in self.seconds.lock().unwrap().iter() {
+            if second.at < now && now - second.at < WINDOW as u64 {
+                rates.requests += second.requests as f64;
+                rates.client_errors += second.client_errors as f64;
+                rates.server_errors += second.server_errors as f64;
+            }
+        }
+        rates.requests /= span;
+        rates.client_errors /= span;
+        rates.server_errors /= span;
+
+        Counts {
+            uptime_secs: now,
+            requests: self.requests.load(Ordering::Relaxed),
+            client_errors: self.client_errors.load(Ordering::Relaxed),
+            server_errors: self.server_errors.load(Ordering::Relaxed),
+            rates,
+            writes_in_flight: self.writes_in_flight.load(Ordering::Relaxed),
+            shed: self.shed.load(Ordering::Relaxed),
+        }
+    }
+}
+
+// Decrements an in-flight count even if the request is dropped midway
+pub struct InFlight<'a>(pub &'a AtomicU64);
+
+impl Drop for InFlight<'_> {
+    fn drop(&mut self) {
+        self.0.fetch_sub(1, Ordering::Relaxed);
+    }
+}
+
+pub async fn metrics_middleware(
+    State(state): State<Arc<AppState>>,
+    request: Request,
+    next: Next,
+) -> Response {
+    let metrics = &state.metrics;
+    let _in_flight = matches!(*request.method(), Method::PUT | Method::POST).then(|| {
+        metrics.writes_in_flight.fetch_add(1, Ordering::Relaxed);
+        InFlight(&metrics.writes_in_flight)
+    });
+
+    let response = next.run(request).await;
+    metrics.record(response.status().as_u16());
+    response
+}
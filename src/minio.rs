@@ -0,0 +1,264 @@
+use axum::{
+    Json, Router,
+    extract::{Extension, Query, Request, State},
+    http::{HeaderMap, StatusCode},
+    response::{IntoResponse, Response},
+    routing::{any, get, post},
+};
+use serde::{Deserialize, Serialize};
+use std::{collections::HashMap, sync::Arc, time::Duration};
+use tracing::{info, warn};
+
+use crate::{AppState, admin, proxy::ClientInfo};
+
+// A subset of the MinIO admin API, so `mc admin info` and `mc admin service
+// restart|stop` work against the server with --minio-admin. mc sends these
+// to the S3 port under ADMIN_PREFIX, signed like any S3 request. The server
+// has a single access key and no per-user IAM policies, only the bucket
+// policy, so user and policy commands are answered with NotImplemented, as
+// are the rest. Restart re-executes the binary with the same arguments once
+// the response is out.
+//
+// User, policy and bucket commands wait on more than one key and bucket (see
+// buckets.rs).
+
+pub const ADMIN_PREFIX: &str = "/minio/admin/v3/";
+// Time for the response to reach mc before the process goes away
+const SERVICE_DELAY: Duration = Duration::from_millis(500);
+
+#[derive(Serialize)]
+struct Count {
+    count: u64,
+}
+
+#[derive(Serialize)]
+struct Usage {
+    size: u64,
+}
+
+#[derive(Serialize)]
+#[serde(rename_all = "camelCase")]
+struct Backend {
+    backend_type: &'static str,
+    online_disks: usize,
+    offline_disks: usize,
+}
+
+#[derive(Serialize)]
+struct Drive {
+    endpoint: String,
+    path: String,
+    state: &'static str,
+    totalspace: u64,
+    usedspace: u64,
+    availspace: u64,
+}
+
+#[derive(Serialize)]
+#[serde(rename_all = "camelCase")]
+struct Server {
+    state: &'static str,
+    endpoint: String,
+    scheme: String,
+    uptime: u64,
+    version: &'static str,
+    #[serde(rename = "commitID")]
+    commit_id: &'static str,
+    network: HashMap<String, &'static str>,
+    drives: Vec<Drive>,
+}
+
+#[derive(Serialize)]
+struct InfoMessage {
+    mode: &'static str,
+    region: &'static str,
+    buckets: Count,
+    objects: Count,
+    usage: Usage,
+    backend: Backend,
+    servers: Vec<Server>,
+}
+
+#[derive(Serialize)]
+struct AdminError {
+    #[serde(rename = "Code")]
+    code: &'static str,
+    #[serde(rename = "Message")]
+    message: String,
+    #[serde(rename = "Resource")]
+    resource: String,
+}
+
+#[derive(Deserialize)]
+struct ServiceQuery {
+    action: String,
+    // 2 for the JSON response of newer mc versions
+    #[serde(rename = "type")]
+    kind: Option<String>,
+}
+
+#[derive(Serialize)]
+struct ServiceResult {
+    host: String,
+    err: String,
+}
+
+#[derive(Serialize)]
+#[serde(rename_all = "camelCase")]
+struct ServiceActionResult {
+    action: String,
+    dry_run: bool,
+    results: Vec<ServiceResult>,
+}
+
+pub fn router() -> Router<Arc<AppState>> {
+    Router::new()
+        .route("/minio/admin/v3/info", get(server_info))
+        .route("/minio/admin/v3/service", post(service))
+        .route("/minio/admin/v3/{*op}", any(not_implemented))
+}
+
+pub fn is_admin_path(path: &str) -> bool {
+    path.starts_with(ADMIN_PREFIX)
+}
+
+fn host(headers: &HeaderMap) -> String {
+    headers
+        .get("host")
+        .and_then(|v| v.to_str().ok())
+        .unwrap_or_default()
+        .to_string()
+}
+
+fn admin_error(status: StatusCode, code: &'static str, message: &str, resource: &str) -> Response {
+    let error = AdminError {
+        code,
+        message: message.to_string(),
+        resource: resource.to_string(),
+    };
+    (status, Json(error)).into_response()
+}
+
+// GET /minio/admin/v3/info, for `mc admin info`
+async fn server_info(
+    State(state): State<Arc<AppState>>,
+    client: Option<Extension<ClientInfo>>,
+    headers: HeaderMap,
+) -> Json<InfoMessage> {
+    let endpoint = host(&headers);
+    let totals = state.stats.totals();
+    let disk = admin::disk(&state.data_root);
+    let drive = Drive {
+        endpoint: endpoint.clone(),
+        path: state.data_dir.display().to_string(),
+        state: "ok",
+        totalspace: disk.as_ref().map_or(0, |disk| disk.total_bytes),
+        usedspace: disk
+            .as_ref()
+            .map_or(0, |disk| disk.total_bytes.saturating_sub(disk.free_bytes)),
+        availspace: disk.as_ref().map_or(0, |disk| disk.free_bytes),
+    };
+
+    Json(InfoMessage {
+        mode: "online",
+        region: "us-east-1",
+        buckets: Count { count: 1 },
+        objects: Count {
+            count: totals.objects,
+        },
+        usage: Usage { size: totals.bytes },
+        backend: Backend {
+            backend_type: "FS",
+            online_disks: 1,
+            offline_disks: 0,
+        },
+        servers: vec![Server {
+            state: "online",
+            endpoint: endpoint.clone(),
+            scheme: client.map_or("http".to_string(), |client| client.scheme.clone()),
+            uptime: state.metrics.uptime().as_secs(),
+            version: env!("CARGO_PKG_VERSION"),
+            commit_id: "",
+            network: HashMap::from([(endpoint, "online")]),
+            drives: vec![drive],
+        }],
+    })
+}
+
+// POST /minio/admin/v3/service?action=restart|stop
+async fn service(Query(query): Query<ServiceQuery>, headers: HeaderMap) -> Response {
+    let action = query.action.as_str();
+    match action {
+        "restart" if cfg!(unix) => {}
+        "stop" => {}
+        _ => {
+            return admin_error(
+                StatusCode::NOT_IMPLEMENTED,
+                "NotImplemented",
+                &format!("Service action {} is not supported", action),
+                "/minio/admin/v3/service",
+            );
+        }
+    }
+
+    warn!("🛠️ {} requested over the MinIO admin API", action);
+    let restart = action == "restart";
+    tokio::spawn(async move {
+        tokio::time::sleep(SERVICE_DELAY).await;
+        if restart {
+            let e = reexec();
+            warn!("🛠️ Failed to restart: {}", e);
+        } else {
+            info!("👋 Stopping");
+            std::process::exit(0);
+        }
+    });
+
+    if query.kind.as_deref() == Some("2") {
+        Json(ServiceActionResult {
+            action: action.to_string(),
+            dry_run: false,
+            results: vec![ServiceResult {
+                host: host(&headers),
+                err: String::new(),
+            }],
+        })
+        .into_response()
+    } else {
+        StatusCode::OK.into_response()
+    }
+}
+
+// Replaces the process with a fresh one of the same binary and arguments,
+// returning only if that fails
+#[cfg(unix)]
+fn reexec() -> std::io::Error {
+    use std::os::unix::process::CommandExt;
+
+    info!("🔄 Restarting");
+    match std::env::current_exe() {
+        Ok(exe) => std::process::Command::new(exe)
+            .args(std::env::args_os().skip(1))
+            .exec(),
+        Err(e) => e,
+    }
+}
+
+#[cfg(not(unix))]
+fn reexec() -> std::io::Error {
+    std::io::Error::other("restarting is only supported on Unix")
+}
+
+async fn not_implemented(request: Request) -> Response {
+    let path = request.uri().path().to_string();
+    let message = match path.trim_start_matches(ADMIN_PREFIX) {
+        "add-user" | "remove-user" | "list-users" | "user-info" | "set-user-status" => {
+            "simpleS3 has a single access key, set with --access-key, and no other users"
+        }
+        op if op.contains("policy") || op.contains("policies") => {
+            "simpleS3 has no IAM policies for users, only a bucket policy (PUT /?policy)"
+        }
+        _ => "This admin API is not supported by simpleS3",
+    };
+    admin_error(StatusCode::NOT_IMPLEMENTED, "NotImplemented", message, &path)
+}
@@ -0,0 +1,200 @@
+use axum::{
+    body::Body,
+    extract::{OriginalUri, Request, State},
+    http::StatusCode,
+    middleware::Next,
+    response::{IntoResponse, Response},
+};
+use serde::Serialize;
+use std::{
+    sync::{
+        Arc,
+        atomic::{AtomicU64, Ordering},
+    },
+    time::Duration,
+};
+use tokio::sync::{Semaphore, oneshot};
+use tracing::{info, warn};
+
+use crate::AppState;
+
+// Shadow traffic. With --mirror-to, a copy of --mirror-percent of the S3
+// API requests, bodies included, is sent to another endpoint once it has
+// been received, and whatever that answers is thrown away. Clients only
+// ever see this server's responses. It's for trying a new version or
+// storage backend on production-shaped traffic before cutting over, so
+// requests go out as they came in, signatures, credentials and Host header
+// included: give the shadow the same keys and bucket name.
+//
+// A mirrored request that gets a different status than this server gave is
+// logged, and counted on the status page with those mirrored and those that
+// failed outright. At most MAX_IN_FLIGHT copies are outstanding; requests
+// beyond that, and those whose body is streamed or over MAX_BODY, aren't
+// mirrored but counted as skipped.
+
+const MAX_IN_FLIGHT: usize = 64;
+const MAX_BODY: u64 = 64 * 1024 * 1024;
+const TIMEOUT: Duration = Duration::from_secs(60);
+
+// Not passed on, they describe this connection rather than the request
+const HOP_HEADERS: &[&str] = &["connection", "keep-alive", "transfer-encoding", "te", "upgrade"];
+
+pub struct Mirror {
+    client: reqwest::Client,
+    url: String,
+    percent: f64,
+    in_flight: Arc<Semaphore>,
+    mirrored: AtomicU64,
+    failed: AtomicU64,
+    mismatched: AtomicU64,
+    skipped: AtomicU64,
+    // Picks which requests are mirrored
+    sampled: AtomicU64,
+}
+
+#[derive(Debug, Serialize)]
+pub struct Counts {
+    pub mirrored: u64,
+    // The shadow couldn't be reached or didn't answer in time
+    pub failed: u64,
+    // The shadow answered with another status than this server
+    pub mismatched: u64,
+    pub skipped: u64,
+}
+
+impl Mirror {
+    pub fn new(url: &str, percent: f64) -> Result<Self, String> {
+        if !(0.0..=100.0).contains(&percent) {
+            return Err(format!("--mirror-percent must be between 0 and 100, not {}", percent));
+        }
+        let url = url.trim().trim_end_matches('/');
+        let url = if url.contains("://") {
+            url.to_string()
+        } else {
+            format!("http://{}", url)
+        };
+        reqwest::Url::parse(&url).map_err(|e| format!("invalid --mirror-to {}: {}", url, e))?;
+        let client = reqwest::Client::builder()
+            .timeout(TIMEOUT)
+            .build()
+            .map_err(|e| e.to_string())?;
+
+        info!("🪞 Mirroring {}% of requests to {}", percent, url);
+        Ok(Self {
+            client,
+            url,
+            percent,
+            in_flight: Arc::new(Semaphore::new(MAX_IN_FLIGHT)),
+            mirrored: AtomicU64::new(0),
+            failed: AtomicU64::new(0),
+            mismatched: AtomicU64::new(0),
+            skipped: AtomicU64::new(0),
+            sampled: AtomicU64::new(0),
+        })
+    }
+
+    // Spreads the mirrored requests evenly: the nth is mirrored when that
+    // takes the share so far up to the next whole request
+    fn sampled(&self) -> bool {
+        let n = self.sampled.fetch_add(1, Ordering::Relaxed);
+        let share = |n: u64| (n as f64 * self.percent / 100.0).floor();
+        share(n + 1) > share(n)
+    }
+
+    pub fn counts(&self) -> Counts {
+        Counts {
+            mirrored: self.mirrored.load(Ordering::Relaxed),
+            failed: self.failed.load(Ordering::Relaxed),
+            mismatched: self.mismatched.load(Ordering::Relaxed),
+            skipped: self.skipped.load(Ordering::Relaxed),
+        }
+    }
+}
+
+// Whether the body can be read whole to send it twice
+fn body_fits(request: &Request) -> bool {
+    let headers = request.headers();
+    if headers.contains_key("transfer-encoding") {
+        return false;
+    }
+    headers
+        .get("content-length")
+        .map(|len| len.to_str().ok().and_then(|len| len.parse::<u64>().ok()))
+        .is_none_or(|len| len.is_some_and(|len| len <= MAX_BODY))
+}
+
+pub async fn mirror_middleware(
+    State(state): State<Arc<AppState>>,
+    request: Request,
+    next: Next,
+) -> Response {
+    let Some(mirror) = state.mirror.clone() else {
+        return next.run(request).await;
+    };
+    if !mirror.sampled() {
+        return next.run(request).await;
+    }
+    let permit = match mirror.in_flight.clone().try_acquire_owned() {
+        Ok(permit) if body_fits(&request) => permit,
+        _ => {
+            mirror.skipped.fetch_add(1, Ordering::Relaxed);
+            return next.run(request).await;
+        }
+    };
+
+    let (parts, body) = request.into_parts();
+    let Ok(body) = axum::body::to_bytes(body, MAX_BODY as usize).await else {
+        return StatusCode::BAD_REQUEST.into_response();
+    };
+
+    // As the client sent it, before path-style requests were rewritten
+    let uri = match parts.extensions.get::<OriginalUri>() {
+        Some(OriginalUri(uri)) => uri,
+        None => &parts.uri,
+    };
+    let path = uri.path_and_query().map_or("/", |path| path.as_str());
+    let mut copy = mirror
+        .client
+        .request(parts.method.clone(), format!("{}{}", mirror.url, path))
+        .body(body.clone());
+    for (name, value) in &parts.headers {
+        if !HOP_HEADERS.contains(&name.as_str()) {
+            copy = copy.header(name, value);
+        }
+    }
+
+    let (status_tx, status_rx) = oneshot::channel::<StatusCode>();
+    let description = format!("{} {}", parts.method, uri.path());
+    tokio::spawn(async move {
+        let _permit = permit;
+        let response = copy.send().await;
+        mirror.mirrored.fetch_add(1, Ordering::Relaxed);
+        let response = match response {
+            Ok(response) => response,
+            Err(e) => {
+                mirror.failed.fetch_add(1, Ordering::Relaxed);
+                warn!("🪞 Failed to mirror {}: {}", description, e);
+                return;
+            }
+        };
+        let shadow = response.status();
+        // Read to the end, so the connection can be reused
+        let _ = response.bytes().await;
+
+        if let Ok(status) = status_rx.await
+            && status.as_u16() != shadow.as_u16()
+        {
+            mirror.mismatched.fetch_add(1, Ordering::Relaxed);
+            info!(
+                "🪞 Mirror answered {} with {}, this server with {}",
+                description,
+                shadow.as_u16(),
+                status.as_u16()
+            );
+        }
+    });
+
+    let response = next.run(Request::from_parts(parts, Body::from(body))).await;
+    let _ = status_tx.send(response.status());
+    response
+}
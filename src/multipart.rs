@@ -0,0 +1,884 @@
+use axum::{
+    body::{Body, Bytes},
+    http::{HeaderMap, HeaderValue, Method, StatusCode},
+    response::{IntoResponse, Response},
+};
+use futures_util::StreamExt;
+use serde::{Deserialize, Serialize};
+use sha2::{Digest, Sha256};
+use std::{
+    io::{self, Read, Seek, SeekFrom, Write},
+    path::{Path, PathBuf},
+};
+use tokio::{fs, io::AsyncWriteExt};
+use tracing::{info, warn};
+
+use crate::{
+    AppState, CommonPrefix, ErrorResponse, access, acl, conditional, parse_copy_source,
+    error_response, etag,
+    integrity::{self, ContentMd5},
+    normalize, object_path, rename_into_place, retention, scan, tier, timing, tmp_path,
+    versioning, worm,
+};
+
+// Multipart uploads, as the AWS SDKs, the CLI and tools like the Docker
+// registry use them for large objects. An upload lives in its own directory
+// under MULTIPART_DIR, named by its id, holding UPLOAD_FILE and one file per
+// part plus its ETag. Completing it concatenates the parts into the object
+// and removes the directory; gc removes uploads nobody completed or aborted
+// after a week. Parts are written to disk as they arrive rather than held in
+// memory, so objects of any size can be uploaded a part at a time. Parts
+// aren't held to S3's 5MB minimum, but are to its MAX_PART_SIZE maximum.
+// The object's ETag is the hash of its contents, the same GET answers with,
+// rather than S3's hash of the part hashes.
+
+pub const MULTIPART_DIR: &str = ".simple-s3/multipart";
+const UPLOAD_FILE: &str = "upload.json";
+const MAX_PART_NUMBER: u32 = 10_000;
+const MAX_PART_SIZE: u64 = 5 * 1024 * 1024 * 1024;
+const XMLNS: &str = "http://s3.amazonaws.com/doc/2006-03-01/";
+
+#[derive(Serialize, Deserialize)]
+struct Upload {
+    key: String,
+    initiated: chrono::DateTime<chrono::Utc>,
+    // The object lock and ACL asked for when the upload started
+    #[serde(default)]
+    lock: Option<retention::Lock>,
+    #[serde(default)]
+    acl: Option<acl::Grants>,
+}
+
+#[derive(Debug, Serialize)]
+#[serde(rename = "InitiateMultipartUploadResult")]
+struct InitiateMultipartUploadResult {
+    #[serde(rename = "@xmlns")]
+    xmlns: String,
+    #[serde(rename = "Bucket")]
+    bucket: String,
+    #[serde(rename = "Key")]
+    key: String,
+    #[serde(rename = "UploadId")]
+    upload_id: String,
+}
+
+#[derive(Debug, Serialize)]
+#[serde(rename = "CopyPartResult")]
+struct CopyPartResult {
+    #[serde(rename = "LastModified")]
+    last_modified: String,
+    #[serde(rename = "ETag")]
+    etag: String,
+}
+
+#[derive(Debug, Serialize)]
+struct PartInfo {
+    #[serde(rename = "PartNumber")]
+    part_number: u32,
+    #[serde(rename = "LastModified")]
+    last_modified: String,
+    #[serde(rename = "ETag")]
+    etag: String,
+    #[serde(rename = "Size")]
+    size: u64,
+}
+
+#[derive(Debug, Serialize)]
+#[serde(rename = "ListPartsResult")]
+struct ListPartsResult {
+    #[serde(rename = "@xmlns")]
+    xmlns: String,
+    #[serde(rename = "Bucket")]
+    bucket: String,
+    #[serde(rename = "Key")]
+    key: String,
+    #[serde(rename = "UploadId")]
+    upload_id: String,
+    #[serde(rename = "StorageClass")]
+    storage_class: String,
+    #[serde(rename = "PartNumberMarker")]
+    part_number_marker: u32,
+    #[serde(rename = "NextPartNumberMarker")]
+    next_part_number_marker: u32,
+    #[serde(rename = "MaxParts")]
+    max_parts: usize,
+    #[serde(rename = "IsTruncated")]
+    is_truncated: bool,
+    #[serde(rename = "Part")]
+    parts: Vec<PartInfo>,
+}
+
+#[derive(Debug, Deserialize)]
+struct CompleteMultipartUpload {
+    #[serde(rename = "Part", default)]
+    parts: Vec<CompletedPart>,
+}
+
+#[derive(Debug, Deserialize)]
+struct CompletedPart {
+    #[serde(rename = "PartNumber")]
+    part_number: u32,
+    #[serde(rename = "ETag")]
+    etag: String,
+}
+
+#[derive(Debug, Serialize)]
+#[serde(rename = "CompleteMultipartUploadResult")]
+struct CompleteMultipartUploadResult {
+    #[serde(rename = "@xmlns")]
+    xmlns: String,
+    #[serde(rename = "Location")]
+    location: String,
+    #[serde(rename = "Bucket")]
+    bucket: String,
+    #[serde(rename = "Key")]
+    key: String,
+    #[serde(rename = "ETag")]
+    etag: String,
+}
+
+#[derive(Debug, Serialize)]
+struct UploadInfo {
+    #[serde(rename = "Key")]
+    key: String,
+    #[serde(rename = "UploadId")]
+    upload_id: String,
+    #[serde(rename = "Initiated")]
+    initiated: String,
+    #[serde(rename = "StorageClass")]
+    storage_class: String,
+}
+
+#[derive(Debug, Serialize)]
+#[serde(rename = "ListMultipartUploadsResult")]
+struct ListMultipartUploadsResult {
+    #[serde(rename = "@xmlns")]
+    xmlns: String,
+    #[serde(rename = "Bucket")]
+    bucket: String,
+    #[serde(rename = "KeyMarker")]
+    key_marker: String,
+    #[serde(rename = "UploadIdMarker")]
+    upload_id_marker: String,
+    #[serde(rename = "NextKeyMarker", skip_serializing_if = "Option::is_none")]
+    next_key_marker: Option<String>,
+    #[serde(rename = "NextUploadIdMarker", skip_serializing_if = "Option::is_none")]
+    next_upload_id_marker: Option<String>,
+    #[serde(rename = "Delimiter", skip_serializing_if = "Option::is_none")]
+    delimiter: Option<String>,
+    #[serde(rename = "Prefix")]
+    prefix: String,
+    #[serde(rename = "MaxUploads")]
+    max_uploads: usize,
+    #[serde(rename = "IsTruncated")]
+    is_truncated: bool,
+    #[serde(rename = "Upload")]
+    uploads: Vec<UploadInfo>,
+    #[serde(rename = "CommonPrefixes")]
+    common_prefixes: Vec<CommonPrefix>,
+}
+
+// An entry of an uploads listing: an upload, or the common prefix standing
+// for those under it when listing with a delimiter
+enum Listed {
+    Upload(Upload, String),
+    Prefix(String),
+}
+
+// Steps of an upload that don't change the object itself: creating it,
+// uploading parts and aborting it. Completing it is a write like a PUT
+pub fn is_upload_step(method: &Method, query: Option<&str>) -> bool {
+    let has = |name: &str| {
+        query.is_some_and(|query| {
+            query
+                .split('&')
+                .any(|pair| pair.split('=').next() == Some(name))
+        })
+    };
+    has("uploads") || (has("uploadId") && *method != Method::POST)
+}
+
+fn timestamp(time: chrono::DateTime<chrono::Utc>) -> String {
+    time.format("%Y-%m-%dT%H:%M:%S%.3fZ").to_string()
+}
+
+fn xml_response<T: Serialize>(result: &T) -> Result<Response, StatusCode> {
+    let xml = serde_xml_rs::to_string(result).map_err(|_| StatusCode::INTERNAL_SERVER_ERROR)?;
+    let mut headers = HeaderMap::new();
+    headers.insert("content-type", HeaderValue::from_static("application/xml"));
+    Ok((headers, xml).into_response())
+}
+
+fn refusal(status: StatusCode, code: &str, message: &str) -> Response {
+    error_response(
+        status,
+        ErrorResponse {
+            code: code.to_string(),
+            message: message.to_string(),
+            max_size_allowed: None,
+        },
+    )
+}
+
+fn part_too_large() -> Response {
+    error_response(
+        StatusCode::BAD_REQUEST,
+        ErrorResponse {
+            code: "EntityTooLarge".to_string(),
+            message: "Your proposed upload exceeds the maximum allowed size".to_string(),
+            max_size_allowed: Some(MAX_PART_SIZE),
+        },
+    )
+}
+
+fn no_such_upload() -> Response {
+    refusal(
+        StatusCode::NOT_FOUND,
+        "NoSuchUpload",
+        "The specified upload does not exist. The upload ID may be invalid, or the upload may have been aborted or completed.",
+    )
+}
+
+fn invalid_part(message: &str) -> Response {
+    refusal(StatusCode::BAD_REQUEST, "InvalidPart", message)
+}
+
+fn part_path(dir: &Path, part_number: u32) -> PathBuf {
+    dir.join(format!("{:05}.part", part_number))
+}
+
+fn etag_path(dir: &Path, part_number: u32) -> PathBuf {
+    dir.join(format!("{:05}.etag", part_number))
+}
+
+// The directory of upload `upload_id` of `key`, None if there's no such
+// upload. Ids are UUIDs, anything else can't name one
+async fn upload_dir(state: &AppState, key: &str, upload_id: &str) -> Option<PathBuf> {
+    let id = uuid::Uuid::parse_str(upload_id).ok()?;
+    let dir = state.data_dir.join(MULTIPART_DIR).join(id.to_string());
+    let upload: Upload = serde_json::from_slice(&fs::read(dir.join(UPLOAD_FILE)).await.ok()?).ok()?;
+    (upload.key == normalize::stored_key(state, key)).then_some(dir)
+}
+
+// POST /{key}?uploads
+pub async fn create(
+    state: &AppState,
+    key: &str,
+    headers: &HeaderMap,
+) -> Result<Response, StatusCode> {
+    let file_path = object_path(state, key)?;
+    if let Some(refusal) = worm::refuse_with(state, key, &file_path, headers).await {
+        return Ok(refusal);
+    }
+    if let Some(refusal) = retention::refuse_invalid(headers) {
+        return Ok(refusal);
+    }
+    if let Some(refusal) = access::refuse_acl(state, key, headers)
+        .or_else(|| acl::refuse_invalid(state, headers))
+    {
+        return Ok(refusal);
+    }
+
+    let upload_id = uuid::Uuid::new_v4().to_string();
+    let dir = state.data_dir.join(MULTIPART_DIR).join(&upload_id);
+    let upload = Upload {
+        key: normalize::stored_key(state, key).into_owned(),
+        initiated: chrono::Utc::now(),
+        lock: retention::from_headers(headers),
+        acl: acl::from_headers(state, headers),
+    };
+    let created = async {
+        fs::create_dir_all(&dir).await?;
+        fs::write(dir.join(UPLOAD_FILE), serde_json::to_vec(&upload)?).await
+    }
+    .await;
+    if let Err(e) = created {
+        warn!("Failed to create a multipart upload for {}: {}", key, e);
+        let _ = fs::remove_dir_all(&dir).await;
+        return Err(StatusCode::INTERNAL_SERVER_ERROR);
+    }
+
+    info!("🧩 Started multipart upload {} of {}", upload_id, key);
+    xml_response(&InitiateMultipartUploadResult {
+        xmlns: XMLNS.to_string(),
+        bucket: state.bucket_name.clone(),
+        key: key.to_string(),
+        upload_id,
+    })
+}
+
+// Bytes `first` to `last` of a copy source range header, inclusive
+fn copy_range(headers: &HeaderMap) -> Result<Option<(u64, u64)>, StatusCode> {
+    let Some(range) = headers.get("x-amz-copy-source-range") else {
+        return Ok(None);
+    };
+    let (first, last) = range
+        .to_str()
+        .ok()
+        .and_then(|range| range.trim().strip_prefix("bytes="))
+        .and_then(|range| range.split_once('-'))
+        .ok_or(StatusCode::BAD_REQUEST)?;
+    let first: u64 = first.parse().map_err(|_| StatusCode::BAD_REQUEST)?;
+    let last: u64 = last.parse().map_err(|_| StatusCode::BAD_REQUEST)?;
+    if last < first {
+        return Err(StatusCode::BAD_REQUEST);
+    }
+    Ok(Some((first, last)))
+}
+
+// Copies what an UploadPartCopy takes from its source into `dst`, a chunk
+// at a time so a part of several GB isn't held in memory. Its ETag
+async fn copy_part(
+    state: &AppState,
+    headers: &HeaderMap,
+    copy_source: &str,
+    dst: &Path,
+) -> Result<String, Response> {
+    let source = parse_copy_source(state, copy_source).map_err(IntoResponse::into_response)?;
+    let source_path = object_path(state, &source.key).map_err(IntoResponse::into_response)?;
+    let range = copy_range(headers).map_err(IntoResponse::into_response)?;
+    let version_path = match &source.version_id {
+        Some(version_id) => {
+            versioning::resolve_source(state, &source.key, &source_path, version_id).await?
+        }
+        None => None,
+    };
+    if version_path.is_none() {
+        tier::recall(state, &source.key, &source_path)
+            .await
+            .map_err(|_| StatusCode::INTERNAL_SERVER_ERROR.into_response())?;
+    }
+    let source_path = version_path.unwrap_or(source_path);
+
+    let (read_buffer, write_buffer) = (state.read_buffer, state.write_buffer);
+    let dst = dst.to_path_buf();
+    let copied = tokio::task::spawn_blocking(move || -> io::Result<String> {
+        let mut file = std::fs::File::open(&source_path)?;
+        let len = file.metadata()?.len();
+        let (first, count) = match range {
+            Some((_, last)) if last >= len => return Err(io::ErrorKind::UnexpectedEof.into()),
+            Some((first, last)) => (first, last - first + 1),
+            None => (0, len),
+        };
+        if count > MAX_PART_SIZE {
+            return Err(io::ErrorKind::FileTooLarge.into());
+        }
+        file.seek(SeekFrom::Start(first))?;
+
+        let mut out = io::BufWriter::with_capacity(write_buffer, std::fs::File::create(&dst)?);
+        let mut hasher = Sha256::new();
+        let mut buf = vec![0; read_buffer];
+        let mut left = count;
+        while left > 0 {
+            let want = left.min(buf.len() as u64) as usize;
+            let n = file.read(&mut buf[..want])?;
+            if n == 0 {
+                return Err(io::ErrorKind::UnexpectedEof.into());
+            }
+            hasher.update(&buf[..n]);
+            out.write_all(&buf[..n])?;
+            left -= n as u64;
+        }
+        out.flush()?;
+        Ok(format!("\"{}\"", hex::encode(hasher.finalize())))
+    })
+    .await
+    .map_err(|_| StatusCode::INTERNAL_SERVER_ERROR.into_response())?;
+
+    match copied {
+        Ok(etag) => Ok(etag),
+        Err(e) if e.kind() == io::ErrorKind::NotFound => Err(refusal(
+            StatusCode::NOT_FOUND,
+            "NoSuchKey",
+            "The specified key does not exist.",
+        )),
+        Err(e) if e.kind() == io::ErrorKind::UnexpectedEof => Err(refusal(
+            StatusCode::BAD_REQUEST,
+            "InvalidRange",
+            "The x-amz-copy-source-range is beyond the end of the source object",
+        )),
+        Err(e) if e.kind() == io::ErrorKind::FileTooLarge => Err(refusal(
+            StatusCode::BAD_REQUEST,
+            "InvalidRequest",
+            "The specified copy source is larger than the maximum allowable size for a copy source: 5368709120",
+        )),
+        Err(e) => {
+            warn!("Failed to copy {} for a part copy: {}", source.key, e);
+            Err(StatusCode::INTERNAL_SERVER_ERROR.into_response())
+        }
+    }
+}
+
+// PUT /{key}?partNumber=N&uploadId=ID, with the part as the body or copied
+// from x-amz-copy-source
+pub async fn upload_part(
+    state: &AppState,
+    key: &str,
+    upload_id: &str,
+    part_number: u32,
+    headers: &HeaderMap,
+    body: Body,
+) -> Result<Response, StatusCode> {
+    if !(1..=MAX_PART_NUMBER).contains(&part_number) {
+        return Ok(refusal(
+            StatusCode::BAD_REQUEST,
+            "InvalidArgument",
+            "Part number must be an integer between 1 and 10000, inclusive",
+        ));
+    }
+    let Some(dir) = upload_dir(state, key, upload_id).await else {
+        return Ok(no_such_upload());
+    };
+
+    let copy_source = headers
+        .get("x-amz-copy-source")
+        .map(|source| source.to_str().map_err(|_| StatusCode::BAD_REQUEST))
+        .transpose()?;
+    let declared = headers
+        .get("content-length")
+        .and_then(|len| len.to_str().ok())
+        .and_then(|len| len.parse::<u64>().ok());
+    if copy_source.is_none() && declared.is_some_and(|len| len > MAX_PART_SIZE) {
+        return Ok(part_too_large());
+    }
+
+    let tmp_path = tmp_path(state);
+    let received = match copy_source {
+        Some(copy_source) => match copy_part(state, headers, copy_source, &tmp_path).await {
+            Ok(etag) => Ok(Some(etag)),
+            Err(refusal) => {
+                let _ = fs::remove_file(&tmp_path).await;
+                return Ok(refusal);
+            }
+        },
+        None => {
+            if let Some(refusal) = integrity::refuse_invalid(headers) {
+                return Ok(refusal);
+            }
+            let mut md5 = ContentMd5::from_headers(headers);
+            let received =
+                timing::timed("body", receive_part(state, body, &tmp_path, md5.as_mut())).await;
+            if matches!(received, Ok(Some(_)))
+                && let Some(refusal) = md5.and_then(ContentMd5::finish)
+            {
+                let _ = fs::remove_file(&tmp_path).await;
+                return Ok(refusal);
+            }
+            received
+        }
+    };
+    let stored = match received {
+        Ok(Some(etag)) => async {
+            fs::write(etag_path(&dir, part_number), &etag).await?;
+            fs::rename(&tmp_path, part_path(&dir, part_number)).await?;
+            Ok(etag)
+        }
+        .await,
+        Ok(None) => {
+            let _ = fs::remove_file(&tmp_path).await;
+            return Ok(part_too_large());
+        }
+        Err(e) => Err(e),
+    };
+    let etag = match stored {
+        Ok(etag) => etag,
+        Err(e) => {
+            warn!("Failed to store part {} of {}: {}", part_number, key, e);
+            let _ = fs::remove_file(&tmp_path).await;
+            return Err(StatusCode::INTERNAL_SERVER_ERROR);
+        }
+    };
+
+    if copy_source.is_some() {
+        return xml_response(&CopyPartResult {
+            last_modified: timestamp(chrono::Utc::now()),
+            etag,
+        });
+    }
+    let mut headers = HeaderMap::new();
+    headers.insert("etag", HeaderValue::from_str(&etag).unwrap());
+    Ok((StatusCode::OK, headers).into_response())
+}
+
+// Writes `body` to `path` as it arrives, hashing it on the way, for `md5`
+// too if the client sent one. Its ETag, None once it's over MAX_PART_SIZE
+async fn receive_part(
+    state: &AppState,
+    body: Body,
+    path: &Path,
+    mut md5: Option<&mut ContentMd5>,
+) -> io::Result<Option<String>> {
+    let mut file = fs::File::create(path).await?;
+    file.set_max_buf_size(state.write_buffer);
+    let mut hasher = Sha256::new();
+    let mut len = 0;
+    let mut stream = body.into_data_stream();
+    while let Some(chunk) = stream.next().await {
+        let chunk = chunk.map_err(io::Error::other)?;
+        len += chunk.len() as u64;
+        if len > MAX_PART_SIZE {
+            return Ok(None);
+        }
+        hasher.update(&chunk);
+        if let Some(md5) = md5.as_deref_mut() {
+            md5.update(&chunk);
+        }
+        file.write_all(&chunk).await?;
+    }
+    file.flush().await?;
+    Ok(Some(format!("\"{}\"", hex::encode(hasher.finalize()))))
+}
+
+async fn read_parts(dir: &Path) -> io::Result<Vec<PartInfo>> {
+    let mut parts = Vec::new();
+    let mut entries = fs::read_dir(dir).await?;
+    while let Some(entry) = entries.next_entry().await? {
+        let name = entry.file_name();
+        let Some(part_number) = name
+            .to_str()
+            .and_then(|name| name.strip_suffix(".part"))
+            .and_then(|number| number.parse().ok())
+        else {
+            continue;
+        };
+        let metadata = entry.metadata().await?;
+        let modified = metadata.modified().unwrap_or(std::time::SystemTime::now());
+        parts.push(PartInfo {
+            part_number,
+            last_modified: timestamp(modified.into()),
+            etag: fs::read_to_string(etag_path(dir, part_number)).await?,
+            size: metadata.len(),
+        });
+    }
+    parts.sort_by_key(|part| part.part_number);
+    Ok(parts)
+}
+
+// GET /{key}?uploadId=ID
+pub async fn list_parts(
+    state: &AppState,
+    key: &str,
+    upload_id: &str,
+    part_number_marker: Option<u32>,
+    max_parts: Option<usize>,
+) -> Result<Response, StatusCode> {
+    let Some(dir) = upload_dir(state, key, upload_id).await else {
+        return Ok(no_such_upload());
+    };
+    let part_number_marker = part_number_marker.unwrap_or(0);
+    let max_parts = max_parts.unwrap_or(1000).min(1000);
+
+    let mut parts = read_parts(&dir)
+        .await
+        .map_err(|_| StatusCode::INTERNAL_SERVER_ERROR)?;
+    parts.retain(|part| part.part_number > part_number_marker);
+    let is_truncated = parts.len() > max_parts;
+    parts.truncate(max_parts);
+
+    xml_response(&ListPartsResult {
+        xmlns: XMLNS.to_string(),
+        bucket: state.bucket_name.clone(),
+        key: key.to_string(),
+        upload_id: upload_id.to_string(),
+        storage_class: "STANDARD".to_string(),
+        part_number_marker,
+        next_part_number_marker: parts.last().map_or(0, |part| part.part_number),
+        max_parts,
+        is_truncated,
+        parts,
+    })
+}
+
+// Concatenates `parts` into `dst`, returning the hash of the whole. Blocks
+fn concatenate(
+    parts: &[PathBuf],
+    dst: &Path,
+    read_buffer: usize,
+    write_buffer: usize,
+) -> io::Result<String> {
+    let mut out = io::BufWriter::with_capacity(write_buffer, std::fs::File::create(dst)?);
+    let mut hasher = Sha256::new();
+    let mut buf = vec![0; read_buffer];
+    for part in parts {
+        let mut part = std::fs::File::open(part)?;
+        loop {
+            let n = part.read(&mut buf)?;
+            if n == 0 {
+                break;
+            }
+            hasher.update(&buf[..n]);
+            out.write_all(&buf[..n])?;
+        }
+    }
+    out.flush()?;
+    Ok(format!("\"{}\"", hex::encode(hasher.finalize())))
+}
+
+// POST /{key}?uploadId=ID
+pub async fn complete(
+    state: &AppState,
+    key: &str,
+    upload_id: &str,
+    headers: &HeaderMap,
+    body: Bytes,
+) -> Result<Response, StatusCode> {
+    let Some(dir) = upload_dir(state, key, upload_id).await else {
+        return Ok(no_such_upload());
+    };
+    if let Some(refusal) = conditional::refuse_unsupported(headers) {
+        return Ok(refusal);
+    }
+    let Ok(request) = serde_xml_rs::from_reader::<CompleteMultipartUpload, _>(body.as_ref()) else {
+        return Ok(refusal(
+            StatusCode::BAD_REQUEST,
+            "MalformedXML",
+            "The XML you provided was not well-formed or did not validate against our published schema",
+        ));
+    };
+    if request.parts.is_empty() {
+        return Ok(invalid_part("You must specify at least one part"));
+    }
+    if request
+        .parts
+        .windows(2)
+        .any(|pair| pair[0].part_number >= pair[1].part_number)
+    {
+        return Ok(refusal(
+            StatusCode::BAD_REQUEST,
+            "InvalidPartOrder",
+            "The list of parts was not in ascending order. Parts must be ordered by part number.",
+        ));
+    }
+
+    let mut parts = Vec::new();
+    for part in &request.parts {
+        let etag = fs::read_to_string(etag_path(&dir, part.part_number)).await;
+        if etag.is_err() || etag.is_ok_and(|etag| etag.trim_matches('"') != part.etag.trim_matches('"')) {
+            return Ok(invalid_part(
+                "One or more of the specified parts could not be found. The part may not have been uploaded, or the specified entity tag may not match the part's entity tag.",
+            ));
+        }
+        parts.push(part_path(&dir, part.part_number));
+    }
+
+    let file_path = object_path(state, key)?;
+    if let Some(refusal) = worm::refuse_with(state, key, &file_path, headers).await {
+        return Ok(refusal);
+    }
+
+    let tmp_path = tmp_path(state);
+    let dst = tmp_path.clone();
+    let (read_buffer, write_buffer) = (state.read_buffer, state.write_buffer);
+    let etag = tokio::task::spawn_blocking(move || {
+        concatenate(&parts, &dst, read_buffer, write_buffer)
+    })
+    .await
+    .map_err(|_| StatusCode::INTERNAL_SERVER_ERROR)?;
+    let etag = match etag {
+        Ok(etag) => etag,
+        Err(e) => {
+            warn!("Failed to complete multipart upload {} of {}: {}", upload_id, key, e);
+            let _ = fs::remove_file(&tmp_path).await;
+            return Err(StatusCode::INTERNAL_SERVER_ERROR);
+        }
+    };
+    if let Err(refusal) = scan::check_file(state, "s3", key, &tmp_path).await {
+        let _ = fs::remove_file(&tmp_path).await;
+        return Ok(refusal.response());
+    }
+
+    // A refused condition leaves the upload in place, to complete again
+    // or abort
+    let versions = versioning::prepare(state, key, &file_path).await?;
+    let stored = match conditional::condition(headers) {
+        Some(condition) => conditional::place(state, key, &tmp_path, &file_path, &condition).await,
+        None => rename_into_place(&tmp_path, &file_path).await.map(|()| None),
+    };
+    match stored {
+        Ok(None) => {}
+        Ok(Some(refusal)) => {
+            versioning::abort(versions).await;
+            return Ok(refusal);
+        }
+        Err(e) => {
+            warn!("Failed to store object {}: {}", key, e);
+            let _ = fs::remove_file(&tmp_path).await;
+            versioning::abort(versions).await;
+            return Err(StatusCode::INTERNAL_SERVER_ERROR);
+        }
+    }
+    let mut headers = HeaderMap::new();
+    versioning::commit(state, versions, &file_path, &mut headers).await;
+    etag::store(state, key, &file_path, &etag).await;
+    let upload = fs::read(dir.join(UPLOAD_FILE))
+        .await
+        .ok()
+        .and_then(|upload| serde_json::from_slice::<Upload>(&upload).ok());
+    let (lock, grants) = upload.map_or((None, None), |upload| (upload.lock, upload.acl));
+    retention::store_or_warn(state, key, lock).await;
+    if let Some(grants) = grants {
+        acl::store_or_warn(state, key, &file_path, grants).await;
+    }
+    let _ = fs::remove_dir_all(&dir).await;
+
+    info!(
+        "📁 Stored object from multipart upload: {} ({} parts)",
+        key,
+        request.parts.len()
+    );
+    let mut response = xml_response(&CompleteMultipartUploadResult {
+        xmlns: XMLNS.to_string(),
+        location: format!("/{}", key),
+        bucket: state.bucket_name.clone(),
+        key: key.to_string(),
+        etag,
+    })?;
+    response.headers_mut().extend(headers);
+    Ok(response)
+}
+
+// DELETE /{key}?uploadId=ID
+pub async fn abort(state: &AppState, key: &str, upload_id: &str) -> Result<Response, StatusCode> {
+    let Some(dir) = upload_dir(state, key, upload_id).await else {
+        return Ok(no_such_upload());
+    };
+    if let Err(e) = fs::remove_dir_all(&dir).await {
+        warn!("Failed to abort multipart upload {} of {}: {}", upload_id, key, e);
+        return Err(StatusCode::INTERNAL_SERVER_ERROR);
+    }
+    info!("🗑️ Aborted multipart upload {} of {}", upload_id, key);
+    Ok(StatusCode::NO_CONTENT.into_response())
+}
+
+// Aborts the uploads `stale` picks by their key and when they started,
+// returning how many
+pub async fn abort_stale(
+    state: &AppState,
+    stale: impl Fn(&str, chrono::DateTime<chrono::Utc>) -> bool,
+) -> usize {
+    let Ok(mut entries) = fs::read_dir(state.data_dir.join(MULTIPART_DIR)).await else {
+        return 0;
+    };
+    let mut aborted = 0;
+    while let Ok(Some(entry)) = entries.next_entry().await {
+        let dir = entry.path();
+        let Ok(upload) = fs::read(dir.join(UPLOAD_FILE)).await else {
+            continue;
+        };
+        let Ok(upload) = serde_json::from_slice::<Upload>(&upload) else {
+            continue;
+        };
+        if !stale(&upload.key, upload.initiated) {
+            continue;
+        }
+        match fs::remove_dir_all(&dir).await {
+            Ok(()) => aborted += 1,
+            Err(e) => warn!("Failed to abort multipart upload of {}: {}", upload.key, e),
+        }
+    }
+    aborted
+}
+
+// GET /?uploads, the uploads in progress under `prefix`, by key and then by
+// when they were started. A page picks up after `key_marker`, or after
+// `upload_id_marker` among the uploads of that key when it's given
+pub async fn list_uploads(
+    state: &AppState,
+    prefix: &str,
+    delimiter: Option<&str>,
+    key_marker: &str,
+    upload_id_marker: &str,
+    max_uploads: Option<usize>,
+) -> Result<Response, StatusCode> {
+    let max_uploads = max_uploads.unwrap_or(1000).min(1000);
+    let mut uploads = Vec::new();
+
+    if let Ok(mut entries) = fs::read_dir(state.data_dir.join(MULTIPART_DIR)).await {
+        while let Ok(Some(entry)) = entries.next_entry().await {
+            let Ok(data) = fs::read(entry.path().join(UPLOAD_FILE)).await else {
+                continue;
+            };
+            let Ok(upload) = serde_json::from_slice::<Upload>(&data) else {
+                continue;
+            };
+            if upload.key.starts_with(prefix) {
+                uploads.push((upload, entry.file_name().to_string_lossy().into_owned()));
+            }
+        }
+    }
+    uploads.sort_by(|(a, a_id), (b, b_id)| {
+        a.key
+            .cmp(&b.key)
+            .then(a.initiated.cmp(&b.initiated))
+            .then(a_id.cmp(b_id))
+    });
+
+    // Past the marker: later keys, and with an upload id marker the uploads
+    // of the marker's key after that one
+    let mut past_upload_id = upload_id_marker.is_empty();
+    uploads.retain(|(upload, upload_id)| {
+        if upload.key != key_marker {
+            return upload.key.as_str() > key_marker;
+        }
+        if past_upload_id {
+            return !upload_id_marker.is_empty();
+        }
+        past_upload_id = upload_id == upload_id_marker;
+        false
+    });
+
+    let delimiter = delimiter.filter(|delimiter| !delimiter.is_empty());
+    let mut listed: Vec<Listed> = Vec::new();
+    for (upload, upload_id) in uploads {
+        let common = delimiter.and_then(|delimiter| {
+            let rest = &upload.key[prefix.len()..];
+            rest.find(delimiter)
+                .map(|at| upload.key[..prefix.len() + at + delimiter.len()].to_string())
+        });
+        match common {
+            Some(common) if common.as_str() <= key_marker => {}
+            Some(common) => {
+                if !matches!(listed.last(), Some(Listed::Prefix(last)) if *last == common) {
+                    listed.push(Listed::Prefix(common));
+                }
+            }
+            None => listed.push(Listed::Upload(upload, upload_id)),
+        }
+    }
+    let is_truncated = listed.len() > max_uploads;
+    listed.truncate(max_uploads);
+
+    let (next_key_marker, next_upload_id_marker) = match listed.last() {
+        Some(Listed::Upload(upload, upload_id)) if is_truncated => {
+            (Some(upload.key.clone()), Some(upload_id.clone()))
+        }
+        Some(Listed::Prefix(common)) if is_truncated => (Some(common.clone()), None),
+        _ => (None, None),
+    };
+    let mut result = ListMultipartUploadsResult {
+        xmlns: XMLNS.to_string(),
+        bucket: state.bucket_name.clone(),
+        key_marker: key_marker.to_string(),
+        upload_id_marker: upload_id_marker.to_string(),
+        next_key_marker,
+        next_upload_id_marker,
+        delimiter: delimiter.map(str::to_string),
+        prefix: prefix.to_string(),
+        max_uploads,
+        is_truncated,
+        uploads: Vec::new(),
+        common_prefixes: Vec::new(),
+    };
+    for entry in listed {
+        match entry {
+            Listed::Upload(upload, upload_id) => result.uploads.push(UploadInfo {
+                key: upload.key,
+                upload_id,
+                initiated: timestamp(upload.initiated),
+                storage_class: "STANDARD".to_string(),
+            }),
+            Listed::Prefix(prefix) => result.common_prefixes.push(CommonPrefix { prefix }),
+        }
+    }
+    xml_response(&result)
+}
@@ -0,0 +1,78 @@
+use std::{borrow::Cow, error::Error, fs, path::Path};
+use tracing::{info, warn};
+use unicode_normalization::{UnicodeNormalization, is_nfc};
+
+use crate::{AppState, INTERNAL_DIR};
+
+// Unicode normalization of keys. macOS tends to send names in NFD and most
+// other systems in NFC, so the same visible name can become two objects.
+// With --normalize-keys every key is converted to NFC before it touches the
+// disk; `simpleS3 normalize-keys` converts what is already stored.
+
+// The key as stored on disk
+pub fn stored_key<'a>(state: &AppState, key: &'a str) -> Cow<'a, str> {
+    if state.normalize_keys && !is_nfc(key) {
+        Cow::Owned(key.nfc().collect())
+    } else {
+        Cow::Borrowed(key)
+    }
+}
+
+// `simpleS3 normalize-keys`: rename stored files and directories to NFC.
+// Names whose NFC form is already taken are reported and left alone
+pub fn normalize_keys(data_dir: &Path) -> Result<(), Box<dyn Error>> {
+    info!("🔤 Normalizing keys in {} to NFC", data_dir.display());
+
+    let mut renamed = 0;
+    let mut conflicts = 0;
+    normalize_dir(data_dir, data_dir, &mut renamed, &mut conflicts)?;
+
+    info!("✅ Renamed {} names, {} conflicts", renamed, conflicts);
+    if conflicts > 0 {
+        return Err(format!("{} names could not be normalized, see the log", conflicts).into());
+    }
+    Ok(())
+}
+
+fn normalize_dir(
+    data_dir: &Path,
+    dir: &Path,
+    renamed: &mut usize,
+    conflicts: &mut usize,
+) -> Result<(), Box<dyn Error>> {
+    for entry in fs::read_dir(dir)? {
+        let entry = entry?;
+        if dir == data_dir && entry.file_name() == INTERNAL_DIR {
+            continue;
+        }
+
+        let mut path = entry.path();
+        let Some(name) = entry.file_name().to_str().map(str::to_string) else {
+            warn!("Skipping non UTF-8 name {}", path.display());
+            continue;
+        };
+
+        if !is_nfc(&name) {
+            let target = dir.join(name.nfc().collect::<String>());
+            if target.exists() {
+                warn!(
+                    "🔤 {} conflicts with existing {}, skipping",
+                    path.display(),
+                    target.display()
+                );
+                *conflicts += 1;
+            } else {
+                fs::rename(&path, &target)?;
+                info!("🔤 {} -> {}", path.display(), target.display());
+                *renamed += 1;
+                path = target;
+            }
+        }
+
+        if entry.file_type()?.is_dir() {
+            normalize_dir(data_dir, &path, renamed, conflicts)?;
+        }
+    }
+
+    Ok(())
+}
@@ -0,0 +1,63 @@
+use axum::{
+    extract::{OriginalUri, Request, State},
+    http::Uri,
+    middleware::Next,
+    response::Response,
+};
+use std::sync::Arc;
+
+use crate::AppState;
+
+// Path-style requests. The server is addressed like a virtual-hosted bucket,
+// with keys right after the host, but some clients always put the bucket in
+// the path: http://host/BUCKET/KEY. With --path-style, a leading /BUCKET
+// matching --bucket is dropped before routing, so those work too; keys of
+// their own that start with the bucket name then need the prefix twice.
+// SigV4 covers the path the client sent, which is kept as the OriginalUri
+// for checking signatures.
+
+// Marks a path-style request for / itself, which names no bucket: a call on
+// the service, ListBuckets, rather than on the bucket
+#[derive(Clone, Copy)]
+pub struct ServiceRequest;
+
+// Runs before routing, wrapped around the whole router
+pub async fn path_style_middleware(
+    State(state): State<Arc<AppState>>,
+    mut request: Request,
+    next: Next,
+) -> Response {
+    if state.path_style {
+        if let Some(uri) = strip_bucket(request.uri(), &state.bucket_name) {
+            let original = std::mem::replace(request.uri_mut(), uri);
+            request.extensions_mut().insert(OriginalUri(original));
+        } else if request.uri().path() == "/" {
+            request.extensions_mut().insert(ServiceRequest);
+        }
+    }
+    next.run(request).await
+}
+
+fn strip_bucket(uri: &Uri, bucket: &str) -> Option<Uri> {
+    let rest = uri.path().strip_prefix('/')?.strip_prefix(bucket)?;
+    if !rest.is_empty() && !rest.starts_with('/') {
+        return None;
+    }
+    let path = if rest.is_empty() { "/" } else { rest };
+    let path_and_query = match uri.query() {
+        Some(query) => format!("{}?{}", path, query),
+        None => path.to_string(),
+    };
+
+    let mut parts = uri.clone().into_parts();
+    parts.path_and_query = Some(path_and_query.parse().ok()?);
+    Uri::from_parts(parts).ok()
+}
+
+// The path a request was signed for
+pub fn signed_path(request: &Request) -> String {
+    match request.extensions().get::<OriginalUri>() {
+        Some(OriginalUri(uri)) => uri.path().to_string(),
+        None => request.uri().path().to_string(),
+    }
+}
@@ -0,0 +1,242 @@
+use axum::{
+    body::Body,
+    extract::{Request, State},
+    http::{HeaderMap, HeaderValue, Method, StatusCode},
+    middleware::Next,
+    response::{IntoResponse, Response},
+};
+use percent_encoding::percent_decode_str;
+use sha2::{Digest, Sha256};
+use std::sync::Arc;
+use tracing::warn;
+
+use crate::{AppState, cluster::Member, minio, object_path, send_signed};
+
+// Consistent-hash placement of objects across cluster nodes. Each key lives
+// on `replicas` nodes picked by walking a hash ring of the healthy members.
+// A request that lands on a node that doesn't own the key is proxied to an
+// owner, and writes accepted by an owner are copied to the other owners.
+//
+// Forwarded requests carry FORWARDED_HEADER and are always handled locally,
+// so a request crosses at most one extra hop. They authenticate with the
+// shared cluster credentials because a client's SigV4 signature covers the
+// Host header and can't be replayed against another node.
+//
+// Membership changes move ownership without moving data, and listings only
+// show the node's own objects.
+
+const FORWARDED_HEADER: &str = "x-simple-s3-forwarded";
+const VIRTUAL_NODES: usize = 64;
+
+struct Owner {
+    node_id: String,
+    url: Option<String>,
+}
+
+fn ring_position(value: &str) -> u64 {
+    let digest = Sha256::digest(value.as_bytes());
+    u64::from_be_bytes(digest[..8].try_into().unwrap())
+}
+
+// The nodes that should hold `key`, in preference order. `url` is None for
+// this node
+fn owners(node_id: &str, members: &[Member], key: &str, replicas: usize) -> Vec<Owner> {
+    let mut nodes: Vec<Owner> = members
+        .iter()
+        .filter_map(|member| {
+            Some(Owner {
+                node_id: member.node_id.clone()?,
+                url: Some(member.url.clone()),
+            })
+        })
+        .collect();
+    nodes.push(Owner {
+        node_id: node_id.to_string(),
+        url: None,
+    });
+
+    let mut ring: Vec<(u64, usize)> = nodes
+        .iter()
+        .enumerate()
+        .flat_map(|(i, node)| {
+            (0..VIRTUAL_NODES).map(move |v| (ring_position(&format!("{}#{}", node.node_id, v)), i))
+        })
+        .collect();
+    ring.sort_unstable();
+
+    let start = ring.partition_point(|(position, _)| *position < ring_position(key));
+    let mut picked: Vec<usize> = Vec::new();
+
+    for (_, i) in ring.iter().cycle().skip(start).take(ring.len()) {
+        if !picked.contains(i) {
+            picked.push(*i);
+            if picked.len() == replicas {
+                break;
+            }
+        }
+    }
+
+    let mut nodes: Vec<Option<Owner>> = nodes.into_iter().map(Some).collect();
+    picked.into_iter().filter_map(|i| nodes[i].take()).collect()
+}
+
+fn is_write(method: &Method) -> bool {
+    matches!(*method, Method::PUT | Method::POST | Method::DELETE)
+}
+
+pub async fn placement_middleware(
+    State(state): State<Arc<AppState>>,
+    request: Request,
+    next: Next,
+) -> Result<Response, StatusCode> {
+    let (Some(cluster), Some(replicas)) = (&state.cluster, state.replicas) else {
+        return Ok(next.run(request).await);
+    };
+
+    let path = request.uri().path();
+    let key = percent_decode_str(path.trim_start_matches('/'))
+        .decode_utf8_lossy()
+        .to_string();
+
+    if object_path(&state, &key).is_err()
+        || minio::is_admin_path(path)
+        || request.headers().contains_key(FORWARDED_HEADER)
+    {
+        return Ok(next.run(request).await);
+    }
+
+    let members = cluster.healthy_members().await;
+    let owners = owners(&cluster.node_id, &members, &key, replicas);
+    let local = owners.iter().any(|owner| owner.url.is_none());
+    let peers: Vec<String> = owners.into_iter().filter_map(|owner| owner.url).collect();
+
+    if peers.is_empty() {
+        return Ok(next.run(request).await);
+    }
+
+    let (parts, body) = request.into_parts();
+    let body = axum::body::to_bytes(body, usize::MAX)
+        .await
+        .map_err(|_| StatusCode::BAD_REQUEST)?;
+
+    let write = is_write(&parts.method);
+
+    if !local && write {
+        return write_to_owners(&state, &key, &peers, &parts, body).await;
+    }
+
+    if !local {
+        // Try owners in preference order until one answers
+        for peer in &peers {
+            match forward(&state, peer, &parts.method, &parts.uri, &parts.headers, body.clone()).await {
+                Ok(response) => return Ok(response),
+                Err(e) => warn!("🛰️ Failed to proxy {} to {}: {}", key, peer, e),
+            }
+        }
+        return Err(StatusCode::BAD_GATEWAY);
+    }
+
+    let request = Request::from_parts(
+        parts.clone(),
+        Body::from(body.clone()),
+    );
+    let response = next.run(request).await;
+
+    // Copy successful writes to the other owners
+    if write && response.status().is_success() {
+        let results = futures_util::future::join_all(peers.iter().map(|peer| {
+            forward(&state, peer, &parts.method, &parts.uri, &parts.headers, body.clone())
+        }))
+        .await;
+
+        for (peer, result) in peers.iter().zip(results) {
+            match result {
+                Ok(replica) if replica.status().is_success() => {}
+                Ok(replica) => warn!("🛰️ Replica {} answered {} for {}", peer, replica.status(), key),
+                Err(e) => warn!("🛰️ Failed to replicate {} to {}: {}", key, peer, e),
+            }
+        }
+    }
+
+    Ok(response)
+}
+
+// A write that landed on a non-owner goes to every owner; the client gets
+// the first successful answer
+async fn write_to_owners(
+    state: &AppState,
+    key: &str,
+    peers: &[String],
+    parts: &axum::http::request::Parts,
+    body: axum::body::Bytes,
+) -> Result<Response, StatusCode> {
+    let results = futures_util::future::join_all(peers.iter().map(|peer| {
+        forward(state, peer, &parts.method, &parts.uri, &parts.headers, body.clone())
+    }))
+    .await;
+
+    let mut success = None;
+    let mut failure = None;
+    for (peer, result) in peers.iter().zip(results) {
+        match result {
+            Ok(response) if response.status().is_success() => {
+                success.get_or_insert(response);
+            }
+            Ok(response) => {
+                warn!("🛰️ Owner {} answered {} for {}", peer, response.status(), key);
+                failure.get_or_insert(response);
+            }
+            Err(e) => warn!("🛰️ Failed to write {} to {}: {}", key, peer, e),
+        }
+    }
+
+    success.or(failure).ok_or(StatusCode::BAD_GATEWAY)
+}
+
+async fn forward(
+    state: &AppState,
+    peer: &str,
+    method: &Method,
+    uri: &axum::http::Uri,
+    headers: &HeaderMap,
+    body: axum::body::Bytes,
+) -> Result<Response, reqwest::Error> {
+    let cluster = state.cluster.as_ref().expect("placement requires cluster mode");
+    let path_and_query = uri.path_and_query().map(|pq| pq.as_str()).unwrap_or("/");
+
+    let mut request = cluster
+        .client()
+        .request(method.clone(), format!("{}{}", peer, path_and_query))
+        .header(FORWARDED_HEADER, &cluster.url)
+        .body(body);
+
+    for (name, value) in headers {
+        if !matches!(
+            name.as_str(),
+            "host"
+                | "authorization"
+                | "x-amz-access-key"
+                | "x-amz-secret-key"
+                | "content-length"
+                | "connection"
+                | "transfer-encoding"
+        ) {
+            request = request.header(name, value);
+        }
+    }
+
+    let response = send_signed(state, request).await?;
+    let status = StatusCode::from_u16(response.status().as_u16())
+        .unwrap_or(StatusCode::BAD_GATEWAY);
+
+    let mut forwarded = HeaderMap::new();
+    for (name, value) in response.headers() {
+        if !matches!(name.as_str(), "connection" | "transfer-encoding") {
+            forwarded.insert(name.clone(), value.clone());
+        }
+    }
+    forwarded.insert(FORWARDED_HEADER, HeaderValue::from_str(peer).unwrap());
+
+    let bytes = response.bytes().await?;
+    Ok((status, forwarded, bytes).into_response())
+}
@@ -0,0 +1,1053 @@
+use axum::{
+    body::Bytes,
+    extract::{MatchedPath, Request},
+    http::{HeaderMap, HeaderValue, Method, StatusCode},
+    response::{IntoResponse, Response},
+};
+use chrono::{DateTime, NaiveDate, Utc};
+use percent_encoding::percent_decode_str;
+use serde::Deserialize;
+use std::{
+    collections::HashMap,
+    net::IpAddr,
+    path::{Path, PathBuf},
+    sync::Mutex,
+};
+use tokio::fs;
+use tracing::{info, warn};
+
+use crate::{
+    AppState, ErrorResponse, error_response, pathstyle, proxy::Cidr, tmp_path, write_and_rename,
+};
+
+// Bucket policies, S3's ?policy subresource. PUT, GET and DELETE /?policy
+// store, read and remove a JSON policy, and the auth middleware checks every
+// S3 API request against it:
+//
+// - Requests with the bucket's keys are refused with 403 AccessDenied when a
+//   Deny statement matches them. Without one they may do anything, as the
+//   bucket owner can on S3
+// - Requests without credentials, which are otherwise refused, go ahead when
+//   an Allow statement for Principal "*" matches them, or an ACL grants them
+//   what they ask (see acl.rs), and no Deny does
+//
+// Statements match a request by Principal, Action or NotAction, Resource or
+// NotResource, and Condition. The Not forms match everything but what they
+// list, so a Deny with NotResource can hold even the bucket's keys to one
+// prefix.
+// The holder of the bucket's keys is "*", the access key, an ARN ending in
+// "/<access key>" or ":root", a 12 digit account ID and the CanonicalUser of
+// the bucket's owner; anonymous callers are only "*". Actions are those S3
+// authorizes each call with, such as s3:GetObject or s3:ListBucket, and
+// resources arn:aws:s3:::BUCKET and arn:aws:s3:::BUCKET/KEY, both with * and
+// ? wildcards. Conditions may test aws:SourceIp with IpAddress and
+// NotIpAddress, s3:prefix with the String operators, aws:SecureTransport
+// with Bool and aws:CurrentTime with the Date operators, so access can be
+// limited to a time window, such as a contractor's project, without anyone
+// having to remember to end it. Dates are ISO 8601, a day on its own meaning
+// midnight UTC, or seconds since the epoch. Anything else is refused with
+// 400 MalformedPolicy, as is NotPrincipal.
+//
+// BlockPublicPolicy refuses policies with an Allow for "*" whatever its
+// conditions, and RestrictPublicBuckets stops them letting anonymous callers
+// in. WebDAV requests are checked as the S3 calls they amount to, so a Deny
+// by address or for plain HTTP holds there too. The server's own endpoints
+// and SFTP don't look at the policy; SFTP is always encrypted, and
+// --ip-filter keeps it to a network.
+
+const POLICY_FILE: &str = ".simple-s3/policy.json";
+const MAX_POLICY_SIZE: usize = 20 * 1024;
+const VERSIONS: [&str; 2] = ["2012-10-17", "2008-10-17"];
+const RESOURCE_PREFIX: &str = "arn:aws:s3:::";
+
+// Many comes first, or a list of condition values would be read as one
+// value holding a list
+#[derive(Debug, Clone, Deserialize)]
+#[serde(untagged)]
+enum OneOrMany<T> {
+    Many(Vec<T>),
+    One(T),
+}
+
+impl<T> OneOrMany<T> {
+    fn iter(&self) -> std::slice::Iter<'_, T> {
+        match self {
+            Self::One(one) => std::slice::from_ref(one).iter(),
+            Self::Many(many) => many.iter(),
+        }
+    }
+}
+
+#[derive(Debug, Clone, Deserialize)]
+#[serde(rename_all = "PascalCase", deny_unknown_fields)]
+struct Policy {
+    version: Option<String>,
+    #[serde(rename = "Id")]
+    _id: Option<String>,
+    statement: OneOrMany<Statement>,
+}
+
+#[derive(Debug, Clone, Deserialize)]
+#[serde(rename_all = "PascalCase", deny_unknown_fields)]
+struct Statement {
+    #[serde(rename = "Sid")]
+    _sid: Option<String>,
+    effect: Effect,
+    principal: Principal,
+    action: Option<OneOrMany<String>>,
+    not_action: Option<OneOrMany<String>>,
+    resource: Option<OneOrMany<String>>,
+    not_resource: Option<OneOrMany<String>>,
+    // Operator to condition key to the values it's compared with
+    #[serde(default)]
+    condition: HashMap<String, HashMap<String, OneOrMany<serde_json::Value>>>,
+}
+
+#[derive(Debug, Clone, Copy, PartialEq, Deserialize)]
+enum Effect {
+    Allow,
+    Deny,
+}
+
+#[derive(Debug, Clone, Deserialize)]
+#[serde(untagged)]
+enum Principal {
+    // Only "*"
+    Anyone(String),
+    Named(Principals),
+}
+
+#[derive(Debug, Clone, Deserialize)]
+#[serde(deny_unknown_fields)]
+struct Principals {
+    #[serde(rename = "AWS")]
+    aws: Option<OneOrMany<String>>,
+    #[serde(rename = "CanonicalUser")]
+    canonical_user: Option<OneOrMany<String>>,
+}
+
+// Who sent a request and from where, put in its extensions by the auth
+// middleware
+#[derive(Debug, Clone)]
+pub struct Caller {
+    pub anonymous: bool,
+    pub ip: Option<IpAddr>,
+    pub secure: bool,
+}
+
+// An action a request needs, on the bucket or one of its objects
+pub struct Target {
+    pub action: &'static str,
+    pub key: Option<String>,
+}
+
+// What statements are matched and conditions tested on
+struct Context<'a> {
+    bucket: &'a str,
+    access_key: &'a str,
+    // The owner's canonical user ID
+    owner_id: String,
+    caller: &'a Caller,
+    prefix: Option<&'a str>,
+    now: DateTime<Utc>,
+}
+
+impl<'a> Context<'a> {
+    fn new(state: &'a AppState, caller: &'a Caller, prefix: Option<&'a str>) -> Self {
+        Self {
+            bucket: &state.bucket_name,
+            access_key: &state.access_key,
+            owner_id: crate::Owner::of(state).id,
+            caller,
+            prefix,
+            now: Utc::now(),
+        }
+    }
+}
+
+#[derive(Debug, Clone, Copy, PartialEq)]
+enum Decision {
+    Allow,
+    Deny,
+    // No statement matches
+    Neither,
+}
+
+// Whether `text` matches `pattern`, where * is any run of characters and ?
+// any one
+fn glob(pattern: &str, text: &str) -> bool {
+    let pattern: Vec<char> = pattern.chars().collect();
+    let text: Vec<char> = text.chars().collect();
+    let (mut p, mut t) = (0, 0);
+    // Where the last * was and the text it has been stretched to
+    let mut star: Option<(usize, usize)> = None;
+    while t < text.len() {
+        if p < pattern.len() && (pattern[p] == '?' || pattern[p] == text[t]) {
+            p += 1;
+            t += 1;
+        } else if p < pattern.len() && pattern[p] == '*' {
+            star = Some((p, t));
+            p += 1;
+        } else if let Some((star_p, star_t)) = star {
+            p = star_p + 1;
+            t = star_t + 1;
+            star = Some((star_p, star_t + 1));
+        } else {
+            return false;
+        }
+    }
+    pattern[p..].iter().all(|&c| c == '*')
+}
+
+// Whether a statement with `listed`, or else `not_listed`, applies to what
+// `matches` matches
+fn applies(
+    listed: &Option<OneOrMany<String>>,
+    not_listed: &Option<OneOrMany<String>>,
+    matches: impl Fn(&String) -> bool,
+) -> bool {
+    match (listed, not_listed) {
+        (Some(listed), _) => listed.iter().any(matches),
+        (None, Some(not_listed)) => !not_listed.iter().any(matches),
+        (None, None) => false,
+    }
+}
+
+// A condition value as the string it's compared as
+fn condition_value(value: &serde_json::Value) -> Option<String> {
+    match value {
+        serde_json::Value::String(value) => Some(value.clone()),
+        serde_json::Value::Bool(value) => Some(value.to_string()),
+        serde_json::Value::Number(value) => Some(value.to_string()),
+        _ => None,
+    }
+}
+
+// A date condition value
+fn date(value: &str) -> Option<DateTime<Utc>> {
+    if let Ok(date) = DateTime::parse_from_rfc3339(value) {
+        return Some(date.with_timezone(&Utc));
+    }
+    if let Ok(day) = NaiveDate::parse_from_str(value, "%Y-%m-%d") {
+        return Some(day.and_hms_opt(0, 0, 0)?.and_utc());
+    }
+    DateTime::from_timestamp(value.parse().ok()?, 0)
+}
+
+// The operators a condition key may be tested with
+fn operators(key: &str) -> Option<&'static [&'static str]> {
+    match key.to_ascii_lowercase().as_str() {
+        "aws:sourceip" => Some(&["IpAddress", "NotIpAddress"]),
+        "s3:prefix" => Some(&["StringEquals", "StringNotEquals", "StringLike", "StringNotLike"]),
+        "aws:securetransport" => Some(&["Bool"]),
+        "aws:currenttime" => Some(&[
+            "DateEquals",
+            "DateNotEquals",
+            "DateLessThan",
+            "DateLessThanEquals",
+            "DateGreaterThan",
+            "DateGreaterThanEquals",
+        ]),
+        _ => None,
+    }
+}
+
+impl Principal {
+    fn is_valid(&self) -> bool {
+        match self {
+            Self::Anyone(anyone) => anyone == "*",
+            Self::Named(named) => named.aws.is_some() || named.canonical_user.is_some(),
+        }
+    }
+
+    fn is_anyone(&self) -> bool {
+        match self {
+            Self::Anyone(_) => true,
+            Self::Named(named) => named
+                .aws
+                .as_ref()
+                .is_some_and(|aws| aws.iter().any(|principal| principal == "*")),
+        }
+    }
+
+    fn matches(&self, context: &Context) -> bool {
+        if self.is_anyone() {
+            return true;
+        }
+        let Self::Named(named) = self else {
+            return false;
+        };
+        if context.caller.anonymous {
+            return false;
+        }
+        let owner = |principal: &String| {
+            principal == context.access_key
+                || principal.ends_with(&format!("/{}", context.access_key))
+                || (principal.starts_with("arn:") && principal.ends_with(":root"))
+                || (principal.len() == 12 && principal.bytes().all(|b| b.is_ascii_digit()))
+        };
+        named.aws.as_ref().is_some_and(|aws| aws.iter().any(owner))
+            || named
+                .canonical_user
+                .as_ref()
+                .is_some_and(|users| users.iter().any(|user| *user == context.owner_id))
+    }
+}
+
+impl Statement {
+    // Why the statement can't be in a policy, None if it can
+    fn invalid(&self) -> Option<&'static str> {
+        if !self.principal.is_valid() {
+            return Some("Invalid principal in policy");
+        }
+        let action_valid = |action: &String| {
+            let action = action.to_ascii_lowercase();
+            action == "*" || action.strip_prefix("s3:").is_some_and(|name| !name.is_empty())
+        };
+        if self.action.is_some() == self.not_action.is_some()
+            || !self
+                .action
+                .iter()
+                .chain(&self.not_action)
+                .flat_map(OneOrMany::iter)
+                .all(action_valid)
+        {
+            return Some("Policy has invalid action");
+        }
+        let resource_valid = |resource: &String| resource.starts_with(RESOURCE_PREFIX);
+        if self.resource.is_some() == self.not_resource.is_some()
+            || !self
+                .resource
+                .iter()
+                .chain(&self.not_resource)
+                .flat_map(OneOrMany::iter)
+                .all(resource_valid)
+        {
+            return Some("Policy has invalid resource");
+        }
+        for (operator, keys) in &self.condition {
+            for (key, values) in keys {
+                let values: Option<Vec<String>> = values.iter().map(condition_value).collect();
+                let valid = operators(key).is_some_and(|ops| ops.contains(&operator.as_str()))
+                    && values.is_some_and(|values| {
+                        (!operator.ends_with("IpAddress")
+                            || values.iter().all(|value| value.parse::<Cidr>().is_ok()))
+                            && (!operator.starts_with("Date")
+                                || values.iter().all(|value| date(value).is_some()))
+                    });
+                if !valid {
+                    return Some("Policy has an invalid condition key");
+                }
+            }
+        }
+        None
+    }
+
+    fn matches(&self, context: &Context, target: &Target) -> bool {
+        let resource = match &target.key {
+            Some(key) => format!("{}{}/{}", RESOURCE_PREFIX, context.bucket, key),
+            None => format!("{}{}", RESOURCE_PREFIX, context.bucket),
+        };
+        let action = target.action.to_ascii_lowercase();
+        self.principal.matches(context)
+            && applies(&self.action, &self.not_action, |pattern| {
+                glob(&pattern.to_ascii_lowercase(), &action)
+            })
+            && applies(&self.resource, &self.not_resource, |pattern| glob(pattern, &resource))
+            && self.conditions_hold(context)
+    }
+
+    fn conditions_hold(&self, context: &Context) -> bool {
+        self.condition.iter().all(|(operator, keys)| {
+            keys.iter().all(|(key, values)| {
+                let values: Vec<String> = values.iter().filter_map(condition_value).collect();
+                condition_holds(operator, key, &values, context)
+            })
+        })
+    }
+}
+
+fn condition_holds(operator: &str, key: &str, values: &[String], context: &Context) -> bool {
+    let in_cidrs = |ip: IpAddr| {
+        values
+            .iter()
+            .filter_map(|value| value.parse::<Cidr>().ok())
+            .any(|cidr| cidr.contains(ip))
+    };
+    let ip = context.caller.ip;
+    let now = context.now;
+    let dates = || values.iter().filter_map(|value| date(value));
+    // A key the request has no value for only satisfies negated operators
+    let actual = match key.to_ascii_lowercase().as_str() {
+        "s3:prefix" => context.prefix.map(str::to_string),
+        "aws:securetransport" => Some(context.caller.secure.to_string()),
+        _ => None,
+    };
+    match operator {
+        "IpAddress" => ip.is_some_and(in_cidrs),
+        "NotIpAddress" => ip.is_none_or(|ip| !in_cidrs(ip)),
+        "StringEquals" => actual.is_some_and(|actual| values.contains(&actual)),
+        "StringNotEquals" => actual.is_none_or(|actual| !values.contains(&actual)),
+        "StringLike" => {
+            actual.is_some_and(|actual| values.iter().any(|value| glob(value, &actual)))
+        }
+        "StringNotLike" => {
+            actual.is_none_or(|actual| !values.iter().any(|value| glob(value, &actual)))
+        }
+        "Bool" => actual.is_some_and(|actual| {
+            values.iter().any(|value| value.eq_ignore_ascii_case(&actual))
+        }),
+        "DateEquals" => dates().any(|date| now == date),
+        "DateNotEquals" => !dates().any(|date| now == date),
+        "DateLessThan" => dates().any(|date| now < date),
+        "DateLessThanEquals" => dates().any(|date| now <= date),
+        "DateGreaterThan" => dates().any(|date| now > date),
+        "DateGreaterThanEquals" => dates().any(|date| now >= date),
+        _ => false,
+    }
+}
+
+impl Policy {
+    // Why the policy can't be the bucket's, None if it can
+    fn invalid(&self) -> Option<&'static str> {
+        if self.version.as_ref().is_some_and(|v| !VERSIONS.contains(&v.as_str())) {
+            return Some("Invalid policy syntax.");
+        }
+        if self.statement.iter().next().is_none() {
+            return Some("Missing required field Statement");
+        }
+        self.statement.iter().find_map(Statement::invalid)
+    }
+
+    fn is_public(&self) -> bool {
+        self.statement.iter().any(|statement| {
+            statement.effect == Effect::Allow && statement.principal.is_anyone()
+        })
+    }
+
+    fn decide(&self, context: &Context, target: &Target) -> Decision {
+        let matching = self
+            .statement
+            .iter()
+            .filter(|statement| statement.matches(context, target));
+        let mut decision = Decision::Neither;
+        for statement in matching {
+            match statement.effect {
+                Effect::Deny => return Decision::Deny,
+                Effect::Allow => decision = Decision::Allow,
+            }
+        }
+        decision
+    }
+}
+
+struct Stored {
+    // As it was PUT, which GET returns
+    text: String,
+    policy: Policy,
+}
+
+pub struct BucketPolicy {
+    path: PathBuf,
+    stored: Mutex<Option<Stored>>,
+}
+
+impl BucketPolicy {
+    pub async fn load(data_dir: &Path) -> std::io::Result<Self> {
+        let path = data_dir.join(POLICY_FILE);
+        let stored = match fs::read_to_string(&path).await {
+            Ok(text) => {
+                let policy = serde_json::from_str(&text).map_err(|e| {
+                    std::io::Error::other(format!("invalid {}: {}", path.display(), e))
+                })?;
+                Some(Stored { text, policy })
+            }
+            Err(e) if e.kind() == std::io::ErrorKind::NotFound => None,
+            Err(e) => return Err(e),
+        };
+        Ok(Self {
+            path,
+            stored: Mutex::new(stored),
+        })
+    }
+
+    fn policy(&self) -> Option<Policy> {
+        self.stored
+            .lock()
+            .unwrap()
+            .as_ref()
+            .map(|stored| stored.policy.clone())
+    }
+}
+
+fn refusal(status: StatusCode, code: &str, message: &str) -> Response {
+    error_response(
+        status,
+        ErrorResponse {
+            code: code.to_string(),
+            message: message.to_string(),
+            max_size_allowed: None,
+        },
+    )
+}
+
+fn access_denied() -> Response {
+    refusal(StatusCode::FORBIDDEN, "AccessDenied", "Access Denied")
+}
+
+fn decode(value: &str) -> String {
+    percent_decode_str(&value.replace('+', " "))
+        .decode_utf8_lossy()
+        .into_owned()
+}
+
+// The key a path names, None for the bucket itself
+fn key_of(path: &str) -> Option<String> {
+    let key = path.trim_start_matches('/');
+    (!key.is_empty()).then(|| percent_decode_str(key).decode_utf8_lossy().into_owned())
+}
+
+// The actions an S3 API request needs, and what on. None for requests to the
+// server's own endpoints and ListBuckets, which aren't on the bucket, and
+// none at all for DeleteObjects, which is checked key by key
+pub fn targets(state: &AppState, request: &Request) -> Option<Vec<Target>> {
+    let routed = request
+        .extensions()
+        .get::<MatchedPath>()
+        .is_none_or(|matched| matches!(matched.as_str(), "/" | "/{*key}"));
+    if !routed || request.extensions().get::<pathstyle::ServiceRequest>().is_some() {
+        return None;
+    }
+    let query = request.uri().query().unwrap_or_default();
+    let has = |name: &str| {
+        query
+            .split('&')
+            .any(|param| param.split_once('=').map_or(param, |(param, _)| param) == name)
+    };
+    let method = request.method();
+    let key = key_of(request.uri().path());
+
+    let action = match (method, &key) {
+        (&Method::GET, None) => match () {
+            _ if has("policy") => "s3:GetBucketPolicy",
+            _ if has("publicAccessBlock") => "s3:GetBucketPublicAccessBlock",
+            _ if has("ownershipControls") => "s3:GetBucketOwnershipControls",
+            _ if has("versioning") => "s3:GetBucketVersioning",
+            _ if has("versions") => "s3:ListBucketVersions",
+            _ if has("object-lock") => "s3:GetBucketObjectLockConfiguration",
+            _ if has("lifecycle") => "s3:GetLifecycleConfiguration",
+            _ if has("cors") => "s3:GetBucketCORS",
+            _ if has("acl") => "s3:GetBucketAcl",
+            _ if has("uploads") => "s3:ListBucketMultipartUploads",
+            _ => "s3:ListBucket",
+        },
+        (&Method::HEAD, None) => "s3:ListBucket",
+        (&Method::PUT, None) => match () {
+            _ if has("policy") => "s3:PutBucketPolicy",
+            _ if has("publicAccessBlock") => "s3:PutBucketPublicAccessBlock",
+            _ if has("ownershipControls") => "s3:PutBucketOwnershipControls",
+            _ if has("versioning") => "s3:PutBucketVersioning",
+            _ if has("lifecycle") => "s3:PutLifecycleConfiguration",
+            _ if has("cors") => "s3:PutBucketCORS",
+            _ if has("acl") => "s3:PutBucketAcl",
+            _ => "s3:CreateBucket",
+        },
+        // S3 authorizes removing a configuration as putting it
+        (&Method::DELETE, None) => match () {
+            _ if has("policy") => "s3:DeleteBucketPolicy",
+            _ if has("publicAccessBlock") => "s3:PutBucketPublicAccessBlock",
+            _ if has("ownershipControls") => "s3:PutBucketOwnershipControls",
+            _ if has("lifecycle") => "s3:PutLifecycleConfiguration",
+            _ if has("cors") => "s3:PutBucketCORS",
+            _ => "s3:DeleteBucket",
+        },
+        (&Method::POST, None) => return Some(Vec::new()),
+        (&Method::GET | &Method::HEAD, Some(_)) => match () {
+            _ if has("tagging") => "s3:GetObjectTagging",
+            _ if has("retention") => "s3:GetObjectRetention",
+            _ if has("legal-hold") => "s3:GetObjectLegalHold",
+            _ if has("acl") => "s3:GetObjectAcl",
+            _ if has("uploadId") => "s3:ListMultipartUploadParts",
+            _ if has("attributes") => "s3:GetObjectAttributes",
+            _ if has("torrent") => "s3:GetObjectTorrent",
+            _ if has("versionId") => "s3:GetObjectVersion",
+            _ => "s3:GetObject",
+        },
+        (&Method::PUT, Some(_)) => match () {
+            _ if has("tagging") => "s3:PutObjectTagging",
+            _ if has("retention") => "s3:PutObjectRetention",
+            _ if has("legal-hold") => "s3:PutObjectLegalHold",
+            _ if has("acl") => "s3:PutObjectAcl",
+            _ => "s3:PutObject",
+        },
+        (&Method::POST, Some(_)) => "s3:PutObject",
+        (&Method::DELETE, Some(_)) => match () {
+            _ if has("tagging") => "s3:DeleteObjectTagging",
+            _ if has("uploadId") => "s3:AbortMultipartUpload",
+            _ if has("versionId") => "s3:DeleteObjectVersion",
+            _ => "s3:DeleteObject",
+        },
+        _ => return None,
+    };
+
+    let headers = request.headers();
+    let mut targets = vec![Target {
+        action,
+        key: key.clone(),
+    }];
+    // A copy reads its source
+    if method == Method::PUT
+        && let Some(source) = headers.get("x-amz-copy-source").and_then(|v| v.to_str().ok())
+        && let Ok(source) = crate::parse_copy_source(state, source)
+    {
+        targets.push(Target {
+            action: match source.version_id {
+                Some(_) => "s3:GetObjectVersion",
+                None => "s3:GetObject",
+            },
+            key: Some(source.key),
+        });
+    }
+    if crate::retention::bypasses(headers) {
+        targets.push(Target {
+            action: "s3:BypassGovernanceRetention",
+            key,
+        });
+    }
+    Some(targets)
+}
+
+fn prefix_of(request: &Request) -> Option<String> {
+    request
+        .uri()
+        .query()?
+        .split('&')
+        .find_map(|param| param.strip_prefix("prefix="))
+        .map(decode)
+}
+
+// The refusal for a request with the bucket's keys that the policy denies,
+// None if it may go ahead
+pub fn refuse(state: &AppState, caller: &Caller, request: &Request) -> Option<Response> {
+    let policy = state.policy.policy()?;
+    let targets = targets(state, request)?;
+    let prefix = prefix_of(request);
+    let context = Context::new(state, caller, prefix.as_deref());
+    let denied = targets
+        .iter()
+        .find(|target| policy.decide(&context, target) == Decision::Deny)?;
+    warn!("📜 Bucket policy denied {} on {}", denied.action, request.uri().path());
+    Some(access_denied())
+}
+
+// What the policy makes of a request without credentials
+pub enum Anonymous {
+    Allowed,
+    Denied,
+    // Neither, which leaves it to the ACLs of what it asks for
+    Undecided(Vec<Target>),
+}
+
+pub fn anonymous(state: &AppState, caller: &Caller, request: &Request) -> Anonymous {
+    let Some(targets) = targets(state, request) else {
+        return Anonymous::Denied;
+    };
+    let Some(policy) = state.policy.policy() else {
+        return Anonymous::Undecided(targets);
+    };
+    let prefix = prefix_of(request);
+    let context = Context::new(state, caller, prefix.as_deref());
+    let decisions: Vec<Decision> = targets
+        .iter()
+        .map(|target| policy.decide(&context, target))
+        .collect();
+    if let Some(at) = decisions.iter().position(|&decision| decision == Decision::Deny) {
+        let action = targets[at].action;
+        warn!("📜 Bucket policy denied {} on {}", action, request.uri().path());
+        return Anonymous::Denied;
+    }
+    let allowed = decisions.iter().all(|&decision| decision == Decision::Allow);
+    match allowed && !state.access.restricts_public_buckets() {
+        true => Anonymous::Allowed,
+        false => Anonymous::Undecided(targets),
+    }
+}
+
+// Whether `caller` may do `action` on `key`, for the keys of DeleteObjects
+// and WebDAV requests
+pub fn permits(state: &AppState, caller: &Caller, action: &'static str, key: &str) -> bool {
+    let target = Target {
+        action,
+        key: Some(key.to_string()),
+    };
+    permits_target(state, caller, &target, None)
+}
+
+// Whether `caller` may list the keys under `prefix`, for WebDAV requests
+pub fn permits_listing(state: &AppState, caller: &Caller, prefix: &str) -> bool {
+    let target = Target {
+        action: "s3:ListBucket",
+        key: None,
+    };
+    permits_target(state, caller, &target, Some(prefix))
+}
+
+fn permits_target(
+    state: &AppState,
+    caller: &Caller,
+    target: &Target,
+    prefix: Option<&str>,
+) -> bool {
+    let Some(policy) = state.policy.policy() else {
+        return !caller.anonymous;
+    };
+    let context = Context::new(state, caller, prefix);
+    match policy.decide(&context, target) {
+        Decision::Allow => true,
+        Decision::Deny => false,
+        Decision::Neither => !caller.anonymous,
+    }
+}
+
+// GET /?policy
+pub async fn get(state: &AppState) -> Result<Response, StatusCode> {
+    let text = state
+        .policy
+        .stored
+        .lock()
+        .unwrap()
+        .as_ref()
+        .map(|stored| stored.text.clone());
+    let Some(text) = text else {
+        return Ok(refusal(
+            StatusCode::NOT_FOUND,
+            "NoSuchBucketPolicy",
+            "The bucket policy does not exist",
+        ));
+    };
+    let mut headers = HeaderMap::new();
+    headers.insert("content-type", HeaderValue::from_static("application/json"));
+    Ok((headers, text).into_response())
+}
+
+// Why a policy wasn't stored
+pub enum Refused {
+    // 400 MalformedPolicy over S3
+    Malformed(&'static str),
+    // It allows "*" while BlockPublicPolicy is set
+    Public,
+    Failed(std::io::Error),
+}
+
+impl std::fmt::Display for Refused {
+    fn fmt(&self, f: &mut std::fmt::Formatter<'_>) -> std::fmt::Result {
+        match self {
+            Self::Malformed(message) => write!(f, "{}", message),
+            Self::Public => write!(f, "it allows \"*\" and BlockPublicPolicy is set"),
+            Self::Failed(e) => write!(f, "it couldn't be stored: {}", e),
+        }
+    }
+}
+
+// Checks a policy as S3 does and stores it in place of the one before. PUT
+// /?policy and --provision both set the policy this way
+pub async fn store(state: &AppState, body: &[u8]) -> Result<(), Refused> {
+    if body.len() > MAX_POLICY_SIZE {
+        return Err(Refused::Malformed("Policy exceeds the maximum allowed document size."));
+    }
+    let Ok(text) = std::str::from_utf8(body) else {
+        return Err(Refused::Malformed("This policy contains invalid Json"));
+    };
+    let policy = match serde_json::from_str::<Policy>(text) {
+        Ok(policy) => policy,
+        Err(e) => {
+            warn!("📜 Refused a bucket policy that doesn't parse: {}", e);
+            return Err(Refused::Malformed("This policy contains invalid Json"));
+        }
+    };
+    if let Some(message) = policy.invalid() {
+        return Err(Refused::Malformed(message));
+    }
+    if policy.is_public() && state.access.blocks_public_policy() {
+        warn!("📜 Refused a public bucket policy, BlockPublicPolicy is set");
+        return Err(Refused::Public);
+    }
+
+    let path = &state.policy.path;
+    if let Some(parent) = path.parent() {
+        fs::create_dir_all(parent).await.map_err(Refused::Failed)?;
+    }
+    let tmp = tmp_path(state);
+    if let Err(e) = write_and_rename(state, &tmp, path, text.as_bytes()).await {
+        let _ = fs::remove_file(&tmp).await;
+        warn!("Failed to store {}: {}", path.display(), e);
+        return Err(Refused::Failed(e));
+    }
+    info!(
+        "📜 Stored a bucket policy with {} statements",
+        policy.statement.iter().count()
+    );
+    *state.policy.stored.lock().unwrap() = Some(Stored {
+        text: text.to_string(),
+        policy,
+    });
+    Ok(())
+}
+
+// PUT /?policy
+pub async fn put(state: &AppState, body: &Bytes) -> Result<Response, StatusCode> {
+    match store(state, body).await {
+        Ok(()) => Ok(StatusCode::NO_CONTENT.into_response()),
+        Err(Refused::Malformed(message)) => {
+            Ok(refusal(StatusCode::BAD_REQUEST, "MalformedPolicy", message))
+        }
+        Err(Refused::Public) => Ok(access_denied()),
+        Err(Refused::Failed(_)) => Err(StatusCode::INTERNAL_SERVER_ERROR),
+    }
+}
+
+// DELETE /?policy
+pub async fn delete(state: &AppState) -> Result<Response, StatusCode> {
+    match fs::remove_file(&state.policy.path).await {
+        Ok(()) => {}
+        Err(e) if e.kind() == std::io::ErrorKind::NotFound => {}
+        Err(_) => return Err(StatusCode::INTERNAL_SERVER_ERROR),
+    }
+    *state.policy.stored.lock().unwrap() = None;
+    info!("📜 Removed the bucket policy");
+    Ok(StatusCode::NO_CONTENT.into_response())
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    const BUCKET: &str = "photos";
+    const ACCESS_KEY: &str = "mykey";
+
+    fn policy(statements: serde_json::Value) -> Policy {
+        let policy: Policy = serde_json::from_value(serde_json::json!({
+            "Version": "2012-10-17",
+            "Statement": statements,
+        }))
+        .unwrap();
+        assert_eq!(policy.invalid(), None);
+        policy
+    }
+
+    fn caller(ip: &str, secure: bool) -> Caller {
+        Caller {
+            anonymous: true,
+            ip: ip.parse().ok(),
+            secure,
+        }
+    }
+
+    fn context<'a>(caller: &'a Caller, prefix: Option<&'a str>) -> Context<'a> {
+        Context {
+            bucket: BUCKET,
+            access_key: ACCESS_KEY,
+            owner_id: String::new(),
+            caller,
+            prefix,
+            now: date("2024-06-30T12:00:00Z").unwrap(),
+        }
+    }
+
+    fn target(action: &'static str, key: Option<&str>) -> Target {
+        Target {
+            action,
+            key: key.map(str::to_string),
+        }
+    }
+
+    fn decide(policy: &Policy, context: &Context, action: &'static str, key: &str) -> Decision {
+        policy.decide(context, &target(action, Some(key)))
+    }
+
+    #[test]
+    fn an_explicit_deny_overrides_any_allow() {
+        let allow = serde_json::json!({
+            "Effect": "Allow",
+            "Principal": "*",
+            "Action": "s3:*",
+            "Resource": "arn:aws:s3:::photos/*",
+        });
+        let deny = serde_json::json!({
+            "Effect": "Deny",
+            "Principal": "*",
+            "Action": "s3:DeleteObject",
+            "Resource": "arn:aws:s3:::photos/old/*",
+        });
+        let caller = caller("203.0.113.7", true);
+        let context = context(&caller, None);
+
+        // Whichever order the statements come in
+        for policy in [
+            policy(serde_json::json!([allow, deny])),
+            policy(serde_json::json!([deny, allow])),
+        ] {
+            assert_eq!(decide(&policy, &context, "s3:GetObject", "old/a.jpg"), Decision::Allow);
+            assert_eq!(decide(&policy, &context, "s3:DeleteObject", "old/a.jpg"), Decision::Deny);
+            assert_eq!(decide(&policy, &context, "s3:DeleteObject", "new/a.jpg"), Decision::Allow);
+        }
+    }
+
+    #[test]
+    fn resources_and_actions_match_wildcards() {
+        let policy = policy(serde_json::json!({
+            "Effect": "Allow",
+            "Principal": "*",
+            "Action": ["s3:Get*", "s3:ListBucket"],
+            "Resource": ["arn:aws:s3:::photos/*.jpg", "arn:aws:s3:::photos/day?.png"],
+        }));
+        let caller = caller("203.0.113.7", true);
+        let context = context(&caller, None);
+
+        // * spans slashes, ? is exactly one character
+        assert_eq!(decide(&policy, &context, "s3:GetObject", "2024/06/cat.jpg"), Decision::Allow);
+        assert_eq!(decide(&policy, &context, "s3:GetObjectTagging", "cat.jpg"), Decision::Allow);
+        assert_eq!(decide(&policy, &context, "s3:GetObject", "day1.png"), Decision::Allow);
+        assert_eq!(decide(&policy, &context, "s3:GetObject", "day10.png"), Decision::Neither);
+        assert_eq!(decide(&policy, &context, "s3:GetObject", "cat.png"), Decision::Neither);
+        assert_eq!(decide(&policy, &context, "s3:PutObject", "cat.jpg"), Decision::Neither);
+        // The bucket's own ARN isn't under photos/
+        let listing = target("s3:ListBucket", None);
+        assert_eq!(policy.decide(&context, &listing), Decision::Neither);
+    }
+
+    #[test]
+    fn not_resource_and_not_action_match_everything_else() {
+        let policy = policy(serde_json::json!([
+            {
+                "Effect": "Allow",
+                "Principal": "*",
+                "NotAction": "s3:Delete*",
+                "Resource": "arn:aws:s3:::photos/*",
+            },
+            {
+                "Effect": "Deny",
+                "Principal": "*",
+                "Action": "s3:*",
+                "NotResource": ["arn:aws:s3:::photos", "arn:aws:s3:::photos/public/*"],
+            },
+        ]));
+        let caller = caller("203.0.113.7", true);
+        let context = context(&caller, None);
+
+        assert_eq!(decide(&policy, &context, "s3:GetObject", "public/a.jpg"), Decision::Allow);
+        assert_eq!(decide(&policy, &context, "s3:DeleteObject", "public/a.jpg"), Decision::Neither);
+        assert_eq!(decide(&policy, &context, "s3:GetObject", "private/a.jpg"), Decision::Deny);
+        let listing = target("s3:ListBucket", None);
+        assert_eq!(policy.decide(&context, &listing), Decision::Neither);
+    }
+
+    // The decision on a GetObject of a.jpg by `caller`, listing `prefix`, of
+    // a policy allowing it under `condition`
+    fn allowed_when(condition: serde_json::Value, caller: &Caller, prefix: Option<&str>) -> bool {
+        let policy = policy(serde_json::json!({
+            "Effect": "Allow",
+            "Principal": "*",
+            "Action": "s3:GetObject",
+            "Resource": "arn:aws:s3:::photos/*",
+            "Condition": condition,
+        }));
+        decide(&policy, &context(caller, prefix), "s3:GetObject", "a.jpg") == Decision::Allow
+    }
+
+    #[test]
+    fn conditions_test_the_address_prefix_and_transport() {
+        let inside = caller("10.1.2.3", false);
+        let outside = caller("203.0.113.7", true);
+        let unknown = caller("", true);
+
+        let ip = serde_json::json!({"IpAddress": {"aws:SourceIp": ["10.0.0.0/8"]}});
+        assert!(allowed_when(ip.clone(), &inside, None));
+        assert!(!allowed_when(ip, &outside, None));
+        let not_ip = serde_json::json!({"NotIpAddress": {"aws:SourceIp": "10.0.0.0/8"}});
+        assert!(allowed_when(not_ip.clone(), &outside, None));
+        assert!(!allowed_when(not_ip.clone(), &inside, None));
+        // Without an address only the negated operator holds
+        assert!(allowed_when(not_ip, &unknown, None));
+
+        let tls = serde_json::json!({"Bool": {"aws:SecureTransport": true}});
+        assert!(allowed_when(tls.clone(), &outside, None));
+        assert!(!allowed_when(tls, &inside, None));
+
+        let like = serde_json::json!({"StringLike": {"s3:prefix": "home/*"}});
+        assert!(allowed_when(like.clone(), &outside, Some("home/ann/")));
+        assert!(!allowed_when(like.clone(), &outside, Some("work/")));
+        assert!(!allowed_when(like, &outside, None));
+        let equals = serde_json::json!({"StringEquals": {"s3:prefix": ["", "home/"]}});
+        assert!(allowed_when(equals.clone(), &outside, Some("home/")));
+        assert!(!allowed_when(equals, &outside, Some("home/ann/")));
+        let not_equals = serde_json::json!({"StringNotEquals": {"s3:prefix": "home/"}});
+        assert!(allowed_when(not_equals.clone(), &outside, None));
+        assert!(!allowed_when(not_equals, &outside, Some("home/")));
+    }
+
+    #[test]
+    fn date_conditions_compare_the_current_time() {
+        // The context's time is 2024-06-30T12:00:00Z
+        let caller = caller("203.0.113.7", true);
+        let holds = |operator: &str, value: serde_json::Value| {
+            let condition = serde_json::json!({operator: {"aws:CurrentTime": value}});
+            allowed_when(condition, &caller, None)
+        };
+
+        assert!(holds("DateLessThan", "2024-07-01".into()));
+        assert!(!holds("DateLessThan", "2024-06-30T12:00:00Z".into()));
+        assert!(holds("DateLessThanEquals", "2024-06-30T12:00:00Z".into()));
+        assert!(holds("DateGreaterThan", "2024-06-30T11:59:59Z".into()));
+        assert!(!holds("DateGreaterThan", "2024-06-30T14:00:00+02:00".into()));
+        assert!(holds("DateGreaterThanEquals", "2024-06-30T14:00:00+02:00".into()));
+        assert!(holds("DateEquals", 1_719_748_800.into()));
+        assert!(holds("DateNotEquals", "1719748801".into()));
+        // Any of several values
+        assert!(holds("DateGreaterThan", serde_json::json!(["2099-01-01", "2000-01-01"])));
+    }
+
+    #[test]
+    fn invalid_statements_are_refused() {
+        let invalid = |statement: serde_json::Value| {
+            let policy: Policy = serde_json::from_value(serde_json::json!({
+                "Statement": statement,
+            }))
+            .unwrap();
+            policy.invalid()
+        };
+        let statement = |extra: serde_json::Value| {
+            let mut statement = serde_json::json!({
+                "Effect": "Allow",
+                "Principal": "*",
+                "Action": "s3:GetObject",
+                "Resource": "arn:aws:s3:::photos/*",
+            });
+            for (key, value) in extra.as_object().unwrap() {
+                statement[key] = value.clone();
+            }
+            statement
+        };
+
+        assert_eq!(invalid(statement(serde_json::json!({}))), None);
+        assert_eq!(
+            invalid(statement(serde_json::json!({"NotAction": "s3:PutObject"}))),
+            Some("Policy has invalid action")
+        );
+        assert_eq!(
+            invalid(statement(serde_json::json!({"NotResource": "arn:aws:s3:::other"}))),
+            Some("Policy has invalid resource")
+        );
+        for condition in [
+            serde_json::json!({"DateLessThan": {"aws:CurrentTime": "next tuesday"}}),
+            serde_json::json!({"IpAddress": {"aws:SourceIp": "not an address"}}),
+            serde_json::json!({"StringLike": {"aws:SourceIp": "10.*"}}),
+            serde_json::json!({"StringEquals": {"aws:UserAgent": "curl"}}),
+        ] {
+            assert_eq!(
+                invalid(statement(serde_json::json!({"Condition": condition}))),
+                Some("Policy has an invalid condition key")
+            );
+        }
+    }
+}
@@ -0,0 +1,340 @@
+use serde::Deserialize;
+use std::{
+    error::Error,
+    fs,
+    path::{Path, PathBuf},
+};
+use tracing::info;
+use unicode_normalization::UnicodeNormalization;
+
+use crate::{
+    AppState, Args,
+    access::{OwnershipControls, PublicAccessBlockConfiguration},
+    ingest, keys, policy, relative_path,
+};
+
+// Declarative setup at startup. --provision FILE names a TOML file saying
+// what the server should look like once it's up, so a docker-compose test
+// environment needs no script of aws-cli calls:
+//
+//   bucket = "assets"
+//
+//   [credentials]
+//   access_key = "test"
+//   secret_key = "testsecret"
+//
+//   [quota]
+//   stored_bytes = 1073741824
+//
+//   [access]
+//   block_public_acls = true
+//   object_ownership = "BucketOwnerEnforced"
+//
+//   [policy]
+//   Version = "2012-10-17"
+//   [[policy.Statement]]
+//   Effect = "Allow"
+//   Principal = "*"
+//   Action = "s3:GetObject"
+//   Resource = "arn:aws:s3:::assets/public/*"
+//
+//   [[objects]]
+//   key = "fixtures/"
+//   path = "seed/fixtures"
+//
+// The server has one bucket and one key pair, so the bucket, credentials and
+// quotas take the place of the flags of the same name. Access settings and
+// the bucket policy, a JSON string or a table of the same shape, are stored
+// as if PUT over S3, on every start, and a policy S3 would refuse stops the
+// server from starting. Seed objects are copied in from
+// `path`, relative to the file: a file becomes `key`, a directory becomes an
+// object per file under it with `key` as the prefix. Objects that already
+// exist are left alone, so what a test changed survives a restart.
+//
+// Several buckets or users can't be declared yet (see buckets.rs), so a file
+// with `[[buckets]]` or `[[users]]` is refused as having unknown fields
+// rather than set up in part.
+
+#[derive(Debug, Deserialize)]
+#[serde(deny_unknown_fields)]
+pub struct Provision {
+    bucket: Option<String>,
+    credentials: Option<Credentials>,
+    quota: Option<Quota>,
+    access: Option<Access>,
+    policy: Option<PolicyDocument>,
+    #[serde(default)]
+    objects: Vec<Seed>,
+    // Where seed paths are relative to
+    #[serde(skip)]
+    dir: PathBuf,
+}
+
+#[derive(Debug, Deserialize)]
+#[serde(deny_unknown_fields)]
+struct Credentials {
+    access_key: String,
+    secret_key: String,
+}
+
+#[derive(Debug, Deserialize)]
+#[serde(deny_unknown_fields)]
+struct Quota {
+    stored_bytes: Option<u64>,
+    monthly_transfer: Option<u64>,
+}
+
+#[derive(Debug, Deserialize)]
+#[serde(deny_unknown_fields)]
+struct Access {
+    block_public_acls: Option<bool>,
+    ignore_public_acls: Option<bool>,
+    block_public_policy: Option<bool>,
+    restrict_public_buckets: Option<bool>,
+    object_ownership: Option<String>,
+}
+
+impl Access {
+    // The public access block settings, if the file gives any
+    fn public_access_block(&self) -> Option<PublicAccessBlockConfiguration> {
+        let flags = [
+            self.block_public_acls,
+            self.ignore_public_acls,
+            self.block_public_policy,
+            self.restrict_public_buckets,
+        ];
+        flags.iter().any(Option::is_some).then(|| {
+            let [block_acls, ignore_acls, block_policy, restrict] =
+                flags.map(Option::unwrap_or_default);
+            PublicAccessBlockConfiguration::new(block_acls, ignore_acls, block_policy, restrict)
+        })
+    }
+}
+
+// The bucket policy, as the JSON PUT /?policy takes or as TOML
+#[derive(Debug, Deserialize)]
+#[serde(untagged)]
+enum PolicyDocument {
+    Json(String),
+    Table(toml::Table),
+}
+
+impl PolicyDocument {
+    fn to_json(&self) -> String {
+        match self {
+            Self::Json(json) => json.clone(),
+            Self::Table(table) => serde_json::to_string(table).unwrap_or_default(),
+        }
+    }
+}
+
+#[derive(Debug, Deserialize)]
+#[serde(deny_unknown_fields)]
+struct Seed {
+    key: String,
+    path: PathBuf,
+}
+
+#[derive(Default)]
+struct Counts {
+    objects: u64,
+    bytes: u64,
+    existing: u64,
+}
+
+impl Provision {
+    pub fn load(path: &Path) -> Result<Self, String> {
+        let text = fs::read_to_string(path)
+            .map_err(|e| format!("can't read --provision {}: {}", path.display(), e))?;
+        let mut provision: Self = toml::from_str(&text)
+            .map_err(|e| format!("invalid --provision {}: {}", path.display(), e))?;
+        provision.dir = path.parent().unwrap_or(Path::new(".")).to_path_buf();
+
+        if let Some(access) = &provision.access
+            && let Some(ownership) = &access.object_ownership
+            && OwnershipControls::new(ownership).is_none()
+        {
+            return Err(format!(
+                "invalid --provision {}: object_ownership must be BucketOwnerPreferred, ObjectWriter or BucketOwnerEnforced, not {}",
+                path.display(),
+                ownership
+            ));
+        }
+        info!("🧾 Provisioning from {}", path.display());
+        Ok(provision)
+    }
+
+    // Puts the bucket, credentials and quotas in place of the flags
+    pub fn apply_to(&self, args: &mut Args) {
+        if let Some(bucket) = &self.bucket {
+            args.bucket = bucket.clone();
+        }
+        if let Some(credentials) = &self.credentials {
+            args.access_key = credentials.access_key.clone();
+            args.secret_key = credentials.secret_key.clone();
+        }
+        if let Some(quota) = &self.quota {
+            if let Some(stored_bytes) = quota.stored_bytes {
+                args.quota_stored_bytes = stored_bytes;
+            }
+            if let Some(monthly_transfer) = quota.monthly_transfer {
+                args.quota_monthly_transfer = monthly_transfer;
+            }
+        }
+    }
+
+    // Copies in the seed objects that don't exist yet. Runs before the
+    // server starts, like ingest, so there's nothing to record beyond
+    // putting the files in place
+    pub async fn seed(&self, data_dir: &Path, normalize_keys: bool) -> Result<(), Box<dyn Error>> {
+        let mut counts = Counts::default();
+        for seed in &self.objects {
+            let from = self.dir.join(&seed.path);
+            let metadata = fs::metadata(&from)
+                .map_err(|e| format!("seed object {}: {}: {}", seed.key, from.display(), e))?;
+            if !metadata.is_dir() {
+                place(data_dir, &seed.key, &from, normalize_keys, &mut counts).await?;
+                continue;
+            }
+
+            let prefix = match seed.key.as_str() {
+                "" => String::new(),
+                key => format!("{}/", key.trim_end_matches('/')),
+            };
+            let mut dirs = vec![from.clone()];
+            while let Some(dir) = dirs.pop() {
+                for entry in fs::read_dir(&dir)? {
+                    let path = entry?.path();
+                    if path.is_dir() {
+                        dirs.push(path);
+                        continue;
+                    }
+                    let segments = path
+                        .strip_prefix(&from)?
+                        .components()
+                        .map(|component| component.as_os_str().to_str())
+                        .collect::<Option<Vec<_>>>();
+                    let Some(segments) = segments else {
+                        return Err(format!("{} is not valid UTF-8", path.display()).into());
+                    };
+                    let key = format!("{}{}", prefix, segments.join("/"));
+                    place(data_dir, &key, &path, normalize_keys, &mut counts).await?;
+                }
+            }
+        }
+
+        if !self.objects.is_empty() {
+            info!(
+                "🧾 Seeded {} objects ({} bytes), {} already there",
+                counts.objects, counts.bytes, counts.existing
+            );
+        }
+        Ok(())
+    }
+
+    // Stores the access settings, then the policy so BlockPublicPolicy set
+    // in the same file applies to it, once the server's state is loaded
+    pub async fn apply_access(&self, state: &AppState) -> Result<(), Box<dyn Error>> {
+        if let Some(access) = &self.access {
+            if let Some(config) = access.public_access_block() {
+                state.access.public_access_block.store(state, config).await?;
+            }
+            let controls = access.object_ownership.as_deref().and_then(OwnershipControls::new);
+            if let Some(controls) = controls {
+                state.access.ownership_controls.store(state, controls).await?;
+            }
+        }
+        if let Some(document) = &self.policy {
+            policy::store(state, document.to_json().as_bytes())
+                .await
+                .map_err(|refused| format!("--provision policy refused: {}", refused))?;
+            info!("🧾 Provisioned the bucket policy");
+        }
+        Ok(())
+    }
+}
+
+async fn place(
+    data_dir: &Path,
+    key: &str,
+    path: &Path,
+    normalize_keys: bool,
+    counts: &mut Counts,
+) -> Result<(), Box<dyn Error>> {
+    let key: String = if normalize_keys { key.nfc().collect() } else { key.to_string() };
+    let Some(rel) = relative_path(&key).filter(|rel| !rel.as_os_str().is_empty()) else {
+        return Err(format!("seed object {} is not a valid key", key).into());
+    };
+    let target = keys::long_path(data_dir.join(rel));
+    if target.exists() {
+        counts.existing += 1;
+        return Ok(());
+    }
+    if let Some(parent) = target.parent() {
+        fs::create_dir_all(parent)?;
+    }
+    ingest::place_copy(data_dir, path, &target)
+        .await
+        .map_err(|e| format!("seed object {}: {}: {}", key, path.display(), e))?;
+    counts.objects += 1;
+    counts.bytes += fs::metadata(&target)?.len();
+    Ok(())
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    const POLICY: &str = r#"
+        [policy]
+        Version = "2012-10-17"
+        [[policy.Statement]]
+        Effect = "Allow"
+        Principal = "*"
+        Action = "s3:GetObject"
+        Resource = "arn:aws:s3:::simple-bucket/public/*"
+    "#;
+
+    async fn provision(text: &str) -> (PathBuf, AppState, Result<(), String>) {
+        let dir = std::env::temp_dir().join(format!("provision-{}", uuid::Uuid::new_v4()));
+        let state = crate::tests::state(&dir).await;
+        let provision: Provision = toml::from_str(text).unwrap();
+        let applied = provision.apply_access(&state).await.map_err(|e| e.to_string());
+        (dir, state, applied)
+    }
+
+    async fn stored_policy(state: &AppState) -> Option<serde_json::Value> {
+        let response = policy::get(state).await.unwrap();
+        let body = axum::body::to_bytes(response.into_body(), usize::MAX).await.unwrap();
+        serde_json::from_slice(&body).ok()
+    }
+
+    #[tokio::test]
+    async fn policy_is_stored_from_a_table_or_json() {
+        let (dir, state, applied) = provision(POLICY).await;
+        assert!(applied.is_ok());
+        let stored = stored_policy(&state).await.unwrap();
+        assert_eq!(stored["Statement"][0]["Action"], "s3:GetObject");
+        let _ = fs::remove_dir_all(dir);
+
+        let json = r#"policy = '''{"Version": "2012-10-17", "Statement": [{"Effect": "Deny",
+            "Principal": "*", "Action": "s3:DeleteObject", "Resource": "arn:aws:s3:::b/*"}]}'''"#;
+        let (dir, state, applied) = provision(json).await;
+        assert!(applied.is_ok());
+        assert_eq!(stored_policy(&state).await.unwrap()["Statement"][0]["Effect"], "Deny");
+        let _ = fs::remove_dir_all(dir);
+    }
+
+    #[tokio::test]
+    async fn policy_is_checked_as_over_s3() {
+        let blocked = format!("[access]\nblock_public_policy = true\n{}", POLICY);
+        let (dir, state, applied) = provision(&blocked).await;
+        assert!(applied.unwrap_err().contains("BlockPublicPolicy"));
+        assert!(stored_policy(&state).await.is_none());
+        let _ = fs::remove_dir_all(dir);
+
+        let (dir, _, applied) = provision(&POLICY.replace("Allow", "Perhaps")).await;
+        assert!(applied.is_err());
+        let _ = fs::remove_dir_all(dir);
+    }
+}
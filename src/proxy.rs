@@ -0,0 +1,166 @@
+use axum::{
+    extract::{ConnectInfo, Request, State},
+    http::{HeaderMap, HeaderValue},
+    middleware::Next,
+    response::Response,
+};
+use std::{
+    fmt,
+    net::{IpAddr, SocketAddr},
+    str::FromStr,
+    sync::Arc,
+};
+
+use crate::AppState;
+
+// Reverse proxy awareness. Requests arriving from an address in
+// --trusted-proxies have their X-Forwarded-For, X-Forwarded-Proto and
+// X-Forwarded-Host headers believed: the client address is the rightmost
+// X-Forwarded-For entry that isn't itself a trusted proxy, and the Host
+// header is replaced with X-Forwarded-Host so SigV4 checks the host the
+// client signed. From anyone else these headers are ignored.
+
+#[derive(Debug, Clone, Copy, serde::Deserialize)]
+#[serde(try_from = "String")]
+pub struct Cidr {
+    addr: IpAddr,
+    prefix: u8,
+}
+
+impl FromStr for Cidr {
+    type Err = String;
+
+    fn from_str(s: &str) -> Result<Self, Self::Err> {
+        let (addr, prefix) = match s.trim().split_once('/') {
+            Some((addr, prefix)) => (addr, Some(prefix)),
+            None => (s.trim(), None),
+        };
+        let addr: IpAddr = addr
+            .parse()
+            .map_err(|_| format!("{:?} is not an IP address or CIDR range", s))?;
+        let max = if addr.is_ipv4() { 32 } else { 128 };
+        let prefix = match prefix {
+            Some(prefix) => prefix
+                .parse::<u8>()
+                .ok()
+                .filter(|prefix| *prefix <= max)
+                .ok_or_else(|| format!("bad prefix length in {:?}", s))?,
+            None => max,
+        };
+        Ok(Self {
+            addr: addr.to_canonical(),
+            prefix,
+        })
+    }
+}
+
+impl TryFrom<String> for Cidr {
+    type Error = String;
+
+    fn try_from(s: String) -> Result<Self, Self::Error> {
+        s.parse()
+    }
+}
+
+impl fmt::Display for Cidr {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        write!(f, "{}/{}", self.addr, self.prefix)
+    }
+}
+
+impl Cidr {
+    pub fn contains(&self, ip: IpAddr) -> bool {
+        match (self.addr, ip.to_canonical()) {
+            (IpAddr::V4(net), IpAddr::V4(ip)) => {
+                let mask = u32::MAX.checked_shl(32 - self.prefix as u32).unwrap_or(0);
+                u32::from(net) & mask == u32::from(ip) & mask
+            }
+            (IpAddr::V6(net), IpAddr::V6(ip)) => {
+                let mask = u128::MAX.checked_shl(128 - self.prefix as u32).unwrap_or(0);
+                u128::from(net) & mask == u128::from(ip) & mask
+            }
+            _ => false,
+        }
+    }
+}
+
+// Who a request really came from, added to every request's extensions
+#[derive(Debug, Clone)]
+pub struct ClientInfo {
+    pub ip: Option<IpAddr>,
+    pub scheme: String,
+}
+
+impl fmt::Display for ClientInfo {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        match self.ip {
+            Some(ip) => write!(f, "{} ({})", ip, self.scheme),
+            None => write!(f, "unknown client ({})", self.scheme),
+        }
+    }
+}
+
+fn trusted(state: &AppState, ip: IpAddr) -> bool {
+    state.trusted_proxies.iter().any(|cidr| cidr.contains(ip))
+}
+
+fn first_value<'a>(headers: &'a HeaderMap, name: &str) -> Option<&'a str> {
+    headers
+        .get(name)?
+        .to_str()
+        .ok()?
+        .split(',')
+        .next()
+        .map(str::trim)
+        .filter(|value| !value.is_empty())
+}
+
+pub async fn forwarded_middleware(
+    State(state): State<Arc<AppState>>,
+    mut request: Request,
+    next: Next,
+) -> Response {
+    let peer = request
+        .extensions()
+        .get::<ConnectInfo<SocketAddr>>()
+        .map(|ConnectInfo(addr)| addr.ip().to_canonical());
+
+    let mut client = ClientInfo {
+        ip: peer,
+        scheme: "http".to_string(),
+    };
+
+    if peer.is_some_and(|peer| trusted(&state, peer)) {
+        let headers = request.headers();
+
+        let forwarded_for: Vec<IpAddr> = headers
+            .get_all("x-forwarded-for")
+            .iter()
+            .filter_map(|value| value.to_str().ok())
+            .flat_map(|value| value.split(','))
+            .filter_map(|addr| addr.trim().parse::<IpAddr>().ok())
+            .map(|addr| addr.to_canonical())
+            .collect();
+        if let Some(ip) = forwarded_for
+            .iter()
+            .rev()
+            .find(|ip| !trusted(&state, **ip))
+            .or(forwarded_for.first())
+        {
+            client.ip = Some(*ip);
+        }
+
+        if let Some(proto) = first_value(headers, "x-forwarded-proto") {
+            client.scheme = proto.to_ascii_lowercase();
+        }
+
+        if let Some(host) = first_value(headers, "x-forwarded-host")
+            .and_then(|host| HeaderValue::from_str(host).ok())
+        {
+            request.headers_mut().insert("host", host);
+        }
+    }
+
+    request.extensions_mut().insert(client);
+    next.run(request).await
+}
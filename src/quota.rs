@@ -0,0 +1,117 @@
+use axum::{
+    Json,
+    extract::{Request, State},
+    http::{Method, StatusCode},
+    middleware::Next,
+    response::Response,
+};
+use serde::Serialize;
+use std::sync::Arc;
+use tracing::{info, warn};
+
+use crate::{AppState, ErrorResponse, error_response};
+
+// Per access key quotas. --quota-stored-bytes caps how much an access key's
+// objects may take up and --quota-monthly-transfer caps the bytes it uploads
+// plus downloads in a calendar month (UTC), as counted by the usage ledger.
+// Once either is reached, writes are refused with 403 QuotaExceeded; reads
+// and deletes keep working so data can still be taken out. Objects don't
+// record which key wrote them and the server has a single key, so a key's
+// stored bytes are the bucket's total.
+//
+// Limits per key and per bucket wait on there being more than one of either
+// (see buckets.rs), so these are the limits of the one key.
+
+#[derive(Debug, Serialize)]
+pub struct KeyUsage {
+    pub access_key: String,
+    pub stored_bytes: u64,
+    // 0 means no limit
+    pub stored_limit: u64,
+    pub month: String,
+    pub transferred_bytes: u64,
+    pub transfer_limit: u64,
+}
+
+pub struct Quotas {
+    stored_limit: u64,
+    transfer_limit: u64,
+}
+
+impl Quotas {
+    pub fn new(stored_limit: u64, transfer_limit: u64) -> Self {
+        if stored_limit > 0 || transfer_limit > 0 {
+            info!(
+                "🪣 Quotas per access key: {} stored, {} transferred a month (0 is no limit)",
+                stored_limit, transfer_limit
+            );
+        }
+        Self {
+            stored_limit,
+            transfer_limit,
+        }
+    }
+
+    pub fn usage(&self, state: &AppState, key: &str) -> KeyUsage {
+        KeyUsage {
+            access_key: key.to_string(),
+            stored_bytes: state.stats.totals().bytes,
+            stored_limit: self.stored_limit,
+            month: chrono::Utc::now().format("%Y-%m").to_string(),
+            transferred_bytes: state.usage.transferred(key),
+            transfer_limit: self.transfer_limit,
+        }
+    }
+
+    // Why a write of `incoming` bytes (if known) by `key` is refused, if it is
+    fn refusal(&self, state: &AppState, key: &str, incoming: Option<u64>) -> Option<&'static str> {
+        let usage = self.usage(state, key);
+        let stored_after = usage.stored_bytes + incoming.unwrap_or(0);
+        if self.stored_limit > 0
+            && (usage.stored_bytes >= self.stored_limit || stored_after > self.stored_limit)
+        {
+            return Some("The access key's storage quota is used up");
+        }
+        if self.transfer_limit > 0 && usage.transferred_bytes >= self.transfer_limit {
+            return Some("The access key's monthly transfer quota is used up");
+        }
+        None
+    }
+}
+
+fn quota_exceeded(message: &str) -> Response {
+    error_response(
+        StatusCode::FORBIDDEN,
+        ErrorResponse {
+            code: "QuotaExceeded".to_string(),
+            message: message.to_string(),
+            max_size_allowed: None,
+        },
+    )
+}
+
+// Refuses writes over quota. Runs after auth, so the request was made with
+// the server's access key
+pub async fn quota_middleware(
+    State(state): State<Arc<AppState>>,
+    request: Request,
+    next: Next,
+) -> Response {
+    if matches!(*request.method(), Method::PUT | Method::POST) {
+        let declared = request
+            .headers()
+            .get("content-length")
+            .and_then(|v| v.to_str().ok())
+            .and_then(|v| v.parse::<u64>().ok());
+        if let Some(message) = state.quotas.refusal(&state, &state.access_key, declared) {
+            warn!("🪣 Refused write over quota: {}", message);
+            return quota_exceeded(message);
+        }
+    }
+    next.run(request).await
+}
+
+// GET /admin/quotas on the admin port
+pub async fn quota_usage(State(state): State<Arc<AppState>>) -> Json<Vec<KeyUsage>> {
+    Json(vec![state.quotas.usage(&state, &state.access_key)])
+}
@@ -0,0 +1,175 @@
+use std::{
+    collections::HashMap,
+    fs::File,
+    io,
+    net::IpAddr,
+    path::{Path, PathBuf},
+    sync::Mutex,
+    time::{Duration, Instant},
+};
+use tracing::debug;
+
+use crate::handles::Handle;
+
+// Readahead for sequential range reads. Video players and other streaming
+// clients fetch a large object as a run of small Range requests, each
+// starting where the last one ended. When a client's range of an object
+// carries on from its previous one, the next WINDOW_FACTOR ranges' worth of
+// the file, at most MAX_WINDOW, is brought into the page cache from the open
+// handle in the background, so the next request is served from memory
+// rather than waiting on the disk. What's been prefetched is remembered, so
+// a steady stream reads each part of the file ahead once. Clients are told
+// apart by address, and a stream is forgotten after STREAM_TTL without a
+// request.
+
+const STREAM_TTL: Duration = Duration::from_secs(30);
+const MAX_STREAMS: usize = 1024;
+const WINDOW_FACTOR: u64 = 4;
+const MAX_WINDOW: u64 = 16 * 1024 * 1024;
+// Where the page cache can't be told to read ahead, the window is read in
+// chunks of this size
+#[cfg(not(any(target_os = "linux", target_os = "android", target_os = "freebsd")))]
+const CHUNK: usize = 1024 * 1024;
+
+struct Stream {
+    // Where the next range starts if it carries on from the last
+    next: u64,
+    // The end of what's been read ahead
+    prefetched: u64,
+    seen: Instant,
+}
+
+#[derive(Default)]
+pub struct Readahead {
+    streams: Mutex<HashMap<(Option<IpAddr>, PathBuf), Stream>>,
+}
+
+impl Readahead {
+    // Notes that `ip` read bytes `start..=end` of the `len` bytes at `path`,
+    // and returns the offset and length to read ahead if that carried on from
+    // its last range
+    fn window(
+        &self,
+        ip: Option<IpAddr>,
+        path: &Path,
+        start: u64,
+        end: u64,
+        len: u64,
+    ) -> Option<(u64, u64)> {
+        let now = Instant::now();
+        let mut streams = self.streams.lock().unwrap();
+        if streams.len() >= MAX_STREAMS {
+            streams.retain(|_, stream| now.duration_since(stream.seen) < STREAM_TTL);
+            if streams.len() >= MAX_STREAMS {
+                streams.clear();
+            }
+        }
+
+        let stream = streams.entry((ip, path.to_path_buf())).or_insert(Stream {
+            next: u64::MAX,
+            prefetched: 0,
+            seen: now,
+        });
+        let sequential = stream.next == start && now.duration_since(stream.seen) < STREAM_TTL;
+        stream.next = end + 1;
+        stream.seen = now;
+        if !sequential {
+            stream.prefetched = 0;
+            return None;
+        }
+
+        let window = ((end + 1 - start) * WINDOW_FACTOR).min(MAX_WINDOW);
+        let from = (end + 1).max(stream.prefetched);
+        let to = (end + 1 + window).min(len);
+        if from >= to {
+            return None;
+        }
+        stream.prefetched = to;
+        Some((from, to - from))
+    }
+
+    // Reads ahead of a range just read, if it carried on from the last
+    pub fn after(&self, ip: Option<IpAddr>, path: &Path, handle: &Handle, start: u64, end: u64) {
+        let Some((offset, len)) = self.window(ip, path, start, end, handle.len) else {
+            return;
+        };
+        let file = handle.file.clone();
+        let path = path.to_path_buf();
+        tokio::task::spawn_blocking(move || {
+            if let Err(e) = prefetch(&file, offset, len) {
+                debug!("Failed to read ahead in {}: {}", path.display(), e);
+            }
+        });
+    }
+}
+
+#[cfg(any(target_os = "linux", target_os = "android", target_os = "freebsd"))]
+fn prefetch(file: &File, offset: u64, len: u64) -> io::Result<()> {
+    use std::os::fd::AsRawFd;
+    // SAFETY: the descriptor belongs to `file`, which is open for the call
+    let advised = unsafe {
+        libc::posix_fadvise(
+            file.as_raw_fd(),
+            offset as libc::off_t,
+            len as libc::off_t,
+            libc::POSIX_FADV_WILLNEED,
+        )
+    };
+    match advised {
+        0 => Ok(()),
+        errno => Err(io::Error::from_raw_os_error(errno)),
+    }
+}
+
+// Reading the window is what brings it into the page cache here
+#[cfg(not(any(target_os = "linux", target_os = "android", target_os = "freebsd")))]
+fn prefetch(file: &File, offset: u64, len: u64) -> io::Result<()> {
+    let mut buf = vec![0; CHUNK.min(len as usize)];
+    let mut read = 0;
+    while read < len {
+        let n = buf.len().min((len - read) as usize);
+        crate::read_at(file, &mut buf[..n], offset + read)?;
+        read += n as u64;
+    }
+    Ok(())
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn only_ranges_that_carry_on_read_ahead() {
+        let readahead = Readahead::default();
+        let path = Path::new("video.mp4");
+        let len = 100 * 1024 * 1024;
+
+        assert_eq!(readahead.window(None, path, 0, 1023, len), None);
+        assert_eq!(readahead.window(None, path, 1024, 2047, len), Some((2048, 4096)));
+        // What's already been read ahead isn't read again
+        assert_eq!(readahead.window(None, path, 2048, 3071, len), Some((6144, 1024)));
+        // A seek starts over
+        assert_eq!(readahead.window(None, path, 50_000, 50_999, len), None);
+        // Another client's ranges are its own
+        let other = Some(IpAddr::from([10, 0, 0, 1]));
+        assert_eq!(readahead.window(other, path, 51_000, 51_999, len), None);
+    }
+
+    #[test]
+    fn the_window_stops_at_the_end_and_at_its_limit() {
+        let readahead = Readahead::default();
+        let path = Path::new("video.mp4");
+
+        readahead.window(None, path, 0, 999, 3000);
+        assert_eq!(readahead.window(None, path, 1000, 1999, 3000), Some((2000, 1000)));
+        assert_eq!(readahead.window(None, path, 2000, 2999, 3000), None);
+
+        let len = 1 << 40;
+        let chunk = 8 * 1024 * 1024;
+        readahead.window(None, path, 0, chunk - 1, len);
+        assert_eq!(
+            readahead.window(None, path, chunk, 2 * chunk - 1, len),
+            Some((2 * chunk, MAX_WINDOW))
+        );
+    }
+}
@@ -0,0 +1,360 @@
+use axum::{
+    Json,
+    extract::{Query, Request, State},
+    http::{Method, StatusCode},
+    middleware::Next,
+    response::Response,
+};
+use percent_encoding::{percent_decode_str, utf8_percent_encode};
+use serde::{Deserialize, Serialize};
+use std::{
+    path::PathBuf,
+    sync::Arc,
+    time::Duration,
+};
+use tokio::{
+    fs,
+    io::AsyncWriteExt,
+    sync::Mutex,
+};
+use tracing::{info, warn};
+
+use crate::{
+    AppState, KEY_ENCODE_SET, multipart, object_path, remove_empty_parents, send_signed, stats, tmp_path,
+    walk_objects,
+    write_and_rename,
+};
+
+// Read replicas. A primary started with --change-feed appends every object
+// PUT and DELETE made through the S3 API or tus to a log, numbered from 1,
+// and serves it at CHANGES_PATH. A follower started with --replicate-from
+// polls that log, fetches each changed object from the primary and applies
+// it locally, remembering how far it got so a restart resumes where it
+// stopped. Followers refuse writes on every protocol.
+//
+// The log is seeded with the objects already on disk when it is first
+// created, so a new follower catches up from scratch. It is never compacted.
+
+pub const CHANGES_PATH: &str = "/.simple-s3/changes";
+const CHANGES_FILE: &str = ".simple-s3/changes.log";
+const REPLICA_SEQ_FILE: &str = ".simple-s3/replica-seq";
+const POLL_INTERVAL: Duration = Duration::from_secs(2);
+pub const MAX_BATCH: usize = 1000;
+
+#[derive(Debug, Clone, Copy, PartialEq, Serialize, Deserialize)]
+#[serde(rename_all = "lowercase")]
+pub enum Op {
+    Put,
+    Delete,
+}
+
+#[derive(Debug, Serialize, Deserialize)]
+pub struct Change {
+    pub seq: u64,
+    pub op: Op,
+    pub key: String,
+}
+
+#[derive(Debug, Serialize, Deserialize)]
+pub struct ChangeBatch {
+    pub changes: Vec<Change>,
+}
+
+#[derive(Debug, Deserialize)]
+pub struct ChangesQuery {
+    since: Option<u64>,
+    limit: Option<usize>,
+}
+
+pub struct ChangeFeed {
+    path: PathBuf,
+    // The open log and the last sequence number written to it
+    log: Mutex<(fs::File, u64)>,
+}
+
+impl ChangeFeed {
+    pub async fn open(state: &AppState) -> std::io::Result<Self> {
+        let path = state.data_dir.join(CHANGES_FILE);
+        let existing = fs::read_to_string(&path).await.ok();
+
+        let mut file = fs::OpenOptions::new()
+            .create(true)
+            .append(true)
+            .open(&path)
+            .await?;
+
+        let seq = match existing {
+            Some(log) => log.lines().filter(|line| !line.is_empty()).count() as u64,
+            None => {
+                let keys: Vec<String> = walk_objects(state)
+                    .await
+                    .into_iter()
+                    .map(|(key, _)| key)
+                    .collect();
+
+                let mut seeded = String::new();
+                for (i, key) in keys.iter().enumerate() {
+                    seeded.push_str(&entry(i as u64 + 1, Op::Put, key));
+                }
+                file.write_all(seeded.as_bytes()).await?;
+                file.flush().await?;
+
+                info!("📜 Started change feed with {} existing objects", keys.len());
+                keys.len() as u64
+            }
+        };
+
+        Ok(Self {
+            path,
+            log: Mutex::new((file, seq)),
+        })
+    }
+
+    pub async fn record(&self, op: Op, key: &str) {
+        let mut log = self.log.lock().await;
+        let seq = log.1 + 1;
+
+        let written = async {
+            log.0.write_all(entry(seq, op, key).as_bytes()).await?;
+            log.0.flush().await
+        }
+        .await;
+
+        match written {
+            Ok(()) => log.1 = seq,
+            Err(e) => warn!("Failed to record change for {}: {}", key, e),
+        }
+    }
+
+    pub async fn read(&self, since: u64, limit: usize) -> std::io::Result<Vec<Change>> {
+        let log = fs::read_to_string(&self.path).await?;
+
+        Ok(log
+            .lines()
+            .filter_map(|line| serde_json::from_str::<Change>(line).ok())
+            .filter(|change| change.seq > since)
+            .take(limit)
+            .collect())
+    }
+}
+
+fn entry(seq: u64, op: Op, key: &str) -> String {
+    let change = Change {
+        seq,
+        op,
+        key: key.to_string(),
+    };
+    format!("{}\n", serde_json::to_string(&change).unwrap())
+}
+
+// GET /.simple-s3/changes?since=N: changes after sequence number N
+pub async fn changes(
+    State(state): State<Arc<AppState>>,
+    Query(query): Query<ChangesQuery>,
+) -> Result<Json<ChangeBatch>, StatusCode> {
+    let feed = state.changes.as_ref().ok_or(StatusCode::NOT_FOUND)?;
+    let limit = query.limit.unwrap_or(MAX_BATCH).min(MAX_BATCH);
+
+    let changes = feed
+        .read(query.since.unwrap_or(0), limit)
+        .await
+        .map_err(|_| StatusCode::INTERNAL_SERVER_ERROR)?;
+
+    Ok(Json(ChangeBatch { changes }))
+}
+
+fn is_write(method: &Method) -> bool {
+    matches!(*method, Method::PUT | Method::POST | Method::DELETE)
+}
+
+// Record successful object writes in the change feed
+pub async fn record_changes(
+    State(state): State<Arc<AppState>>,
+    request: Request,
+    next: Next,
+) -> Response {
+    let Some(feed) = state.changes.clone() else {
+        return next.run(request).await;
+    };
+
+    let method = request.method().clone();
+    let upload_step = multipart::is_upload_step(&method, request.uri().query());
+    let key = percent_decode_str(request.uri().path().trim_start_matches('/'))
+        .decode_utf8_lossy()
+        .to_string();
+
+    let response = next.run(request).await;
+
+    if is_write(&method)
+        && !upload_step
+        && response.status().is_success()
+        && object_path(&state, &key).is_ok()
+    {
+        let op = if method == Method::DELETE {
+            Op::Delete
+        } else {
+            Op::Put
+        };
+        feed.record(op, &key).await;
+    }
+
+    response
+}
+
+// Followers only serve reads
+pub async fn read_only_middleware(
+    State(state): State<Arc<AppState>>,
+    request: Request,
+    next: Next,
+) -> Result<Response, StatusCode> {
+    if state.read_only && !matches!(*request.method(), Method::GET | Method::HEAD) {
+        warn!("🚫 Rejected {} on read replica", request.method());
+        return Err(StatusCode::FORBIDDEN);
+    }
+
+    Ok(next.run(request).await)
+}
+
+pub fn spawn_follower(state: Arc<AppState>, primary: String) {
+    let primary = primary.trim().trim_end_matches('/').to_string();
+    let primary = if primary.contains("://") {
+        primary
+    } else {
+        format!("http://{}", primary)
+    };
+
+    tokio::spawn(async move {
+        let client = match reqwest::Client::builder()
+            .connect_timeout(POLL_INTERVAL)
+            .build()
+        {
+            Ok(client) => client,
+            Err(e) => {
+                warn!("Failed to start replication: {}", e);
+                return;
+            }
+        };
+
+        let seq_path = state.data_dir.join(REPLICA_SEQ_FILE);
+        let mut seq: u64 = fs::read_to_string(&seq_path)
+            .await
+            .ok()
+            .and_then(|seq| seq.trim().parse().ok())
+            .unwrap_or(0);
+
+        info!("📜 Replicating from {} after change {}", primary, seq);
+
+        let mut interval = tokio::time::interval(POLL_INTERVAL);
+        loop {
+            interval.tick().await;
+
+            match pull(&state, &client, &primary, seq).await {
+                Ok(applied) if applied > seq => {
+                    info!("📜 Applied changes {}..={} from primary", seq + 1, applied);
+                    seq = applied;
+                    if let Err(e) = fs::write(&seq_path, seq.to_string()).await {
+                        warn!("Failed to save replication position: {}", e);
+                    }
+                }
+                Ok(_) => {}
+                Err(e) => warn!("📜 Replication from {} failed: {}", primary, e),
+            }
+        }
+    });
+}
+
+// Apply the next batch of changes, returning the last sequence number applied.
+// A failed change stops the batch so it's retried on the next poll
+async fn pull(
+    state: &AppState,
+    client: &reqwest::Client,
+    primary: &str,
+    since: u64,
+) -> Result<u64, String> {
+    let batch: ChangeBatch = send_signed(
+        state,
+        client
+            .get(format!("{}{}", primary, CHANGES_PATH))
+            .query(&[("since", since)]),
+    )
+    .await
+        .and_then(|response| response.error_for_status())
+        .map_err(|e| e.to_string())?
+        .json()
+        .await
+        .map_err(|e| e.to_string())?;
+
+    let mut applied = since;
+    for change in batch.changes {
+        if let Err(e) = apply(state, client, primary, &change).await {
+            if applied > since {
+                warn!("📜 Failed to apply change {} ({}): {}", change.seq, change.key, e);
+                return Ok(applied);
+            }
+            return Err(format!("change {} ({}): {}", change.seq, change.key, e));
+        }
+        applied = change.seq;
+    }
+
+    Ok(applied)
+}
+
+async fn apply(
+    state: &AppState,
+    client: &reqwest::Client,
+    primary: &str,
+    change: &Change,
+) -> Result<(), String> {
+    let Ok(file_path) = object_path(state, &change.key) else {
+        warn!("📜 Skipping change for invalid key {}", change.key);
+        return Ok(());
+    };
+
+    if change.op == Op::Delete {
+        let old = stats::size(&file_path).await;
+        return match fs::remove_file(&file_path).await {
+            Ok(()) => {
+                state.stats.replaced(old, None);
+                state.handles.invalidate(&file_path);
+                remove_empty_parents(state, &file_path).await;
+                Ok(())
+            }
+            Err(e) if e.kind() == std::io::ErrorKind::NotFound => Ok(()),
+            Err(e) => Err(e.to_string()),
+        };
+    }
+
+    let response = send_signed(
+        state,
+        client.get(format!(
+            "{}/{}",
+            primary,
+            utf8_percent_encode(&change.key, KEY_ENCODE_SET)
+        )),
+    )
+    .await
+        .map_err(|e| e.to_string())?;
+
+    // Deleted again since; a later change in the feed says so
+    if response.status() == reqwest::StatusCode::NOT_FOUND {
+        return Ok(());
+    }
+    let bytes = response
+        .error_for_status()
+        .map_err(|e| e.to_string())?
+        .bytes()
+        .await
+        .map_err(|e| e.to_string())?;
+
+    if let Some(parent) = file_path.parent() {
+        fs::create_dir_all(parent).await.map_err(|e| e.to_string())?;
+    }
+    let old = stats::size(&file_path).await;
+    write_and_rename(state, &tmp_path(state), &file_path, &bytes)
+        .await
+        .map_err(|e| e.to_string())?;
+    state.stats.replaced(old, Some(bytes.len() as u64));
+    state.handles.invalidate(&file_path);
+
+    Ok(())
+}
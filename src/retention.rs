@@ -0,0 +1,420 @@
+use axum::{
+    body::Bytes,
+    http::{HeaderMap, HeaderValue, StatusCode},
+    response::{IntoResponse, Response},
+};
+use chrono::{DateTime, SecondsFormat, Utc};
+use serde::{Deserialize, Serialize};
+use sha2::{Digest, Sha256};
+use std::{
+    io,
+    path::{Path, PathBuf},
+};
+use tokio::fs;
+use tracing::{info, warn};
+
+use crate::{AppState, ErrorResponse, error_response, normalize, tmp_path, write_and_rename};
+
+// Object Lock, per object. A PUT, copy or multipart upload can lock what it
+// writes with x-amz-object-lock-mode and -retain-until-date, and with
+// x-amz-object-lock-legal-hold, and the ?retention and ?legal-hold
+// subresources read and change the lock afterwards. While an object is
+// retained or under a legal hold, it can't be overwritten or deleted, over
+// S3 or any other way in, like objects under --worm. GOVERNANCE retention
+// gives way to requests with x-amz-bypass-governance-retention: true, and
+// can be shortened or lifted with it. COMPLIANCE retention can only be
+// extended. A legal hold has no end, it stays until it's set OFF.
+//
+// Locks are kept under RETENTION_DIR, one file per key. As a locked object
+// can't change, they aren't stamped like tags; an S3 write replaces the lock
+// with the one it asks for, if any, and an S3 delete forgets it.
+
+pub const RETENTION_DIR: &str = ".simple-s3/retention";
+const GOVERNANCE: &str = "GOVERNANCE";
+const COMPLIANCE: &str = "COMPLIANCE";
+const XMLNS: &str = "http://s3.amazonaws.com/doc/2006-03-01/";
+
+#[derive(Debug, Clone, Default, Serialize, Deserialize)]
+pub struct Lock {
+    mode: Option<String>,
+    retain_until: Option<DateTime<Utc>>,
+    legal_hold: bool,
+}
+
+impl Lock {
+    fn is_empty(&self) -> bool {
+        self.mode.is_none() && !self.legal_hold
+    }
+
+    fn retained(&self) -> bool {
+        self.retain_until.is_some_and(|until| until > Utc::now())
+    }
+
+    // Whether the lock keeps the object as it is
+    fn protects(&self, bypass_governance: bool) -> bool {
+        let bypassed = bypass_governance && self.mode.as_deref() == Some(GOVERNANCE);
+        self.legal_hold || (self.retained() && !bypassed)
+    }
+}
+
+#[derive(Debug, Serialize, Deserialize)]
+#[serde(rename = "Retention")]
+struct Retention {
+    #[serde(rename = "@xmlns", default)]
+    xmlns: String,
+    #[serde(rename = "Mode", skip_serializing_if = "Option::is_none")]
+    mode: Option<String>,
+    #[serde(rename = "RetainUntilDate", skip_serializing_if = "Option::is_none")]
+    retain_until_date: Option<String>,
+}
+
+#[derive(Debug, Serialize, Deserialize)]
+#[serde(rename = "LegalHold")]
+struct LegalHold {
+    #[serde(rename = "@xmlns", default)]
+    xmlns: String,
+    #[serde(rename = "Status")]
+    status: String,
+}
+
+#[derive(Debug, Serialize)]
+#[serde(rename = "ObjectLockConfiguration")]
+struct ObjectLockConfiguration {
+    #[serde(rename = "@xmlns")]
+    xmlns: String,
+    #[serde(rename = "ObjectLockEnabled")]
+    object_lock_enabled: String,
+}
+
+fn refusal(status: StatusCode, code: &str, message: &str) -> Response {
+    error_response(
+        status,
+        ErrorResponse {
+            code: code.to_string(),
+            message: message.to_string(),
+            max_size_allowed: None,
+        },
+    )
+}
+
+fn invalid_argument(message: &str) -> Response {
+    refusal(StatusCode::BAD_REQUEST, "InvalidArgument", message)
+}
+
+fn malformed_xml() -> Response {
+    refusal(
+        StatusCode::BAD_REQUEST,
+        "MalformedXML",
+        "The XML you provided was not well-formed or did not validate against our published schema",
+    )
+}
+
+fn no_such_key() -> Response {
+    refusal(
+        StatusCode::NOT_FOUND,
+        "NoSuchKey",
+        "The specified key does not exist",
+    )
+}
+
+// The refusal for a change the lock of `key` doesn't allow
+pub fn locked(key: &str) -> Response {
+    warn!("🔏 Refused to change {}, it's under an object lock", key);
+    refusal(
+        StatusCode::FORBIDDEN,
+        "AccessDenied",
+        "Access Denied because object protected by object lock.",
+    )
+}
+
+fn parse_mode(value: &str) -> Option<String> {
+    [GOVERNANCE, COMPLIANCE]
+        .into_iter()
+        .find(|mode| mode.eq_ignore_ascii_case(value.trim()))
+        .map(str::to_string)
+}
+
+fn iso8601(until: &DateTime<Utc>) -> String {
+    until.to_rfc3339_opts(SecondsFormat::Millis, true)
+}
+
+fn retain_until(value: &str) -> Option<DateTime<Utc>> {
+    DateTime::parse_from_rfc3339(value.trim())
+        .ok()
+        .map(|until| until.with_timezone(&Utc))
+}
+
+const BOTH: &str =
+    "x-amz-object-lock-retain-until-date and x-amz-object-lock-mode must both be supplied";
+
+// The retention a mode and retain-until date ask for, none without either,
+// or why they can't be an object's
+fn requested_retention(
+    mode: Option<&str>,
+    retain_until_date: Option<&str>,
+) -> Result<(Option<String>, Option<DateTime<Utc>>), &'static str> {
+    let (mode, until) = match (mode, retain_until_date) {
+        (None, None) => return Ok((None, None)),
+        (Some(mode), Some(until)) => (mode, until),
+        _ => return Err(BOTH),
+    };
+    let mode = parse_mode(mode).ok_or("Unknown wormMode directive.")?;
+    let until = retain_until(until)
+        .ok_or("The retain until date must be provided in ISO 8601 format")?;
+    if until <= Utc::now() {
+        return Err("The retain until date must be in the future!");
+    }
+    Ok((Some(mode), Some(until)))
+}
+
+fn parse_legal_hold(value: &str) -> Option<bool> {
+    match value.trim() {
+        "ON" => Some(true),
+        "OFF" => Some(false),
+        _ => None,
+    }
+}
+
+// The lock the x-amz-object-lock-* headers ask for, None without any
+fn header_lock(headers: &HeaderMap) -> Option<Result<Lock, &'static str>> {
+    let header = |name: &str| headers.get(name).map(|value| value.to_str().unwrap_or_default());
+    let mode = header("x-amz-object-lock-mode");
+    let until = header("x-amz-object-lock-retain-until-date");
+    let hold = header("x-amz-object-lock-legal-hold");
+    if mode.is_none() && until.is_none() && hold.is_none() {
+        return None;
+    }
+    let lock = requested_retention(mode, until).and_then(|(mode, retain_until)| {
+        let legal_hold = hold
+            .map_or(Some(false), parse_legal_hold)
+            .ok_or("Legal Hold must be either of 'ON' or 'OFF'")?;
+        Ok(Lock {
+            mode,
+            retain_until,
+            legal_hold,
+        })
+    });
+    Some(lock)
+}
+
+// The refusal for x-amz-object-lock-* headers that don't make a lock, None
+// if the write may go ahead
+pub fn refuse_invalid(headers: &HeaderMap) -> Option<Response> {
+    match header_lock(headers)? {
+        Ok(_) => None,
+        Err(message) => Some(invalid_argument(message)),
+    }
+}
+
+// The lock a write asks for, once refuse_invalid let it through
+pub fn from_headers(headers: &HeaderMap) -> Option<Lock> {
+    header_lock(headers)?.ok()
+}
+
+// Whether a request asks to get past GOVERNANCE retention
+pub fn bypasses(headers: &HeaderMap) -> bool {
+    headers
+        .get("x-amz-bypass-governance-retention")
+        .and_then(|bypass| bypass.to_str().ok())
+        .is_some_and(|bypass| bypass.trim().eq_ignore_ascii_case("true"))
+}
+
+fn lock_path(state: &AppState, key: &str) -> PathBuf {
+    let key = normalize::stored_key(state, key);
+    state
+        .data_dir
+        .join(RETENTION_DIR)
+        .join(hex::encode(Sha256::digest(key.as_bytes())))
+}
+
+async fn load(state: &AppState, key: &str) -> Lock {
+    let Ok(data) = fs::read(lock_path(state, key)).await else {
+        return Lock::default();
+    };
+    serde_json::from_slice(&data).unwrap_or_default()
+}
+
+async fn store(state: &AppState, key: &str, lock: &Lock) -> io::Result<()> {
+    let path = lock_path(state, key);
+    if lock.is_empty() {
+        return match fs::remove_file(&path).await {
+            Err(e) if e.kind() != io::ErrorKind::NotFound => Err(e),
+            _ => Ok(()),
+        };
+    }
+    let json = serde_json::to_vec(lock).map_err(io::Error::other)?;
+    fs::create_dir_all(state.data_dir.join(RETENTION_DIR)).await?;
+    let tmp = tmp_path(state);
+    let written = write_and_rename(state, &tmp, &path, &json).await;
+    if written.is_err() {
+        let _ = fs::remove_file(&tmp).await;
+    }
+    written
+}
+
+// Puts the lock a write asked for on the object it wrote, replacing the one
+// it had. The write has already succeeded and can't be refused any more
+pub async fn store_or_warn(state: &AppState, key: &str, lock: Option<Lock>) {
+    let lock = lock.unwrap_or_default();
+    if let Err(e) = store(state, key, &lock).await {
+        warn!("Failed to keep the object lock of {}: {}", key, e);
+    } else if !lock.is_empty() {
+        info!("🔏 Locked {}", key);
+    }
+}
+
+// Forgets the lock of an object that's been deleted
+pub async fn forget(state: &AppState, key: &str) {
+    let _ = fs::remove_file(lock_path(state, key)).await;
+}
+
+// Whether the lock of `key` keeps it as it is
+pub async fn protects(state: &AppState, key: &str, bypass_governance: bool) -> bool {
+    load(state, key).await.protects(bypass_governance)
+}
+
+// The x-amz-object-lock-* headers for a GET or HEAD of a locked object
+pub async fn insert_headers(state: &AppState, key: &str, headers: &mut HeaderMap) {
+    let lock = load(state, key).await;
+    if let (Some(mode), Some(until)) = (&lock.mode, &lock.retain_until) {
+        if let Ok(mode) = HeaderValue::from_str(mode) {
+            headers.insert("x-amz-object-lock-mode", mode);
+        }
+        if let Ok(until) = HeaderValue::from_str(&iso8601(until)) {
+            headers.insert("x-amz-object-lock-retain-until-date", until);
+        }
+    }
+    if lock.legal_hold {
+        headers.insert("x-amz-object-lock-legal-hold", HeaderValue::from_static("ON"));
+    }
+}
+
+fn xml<T: Serialize>(document: &T) -> Result<Response, StatusCode> {
+    let xml = serde_xml_rs::to_string(document).map_err(|_| StatusCode::INTERNAL_SERVER_ERROR)?;
+    let mut headers = HeaderMap::new();
+    headers.insert("content-type", HeaderValue::from_static("application/xml"));
+    Ok((headers, xml).into_response())
+}
+
+fn stored(result: io::Result<()>, key: &str) -> Result<Response, StatusCode> {
+    match result {
+        Ok(()) => Ok(StatusCode::OK.into_response()),
+        Err(e) => {
+            warn!("Failed to store the object lock of {}: {}", key, e);
+            Err(StatusCode::INTERNAL_SERVER_ERROR)
+        }
+    }
+}
+
+// GET /?object-lock. Any object can be locked, so the bucket always has
+// Object Lock enabled, with no default retention
+pub fn configuration() -> Result<Response, StatusCode> {
+    xml(&ObjectLockConfiguration {
+        xmlns: XMLNS.to_string(),
+        object_lock_enabled: "Enabled".to_string(),
+    })
+}
+
+// GET /{key}?retention
+pub async fn get_retention(
+    state: &AppState,
+    key: &str,
+    file_path: &Path,
+) -> Result<Response, StatusCode> {
+    if !fs::try_exists(file_path).await.unwrap_or(false) {
+        return Ok(no_such_key());
+    }
+    let lock = load(state, key).await;
+    let (Some(mode), Some(until)) = (lock.mode, lock.retain_until) else {
+        return Ok(refusal(
+            StatusCode::NOT_FOUND,
+            "NoSuchObjectLockConfiguration",
+            "The specified object does not have a ObjectLock configuration",
+        ));
+    };
+    xml(&Retention {
+        xmlns: XMLNS.to_string(),
+        mode: Some(mode),
+        retain_until_date: Some(iso8601(&until)),
+    })
+}
+
+// PUT /{key}?retention. Retention can always be extended, or turned from
+// GOVERNANCE to COMPLIANCE. Shortening, lifting or otherwise weakening it
+// takes x-amz-bypass-governance-retention while it's GOVERNANCE, and isn't
+// possible while it's COMPLIANCE
+pub async fn put_retention(
+    state: &AppState,
+    key: &str,
+    file_path: &Path,
+    headers: &HeaderMap,
+    body: &Bytes,
+) -> Result<Response, StatusCode> {
+    let Ok(retention) = serde_xml_rs::from_reader::<Retention, _>(body.as_ref()) else {
+        return Ok(malformed_xml());
+    };
+    let requested =
+        requested_retention(retention.mode.as_deref(), retention.retain_until_date.as_deref());
+    let (mode, retain_until) = match requested {
+        Ok(requested) => requested,
+        Err(message) => return Ok(invalid_argument(message)),
+    };
+    if !fs::try_exists(file_path).await.unwrap_or(false) {
+        return Ok(no_such_key());
+    }
+
+    let mut lock = load(state, key).await;
+    if lock.retained() {
+        let extends = retain_until >= lock.retain_until
+            && (mode.as_deref() == Some(COMPLIANCE) || lock.mode == mode);
+        let bypassed = lock.mode.as_deref() == Some(GOVERNANCE) && bypasses(headers);
+        if !extends && !bypassed {
+            return Ok(locked(key));
+        }
+    }
+    lock.mode = mode;
+    lock.retain_until = retain_until;
+    info!("🔏 Set the retention of {}", key);
+    stored(store(state, key, &lock).await, key)
+}
+
+// GET /{key}?legal-hold
+pub async fn get_legal_hold(
+    state: &AppState,
+    key: &str,
+    file_path: &Path,
+) -> Result<Response, StatusCode> {
+    if !fs::try_exists(file_path).await.unwrap_or(false) {
+        return Ok(no_such_key());
+    }
+    let status = match load(state, key).await.legal_hold {
+        true => "ON",
+        false => "OFF",
+    };
+    xml(&LegalHold {
+        xmlns: XMLNS.to_string(),
+        status: status.to_string(),
+    })
+}
+
+// PUT /{key}?legal-hold
+pub async fn put_legal_hold(
+    state: &AppState,
+    key: &str,
+    file_path: &Path,
+    body: &Bytes,
+) -> Result<Response, StatusCode> {
+    let hold = serde_xml_rs::from_reader::<LegalHold, _>(body.as_ref())
+        .ok()
+        .and_then(|hold| parse_legal_hold(&hold.status));
+    let Some(hold) = hold else {
+        return Ok(malformed_xml());
+    };
+    if !fs::try_exists(file_path).await.unwrap_or(false) {
+        return Ok(no_such_key());
+    }
+    let mut lock = load(state, key).await;
+    lock.legal_hold = hold;
+    info!("🔏 Set the legal hold of {} {}", key, if hold { "ON" } else { "OFF" });
+    stored(store(state, key, &lock).await, key)
+}
@@ -0,0 +1,377 @@
+use axum::{http::StatusCode, response::Response};
+use serde::Serialize;
+use std::{
+    fs::OpenOptions,
+    io::{self, Write},
+    path::{Path, PathBuf},
+    process::Stdio,
+    str::FromStr,
+    time::Duration,
+};
+use tokio::{
+    fs,
+    io::{AsyncRead, AsyncReadExt, AsyncWrite, AsyncWriteExt},
+    process::Command,
+};
+use tracing::{info, warn};
+
+use crate::{AppState, ErrorResponse, error_response, keys, timing};
+
+// Upload scanning. With --clamd or --scan-command, every upload over S3 (PUT
+// and tus), WebDAV or SFTP is scanned before it's put in place; an infected
+// one is refused, and with --scan-action quarantine also kept in
+// QUARANTINE_DIR for review. Either way a line goes to --scan-log:
+//
+//   2026-01-31T12:00:00Z simple-s3 scan infected: service=s3 signature=Eicar-Test-Signature action=quarantined file=20260131T120000Z-UUID key=uploads/x.pdf
+//
+// clamd is sent the object with INSTREAM, over TCP (host:port) or a Unix
+// socket (a path). A command is run with the object on stdin and reports
+// like clamscan: exit status 0 for clean, 1 for infected with the signature
+// on stdout, anything else for an error. Uploads are refused with 503 while
+// the scanner fails, so nothing gets in unscanned. Ranged writes are scanned
+// one range at a time, and copies and composes aren't scanned, their
+// sources being in the bucket already.
+
+const QUARANTINE_DIR: &str = ".simple-s3/quarantine";
+const SCAN_TIMEOUT: Duration = Duration::from_secs(5 * 60);
+const CHUNK: usize = 64 * 1024;
+const STAMP_FORMAT: &str = "%Y%m%dT%H%M%SZ";
+// Longest key or signature written to the log
+const MAX_FIELD_LEN: usize = 1024;
+
+#[derive(Debug, Clone, Copy, PartialEq)]
+pub enum Action {
+    Reject,
+    Quarantine,
+}
+
+impl FromStr for Action {
+    type Err = String;
+
+    fn from_str(s: &str) -> Result<Self, Self::Err> {
+        match s.to_ascii_lowercase().as_str() {
+            "reject" => Ok(Self::Reject),
+            "quarantine" => Ok(Self::Quarantine),
+            _ => Err(format!("unknown scan action {}, expected reject or quarantine", s)),
+        }
+    }
+}
+
+enum Engine {
+    Clamd(String),
+    Command(Vec<String>),
+}
+
+enum Verdict {
+    Clean,
+    Infected(String),
+}
+
+// Why an upload was refused
+pub enum Refusal {
+    Infected(String),
+    Unavailable,
+}
+
+impl Refusal {
+    pub fn status(&self) -> StatusCode {
+        match self {
+            Refusal::Infected(_) => StatusCode::FORBIDDEN,
+            Refusal::Unavailable => StatusCode::SERVICE_UNAVAILABLE,
+        }
+    }
+
+    pub fn response(&self) -> Response {
+        let (code, message) = match self {
+            Refusal::Infected(signature) => (
+                "MalwareDetected",
+                format!("The upload was refused, it contains {}", signature),
+            ),
+            Refusal::Unavailable => (
+                "ServiceUnavailable",
+                "Uploads can't be scanned right now, please try again later".to_string(),
+            ),
+        };
+        error_response(
+            self.status(),
+            ErrorResponse {
+                code: code.to_string(),
+                message,
+                max_size_allowed: None,
+            },
+        )
+    }
+}
+
+#[derive(Serialize)]
+struct QuarantineRecord<'a> {
+    key: &'a str,
+    service: &'a str,
+    signature: &'a str,
+    time: String,
+}
+
+pub struct Scanner {
+    engine: Engine,
+    action: Action,
+    log: Option<PathBuf>,
+}
+
+impl Scanner {
+    pub fn new(
+        clamd: Option<String>,
+        command: Option<String>,
+        action: Action,
+        log: Option<PathBuf>,
+    ) -> Result<Option<Self>, String> {
+        let engine = match (clamd, command) {
+            (None, None) => return Ok(None),
+            (Some(addr), None) => Engine::Clamd(addr),
+            (None, Some(command)) => {
+                let argv: Vec<String> = command.split_whitespace().map(str::to_string).collect();
+                if argv.is_empty() {
+                    return Err("--scan-command is empty".to_string());
+                }
+                Engine::Command(argv)
+            }
+            (Some(_), Some(_)) => {
+                return Err("--clamd and --scan-command can't be used together".to_string());
+            }
+        };
+        if let Some(log) = &log {
+            // Fail at startup rather than on the first infected upload
+            OpenOptions::new()
+                .create(true)
+                .append(true)
+                .open(log)
+                .map_err(|e| format!("can't open --scan-log {}: {}", log.display(), e))?;
+        }
+        Ok(Some(Self {
+            engine,
+            action,
+            log,
+        }))
+    }
+
+    async fn scan(&self, body: impl AsyncRead + Unpin) -> io::Result<Verdict> {
+        let scanned = async {
+            match &self.engine {
+                Engine::Clamd(addr) => clamd(addr, body).await,
+                Engine::Command(argv) => command(argv, body).await,
+            }
+        };
+        tokio::time::timeout(SCAN_TIMEOUT, scanned)
+            .await
+            .map_err(|_| io::Error::new(io::ErrorKind::TimedOut, "scan timed out"))?
+    }
+
+    fn log(&self, service: &str, key: &str, signature: &str, action: &str, file: &str) {
+        let Some(path) = &self.log else {
+            return;
+        };
+        let line = format!(
+            "{} simple-s3 scan infected: service={} signature={} action={} file={} key={}\n",
+            chrono::Utc::now().format("%Y-%m-%dT%H:%M:%SZ"),
+            service,
+            sanitize(signature).replace(' ', "_"),
+            action,
+            file,
+            sanitize(key)
+        );
+        let written = OpenOptions::new()
+            .create(true)
+            .append(true)
+            .open(path)
+            .and_then(|mut file| file.write_all(line.as_bytes()));
+        if let Err(e) = written {
+            warn!("Failed to write the scan log {}: {}", path.display(), e);
+        }
+    }
+}
+
+fn sanitize(value: &str) -> String {
+    value
+        .chars()
+        .take(MAX_FIELD_LEN)
+        .map(|c| if c.is_ascii_graphic() || c == ' ' { c } else { '?' })
+        .collect()
+}
+
+async fn clamd(addr: &str, body: impl AsyncRead + Unpin) -> io::Result<Verdict> {
+    #[cfg(unix)]
+    if addr.starts_with('/') {
+        return instream(tokio::net::UnixStream::connect(addr).await?, body).await;
+    }
+    instream(tokio::net::TcpStream::connect(addr).await?, body).await
+}
+
+// clamd's INSTREAM: the object in length-prefixed chunks, then a zero length
+async fn instream(
+    mut stream: impl AsyncRead + AsyncWrite + Unpin,
+    mut body: impl AsyncRead + Unpin,
+) -> io::Result<Verdict> {
+    stream.write_all(b"zINSTREAM\0").await?;
+    let mut chunk = vec![0; CHUNK];
+    loop {
+        let n = body.read(&mut chunk).await?;
+        if n == 0 {
+            break;
+        }
+        stream.write_all(&(n as u32).to_be_bytes()).await?;
+        stream.write_all(&chunk[..n]).await?;
+    }
+    stream.write_all(&[0; 4]).await?;
+
+    let mut reply = Vec::new();
+    stream.read_to_end(&mut reply).await?;
+    let reply = String::from_utf8_lossy(&reply);
+    let reply = reply.trim_end_matches(['\0', '\n']);
+    match reply.strip_prefix("stream: ") {
+        Some("OK") => Ok(Verdict::Clean),
+        Some(found) if found.ends_with(" FOUND") => Ok(Verdict::Infected(
+            found.trim_end_matches(" FOUND").to_string(),
+        )),
+        _ => Err(io::Error::other(format!("clamd answered {:?}", reply))),
+    }
+}
+
+async fn command(argv: &[String], mut body: impl AsyncRead + Unpin) -> io::Result<Verdict> {
+    let mut child = Command::new(&argv[0])
+        .args(&argv[1..])
+        .stdin(Stdio::piped())
+        .stdout(Stdio::piped())
+        .stderr(Stdio::null())
+        .kill_on_drop(true)
+        .spawn()?;
+    let mut stdin = child.stdin.take().ok_or_else(|| io::Error::other("no stdin"))?;
+    // A scanner may stop reading once it has seen enough, so a failed write
+    // is left to the exit status
+    let feed = async move {
+        let _ = tokio::io::copy(&mut body, &mut stdin).await;
+    };
+    let ((), output) = tokio::join!(feed, child.wait_with_output());
+    let output = output?;
+    match output.status.code() {
+        Some(0) => Ok(Verdict::Clean),
+        Some(1) => {
+            let stdout = String::from_utf8_lossy(&output.stdout);
+            let signature = stdout.lines().next().unwrap_or_default().trim();
+            Ok(Verdict::Infected(if signature.is_empty() {
+                "unknown malware".to_string()
+            } else {
+                signature.to_string()
+            }))
+        }
+        _ => Err(io::Error::other(format!("{} exited with {}", argv[0], output.status))),
+    }
+}
+
+// Keeps an infected upload for review, returning its name in QUARANTINE_DIR
+async fn quarantine(
+    state: &AppState,
+    service: &str,
+    key: &str,
+    signature: &str,
+    store: impl AsyncFnOnce(&Path) -> io::Result<()>,
+) -> io::Result<String> {
+    let now = chrono::Utc::now();
+    let name = format!("{}-{}", now.format(STAMP_FORMAT), uuid::Uuid::new_v4());
+    let dir = state.data_dir.join(QUARANTINE_DIR);
+    fs::create_dir_all(&dir).await?;
+    store(&dir.join(&name)).await?;
+    let record = QuarantineRecord {
+        key,
+        service,
+        signature,
+        time: now.to_rfc3339(),
+    };
+    fs::write(
+        dir.join(format!("{}.json", name)),
+        serde_json::to_vec_pretty(&record)?,
+    )
+    .await?;
+    Ok(name)
+}
+
+async fn refuse(
+    state: &AppState,
+    scanner: &Scanner,
+    service: &str,
+    key: &str,
+    verdict: io::Result<Verdict>,
+    store: impl AsyncFnOnce(&Path) -> io::Result<()>,
+) -> Result<(), Refusal> {
+    let signature = match verdict {
+        Ok(Verdict::Clean) => return Ok(()),
+        Ok(Verdict::Infected(signature)) => signature,
+        Err(e) => {
+            warn!("🦠 Couldn't scan {} uploaded over {}: {}", key, service, e);
+            return Err(Refusal::Unavailable);
+        }
+    };
+
+    let (action, file) = match scanner.action {
+        Action::Reject => ("rejected", "-".to_string()),
+        Action::Quarantine => match quarantine(state, service, key, &signature, store).await {
+            Ok(name) => ("quarantined", name),
+            Err(e) => {
+                warn!("🦠 Failed to quarantine {}: {}", key, e);
+                ("rejected", "-".to_string())
+            }
+        },
+    };
+    warn!("🦠 Refused {} uploaded over {}: {} ({})", key, service, signature, action);
+    scanner.log(service, key, &signature, action, &file);
+    Err(Refusal::Infected(signature))
+}
+
+// Scans an upload held in memory
+pub async fn check_bytes(
+    state: &AppState,
+    service: &str,
+    key: &str,
+    bytes: &[u8],
+) -> Result<(), Refusal> {
+    let Some(scanner) = &state.scanner else {
+        return Ok(());
+    };
+    let verdict = timing::timed("scan", scanner.scan(bytes)).await;
+    refuse(state, scanner, service, key, verdict, async |dst: &Path| {
+        fs::write(dst, bytes).await
+    })
+    .await
+}
+
+// Scans an upload written to `path`, moving it away if it's quarantined
+pub async fn check_file(
+    state: &AppState,
+    service: &str,
+    key: &str,
+    path: &Path,
+) -> Result<(), Refusal> {
+    let Some(scanner) = &state.scanner else {
+        return Ok(());
+    };
+    let verdict = match fs::File::open(path).await {
+        Ok(file) => timing::timed("scan", scanner.scan(file)).await,
+        Err(e) => Err(e),
+    };
+    refuse(state, scanner, service, key, verdict, async |dst: &Path| {
+        fs::rename(path, dst).await
+    })
+    .await
+}
+
+// The key logged for an upload over WebDAV or SFTP, which work with paths
+pub fn key_at(state: &AppState, path: &Path) -> String {
+    path.strip_prefix(&state.data_dir)
+        .map(keys::key_of)
+        .unwrap_or_else(|_| path.display().to_string())
+}
+
+pub fn describe(scanner: &Scanner) {
+    match &scanner.engine {
+        Engine::Clamd(addr) => info!("🦠 Scanning uploads with clamd at {}", addr),
+        Engine::Command(argv) => info!("🦠 Scanning uploads with {}", argv.join(" ")),
+    }
+}
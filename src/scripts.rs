@@ -0,0 +1,245 @@
+use axum::{
+    extract::{Request, State},
+    http::{HeaderName, HeaderValue, Method, StatusCode},
+    middleware::Next,
+    response::{IntoResponse, Response},
+};
+use mlua::{Function, HookTriggers, Lua, Table, Value, VmState};
+use percent_encoding::percent_decode_str;
+use std::{
+    error::Error,
+    path::Path,
+    sync::Arc,
+    time::{Duration, Instant},
+};
+use tracing::{info, warn};
+
+use crate::{ErrorResponse, INTERNAL_DIR, error_response, multipart};
+
+// Lua hooks, a lighter alternative to WASM plugins. Every *.lua file in
+// --scripts-dir is loaded into its own interpreter at startup, in name order,
+// and may define global functions on_get (GET and HEAD), on_put (PUT and
+// POST) and on_delete. Each is called with a request table
+// `{method = ..., key = ..., headers = {...}}` before the object request is
+// handled, and returns:
+//
+//   nil or true                       let it through
+//   false                             refuse it with 403
+//   {reject = true, status = 451, message = "..."}
+//   {headers = {["x-reviewed"] = "yes"}}   annotate the response
+//
+// A hook that raises an error or runs past SCRIPT_TIMEOUT fails the request
+// with 500.
+
+const SCRIPT_TIMEOUT: Duration = Duration::from_secs(1);
+
+struct Script {
+    name: String,
+    lua: Lua,
+}
+
+// Per call deadline checked by the instruction hook
+struct Deadline(Instant);
+
+pub struct Scripts {
+    scripts: Vec<Script>,
+    // With tus enabled, /tus/ is its endpoint rather than object keys
+    tus: bool,
+}
+
+// What hooks see of a request
+struct HookRequest {
+    method: String,
+    key: String,
+    headers: Vec<(String, String)>,
+}
+
+enum Outcome {
+    Allow(Vec<(String, String)>),
+    Reject(StatusCode, String),
+}
+
+impl Scripts {
+    pub fn load(dir: &Path, tus: bool) -> Result<Self, Box<dyn Error>> {
+        let mut paths = std::fs::read_dir(dir)?
+            .filter_map(|entry| entry.ok().map(|entry| entry.path()))
+            .filter(|path| path.extension().is_some_and(|ext| ext == "lua"))
+            .collect::<Vec<_>>();
+        paths.sort();
+
+        let mut scripts = Vec::new();
+        for path in paths {
+            let name = path.file_name().unwrap_or_default().to_string_lossy().to_string();
+            let lua = Lua::new();
+            lua.set_hook(HookTriggers::new().every_nth_instruction(10_000), |lua, _| {
+                match lua.app_data_ref::<Deadline>() {
+                    Some(deadline) if Instant::now() > deadline.0 => {
+                        Err(mlua::Error::runtime("script timed out"))
+                    }
+                    _ => Ok(VmState::Continue),
+                }
+            })?;
+            lua.load(std::fs::read_to_string(&path)?)
+                .set_name(format!("@{}", name))
+                .exec()
+                .map_err(|e| format!("{}: {}", path.display(), e))?;
+            scripts.push(Script { name, lua });
+        }
+
+        info!("🌙 Loaded {} Lua scripts from {}", scripts.len(), dir.display());
+        Ok(Self { scripts, tus })
+    }
+
+    fn hook(&self, method: &Method, key: &str) -> Option<&'static str> {
+        if key.is_empty()
+            || key.starts_with(INTERNAL_DIR)
+            || (self.tus && (key == "tus" || key.starts_with("tus/")))
+        {
+            return None;
+        }
+        match *method {
+            Method::GET | Method::HEAD => Some("on_get"),
+            Method::PUT | Method::POST => Some("on_put"),
+            Method::DELETE => Some("on_delete"),
+            _ => None,
+        }
+    }
+
+    // Call `hook` in every script that defines it, stopping at the first
+    // refusal. Blocks, call from spawn_blocking
+    fn run(&self, hook: &str, request: &HookRequest) -> Result<Outcome, String> {
+        let mut annotations = Vec::new();
+
+        for script in &self.scripts {
+            let lua = &script.lua;
+            let Some(function) = lua
+                .globals()
+                .get::<Option<Function>>(hook)
+                .map_err(|e| e.to_string())?
+            else {
+                continue;
+            };
+
+            let call = || -> mlua::Result<Value> {
+                let headers = lua.create_table()?;
+                for (name, value) in &request.headers {
+                    headers.set(name.as_str(), value.as_str())?;
+                }
+                let table = lua.create_table()?;
+                table.set("method", request.method.as_str())?;
+                table.set("key", request.key.as_str())?;
+                table.set("headers", headers)?;
+
+                lua.set_app_data(Deadline(Instant::now() + SCRIPT_TIMEOUT));
+                function.call(table)
+            };
+            let result = call().map_err(|e| format!("{} {}: {}", script.name, hook, e))?;
+
+            match result {
+                Value::Nil | Value::Boolean(true) => {}
+                Value::Boolean(false) => {
+                    return Ok(Outcome::Reject(StatusCode::FORBIDDEN, "Access Denied".to_string()));
+                }
+                Value::Table(table) => {
+                    if let Some(outcome) = reject(&table).map_err(|e| e.to_string())? {
+                        return Ok(outcome);
+                    }
+                    if let Some(headers) = table
+                        .get::<Option<Table>>("headers")
+                        .map_err(|e| e.to_string())?
+                    {
+                        for pair in headers.pairs::<String, String>() {
+                            annotations.push(pair.map_err(|e| e.to_string())?);
+                        }
+                    }
+                }
+                other => {
+                    return Err(format!(
+                        "{} {} returned a {}",
+                        script.name,
+                        hook,
+                        other.type_name()
+                    ));
+                }
+            }
+        }
+
+        Ok(Outcome::Allow(annotations))
+    }
+}
+
+fn reject(table: &Table) -> mlua::Result<Option<Outcome>> {
+    if !table.get::<Option<bool>>("reject")?.unwrap_or(false) {
+        return Ok(None);
+    }
+    let status = table
+        .get::<Option<u16>>("status")?
+        .and_then(|status| StatusCode::from_u16(status).ok())
+        .filter(|status| status.is_client_error() || status.is_server_error())
+        .unwrap_or(StatusCode::FORBIDDEN);
+    let message = table
+        .get::<Option<String>>("message")?
+        .unwrap_or_else(|| "Access Denied".to_string());
+    Ok(Some(Outcome::Reject(status, message)))
+}
+
+pub async fn script_middleware(
+    State(scripts): State<Arc<Scripts>>,
+    request: Request,
+    next: Next,
+) -> Response {
+    let key = percent_decode_str(request.uri().path().trim_start_matches('/'))
+        .decode_utf8_lossy()
+        .to_string();
+    let Some(hook) = scripts
+        .hook(request.method(), &key)
+        .filter(|_| !multipart::is_upload_step(request.method(), request.uri().query()))
+    else {
+        return next.run(request).await;
+    };
+
+    let hook_request = HookRequest {
+        method: request.method().to_string(),
+        key,
+        headers: request
+            .headers()
+            .iter()
+            .filter_map(|(name, value)| Some((name.to_string(), value.to_str().ok()?.to_string())))
+            .collect(),
+    };
+    let runner = scripts.clone();
+    let Ok(outcome) = tokio::task::spawn_blocking(move || runner.run(hook, &hook_request)).await
+    else {
+        return StatusCode::INTERNAL_SERVER_ERROR.into_response();
+    };
+
+    match outcome {
+        Ok(Outcome::Allow(annotations)) => {
+            let mut response = next.run(request).await;
+            for (name, value) in annotations {
+                match (HeaderName::try_from(name.as_str()), HeaderValue::try_from(value)) {
+                    (Ok(name), Ok(value)) => {
+                        response.headers_mut().insert(name, value);
+                    }
+                    _ => warn!("🌙 Script set invalid header {}", name),
+                }
+            }
+            response
+        }
+        Ok(Outcome::Reject(status, message)) => {
+            warn!("🌙 Script {} refused {} {}", hook, request.method(), request.uri().path());
+            error_response(
+                status,
+                ErrorResponse {
+                    code: "AccessDenied".to_string(),
+                    message,
+                    max_size_allowed: None,
+                },
+            )
+        }
+        Err(e) => {
+            warn!("🌙 Script failed: {}", e);
+            StatusCode::INTERNAL_SERVER_ERROR.into_response()
+        }
+    }
+}
@@ -0,0 +1,481 @@
+use axum::{
+    extract::{Request, State},
+    http::{HeaderMap, HeaderValue, Method, StatusCode},
+    middleware::Next,
+    response::{IntoResponse, Response},
+};
+use percent_encoding::percent_decode_str;
+use serde::{Deserialize, Serialize};
+use std::{
+    collections::{HashMap, HashSet},
+    fs::Metadata,
+    path::{Path, PathBuf},
+    sync::{Arc, Mutex},
+    time::{Duration, Instant, SystemTime},
+};
+use tokio::{fs, io::AsyncReadExt};
+use tracing::{info, warn};
+
+use crate::{
+    AppState, ErrorResponse, error_response, normalize, object_path, tmp_path, walk,
+    write_and_rename,
+};
+
+// Full-text search. With --search, text-like objects (by the content type
+// their key implies) under --search-prefixes, or anywhere without them, are
+// split into lowercase words and kept in an inverted index, which
+// GET /?query=some+words searches for objects holding every word, best
+// matches first, each with a snippet around the first match. Only the first
+// MAX_INDEXED_BYTES of an object are indexed.
+//
+// S3 writes update the index as they succeed. Objects changed some other
+// way, over WebDAV, SFTP or on disk, are picked up by a reconcile pass every
+// RECONCILE_INTERVAL, the first on startup. The index is kept in memory and
+// saved to INDEX_FILE, so a restart only reindexes what changed.
+
+const INDEX_FILE: &str = ".simple-s3/search-index.json";
+const MAX_INDEXED_BYTES: u64 = 1024 * 1024;
+const SAVE_INTERVAL: Duration = Duration::from_secs(60);
+const RECONCILE_INTERVAL: Duration = Duration::from_secs(10 * 60);
+const MIN_TERM_CHARS: usize = 2;
+const MAX_TERM_CHARS: usize = 64;
+const MAX_RESULTS: usize = 100;
+// Characters of context around the match in a snippet
+const SNIPPET_BEFORE: usize = 60;
+const SNIPPET_AFTER: usize = 100;
+
+#[derive(Debug, Clone, Copy, PartialEq, Serialize, Deserialize)]
+struct Version {
+    len: u64,
+    modified_secs: u64,
+    modified_nanos: u32,
+}
+
+impl Version {
+    fn of(metadata: &Metadata) -> Option<Self> {
+        let modified = metadata
+            .modified()
+            .ok()?
+            .duration_since(SystemTime::UNIX_EPOCH)
+            .ok()?;
+        Some(Self {
+            len: metadata.len(),
+            modified_secs: modified.as_secs(),
+            modified_nanos: modified.subsec_nanos(),
+        })
+    }
+}
+
+// An indexed object: the version indexed and how often each word occurs
+#[derive(Debug, Clone, Serialize, Deserialize)]
+struct Doc {
+    version: Version,
+    terms: HashMap<String, u32>,
+}
+
+#[derive(Default)]
+struct Index {
+    docs: HashMap<String, Doc>,
+    postings: HashMap<String, HashSet<String>>,
+    dirty: bool,
+}
+
+impl Index {
+    fn insert(&mut self, key: &str, doc: Doc) {
+        self.remove(key);
+        for term in doc.terms.keys() {
+            self.postings
+                .entry(term.clone())
+                .or_default()
+                .insert(key.to_string());
+        }
+        self.docs.insert(key.to_string(), doc);
+        self.dirty = true;
+    }
+
+    fn remove(&mut self, key: &str) {
+        let Some(doc) = self.docs.remove(key) else {
+            return;
+        };
+        for term in doc.terms.keys() {
+            if let Some(keys) = self.postings.get_mut(term) {
+                keys.remove(key);
+                if keys.is_empty() {
+                    self.postings.remove(term);
+                }
+            }
+        }
+        self.dirty = true;
+    }
+}
+
+pub struct SearchIndex {
+    prefixes: Vec<String>,
+    path: PathBuf,
+    index: Mutex<Index>,
+}
+
+impl SearchIndex {
+    pub async fn load(data_dir: &Path, prefixes: Vec<String>) -> std::io::Result<Self> {
+        let path = data_dir.join(INDEX_FILE);
+        let docs: HashMap<String, Doc> = match fs::read(&path).await {
+            Ok(bytes) => serde_json::from_slice(&bytes).unwrap_or_else(|e| {
+                warn!("🔎 Ignoring unreadable {}, reindexing: {}", path.display(), e);
+                HashMap::new()
+            }),
+            Err(e) if e.kind() == std::io::ErrorKind::NotFound => HashMap::new(),
+            Err(e) => return Err(e),
+        };
+        let mut index = Index::default();
+        for (key, doc) in docs {
+            index.insert(&key, doc);
+        }
+        index.dirty = false;
+        Ok(Self {
+            prefixes,
+            path,
+            index: Mutex::new(index),
+        })
+    }
+
+    fn indexable(&self, key: &str) -> bool {
+        let prefixed = self.prefixes.is_empty()
+            || self.prefixes.iter().any(|prefix| key.starts_with(prefix.as_str()));
+        prefixed && is_text(key)
+    }
+
+    async fn save(&self, state: &AppState) {
+        let json = {
+            let mut index = self.index.lock().unwrap();
+            if !index.dirty {
+                return;
+            }
+            index.dirty = false;
+            serde_json::to_vec(&index.docs)
+        };
+        let Ok(json) = json else {
+            return;
+        };
+        let tmp = tmp_path(state);
+        if let Err(e) = write_and_rename(state, &tmp, &self.path, &json).await {
+            warn!("🔎 Failed to save the search index: {}", e);
+            let _ = fs::remove_file(&tmp).await;
+            self.index.lock().unwrap().dirty = true;
+        }
+    }
+}
+
+// Whether the content type a key implies is text
+fn is_text(key: &str) -> bool {
+    let mime = mime_guess::from_path(key).first_or_octet_stream();
+    mime.type_() == mime_guess::mime::TEXT
+        || matches!(
+            mime.subtype().as_str(),
+            "json" | "xml" | "javascript" | "x-javascript" | "yaml" | "x-yaml" | "toml" | "x-sh"
+        )
+        || mime.suffix().is_some_and(|suffix| suffix == "json" || suffix == "xml")
+}
+
+// The lowercase words of `text` and where each starts
+fn tokens(text: &str) -> Vec<(usize, String)> {
+    let mut found = Vec::new();
+    let mut start = None;
+    for (i, c) in text.char_indices().chain(std::iter::once((text.len(), ' '))) {
+        match (c.is_alphanumeric(), start) {
+            (true, None) => start = Some(i),
+            (false, Some(from)) => {
+                let term = text[from..i].to_lowercase();
+                if (MIN_TERM_CHARS..=MAX_TERM_CHARS).contains(&term.chars().count()) {
+                    found.push((from, term));
+                }
+                start = None;
+            }
+            _ => {}
+        }
+    }
+    found
+}
+
+// The indexed part of an object as text, None if it isn't text after all
+async fn read_text(path: &Path) -> Option<String> {
+    let file = fs::File::open(path).await.ok()?;
+    let mut bytes = Vec::new();
+    file.take(MAX_INDEXED_BYTES)
+        .read_to_end(&mut bytes)
+        .await
+        .ok()?;
+    if bytes.contains(&0) {
+        return None;
+    }
+    Some(String::from_utf8_lossy(&bytes).into_owned())
+}
+
+// Brings the index up to date with the object at `key`
+pub async fn update(state: &AppState, index: &SearchIndex, key: &str) {
+    let Ok(path) = object_path(state, key) else {
+        return;
+    };
+    let version = fs::metadata(&path)
+        .await
+        .ok()
+        .filter(|metadata| metadata.is_file())
+        .and_then(|metadata| Version::of(&metadata));
+    let Some(version) = version.filter(|_| index.indexable(key)) else {
+        index.index.lock().unwrap().remove(key);
+        return;
+    };
+    let current = index
+        .index
+        .lock()
+        .unwrap()
+        .docs
+        .get(key)
+        .is_some_and(|doc| doc.version == version);
+    if current {
+        return;
+    }
+
+    let Some(text) = read_text(&path).await else {
+        index.index.lock().unwrap().remove(key);
+        return;
+    };
+    let terms = tokio::task::spawn_blocking(move || {
+        let mut terms: HashMap<String, u32> = HashMap::new();
+        for (_, term) in tokens(&text) {
+            *terms.entry(term).or_default() += 1;
+        }
+        terms
+    })
+    .await
+    .unwrap_or_default();
+    index
+        .index
+        .lock()
+        .unwrap()
+        .insert(key, Doc { version, terms });
+}
+
+// Reindexes objects that changed outside the S3 API and drops deleted ones
+async fn reconcile(state: &AppState, index: &SearchIndex) {
+    let mut found = HashMap::new();
+    walk::walk(state, state.data_dir.clone(), |key, metadata| {
+        if index.indexable(&key)
+            && let Some(version) = Version::of(&metadata)
+        {
+            found.insert(key, version);
+        }
+    })
+    .await;
+
+    let (stale, gone): (Vec<String>, Vec<String>) = {
+        let current = index.index.lock().unwrap();
+        let stale = found
+            .iter()
+            .filter(|(key, version)| {
+                current
+                    .docs
+                    .get(*key)
+                    .is_none_or(|doc| doc.version != **version)
+            })
+            .map(|(key, _)| key.clone())
+            .collect();
+        let gone = current
+            .docs
+            .keys()
+            .filter(|key| !found.contains_key(*key))
+            .cloned()
+            .collect();
+        (stale, gone)
+    };
+    for key in &gone {
+        index.index.lock().unwrap().remove(key);
+    }
+    for key in &stale {
+        update(state, index, key).await;
+    }
+    if !stale.is_empty() || !gone.is_empty() {
+        info!("🔎 Reindexed {} objects, dropped {}", stale.len(), gone.len());
+    }
+}
+
+pub fn spawn(state: Arc<AppState>) {
+    let Some(index) = state.search.clone() else {
+        return;
+    };
+    tokio::spawn(async move {
+        let mut interval = tokio::time::interval(SAVE_INTERVAL);
+        let mut reconciled: Option<Instant> = None;
+        loop {
+            interval.tick().await;
+            if reconciled.is_none_or(|at| at.elapsed() >= RECONCILE_INTERVAL) {
+                reconcile(&state, &index).await;
+                reconciled = Some(Instant::now());
+            }
+            index.save(&state).await;
+        }
+    });
+}
+
+// Update the index after successful S3 writes and deletes
+pub async fn index_changes(
+    State(state): State<Arc<AppState>>,
+    request: Request,
+    next: Next,
+) -> Response {
+    let Some(index) = state.search.clone() else {
+        return next.run(request).await;
+    };
+    if !matches!(*request.method(), Method::PUT | Method::POST | Method::DELETE) {
+        return next.run(request).await;
+    }
+
+    let key = percent_decode_str(request.uri().path().trim_start_matches('/'))
+        .decode_utf8_lossy()
+        .to_string();
+    let key = normalize::stored_key(&state, &key).into_owned();
+    let response = next.run(request).await;
+
+    if response.status().is_success() && object_path(&state, &key).is_ok() {
+        update(&state, &index, &key).await;
+    }
+    response
+}
+
+#[derive(Debug, Serialize)]
+struct Match {
+    #[serde(rename = "Key")]
+    key: String,
+    #[serde(rename = "Size")]
+    size: u64,
+    #[serde(rename = "Score")]
+    score: u32,
+    #[serde(rename = "Snippet")]
+    snippet: String,
+}
+
+#[derive(Debug, Serialize)]
+#[serde(rename = "SearchResult")]
+struct SearchResult {
+    #[serde(rename = "@xmlns")]
+    xmlns: String,
+    #[serde(rename = "Query")]
+    query: String,
+    #[serde(rename = "Prefix")]
+    prefix: String,
+    #[serde(rename = "MaxKeys")]
+    max_keys: usize,
+    #[serde(rename = "IsTruncated")]
+    is_truncated: bool,
+    #[serde(rename = "Match")]
+    matches: Vec<Match>,
+}
+
+// The text around the first of `terms` in `text`, on one line
+fn snippet(text: &str, terms: &HashSet<String>) -> String {
+    let Some(at) = tokens(text)
+        .into_iter()
+        .find(|(_, term)| terms.contains(term))
+        .map(|(at, _)| at)
+    else {
+        return String::new();
+    };
+    let start = text[..at]
+        .char_indices()
+        .rev()
+        .nth(SNIPPET_BEFORE - 1)
+        .map_or(0, |(i, _)| i);
+    let end = text[at..]
+        .char_indices()
+        .nth(SNIPPET_AFTER)
+        .map_or(text.len(), |(i, _)| at + i);
+
+    let words: Vec<&str> = text[start..end].split_whitespace().collect();
+    let mut snippet: String = words.join(" ").chars().filter(|c| !c.is_control()).collect();
+    if start > 0 {
+        snippet.insert(0, '…');
+    }
+    if end < text.len() {
+        snippet.push('…');
+    }
+    snippet
+}
+
+// GET /?query=words[&prefix=][&max-keys=]
+pub async fn search(
+    state: &AppState,
+    index: &SearchIndex,
+    query: &str,
+    prefix: &str,
+    max_keys: Option<usize>,
+) -> Result<Response, StatusCode> {
+    let terms: HashSet<String> = tokens(query).into_iter().map(|(_, term)| term).collect();
+    if terms.is_empty() {
+        return Ok(error_response(
+            StatusCode::BAD_REQUEST,
+            ErrorResponse {
+                code: "InvalidArgument".to_string(),
+                message: "The query has no words to search for".to_string(),
+                max_size_allowed: None,
+            },
+        ));
+    }
+    let max_keys = max_keys.unwrap_or(MAX_RESULTS).min(MAX_RESULTS);
+
+    let mut scored: Vec<(String, u32, u64)> = {
+        let current = index.index.lock().unwrap();
+        let mut postings: Vec<&HashSet<String>> = Vec::new();
+        for term in &terms {
+            match current.postings.get(term) {
+                Some(keys) => postings.push(keys),
+                None => postings.clear(),
+            }
+            if postings.is_empty() {
+                break;
+            }
+        }
+        postings.sort_by_key(|keys| keys.len());
+        match postings.split_first() {
+            Some((rarest, rest)) => rarest
+                .iter()
+                .filter(|key| key.starts_with(prefix) && rest.iter().all(|keys| keys.contains(*key)))
+                .filter_map(|key| {
+                    let doc = current.docs.get(key)?;
+                    let score = terms.iter().filter_map(|term| doc.terms.get(term)).sum();
+                    Some((key.clone(), score, doc.version.len))
+                })
+                .collect(),
+            None => Vec::new(),
+        }
+    };
+    scored.sort_by(|a, b| b.1.cmp(&a.1).then_with(|| a.0.cmp(&b.0)));
+    let is_truncated = scored.len() > max_keys;
+    scored.truncate(max_keys);
+
+    let mut matches = Vec::new();
+    for (key, score, size) in scored {
+        let text = match object_path(state, &key) {
+            Ok(path) => read_text(&path).await.unwrap_or_default(),
+            Err(_) => String::new(),
+        };
+        matches.push(Match {
+            snippet: snippet(&text, &terms),
+            key,
+            size,
+            score,
+        });
+    }
+
+    let result = SearchResult {
+        xmlns: "http://s3.amazonaws.com/doc/2006-03-01/".to_string(),
+        query: query.to_string(),
+        prefix: prefix.to_string(),
+        max_keys,
+        is_truncated,
+        matches,
+    };
+    let xml = serde_xml_rs::to_string(&result).map_err(|_| StatusCode::INTERNAL_SERVER_ERROR)?;
+
+    let mut headers = HeaderMap::new();
+    headers.insert("content-type", HeaderValue::from_static("application/xml"));
+    Ok((headers, xml).into_response())
+}
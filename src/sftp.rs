@@ -0,0 +1,626 @@
+use russh::{
+    Channel, ChannelId,
+    keys::{PrivateKey, PublicKey, ssh_key::LineEnding},
+    server::{Auth, ChannelOpenHandle, Config, Handler, Msg, Server, Session},
+};
+use russh_sftp::protocol::{
+    Attrs, Data, File, FileAttributes, Handle, Name, OpenFlags, Status, StatusCode, Version,
+};
+use std::{
+    collections::HashMap,
+    io,
+    net::SocketAddr,
+    path::{Path, PathBuf},
+    sync::Arc,
+    time::Duration,
+};
+use tokio::{
+    fs,
+    io::{AsyncReadExt, AsyncSeekExt, AsyncWriteExt},
+    sync::Mutex,
+};
+use tracing::{info, warn};
+
+use crate::{AppState, INTERNAL_DIR, authlog, inside_data_dir, keys, normalize, relative_path, scan, stats, tier, tmp_path, worm};
+
+// SFTP gateway to the bucket. Users log in with the access key as the
+// username and either the secret key as the password or a public key from
+// the authorized keys file. Writes are staged and renamed into place on
+// close, the same as uploads through the S3 API.
+
+const HOST_KEY: &str = ".simple-s3/ssh_host_ed25519_key";
+
+pub async fn serve(
+    state: Arc<AppState>,
+    addr: String,
+    authorized_keys: Option<PathBuf>,
+) -> io::Result<()> {
+    let authorized_keys = match authorized_keys {
+        Some(path) => load_authorized_keys(&path).await?,
+        None => Vec::new(),
+    };
+
+    let config = Config {
+        auth_rejection_time: Duration::from_secs(3),
+        auth_rejection_time_initial: Some(Duration::from_secs(0)),
+        keys: vec![host_key(&state).await?],
+        ..Default::default()
+    };
+
+    let mut server = SftpServer {
+        state,
+        authorized_keys: Arc::new(authorized_keys),
+    };
+    server.run_on_address(Arc::new(config), addr).await
+}
+
+// The host key is generated on first start and kept in the data dir so
+// clients don't see it change between restarts
+async fn host_key(state: &AppState) -> io::Result<PrivateKey> {
+    let path = state.data_dir.join(HOST_KEY);
+
+    if fs::try_exists(&path).await? {
+        return russh::keys::load_secret_key(&path, None).map_err(io::Error::other);
+    }
+
+    let mut seed = [0u8; 32];
+    getrandom::fill(&mut seed).map_err(io::Error::other)?;
+    let key = PrivateKey::from(russh::keys::ssh_key::private::Ed25519Keypair::from_seed(&seed));
+
+    let pem = key.to_openssh(LineEnding::LF).map_err(io::Error::other)?;
+    fs::write(&path, pem.as_bytes()).await?;
+    #[cfg(unix)]
+    {
+        use std::os::unix::fs::PermissionsExt;
+        fs::set_permissions(&path, std::fs::Permissions::from_mode(0o600)).await?;
+    }
+    info!("🔑 Generated SFTP host key: {}", path.display());
+
+    Ok(key)
+}
+
+// OpenSSH authorized_keys format: "[options] type base64 [comment]"
+async fn load_authorized_keys(path: &Path) -> io::Result<Vec<PublicKey>> {
+    let contents = fs::read_to_string(path).await?;
+    let mut keys = Vec::new();
+
+    for line in contents.lines() {
+        let line = line.trim();
+        if line.is_empty() || line.starts_with('#') {
+            continue;
+        }
+
+        let key = line
+            .split_whitespace()
+            .find_map(|field| russh::keys::parse_public_key_base64(field).ok());
+        match key {
+            Some(key) => keys.push(key),
+            None => warn!("Skipping unparseable authorized key: {}", line),
+        }
+    }
+
+    Ok(keys)
+}
+
+#[derive(Clone)]
+struct SftpServer {
+    state: Arc<AppState>,
+    authorized_keys: Arc<Vec<PublicKey>>,
+}
+
+impl Server for SftpServer {
+    type Handler = SshSession;
+
+    fn new_client(&mut self, peer: Option<SocketAddr>) -> Self::Handler {
+        SshSession {
+            state: self.state.clone(),
+            authorized_keys: self.authorized_keys.clone(),
+            peer,
+            channels: HashMap::new(),
+        }
+    }
+}
+
+struct SshSession {
+    state: Arc<AppState>,
+    authorized_keys: Arc<Vec<PublicKey>>,
+    peer: Option<SocketAddr>,
+    channels: HashMap<ChannelId, Channel<Msg>>,
+}
+
+impl SshSession {
+    fn address_allowed(&self) -> bool {
+        let allowed = self
+            .state
+            .ip_filter
+            .as_ref()
+            .is_none_or(|filter| filter.allows(self.peer.map(|peer| peer.ip())));
+        if !allowed {
+            warn!("🧱 IP filter refused SFTP login from {:?}", self.peer);
+        }
+        #[cfg(feature = "geoip")]
+        let allowed = allowed
+            && self
+                .state
+                .geoip
+                .as_ref()
+                .is_none_or(|geoip| geoip.allows(self.peer.map(|peer| peer.ip())));
+        allowed
+    }
+}
+
+impl Handler for SshSession {
+    type Error = russh::Error;
+
+    async fn auth_password(&mut self, user: &str, password: &str) -> Result<Auth, Self::Error> {
+        let ip = self.peer.map(|peer| peer.ip().to_canonical());
+        if !self.address_allowed() {
+            return Ok(Auth::reject());
+        }
+        if self.state.lockout.blocked(ip).is_some() {
+            authlog::failure(&self.state, "sftp", ip, Some(user), authlog::Reason::Throttled);
+            warn!("🔒 SFTP password login from {:?} refused, too many failures", self.peer);
+            return Ok(Auth::reject());
+        }
+
+        if user == self.state.access_key && password == self.state.secret_key {
+            info!("✓ SFTP password login from {:?}", self.peer);
+            self.state.lockout.succeeded(ip);
+            return Ok(Auth::Accept);
+        }
+
+        warn!("🚫 SFTP password login rejected from {:?}", self.peer);
+        self.state.lockout.failed(ip);
+        authlog::failure(&self.state, "sftp", ip, Some(user), authlog::Reason::InvalidCredentials);
+        Ok(Auth::reject())
+    }
+
+    async fn auth_publickey(
+        &mut self,
+        user: &str,
+        public_key: &PublicKey,
+    ) -> Result<Auth, Self::Error> {
+        if !self.address_allowed() {
+            return Ok(Auth::reject());
+        }
+        let known = self
+            .authorized_keys
+            .iter()
+            .any(|key| key.key_data() == public_key.key_data());
+
+        if user == self.state.access_key && known {
+            info!("✓ SFTP key login from {:?}", self.peer);
+            return Ok(Auth::Accept);
+        }
+
+        Ok(Auth::reject())
+    }
+
+    async fn channel_open_session(
+        &mut self,
+        channel: Channel<Msg>,
+        reply: ChannelOpenHandle,
+        _session: &mut Session,
+    ) -> Result<(), Self::Error> {
+        self.channels.insert(channel.id(), channel);
+        reply.accept().await;
+        Ok(())
+    }
+
+    async fn channel_eof(
+        &mut self,
+        channel: ChannelId,
+        session: &mut Session,
+    ) -> Result<(), Self::Error> {
+        session.close(channel)?;
+        Ok(())
+    }
+
+    async fn subsystem_request(
+        &mut self,
+        channel_id: ChannelId,
+        name: &str,
+        session: &mut Session,
+    ) -> Result<(), Self::Error> {
+        match self.channels.remove(&channel_id) {
+            Some(channel) if name == "sftp" => {
+                session.channel_success(channel_id)?;
+                let sftp = SftpSession::new(self.state.clone());
+                tokio::spawn(russh_sftp::server::run(channel.into_stream(), sftp));
+            }
+            _ => session.channel_failure(channel_id)?,
+        }
+
+        Ok(())
+    }
+}
+
+enum OpenHandle {
+    Read(fs::File),
+    // Written to a staging file, renamed over `path` on close
+    Write {
+        file: fs::File,
+        tmp_path: PathBuf,
+        path: PathBuf,
+    },
+    Dir(Option<Vec<File>>),
+}
+
+struct SftpSession {
+    state: Arc<AppState>,
+    handles: HashMap<String, Arc<Mutex<OpenHandle>>>,
+    next_handle: u64,
+}
+
+impl SftpSession {
+    fn new(state: Arc<AppState>) -> Self {
+        Self {
+            state,
+            handles: HashMap::new(),
+            next_handle: 0,
+        }
+    }
+
+    fn resolve(&self, path: &str) -> Result<PathBuf, StatusCode> {
+        relative_path(&normalize::stored_key(&self.state, path))
+            .map(|rel| keys::long_path(self.state.data_dir.join(rel)))
+            .filter(|path| inside_data_dir(&self.state, path))
+            .ok_or(StatusCode::PermissionDenied)
+    }
+
+    // Read replicas refuse anything that changes the data dir
+    fn writable(&self) -> Result<(), StatusCode> {
+        if self.state.read_only {
+            return Err(StatusCode::PermissionDenied);
+        }
+        Ok(())
+    }
+
+    // Nothing new is written while disk space is low, with --min-free-disk
+    fn has_space(&self, len: u64) -> Result<(), StatusCode> {
+        if self.state.shedder.refuses_write(len) {
+            self.state.metrics.shed();
+            return Err(StatusCode::Failure);
+        }
+        Ok(())
+    }
+
+    // --worm and object locks refuse anything that changes an existing object
+    async fn changeable(&self, path: &Path) -> Result<(), StatusCode> {
+        if worm::locked(&self.state, path).await {
+            return Err(StatusCode::PermissionDenied);
+        }
+        Ok(())
+    }
+
+    fn insert(&mut self, handle: OpenHandle) -> String {
+        self.next_handle += 1;
+        let id = self.next_handle.to_string();
+        self.handles.insert(id.clone(), Arc::new(Mutex::new(handle)));
+        id
+    }
+
+    fn handle(&self, handle: &str) -> Result<Arc<Mutex<OpenHandle>>, StatusCode> {
+        self.handles.get(handle).cloned().ok_or(StatusCode::Failure)
+    }
+}
+
+fn ok(id: u32) -> Status {
+    Status {
+        id,
+        status_code: StatusCode::Ok,
+        error_message: "Ok".to_string(),
+        language_tag: "en-US".to_string(),
+    }
+}
+
+fn status(err: io::Error) -> StatusCode {
+    match err.kind() {
+        io::ErrorKind::NotFound => StatusCode::NoSuchFile,
+        io::ErrorKind::PermissionDenied => StatusCode::PermissionDenied,
+        _ => StatusCode::Failure,
+    }
+}
+
+// Collapse "." and ".." against the root, SFTP clients send both
+fn normalize(path: &str) -> String {
+    let mut parts: Vec<&str> = Vec::new();
+    for part in path.split('/') {
+        match part {
+            "" | "." => {}
+            ".." => {
+                parts.pop();
+            }
+            part => parts.push(part),
+        }
+    }
+    format!("/{}", parts.join("/"))
+}
+
+impl russh_sftp::server::Handler for SftpSession {
+    type Error = StatusCode;
+
+    fn unimplemented(&self) -> Self::Error {
+        StatusCode::OpUnsupported
+    }
+
+    async fn init(
+        &mut self,
+        _version: u32,
+        _extensions: HashMap<String, String>,
+    ) -> Result<Version, Self::Error> {
+        Ok(Version::new())
+    }
+
+    async fn realpath(&mut self, id: u32, path: String) -> Result<Name, Self::Error> {
+        Ok(Name {
+            id,
+            files: vec![File::dummy(normalize(&path))],
+        })
+    }
+
+    async fn open(
+        &mut self,
+        id: u32,
+        filename: String,
+        pflags: OpenFlags,
+        _attrs: FileAttributes,
+    ) -> Result<Handle, Self::Error> {
+        let path = self.resolve(&filename)?;
+
+        let handle = if pflags.contains(OpenFlags::WRITE) {
+            self.writable()?;
+            self.has_space(0)?;
+            // Start from the current contents unless the client truncates,
+            // so appends and partial rewrites keep the rest of the object
+            let tmp_path = tmp_path(&self.state);
+            self.changeable(&path).await?;
+            let existing = fs::try_exists(&path).await.unwrap_or(false);
+            if existing && !pflags.contains(OpenFlags::TRUNCATE) {
+                tier::recall_path(&self.state, &path).await.map_err(status)?;
+                crate::copy::copy_file(&path, &tmp_path).await.map_err(status)?;
+            } else if !existing && !pflags.contains(OpenFlags::CREATE) {
+                return Err(StatusCode::NoSuchFile);
+            }
+
+            let file = fs::OpenOptions::new()
+                .write(true)
+                .create(true)
+                .truncate(false)
+                .open(&tmp_path)
+                .await
+                .map_err(status)?;
+            OpenHandle::Write {
+                file,
+                tmp_path,
+                path,
+            }
+        } else {
+            tier::recall_path(&self.state, &path).await.map_err(status)?;
+            OpenHandle::Read(fs::File::open(&path).await.map_err(status)?)
+        };
+
+        Ok(Handle {
+            id,
+            handle: self.insert(handle),
+        })
+    }
+
+    async fn close(&mut self, id: u32, handle: String) -> Result<Status, Self::Error> {
+        let Some(open) = self.handles.remove(&handle) else {
+            return Err(StatusCode::Failure);
+        };
+
+        if let OpenHandle::Write {
+            file,
+            tmp_path,
+            path,
+        } = &mut *open.lock().await
+        {
+            file.flush().await.map_err(status)?;
+            let key = scan::key_at(&self.state, path);
+            if scan::check_file(&self.state, "sftp", &key, tmp_path).await.is_err() {
+                let _ = fs::remove_file(&tmp_path).await;
+                return Err(StatusCode::PermissionDenied);
+            }
+            let old = stats::size(path).await;
+            if let Err(e) = fs::rename(&tmp_path, &path).await {
+                warn!("Failed to store {} over SFTP: {}", path.display(), e);
+                let _ = fs::remove_file(&tmp_path).await;
+                return Err(status(e));
+            }
+            self.state.stats.replaced(old, stats::size(path).await);
+            self.state.handles.invalidate(path);
+            info!("📁 Stored object over SFTP: {}", path.display());
+        }
+
+        Ok(ok(id))
+    }
+
+    async fn read(
+        &mut self,
+        id: u32,
+        handle: String,
+        offset: u64,
+        len: u32,
+    ) -> Result<Data, Self::Error> {
+        let open = self.handle(&handle)?;
+        let mut open = open.lock().await;
+        let OpenHandle::Read(file) = &mut *open else {
+            return Err(StatusCode::Failure);
+        };
+
+        file.seek(io::SeekFrom::Start(offset))
+            .await
+            .map_err(status)?;
+        let mut data = vec![0; len.min(256 * 1024) as usize];
+        let n = file.read(&mut data).await.map_err(status)?;
+        if n == 0 {
+            return Err(StatusCode::Eof);
+        }
+        data.truncate(n);
+
+        Ok(Data { id, data })
+    }
+
+    async fn write(
+        &mut self,
+        id: u32,
+        handle: String,
+        offset: u64,
+        data: Vec<u8>,
+    ) -> Result<Status, Self::Error> {
+        self.has_space(data.len() as u64)?;
+        let open = self.handle(&handle)?;
+        let mut open = open.lock().await;
+        let OpenHandle::Write { file, .. } = &mut *open else {
+            return Err(StatusCode::Failure);
+        };
+
+        file.seek(io::SeekFrom::Start(offset))
+            .await
+            .map_err(status)?;
+        file.write_all(&data).await.map_err(status)?;
+
+        Ok(ok(id))
+    }
+
+    async fn stat(&mut self, id: u32, path: String) -> Result<Attrs, Self::Error> {
+        let metadata = fs::metadata(self.resolve(&path)?)
+            .await
+            .map_err(status)?;
+        Ok(Attrs {
+            id,
+            attrs: FileAttributes::from(&metadata),
+        })
+    }
+
+    async fn lstat(&mut self, id: u32, path: String) -> Result<Attrs, Self::Error> {
+        self.stat(id, path).await
+    }
+
+    async fn fstat(&mut self, id: u32, handle: String) -> Result<Attrs, Self::Error> {
+        let open = self.handle(&handle)?;
+        let open = open.lock().await;
+        let metadata = match &*open {
+            OpenHandle::Read(file) | OpenHandle::Write { file, .. } => {
+                file.metadata().await.map_err(status)?
+            }
+            OpenHandle::Dir(_) => return Err(StatusCode::Failure),
+        };
+
+        Ok(Attrs {
+            id,
+            attrs: FileAttributes::from(&metadata),
+        })
+    }
+
+    // Objects have no owners or modes to change, accept and ignore
+    async fn setstat(
+        &mut self,
+        id: u32,
+        _path: String,
+        _attrs: FileAttributes,
+    ) -> Result<Status, Self::Error> {
+        Ok(ok(id))
+    }
+
+    async fn fsetstat(
+        &mut self,
+        id: u32,
+        _handle: String,
+        _attrs: FileAttributes,
+    ) -> Result<Status, Self::Error> {
+        Ok(ok(id))
+    }
+
+    async fn opendir(&mut self, id: u32, path: String) -> Result<Handle, Self::Error> {
+        let dir = self.resolve(&path)?;
+        let mut entries = fs::read_dir(&dir).await.map_err(status)?;
+        let mut files = Vec::new();
+
+        while let Ok(Some(entry)) = entries.next_entry().await {
+            if (dir == self.state.data_dir && entry.file_name() == INTERNAL_DIR)
+                || !inside_data_dir(&self.state, &entry.path())
+            {
+                continue;
+            }
+            let name = keys::decode_segment(&entry.file_name().to_string_lossy()).into_owned();
+            if let Ok(metadata) = entry.metadata().await {
+                files.push(File::new(name, FileAttributes::from(&metadata)));
+            }
+        }
+
+        Ok(Handle {
+            id,
+            handle: self.insert(OpenHandle::Dir(Some(files))),
+        })
+    }
+
+    async fn readdir(&mut self, id: u32, handle: String) -> Result<Name, Self::Error> {
+        let open = self.handle(&handle)?;
+        let mut open = open.lock().await;
+        let OpenHandle::Dir(files) = &mut *open else {
+            return Err(StatusCode::Failure);
+        };
+
+        // Everything goes out in the first batch, then EOF
+        match files.take() {
+            Some(files) => Ok(Name { id, files }),
+            None => Err(StatusCode::Eof),
+        }
+    }
+
+    async fn remove(&mut self, id: u32, filename: String) -> Result<Status, Self::Error> {
+        self.writable()?;
+        let path = self.resolve(&filename)?;
+        self.changeable(&path).await?;
+        let old = stats::size(&path).await;
+        fs::remove_file(&path).await.map_err(status)?;
+        self.state.stats.replaced(old, None);
+        self.state.handles.invalidate(&path);
+        info!("🗑️ Deleted over SFTP: {}", path.display());
+        Ok(ok(id))
+    }
+
+    async fn mkdir(
+        &mut self,
+        id: u32,
+        path: String,
+        _attrs: FileAttributes,
+    ) -> Result<Status, Self::Error> {
+        self.writable()?;
+        self.has_space(0)?;
+        fs::create_dir(self.resolve(&path)?)
+            .await
+            .map_err(status)?;
+        Ok(ok(id))
+    }
+
+    async fn rmdir(&mut self, id: u32, path: String) -> Result<Status, Self::Error> {
+        self.writable()?;
+        fs::remove_dir(self.resolve(&path)?)
+            .await
+            .map_err(status)?;
+        Ok(ok(id))
+    }
+
+    async fn rename(
+        &mut self,
+        id: u32,
+        oldpath: String,
+        newpath: String,
+    ) -> Result<Status, Self::Error> {
+        self.writable()?;
+        let oldpath = self.resolve(&oldpath)?;
+        let newpath = self.resolve(&newpath)?;
+        self.changeable(&oldpath).await?;
+        self.changeable(&newpath).await?;
+        tier::recall_under(&self.state, &oldpath).await.map_err(status)?;
+        fs::rename(oldpath, newpath)
+            .await
+            .map_err(status)?;
+        self.state.handles.clear();
+        Ok(ok(id))
+    }
+}
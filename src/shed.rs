@@ -0,0 +1,172 @@
+use axum::{
+    extract::{Request, State},
+    http::{HeaderValue, Method, StatusCode},
+    middleware::Next,
+    response::Response,
+};
+use std::{
+    sync::{
+        Arc,
+        atomic::{AtomicBool, AtomicU64, Ordering},
+    },
+    time::Duration,
+};
+use tracing::{info, warn};
+
+use crate::{AppState, ErrorResponse, admin, error_response, metrics::InFlight};
+
+// Load shedding. With --max-concurrent-requests, S3 API requests beyond that
+// many in flight are answered 503 SlowDown; with --min-free-disk, so are
+// writes while the data dir's filesystem has less free space than that.
+// Either way the response carries a Retry-After the SDKs honour: for
+// concurrency, roughly how long the requests in flight take to drain at the
+// last minute's rate, for disk pressure DISK_RETRY_AFTER. Shed requests are
+// counted on the status page.
+//
+// Free space is checked every DISK_CHECK_INTERVAL in the background, and
+// the server goes read-only while it's low: reads and deletes go through,
+// so space can be freed, and writes over WebDAV, SFTP and tus are refused
+// too. A write whose Content-Length would take free space under the limit
+// is refused up front rather than failing halfway. Going read-only and
+// back is logged once each way and shows on the status page.
+
+const DISK_CHECK_INTERVAL: Duration = Duration::from_secs(1);
+const DISK_RETRY_AFTER: u64 = 60;
+const MAX_RETRY_AFTER: u64 = 30;
+
+pub struct Shedder {
+    max_concurrent: u64,
+    min_free_disk: u64,
+    in_flight: AtomicU64,
+    // Free bytes as of the last check, u64::MAX while unknown, so writes
+    // don't each statvfs
+    free_disk: AtomicU64,
+    disk_low: AtomicBool,
+}
+
+impl Shedder {
+    pub fn new(max_concurrent: u64, min_free_disk: u64) -> Self {
+        Self {
+            max_concurrent,
+            min_free_disk,
+            in_flight: AtomicU64::new(0),
+            free_disk: AtomicU64::new(u64::MAX),
+            disk_low: AtomicBool::new(false),
+        }
+    }
+
+    // Whether writes are refused for lack of disk space
+    pub fn disk_low(&self) -> bool {
+        self.disk_low.load(Ordering::Relaxed)
+    }
+
+    // Whether a write of `len` bytes has to be refused, as it would take
+    // free disk space under --min-free-disk
+    pub fn refuses_write(&self, len: u64) -> bool {
+        self.min_free_disk > 0
+            && (self.disk_low()
+                || self.free_disk.load(Ordering::Relaxed).saturating_sub(len) < self.min_free_disk)
+    }
+}
+
+// Checks free disk space every DISK_CHECK_INTERVAL, with --min-free-disk
+pub fn spawn(state: Arc<AppState>) {
+    let min_free_disk = state.shedder.min_free_disk;
+    if min_free_disk == 0 {
+        return;
+    }
+    tokio::spawn(async move {
+        let mut interval = tokio::time::interval(DISK_CHECK_INTERVAL);
+        loop {
+            interval.tick().await;
+
+            let data_root = state.data_root.clone();
+            let free = tokio::task::spawn_blocking(move || admin::disk(&data_root))
+                .await
+                .ok()
+                .flatten()
+                .map_or(u64::MAX, |disk| disk.free_bytes);
+            let shedder = &state.shedder;
+            shedder.free_disk.store(free, Ordering::Relaxed);
+
+            let low = free < min_free_disk;
+            if shedder.disk_low.swap(low, Ordering::Relaxed) == low {
+                continue;
+            }
+            if low {
+                warn!(
+                    "💾 Free disk space is down to {} bytes, under {}: refusing writes until there's more",
+                    free, min_free_disk
+                );
+            } else {
+                info!("💾 Free disk space is back to {} bytes, accepting writes again", free);
+            }
+        }
+    });
+}
+
+// Requests that take up disk space. Multi-object deletes are POSTs too, but
+// free it
+fn is_write(request: &Request) -> bool {
+    let is_delete = || {
+        request.uri().path() == "/"
+            && request.uri().query().is_some_and(|query| {
+                query
+                    .split('&')
+                    .any(|pair| pair.split('=').next() == Some("delete"))
+            })
+    };
+    match *request.method() {
+        Method::PUT | Method::PATCH => true,
+        Method::POST => !is_delete(),
+        _ => false,
+    }
+}
+
+fn slow_down(message: &str, retry_after: u64) -> Response {
+    let mut response = error_response(
+        StatusCode::SERVICE_UNAVAILABLE,
+        ErrorResponse {
+            code: "SlowDown".to_string(),
+            message: message.to_string(),
+            max_size_allowed: None,
+        },
+    );
+    response
+        .headers_mut()
+        .insert("retry-after", HeaderValue::from(retry_after));
+    response
+}
+
+pub async fn shed_middleware(
+    State(state): State<Arc<AppState>>,
+    request: Request,
+    next: Next,
+) -> Response {
+    let shedder = &state.shedder;
+
+    if is_write(&request) {
+        let len = request
+            .headers()
+            .get("content-length")
+            .and_then(|len| len.to_str().ok())
+            .and_then(|len| len.parse().ok())
+            .unwrap_or(0);
+        if shedder.refuses_write(len) {
+            state.metrics.shed();
+            return slow_down("Disk space is low, please try again later", DISK_RETRY_AFTER);
+        }
+    }
+
+    let in_flight = shedder.in_flight.fetch_add(1, Ordering::Relaxed);
+    let _in_flight = InFlight(&shedder.in_flight);
+    if shedder.max_concurrent > 0 && in_flight >= shedder.max_concurrent {
+        state.metrics.shed();
+        let rate = state.metrics.counts().rates.requests.max(1.0);
+        let retry_after = ((in_flight as f64 / rate).ceil() as u64).clamp(1, MAX_RETRY_AFTER);
+        warn!("🚦 Shed a request, {} in flight", in_flight);
+        return slow_down("Too many requests in flight, please reduce your request rate", retry_after);
+    }
+
+    next.run(request).await
+}
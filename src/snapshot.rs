@@ -0,0 +1,264 @@
+use axum::{Json, extract::State};
+use serde::Serialize;
+use std::{
+    collections::HashMap,
+    fmt, io,
+    path::{Path, PathBuf},
+    str::FromStr,
+    sync::{Arc, Mutex},
+    time::Duration,
+};
+use tokio::fs;
+use tracing::{info, warn};
+
+use crate::{AppState, copy, object_path, walk_objects};
+
+// Scheduled snapshots. --snapshot-schedules takes NAME:KEEP pairs, like
+// "hourly:24,daily:7", NAME being hourly, daily or weekly. A snapshot is a
+// copy of every object in SNAPSHOT_DIR/NAME-YYYYMMDDTHHMMSSZ, laid out like
+// the data dir, made with reflinks where the filesystem has them. It's
+// written under a .partial name and renamed when complete, so a directory
+// without the suffix is always whole. Once a schedule's latest snapshot is
+// older than its interval a new one is taken, and the oldest beyond KEEP
+// are removed. Each node of a cluster snapshots its own data dir.
+
+pub const SNAPSHOT_DIR: &str = ".simple-s3/snapshots";
+const CHECK_INTERVAL: Duration = Duration::from_secs(60);
+const PARTIAL: &str = ".partial";
+const STAMP_FORMAT: &str = "%Y%m%dT%H%M%SZ";
+
+#[derive(Debug, Clone)]
+pub struct Schedule {
+    name: String,
+    every: Duration,
+    keep: usize,
+}
+
+impl FromStr for Schedule {
+    type Err = String;
+
+    fn from_str(s: &str) -> Result<Self, Self::Err> {
+        let (name, keep) = s
+            .split_once(':')
+            .ok_or_else(|| format!("expected NAME:KEEP, got {}", s))?;
+        let every = match name {
+            "hourly" => Duration::from_secs(60 * 60),
+            "daily" => Duration::from_secs(24 * 60 * 60),
+            "weekly" => Duration::from_secs(7 * 24 * 60 * 60),
+            _ => return Err(format!("unknown schedule {}, expected hourly, daily or weekly", name)),
+        };
+        let keep = keep
+            .parse()
+            .ok()
+            .filter(|keep| *keep > 0)
+            .ok_or_else(|| format!("invalid count to keep in {}", s))?;
+
+        Ok(Self {
+            name: name.to_string(),
+            every,
+            keep,
+        })
+    }
+}
+
+impl fmt::Display for Schedule {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        write!(f, "{}:{}", self.name, self.keep)
+    }
+}
+
+#[derive(Debug, Clone, Default, Serialize)]
+pub struct ScheduleStatus {
+    pub schedule: String,
+    pub last_success: Option<String>,
+    pub last_failure: Option<String>,
+    pub last_error: Option<String>,
+    pub snapshots: Vec<String>,
+}
+
+pub struct Snapshots {
+    schedules: Vec<Schedule>,
+    status: Mutex<HashMap<String, ScheduleStatus>>,
+}
+
+impl Snapshots {
+    pub fn new(schedules: Vec<Schedule>) -> Self {
+        Self {
+            schedules,
+            status: Mutex::new(HashMap::new()),
+        }
+    }
+
+    pub fn is_empty(&self) -> bool {
+        self.schedules.is_empty()
+    }
+
+    pub async fn statuses(&self, state: &AppState) -> Vec<ScheduleStatus> {
+        let mut statuses = Vec::new();
+        for schedule in &self.schedules {
+            let mut status = self
+                .status
+                .lock()
+                .unwrap()
+                .get(&schedule.name)
+                .cloned()
+                .unwrap_or_default();
+            status.schedule = schedule.to_string();
+            status.snapshots = existing(state, schedule).await;
+            // Before this process took any, the newest one on disk
+            if status.last_success.is_none() {
+                status.last_success = status
+                    .snapshots
+                    .last()
+                    .and_then(|name| taken_at(name))
+                    .map(|time| time.to_rfc3339());
+            }
+            statuses.push(status);
+        }
+        statuses
+    }
+}
+
+// Complete snapshots of `schedule`, oldest first
+async fn existing(state: &AppState, schedule: &Schedule) -> Vec<String> {
+    let Ok(mut entries) = fs::read_dir(state.data_dir.join(SNAPSHOT_DIR)).await else {
+        return Vec::new();
+    };
+    let prefix = format!("{}-", schedule.name);
+    let mut names = Vec::new();
+    while let Ok(Some(entry)) = entries.next_entry().await {
+        let name = entry.file_name().to_string_lossy().to_string();
+        if name.starts_with(&prefix) && !name.ends_with(PARTIAL) {
+            names.push(name);
+        }
+    }
+    // Timestamps in the names sort chronologically
+    names.sort();
+    names
+}
+
+fn taken_at(name: &str) -> Option<chrono::DateTime<chrono::Utc>> {
+    let stamp = name.rsplit_once('-')?.1;
+    chrono::NaiveDateTime::parse_from_str(stamp, STAMP_FORMAT)
+        .ok()
+        .map(|time| time.and_utc())
+}
+
+async fn copy_objects(state: &AppState, dir: &Path) -> io::Result<u64> {
+    let mut copied = 0;
+    for (key, _) in walk_objects(state).await {
+        let Ok(src) = object_path(state, &key) else {
+            continue;
+        };
+        let Ok(rel) = src.strip_prefix(&state.data_dir) else {
+            continue;
+        };
+        let dst = dir.join(rel);
+        if let Some(parent) = dst.parent() {
+            fs::create_dir_all(parent).await?;
+        }
+        match copy::copy_file(&src, &dst).await {
+            Ok(_) => copied += 1,
+            // Deleted since the walk
+            Err(e) if e.kind() == io::ErrorKind::NotFound => {}
+            Err(e) => return Err(e),
+        }
+    }
+    Ok(copied)
+}
+
+async fn take(state: &AppState, schedule: &Schedule) -> io::Result<PathBuf> {
+    let name = format!(
+        "{}-{}",
+        schedule.name,
+        chrono::Utc::now().format(STAMP_FORMAT)
+    );
+    let root = state.data_dir.join(SNAPSHOT_DIR);
+    let partial = root.join(format!("{}{}", name, PARTIAL));
+    let done = root.join(&name);
+
+    fs::create_dir_all(&partial).await?;
+    match copy_objects(state, &partial).await {
+        Ok(copied) => {
+            fs::rename(&partial, &done).await?;
+            info!("📸 Took snapshot {} ({} objects)", name, copied);
+            Ok(done)
+        }
+        Err(e) => {
+            let _ = fs::remove_dir_all(&partial).await;
+            Err(e)
+        }
+    }
+}
+
+async fn prune(state: &AppState, schedule: &Schedule) {
+    let names = existing(state, schedule).await;
+    let excess = names.len().saturating_sub(schedule.keep);
+    for name in &names[..excess] {
+        let path = state.data_dir.join(SNAPSHOT_DIR).join(name);
+        match fs::remove_dir_all(&path).await {
+            Ok(()) => info!("📸 Removed snapshot {}", name),
+            Err(e) => warn!("Failed to remove snapshot {}: {}", name, e),
+        }
+    }
+}
+
+async fn run_due(state: &AppState) {
+    let snapshots = &state.snapshots;
+    for schedule in &snapshots.schedules {
+        let latest = existing(state, schedule).await.last().and_then(|name| taken_at(name));
+        let due = latest.is_none_or(|latest| {
+            (chrono::Utc::now() - latest)
+                .to_std()
+                .is_ok_and(|age| age >= schedule.every)
+        });
+        if !due {
+            continue;
+        }
+
+        let result = take(state, schedule).await;
+        let now = chrono::Utc::now().to_rfc3339();
+        {
+            let mut status = snapshots.status.lock().unwrap();
+            let status = status.entry(schedule.name.clone()).or_default();
+            match &result {
+                Ok(_) => status.last_success = Some(now),
+                Err(e) => {
+                    warn!("📸 {} snapshot failed: {}", schedule.name, e);
+                    status.last_failure = Some(now);
+                    status.last_error = Some(e.to_string());
+                }
+            }
+        }
+        if result.is_ok() {
+            prune(state, schedule).await;
+        }
+    }
+}
+
+pub fn spawn(state: Arc<AppState>) {
+    if state.snapshots.is_empty() {
+        return;
+    }
+    tokio::spawn(async move {
+        // Leftovers of snapshots interrupted by a restart
+        if let Ok(mut entries) = fs::read_dir(state.data_dir.join(SNAPSHOT_DIR)).await {
+            while let Ok(Some(entry)) = entries.next_entry().await {
+                if entry.file_name().to_string_lossy().ends_with(PARTIAL) {
+                    let _ = fs::remove_dir_all(entry.path()).await;
+                }
+            }
+        }
+
+        let mut interval = tokio::time::interval(CHECK_INTERVAL);
+        loop {
+            interval.tick().await;
+            run_due(&state).await;
+        }
+    });
+}
+
+// GET /admin/snapshots on the admin port
+pub async fn snapshot_status(State(state): State<Arc<AppState>>) -> Json<Vec<ScheduleStatus>> {
+    Json(state.snapshots.statuses(&state).await)
+}
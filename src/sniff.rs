@@ -0,0 +1,69 @@
+use std::{io, path::Path};
+
+use crate::{AppState, handles::Handle};
+
+// Content types. Objects are served with the type their key's extension
+// maps to; the server keeps no Content-Type of its own, so without a known
+// extension that's application/octet-stream. With --sniff-content-type such
+// objects are served with the type their first SNIFF_LEN bytes point to
+// instead: the magic numbers of common formats (images, audio and video,
+// archives, PDFs, Office documents, executables...), or text/plain for
+// UTF-8 text. Known extensions always win, so a key can still be given a
+// type by naming it.
+
+const SNIFF_LEN: usize = 8 * 1024;
+const OCTET_STREAM: &str = "application/octet-stream";
+
+// By the extension alone, None when it's unknown
+fn by_extension(path: &Path) -> Option<String> {
+    mime_guess::from_path(path).first().map(|mime| mime.to_string())
+}
+
+fn by_content(head: &[u8]) -> &'static str {
+    if let Some(kind) = infer::get(head) {
+        return kind.mime_type();
+    }
+    // A multi-byte character may be cut off at the end of the sample
+    let text = match std::str::from_utf8(head) {
+        Ok(text) => text,
+        Err(e) if e.error_len().is_none() => {
+            std::str::from_utf8(&head[..e.valid_up_to()]).unwrap_or_default()
+        }
+        Err(_) => return OCTET_STREAM,
+    };
+    if !text.is_empty()
+        && !text
+            .chars()
+            .any(|c| c.is_control() && !c.is_ascii_whitespace())
+    {
+        "text/plain; charset=utf-8"
+    } else {
+        OCTET_STREAM
+    }
+}
+
+// The type of the object at `path` whose contents start with `data`
+pub fn content_type(state: &AppState, path: &Path, data: &[u8]) -> String {
+    if let Some(mime) = by_extension(path) {
+        return mime;
+    }
+    if !state.sniff_content_type {
+        return OCTET_STREAM.to_string();
+    }
+    by_content(&data[..data.len().min(SNIFF_LEN)]).to_string()
+}
+
+// Same for an object that isn't read whole, like on HEAD
+pub async fn content_type_of(state: &AppState, path: &Path, handle: &Handle) -> io::Result<String> {
+    if !state.sniff_content_type || by_extension(path).is_some() {
+        return Ok(content_type(state, path, &[]));
+    }
+    let file = handle.file.clone();
+    let mut head = vec![0; (handle.len as usize).min(SNIFF_LEN)];
+    let head = tokio::task::spawn_blocking(move || {
+        crate::read_at(&file, &mut head, 0).map(|()| head)
+    })
+    .await
+    .map_err(io::Error::other)??;
+    Ok(content_type(state, path, &head))
+}
@@ -0,0 +1,140 @@
+use axum::{
+    Json,
+    extract::{Request, State},
+    http::{HeaderMap, HeaderValue, Method, StatusCode},
+    middleware::Next,
+    response::{IntoResponse, Response},
+};
+use percent_encoding::percent_decode_str;
+use serde::Serialize;
+use std::{
+    path::Path,
+    sync::{Arc, Mutex},
+    time::Duration,
+};
+use tokio::fs;
+use tracing::info;
+
+use crate::{AppState, object_path, walk_objects};
+
+// Object count and total size of the bucket, kept up to date by every write
+// path instead of scanning the data dir on request. Writes that touch whole
+// directories (WebDAV MOVE, COPY and collection DELETE) aren't tracked, and
+// concurrent writes to the same key can skew the totals, so a background
+// pass rescans the data dir periodically and replaces them.
+
+pub const STATS_PATH: &str = "/.simple-s3/stats";
+const RECONCILE_INTERVAL: Duration = Duration::from_secs(15 * 60);
+
+#[derive(Debug, Default, Clone, Copy, Serialize)]
+pub struct Totals {
+    pub objects: u64,
+    pub bytes: u64,
+}
+
+#[derive(Default)]
+pub struct BucketStats {
+    totals: Mutex<Totals>,
+}
+
+impl BucketStats {
+    pub fn totals(&self) -> Totals {
+        *self.totals.lock().unwrap()
+    }
+
+    // An object went from `old` to `new` bytes, None meaning it doesn't exist
+    pub fn replaced(&self, old: Option<u64>, new: Option<u64>) {
+        let mut totals = self.totals.lock().unwrap();
+
+        if let Some(old) = old {
+            totals.objects = totals.objects.saturating_sub(1);
+            totals.bytes = totals.bytes.saturating_sub(old);
+        }
+        if let Some(new) = new {
+            totals.objects += 1;
+            totals.bytes += new;
+        }
+    }
+
+    async fn reconcile(&self, state: &AppState) {
+        let objects = walk_objects(state).await;
+        let scanned = Totals {
+            objects: objects.len() as u64,
+            bytes: objects.iter().map(|(_, size)| size).sum(),
+        };
+
+        let mut totals = self.totals.lock().unwrap();
+        if totals.objects != scanned.objects || totals.bytes != scanned.bytes {
+            info!(
+                "📊 Bucket stats reconciled: {} objects, {} bytes",
+                scanned.objects, scanned.bytes
+            );
+        }
+        *totals = scanned;
+    }
+}
+
+// Size of the object at `path`, None if there is no object there
+pub async fn size(path: &Path) -> Option<u64> {
+    fs::metadata(path)
+        .await
+        .ok()
+        .filter(|metadata| metadata.is_file())
+        .map(|metadata| metadata.len())
+}
+
+// Scans once at startup, then every RECONCILE_INTERVAL
+pub fn spawn(state: Arc<AppState>) {
+    tokio::spawn(async move {
+        let mut interval = tokio::time::interval(RECONCILE_INTERVAL);
+        loop {
+            interval.tick().await;
+            state.stats.reconcile(&state).await;
+        }
+    });
+}
+
+// Track object writes made through the S3 API by comparing the object
+// before and after the request
+pub async fn track_changes(
+    State(state): State<Arc<AppState>>,
+    request: Request,
+    next: Next,
+) -> Response {
+    if !matches!(*request.method(), Method::PUT | Method::POST | Method::DELETE) {
+        return next.run(request).await;
+    }
+
+    let key = percent_decode_str(request.uri().path())
+        .decode_utf8_lossy()
+        .to_string();
+    let Ok(path) = object_path(&state, &key) else {
+        return next.run(request).await;
+    };
+
+    let old = size(&path).await;
+    let response = next.run(request).await;
+
+    if response.status().is_success() {
+        state.stats.replaced(old, size(&path).await);
+        state.handles.invalidate(&path);
+    }
+
+    response
+}
+
+// HEAD /: HeadBucket, with the bucket totals as extension headers
+pub async fn head_bucket(State(state): State<Arc<AppState>>) -> impl IntoResponse {
+    let totals = state.stats.totals();
+
+    let mut headers = HeaderMap::new();
+    headers.insert("x-simple-s3-object-count", HeaderValue::from(totals.objects));
+    headers.insert("x-simple-s3-bytes-used", HeaderValue::from(totals.bytes));
+
+    (StatusCode::OK, headers)
+}
+
+// GET /.simple-s3/stats
+pub async fn bucket_stats(State(state): State<Arc<AppState>>) -> Json<Totals> {
+    Json(state.stats.totals())
+}
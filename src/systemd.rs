@@ -0,0 +1,114 @@
+use std::{net::TcpListener, time::Duration};
+use tracing::{info, warn};
+
+// systemd integration, without linking libsystemd. Sockets passed by socket
+// activation (LISTEN_FDS, named with FileDescriptorName=) are served instead
+// of binding ports, so systemd holds connections while the service restarts.
+// With Type=notify the server reports READY=1 once it's accepting, and pings
+// the watchdog at half of WatchdogSec=. Outside systemd all of this does
+// nothing.
+
+// First fd systemd passes, SD_LISTEN_FDS_START
+#[cfg(unix)]
+const LISTEN_FDS_START: i32 = 3;
+
+// The sockets passed to this process, with their names. Unnamed sockets are
+// called "unknown", as systemd does
+#[cfg(unix)]
+pub fn listeners() -> Vec<(String, TcpListener)> {
+    use std::os::fd::FromRawFd;
+
+    let ours = std::env::var("LISTEN_PID")
+        .ok()
+        .and_then(|pid| pid.parse::<u32>().ok())
+        .is_some_and(|pid| pid == std::process::id());
+    let count = std::env::var("LISTEN_FDS")
+        .ok()
+        .and_then(|count| count.parse::<i32>().ok())
+        .unwrap_or(0);
+    if !ours || count <= 0 {
+        return Vec::new();
+    }
+    let names = std::env::var("LISTEN_FDNAMES").unwrap_or_default();
+    let mut names = names.split(':');
+
+    (LISTEN_FDS_START..LISTEN_FDS_START + count)
+        .map(|fd| {
+            let name = names.next().filter(|name| !name.is_empty()).unwrap_or("unknown");
+            // Inherited fds don't have close-on-exec set
+            unsafe { libc::fcntl(fd, libc::F_SETFD, libc::FD_CLOEXEC) };
+            let listener = unsafe { TcpListener::from_raw_fd(fd) };
+            info!("🔌 Using socket {} passed by systemd (fd {})", name, fd);
+            (name.to_string(), listener)
+        })
+        .collect()
+}
+
+#[cfg(not(unix))]
+pub fn listeners() -> Vec<(String, TcpListener)> {
+    Vec::new()
+}
+
+// Removes the socket called `name` from `listeners`, making it usable by tokio
+pub fn take(
+    listeners: &mut Vec<(String, TcpListener)>,
+    name: impl Fn(&str) -> bool,
+) -> std::io::Result<Option<tokio::net::TcpListener>> {
+    let Some(index) = listeners.iter().position(|(n, _)| name(n)) else {
+        return Ok(None);
+    };
+    let (_, listener) = listeners.remove(index);
+    listener.set_nonblocking(true)?;
+    tokio::net::TcpListener::from_std(listener).map(Some)
+}
+
+// Sends a state like "READY=1" to the service manager, if there is one
+#[cfg(unix)]
+pub fn notify(message: &str) {
+    use std::os::unix::net::UnixDatagram;
+
+    let Some(path) = std::env::var_os("NOTIFY_SOCKET") else {
+        return;
+    };
+    let sent = UnixDatagram::unbound().and_then(|socket| {
+        // Names starting with @ are in the abstract namespace
+        #[cfg(target_os = "linux")]
+        if let Some(name) = path.as_encoded_bytes().strip_prefix(b"@") {
+            use std::os::linux::net::SocketAddrExt;
+            let addr = std::os::unix::net::SocketAddr::from_abstract_name(name)?;
+            return socket.send_to_addr(message.as_bytes(), &addr);
+        }
+        socket.send_to(message.as_bytes(), &path)
+    });
+    if let Err(e) = sent {
+        warn!("Failed to notify systemd: {}", e);
+    }
+}
+
+#[cfg(not(unix))]
+pub fn notify(_message: &str) {}
+
+// Pings the watchdog for as long as the runtime is responsive
+pub fn spawn_watchdog() {
+    let for_us = std::env::var("WATCHDOG_PID")
+        .ok()
+        .and_then(|pid| pid.parse::<u32>().ok())
+        .is_none_or(|pid| pid == std::process::id());
+    let Some(timeout) = std::env::var("WATCHDOG_USEC")
+        .ok()
+        .and_then(|usec| usec.parse::<u64>().ok())
+        .filter(|usec| *usec > 0 && for_us)
+        .map(Duration::from_micros)
+    else {
+        return;
+    };
+
+    info!("🐕 Pinging the systemd watchdog every {:?}", timeout / 2);
+    tokio::spawn(async move {
+        let mut interval = tokio::time::interval(timeout / 2);
+        loop {
+            interval.tick().await;
+            notify("WATCHDOG=1");
+        }
+    });
+}
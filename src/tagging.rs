@@ -0,0 +1,442 @@
+use axum::{
+    body::Bytes,
+    http::{HeaderMap, HeaderValue, StatusCode},
+    response::{IntoResponse, Response},
+};
+use percent_encoding::percent_decode_str;
+use serde::{Deserialize, Serialize};
+use sha2::{Digest, Sha256};
+use std::{
+    collections::HashSet,
+    fs::Metadata,
+    io,
+    path::{Path, PathBuf},
+    time::SystemTime,
+};
+use tokio::fs;
+use tracing::{info, warn};
+
+use crate::{
+    AppState, ErrorResponse, error_response, object_path, object_stamp, tmp_path, walk,
+    write_and_rename,
+};
+
+// Object tags, S3's ?tagging subresource. PUT, GET and DELETE /{key}?tagging
+// replace, read and remove an object's tags, and a PUT or copy can set them
+// with the x-amz-tagging header, as a URL query string. A copy keeps the
+// source's tags unless x-amz-tagging-directive is REPLACE.
+//
+// Tags are kept under TAGS_DIR, one file per key stamped with the object's
+// modification time and size, like checksums. Writing the object anew gives
+// it a new stamp, so its old tags are gone, as in S3 where they belong to
+// what was written.
+//
+// Lifecycle rules pick objects by tag, and reading the tags of every object
+// on each pass would be slow, so tags are also indexed under TAG_INDEX_DIR:
+// a directory per tag holding a file per object with it, naming its key.
+// Setting tags adds the object to the directories of its new tags before
+// taking it out of those of its old ones. Entries an overwrite or delete
+// left behind are dropped when a lookup finds the object no longer has the
+// tag. Tags set before the index existed are added by the first lookup,
+// which leaves INDEXED_FILE behind.
+
+pub const TAGS_DIR: &str = ".simple-s3/tags";
+const TAG_INDEX_DIR: &str = ".simple-s3/tag-index";
+const INDEXED_FILE: &str = ".simple-s3/tag-index/complete";
+const MAX_TAGS: usize = 10;
+const MAX_TAG_KEY_LEN: usize = 128;
+const MAX_TAG_VALUE_LEN: usize = 256;
+const XMLNS: &str = "http://s3.amazonaws.com/doc/2006-03-01/";
+
+pub type Tags = Vec<(String, String)>;
+
+#[derive(Debug, Serialize, Deserialize)]
+#[serde(rename = "Tagging")]
+struct Tagging {
+    #[serde(rename = "@xmlns", default)]
+    xmlns: String,
+    #[serde(rename = "TagSet")]
+    tag_set: TagSet,
+}
+
+#[derive(Debug, Serialize, Deserialize)]
+struct TagSet {
+    #[serde(rename = "Tag", default)]
+    tags: Vec<Tag>,
+}
+
+#[derive(Debug, Serialize, Deserialize)]
+struct Tag {
+    #[serde(rename = "Key")]
+    key: String,
+    #[serde(rename = "Value", default)]
+    value: String,
+}
+
+#[derive(Serialize, Deserialize)]
+struct Stored {
+    stamp: String,
+    tags: Tags,
+}
+
+fn refusal(status: StatusCode, code: &str, message: &str) -> Response {
+    error_response(
+        status,
+        ErrorResponse {
+            code: code.to_string(),
+            message: message.to_string(),
+            max_size_allowed: None,
+        },
+    )
+}
+
+fn no_such_key() -> Response {
+    refusal(
+        StatusCode::NOT_FOUND,
+        "NoSuchKey",
+        "The specified key does not exist",
+    )
+}
+
+// Why `tags` can't be an object's tags, None if they can
+fn invalid(tags: &Tags) -> Option<&'static str> {
+    if tags.len() > MAX_TAGS {
+        return Some("Object tags cannot be greater than 10");
+    }
+    let mut keys = HashSet::new();
+    for (key, value) in tags {
+        if key.is_empty() || key.chars().count() > MAX_TAG_KEY_LEN {
+            return Some("The TagKey you have provided is invalid");
+        }
+        if value.chars().count() > MAX_TAG_VALUE_LEN {
+            return Some("The TagValue you have provided is invalid");
+        }
+        if !keys.insert(key) {
+            return Some("Cannot provide multiple Tags with the same key");
+        }
+    }
+    None
+}
+
+fn invalid_tag(message: &str) -> Response {
+    refusal(StatusCode::BAD_REQUEST, "InvalidTag", message)
+}
+
+const HEADER_ENCODING: &str = "The header 'x-amz-tagging' shall be encoded as UTF-8 then \
+URLEncoded URL query parameters without tag name duplicates.";
+
+// The tags in an x-amz-tagging header, None without one
+fn header_tags(headers: &HeaderMap) -> Option<Result<Tags, &'static str>> {
+    let value = headers.get("x-amz-tagging")?;
+    let Ok(value) = value.to_str() else {
+        return Some(Err(HEADER_ENCODING));
+    };
+    let decode = |part: &str| {
+        percent_decode_str(&part.replace('+', " "))
+            .decode_utf8_lossy()
+            .into_owned()
+    };
+    let tags: Tags = value
+        .split('&')
+        .filter(|pair| !pair.is_empty())
+        .map(|pair| {
+            let (key, value) = pair.split_once('=').unwrap_or((pair, ""));
+            (decode(key), decode(value))
+        })
+        .collect();
+    Some(match invalid(&tags) {
+        Some(message) => Err(message),
+        None => Ok(tags),
+    })
+}
+
+// The refusal for an x-amz-tagging header that doesn't hold valid tags, None
+// if it may go ahead
+pub fn refuse_invalid(headers: &HeaderMap) -> Option<Response> {
+    match header_tags(headers)? {
+        Ok(_) => None,
+        Err(message) => Some(invalid_tag(message)),
+    }
+}
+
+// The tags a PUT or copy sets with x-amz-tagging, once refuse_invalid let it
+// through
+pub fn from_headers(headers: &HeaderMap) -> Option<Tags> {
+    header_tags(headers)?.ok()
+}
+
+// The x-amz-tagging header for a Tagging document, as browser uploads give
+// their tags, None if it isn't one
+pub fn header_value(xml: &str) -> Option<String> {
+    let tagging = serde_xml_rs::from_str::<Tagging>(xml).ok()?;
+    let mut header = url::form_urlencoded::Serializer::new(String::new());
+    for tag in &tagging.tag_set.tags {
+        header.append_pair(&tag.key, &tag.value);
+    }
+    Some(header.finish())
+}
+
+// Whether a copy takes the tags of its request rather than of its source
+pub fn replaces(headers: &HeaderMap) -> bool {
+    headers
+        .get("x-amz-tagging-directive")
+        .and_then(|directive| directive.to_str().ok())
+        .is_some_and(|directive| directive.eq_ignore_ascii_case("REPLACE"))
+}
+
+fn hashed(key: &str) -> String {
+    hex::encode(Sha256::digest(key.as_bytes()))
+}
+
+fn tags_path(state: &AppState, key: &str) -> PathBuf {
+    state.data_dir.join(TAGS_DIR).join(hashed(key))
+}
+
+// The index directory of the objects tagged `tag_key`=`value`
+fn index_dir(state: &AppState, tag_key: &str, value: &str) -> PathBuf {
+    let tag = format!("{}:{}={}", tag_key.len(), tag_key, value);
+    state.data_dir.join(TAG_INDEX_DIR).join(hashed(&tag))
+}
+
+async fn write_file(state: &AppState, dir: &Path, path: &Path, data: &[u8]) -> io::Result<()> {
+    fs::create_dir_all(dir).await?;
+    let tmp = tmp_path(state);
+    let written = write_and_rename(state, &tmp, path, data).await;
+    if written.is_err() {
+        let _ = fs::remove_file(&tmp).await;
+    }
+    written
+}
+
+async fn add_to_index(state: &AppState, key: &str, tags: &Tags) -> io::Result<()> {
+    for (tag_key, value) in tags {
+        let dir = index_dir(state, tag_key, value);
+        write_file(state, &dir, &dir.join(hashed(key)), key.as_bytes()).await?;
+    }
+    Ok(())
+}
+
+async fn remove_from_index(state: &AppState, key: &str, tags: &Tags) {
+    for (tag_key, value) in tags {
+        let _ = fs::remove_file(index_dir(state, tag_key, value).join(hashed(key))).await;
+    }
+}
+
+// Keeps `tags` for the object now at `file_path`, or forgets its tags if
+// there are none
+pub async fn store(state: &AppState, key: &str, file_path: &Path, tags: Tags) -> io::Result<()> {
+    let path = tags_path(state, key);
+    let previous = match fs::read(&path).await {
+        Ok(data) => serde_json::from_slice::<Stored>(&data)
+            .map(|stored| stored.tags)
+            .unwrap_or_default(),
+        Err(_) => Tags::new(),
+    };
+    if tags.is_empty() {
+        match fs::remove_file(&path).await {
+            Err(e) if e.kind() != io::ErrorKind::NotFound => return Err(e),
+            _ => {}
+        }
+    } else {
+        let metadata = fs::metadata(file_path).await?;
+        let stored = Stored {
+            stamp: object_stamp(metadata.modified()?, metadata.len()),
+            tags: tags.clone(),
+        };
+        let json = serde_json::to_vec(&stored).map_err(io::Error::other)?;
+        write_file(state, &state.data_dir.join(TAGS_DIR), &path, &json).await?;
+        add_to_index(state, key, &tags).await?;
+    }
+    let dropped: Tags = previous
+        .into_iter()
+        .filter(|tag| !tags.contains(tag))
+        .collect();
+    remove_from_index(state, key, &dropped).await;
+    Ok(())
+}
+
+// Like store, for writes that have already succeeded and can't be refused
+// any more
+pub async fn store_or_warn(state: &AppState, key: &str, file_path: &Path, tags: Tags) {
+    if let Err(e) = store(state, key, file_path, tags).await {
+        warn!("Failed to keep the tags of {}: {}", key, e);
+    }
+}
+
+// The tags of `key`, if they were set on the object as it is now
+pub async fn load(state: &AppState, key: &str, modified: SystemTime, len: u64) -> Tags {
+    let Ok(data) = fs::read(tags_path(state, key)).await else {
+        return Tags::new();
+    };
+    serde_json::from_slice::<Stored>(&data)
+        .ok()
+        .filter(|stored| stored.stamp == object_stamp(modified, len))
+        .map(|stored| stored.tags)
+        .unwrap_or_default()
+}
+
+// The tags of the object at `file_path`, None if it doesn't exist
+pub async fn load_file(state: &AppState, key: &str, file_path: &Path) -> Option<Tags> {
+    let metadata = fs::metadata(file_path).await.ok()?;
+    let modified = metadata.modified().ok()?;
+    Some(load(state, key, modified, metadata.len()).await)
+}
+
+// Adds the tags set before the index existed to it, finding the keys their
+// files are named after by walking the objects. Once done, INDEXED_FILE
+// keeps later lookups from doing it again
+async fn index_existing(state: &AppState) {
+    let indexed = state.data_dir.join(INDEXED_FILE);
+    if fs::try_exists(&indexed).await.unwrap_or(false) {
+        return;
+    }
+    let mut names = HashSet::new();
+    if let Ok(mut entries) = fs::read_dir(state.data_dir.join(TAGS_DIR)).await {
+        while let Ok(Some(entry)) = entries.next_entry().await {
+            names.insert(entry.file_name().to_string_lossy().into_owned());
+        }
+    }
+    let mut tagged = Vec::new();
+    if !names.is_empty() {
+        walk::walk(state, state.data_dir.clone(), |key, metadata| {
+            if names.contains(&hashed(&key)) {
+                tagged.push((key, metadata));
+            }
+        })
+        .await;
+    }
+
+    for (key, metadata) in &tagged {
+        let Ok(modified) = metadata.modified() else {
+            continue;
+        };
+        let tags = load(state, key, modified, metadata.len()).await;
+        if let Err(e) = add_to_index(state, key, &tags).await {
+            warn!("Failed to index the tags of {}: {}", key, e);
+            return;
+        }
+    }
+    if let Err(e) = write_file(state, &state.data_dir.join(TAG_INDEX_DIR), &indexed, b"").await {
+        warn!("Failed to mark the tags as indexed: {}", e);
+        return;
+    }
+    info!("🏷️ Indexed the tags of {} objects", tagged.len());
+}
+
+// The objects tagged `tag_key`=`value`, with their metadata and all their
+// tags, as found in the index. Entries for objects that no longer have the
+// tag are dropped
+pub async fn tagged_with(
+    state: &AppState,
+    tag_key: &str,
+    value: &str,
+) -> Vec<(String, Metadata, Tags)> {
+    index_existing(state).await;
+
+    let mut tagged = Vec::new();
+    let Ok(mut entries) = fs::read_dir(index_dir(state, tag_key, value)).await else {
+        return tagged;
+    };
+    while let Ok(Some(entry)) = entries.next_entry().await {
+        let Ok(key) = fs::read_to_string(entry.path()).await else {
+            continue;
+        };
+        let found = async {
+            let file_path = object_path(state, &key).ok()?;
+            let metadata = fs::metadata(&file_path).await.ok()?;
+            let tags = load(state, &key, metadata.modified().ok()?, metadata.len()).await;
+            let has_tag = tags.iter().any(|(k, v)| k == tag_key && v == value);
+            (metadata.is_file() && has_tag).then_some((metadata, tags))
+        }
+        .await;
+        match found {
+            Some((metadata, tags)) => tagged.push((key, metadata, tags)),
+            None => {
+                let _ = fs::remove_file(entry.path()).await;
+            }
+        }
+    }
+    tagged
+}
+
+// x-amz-tagging-count for a GET or HEAD of an object with tags
+pub async fn insert_count(
+    state: &AppState,
+    key: &str,
+    modified: SystemTime,
+    len: u64,
+    headers: &mut HeaderMap,
+) {
+    let count = load(state, key, modified, len).await.len();
+    if count > 0 {
+        headers.insert("x-amz-tagging-count", HeaderValue::from(count));
+    }
+}
+
+// GET /{key}?tagging
+pub async fn get(state: &AppState, key: &str, file_path: &Path) -> Result<Response, StatusCode> {
+    let Some(tags) = load_file(state, key, file_path).await else {
+        return Ok(no_such_key());
+    };
+    let tagging = Tagging {
+        xmlns: XMLNS.to_string(),
+        tag_set: TagSet {
+            tags: tags
+                .into_iter()
+                .map(|(key, value)| Tag { key, value })
+                .collect(),
+        },
+    };
+    let xml = serde_xml_rs::to_string(&tagging).map_err(|_| StatusCode::INTERNAL_SERVER_ERROR)?;
+    let mut headers = HeaderMap::new();
+    headers.insert("content-type", HeaderValue::from_static("application/xml"));
+    Ok((headers, xml).into_response())
+}
+
+// PUT /{key}?tagging, replacing the object's tags with those in `body`
+pub async fn put(
+    state: &AppState,
+    key: &str,
+    file_path: &Path,
+    body: &Bytes,
+) -> Result<Response, StatusCode> {
+    let Ok(tagging) = serde_xml_rs::from_reader::<Tagging, _>(body.as_ref()) else {
+        return Ok(refusal(
+            StatusCode::BAD_REQUEST,
+            "MalformedXML",
+            "The XML you provided was not well-formed or did not validate against our published schema",
+        ));
+    };
+    let tags: Tags = tagging
+        .tag_set
+        .tags
+        .into_iter()
+        .map(|tag| (tag.key, tag.value))
+        .collect();
+    if let Some(message) = invalid(&tags) {
+        return Ok(invalid_tag(message));
+    }
+    if !fs::try_exists(file_path).await.unwrap_or(false) {
+        return Ok(no_such_key());
+    }
+    let count = tags.len();
+    if let Err(e) = store(state, key, file_path, tags).await {
+        warn!("Failed to store the tags of {}: {}", key, e);
+        return Err(StatusCode::INTERNAL_SERVER_ERROR);
+    }
+    info!("🏷️ Tagged {} with {} tags", key, count);
+    Ok(StatusCode::OK.into_response())
+}
+
+// DELETE /{key}?tagging
+pub async fn delete(state: &AppState, key: &str, file_path: &Path) -> Result<Response, StatusCode> {
+    if !fs::try_exists(file_path).await.unwrap_or(false) {
+        return Ok(no_such_key());
+    }
+    if let Err(e) = store(state, key, file_path, Tags::new()).await {
+        warn!("Failed to remove the tags of {}: {}", key, e);
+        return Err(StatusCode::INTERNAL_SERVER_ERROR);
+    }
+    info!("🏷️ Removed the tags of {}", key);
+    Ok(StatusCode::NO_CONTENT.into_response())
+}
@@ -0,0 +1,526 @@
+use percent_encoding::utf8_percent_encode;
+use serde::{Deserialize, Serialize};
+use std::{
+    collections::HashMap,
+    fs::{File, FileTimes, Metadata},
+    io,
+    path::{Path, PathBuf},
+    sync::{Arc, Mutex},
+    time::{Duration, SystemTime},
+};
+use tokio::fs;
+use tracing::{info, warn};
+
+use crate::{
+    AppState, KEY_ENCODE_SET, copy, keys, normalize, object_path, relative_path, sign_request,
+    tmp_path, walk, write_and_rename,
+};
+
+// Tiering. With --tier-after-days N and a cold backend, --cold-dir,
+// --cold-url (S3, or GCS through its XML API) or --cold-azure-url, objects nobody has read for N days are copied to the backend
+// and replaced by a sparse stub of the same size and modification time, so
+// listings, sizes and ETags don't change while the data takes no space. The
+// stubs are recorded in INDEX_FILE. Reading a tiered object, or moving it
+// away from its key, first recalls it: the data is fetched back into place
+// and removed from the backend. An object rewritten since it was tiered no
+// longer matches its stub and is left alone, its cold copy removed on the
+// next pass.
+//
+// Reads only move an object's access time forward once it's ATIME_RESOLUTION
+// old, so a hot object isn't written to on every GET. Objects are moved one
+// at a time; in cluster mode each node tiers its own.
+
+const TIER_INTERVAL: Duration = Duration::from_secs(60 * 60);
+const INDEX_FILE: &str = ".simple-s3/tiered.json";
+const ATIME_RESOLUTION: Duration = Duration::from_secs(24 * 60 * 60);
+
+pub enum Backend {
+    Dir(PathBuf),
+    S3 {
+        client: reqwest::Client,
+        url: String,
+        access_key: String,
+        secret_key: String,
+    },
+    // An Azure Blob Storage container, reached with a SAS token that allows
+    // reading, writing and deleting blobs
+    Azure {
+        client: reqwest::Client,
+        url: String,
+        sas: String,
+    },
+}
+
+impl Backend {
+    fn path(dir: &Path, key: &str) -> io::Result<PathBuf> {
+        relative_path(key)
+            .map(|rel| dir.join(rel))
+            .ok_or_else(|| io::Error::other("invalid key"))
+    }
+
+    fn url(url: &str, key: &str) -> String {
+        format!("{}/{}", url, utf8_percent_encode(key, KEY_ENCODE_SET))
+    }
+
+    fn azure_url(url: &str, sas: &str, key: &str) -> String {
+        format!("{}?{}", Self::url(url, key), sas)
+    }
+
+    async fn send(
+        client: &reqwest::Client,
+        access_key: &str,
+        secret_key: &str,
+        request: reqwest::RequestBuilder,
+    ) -> io::Result<reqwest::Response> {
+        let (_, request) = request.build_split();
+        let mut request = request.map_err(io::Error::other)?;
+        sign_request(access_key, secret_key, &mut request);
+        client.execute(request).await.map_err(io::Error::other)
+    }
+
+    async fn upload(&self, key: &str, path: &Path) -> io::Result<()> {
+        match self {
+            Self::Dir(dir) => {
+                let dst = Self::path(dir, key)?;
+                if let Some(parent) = dst.parent() {
+                    fs::create_dir_all(parent).await?;
+                }
+                copy::copy_file(path, &dst).await?;
+            }
+            Self::S3 {
+                client,
+                url,
+                access_key,
+                secret_key,
+            } => {
+                let bytes = fs::read(path).await?;
+                let request = client.put(Self::url(url, key)).body(bytes);
+                Self::send(client, access_key, secret_key, request)
+                    .await?
+                    .error_for_status()
+                    .map_err(io::Error::other)?;
+            }
+            Self::Azure { client, url, sas } => {
+                let bytes = fs::read(path).await?;
+                client
+                    .put(Self::azure_url(url, sas, key))
+                    .header("x-ms-blob-type", "BlockBlob")
+                    .body(bytes)
+                    .send()
+                    .await
+                    .and_then(|response| response.error_for_status())
+                    .map_err(io::Error::other)?;
+            }
+        }
+        Ok(())
+    }
+
+    async fn download(&self, key: &str, dst: &Path) -> io::Result<()> {
+        match self {
+            Self::Dir(dir) => {
+                copy::copy_file(&Self::path(dir, key)?, dst).await?;
+            }
+            Self::S3 {
+                client,
+                url,
+                access_key,
+                secret_key,
+            } => {
+                let request = client.get(Self::url(url, key));
+                let bytes = Self::send(client, access_key, secret_key, request)
+                    .await?
+                    .error_for_status()
+                    .map_err(io::Error::other)?
+                    .bytes()
+                    .await
+                    .map_err(io::Error::other)?;
+                fs::write(dst, bytes).await?;
+            }
+            Self::Azure { client, url, sas } => {
+                let bytes = client
+                    .get(Self::azure_url(url, sas, key))
+                    .send()
+                    .await
+                    .and_then(|response| response.error_for_status())
+                    .map_err(io::Error::other)?
+                    .bytes()
+                    .await
+                    .map_err(io::Error::other)?;
+                fs::write(dst, bytes).await?;
+            }
+        }
+        Ok(())
+    }
+
+    async fn remove(&self, key: &str) -> io::Result<()> {
+        match self {
+            Self::Dir(dir) => match fs::remove_file(Self::path(dir, key)?).await {
+                Err(e) if e.kind() != io::ErrorKind::NotFound => return Err(e),
+                _ => {}
+            },
+            Self::S3 {
+                client,
+                url,
+                access_key,
+                secret_key,
+            } => {
+                let request = client.delete(Self::url(url, key));
+                let response = Self::send(client, access_key, secret_key, request).await?;
+                if response.status() != reqwest::StatusCode::NOT_FOUND {
+                    response.error_for_status().map_err(io::Error::other)?;
+                }
+            }
+            Self::Azure { client, url, sas } => {
+                let response = client
+                    .delete(Self::azure_url(url, sas, key))
+                    .send()
+                    .await
+                    .map_err(io::Error::other)?;
+                if response.status() != reqwest::StatusCode::NOT_FOUND {
+                    response.error_for_status().map_err(io::Error::other)?;
+                }
+            }
+        }
+        Ok(())
+    }
+}
+
+// What a tiered object's stub looks like, so a rewrite can be told apart
+#[derive(Debug, Clone, Copy, PartialEq, Serialize, Deserialize)]
+struct Stub {
+    len: u64,
+    modified_secs: u64,
+    modified_nanos: u32,
+}
+
+impl Stub {
+    fn of(metadata: &Metadata) -> Option<Self> {
+        let modified = metadata
+            .modified()
+            .ok()?
+            .duration_since(SystemTime::UNIX_EPOCH)
+            .ok()?;
+        Some(Self {
+            len: metadata.len(),
+            modified_secs: modified.as_secs(),
+            modified_nanos: modified.subsec_nanos(),
+        })
+    }
+
+    fn modified(&self) -> SystemTime {
+        SystemTime::UNIX_EPOCH + Duration::new(self.modified_secs, self.modified_nanos)
+    }
+}
+
+pub struct Tiering {
+    after: Duration,
+    backend: Backend,
+    index: PathBuf,
+    // Stubs by key, as in INDEX_FILE
+    stubs: Mutex<HashMap<String, Stub>>,
+    // Keys being moved to the cold tier, and whether they were read since
+    picked: Mutex<HashMap<String, bool>>,
+    // Held while an object moves between tiers, and around every change to
+    // `stubs`, so the index is saved in order
+    moving: tokio::sync::Mutex<()>,
+}
+
+impl Tiering {
+    pub async fn load(data_dir: &Path, after_days: u64, backend: Backend) -> io::Result<Self> {
+        let index = data_dir.join(INDEX_FILE);
+        let stubs = match fs::read(&index).await {
+            Ok(raw) => serde_json::from_slice(&raw)?,
+            Err(e) if e.kind() == io::ErrorKind::NotFound => HashMap::new(),
+            Err(e) => return Err(e),
+        };
+        Ok(Self {
+            after: Duration::from_secs(after_days * 24 * 60 * 60),
+            backend,
+            index,
+            stubs: Mutex::new(stubs),
+            picked: Mutex::new(HashMap::new()),
+            moving: tokio::sync::Mutex::new(()),
+        })
+    }
+
+    fn stub(&self, key: &str) -> Option<Stub> {
+        self.stubs.lock().unwrap().get(key).copied()
+    }
+
+    async fn save(&self, state: &AppState) -> io::Result<()> {
+        let raw = serde_json::to_vec(&*self.stubs.lock().unwrap())?;
+        let tmp = tmp_path(state);
+        let saved = write_and_rename(state, &tmp, &self.index, &raw).await;
+        if saved.is_err() {
+            let _ = fs::remove_file(&tmp).await;
+        }
+        saved
+    }
+
+    // Drops the stub of an object that was rewritten or removed, and its
+    // cold copy. Called with `moving` held
+    async fn forget(&self, state: &AppState, key: &str) -> io::Result<()> {
+        self.stubs.lock().unwrap().remove(key);
+        self.save(state).await?;
+        self.backend.remove(key).await
+    }
+}
+
+async fn set_times(path: &Path, times: FileTimes) -> io::Result<()> {
+    let path = path.to_path_buf();
+    tokio::task::spawn_blocking(move || {
+        File::options().write(true).open(path)?.set_times(times)
+    })
+    .await
+    .map_err(io::Error::other)?
+}
+
+// Brings a tiered object back before it's read or moved, and records the
+// access. A no-op without tiering
+pub async fn recall(state: &AppState, key: &str, path: &Path) -> io::Result<()> {
+    let Some(tiering) = &state.tiering else {
+        return Ok(());
+    };
+    let key = &*normalize::stored_key(state, key);
+
+    // Stops a move to the cold tier under way. If it got past checking
+    // for this first, its stub is already recorded and recalled below
+    if let Some(read) = tiering.picked.lock().unwrap().get_mut(key) {
+        *read = true;
+    }
+    if tiering.stub(key).is_none() {
+        let accessed = fs::metadata(path).await.and_then(|metadata| metadata.accessed());
+        let stale = accessed.is_ok_and(|accessed| {
+            SystemTime::now()
+                .duration_since(accessed)
+                .is_ok_and(|age| age >= ATIME_RESOLUTION)
+        });
+        if stale {
+            let _ = set_times(path, FileTimes::new().set_accessed(SystemTime::now())).await;
+        }
+        return Ok(());
+    }
+
+    let _moving = tiering.moving.lock().await;
+    let Some(stub) = tiering.stub(key) else {
+        return Ok(());
+    };
+    let current = fs::metadata(path).await.ok().and_then(|metadata| Stub::of(&metadata));
+    if current != Some(stub) {
+        return tiering.forget(state, key).await;
+    }
+
+    let tmp = tmp_path(state);
+    let fetched = async {
+        tiering.backend.download(key, &tmp).await?;
+        if fs::metadata(&tmp).await?.len() != stub.len {
+            return Err(io::Error::other("cold copy has the wrong size"));
+        }
+        set_times(
+            &tmp,
+            FileTimes::new()
+                .set_modified(stub.modified())
+                .set_accessed(SystemTime::now()),
+        )
+        .await?;
+        // Overwritten while fetching: nothing to put back
+        let current = fs::metadata(path).await.ok().and_then(|metadata| Stub::of(&metadata));
+        if current == Some(stub) {
+            fs::rename(&tmp, path).await?;
+        }
+        Ok(())
+    }
+    .await;
+    if let Err(e) = fetched {
+        let _ = fs::remove_file(&tmp).await;
+        warn!("🧊 Failed to recall {}: {}", key, e);
+        return Err(e);
+    }
+
+    state.handles.invalidate(path);
+    info!("🧊 Recalled {}", key);
+    tiering.forget(state, key).await
+}
+
+fn key_of(state: &AppState, path: &Path) -> Option<String> {
+    path.strip_prefix(&state.data_dir).ok().map(keys::key_of)
+}
+
+// recall() for protocols that work with paths, WebDAV and SFTP
+pub async fn recall_path(state: &AppState, path: &Path) -> io::Result<()> {
+    match key_of(state, path) {
+        Some(key) => recall(state, &key, path).await,
+        None => Ok(()),
+    }
+}
+
+// Recalls every tiered object at or under `path`, before a WebDAV or SFTP
+// move or copy of a whole tree
+pub async fn recall_under(state: &AppState, path: &Path) -> io::Result<()> {
+    let (Some(tiering), Some(key)) = (&state.tiering, key_of(state, path)) else {
+        return Ok(());
+    };
+    let dir = format!("{}/", key);
+    let tiered: Vec<String> = tiering
+        .stubs
+        .lock()
+        .unwrap()
+        .keys()
+        .filter(|tiered| **tiered == key || key.is_empty() || tiered.starts_with(&dir))
+        .cloned()
+        .collect();
+    for key in tiered {
+        if let Ok(path) = object_path(state, &key) {
+            recall(state, &key, &path).await?;
+        }
+    }
+    Ok(())
+}
+
+fn idle(tiering: &Tiering, time: io::Result<SystemTime>) -> bool {
+    time.ok()
+        .and_then(|time| SystemTime::now().duration_since(time).ok())
+        .is_some_and(|age| age >= tiering.after)
+}
+
+// Moves one object to the cold backend. Ok(false) if it was written or read
+// meanwhile
+async fn tier_object(state: &AppState, tiering: &Tiering, key: &str, stub: Stub) -> io::Result<bool> {
+    let path = object_path(state, key).map_err(|_| io::Error::other("invalid key"))?;
+    let _moving = tiering.moving.lock().await;
+    tiering.picked.lock().unwrap().insert(key.to_string(), false);
+    let moved = move_object(state, tiering, key, &path, stub).await;
+    tiering.picked.lock().unwrap().remove(key);
+    moved
+}
+
+async fn move_object(
+    state: &AppState,
+    tiering: &Tiering,
+    key: &str,
+    path: &Path,
+    stub: Stub,
+) -> io::Result<bool> {
+    let unchanged = |metadata: io::Result<Metadata>| {
+        metadata.is_ok_and(|metadata| Stub::of(&metadata) == Some(stub))
+    };
+    let accessed = fs::metadata(path).await?.accessed()?;
+
+    tiering.backend.upload(key, path).await?;
+
+    // A new file rather than truncating the object, so reads of it that are
+    // under way, mapped ones in particular, keep their data
+    let tmp = tmp_path(state);
+    let made = {
+        let tmp = tmp.clone();
+        tokio::task::spawn_blocking(move || {
+            let file = File::create(tmp)?;
+            file.set_len(stub.len)?;
+            file.set_times(
+                FileTimes::new()
+                    .set_modified(stub.modified())
+                    .set_accessed(accessed),
+            )
+        })
+        .await
+        .map_err(io::Error::other)?
+    };
+    if let Err(e) = made {
+        let _ = fs::remove_file(&tmp).await;
+        let _ = tiering.backend.remove(key).await;
+        return Err(e);
+    }
+
+    // The stub is recorded before it replaces the object, so a crash in
+    // between leaves the data in place with a stub entry that a read
+    // recalls harmlessly
+    let read = {
+        let mut picked = tiering.picked.lock().unwrap();
+        let read = picked.get(key).copied().unwrap_or(true);
+        if !read {
+            tiering.stubs.lock().unwrap().insert(key.to_string(), stub);
+            picked.remove(key);
+        }
+        read
+    };
+    if read || !unchanged(fs::metadata(path).await) {
+        let _ = fs::remove_file(&tmp).await;
+        if read {
+            tiering.backend.remove(key).await?;
+        } else {
+            tiering.forget(state, key).await?;
+        }
+        return Ok(false);
+    }
+    tiering.save(state).await?;
+
+    // A write landing between the check above and the rename would be
+    // replaced by the stub. Objects are only picked after days without
+    // reads or writes, so that's left unguarded
+    fs::rename(&tmp, path).await?;
+    state.handles.invalidate(path);
+    Ok(true)
+}
+
+async fn tier_pass(state: &AppState, tiering: &Tiering) {
+    // Stubs of objects rewritten or removed since
+    let stubs: Vec<(String, Stub)> = tiering
+        .stubs
+        .lock()
+        .unwrap()
+        .iter()
+        .map(|(key, stub)| (key.clone(), *stub))
+        .collect();
+    for (key, stub) in stubs {
+        let current = match object_path(state, &key) {
+            Ok(path) => fs::metadata(path).await.ok().and_then(|metadata| Stub::of(&metadata)),
+            Err(_) => None,
+        };
+        if current != Some(stub) {
+            let _moving = tiering.moving.lock().await;
+            if tiering.stub(&key) == Some(stub)
+                && let Err(e) = tiering.forget(state, &key).await
+            {
+                warn!("🧊 Failed to remove the cold copy of {}: {}", key, e);
+            }
+        }
+    }
+
+    let mut cold = Vec::new();
+    walk::walk(state, state.data_dir.clone(), |key, metadata| {
+        if metadata.len() > 0
+            && idle(tiering, metadata.accessed())
+            && idle(tiering, metadata.modified())
+            && let Some(stub) = Stub::of(&metadata)
+            && tiering.stub(&key).is_none()
+        {
+            cold.push((key, stub));
+        }
+    })
+    .await;
+
+    let mut moved = 0;
+    for (key, stub) in cold {
+        match tier_object(state, tiering, &key, stub).await {
+            Ok(true) => moved += 1,
+            Ok(false) => {}
+            Err(e) => warn!("🧊 Failed to tier {}: {}", key, e),
+        }
+    }
+    if moved > 0 {
+        info!("🧊 Moved {} objects to the cold tier", moved);
+    }
+}
+
+pub fn spawn(state: Arc<AppState>) {
+    tokio::spawn(async move {
+        let Some(tiering) = state.tiering.clone() else {
+            return;
+        };
+        let mut interval = tokio::time::interval(TIER_INTERVAL);
+        loop {
+            interval.tick().await;
+            tier_pass(&state, &tiering).await;
+        }
+    });
+}
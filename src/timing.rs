@@ -0,0 +1,300 @@
+use axum::{
+    Json,
+    extract::{Query, Request, State},
+    middleware::Next,
+    response::Response,
+};
+use serde::{Deserialize, Serialize};
+use std::{
+    collections::HashMap,
+    future::Future,
+    sync::{
+        Arc, Mutex,
+        atomic::{AtomicU64, Ordering},
+    },
+    time::{Duration, Instant},
+};
+use tracing::warn;
+
+use crate::AppState;
+
+// Where requests spend their time. Every S3 API request is tracked from the
+// moment it's received until its response starts, and the steps that can
+// take long (checking the signature, reading the body, scanning, reading
+// and writing the disk, hashing, recalling from the cold tier, walking for
+// a listing) record how long they took. With --slow-request-ms, requests
+// taking longer are logged with that breakdown; what isn't accounted for is
+// middleware, routing and waiting on locks.
+//
+// The admin port serves what's going on right now, without a restart:
+// /admin/requests lists the requests in flight with the step each is in,
+// and /admin/profile?seconds=N watches the server for N seconds and reports
+// CPU use, how busy each runtime worker was and the slowest requests seen.
+// There's no in-process CPU sampler; use perf or similar for flamegraphs.
+
+const MAX_PROFILE_SECS: u64 = 60;
+const PROFILE_SLOWEST: usize = 20;
+
+tokio::task_local! {
+    static CURRENT: Arc<Tracked>;
+}
+
+#[derive(Default)]
+struct Steps {
+    // In order
+    done: Vec<(&'static str, Duration)>,
+    current: Option<&'static str>,
+}
+
+struct Tracked {
+    method: String,
+    path: String,
+    started: Instant,
+    steps: Mutex<Steps>,
+}
+
+impl Tracked {
+    fn report(&self) -> RequestReport {
+        let steps = self.steps.lock().unwrap();
+        RequestReport {
+            method: self.method.clone(),
+            path: self.path.clone(),
+            elapsed_ms: self.started.elapsed().as_millis() as u64,
+            step: steps.current,
+            steps: steps.done.iter().map(|(name, took)| Step::new(name, *took)).collect(),
+            status: None,
+        }
+    }
+}
+
+#[derive(Debug, Clone, Serialize)]
+pub struct Step {
+    step: &'static str,
+    ms: f64,
+}
+
+impl Step {
+    fn new(step: &'static str, took: Duration) -> Self {
+        Self {
+            step,
+            ms: took.as_secs_f64() * 1000.0,
+        }
+    }
+}
+
+#[derive(Debug, Clone, Serialize)]
+pub struct RequestReport {
+    method: String,
+    path: String,
+    elapsed_ms: u64,
+    // The step under way, for requests in flight
+    #[serde(skip_serializing_if = "Option::is_none")]
+    step: Option<&'static str>,
+    steps: Vec<Step>,
+    #[serde(skip_serializing_if = "Option::is_none")]
+    status: Option<u16>,
+}
+
+// Requests in flight, and those finished while a profile is being taken
+#[derive(Default)]
+pub struct Requests {
+    next_id: AtomicU64,
+    in_flight: Mutex<HashMap<u64, Arc<Tracked>>>,
+    profiles: Mutex<Vec<Arc<Mutex<Vec<RequestReport>>>>>,
+}
+
+// Runs `step` of the current request, recording how long it took. Steps
+// don't nest, an inner one is just part of the outer
+pub async fn timed<T>(name: &'static str, step: impl Future<Output = T>) -> T {
+    let Ok(tracked) = CURRENT.try_with(|tracked| tracked.clone()) else {
+        return step.await;
+    };
+    let nested = {
+        let mut steps = tracked.steps.lock().unwrap();
+        let nested = steps.current.is_some();
+        if !nested {
+            steps.current = Some(name);
+        }
+        nested
+    };
+    if nested {
+        return step.await;
+    }
+    let started = Instant::now();
+    let output = step.await;
+    let mut steps = tracked.steps.lock().unwrap();
+    steps.done.push((name, started.elapsed()));
+    steps.current = None;
+    output
+}
+
+// Records a step of the current request that has already been done
+pub fn record(name: &'static str, took: Duration) {
+    let _ = CURRENT.try_with(|tracked| tracked.steps.lock().unwrap().done.push((name, took)));
+}
+
+pub async fn timing_middleware(
+    State(state): State<Arc<AppState>>,
+    request: Request,
+    next: Next,
+) -> Response {
+    let requests = &state.requests;
+    let id = requests.next_id.fetch_add(1, Ordering::Relaxed);
+    let tracked = Arc::new(Tracked {
+        method: request.method().to_string(),
+        path: request.uri().path().to_string(),
+        started: Instant::now(),
+        steps: Mutex::default(),
+    });
+    requests.in_flight.lock().unwrap().insert(id, tracked.clone());
+
+    let response = CURRENT.scope(tracked.clone(), next.run(request)).await;
+
+    requests.in_flight.lock().unwrap().remove(&id);
+    let took = tracked.started.elapsed();
+    let slow = state.slow_request.is_some_and(|threshold| took >= threshold);
+    let profiles = requests.profiles.lock().unwrap();
+    if !slow && profiles.is_empty() {
+        return response;
+    }
+
+    let mut report = tracked.report();
+    report.status = Some(response.status().as_u16());
+    for profile in profiles.iter() {
+        profile.lock().unwrap().push(report.clone());
+    }
+    if slow {
+        warn!(
+            "🐢 Slow request: {} {} took {}ms ({}): {}",
+            report.method,
+            report.path,
+            took.as_millis(),
+            response.status().as_u16(),
+            breakdown(&report.steps, took)
+        );
+    }
+    response
+}
+
+fn breakdown(steps: &[Step], took: Duration) -> String {
+    let accounted: f64 = steps.iter().map(|step| step.ms).sum();
+    let other = (took.as_secs_f64() * 1000.0 - accounted).max(0.0);
+    steps
+        .iter()
+        .map(|step| format!("{} {:.1}ms", step.step, step.ms))
+        .chain(std::iter::once(format!("other {:.1}ms", other)))
+        .collect::<Vec<_>>()
+        .join(", ")
+}
+
+// GET /admin/requests, on the admin port: requests in flight, longest first
+pub async fn requests_in_flight(State(state): State<Arc<AppState>>) -> Json<Vec<RequestReport>> {
+    Json(in_flight(&state))
+}
+
+fn in_flight(state: &AppState) -> Vec<RequestReport> {
+    let mut reports: Vec<RequestReport> = state
+        .requests
+        .in_flight
+        .lock()
+        .unwrap()
+        .values()
+        .map(|tracked| tracked.report())
+        .collect();
+    reports.sort_by_key(|report| std::cmp::Reverse(report.elapsed_ms));
+    reports
+}
+
+#[derive(Debug, Deserialize)]
+pub struct ProfileQuery {
+    seconds: Option<u64>,
+}
+
+#[derive(Debug, Serialize)]
+pub struct Worker {
+    busy_percent: f64,
+    parks: u64,
+}
+
+#[derive(Debug, Serialize)]
+pub struct Profile {
+    seconds: u64,
+    // Of one core, so up to 100 times the number of cores; None where the
+    // platform doesn't say
+    cpu_percent: Option<f64>,
+    workers: Vec<Worker>,
+    alive_tasks: usize,
+    global_queue_depth: usize,
+    requests: usize,
+    // The slowest requests finished during the profile
+    slowest: Vec<RequestReport>,
+    // Requests still in flight at the end, longest first
+    in_flight: Vec<RequestReport>,
+}
+
+#[cfg(unix)]
+fn cpu_time() -> Option<Duration> {
+    let mut usage: libc::rusage = unsafe { std::mem::zeroed() };
+    if unsafe { libc::getrusage(libc::RUSAGE_SELF, &mut usage) } != 0 {
+        return None;
+    }
+    let time = |tv: libc::timeval| Duration::new(tv.tv_sec as u64, tv.tv_usec as u32 * 1000);
+    Some(time(usage.ru_utime) + time(usage.ru_stime))
+}
+
+#[cfg(not(unix))]
+fn cpu_time() -> Option<Duration> {
+    None
+}
+
+// GET /admin/profile?seconds=N, on the admin port
+pub async fn profile(
+    State(state): State<Arc<AppState>>,
+    Query(query): Query<ProfileQuery>,
+) -> Json<Profile> {
+    let seconds = query.seconds.unwrap_or(10).clamp(1, MAX_PROFILE_SECS);
+    let metrics = tokio::runtime::Handle::current().metrics();
+    let workers = metrics.num_workers();
+    let busy = |worker| metrics.worker_total_busy_duration(worker);
+    let parks = |worker| metrics.worker_park_count(worker);
+
+    let finished = Arc::new(Mutex::new(Vec::new()));
+    state.requests.profiles.lock().unwrap().push(finished.clone());
+    let started = Instant::now();
+    let cpu_started = cpu_time();
+    let busy_started: Vec<Duration> = (0..workers).map(busy).collect();
+    let parks_started: Vec<u64> = (0..workers).map(parks).collect();
+
+    tokio::time::sleep(Duration::from_secs(seconds)).await;
+
+    let elapsed = started.elapsed();
+    state
+        .requests
+        .profiles
+        .lock()
+        .unwrap()
+        .retain(|profile| !Arc::ptr_eq(profile, &finished));
+    let mut finished = std::mem::take(&mut *finished.lock().unwrap());
+    let requests = finished.len();
+    finished.sort_by_key(|report| std::cmp::Reverse(report.elapsed_ms));
+    finished.truncate(PROFILE_SLOWEST);
+
+    let percent = |took: Duration| took.as_secs_f64() * 100.0 / elapsed.as_secs_f64();
+    Json(Profile {
+        seconds,
+        cpu_percent: cpu_started
+            .zip(cpu_time())
+            .map(|(started, ended)| percent(ended.saturating_sub(started))),
+        workers: (0..workers)
+            .map(|worker| Worker {
+                busy_percent: percent(busy(worker).saturating_sub(busy_started[worker])),
+                parks: parks(worker) - parks_started[worker],
+            })
+            .collect(),
+        alive_tasks: metrics.num_alive_tasks(),
+        global_queue_depth: metrics.global_queue_depth(),
+        requests,
+        slowest: finished,
+        in_flight: in_flight(&state),
+    })
+}
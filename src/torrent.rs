@@ -0,0 +1,260 @@
+use axum::{
+    http::{HeaderMap, HeaderValue, Method, StatusCode},
+    response::{IntoResponse, Response},
+};
+use hmac::{KeyInit, Mac};
+use percent_encoding::utf8_percent_encode;
+use sha1::{Digest, Sha1};
+use std::{
+    collections::HashMap,
+    io::Read,
+    path::{Path, PathBuf},
+    sync::Mutex,
+    time::{Duration, SystemTime},
+};
+use tracing::info;
+
+use crate::{AppState, HmacSha256, KEY_ENCODE_SET, proxy::ClientInfo};
+
+// GetObjectTorrent. GET /KEY?torrent returns a single-file .torrent of the
+// object with this server as its web seed (BEP 19), so downloaders fetch
+// pieces from the server until they have enough peers, and from each other
+// after. The web seed URL carries a seed token, an HMAC of the key and an
+// expiry under the secret key, that lets anyone holding the .torrent GET that
+// one object without credentials for SEED_LIFETIME. Nothing else may be in
+// the query, and a bucket policy denying anonymous reads of the object still
+// refuses it. Changing the secret key revokes every token.
+// Torrents announce to --torrent-trackers, or rely on DHT without any.
+// Hashing reads the whole object, so torrents are cached until the object
+// changes.
+
+const SEED_PARAM: &str = "seed";
+const SEED_LIFETIME: Duration = Duration::from_secs(7 * 24 * 60 * 60);
+const MIN_PIECE_LENGTH: u64 = 256 * 1024;
+const MAX_PIECE_LENGTH: u64 = 16 * 1024 * 1024;
+// Objects get about this many pieces, within the limits above
+const TARGET_PIECES: u64 = 1500;
+const CACHE_CAPACITY: usize = 64;
+
+// Piece hashes of an object, and the size and mtime they were made from
+struct Hashed {
+    len: u64,
+    modified: SystemTime,
+    pieces: Vec<u8>,
+}
+
+#[derive(Default)]
+pub struct Torrents {
+    cache: Mutex<HashMap<PathBuf, Hashed>>,
+}
+
+fn unix_now() -> u64 {
+    SystemTime::now()
+        .duration_since(SystemTime::UNIX_EPOCH)
+        .map_or(0, |now| now.as_secs())
+}
+
+fn seed_mac(state: &AppState, key: &str, expires: u64) -> HmacSha256 {
+    let mut mac = HmacSha256::new_from_slice(state.secret_key.as_bytes()).unwrap();
+    mac.update(format!("torrent-seed:{}:", expires).as_bytes());
+    mac.update(key.as_bytes());
+    mac
+}
+
+// The token that lets the web seed URL of `key` through without credentials,
+// `EXPIRES.HMAC` with EXPIRES in seconds since the epoch
+fn seed_token(state: &AppState, key: &str) -> String {
+    let expires = unix_now() + SEED_LIFETIME.as_secs();
+    let mac = seed_mac(state, key, expires);
+    format!("{}.{}", expires, hex::encode(mac.finalize().into_bytes()))
+}
+
+// Whether the request is a web seed reading `key`: a GET whose whole query is
+// a seed token for that key that hasn't expired
+pub fn is_seed_request(state: &AppState, method: &Method, key: &str, query: &str) -> bool {
+    if *method != Method::GET || key.is_empty() {
+        return false;
+    }
+    let Some((expires, signature)) = query
+        .strip_prefix(SEED_PARAM)
+        .and_then(|rest| rest.strip_prefix('='))
+        .and_then(|token| token.split_once('.'))
+    else {
+        return false;
+    };
+    let Ok(expires) = expires.parse::<u64>() else {
+        return false;
+    };
+    let Ok(signature) = hex::decode(signature) else {
+        return false;
+    };
+    expires >= unix_now() && seed_mac(state, key, expires).verify_slice(&signature).is_ok()
+}
+
+fn piece_length(len: u64) -> u64 {
+    (len / TARGET_PIECES)
+        .next_power_of_two()
+        .clamp(MIN_PIECE_LENGTH, MAX_PIECE_LENGTH)
+}
+
+fn bytes(out: &mut Vec<u8>, value: &[u8]) {
+    out.extend_from_slice(format!("{}:", value.len()).as_bytes());
+    out.extend_from_slice(value);
+}
+
+fn int(out: &mut Vec<u8>, value: u64) {
+    out.extend_from_slice(format!("i{}e", value).as_bytes());
+}
+
+// SHA-1 of every piece of the file, concatenated
+fn hash_pieces(path: &Path, piece_length: u64) -> std::io::Result<Vec<u8>> {
+    let mut file = std::fs::File::open(path)?;
+    let mut pieces = Vec::new();
+    let mut piece = vec![0; piece_length as usize];
+    loop {
+        let mut filled = 0;
+        while filled < piece.len() {
+            match file.read(&mut piece[filled..])? {
+                0 => break,
+                n => filled += n,
+            }
+        }
+        if filled == 0 {
+            break;
+        }
+        pieces.extend_from_slice(&Sha1::digest(&piece[..filled]));
+        if filled < piece.len() {
+            break;
+        }
+    }
+    Ok(pieces)
+}
+
+// Bencoded metainfo, dictionary keys in sorted order as bencode requires
+fn metainfo(
+    state: &AppState,
+    key: &str,
+    len: u64,
+    modified: SystemTime,
+    pieces: &[u8],
+    piece_length: u64,
+    web_seed: &str,
+) -> Vec<u8> {
+    let name = key.rsplit('/').next().unwrap_or(key);
+    let created = modified
+        .duration_since(SystemTime::UNIX_EPOCH)
+        .map_or(0, |since| since.as_secs());
+    let trackers = &state.torrent_trackers;
+
+    let mut out = b"d".to_vec();
+    if let Some(first) = trackers.first() {
+        bytes(&mut out, b"announce");
+        bytes(&mut out, first.as_bytes());
+        bytes(&mut out, b"announce-list");
+        out.push(b'l');
+        for tracker in trackers {
+            out.push(b'l');
+            bytes(&mut out, tracker.as_bytes());
+            out.push(b'e');
+        }
+        out.push(b'e');
+    }
+    bytes(&mut out, b"created by");
+    bytes(&mut out, b"simpleS3");
+    bytes(&mut out, b"creation date");
+    int(&mut out, created);
+    bytes(&mut out, b"info");
+    out.push(b'd');
+    bytes(&mut out, b"length");
+    int(&mut out, len);
+    bytes(&mut out, b"name");
+    bytes(&mut out, name.as_bytes());
+    bytes(&mut out, b"piece length");
+    int(&mut out, piece_length);
+    bytes(&mut out, b"pieces");
+    bytes(&mut out, pieces);
+    out.push(b'e');
+    bytes(&mut out, b"url-list");
+    out.push(b'l');
+    bytes(&mut out, web_seed.as_bytes());
+    out.push(b'e');
+    out.push(b'e');
+    out
+}
+
+// The URL the client reached the object at, with its seed token
+fn web_seed(state: &AppState, key: &str, headers: &HeaderMap, client: Option<&ClientInfo>) -> Option<String> {
+    let host = headers.get("host")?.to_str().ok()?;
+    let scheme = client.map_or("http", |client| client.scheme.as_str());
+    Some(format!(
+        "{}://{}/{}?{}={}",
+        scheme,
+        host,
+        utf8_percent_encode(key, KEY_ENCODE_SET),
+        SEED_PARAM,
+        seed_token(state, key)
+    ))
+}
+
+// GET /KEY?torrent
+pub async fn get_torrent(
+    state: &AppState,
+    key: &str,
+    path: PathBuf,
+    headers: &HeaderMap,
+    client: Option<&ClientInfo>,
+) -> Result<Response, StatusCode> {
+    let metadata = tokio::fs::metadata(&path)
+        .await
+        .ok()
+        .filter(|metadata| metadata.is_file())
+        .ok_or(StatusCode::NOT_FOUND)?;
+    let len = metadata.len();
+    let modified = metadata.modified().unwrap_or(SystemTime::UNIX_EPOCH);
+    let web_seed = web_seed(state, key, headers, client).ok_or(StatusCode::BAD_REQUEST)?;
+
+    let cached = state
+        .torrents
+        .cache
+        .lock()
+        .unwrap()
+        .get(&path)
+        .filter(|hashed| hashed.len == len && hashed.modified == modified)
+        .map(|hashed| hashed.pieces.clone());
+    let piece_length = piece_length(len);
+    let pieces = match cached {
+        Some(pieces) => pieces,
+        None => {
+            let hashed = path.clone();
+            let pieces = tokio::task::spawn_blocking(move || hash_pieces(&hashed, piece_length))
+                .await
+                .map_err(|_| StatusCode::INTERNAL_SERVER_ERROR)?
+                .map_err(|_| StatusCode::NOT_FOUND)?;
+            info!("🧲 Hashed {} into {} pieces", key, pieces.len() / 20);
+            let mut cache = state.torrents.cache.lock().unwrap();
+            if cache.len() >= CACHE_CAPACITY {
+                cache.clear();
+            }
+            cache.insert(
+                path,
+                Hashed {
+                    len,
+                    modified,
+                    pieces: pieces.clone(),
+                },
+            );
+            pieces
+        }
+    };
+
+    let body = metainfo(state, key, len, modified, &pieces, piece_length, &web_seed);
+    let mut headers = HeaderMap::new();
+    headers.insert("content-type", HeaderValue::from_static("application/x-bittorrent"));
+    if let Ok(disposition) = HeaderValue::from_str(&format!(
+        "attachment; filename=\"{}.torrent\"",
+        key.rsplit('/').next().unwrap_or(key).replace(['"', '\\'], "_")
+    )) {
+        headers.insert("content-disposition", disposition);
+    }
+    Ok((StatusCode::OK, headers, body).into_response())
+}
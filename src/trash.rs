@@ -0,0 +1,183 @@
+use axum::{
+    Json,
+    extract::{Query, State},
+    http::StatusCode,
+};
+use serde::{Deserialize, Serialize};
+use std::{
+    io,
+    path::{Path, PathBuf},
+    sync::Arc,
+};
+use tokio::fs;
+use tracing::{info, warn};
+
+use crate::{AppState, object_path, replica::Op, stats, tier};
+
+// Soft delete. With --trash-days, S3 DELETE moves the object into TRASH_DIR
+// as a data file named by a UUID plus a .json file recording its key, and gc
+// removes both once the .json file is older than the retention. The admin
+// port lists the trash and restores objects, one by ID or the latest
+// deletion of every key under a prefix. A restore never overwrites an
+// object that has since been written again.
+
+pub const TRASH_DIR: &str = ".simple-s3/trash";
+
+#[derive(Debug, Clone, Serialize, Deserialize)]
+pub struct Trashed {
+    #[serde(default)]
+    pub id: String,
+    pub key: String,
+    pub deleted_at: String,
+    pub size: u64,
+}
+
+pub async fn init(state: &AppState) -> io::Result<()> {
+    fs::create_dir_all(state.data_dir.join(TRASH_DIR)).await
+}
+
+fn paths(state: &AppState, id: &uuid::Uuid) -> (PathBuf, PathBuf) {
+    let dir = state.data_dir.join(TRASH_DIR);
+    (dir.join(id.to_string()), dir.join(format!("{}.json", id)))
+}
+
+// Moves the object at `path` to the trash. Ok(false) if there was none
+pub async fn move_to_trash(state: &AppState, key: &str, path: &Path) -> io::Result<bool> {
+    // The trash keeps data, not stubs of it
+    tier::recall(state, key, path).await?;
+    let Some(size) = stats::size(path).await else {
+        return Ok(false);
+    };
+    let id = uuid::Uuid::new_v4();
+    let (data_path, info_path) = paths(state, &id);
+    let info = Trashed {
+        id: id.to_string(),
+        key: key.to_string(),
+        deleted_at: chrono::Utc::now().to_rfc3339(),
+        size,
+    };
+
+    fs::write(&info_path, serde_json::to_vec(&info)?).await?;
+    if let Err(e) = fs::rename(path, &data_path).await {
+        let _ = fs::remove_file(&info_path).await;
+        return Err(e);
+    }
+    Ok(true)
+}
+
+async fn list(state: &AppState, prefix: &str) -> Vec<Trashed> {
+    let Ok(mut entries) = fs::read_dir(state.data_dir.join(TRASH_DIR)).await else {
+        return Vec::new();
+    };
+    let mut trashed = Vec::new();
+
+    while let Ok(Some(entry)) = entries.next_entry().await {
+        let path = entry.path();
+        if path.extension().is_none_or(|ext| ext != "json") {
+            continue;
+        }
+        let Some(mut info) = fs::read(&path)
+            .await
+            .ok()
+            .and_then(|raw| serde_json::from_slice::<Trashed>(&raw).ok())
+        else {
+            continue;
+        };
+        if info.key.starts_with(prefix) {
+            if let Some(id) = path.file_stem() {
+                info.id = id.to_string_lossy().to_string();
+            }
+            trashed.push(info);
+        }
+    }
+
+    // Newest first
+    trashed.sort_by(|a, b| b.deleted_at.cmp(&a.deleted_at));
+    trashed
+}
+
+async fn restore(state: &AppState, info: &Trashed) -> Result<(), StatusCode> {
+    let id = uuid::Uuid::parse_str(&info.id).map_err(|_| StatusCode::NOT_FOUND)?;
+    let (data_path, info_path) = paths(state, &id);
+    let path = object_path(state, &info.key)?;
+    if fs::try_exists(&path).await.unwrap_or(false) {
+        return Err(StatusCode::CONFLICT);
+    }
+
+    if let Some(parent) = path.parent() {
+        fs::create_dir_all(parent)
+            .await
+            .map_err(|_| StatusCode::INTERNAL_SERVER_ERROR)?;
+    }
+    fs::rename(&data_path, &path).await.map_err(|e| {
+        warn!("Failed to restore {} from the trash: {}", info.key, e);
+        StatusCode::INTERNAL_SERVER_ERROR
+    })?;
+    let _ = fs::remove_file(&info_path).await;
+
+    state.stats.replaced(None, Some(info.size));
+    state.handles.invalidate(&path);
+    if let Some(feed) = &state.changes {
+        feed.record(Op::Put, &info.key).await;
+    }
+    info!("♻️ Restored {} from the trash", info.key);
+    Ok(())
+}
+
+#[derive(Debug, Deserialize)]
+pub struct TrashQuery {
+    #[serde(default)]
+    prefix: String,
+    id: Option<String>,
+}
+
+#[derive(Debug, Default, Serialize)]
+pub struct Restored {
+    pub restored: Vec<String>,
+    // Keys left in the trash because an object has been written there since
+    pub skipped: Vec<String>,
+}
+
+// GET /admin/trash?prefix=
+pub async fn list_trash(
+    State(state): State<Arc<AppState>>,
+    Query(query): Query<TrashQuery>,
+) -> Json<Vec<Trashed>> {
+    Json(list(&state, &query.prefix).await)
+}
+
+// POST /admin/trash/restore?id= restores one deletion, ?prefix= the latest
+// deletion of each key under the prefix
+pub async fn restore_trash(
+    State(state): State<Arc<AppState>>,
+    Query(query): Query<TrashQuery>,
+) -> Result<Json<Restored>, StatusCode> {
+    if state.read_only {
+        return Err(StatusCode::FORBIDDEN);
+    }
+    let trashed = list(&state, &query.prefix).await;
+    let mut result = Restored::default();
+
+    if let Some(id) = &query.id {
+        let info = trashed
+            .iter()
+            .find(|info| &info.id == id)
+            .ok_or(StatusCode::NOT_FOUND)?;
+        restore(&state, info).await?;
+        result.restored.push(info.key.clone());
+        return Ok(Json(result));
+    }
+
+    let mut seen = std::collections::HashSet::new();
+    for info in &trashed {
+        if !seen.insert(info.key.as_str()) {
+            continue;
+        }
+        match restore(&state, info).await {
+            Ok(()) => result.restored.push(info.key.clone()),
+            Err(StatusCode::CONFLICT) => result.skipped.push(info.key.clone()),
+            Err(status) => return Err(status),
+        }
+    }
+    Ok(Json(result))
+}
@@ -0,0 +1,44 @@
+use std::num::NonZeroUsize;
+
+// Defaults for the runtime and I/O flags, sized from the machine so the same
+// binary suits a Raspberry Pi and a many-core storage server. Each can be
+// overridden: --worker-threads, --max-blocking-threads, --read-buffer-size
+// and --write-buffer-size.
+
+const KIB: usize = 1024;
+const GIB: u64 = 1024 * 1024 * 1024;
+
+pub fn cores() -> usize {
+    std::thread::available_parallelism().map_or(1, NonZeroUsize::get)
+}
+
+// Physical memory, None where the platform doesn't say
+#[cfg(unix)]
+fn memory() -> Option<u64> {
+    // SAFETY: sysconf only reads system configuration
+    let (pages, page_size) =
+        unsafe { (libc::sysconf(libc::_SC_PHYS_PAGES), libc::sysconf(libc::_SC_PAGESIZE)) };
+    (pages > 0 && page_size > 0).then(|| pages as u64 * page_size as u64)
+}
+
+#[cfg(not(unix))]
+fn memory() -> Option<u64> {
+    None
+}
+
+// Blocking threads mostly wait on the disk, so there can be many per core,
+// but not so many on a small board that their stacks crowd out the page
+// cache
+pub fn max_blocking_threads() -> usize {
+    (cores() * 32).clamp(64, 1024)
+}
+
+// Bytes read or written at a time when an object is streamed through the
+// server rather than handled whole
+pub fn buffer_size() -> usize {
+    match memory() {
+        Some(memory) if memory < 2 * GIB => 64 * KIB,
+        Some(memory) if memory >= 16 * GIB => 1024 * KIB,
+        _ => 256 * KIB,
+    }
+}
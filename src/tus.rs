@@ -0,0 +1,287 @@
+use axum::{
+    Router,
+    body::Body,
+    extract::{Path, State},
+    http::{HeaderMap, HeaderValue, StatusCode},
+    response::{IntoResponse, Response},
+    routing::{head, post},
+};
+use base64::Engine;
+use futures_util::StreamExt;
+use serde::{Deserialize, Serialize};
+use std::{path::PathBuf, sync::Arc};
+use tokio::{fs, io::AsyncWriteExt};
+use tracing::{info, warn};
+
+use crate::{AppState, object_path, rename_into_place};
+
+// tus resumable upload protocol (https://tus.io/protocols/resumable-upload),
+// core protocol plus the creation and termination extensions. Finished
+// uploads are renamed into place as ordinary objects. OPTIONS discovery is
+// not served because the CORS layer answers every OPTIONS request.
+
+pub const TUS_DIR: &str = ".simple-s3/tus";
+const TUS_VERSION: &str = "1.0.0";
+
+#[derive(Debug, Serialize, Deserialize)]
+struct UploadInfo {
+    key: String,
+    length: u64,
+}
+
+pub fn router() -> Router<Arc<AppState>> {
+    Router::new()
+        .route("/tus/", post(create_upload))
+        .route("/tus", post(create_upload))
+        .route(
+            "/tus/{id}",
+            head(upload_offset)
+                .patch(append_upload)
+                .delete(terminate_upload),
+        )
+}
+
+pub async fn init(state: &AppState) -> std::io::Result<()> {
+    fs::create_dir_all(state.data_dir.join(TUS_DIR)).await
+}
+
+fn tus_headers() -> HeaderMap {
+    let mut headers = HeaderMap::new();
+    headers.insert("tus-resumable", HeaderValue::from_static(TUS_VERSION));
+    headers.insert("cache-control", HeaderValue::from_static("no-store"));
+    headers
+}
+
+// Upload IDs are UUIDs, anything else could escape the tus directory
+fn upload_paths(state: &AppState, id: &str) -> Result<(PathBuf, PathBuf), StatusCode> {
+    let id = uuid::Uuid::parse_str(id).map_err(|_| StatusCode::NOT_FOUND)?;
+    let dir = state.data_dir.join(TUS_DIR);
+    Ok((dir.join(id.to_string()), dir.join(format!("{}.json", id))))
+}
+
+async fn read_info(info_path: &std::path::Path) -> Result<UploadInfo, StatusCode> {
+    let raw = fs::read(info_path).await.map_err(|_| StatusCode::NOT_FOUND)?;
+    serde_json::from_slice(&raw).map_err(|_| StatusCode::INTERNAL_SERVER_ERROR)
+}
+
+fn header_u64(headers: &HeaderMap, name: &str) -> Option<u64> {
+    headers.get(name)?.to_str().ok()?.parse().ok()
+}
+
+// Upload-Metadata is a comma separated list of "name base64(value)" pairs.
+// The object key comes from "key", falling back to the "filename" that
+// tus-js-client sends by default
+fn metadata_key(headers: &HeaderMap) -> Option<String> {
+    let metadata = headers.get("upload-metadata")?.to_str().ok()?;
+    let mut filename = None;
+
+    for pair in metadata.split(',') {
+        let mut parts = pair.trim().splitn(2, ' ');
+        let name = parts.next()?;
+        let value = base64::engine::general_purpose::STANDARD
+            .decode(parts.next().unwrap_or(""))
+            .ok()
+            .and_then(|v| String::from_utf8(v).ok());
+
+        match name {
+            "key" => return value,
+            "filename" => filename = value,
+            _ => {}
+        }
+    }
+
+    filename
+}
+
+// POST: create an upload and return its URL
+async fn create_upload(
+    State(state): State<Arc<AppState>>,
+    headers: HeaderMap,
+) -> Result<Response, StatusCode> {
+    let length = header_u64(&headers, "upload-length").ok_or(StatusCode::BAD_REQUEST)?;
+    let key = metadata_key(&headers)
+        .filter(|key| !key.is_empty())
+        .ok_or(StatusCode::BAD_REQUEST)?;
+    crate::worm::check(&state, &object_path(&state, &key)?).await?;
+
+    let id = uuid::Uuid::new_v4().to_string();
+    let (data_path, info_path) = upload_paths(&state, &id)?;
+
+    let info = serde_json::to_vec(&UploadInfo { key: key.clone(), length })
+        .map_err(|_| StatusCode::INTERNAL_SERVER_ERROR)?;
+
+    let created = async {
+        fs::File::create(&data_path).await?;
+        fs::write(&info_path, info).await
+    }
+    .await;
+
+    if let Err(e) = created {
+        warn!("Failed to create tus upload for {}: {}", key, e);
+        return Err(StatusCode::INTERNAL_SERVER_ERROR);
+    }
+
+    // Zero length uploads are complete as soon as they exist
+    if length == 0 {
+        finish_upload(&state, &data_path, &info_path, &key).await?;
+    }
+
+    let mut headers = tus_headers();
+    headers.insert(
+        "location",
+        HeaderValue::from_str(&format!("/tus/{}", id)).unwrap(),
+    );
+
+    info!("⏫ Created tus upload {} for {} ({} bytes)", id, key, length);
+
+    Ok((StatusCode::CREATED, headers).into_response())
+}
+
+// HEAD: report how much of the upload the server has
+async fn upload_offset(
+    State(state): State<Arc<AppState>>,
+    Path(id): Path<String>,
+) -> Result<Response, StatusCode> {
+    let (data_path, info_path) = upload_paths(&state, &id)?;
+    let info = read_info(&info_path).await?;
+    let offset = fs::metadata(&data_path)
+        .await
+        .map_err(|_| StatusCode::NOT_FOUND)?
+        .len();
+
+    let mut headers = tus_headers();
+    headers.insert("upload-offset", HeaderValue::from(offset));
+    headers.insert("upload-length", HeaderValue::from(info.length));
+
+    Ok((StatusCode::OK, headers).into_response())
+}
+
+// PATCH: append to the upload at the offset the client claims to resume from.
+// The body is written as it arrives so a dropped connection keeps whatever
+// made it to disk
+async fn append_upload(
+    State(state): State<Arc<AppState>>,
+    Path(id): Path<String>,
+    headers: HeaderMap,
+    body: Body,
+) -> Result<Response, StatusCode> {
+    let content_type = headers
+        .get("content-type")
+        .and_then(|v| v.to_str().ok())
+        .unwrap_or("");
+    if content_type != "application/offset+octet-stream" {
+        return Err(StatusCode::UNSUPPORTED_MEDIA_TYPE);
+    }
+
+    let claimed = header_u64(&headers, "upload-offset").ok_or(StatusCode::BAD_REQUEST)?;
+    let (data_path, info_path) = upload_paths(&state, &id)?;
+    let info = read_info(&info_path).await?;
+
+    let mut file = fs::OpenOptions::new()
+        .append(true)
+        .open(&data_path)
+        .await
+        .map_err(|_| StatusCode::NOT_FOUND)?;
+    file.set_max_buf_size(state.write_buffer);
+    let mut offset = file
+        .metadata()
+        .await
+        .map_err(|_| StatusCode::INTERNAL_SERVER_ERROR)?
+        .len();
+
+    if claimed != offset {
+        return Err(StatusCode::CONFLICT);
+    }
+
+    let mut stream = body.into_data_stream();
+    while let Some(chunk) = stream.next().await {
+        let Ok(chunk) = chunk else { break };
+
+        if offset + chunk.len() as u64 > info.length {
+            return Err(StatusCode::PAYLOAD_TOO_LARGE);
+        }
+        file.write_all(&chunk)
+            .await
+            .map_err(|_| StatusCode::INTERNAL_SERVER_ERROR)?;
+        offset += chunk.len() as u64;
+    }
+
+    file.flush()
+        .await
+        .map_err(|_| StatusCode::INTERNAL_SERVER_ERROR)?;
+    drop(file);
+
+    if offset == info.length {
+        finish_upload(&state, &data_path, &info_path, &info.key).await?;
+    }
+
+    let mut headers = tus_headers();
+    headers.insert("upload-offset", HeaderValue::from(offset));
+
+    Ok((StatusCode::NO_CONTENT, headers).into_response())
+}
+
+// DELETE: abandon an upload and free its space
+async fn terminate_upload(
+    State(state): State<Arc<AppState>>,
+    Path(id): Path<String>,
+) -> Result<Response, StatusCode> {
+    let (data_path, info_path) = upload_paths(&state, &id)?;
+
+    fs::remove_file(&info_path)
+        .await
+        .map_err(|_| StatusCode::NOT_FOUND)?;
+    let _ = fs::remove_file(&data_path).await;
+
+    info!("🗑️ Terminated tus upload {}", id);
+
+    Ok((StatusCode::NO_CONTENT, tus_headers()).into_response())
+}
+
+// Move a finished upload into place as a regular object
+async fn finish_upload(
+    state: &AppState,
+    data_path: &std::path::Path,
+    info_path: &std::path::Path,
+    key: &str,
+) -> Result<(), StatusCode> {
+    let file_path = object_path(state, key)?;
+    // The key may have been written since the upload was created
+    crate::worm::check(state, &file_path).await?;
+    if let Err(refusal) = crate::scan::check_file(state, "tus", key, data_path).await {
+        // An infected upload is gone for good, a failed scan can be retried
+        if let crate::scan::Refusal::Infected(_) = refusal {
+            let _ = fs::remove_file(data_path).await;
+            let _ = fs::remove_file(info_path).await;
+        }
+        return Err(refusal.status());
+    }
+    let old = crate::stats::size(&file_path).await;
+
+    let moved = async {
+        rename_into_place(data_path, &file_path).await?;
+        fs::remove_file(info_path).await
+    }
+    .await;
+
+    if let Err(e) = moved {
+        warn!("Failed to finish tus upload for {}: {}", key, e);
+        return Err(StatusCode::INTERNAL_SERVER_ERROR);
+    }
+
+    state
+        .stats
+        .replaced(old, crate::stats::size(&file_path).await);
+    state.handles.invalidate(&file_path);
+    if let Some(feed) = &state.changes {
+        feed.record(crate::replica::Op::Put, key).await;
+    }
+    if let Err(e) = crate::erasure::protect(state, key).await {
+        warn!("🧩 Failed to write the shards of {}: {}", key, e);
+        return Err(StatusCode::INTERNAL_SERVER_ERROR);
+    }
+
+    info!("📁 Stored object from tus upload: {}", key);
+
+    Ok(())
+}
@@ -0,0 +1,285 @@
+use axum::{
+    body::Body,
+    extract::{Query, Request, State},
+    http::{HeaderMap, HeaderValue, StatusCode},
+    middleware::Next,
+    response::{IntoResponse, Response},
+};
+use futures_util::StreamExt;
+use serde::{Deserialize, Serialize};
+use std::{
+    collections::BTreeMap,
+    path::{Path, PathBuf},
+    sync::{
+        Arc, Mutex,
+        atomic::{AtomicBool, Ordering},
+    },
+    time::{Duration, Instant},
+};
+use tokio::fs;
+use tracing::warn;
+
+use crate::{AppState, tmp_path, write_and_rename};
+
+// Usage accounting for billing. For each calendar month (UTC), access key
+// and bucket it counts S3 API requests, request and response body bytes,
+// and byte-hours stored, accrued from the bucket's size every
+// ACCRUE_INTERVAL. Each month is kept in USAGE_DIR/YYYY-MM.json, saved
+// every ACCRUE_INTERVAL, and exported at /admin/usage/export on the admin
+// port. Quotas read the current month's transfer from here.
+
+const USAGE_DIR: &str = ".simple-s3/usage";
+const ACCRUE_INTERVAL: Duration = Duration::from_secs(60);
+
+#[derive(Debug, Clone, Default, Serialize, Deserialize)]
+pub struct Usage {
+    pub requests: u64,
+    pub bytes_in: u64,
+    pub bytes_out: u64,
+    pub byte_hours: f64,
+}
+
+#[derive(Debug, Serialize, Deserialize)]
+pub struct Row {
+    pub access_key: String,
+    pub bucket: String,
+    #[serde(flatten)]
+    pub usage: Usage,
+}
+
+struct Month {
+    // "2024-06"
+    name: String,
+    usage: BTreeMap<(String, String), Usage>,
+}
+
+pub struct Ledger {
+    dir: PathBuf,
+    month: Mutex<Month>,
+    dirty: AtomicBool,
+}
+
+fn this_month() -> String {
+    chrono::Utc::now().format("%Y-%m").to_string()
+}
+
+fn rows(usage: &BTreeMap<(String, String), Usage>) -> Vec<Row> {
+    usage
+        .iter()
+        .map(|((access_key, bucket), usage)| Row {
+            access_key: access_key.clone(),
+            bucket: bucket.clone(),
+            usage: usage.clone(),
+        })
+        .collect()
+}
+
+async fn read_rows(path: &Path) -> Option<Vec<Row>> {
+    let raw = fs::read(path).await.ok()?;
+    serde_json::from_slice(&raw)
+        .map_err(|e| warn!("Ignoring unreadable {}: {}", path.display(), e))
+        .ok()
+}
+
+impl Ledger {
+    pub async fn load(data_dir: &Path) -> std::io::Result<Self> {
+        let dir = data_dir.join(USAGE_DIR);
+        fs::create_dir_all(&dir).await?;
+
+        let name = this_month();
+        let usage = read_rows(&dir.join(format!("{}.json", name)))
+            .await
+            .unwrap_or_default()
+            .into_iter()
+            .map(|row| ((row.access_key, row.bucket), row.usage))
+            .collect();
+
+        Ok(Self {
+            dir,
+            month: Mutex::new(Month { name, usage }),
+            dirty: AtomicBool::new(false),
+        })
+    }
+
+    // Applies `f` to this month's usage of `key` in `bucket`, saving the
+    // previous month first if it just ended
+    fn update(&self, key: &str, bucket: &str, f: impl FnOnce(&mut Usage)) {
+        let name = this_month();
+        let mut month = self.month.lock().unwrap();
+        if month.name != name {
+            let ended = std::mem::replace(
+                &mut *month,
+                Month {
+                    name,
+                    usage: BTreeMap::new(),
+                },
+            );
+            let path = self.dir.join(format!("{}.json", ended.name));
+            let raw = serde_json::to_vec(&rows(&ended.usage)).unwrap_or_default();
+            if let Err(e) = std::fs::write(&path, raw) {
+                warn!("Failed to save {}: {}", path.display(), e);
+            }
+        }
+        f(month
+            .usage
+            .entry((key.to_string(), bucket.to_string()))
+            .or_default());
+        self.dirty.store(true, Ordering::Relaxed);
+    }
+
+    // Bytes `key` has uploaded and downloaded this month, in every bucket
+    pub fn transferred(&self, key: &str) -> u64 {
+        let month = self.month.lock().unwrap();
+        if month.name != this_month() {
+            return 0;
+        }
+        month
+            .usage
+            .iter()
+            .filter(|((access_key, _), _)| access_key == key)
+            .map(|(_, usage)| usage.bytes_in + usage.bytes_out)
+            .sum()
+    }
+
+    async fn save(&self, state: &AppState) {
+        if !self.dirty.swap(false, Ordering::Relaxed) {
+            return;
+        }
+        let (name, raw) = {
+            let month = self.month.lock().unwrap();
+            (month.name.clone(), serde_json::to_vec(&rows(&month.usage)).unwrap_or_default())
+        };
+        let path = self.dir.join(format!("{}.json", name));
+        if let Err(e) = write_and_rename(state, &tmp_path(state), &path, &raw).await {
+            warn!("Failed to save {}: {}", path.display(), e);
+            self.dirty.store(true, Ordering::Relaxed);
+        }
+    }
+
+    async fn export(&self, name: &str) -> Option<Vec<Row>> {
+        {
+            let month = self.month.lock().unwrap();
+            if month.name == name {
+                return Some(rows(&month.usage));
+            }
+        }
+        read_rows(&self.dir.join(format!("{}.json", name))).await
+    }
+}
+
+// Accrues byte-hours and saves the ledger every ACCRUE_INTERVAL
+pub fn spawn(state: Arc<AppState>) {
+    tokio::spawn(async move {
+        let mut interval = tokio::time::interval(ACCRUE_INTERVAL);
+        let mut last = Instant::now();
+        loop {
+            interval.tick().await;
+            let hours = last.elapsed().as_secs_f64() / 3600.0;
+            last = Instant::now();
+
+            let stored = state.stats.totals().bytes as f64;
+            state.usage.update(&state.access_key, &state.bucket_name, |usage| {
+                usage.byte_hours += stored * hours
+            });
+            state.usage.save(&state).await;
+        }
+    });
+}
+
+// Counts each S3 API request and the bytes of its bodies. Runs after auth,
+// so the request was made with the server's access key
+pub async fn usage_middleware(
+    State(state): State<Arc<AppState>>,
+    request: Request,
+    next: Next,
+) -> Response {
+    state
+        .usage
+        .update(&state.access_key, &state.bucket_name, |usage| usage.requests += 1);
+
+    let (parts, body) = request.into_parts();
+    let counter = state.clone();
+    let body = body.into_data_stream().inspect(move |chunk| {
+        if let Ok(chunk) = chunk {
+            let len = chunk.len() as u64;
+            counter
+                .usage
+                .update(&counter.access_key, &counter.bucket_name, |usage| usage.bytes_in += len);
+        }
+    });
+    let response = next
+        .run(Request::from_parts(parts, Body::from_stream(body)))
+        .await;
+
+    let (parts, body) = response.into_parts();
+    let body = body.into_data_stream().inspect(move |chunk| {
+        if let Ok(chunk) = chunk {
+            let len = chunk.len() as u64;
+            state
+                .usage
+                .update(&state.access_key, &state.bucket_name, |usage| usage.bytes_out += len);
+        }
+    });
+    Response::from_parts(parts, Body::from_stream(body))
+}
+
+#[derive(Debug, Deserialize)]
+pub struct ExportQuery {
+    month: Option<String>,
+    format: Option<String>,
+}
+
+fn valid_month(month: &str) -> bool {
+    month.len() == 7
+        && chrono::NaiveDate::parse_from_str(&format!("{}-01", month), "%Y-%m-%d").is_ok()
+}
+
+// GET /admin/usage/export?month=2024-06&format=csv on the admin port. The
+// month defaults to the current one and the format to JSON
+pub async fn export(
+    State(state): State<Arc<AppState>>,
+    Query(query): Query<ExportQuery>,
+) -> Result<Response, StatusCode> {
+    let month = query.month.unwrap_or_else(this_month);
+    if !valid_month(&month) {
+        return Err(StatusCode::BAD_REQUEST);
+    }
+    let rows = state.usage.export(&month).await.ok_or(StatusCode::NOT_FOUND)?;
+
+    let mut headers = HeaderMap::new();
+    match query.format.as_deref().unwrap_or("json") {
+        "json" => Ok(axum::Json(rows).into_response()),
+        "csv" => {
+            let mut csv = String::from("month,access_key,bucket,requests,bytes_in,bytes_out,byte_hours\n");
+            for row in &rows {
+                csv.push_str(&format!(
+                    "{},{},{},{},{},{},{:.3}\n",
+                    month,
+                    csv_field(&row.access_key),
+                    csv_field(&row.bucket),
+                    row.usage.requests,
+                    row.usage.bytes_in,
+                    row.usage.bytes_out,
+                    row.usage.byte_hours
+                ));
+            }
+            headers.insert("content-type", HeaderValue::from_static("text/csv"));
+            if let Ok(disposition) =
+                HeaderValue::from_str(&format!("attachment; filename=\"usage-{}.csv\"", month))
+            {
+                headers.insert("content-disposition", disposition);
+            }
+            Ok((headers, csv).into_response())
+        }
+        _ => Err(StatusCode::BAD_REQUEST),
+    }
+}
+
+// Quotes a CSV field if it needs it
+fn csv_field(field: &str) -> String {
+    if field.contains([',', '"', '\n', '\r']) {
+        format!("\"{}\"", field.replace('"', "\"\""))
+    } else {
+        field.to_string()
+    }
+}
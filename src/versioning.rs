@@ -0,0 +1,814 @@
+use axum::{
+    http::{HeaderMap, HeaderValue, StatusCode},
+    response::{IntoResponse, Response},
+};
+use serde::{Deserialize, Serialize};
+use sha2::{Digest, Sha256};
+use std::{
+    io,
+    path::{Path, PathBuf},
+    sync::atomic::{AtomicU64, Ordering},
+    time::{SystemTime, UNIX_EPOCH},
+};
+use tokio::{fs, sync::MutexGuard};
+use tracing::{info, warn};
+
+use crate::{
+    AppState, ErrorResponse, Owner,
+    access::{BucketConfig, Document},
+    etag,
+    error_response, list_from, normalize, object_path, object_stamp, remove_empty_parents, tier,
+    tmp_path, write_and_rename,
+};
+
+// Bucket versioning, S3's ?versioning subresource. Once PUT /?versioning
+// enables it, overwriting or deleting an object keeps what was there as a
+// noncurrent version, and a DELETE leaves a delete marker on top. GET, HEAD
+// and DELETE take ?versionId, GET /?versions lists every version, and
+// deleting a version for good brings back the newest one left under it, the
+// way undeleting works in S3. Suspending it stops keeping new versions, as
+// writes then replace the "null" version, but the kept ones stay.
+//
+// The current version stays where the object always is, so listings, WebDAV
+// and SFTP see nothing different. Each key with versions has a directory
+// under VERSIONS_DIR holding an index and one file per noncurrent version.
+// The index stamps the current version like checksums and tags do, so an
+// object written some other way, by WebDAV, SFTP or tus, a compose or a
+// ranged write, is the "null" version and isn't kept when it's replaced.
+//
+// Versioned writes and deletes wait on one lock, from keeping the old
+// version to recording the new one.
+
+pub const VERSIONS_DIR: &str = ".simple-s3/versions";
+const NULL: &str = "null";
+const INDEX: &str = "index.json";
+const XMLNS: &str = "http://s3.amazonaws.com/doc/2006-03-01/";
+
+#[derive(Debug, Clone, Default, Serialize, Deserialize)]
+#[serde(rename = "VersioningConfiguration")]
+pub struct VersioningConfiguration {
+    #[serde(rename = "@xmlns", default)]
+    xmlns: String,
+    // Enabled or Suspended, unset until versioning is first enabled
+    #[serde(rename = "Status", skip_serializing_if = "Option::is_none")]
+    status: Option<String>,
+}
+
+impl Document for VersioningConfiguration {
+    const FILE: &'static str = ".simple-s3/versioning.xml";
+    // Never answered, a bucket that was never versioned has an empty
+    // configuration
+    const NOT_FOUND: (&'static str, &'static str) = ("", "");
+
+    fn set_xmlns(&mut self, xmlns: &str) {
+        self.xmlns = xmlns.to_string();
+    }
+
+    fn is_valid(&self) -> bool {
+        matches!(self.status.as_deref(), Some("Enabled" | "Suspended"))
+    }
+}
+
+#[derive(Clone, Copy, PartialEq)]
+enum Status {
+    Enabled,
+    Suspended,
+}
+
+pub struct Versioning {
+    pub config: BucketConfig<VersioningConfiguration>,
+    lock: tokio::sync::Mutex<()>,
+}
+
+impl Versioning {
+    pub async fn load(data_dir: &Path) -> io::Result<Self> {
+        Ok(Self {
+            config: BucketConfig::load(data_dir).await?,
+            lock: tokio::sync::Mutex::new(()),
+        })
+    }
+
+    // None if versioning was never enabled
+    fn status(&self) -> Option<Status> {
+        match self.config.get()?.status.as_deref() {
+            Some("Enabled") => Some(Status::Enabled),
+            Some("Suspended") => Some(Status::Suspended),
+            _ => None,
+        }
+    }
+}
+
+// What's kept about the versions of one key, newest first
+#[derive(Default, Serialize, Deserialize)]
+struct Index {
+    key: String,
+    current: Option<Current>,
+    versions: Vec<Version>,
+}
+
+// The version id of the object at the key's path, while it's the one that
+// was written then
+#[derive(Serialize, Deserialize)]
+struct Current {
+    version_id: String,
+    stamp: String,
+}
+
+#[derive(Clone, Serialize, Deserialize)]
+struct Version {
+    version_id: String,
+    delete_marker: bool,
+    size: u64,
+    last_modified: String,
+    // Taken when the version was kept; versions kept before ETags were
+    // recorded are hashed when asked for
+    #[serde(default, skip_serializing_if = "Option::is_none")]
+    etag: Option<String>,
+}
+
+impl Index {
+    // The id of the current version, None if there's no object
+    fn current_id(&self, metadata: Option<&std::fs::Metadata>) -> Option<String> {
+        let metadata = metadata?;
+        let stamp = object_stamp(metadata.modified().ok()?, metadata.len());
+        Some(match &self.current {
+            Some(current) if current.stamp == stamp => current.version_id.clone(),
+            _ => NULL.to_string(),
+        })
+    }
+
+    // Forgets the noncurrent "null" version, which a new one replaces
+    async fn drop_null(&mut self, dir: &Path) {
+        if let Some(at) = self.versions.iter().position(|v| v.version_id == NULL) {
+            let _ = fs::remove_file(dir.join(NULL)).await;
+            self.versions.remove(at);
+        }
+    }
+}
+
+// Ids sort by when they were made, and none is given out twice
+fn new_version_id() -> String {
+    static LAST: AtomicU64 = AtomicU64::new(0);
+    let now = SystemTime::now()
+        .duration_since(UNIX_EPOCH)
+        .map(|now| now.as_nanos() as u64)
+        .unwrap_or_default();
+    let last = LAST
+        .fetch_update(Ordering::SeqCst, Ordering::SeqCst, |last| Some(now.max(last + 1)))
+        .unwrap_or_default();
+    format!("{:016x}", now.max(last + 1))
+}
+
+// Whether a versionId is one this server could have given out, and so is
+// safe as a file name
+fn is_version_id(version_id: &str) -> bool {
+    version_id == NULL
+        || (version_id.len() == 16 && version_id.bytes().all(|b| b.is_ascii_hexdigit()))
+}
+
+fn versions_dir(state: &AppState, key: &str) -> PathBuf {
+    let key = normalize::stored_key(state, key);
+    state
+        .data_dir
+        .join(VERSIONS_DIR)
+        .join(hex::encode(Sha256::digest(key.as_bytes())))
+}
+
+async fn load_index(dir: &Path) -> io::Result<Index> {
+    match fs::read(dir.join(INDEX)).await {
+        Ok(data) => serde_json::from_slice(&data).map_err(io::Error::other),
+        Err(e) if e.kind() == io::ErrorKind::NotFound => Ok(Index::default()),
+        Err(e) => Err(e),
+    }
+}
+
+// Saves `index`, or removes the key's directory once there's nothing to keep
+async fn save_index(state: &AppState, dir: &Path, index: &Index) -> io::Result<()> {
+    if index.current.is_none() && index.versions.is_empty() {
+        return match fs::remove_dir_all(dir).await {
+            Err(e) if e.kind() != io::ErrorKind::NotFound => Err(e),
+            _ => Ok(()),
+        };
+    }
+    let json = serde_json::to_vec(index).map_err(io::Error::other)?;
+    fs::create_dir_all(dir).await?;
+    let tmp = tmp_path(state);
+    let written = write_and_rename(state, &tmp, &dir.join(INDEX), &json).await;
+    if written.is_err() {
+        let _ = fs::remove_file(&tmp).await;
+    }
+    written
+}
+
+fn timestamp(modified: SystemTime) -> String {
+    let modified: chrono::DateTime<chrono::Utc> = modified.into();
+    modified.format("%Y-%m-%dT%H:%M:%S%.3fZ").to_string()
+}
+
+fn delete_marker(version_id: String) -> Version {
+    Version {
+        version_id,
+        delete_marker: true,
+        size: 0,
+        last_modified: timestamp(SystemTime::now()),
+        etag: None,
+    }
+}
+
+// Keeps the object at `file_path` as a noncurrent version, linked so it
+// stays in place for a write to replace, or moved for a delete. None if
+// there's no object, or it's the "null" version and versioning is
+// suspended, which is replaced rather than kept
+async fn keep_current(
+    state: &AppState,
+    key: &str,
+    file_path: &Path,
+    dir: &Path,
+    index: &Index,
+    status: Status,
+    link: bool,
+) -> io::Result<Option<Version>> {
+    tier::recall(state, key, file_path).await?;
+    let metadata = match fs::metadata(file_path).await {
+        Ok(metadata) => metadata,
+        Err(e) if e.kind() == io::ErrorKind::NotFound => return Ok(None),
+        Err(e) => return Err(e),
+    };
+    let Some(version_id) = index.current_id(Some(&metadata)) else {
+        return Ok(None);
+    };
+    if status == Status::Suspended && version_id == NULL {
+        return Ok(None);
+    }
+    let etag = etag::of_file(state, key, file_path).await?;
+
+    fs::create_dir_all(dir).await?;
+    let kept = dir.join(&version_id);
+    let _ = fs::remove_file(&kept).await;
+    if link {
+        // Filesystems without hard links get a copy
+        if fs::hard_link(file_path, &kept).await.is_err() {
+            fs::copy(file_path, &kept).await?;
+        }
+    } else {
+        fs::rename(file_path, &kept).await?;
+    }
+    Ok(Some(Version {
+        version_id,
+        delete_marker: false,
+        size: metadata.len(),
+        last_modified: timestamp(metadata.modified()?),
+        etag: Some(etag),
+    }))
+}
+
+// A write that's about to replace an object of a versioned bucket, holding
+// the lock until it's committed or aborted
+pub struct Pending<'a> {
+    _guard: MutexGuard<'a, ()>,
+    dir: PathBuf,
+    index: Index,
+    kept: Option<Version>,
+    version_id: String,
+}
+
+// Keeps the object at `file_path` before a PUT, copy or multipart upload
+// replaces it. None if the bucket isn't versioned
+pub async fn prepare<'a>(
+    state: &'a AppState,
+    key: &str,
+    file_path: &Path,
+) -> Result<Option<Pending<'a>>, StatusCode> {
+    let Some(status) = state.versioning.status() else {
+        return Ok(None);
+    };
+    let guard = state.versioning.lock.lock().await;
+    let dir = versions_dir(state, key);
+    let prepared = async {
+        let mut index = load_index(&dir).await?;
+        index.key = normalize::stored_key(state, key).into_owned();
+        let kept = keep_current(state, key, file_path, &dir, &index, status, true).await?;
+        io::Result::Ok((index, kept))
+    }
+    .await;
+    let (index, kept) = prepared.map_err(|e| {
+        warn!("Failed to keep the current version of {}: {}", key, e);
+        StatusCode::INTERNAL_SERVER_ERROR
+    })?;
+    let version_id = match status {
+        Status::Enabled => new_version_id(),
+        Status::Suspended => NULL.to_string(),
+    };
+    Ok(Some(Pending {
+        _guard: guard,
+        dir,
+        index,
+        kept,
+        version_id,
+    }))
+}
+
+// Records the object now at `file_path` as the current version, and gives
+// its id in x-amz-version-id
+pub async fn commit(
+    state: &AppState,
+    pending: Option<Pending<'_>>,
+    file_path: &Path,
+    headers: &mut HeaderMap,
+) {
+    let Some(mut pending) = pending else {
+        return;
+    };
+    if pending.version_id == NULL {
+        pending.index.drop_null(&pending.dir).await;
+    }
+    if let Some(kept) = pending.kept.take() {
+        pending.index.versions.retain(|v| v.version_id != kept.version_id);
+        pending.index.versions.insert(0, kept);
+    }
+    let stamp = fs::metadata(file_path)
+        .await
+        .and_then(|metadata| Ok(object_stamp(metadata.modified()?, metadata.len())));
+    match stamp {
+        Ok(stamp) => {
+            pending.index.current = Some(Current {
+                version_id: pending.version_id.clone(),
+                stamp,
+            });
+        }
+        Err(e) => warn!("Failed to stamp the new version of {}: {}", pending.index.key, e),
+    }
+    if let Err(e) = save_index(state, &pending.dir, &pending.index).await {
+        warn!("Failed to record the versions of {}: {}", pending.index.key, e);
+    }
+    insert_id(headers, &pending.version_id);
+}
+
+// Forgets what prepare kept, for a write that was refused or failed and left
+// the object as it was
+pub async fn abort(pending: Option<Pending<'_>>) {
+    if let Some(Pending {
+        dir,
+        kept: Some(kept),
+        ..
+    }) = pending
+    {
+        let _ = fs::remove_file(dir.join(kept.version_id)).await;
+    }
+}
+
+pub fn insert_id(headers: &mut HeaderMap, version_id: &str) {
+    if let Ok(value) = HeaderValue::from_str(version_id) {
+        headers.insert("x-amz-version-id", value);
+    }
+}
+
+// x-amz-version-id for a GET or HEAD of the current version
+pub async fn insert_current_id(
+    state: &AppState,
+    key: &str,
+    modified: SystemTime,
+    len: u64,
+    headers: &mut HeaderMap,
+) {
+    if state.versioning.status().is_none() {
+        return;
+    }
+    let Ok(index) = load_index(&versions_dir(state, key)).await else {
+        return;
+    };
+    let version_id = match &index.current {
+        Some(current) if current.stamp == object_stamp(modified, len) => &current.version_id,
+        _ => NULL,
+    };
+    insert_id(headers, version_id);
+}
+
+fn refusal(status: StatusCode, code: &str, message: &str) -> Response {
+    error_response(
+        status,
+        ErrorResponse {
+            code: code.to_string(),
+            message: message.to_string(),
+            max_size_allowed: None,
+        },
+    )
+}
+
+fn invalid_version_id() -> Response {
+    refusal(
+        StatusCode::BAD_REQUEST,
+        "InvalidArgument",
+        "Invalid version id specified",
+    )
+}
+
+// Where the data of `version_id` of `key` is for a GET or HEAD, None if it's
+// the current version at `file_path`
+pub async fn resolve(
+    state: &AppState,
+    key: &str,
+    file_path: &Path,
+    version_id: &str,
+) -> Result<Option<PathBuf>, Response> {
+    if !is_version_id(version_id) {
+        return Err(invalid_version_id());
+    }
+    let dir = versions_dir(state, key);
+    let index = load_index(&dir)
+        .await
+        .map_err(|_| StatusCode::INTERNAL_SERVER_ERROR.into_response())?;
+    let metadata = fs::metadata(file_path).await.ok();
+    if index.current_id(metadata.as_ref()).as_deref() == Some(version_id) {
+        return Ok(None);
+    }
+    match index.versions.iter().find(|v| v.version_id == version_id) {
+        Some(version) if version.delete_marker => {
+            let mut response = refusal(
+                StatusCode::METHOD_NOT_ALLOWED,
+                "MethodNotAllowed",
+                "The specified method is not allowed against this resource.",
+            );
+            let headers = response.headers_mut();
+            headers.insert("x-amz-delete-marker", HeaderValue::from_static("true"));
+            insert_id(headers, version_id);
+            Err(response)
+        }
+        Some(version) => Ok(Some(dir.join(&version.version_id))),
+        None => Err(refusal(
+            StatusCode::NOT_FOUND,
+            "NoSuchVersion",
+            "The specified version does not exist.",
+        )),
+    }
+}
+
+// Where the data of `version_id` of `key` is for a copy from it, None if
+// it's the current version at `file_path`. A bucket that was never
+// versioned has no versions to name, and delete markers can't be copied
+pub async fn resolve_source(
+    state: &AppState,
+    key: &str,
+    file_path: &Path,
+    version_id: &str,
+) -> Result<Option<PathBuf>, Response> {
+    if state.versioning.status().is_none() {
+        return Err(invalid_version_id());
+    }
+    resolve(state, key, file_path, version_id)
+        .await
+        .map_err(|refused| match refused.status() {
+            StatusCode::METHOD_NOT_ALLOWED => refusal(
+                StatusCode::BAD_REQUEST,
+                "InvalidRequest",
+                "The source of a copy request may not specifically refer to a delete marker by \
+                 version id.",
+            ),
+            _ => refused,
+        })
+}
+
+// The ETag of `version_id` of `key`, kept at `path`: the one recorded when
+// it was kept, or else its contents hashed
+pub async fn etag(
+    state: &AppState,
+    key: &str,
+    version_id: &str,
+    path: &Path,
+) -> io::Result<String> {
+    let index = load_index(&versions_dir(state, key)).await?;
+    let recorded = index
+        .versions
+        .into_iter()
+        .find(|version| version.version_id == version_id)
+        .and_then(|version| version.etag);
+    match recorded {
+        Some(etag) => Ok(etag),
+        None => etag::hash_file(state, path).await,
+    }
+}
+
+// What a DELETE of a versioned bucket did
+pub struct Removed {
+    pub version_id: String,
+    pub delete_marker: bool,
+}
+
+impl Removed {
+    pub fn insert_into(&self, headers: &mut HeaderMap) {
+        insert_id(headers, &self.version_id);
+        if self.delete_marker {
+            headers.insert("x-amz-delete-marker", HeaderValue::from_static("true"));
+        }
+    }
+}
+
+// DELETE of `key` when the bucket is versioned: without `version_id` the
+// object is kept as a noncurrent version under a new delete marker, with one
+// that version is gone for good. None if the bucket isn't versioned, to
+// delete as usual
+pub async fn delete(
+    state: &AppState,
+    key: &str,
+    file_path: &Path,
+    version_id: Option<&str>,
+) -> Result<Option<Removed>, Response> {
+    let Some(status) = state.versioning.status() else {
+        return Ok(None);
+    };
+    if version_id.is_some_and(|version_id| !is_version_id(version_id)) {
+        return Err(invalid_version_id());
+    }
+    let _guard = state.versioning.lock.lock().await;
+    let dir = versions_dir(state, key);
+    let removed = async {
+        let mut index = load_index(&dir).await?;
+        index.key = normalize::stored_key(state, key).into_owned();
+        let removed = match version_id {
+            None => add_delete_marker(state, key, file_path, &dir, &mut index, status).await?,
+            Some(version_id) => {
+                remove_version(state, file_path, &dir, &mut index, version_id).await?
+            }
+        };
+        save_index(state, &dir, &index).await?;
+        io::Result::Ok(removed)
+    }
+    .await;
+    match removed {
+        Ok(removed) => {
+            remove_empty_parents(state, file_path).await;
+            Ok(Some(removed))
+        }
+        Err(e) => {
+            warn!("Failed to delete a version of {}: {}", key, e);
+            Err(StatusCode::INTERNAL_SERVER_ERROR.into_response())
+        }
+    }
+}
+
+async fn add_delete_marker(
+    state: &AppState,
+    key: &str,
+    file_path: &Path,
+    dir: &Path,
+    index: &mut Index,
+    status: Status,
+) -> io::Result<Removed> {
+    let kept = keep_current(state, key, file_path, dir, index, status, false).await?;
+    if kept.is_none() {
+        // A suspended bucket's "null" version isn't kept
+        match fs::remove_file(file_path).await {
+            Err(e) if e.kind() != io::ErrorKind::NotFound => return Err(e),
+            _ => {}
+        }
+    }
+    let marker_id = match status {
+        Status::Enabled => new_version_id(),
+        Status::Suspended => NULL.to_string(),
+    };
+    if marker_id == NULL {
+        index.drop_null(dir).await;
+    }
+    if let Some(kept) = kept {
+        index.versions.retain(|v| v.version_id != kept.version_id);
+        index.versions.insert(0, kept);
+    }
+    index.versions.insert(0, delete_marker(marker_id.clone()));
+    index.current = None;
+    info!("🗑️ Added a delete marker for {}", key);
+    Ok(Removed {
+        version_id: marker_id,
+        delete_marker: true,
+    })
+}
+
+async fn remove_version(
+    state: &AppState,
+    file_path: &Path,
+    dir: &Path,
+    index: &mut Index,
+    version_id: &str,
+) -> io::Result<Removed> {
+    let metadata = fs::metadata(file_path).await.ok();
+    let mut delete_marker = false;
+    if index.current_id(metadata.as_ref()).as_deref() == Some(version_id) {
+        fs::remove_file(file_path).await?;
+        index.current = None;
+    } else if let Some(at) = index.versions.iter().position(|v| v.version_id == version_id) {
+        let version = index.versions.remove(at);
+        delete_marker = version.delete_marker;
+        if !delete_marker {
+            fs::remove_file(dir.join(version_id)).await?;
+        }
+    }
+    info!("🗑️ Deleted version {} of {}", version_id, index.key);
+
+    // With the current version or the delete marker on top gone, the newest
+    // version left under it takes its place
+    let uncovered = index.current.is_none() && !fs::try_exists(file_path).await.unwrap_or(false);
+    if uncovered && index.versions.first().is_some_and(|v| !v.delete_marker) {
+        let version = index.versions.remove(0);
+        if let Some(parent) = file_path.parent() {
+            fs::create_dir_all(parent).await?;
+        }
+        fs::rename(dir.join(&version.version_id), file_path).await?;
+        if let Some(etag) = &version.etag {
+            etag::store(state, &index.key, file_path, etag).await;
+        }
+        let metadata = fs::metadata(file_path).await?;
+        index.current = Some(Current {
+            version_id: version.version_id,
+            stamp: object_stamp(metadata.modified()?, metadata.len()),
+        });
+    }
+    Ok(Removed {
+        version_id: version_id.to_string(),
+        delete_marker,
+    })
+}
+
+// GET /?versioning. A bucket that was never versioned has no Status
+pub async fn get(state: &AppState) -> Result<Response, StatusCode> {
+    let mut config = state.versioning.config.get().unwrap_or_default();
+    config.set_xmlns(XMLNS);
+    let xml = serde_xml_rs::to_string(&config).map_err(|_| StatusCode::INTERNAL_SERVER_ERROR)?;
+    let mut headers = HeaderMap::new();
+    headers.insert("content-type", HeaderValue::from_static("application/xml"));
+    Ok((headers, xml).into_response())
+}
+
+#[derive(Debug, Serialize)]
+#[serde(rename = "ListVersionsResult")]
+struct ListVersionsResult {
+    #[serde(rename = "@xmlns")]
+    xmlns: String,
+    #[serde(rename = "Name")]
+    name: String,
+    #[serde(rename = "Prefix")]
+    prefix: String,
+    #[serde(rename = "KeyMarker")]
+    key_marker: String,
+    #[serde(rename = "VersionIdMarker")]
+    version_id_marker: String,
+    #[serde(rename = "IsTruncated")]
+    is_truncated: bool,
+    #[serde(rename = "Version")]
+    versions: Vec<ObjectVersion>,
+    #[serde(rename = "DeleteMarker")]
+    delete_markers: Vec<DeleteMarkerEntry>,
+}
+
+#[derive(Debug, Serialize)]
+struct ObjectVersion {
+    #[serde(rename = "Key")]
+    key: String,
+    #[serde(rename = "VersionId")]
+    version_id: String,
+    #[serde(rename = "IsLatest")]
+    is_latest: bool,
+    #[serde(rename = "LastModified")]
+    last_modified: String,
+    #[serde(rename = "ETag")]
+    etag: String,
+    #[serde(rename = "Size")]
+    size: u64,
+    #[serde(rename = "StorageClass")]
+    storage_class: String,
+    #[serde(rename = "Owner")]
+    owner: Owner,
+}
+
+#[derive(Debug, Serialize)]
+struct DeleteMarkerEntry {
+    #[serde(rename = "Key")]
+    key: String,
+    #[serde(rename = "VersionId")]
+    version_id: String,
+    #[serde(rename = "IsLatest")]
+    is_latest: bool,
+    #[serde(rename = "LastModified")]
+    last_modified: String,
+    #[serde(rename = "Owner")]
+    owner: Owner,
+}
+
+// The indexes of the keys under `prefix`
+async fn indexes(state: &AppState, prefix: &str) -> Vec<Index> {
+    let Ok(mut entries) = fs::read_dir(state.data_dir.join(VERSIONS_DIR)).await else {
+        return Vec::new();
+    };
+    let mut indexes = Vec::new();
+    while let Ok(Some(entry)) = entries.next_entry().await {
+        if let Ok(index) = load_index(&entry.path()).await
+            && index.key.starts_with(prefix)
+        {
+            indexes.push(index);
+        }
+    }
+    indexes
+}
+
+// GET /?versions, ListObjectVersions: every version of the objects under
+// `prefix`, the current ones from a listing and the rest from their indexes.
+// It's answered in one page
+pub async fn list(state: &AppState, prefix: &str) -> Result<Response, StatusCode> {
+    let mut indexes = indexes(state, prefix).await;
+    indexes.sort_by(|a, b| a.key.cmp(&b.key));
+    let objects = list_from(state, prefix, "").await;
+
+    let mut result = ListVersionsResult {
+        xmlns: XMLNS.to_string(),
+        name: state.bucket_name.clone(),
+        prefix: prefix.to_string(),
+        key_marker: String::new(),
+        version_id_marker: String::new(),
+        is_truncated: false,
+        versions: Vec::new(),
+        delete_markers: Vec::new(),
+    };
+    let mut remaining = indexes.into_iter().peekable();
+    for object in objects {
+        // Keys that only have noncurrent versions come before this one
+        while let Some(index) = remaining.next_if(|index| index.key < object.key) {
+            push_noncurrent(&mut result, index, true, state).await;
+        }
+        let index = remaining.next_if(|index| index.key == object.key);
+        let path = object_path(state, &object.key).ok();
+        let version_id = match &index {
+            Some(index) => {
+                let metadata = match &path {
+                    Some(path) => fs::metadata(path).await.ok(),
+                    None => None,
+                };
+                index.current_id(metadata.as_ref()).unwrap_or_else(|| NULL.to_string())
+            }
+            None => NULL.to_string(),
+        };
+        let etag = match &path {
+            Some(path) => etag::of_file(state, &object.key, path).await.unwrap_or_default(),
+            None => String::new(),
+        };
+        result.versions.push(ObjectVersion {
+            etag,
+            key: object.key,
+            version_id,
+            is_latest: true,
+            last_modified: object.last_modified,
+            size: object.size,
+            storage_class: object.storage_class,
+            owner: Owner::of(state),
+        });
+        if let Some(index) = index {
+            push_noncurrent(&mut result, index, false, state).await;
+        }
+    }
+    for index in remaining {
+        push_noncurrent(&mut result, index, true, state).await;
+    }
+
+    let xml = serde_xml_rs::to_string(&result).map_err(|_| StatusCode::INTERNAL_SERVER_ERROR)?;
+    let mut headers = HeaderMap::new();
+    headers.insert("content-type", HeaderValue::from_static("application/xml"));
+    Ok((headers, xml).into_response())
+}
+
+// Adds the versions in `index` to a listing, the first of them the latest
+// when there's no current object
+async fn push_noncurrent(
+    result: &mut ListVersionsResult,
+    index: Index,
+    first_is_latest: bool,
+    state: &AppState,
+) {
+    let dir = versions_dir(state, &index.key);
+    for (at, version) in index.versions.into_iter().enumerate() {
+        let is_latest = first_is_latest && at == 0;
+        if version.delete_marker {
+            result.delete_markers.push(DeleteMarkerEntry {
+                key: index.key.clone(),
+                version_id: version.version_id,
+                is_latest,
+                last_modified: version.last_modified,
+                owner: Owner::of(state),
+            });
+        } else {
+            let etag = match version.etag {
+                Some(etag) => etag,
+                None => {
+                    let path = dir.join(&version.version_id);
+                    etag::hash_file(state, &path).await.unwrap_or_default()
+                }
+            };
+            result.versions.push(ObjectVersion {
+                key: index.key.clone(),
+                etag,
+                version_id: version.version_id,
+                is_latest,
+                last_modified: version.last_modified,
+                size: version.size,
+                storage_class: "STANDARD".to_string(),
+                owner: Owner::of(state),
+            });
+        }
+    }
+}
@@ -0,0 +1,84 @@
+use futures_util::{StreamExt, stream::FuturesUnordered};
+use std::{fs::Metadata, path::PathBuf};
+use tokio::fs;
+
+use crate::{AppState, INTERNAL_DIR, inside_data_dir, keys, normalize, relative_path};
+
+// Concurrent walk of the data dir. Up to WALK_PARALLELISM directories are
+// read at once, each read running on tokio's blocking pool, and every object
+// is handed to the caller as soon as its directory has been read, so a walk
+// over a deep tree isn't one read_dir after another. Objects come in no
+// particular order. Symlinked directories aren't descended into, a link to
+// an ancestor would never end.
+
+const WALK_PARALLELISM: usize = 16;
+
+struct Listing {
+    dirs: Vec<PathBuf>,
+    files: Vec<(PathBuf, Metadata)>,
+}
+
+async fn read_dir(state: &AppState, dir: PathBuf) -> Listing {
+    let mut listing = Listing {
+        dirs: Vec::new(),
+        files: Vec::new(),
+    };
+    let Ok(mut entries) = fs::read_dir(&dir).await else {
+        return listing;
+    };
+
+    while let Ok(Some(entry)) = entries.next_entry().await {
+        if dir == state.data_dir && entry.file_name() == INTERNAL_DIR {
+            continue;
+        }
+        let path = entry.path();
+        let (Ok(file_type), Ok(metadata)) = (entry.file_type().await, fs::metadata(&path).await)
+        else {
+            continue;
+        };
+        if !inside_data_dir(state, &path) {
+            continue;
+        }
+
+        if file_type.is_dir() {
+            listing.dirs.push(path);
+        } else if metadata.is_file() {
+            listing.files.push((path, metadata));
+        }
+    }
+    listing
+}
+
+// Calls `found` with the key and metadata of every object under `start`
+pub async fn walk(state: &AppState, start: PathBuf, mut found: impl FnMut(String, Metadata)) {
+    let mut pending = vec![start];
+    let mut reading = FuturesUnordered::new();
+
+    loop {
+        while reading.len() < WALK_PARALLELISM
+            && let Some(dir) = pending.pop()
+        {
+            reading.push(read_dir(state, dir));
+        }
+        let Some(listing) = reading.next().await else {
+            break;
+        };
+
+        pending.extend(listing.dirs);
+        for (path, metadata) in listing.files {
+            if let Ok(rel) = path.strip_prefix(&state.data_dir) {
+                found(keys::key_of(rel), metadata);
+            }
+        }
+    }
+}
+
+// The deepest directory holding every key that starts with `prefix`, so a
+// listing of photos/2024/ only reads that part of the tree. None when no
+// key can start with it
+pub fn start_dir(state: &AppState, prefix: &str) -> Option<PathBuf> {
+    let prefix = normalize::stored_key(state, prefix);
+    let dir = prefix.rsplit_once('/').map_or("", |(dir, _)| dir);
+    let start = state.data_dir.join(relative_path(dir)?);
+    inside_data_dir(state, &start).then_some(start)
+}
@@ -0,0 +1,398 @@
+use axum::{
+    body::Bytes,
+    extract::{Request, State},
+    http::{HeaderMap, HeaderName, HeaderValue, Method, StatusCode, Uri},
+    middleware::Next,
+    response::{IntoResponse, Response},
+};
+use percent_encoding::{percent_decode_str, utf8_percent_encode};
+use serde::{Deserialize, Serialize};
+use std::{
+    collections::BTreeMap,
+    error::Error,
+    path::{Path, PathBuf},
+    sync::Arc,
+};
+use tracing::{info, warn};
+use wasmtime::{Config, Engine, Instance, Module, Store, StoreLimits, StoreLimitsBuilder};
+
+use crate::{ErrorResponse, INTERNAL_DIR, KEY_ENCODE_SET, error_response, multipart};
+
+// Sandboxed WASM modules run by the server. Modules get no imports at all,
+// so they can only compute on what they are handed, and every call runs in
+// a fresh instance with a fuel budget (roughly, instructions) and a cap on
+// linear memory. A module that runs out of either traps and the request
+// fails with 500.
+//
+// The calling convention is plain core WASM so any language can target it:
+// the module exports `memory`, `alloc(len: i32) -> i32` returning a buffer
+// the server copies the input into, and a handler taking `(ptr: i32, len:
+// i32)` and returning `(out_ptr << 32) | out_len` as an i64.
+//
+// GET transforms (--transforms) rewrite objects on the way out, like S3
+// Object Lambda. The handler is `transform`; its input is one line of JSON,
+// `{"key": ..., "headers": {...}}`, a newline and the object. It returns the
+// same shape: a JSON line whose `headers` are set on the response, a newline
+// and the new body. Content-Length and ETag are computed from the new body.
+//
+// Plugins (--plugins) see S3 API requests at up to three stages, depending
+// on which handlers they export: `on_auth` after the credentials check,
+// `on_pre_write` before an object PUT, POST or DELETE is handled and
+// `on_post_write` after it succeeded. Their input is the JSON of
+// PluginRequest. `on_auth` and `on_pre_write` return the JSON of a Verdict,
+// which can refuse the request or, before a write, change its key; empty
+// output lets the request through. `on_post_write` output is ignored.
+
+const DEFAULT_FUEL: u64 = 1_000_000_000;
+const DEFAULT_MEMORY_MB: usize = 64;
+
+#[derive(Debug, Deserialize)]
+struct GuestConfig {
+    module: PathBuf,
+    fuel: Option<u64>,
+    memory_mb: Option<usize>,
+}
+
+#[derive(Clone)]
+pub struct Guest {
+    engine: Engine,
+    module: Module,
+    fuel: u64,
+    memory_bytes: usize,
+}
+
+impl Guest {
+    fn load(engine: &Engine, config: &GuestConfig) -> Result<Self, Box<dyn Error>> {
+        let module = Module::from_file(engine, &config.module)
+            .map_err(|e| format!("{}: {}", config.module.display(), e))?;
+
+        Ok(Self {
+            engine: engine.clone(),
+            module,
+            fuel: config.fuel.unwrap_or(DEFAULT_FUEL),
+            memory_bytes: config.memory_mb.unwrap_or(DEFAULT_MEMORY_MB) * 1024 * 1024,
+        })
+    }
+
+    fn exports(&self, handler: &str) -> bool {
+        self.module.get_export(handler).is_some()
+    }
+
+    // Run `handler` on a fresh instance. Blocks, call from spawn_blocking
+    pub fn call(&self, handler: &str, input: &[u8]) -> wasmtime::Result<Vec<u8>> {
+        let limits = StoreLimitsBuilder::new()
+            .memory_size(self.memory_bytes)
+            .instances(1)
+            .build();
+        let mut store: Store<StoreLimits> = Store::new(&self.engine, limits);
+        store.limiter(|limits| limits);
+        store.set_fuel(self.fuel)?;
+
+        let instance = Instance::new(&mut store, &self.module, &[])?;
+        let memory = instance
+            .get_memory(&mut store, "memory")
+            .ok_or_else(|| wasmtime::Error::msg("module doesn't export memory"))?;
+        let alloc = instance.get_typed_func::<i32, i32>(&mut store, "alloc")?;
+        let handler = instance.get_typed_func::<(i32, i32), i64>(&mut store, handler)?;
+
+        let len = i32::try_from(input.len())?;
+        let ptr = alloc.call(&mut store, len)?;
+        memory.write(&mut store, ptr as u32 as usize, input)?;
+
+        let packed = handler.call(&mut store, (ptr, len))? as u64;
+        let mut output = vec![0; (packed & 0xffff_ffff) as usize];
+        memory.read(&store, (packed >> 32) as usize, &mut output)?;
+
+        Ok(output)
+    }
+}
+
+pub fn engine() -> Result<Engine, Box<dyn Error>> {
+    let mut config = Config::new();
+    config.consume_fuel(true);
+    Ok(Engine::new(&config)?)
+}
+
+#[derive(Debug, Deserialize)]
+struct TransformConfig {
+    prefix: String,
+    #[serde(flatten)]
+    guest: GuestConfig,
+}
+
+#[derive(Serialize)]
+struct TransformInput<'a> {
+    key: &'a str,
+    headers: BTreeMap<&'a str, &'a str>,
+}
+
+#[derive(Deserialize)]
+struct TransformOutput {
+    #[serde(default)]
+    headers: BTreeMap<String, String>,
+}
+
+pub struct Transforms {
+    // Longest prefix first
+    rules: Vec<(String, Guest)>,
+}
+
+impl Transforms {
+    // The --transforms file: a JSON list of
+    // {"prefix": ..., "module": ..., "fuel": ..., "memory_mb": ...}
+    pub fn load(path: &Path) -> Result<Self, Box<dyn Error>> {
+        let configs: Vec<TransformConfig> = serde_json::from_str(&std::fs::read_to_string(path)?)
+            .map_err(|e| format!("{}: {}", path.display(), e))?;
+
+        let engine = engine()?;
+        let mut rules = configs
+            .iter()
+            .map(|config| Ok((config.prefix.clone(), Guest::load(&engine, &config.guest)?)))
+            .collect::<Result<Vec<_>, Box<dyn Error>>>()?;
+        rules.sort_by_key(|(prefix, _)| std::cmp::Reverse(prefix.len()));
+
+        info!("🧩 Loaded {} GET transforms from {}", rules.len(), path.display());
+        Ok(Self { rules })
+    }
+
+    fn find(&self, key: &str) -> Option<&Guest> {
+        self.rules
+            .iter()
+            .find(|(prefix, _)| key.starts_with(prefix.as_str()))
+            .map(|(_, guest)| guest)
+    }
+
+    // Rewrite an object being served, if a transform covers its key
+    pub async fn apply(
+        &self,
+        key: &str,
+        headers: &mut HeaderMap,
+        data: Bytes,
+    ) -> Result<Bytes, StatusCode> {
+        let Some(guest) = self.find(key).cloned() else {
+            return Ok(data);
+        };
+
+        let input = TransformInput {
+            key,
+            headers: headers
+                .iter()
+                .filter_map(|(name, value)| Some((name.as_str(), value.to_str().ok()?)))
+                .collect(),
+        };
+        let mut framed = serde_json::to_vec(&input).map_err(|_| StatusCode::INTERNAL_SERVER_ERROR)?;
+        framed.push(b'\n');
+        framed.extend_from_slice(&data);
+
+        let output = tokio::task::spawn_blocking(move || guest.call("transform", &framed))
+            .await
+            .map_err(|_| StatusCode::INTERNAL_SERVER_ERROR)?
+            .map_err(|e| {
+                warn!("🧩 Transform failed for {}: {}", key, e);
+                StatusCode::INTERNAL_SERVER_ERROR
+            })?;
+
+        let split = output.iter().position(|b| *b == b'\n').unwrap_or(output.len());
+        let meta: TransformOutput = serde_json::from_slice(&output[..split]).map_err(|e| {
+            warn!("🧩 Transform for {} returned bad headers: {}", key, e);
+            StatusCode::INTERNAL_SERVER_ERROR
+        })?;
+
+        for (name, value) in meta.headers {
+            match (HeaderName::try_from(name.as_str()), HeaderValue::try_from(value)) {
+                (Ok(name), Ok(value)) => {
+                    headers.insert(name, value);
+                }
+                _ => warn!("🧩 Transform for {} set invalid header {}", key, name),
+            }
+        }
+
+        let mut output = Bytes::from(output);
+        Ok(output.split_off((split + 1).min(output.len())))
+    }
+}
+
+#[derive(Debug, Deserialize)]
+struct PluginConfig {
+    #[serde(flatten)]
+    guest: GuestConfig,
+}
+
+// What plugins are told about a request
+#[derive(Serialize)]
+struct PluginRequest {
+    stage: &'static str,
+    method: String,
+    key: String,
+    query: String,
+    headers: BTreeMap<String, String>,
+    // Only for on_post_write
+    #[serde(skip_serializing_if = "Option::is_none")]
+    status: Option<u16>,
+}
+
+#[derive(Deserialize)]
+struct Verdict {
+    #[serde(default = "allow")]
+    allow: bool,
+    status: Option<u16>,
+    message: Option<String>,
+    // Only honoured from on_pre_write
+    key: Option<String>,
+}
+
+fn allow() -> bool {
+    true
+}
+
+pub struct Plugins {
+    guests: Vec<Guest>,
+    // With tus enabled, /tus/ is its endpoint rather than object keys
+    tus: bool,
+}
+
+impl Plugins {
+    // The --plugins file: a JSON list of
+    // {"module": ..., "fuel": ..., "memory_mb": ...}, run in order
+    pub fn load(path: &Path, tus: bool) -> Result<Self, Box<dyn Error>> {
+        let configs: Vec<PluginConfig> = serde_json::from_str(&std::fs::read_to_string(path)?)
+            .map_err(|e| format!("{}: {}", path.display(), e))?;
+
+        let engine = engine()?;
+        let guests = configs
+            .iter()
+            .map(|config| Guest::load(&engine, &config.guest))
+            .collect::<Result<Vec<_>, _>>()?;
+
+        info!("🧩 Loaded {} plugins from {}", guests.len(), path.display());
+        Ok(Self { guests, tus })
+    }
+
+    fn is_object_write(&self, method: &Method, key: &str) -> bool {
+        matches!(*method, Method::PUT | Method::POST | Method::DELETE)
+            && !key.is_empty()
+            && !key.starts_with(INTERNAL_DIR)
+            && !(self.tus && (key == "tus" || key.starts_with("tus/")))
+    }
+
+    // Run one stage in every plugin that exports its handler, stopping at the
+    // first refusal. A key rewritten by one plugin is what the next one sees
+    async fn run(&self, handler: &str, request: &mut PluginRequest) -> Result<(), Response> {
+        for guest in self.guests.iter().filter(|guest| guest.exports(handler)) {
+            let input = serde_json::to_vec(&*request).map_err(|_| internal_error())?;
+            let guest = guest.clone();
+            let handler_name = handler.to_string();
+
+            let output = tokio::task::spawn_blocking(move || guest.call(&handler_name, &input))
+                .await
+                .map_err(|_| internal_error())?
+                .map_err(|e| {
+                    warn!("🧩 Plugin {} failed for {}: {}", handler, request.key, e);
+                    internal_error()
+                })?;
+
+            if output.is_empty() {
+                continue;
+            }
+            let verdict: Verdict = serde_json::from_slice(&output).map_err(|e| {
+                warn!("🧩 Plugin {} returned a bad verdict for {}: {}", handler, request.key, e);
+                internal_error()
+            })?;
+
+            if !verdict.allow {
+                warn!("🧩 Plugin {} refused {} {}", handler, request.method, request.key);
+                let status = verdict
+                    .status
+                    .and_then(|status| StatusCode::from_u16(status).ok())
+                    .filter(|status| status.is_client_error() || status.is_server_error())
+                    .unwrap_or(StatusCode::FORBIDDEN);
+                return Err(error_response(
+                    status,
+                    ErrorResponse {
+                        code: "AccessDenied".to_string(),
+                        message: verdict.message.unwrap_or_else(|| "Access Denied".to_string()),
+                        max_size_allowed: None,
+                    },
+                ));
+            }
+
+            if handler == "on_pre_write"
+                && let Some(key) = verdict.key
+            {
+                info!("🧩 Plugin rewrote key {} to {}", request.key, key);
+                request.key = key;
+            }
+        }
+
+        Ok(())
+    }
+}
+
+fn internal_error() -> Response {
+    StatusCode::INTERNAL_SERVER_ERROR.into_response()
+}
+
+// The object's URI with a different key, keeping the query
+fn with_key(uri: &Uri, key: &str) -> Option<Uri> {
+    let path = format!("/{}", utf8_percent_encode(key, KEY_ENCODE_SET));
+    let path_and_query = match uri.query() {
+        Some(query) => format!("{}?{}", path, query),
+        None => path,
+    };
+    path_and_query.parse().ok()
+}
+
+pub async fn plugin_middleware(
+    State(plugins): State<Arc<Plugins>>,
+    mut request: Request,
+    next: Next,
+) -> Response {
+    let key = percent_decode_str(request.uri().path().trim_start_matches('/'))
+        .decode_utf8_lossy()
+        .to_string();
+    let mut plugin_request = PluginRequest {
+        stage: "auth",
+        method: request.method().to_string(),
+        key: key.clone(),
+        query: request.uri().query().unwrap_or_default().to_string(),
+        headers: request
+            .headers()
+            .iter()
+            .filter_map(|(name, value)| Some((name.to_string(), value.to_str().ok()?.to_string())))
+            .collect(),
+        status: None,
+    };
+
+    if let Err(response) = plugins.run("on_auth", &mut plugin_request).await {
+        return response;
+    }
+
+    if !plugins.is_object_write(request.method(), &key)
+        || multipart::is_upload_step(request.method(), request.uri().query())
+    {
+        return next.run(request).await;
+    }
+
+    plugin_request.stage = "pre_write";
+    if let Err(response) = plugins.run("on_pre_write", &mut plugin_request).await {
+        return response;
+    }
+    if plugin_request.key != key {
+        match with_key(request.uri(), &plugin_request.key) {
+            Some(uri) => *request.uri_mut() = uri,
+            None => return StatusCode::BAD_REQUEST.into_response(),
+        }
+    }
+
+    let response = next.run(request).await;
+
+    if response.status().is_success() {
+        plugin_request.stage = "post_write";
+        plugin_request.status = Some(response.status().as_u16());
+        tokio::spawn(async move {
+            let _ = plugins.run("on_post_write", &mut plugin_request).await;
+        });
+    }
+
+    response
+}
@@ -0,0 +1,238 @@
+use notify::{EventKind, RecursiveMode, Watcher};
+use percent_encoding::utf8_percent_encode;
+use std::{
+    collections::{BTreeSet, HashMap},
+    error::Error,
+    path::{Path, PathBuf},
+    time::{Duration, Instant},
+};
+use tokio::{fs, sync::mpsc};
+use tracing::{info, warn};
+
+use crate::{KEY_ENCODE_SET, sign_request};
+
+// `simpleS3 watch --dir DIR --to URL`: mirror a local directory into a
+// bucket, continuously. DIR is watched with inotify, FSEvents or
+// ReadDirectoryChangesW, and a file that has been quiet for SETTLE is PUT
+// under its path below DIR, or DELETEd if it's gone. Requests are signed
+// with --access-key and --secret-key, so URL can be this server or any S3
+// endpoint (path style, ending in the bucket). At startup every file whose
+// object is missing or has a different size is uploaded. Files deleted
+// while the watcher isn't running stay in the bucket.
+
+const SETTLE: Duration = Duration::from_secs(2);
+const TICK: Duration = Duration::from_millis(500);
+
+struct Mirror {
+    client: reqwest::Client,
+    to: String,
+    prefix: String,
+    access_key: String,
+    secret_key: String,
+    dir: PathBuf,
+    // Keys uploaded from DIR, so a vanished path deletes only what the
+    // watcher put there
+    known: BTreeSet<String>,
+}
+
+impl Mirror {
+    fn key(&self, path: &Path) -> Option<String> {
+        let rel = path.strip_prefix(&self.dir).ok()?;
+        let segments = rel
+            .components()
+            .map(|component| component.as_os_str().to_str())
+            .collect::<Option<Vec<_>>>()?;
+        (!segments.is_empty()).then(|| format!("{}{}", self.prefix, segments.join("/")))
+    }
+
+    async fn send(&self, request: reqwest::RequestBuilder) -> Result<reqwest::Response, String> {
+        let (client, request) = request.build_split();
+        let mut request = request.map_err(|e| e.to_string())?;
+        sign_request(&self.access_key, &self.secret_key, &mut request);
+        client.execute(request).await.map_err(|e| e.to_string())
+    }
+
+    fn url(&self, key: &str) -> String {
+        format!("{}/{}", self.to, utf8_percent_encode(key, KEY_ENCODE_SET))
+    }
+
+    // Size of the object `key` in the bucket, None if there is none
+    async fn remote_size(&self, key: &str) -> Result<Option<u64>, String> {
+        let response = self.send(self.client.head(self.url(key))).await?;
+        if response.status() == reqwest::StatusCode::NOT_FOUND {
+            return Ok(None);
+        }
+        let response = response.error_for_status().map_err(|e| e.to_string())?;
+        Ok(response.content_length())
+    }
+
+    async fn upload(&mut self, key: String, path: &Path) -> Result<(), String> {
+        let bytes = fs::read(path).await.map_err(|e| e.to_string())?;
+        let len = bytes.len();
+        self.send(self.client.put(self.url(&key)).body(bytes))
+            .await?
+            .error_for_status()
+            .map_err(|e| e.to_string())?;
+        info!("👀 Uploaded {} ({} bytes)", key, len);
+        self.known.insert(key);
+        Ok(())
+    }
+
+    async fn delete(&mut self, key: &str) -> Result<(), String> {
+        let response = self.send(self.client.delete(self.url(key))).await?;
+        if response.status() != reqwest::StatusCode::NOT_FOUND {
+            response.error_for_status().map_err(|e| e.to_string())?;
+        }
+        info!("👀 Deleted {}", key);
+        self.known.remove(key);
+        Ok(())
+    }
+
+    // Regular files under `dir`
+    async fn files(dir: &Path) -> Vec<PathBuf> {
+        let mut files = Vec::new();
+        let mut dirs = vec![dir.to_path_buf()];
+        while let Some(dir) = dirs.pop() {
+            let Ok(mut entries) = fs::read_dir(&dir).await else {
+                continue;
+            };
+            while let Ok(Some(entry)) = entries.next_entry().await {
+                match entry.file_type().await {
+                    Ok(file_type) if file_type.is_dir() => dirs.push(entry.path()),
+                    Ok(file_type) if file_type.is_file() => files.push(entry.path()),
+                    _ => {}
+                }
+            }
+        }
+        files
+    }
+
+    // Brings the object at `path` in line with the file system. A directory
+    // that appeared (moved in, say) is synced file by file
+    async fn sync(&mut self, path: &Path) -> Result<(), String> {
+        let Some(key) = self.key(path) else {
+            return Ok(());
+        };
+        match fs::metadata(path).await {
+            Ok(metadata) if metadata.is_file() => self.upload(key, path).await,
+            Ok(metadata) if metadata.is_dir() => {
+                for file in Self::files(path).await {
+                    if let Some(key) = self.key(&file) {
+                        self.upload(key, &file).await?;
+                    }
+                }
+                Ok(())
+            }
+            Ok(_) => Ok(()),
+            // Gone: the object, or everything uploaded under the directory
+            Err(_) => {
+                let dir = format!("{}/", key);
+                let gone: Vec<String> = self
+                    .known
+                    .iter()
+                    .filter(|known| **known == key || known.starts_with(&dir))
+                    .cloned()
+                    .collect();
+                for key in gone {
+                    self.delete(&key).await?;
+                }
+                Ok(())
+            }
+        }
+    }
+
+    async fn initial_sync(&mut self) {
+        let files = Self::files(&self.dir.clone()).await;
+        let mut uploaded = 0;
+        for file in &files {
+            let Some(key) = self.key(file) else {
+                warn!("👀 Skipping non UTF-8 name {}", file.display());
+                continue;
+            };
+            let local = fs::metadata(file).await.map(|metadata| metadata.len()).ok();
+            let result = match self.remote_size(&key).await {
+                Ok(remote) if remote.is_some() && remote == local => {
+                    self.known.insert(key);
+                    continue;
+                }
+                Ok(_) => self.upload(key, file).await,
+                Err(e) => Err(e),
+            };
+            match result {
+                Ok(()) => uploaded += 1,
+                Err(e) => warn!("👀 Failed to sync {}: {}", file.display(), e),
+            }
+        }
+        info!("👀 Initial sync: {} files, {} uploaded", files.len(), uploaded);
+    }
+}
+
+pub async fn watch(
+    dir: &Path,
+    to: &str,
+    prefix: &str,
+    access_key: &str,
+    secret_key: &str,
+) -> Result<(), Box<dyn Error>> {
+    let dir = std::fs::canonicalize(dir)?;
+    let prefix = match prefix.trim_matches('/') {
+        "" => String::new(),
+        prefix => format!("{}/", prefix),
+    };
+    let mut mirror = Mirror {
+        client: reqwest::Client::new(),
+        to: to.trim_end_matches('/').to_string(),
+        prefix,
+        access_key: access_key.to_string(),
+        secret_key: secret_key.to_string(),
+        dir: dir.clone(),
+        known: BTreeSet::new(),
+    };
+
+    // Watch before the initial sync, so nothing written during it is missed
+    let (tx, mut rx) = mpsc::unbounded_channel();
+    let mut watcher = notify::recommended_watcher(move |event: notify::Result<notify::Event>| {
+        match event {
+            Ok(event) if !matches!(event.kind, EventKind::Access(_)) => {
+                for path in event.paths {
+                    let _ = tx.send(path);
+                }
+            }
+            Ok(_) => {}
+            Err(e) => warn!("👀 Watch error: {}", e),
+        }
+    })?;
+    watcher.watch(&dir, RecursiveMode::Recursive)?;
+    info!("👀 Mirroring {} to {}/{}", dir.display(), mirror.to, mirror.prefix);
+
+    mirror.initial_sync().await;
+
+    // Paths with changes not yet synced, by when they last changed
+    let mut pending: HashMap<PathBuf, Instant> = HashMap::new();
+    let mut tick = tokio::time::interval(TICK);
+    loop {
+        tokio::select! {
+            path = rx.recv() => match path {
+                Some(path) => {
+                    pending.insert(path, Instant::now());
+                }
+                None => return Err("the file system watcher stopped".into()),
+            },
+            _ = tick.tick() => {
+                let settled: Vec<PathBuf> = pending
+                    .iter()
+                    .filter(|(_, changed)| changed.elapsed() >= SETTLE)
+                    .map(|(path, _)| path.clone())
+                    .collect();
+                for path in settled {
+                    pending.remove(&path);
+                    if let Err(e) = mirror.sync(&path).await {
+                        // Try again once it has settled anew
+                        warn!("👀 Failed to sync {}: {}", path.display(), e);
+                        pending.insert(path, Instant::now());
+                    }
+                }
+            }
+        }
+    }
+}
@@ -0,0 +1,557 @@
+use axum::{
+    Router,
+    body::Body,
+    extract::{Request, State},
+    http::{HeaderMap, HeaderValue, Method, StatusCode},
+    middleware::{self, Next},
+    response::{IntoResponse, Response},
+};
+use base64::Engine;
+use percent_encoding::{AsciiSet, CONTROLS, percent_decode_str, utf8_percent_encode};
+use std::{
+    fs::Metadata,
+    path::{Path, PathBuf},
+    sync::Arc,
+};
+use tokio::fs;
+use tracing::{info, warn};
+
+use crate::{AppState, INTERNAL_DIR, authlog, policy, proxy::ClientInfo, copy, inside_data_dir, keys, normalize, relative_path, scan, sniff, stats, tier, tmp_path, worm, write_and_rename, rename_into_place};
+
+// WebDAV (RFC 4918) view of the data directory, served on its own port so
+// file managers can mount the bucket. Locks are advertised but not enforced,
+// which is enough for Finder and Windows Explorer to mount read-write.
+// Requests are checked against the bucket policy as the S3 calls they amount
+// to: PROPFIND as listing the collection, GET as reading, PUT and MKCOL as
+// writing, DELETE as deleting, and COPY and MOVE as reading the source and
+// writing the destination, a MOVE deleting the source as well.
+
+// Characters escaped in hrefs, everything but unreserved characters and '/'
+const HREF: &AsciiSet = &CONTROLS
+    .add(b' ')
+    .add(b'"')
+    .add(b'#')
+    .add(b'%')
+    .add(b'<')
+    .add(b'>')
+    .add(b'?')
+    .add(b'[')
+    .add(b']')
+    .add(b'^')
+    .add(b'`')
+    .add(b'{')
+    .add(b'|')
+    .add(b'}')
+    .add(b'&')
+    .add(b'+');
+
+pub fn router(state: Arc<AppState>) -> Router {
+    Router::new()
+        .fallback(dispatch)
+        .layer(middleware::from_fn_with_state(
+            state.clone(),
+            basic_auth_middleware,
+        ))
+        .with_state(state)
+}
+
+// File managers only speak HTTP Basic, checked against the S3 credentials
+async fn basic_auth_middleware(
+    State(state): State<Arc<AppState>>,
+    request: Request,
+    next: Next,
+) -> Response {
+    match basic_auth(&state, &request, "webdav") {
+        None => next.run(request).await,
+        Some(refusal) => refusal,
+    }
+}
+
+// Checks HTTP Basic credentials for `service`, the refusal to send if they
+// aren't right
+pub fn basic_auth(state: &AppState, request: &Request, service: &str) -> Option<Response> {
+    let ip = request
+        .extensions()
+        .get::<ClientInfo>()
+        .and_then(|client| client.ip);
+    let credentials = request
+        .headers()
+        .get("authorization")
+        .and_then(|v| v.to_str().ok())
+        .and_then(|v| v.strip_prefix("Basic "))
+        .and_then(|v| base64::engine::general_purpose::STANDARD.decode(v).ok())
+        .and_then(|v| String::from_utf8(v).ok());
+    let (access, secret) = credentials
+        .as_deref()
+        .and_then(|v| v.split_once(':'))
+        .unzip();
+
+    if let Some(wait) = state.lockout.blocked(ip) {
+        authlog::failure(state, service, ip, access, authlog::Reason::Throttled);
+        let mut headers = HeaderMap::new();
+        headers.insert("retry-after", HeaderValue::from(wait.as_secs_f64().ceil() as u64));
+        return Some((StatusCode::TOO_MANY_REQUESTS, headers).into_response());
+    }
+
+    if access == Some(state.access_key.as_str()) && secret == Some(state.secret_key.as_str()) {
+        state.lockout.succeeded(ip);
+        return None;
+    }
+
+    warn!("🚫 Unauthorized {} request", service);
+    // Clients probe without credentials before sending them
+    if credentials.is_some() {
+        state.lockout.failed(ip);
+        authlog::failure(state, service, ip, access, authlog::Reason::InvalidCredentials);
+    }
+    let mut headers = HeaderMap::new();
+    headers.insert(
+        "www-authenticate",
+        HeaderValue::from_static("Basic realm=\"simpleS3\""),
+    );
+    Some((StatusCode::UNAUTHORIZED, headers).into_response())
+}
+
+async fn dispatch(State(state): State<Arc<AppState>>, request: Request) -> Response {
+    let (parts, body) = request.into_parts();
+
+    let Some(rel) = resource_path(&state, parts.uri.path()) else {
+        return StatusCode::FORBIDDEN.into_response();
+    };
+    let path = keys::long_path(state.data_dir.join(&rel));
+    if !inside_data_dir(&state, &path) {
+        return StatusCode::FORBIDDEN.into_response();
+    }
+    let href = parts.uri.path().to_string();
+
+    if state.read_only
+        && matches!(
+            parts.method.as_str(),
+            "PUT" | "DELETE" | "MKCOL" | "MOVE" | "COPY" | "LOCK"
+        )
+    {
+        return StatusCode::FORBIDDEN.into_response();
+    }
+    if !permitted(&state, &parts, &rel) {
+        warn!("📜 Bucket policy denied WebDAV {} of {}", parts.method, href);
+        return StatusCode::FORBIDDEN.into_response();
+    }
+    // Out of disk space, the same as over S3
+    if matches!(parts.method.as_str(), "PUT" | "MKCOL" | "COPY") {
+        let len = parts
+            .headers
+            .get("content-length")
+            .and_then(|len| len.to_str().ok())
+            .and_then(|len| len.parse().ok())
+            .unwrap_or(0);
+        if state.shedder.refuses_write(len) {
+            state.metrics.shed();
+            return StatusCode::INSUFFICIENT_STORAGE.into_response();
+        }
+    }
+
+    let result = match parts.method.as_str() {
+        "OPTIONS" => Ok(options()),
+        "PROPFIND" => propfind(&state, &path, &rel, &parts.headers).await,
+        "GET" | "HEAD" => get(&state, &path, parts.method == Method::HEAD).await,
+        "PUT" => put(&state, &path, body).await,
+        "DELETE" => delete(&state, &path).await,
+        "MKCOL" => mkcol(&path).await,
+        "MOVE" | "COPY" => {
+            transfer(&state, &path, &parts.headers, parts.method == "MOVE").await
+        }
+        "LOCK" => Ok(lock(&href)),
+        "UNLOCK" => Ok(StatusCode::NO_CONTENT.into_response()),
+        _ => Err(StatusCode::METHOD_NOT_ALLOWED),
+    };
+
+    result.unwrap_or_else(|status| status.into_response())
+}
+
+fn resource_path(state: &AppState, uri_path: &str) -> Option<PathBuf> {
+    let decoded = percent_decode_str(uri_path).decode_utf8().ok()?;
+    relative_path(&normalize::stored_key(state, &decoded))
+}
+
+// Where the Destination header of a MOVE or COPY points, relative to the data
+// dir
+fn destination_path(state: &AppState, headers: &HeaderMap) -> Result<PathBuf, StatusCode> {
+    let destination = headers
+        .get("destination")
+        .and_then(|v| v.to_str().ok())
+        .ok_or(StatusCode::BAD_REQUEST)?;
+
+    // Destination may be an absolute URL or just a path
+    let destination_path = match url::Url::parse(destination) {
+        Ok(url) => url.path().to_string(),
+        Err(_) => destination.to_string(),
+    };
+    resource_path(state, &destination_path).ok_or(StatusCode::FORBIDDEN)
+}
+
+// Whether the bucket policy lets the request through
+fn permitted(state: &AppState, parts: &axum::http::request::Parts, rel: &Path) -> bool {
+    let client = parts.extensions.get::<ClientInfo>();
+    let caller = policy::Caller {
+        anonymous: false,
+        ip: client.and_then(|client| client.ip),
+        secure: client.is_some_and(|client| client.scheme == "https"),
+    };
+    let key = keys::key_of(rel);
+    let may = |action, key: &str| policy::permits(state, &caller, action, key);
+    match parts.method.as_str() {
+        "PROPFIND" if key.is_empty() => policy::permits_listing(state, &caller, ""),
+        "PROPFIND" => policy::permits_listing(state, &caller, &format!("{}/", key)),
+        "GET" | "HEAD" => may("s3:GetObject", &key),
+        "PUT" | "MKCOL" => may("s3:PutObject", &key),
+        "DELETE" => may("s3:DeleteObject", &key),
+        method @ ("MOVE" | "COPY") => {
+            // A bad destination is refused by the transfer itself
+            let destination = destination_path(state, &parts.headers)
+                .map(|destination| keys::key_of(&destination))
+                .ok();
+            may("s3:GetObject", &key)
+                && (method == "COPY" || may("s3:DeleteObject", &key))
+                && destination.is_none_or(|destination| may("s3:PutObject", &destination))
+        }
+        _ => true,
+    }
+}
+
+fn options() -> Response {
+    let mut headers = HeaderMap::new();
+    headers.insert("dav", HeaderValue::from_static("1, 2"));
+    headers.insert("ms-author-via", HeaderValue::from_static("DAV"));
+    headers.insert(
+        "allow",
+        HeaderValue::from_static(
+            "OPTIONS, PROPFIND, GET, HEAD, PUT, DELETE, MKCOL, MOVE, COPY, LOCK, UNLOCK",
+        ),
+    );
+    (StatusCode::OK, headers).into_response()
+}
+
+async fn propfind(
+    state: &AppState,
+    path: &Path,
+    rel: &Path,
+    headers: &HeaderMap,
+) -> Result<Response, StatusCode> {
+    let metadata = fs::metadata(path)
+        .await
+        .map_err(|_| StatusCode::NOT_FOUND)?;
+
+    let depth = headers
+        .get("depth")
+        .and_then(|v| v.to_str().ok())
+        .unwrap_or("1");
+
+    let mut xml = String::from(
+        "<?xml version=\"1.0\" encoding=\"utf-8\"?>\n<D:multistatus xmlns:D=\"DAV:\">\n",
+    );
+    push_response(&mut xml, rel, &metadata);
+
+    if metadata.is_dir() && depth != "0" {
+        let mut entries = fs::read_dir(path)
+            .await
+            .map_err(|_| StatusCode::INTERNAL_SERVER_ERROR)?;
+
+        while let Ok(Some(entry)) = entries.next_entry().await {
+            let child = rel.join(entry.file_name());
+            if child.starts_with(INTERNAL_DIR) || !inside_data_dir(state, &entry.path()) {
+                continue;
+            }
+            if let Ok(metadata) = entry.metadata().await {
+                push_response(&mut xml, &child, &metadata);
+            }
+        }
+    }
+
+    xml.push_str("</D:multistatus>\n");
+
+    let mut headers = HeaderMap::new();
+    headers.insert(
+        "content-type",
+        HeaderValue::from_static("application/xml; charset=utf-8"),
+    );
+    Ok((StatusCode::MULTI_STATUS, headers, xml).into_response())
+}
+
+fn push_response(xml: &mut String, rel: &Path, metadata: &Metadata) {
+    let mut href = format!("/{}", keys::key_of(rel));
+    if metadata.is_dir() && !href.ends_with('/') {
+        href.push('/');
+    }
+    let name = rel
+        .file_name()
+        .map(|n| keys::decode_segment(&n.to_string_lossy()).into_owned())
+        .unwrap_or_default();
+
+    let modified: chrono::DateTime<chrono::Utc> = metadata
+        .modified()
+        .unwrap_or(std::time::SystemTime::now())
+        .into();
+
+    xml.push_str("<D:response>");
+    xml.push_str(&format!(
+        "<D:href>{}</D:href>",
+        xml_escape(&utf8_percent_encode(&href, HREF).to_string())
+    ));
+    xml.push_str("<D:propstat><D:prop>");
+    xml.push_str(&format!("<D:displayname>{}</D:displayname>", xml_escape(&name)));
+    xml.push_str(&format!(
+        "<D:getlastmodified>{}</D:getlastmodified>",
+        modified.format("%a, %d %b %Y %H:%M:%S GMT")
+    ));
+
+    if metadata.is_dir() {
+        xml.push_str("<D:resourcetype><D:collection/></D:resourcetype>");
+    } else {
+        let mime_type = mime_guess::from_path(rel).first_or_octet_stream();
+        xml.push_str("<D:resourcetype/>");
+        xml.push_str(&format!(
+            "<D:getcontentlength>{}</D:getcontentlength>",
+            metadata.len()
+        ));
+        xml.push_str(&format!(
+            "<D:getcontenttype>{}</D:getcontenttype>",
+            xml_escape(mime_type.as_ref())
+        ));
+        xml.push_str(&format!(
+            "<D:getetag>\"{}-{}\"</D:getetag>",
+            metadata.len(),
+            modified.timestamp_millis()
+        ));
+    }
+
+    xml.push_str("</D:prop><D:status>HTTP/1.1 200 OK</D:status></D:propstat>");
+    xml.push_str("</D:response>\n");
+}
+
+fn xml_escape(value: &str) -> String {
+    value
+        .replace('&', "&amp;")
+        .replace('<', "&lt;")
+        .replace('>', "&gt;")
+        .replace('"', "&quot;")
+}
+
+async fn get(state: &AppState, path: &Path, head_only: bool) -> Result<Response, StatusCode> {
+    let metadata = fs::metadata(path)
+        .await
+        .map_err(|_| StatusCode::NOT_FOUND)?;
+    if metadata.is_dir() {
+        return Err(StatusCode::METHOD_NOT_ALLOWED);
+    }
+
+    let mut headers = HeaderMap::new();
+    headers.insert("content-length", HeaderValue::from(metadata.len()));
+
+    if head_only {
+        let handle = state
+            .handles
+            .open(path)
+            .await
+            .map_err(|_| StatusCode::NOT_FOUND)?;
+        let mime_type = sniff::content_type_of(state, path, &handle)
+            .await
+            .map_err(|_| StatusCode::INTERNAL_SERVER_ERROR)?;
+        headers.insert("content-type", HeaderValue::from_str(&mime_type).unwrap());
+        return Ok((StatusCode::OK, headers).into_response());
+    }
+
+    tier::recall_path(state, path)
+        .await
+        .map_err(|_| StatusCode::INTERNAL_SERVER_ERROR)?;
+    let data = fs::read(path).await.map_err(|_| StatusCode::NOT_FOUND)?;
+    let mime_type = sniff::content_type(state, path, &data);
+    headers.insert("content-type", HeaderValue::from_str(&mime_type).unwrap());
+    Ok((StatusCode::OK, headers, data).into_response())
+}
+
+async fn put(state: &AppState, path: &Path, body: Body) -> Result<Response, StatusCode> {
+    if path.parent().is_none_or(|parent| !parent.is_dir()) {
+        return Err(StatusCode::CONFLICT);
+    }
+    if path.is_dir() {
+        return Err(StatusCode::METHOD_NOT_ALLOWED);
+    }
+    worm::check(state, path).await?;
+
+    let old = stats::size(path).await;
+    let bytes = axum::body::to_bytes(body, usize::MAX)
+        .await
+        .map_err(|_| StatusCode::BAD_REQUEST)?;
+    scan::check_bytes(state, "webdav", &scan::key_at(state, path), &bytes)
+        .await
+        .map_err(|refusal| refusal.status())?;
+
+    let tmp_path = tmp_path(state);
+    if let Err(e) = write_and_rename(state, &tmp_path, path, &bytes).await {
+        warn!("Failed to store {} over WebDAV: {}", path.display(), e);
+        let _ = fs::remove_file(&tmp_path).await;
+        return Err(StatusCode::INTERNAL_SERVER_ERROR);
+    }
+
+    state.stats.replaced(old, Some(bytes.len() as u64));
+    state.handles.invalidate(path);
+    info!("📁 Stored object over WebDAV: {} ({} bytes)", path.display(), bytes.len());
+
+    if old.is_some() {
+        Ok(StatusCode::NO_CONTENT.into_response())
+    } else {
+        Ok(StatusCode::CREATED.into_response())
+    }
+}
+
+async fn delete(state: &AppState, path: &Path) -> Result<Response, StatusCode> {
+    worm::check(state, path).await?;
+    let metadata = fs::metadata(path)
+        .await
+        .map_err(|_| StatusCode::NOT_FOUND)?;
+
+    let removed = if metadata.is_dir() {
+        fs::remove_dir_all(path).await
+    } else {
+        fs::remove_file(path).await
+    };
+    removed.map_err(|_| StatusCode::INTERNAL_SERVER_ERROR)?;
+    if metadata.is_file() {
+        state.stats.replaced(Some(metadata.len()), None);
+        state.handles.invalidate(path);
+    } else {
+        state.handles.clear();
+    }
+
+    info!("🗑️ Deleted over WebDAV: {}", path.display());
+    Ok(StatusCode::NO_CONTENT.into_response())
+}
+
+async fn mkcol(path: &Path) -> Result<Response, StatusCode> {
+    if path.exists() {
+        return Err(StatusCode::METHOD_NOT_ALLOWED);
+    }
+
+    match fs::create_dir(path).await {
+        Ok(()) => Ok(StatusCode::CREATED.into_response()),
+        Err(e) if e.kind() == std::io::ErrorKind::NotFound => Err(StatusCode::CONFLICT),
+        Err(_) => Err(StatusCode::INTERNAL_SERVER_ERROR),
+    }
+}
+
+async fn transfer(
+    state: &AppState,
+    path: &Path,
+    headers: &HeaderMap,
+    is_move: bool,
+) -> Result<Response, StatusCode> {
+    let rel = destination_path(state, headers)?;
+    let target = keys::long_path(state.data_dir.join(rel));
+    if !inside_data_dir(state, &target) {
+        return Err(StatusCode::FORBIDDEN);
+    }
+
+    if !path.exists() {
+        return Err(StatusCode::NOT_FOUND);
+    }
+    if target.parent().is_none_or(|parent| !parent.is_dir()) {
+        return Err(StatusCode::CONFLICT);
+    }
+
+    let overwrite = headers
+        .get("overwrite")
+        .and_then(|v| v.to_str().ok())
+        .is_none_or(|v| !v.eq_ignore_ascii_case("F"));
+    let existed = target.exists();
+    if is_move {
+        worm::check(state, path).await?;
+    }
+    worm::check(state, &target).await?;
+
+    if existed {
+        if !overwrite {
+            return Err(StatusCode::PRECONDITION_FAILED);
+        }
+        let _ = delete(state, &target).await;
+    }
+
+    if let Err(e) = tier::recall_under(state, path).await {
+        warn!("WebDAV transfer of {} failed: {}", path.display(), e);
+        return Err(StatusCode::INTERNAL_SERVER_ERROR);
+    }
+    let result = if is_move {
+        fs::rename(path, &target).await
+    } else {
+        copy_tree(state, path, &target).await
+    };
+
+    if let Err(e) = result {
+        warn!("WebDAV transfer to {} failed: {}", target.display(), e);
+        return Err(StatusCode::INTERNAL_SERVER_ERROR);
+    }
+    state.handles.clear();
+
+    if existed {
+        Ok(StatusCode::NO_CONTENT.into_response())
+    } else {
+        Ok(StatusCode::CREATED.into_response())
+    }
+}
+
+// Copies a file or directory tree, each file written aside and renamed into
+// place so an object is never seen half-copied
+async fn copy_tree(state: &AppState, src: &Path, dst: &Path) -> std::io::Result<()> {
+    let mut pending = vec![(src.to_path_buf(), dst.to_path_buf())];
+
+    while let Some((src, dst)) = pending.pop() {
+        if fs::metadata(&src).await?.is_dir() {
+            fs::create_dir_all(&dst).await?;
+            let mut entries = fs::read_dir(&src).await?;
+            while let Some(entry) = entries.next_entry().await? {
+                pending.push((entry.path(), dst.join(entry.file_name())));
+            }
+        } else {
+            let tmp = tmp_path(state);
+            let copied = async {
+                copy::copy_file(&src, &tmp).await?;
+                rename_into_place(&tmp, &dst).await
+            }
+            .await;
+            if copied.is_err() {
+                let _ = fs::remove_file(&tmp).await;
+            }
+            copied?;
+        }
+    }
+
+    Ok(())
+}
+
+// Locks are not enforced, but clients refuse to write without a token
+fn lock(href: &str) -> Response {
+    let token = format!("opaquelocktoken:{}", uuid::Uuid::new_v4());
+    let xml = format!(
+        "<?xml version=\"1.0\" encoding=\"utf-8\"?>\n\
+         <D:prop xmlns:D=\"DAV:\"><D:lockdiscovery><D:activelock>\
+         <D:locktype><D:write/></D:locktype>\
+         <D:lockscope><D:exclusive/></D:lockscope>\
+         <D:depth>infinity</D:depth>\
+         <D:timeout>Second-3600</D:timeout>\
+         <D:locktoken><D:href>{}</D:href></D:locktoken>\
+         <D:lockroot><D:href>{}</D:href></D:lockroot>\
+         </D:activelock></D:lockdiscovery></D:prop>\n",
+        token,
+        xml_escape(href)
+    );
+
+    let mut headers = HeaderMap::new();
+    headers.insert(
+        "content-type",
+        HeaderValue::from_static("application/xml; charset=utf-8"),
+    );
+    headers.insert(
+        "lock-token",
+        HeaderValue::from_str(&format!("<{}>", token)).unwrap(),
+    );
+    (StatusCode::OK, headers, xml).into_response()
+}
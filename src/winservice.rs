@@ -0,0 +1,347 @@
+use clap::{Parser, Subcommand};
+use std::{
+    error::Error,
+    ffi::{OsStr, OsString},
+    io,
+    time::Duration,
+};
+use tracing::{Level, Metadata, error, info};
+use tracing_subscriber::fmt::MakeWriter;
+use windows_service::{
+    define_windows_service,
+    service::{
+        ServiceAccess, ServiceAction, ServiceActionType, ServiceControl, ServiceControlAccept,
+        ServiceErrorControl, ServiceExitCode, ServiceFailureActions, ServiceFailureResetPeriod,
+        ServiceInfo, ServiceStartType, ServiceState, ServiceStatus, ServiceType,
+    },
+    service_control_handler::{self, ServiceControlHandlerResult, ServiceStatusHandle},
+    service_dispatcher,
+    service_manager::{ServiceManager, ServiceManagerAccess},
+};
+use windows_sys::Win32::{
+    Foundation::HANDLE,
+    System::{
+        EventLog::{
+            DeregisterEventSource, EVENTLOG_ERROR_TYPE, EVENTLOG_INFORMATION_TYPE,
+            EVENTLOG_WARNING_TYPE, REPORT_EVENT_TYPE, RegisterEventSourceW, ReportEventW,
+        },
+        Registry::{
+            HKEY, HKEY_LOCAL_MACHINE, KEY_WRITE, REG_DWORD, REG_EXPAND_SZ,
+            REG_OPTION_NON_VOLATILE, RegCloseKey, RegCreateKeyExW, RegDeleteTreeW, RegSetValueExW,
+        },
+    },
+};
+
+use crate::Args;
+
+// Native Windows service. `service install` registers the binary with the
+// service manager to start automatically, with the flags given alongside it,
+// and restart if it fails. The service manager launches it as `service run`,
+// which serves until the service is stopped and logs to the Application
+// event log under the source SERVICE_NAME.
+
+const SERVICE_NAME: &str = "simpleS3";
+const SERVICE_TYPE: ServiceType = ServiceType::OWN_PROCESS;
+const EVENT_SOURCE_KEY: &str = r"SYSTEM\CurrentControlSet\Services\EventLog\Application\simpleS3";
+// Ships with the .NET Framework and formats every event ID as just its text,
+// so events read properly without a message DLL of our own
+const EVENT_MESSAGE_FILE: &str =
+    r"%SystemRoot%\Microsoft.NET\Framework64\v4.0.30319\EventLogMessages.dll";
+
+#[derive(Subcommand)]
+pub enum ServiceCommand {
+    #[command(about = "Install and start the Windows service, passing it the other flags given")]
+    Install,
+    #[command(about = "Stop and remove the Windows service")]
+    Uninstall,
+    #[command(about = "Run as the Windows service, for the service manager to call")]
+    Run,
+}
+
+pub fn command(command: &ServiceCommand) -> Result<(), Box<dyn Error>> {
+    match command {
+        ServiceCommand::Install => {
+            tracing_subscriber::fmt::init();
+            install()
+        }
+        ServiceCommand::Uninstall => {
+            tracing_subscriber::fmt::init();
+            uninstall()
+        }
+        ServiceCommand::Run => {
+            // Services start in System32, so relative paths are taken from
+            // where the binary is
+            if let Some(dir) = std::env::current_exe()?.parent() {
+                std::env::set_current_dir(dir)?;
+            }
+            Ok(service_dispatcher::start(SERVICE_NAME, ffi_service_main)?)
+        }
+    }
+}
+
+fn wide(s: impl AsRef<OsStr>) -> Vec<u16> {
+    use std::os::windows::ffi::OsStrExt;
+    s.as_ref().encode_wide().chain(std::iter::once(0)).collect()
+}
+
+// This process's arguments with `service install` turned into `service run`
+fn launch_arguments() -> Vec<OsString> {
+    let mut args: Vec<OsString> = std::env::args_os().skip(1).collect();
+    if let Some(i) = args
+        .windows(2)
+        .position(|pair| pair[0] == "service" && pair[1] == "install")
+    {
+        args[i + 1] = "run".into();
+    }
+    args
+}
+
+fn install() -> Result<(), Box<dyn Error>> {
+    let manager = ServiceManager::local_computer(
+        None::<&str>,
+        ServiceManagerAccess::CONNECT | ServiceManagerAccess::CREATE_SERVICE,
+    )?;
+    let info = ServiceInfo {
+        name: SERVICE_NAME.into(),
+        display_name: "simpleS3 object storage".into(),
+        service_type: SERVICE_TYPE,
+        start_type: ServiceStartType::AutoStart,
+        error_control: ServiceErrorControl::Normal,
+        executable_path: std::env::current_exe()?,
+        launch_arguments: launch_arguments(),
+        dependencies: vec![],
+        // LocalSystem
+        account_name: None,
+        account_password: None,
+    };
+    let service = manager.create_service(
+        &info,
+        ServiceAccess::CHANGE_CONFIG | ServiceAccess::START,
+    )?;
+    service.set_description("S3-compatible object storage server")?;
+    service.update_failure_actions(ServiceFailureActions {
+        reset_period: ServiceFailureResetPeriod::After(Duration::from_secs(24 * 60 * 60)),
+        reboot_msg: None,
+        command: None,
+        actions: Some(vec![
+            ServiceAction {
+                action_type: ServiceActionType::Restart,
+                delay: Duration::from_secs(5),
+            };
+            3
+        ]),
+    })?;
+    service.set_failure_actions_on_non_crash_failures(true)?;
+    register_event_source()?;
+
+    service.start::<&OsStr>(&[])?;
+    info!("Installed and started the {} service", SERVICE_NAME);
+    Ok(())
+}
+
+fn uninstall() -> Result<(), Box<dyn Error>> {
+    let manager = ServiceManager::local_computer(None::<&str>, ServiceManagerAccess::CONNECT)?;
+    let service = manager.open_service(
+        SERVICE_NAME,
+        ServiceAccess::QUERY_STATUS | ServiceAccess::STOP | ServiceAccess::DELETE,
+    )?;
+    if service.query_status()?.current_state != ServiceState::Stopped {
+        service.stop()?;
+    }
+    // Takes effect once the service has stopped
+    service.delete()?;
+
+    let key = wide(EVENT_SOURCE_KEY);
+    unsafe { RegDeleteTreeW(HKEY_LOCAL_MACHINE, key.as_ptr()) };
+    info!("Removed the {} service", SERVICE_NAME);
+    Ok(())
+}
+
+// Points the event source at a message file so the event viewer shows the
+// logged text
+fn register_event_source() -> io::Result<()> {
+    let mut key: HKEY = std::ptr::null_mut();
+    let path = wide(EVENT_SOURCE_KEY);
+    let status = unsafe {
+        RegCreateKeyExW(
+            HKEY_LOCAL_MACHINE,
+            path.as_ptr(),
+            0,
+            std::ptr::null(),
+            REG_OPTION_NON_VOLATILE,
+            KEY_WRITE,
+            std::ptr::null(),
+            &mut key,
+            std::ptr::null_mut(),
+        )
+    };
+    if status != 0 {
+        return Err(io::Error::from_raw_os_error(status as i32));
+    }
+
+    let message_file = wide(EVENT_MESSAGE_FILE);
+    let types_supported = (EVENTLOG_ERROR_TYPE | EVENTLOG_WARNING_TYPE | EVENTLOG_INFORMATION_TYPE) as u32;
+    let results = unsafe {
+        [
+            RegSetValueExW(
+                key,
+                wide("EventMessageFile").as_ptr(),
+                0,
+                REG_EXPAND_SZ,
+                message_file.as_ptr().cast(),
+                (message_file.len() * 2) as u32,
+            ),
+            RegSetValueExW(
+                key,
+                wide("TypesSupported").as_ptr(),
+                0,
+                REG_DWORD,
+                (&types_supported as *const u32).cast(),
+                4,
+            ),
+        ]
+    };
+    unsafe { RegCloseKey(key) };
+
+    match results.into_iter().find(|status| *status != 0) {
+        Some(status) => Err(io::Error::from_raw_os_error(status as i32)),
+        None => Ok(()),
+    }
+}
+
+// Tracing output sent to the Application event log, one event per line
+struct EventLog {
+    handle: HANDLE,
+}
+
+// Event log handles can be used from any thread
+unsafe impl Send for EventLog {}
+unsafe impl Sync for EventLog {}
+
+impl EventLog {
+    fn open() -> io::Result<Self> {
+        let source = wide(SERVICE_NAME);
+        let handle = unsafe { RegisterEventSourceW(std::ptr::null(), source.as_ptr()) };
+        if handle.is_null() {
+            return Err(io::Error::last_os_error());
+        }
+        Ok(Self { handle })
+    }
+}
+
+impl Drop for EventLog {
+    fn drop(&mut self) {
+        unsafe { DeregisterEventSource(self.handle) };
+    }
+}
+
+struct EventWriter<'a> {
+    log: &'a EventLog,
+    kind: REPORT_EVENT_TYPE,
+}
+
+impl io::Write for EventWriter<'_> {
+    fn write(&mut self, buf: &[u8]) -> io::Result<usize> {
+        let message = wide(String::from_utf8_lossy(buf).trim_end());
+        let strings = [message.as_ptr()];
+        unsafe {
+            ReportEventW(
+                self.log.handle,
+                self.kind,
+                0,
+                0,
+                std::ptr::null_mut(),
+                1,
+                0,
+                strings.as_ptr(),
+                std::ptr::null(),
+            )
+        };
+        Ok(buf.len())
+    }
+
+    fn flush(&mut self) -> io::Result<()> {
+        Ok(())
+    }
+}
+
+impl<'a> MakeWriter<'a> for EventLog {
+    type Writer = EventWriter<'a>;
+
+    fn make_writer(&'a self) -> Self::Writer {
+        EventWriter {
+            log: self,
+            kind: EVENTLOG_INFORMATION_TYPE,
+        }
+    }
+
+    fn make_writer_for(&'a self, meta: &Metadata<'_>) -> Self::Writer {
+        let kind = match *meta.level() {
+            Level::ERROR => EVENTLOG_ERROR_TYPE,
+            Level::WARN => EVENTLOG_WARNING_TYPE,
+            _ => EVENTLOG_INFORMATION_TYPE,
+        };
+        EventWriter { log: self, kind }
+    }
+}
+
+define_windows_service!(ffi_service_main, service_main);
+
+fn service_main(_arguments: Vec<OsString>) {
+    match EventLog::open() {
+        Ok(log) => tracing_subscriber::fmt()
+            .with_writer(log)
+            .with_ansi(false)
+            .without_time()
+            .init(),
+        Err(_) => tracing_subscriber::fmt::init(),
+    }
+    if let Err(e) = run_service() {
+        error!("Service stopped: {}", e);
+    }
+}
+
+fn set_state(status: &ServiceStatusHandle, state: ServiceState, exit_code: u32) {
+    let controls_accepted = match state {
+        ServiceState::Running => ServiceControlAccept::STOP | ServiceControlAccept::SHUTDOWN,
+        _ => ServiceControlAccept::empty(),
+    };
+    let _ = status.set_service_status(ServiceStatus {
+        service_type: SERVICE_TYPE,
+        current_state: state,
+        controls_accepted,
+        exit_code: ServiceExitCode::Win32(exit_code),
+        checkpoint: 0,
+        wait_hint: Duration::default(),
+        process_id: None,
+    });
+}
+
+fn run_service() -> Result<(), Box<dyn Error>> {
+    let (stop, stopped) = tokio::sync::oneshot::channel::<()>();
+    let mut stop = Some(stop);
+    let status = service_control_handler::register(SERVICE_NAME, move |control| match control {
+        ServiceControl::Stop | ServiceControl::Shutdown => {
+            if let Some(stop) = stop.take() {
+                let _ = stop.send(());
+            }
+            ServiceControlHandlerResult::NoError
+        }
+        ServiceControl::Interrogate => ServiceControlHandlerResult::NoError,
+        _ => ServiceControlHandlerResult::NotImplemented,
+    })?;
+    set_state(&status, ServiceState::Running, 0);
+
+    let args = Args::parse();
+    let result = crate::runtime(&args)
+        .map_err(Box::<dyn Error>::from)
+        .and_then(|runtime| {
+            runtime.block_on(crate::serve(args, async {
+                let _ = stopped.await;
+                info!("Stopping the {} service", SERVICE_NAME);
+            }))
+        });
+
+    set_state(&status, ServiceState::Stopped, if result.is_ok() { 0 } else { 1 });
+    result
+}
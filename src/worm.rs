@@ -0,0 +1,81 @@
+use axum::{
+    http::{HeaderMap, StatusCode},
+    response::Response,
+};
+use std::path::Path;
+use tokio::fs;
+use tracing::warn;
+
+use crate::{AppState, ErrorResponse, error_response, keys, retention};
+
+// Write once, read many. With --worm, an object can be created once and then
+// neither overwritten nor deleted, whichever way it's reached: S3 PUT, copy,
+// compose, ranged writes and DELETE, tus uploads, and WebDAV and SFTP writes,
+// deletes and moves are refused once the key exists. Over the S3 API that's
+// 403 MethodNotAllowed, elsewhere a plain 403 or permission denied. Every
+// access key of the API is subject to it; only someone with access to the
+// data dir can remove objects. Existence is checked before writing, so two
+// uploads racing to create the same key can still both land, the later one
+// winning.
+//
+// Objects under an object lock (see retention.rs) are kept the same way, on
+// their own.
+
+// Whether --worm keeps `path` as it is
+pub async fn write_once(state: &AppState, path: &Path) -> bool {
+    state.worm && fs::symlink_metadata(path).await.is_ok()
+}
+
+// Whether --worm or an object lock keeps `path` as it is
+pub async fn locked(state: &AppState, path: &Path) -> bool {
+    if write_once(state, path).await {
+        return true;
+    }
+    match path.strip_prefix(&state.data_dir) {
+        Ok(rel) => retention::protects(state, &keys::key_of(rel), false).await,
+        Err(_) => false,
+    }
+}
+
+pub fn refusal(key: &str) -> Response {
+    warn!("🔏 Refused to change {}, objects are write-once", key);
+    error_response(
+        StatusCode::FORBIDDEN,
+        ErrorResponse {
+            code: "MethodNotAllowed".to_string(),
+            message: "Objects cannot be overwritten or deleted on this server".to_string(),
+            max_size_allowed: None,
+        },
+    )
+}
+
+// The refusal for a write or delete of `key` at `path`, None if it may go
+// ahead
+pub async fn refuse(state: &AppState, key: &str, path: &Path) -> Option<Response> {
+    refuse_with(state, key, path, &HeaderMap::new()).await
+}
+
+// Same for requests that may get past GOVERNANCE retention with
+// x-amz-bypass-governance-retention
+pub async fn refuse_with(
+    state: &AppState,
+    key: &str,
+    path: &Path,
+    headers: &HeaderMap,
+) -> Option<Response> {
+    if write_once(state, path).await {
+        return Some(refusal(key));
+    }
+    retention::protects(state, key, retention::bypasses(headers))
+        .await
+        .then(|| retention::locked(key))
+}
+
+// For the tus and WebDAV handlers, which answer with a bare status
+pub async fn check(state: &AppState, path: &Path) -> Result<(), StatusCode> {
+    if locked(state, path).await {
+        warn!("🔏 Refused to change {}, it's write-once or locked", path.display());
+        return Err(StatusCode::FORBIDDEN);
+    }
+    Ok(())
+}
@@ -0,0 +1,96 @@
+#!/usr/bin/env bash
+# Pushes an image through a Docker registry (registry:2) whose S3 storage
+# driver points at simpleS3, then pulls it back and checks its contents.
+# The image has a layer larger than the driver's chunk size, so the push
+# goes through multipart uploads as well as listings, copies and moves.
+#
+# Needs Docker with host networking (Linux) and curl. Run from the repo
+# root:
+#
+#   tests/registry.sh
+#
+# S3_PORT and REGISTRY_PORT pick the ports, 9300 and 5300 by default.
+set -euo pipefail
+
+S3_PORT=${S3_PORT:-9300}
+REGISTRY_PORT=${REGISTRY_PORT:-5300}
+BUCKET=registry
+IMAGE=localhost:$REGISTRY_PORT/simple-s3-test/blob:latest
+RUN=simple-s3-registry-test-$$
+
+data_dir=$(mktemp -d)
+build_dir=$(mktemp -d)
+server_pid=
+
+cleanup() {
+    docker rm -f "$RUN" >/dev/null 2>&1 || true
+    docker rmi -f "$IMAGE" >/dev/null 2>&1 || true
+    if [ -n "$server_pid" ]; then
+        kill "$server_pid" 2>/dev/null || true
+        wait "$server_pid" 2>/dev/null || true
+    fi
+    rm -rf "$data_dir" "$data_dir.log" "$build_dir"
+}
+trap cleanup EXIT
+
+fail() {
+    echo "registry test failed: $*" >&2
+    echo "--- simpleS3 log" >&2
+    cat "$data_dir.log" >&2 || true
+    echo "--- registry log" >&2
+    docker logs "$RUN" >&2 || true
+    exit 1
+}
+
+wait_for() {
+    for _ in $(seq 1 60); do
+        if curl -s -o /dev/null "$1"; then
+            return 0
+        fi
+        sleep 0.5
+    done
+    fail "$1 didn't come up"
+}
+
+cargo build --quiet
+./target/debug/simpleS3 --data-dir "$data_dir" --host 127.0.0.1 --port "$S3_PORT" \
+    --bucket "$BUCKET" --access-key mykey --secret-key mysecret --path-style \
+    >"$data_dir.log" 2>&1 &
+server_pid=$!
+wait_for "http://127.0.0.1:$S3_PORT/"
+
+docker run -d --name "$RUN" --network host \
+    -e REGISTRY_HTTP_ADDR="127.0.0.1:$REGISTRY_PORT" \
+    -e REGISTRY_STORAGE=s3 \
+    -e REGISTRY_STORAGE_S3_ACCESSKEY=mykey \
+    -e REGISTRY_STORAGE_S3_SECRETKEY=mysecret \
+    -e REGISTRY_STORAGE_S3_REGION=us-east-1 \
+    -e REGISTRY_STORAGE_S3_REGIONENDPOINT="http://127.0.0.1:$S3_PORT" \
+    -e REGISTRY_STORAGE_S3_FORCEPATHSTYLE=true \
+    -e REGISTRY_STORAGE_S3_BUCKET="$BUCKET" \
+    -e REGISTRY_STORAGE_S3_SECURE=false \
+    -e REGISTRY_STORAGE_S3_V4AUTH=true \
+    -e REGISTRY_STORAGE_S3_CHUNKSIZE=5242880 \
+    -e REGISTRY_STORAGE_REDIRECT_DISABLE=true \
+    registry:2 >/dev/null
+wait_for "http://127.0.0.1:$REGISTRY_PORT/v2/"
+
+# A 12 MiB layer of random bytes, more than two chunks
+cat >"$build_dir/Dockerfile" <<'EOF'
+FROM busybox:1.36
+RUN head -c 12582912 /dev/urandom >/blob && sha256sum /blob >/blob.sha256
+EOF
+docker build --quiet -t "$IMAGE" "$build_dir" >/dev/null
+expected=$(docker run --rm "$IMAGE" cat /blob.sha256)
+
+docker push "$IMAGE" >/dev/null || fail "push"
+docker rmi "$IMAGE" >/dev/null
+docker pull "$IMAGE" >/dev/null || fail "pull"
+
+pulled=$(docker run --rm "$IMAGE" sha256sum /blob)
+[ "$pulled" = "$expected" ] || fail "pulled layer is '$pulled', pushed '$expected'"
+[ -d "$data_dir/docker/registry/v2/blobs" ] || fail "no blobs in the data dir"
+curl -sf "http://127.0.0.1:$REGISTRY_PORT/v2/simple-s3-test/blob/tags/list" \
+    | grep -q '"latest"' || fail "the tag isn't listed"
+
+echo "registry test passed"